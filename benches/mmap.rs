@@ -0,0 +1,87 @@
+//! Reads of the blocks of an image file through each way of opening it: `pread` on the file,
+//! copies out of its mapping (`MmapVdev`), and the ARC, which holds copies of the blocks read
+//! with `pread` and the mapped blocks themselves. Each pass reads every block once, with the
+//! data in the page cache. The ARC has room for all of them (twice over, it's split in shards by
+//! DVA), but for the `miss` runs. Run with `cargo bench --bench mmap`.
+
+extern crate zfs;
+
+use std::{cmp, env};
+use std::fs::{self, File};
+use std::io::Write;
+use std::time::Instant;
+
+use zfs::arcache::ArCache;
+use zfs::dvaddr::DVAddr;
+use zfs::vdev_io::MmapVdev;
+use zfs::zio::Reader;
+
+/// Blocks in the image, after the 4 MiB of labels and boot block
+const BLOCKS: u64 = 1024;
+const BLOCK_SIZE: u64 = 128 << 10;
+const PASSES: u32 = 16;
+
+fn dva(block: u64) -> DVAddr {
+    DVAddr {
+        vdev: BLOCK_SIZE >> 9,
+        offset: block * (BLOCK_SIZE >> 9),
+    }
+}
+
+/// Read every block `PASSES` times with `read`, and print how long a read took on average
+fn bench<F: FnMut(&DVAddr) -> usize>(name: &str, mut read: F) {
+    let mut bytes = 0;
+    for block in 0..BLOCKS {
+        bytes += read(&dva(block));
+    }
+    let begin = Instant::now();
+    for _ in 0..PASSES {
+        for block in 0..BLOCKS {
+            bytes += read(&dva(block));
+        }
+    }
+    let elapsed = begin.elapsed();
+    let ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    let reads = BLOCKS * PASSES as u64;
+    println!("{:<12} {:>8} ns/read {:>8} MiB/s",
+             name,
+             ns / reads,
+             (reads * BLOCK_SIZE * 1_000_000_000 / cmp::max(ns, 1)) >> 20);
+    assert!(bytes > 0);
+}
+
+fn main() {
+    let path = env::temp_dir().join("zfs_bench_mmap");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&vec![0; 4 << 20]).unwrap();
+        let block: Vec<u8> = (0..BLOCK_SIZE).map(|i| i as u8).collect();
+        for _ in 0..BLOCKS {
+            file.write_all(&block).unwrap();
+        }
+    }
+    let path_str = path.to_str().unwrap();
+
+    let pread = Reader::new(File::open(&path).unwrap());
+    bench("pread", |dva| pread.read_dva(dva, BLOCK_SIZE).unwrap().len());
+
+    let mmap = Reader::new(MmapVdev::open(path_str, false).unwrap());
+    bench("mmap copy", |dva| mmap.read_dva(dva, BLOCK_SIZE).unwrap().len());
+
+    let arc = ArCache::with_size((2 * BLOCKS * BLOCK_SIZE) as usize);
+    bench("arc pread", |dva| arc.read(&pread, dva, BLOCK_SIZE).unwrap().len());
+
+    let arc = ArCache::with_size((2 * BLOCKS * BLOCK_SIZE) as usize);
+    bench("arc mapped", |dva| arc.read(&mmap, dva, BLOCK_SIZE).unwrap().len());
+
+    // A cache of a tenth of the blocks, which misses on all of them: copies go to the heap and
+    // are evicted again, mapped blocks are only borrowed
+    let arc = ArCache::with_size((BLOCKS / 10 * BLOCK_SIZE) as usize);
+    bench("miss pread", |dva| arc.read(&pread, dva, BLOCK_SIZE).unwrap().len());
+
+    let arc = ArCache::with_size((BLOCKS / 10 * BLOCK_SIZE) as usize);
+    bench("miss mapped", |dva| arc.read(&mmap, dva, BLOCK_SIZE).unwrap().len());
+
+    drop(mmap);
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,316 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use super::ZfsReader;
+use super::dmu_objset::ObjectSet;
+#[cfg(feature = "std")]
+use super::dmu_send::{self, SendOptions, SendStats};
+use super::dsl_crypt;
+use super::dsl_dataset;
+use super::dsl_pool::DslPool;
+use super::metaslab::{self, VdevSpace};
+use super::nvpair::NvList;
+use super::scrub::{self, ScrubReport};
+use super::spa::{self, ImportOptions, Spa};
+use super::spa_config::VdevConfig;
+use super::traverse::Filter;
+use super::vdev_io::Vdev;
+use super::version::PoolVersion;
+use super::zfs;
+use super::zpl::{self, DirEntry, FileHandle, Stat};
+
+/// Everything needed to read a pool, in one import: `use zfs::prelude::*;`
+pub mod prelude {
+    pub use super::{Dataset, File, Pool, Snapshot};
+    #[cfg(feature = "std")]
+    pub use super::super::dmu_send::{SendOptions, SendStats};
+    pub use super::super::metaslab::VdevSpace;
+    pub use super::super::spa::{ImportOptions, Rewind};
+    pub use super::super::version::PoolVersion;
+    pub use super::super::zfs::{Error, Result};
+    pub use super::super::zpl::{DirEntry, FileType, Stat};
+}
+
+/// An imported pool, the way in to its datasets. The datasets, snapshots and files opened from
+/// it take the pool along to every read, since that's what does the IO (and the caching).
+pub struct Pool {
+    spa: Spa,
+    dsl_pool: DslPool,
+}
+
+impl Pool {
+    /// Import the pool on the devices (or image files) at `paths`, all of them
+    #[cfg(feature = "std")]
+    pub fn import(paths: &[&str]) -> zfs::Result<Self> {
+        Pool::import_with(paths, &ImportOptions::default())
+    }
+
+    /// Like `import`, with `options`, e.g. to import a pool another host seems to have imported
+    #[cfg(feature = "std")]
+    pub fn import_with(paths: &[&str], options: &ImportOptions) -> zfs::Result<Self> {
+        Pool::new(try!(spa::open_with(paths, options).0))
+    }
+
+    /// Import the pool on `devices`, all of them, with `options`. This is how a pool is imported
+    /// without std, from devices of the backend's own (see `Vdev`).
+    pub fn import_vdevs(devices: Vec<Box<Vdev>>, options: &ImportOptions) -> zfs::Result<Self> {
+        Pool::new(try!(spa::open_vdevs(devices, options).0))
+    }
+
+    fn new(mut spa: Spa) -> zfs::Result<Self> {
+        let dsl_pool = {
+            let (reader, mos) = try!(spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+            try!(DslPool::open(reader, mos))
+        };
+        Ok(Pool {
+            spa: spa,
+            dsl_pool: dsl_pool,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.spa.name()
+    }
+
+    /// The version of the pool's on-disk format
+    pub fn version(&self) -> zfs::Result<PoolVersion> {
+        self.spa.version().ok_or(zfs::Error::Invalid)
+    }
+
+    /// Whether the pool can only be read, because of features we can't write or a device we
+    /// couldn't open for writing
+    pub fn is_read_only(&self) -> bool {
+        self.spa.is_read_only()
+    }
+
+    /// The names of every dataset of the pool, parents before their children. The root dataset
+    /// is named after the pool.
+    pub fn datasets(&mut self) -> zfs::Result<Vec<String>> {
+        let name = self.spa.name().to_owned();
+        let (reader, mos) = try!(self.spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+        let datasets = try!(self.dsl_pool.datasets(reader, mos, &name));
+        Ok(datasets.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Open the dataset called `name`, e.g. `tank/home`, as it is now. A snapshot name, e.g.
+    /// `tank/home@monday`, opens the snapshot.
+    pub fn dataset(&mut self, name: &str) -> zfs::Result<Dataset> {
+        let (reader, mos) = try!(self.spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+        let dataset = try!(self.dsl_pool.open_dataset(reader, mos, name, true));
+        Ok(Dataset {
+            name: name.to_owned(),
+            dataset: dataset,
+        })
+    }
+
+    /// The size and allocated space of each top-level vdev, like `zpool list -v`
+    pub fn space(&mut self) -> zfs::Result<Vec<VdevSpace>> {
+        let vdev_tree = {
+            let nv: &NvList = try!(self.spa.config().get("vdev_tree").ok_or(zfs::Error::Invalid));
+            try!(VdevConfig::from_nv_list(nv))
+        };
+        let (reader, mos) = try!(self.reader_and_mos());
+        metaslab::pool_space(reader, mos, &vdev_tree)
+    }
+
+    /// Verify the checksum of every block of the pool, like `zpool scrub`, rewriting the bad
+    /// copies from good ones if `repair` is set
+    pub fn scrub(&mut self, filter: &Filter, repair: bool) -> zfs::Result<ScrubReport> {
+        let name = self.spa.name().to_owned();
+        let (reader, mos) = try!(self.spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+        scrub::scrub_pool(reader, mos, &self.dsl_pool, &name, filter, repair, None)
+    }
+
+    /// Load the key of the encryption root of dataset `name`, so it and the datasets that
+    /// inherit its key can be read. `key` is in the root's `keyformat`: a passphrase, 64 hex
+    /// digits or 32 raw bytes.
+    pub fn load_key(&mut self, name: &str, key: &[u8]) -> zfs::Result<()> {
+        let pool_name = self.spa.name().to_owned();
+        let (reader, mos) = try!(self.spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+        dsl_crypt::load_keys(reader, mos, &self.dsl_pool, &pool_name, name, key).map(|_| ())
+    }
+
+    fn reader_and_mos(&mut self) -> zfs::Result<(&mut ZfsReader, &ObjectSet)> {
+        self.spa.reader_and_mos().ok_or(zfs::Error::Invalid)
+    }
+}
+
+/// A dataset, or a snapshot of one, opened for reading. It stays at the txg it was opened at:
+/// open it again to see what was written since.
+pub struct Dataset {
+    name: String,
+    dataset: dsl_dataset::Dataset,
+}
+
+impl Dataset {
+    /// The full name, `pool/dataset` or `pool/dataset@snapshot`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The txg the dataset was last written in
+    pub fn txg(&self) -> u64 {
+        self.dataset.txg()
+    }
+
+    /// The snapshots of the dataset, oldest first
+    pub fn snapshots(&self, pool: &mut Pool) -> zfs::Result<Vec<Snapshot>> {
+        let (reader, mos) = try!(pool.reader_and_mos());
+        let snapshots = try!(self.dataset.snapshots(reader, mos).map_err(|_| zfs::Error::Invalid));
+        Ok(snapshots.into_iter()
+                    .map(|snapshot| {
+                        Snapshot {
+                            dataset: self.name.clone(),
+                            snapshot: snapshot,
+                        }
+                    })
+                    .collect())
+    }
+
+    /// Open the snapshot of the dataset called `name`, e.g. `monday` for `tank/home@monday`, to
+    /// read the files as they were when it was taken
+    pub fn open_snapshot(&self, pool: &mut Pool, name: &str) -> zfs::Result<Dataset> {
+        let (reader, mos) = try!(pool.reader_and_mos());
+        let snapshot = try!(self.dataset
+                                .open_snapshot(reader, mos, name)
+                                .map_err(|_| zfs::Error::NoEntity));
+        Ok(Dataset {
+            name: format!("{}@{}", self.name, name),
+            dataset: snapshot,
+        })
+    }
+
+    /// Open the regular file at `path` for reading. Fails with `NoEntity` if there's no such
+    /// file, or it isn't a regular file.
+    pub fn open(&self, pool: &mut Pool, path: &str) -> zfs::Result<File> {
+        let reader = try!(pool.reader_and_mos()).0;
+        let handle = try!(zpl::open(reader, &self.dataset, path)
+                              .map_err(|_| zfs::Error::NoEntity));
+        Ok(File { handle: handle })
+    }
+
+    /// The metadata of the file at `path`, following symlinks
+    pub fn stat(&self, pool: &mut Pool, path: &str) -> zfs::Result<Stat> {
+        let reader = try!(pool.reader_and_mos()).0;
+        zpl::stat(reader, &self.dataset, path).map_err(|_| zfs::Error::NoEntity)
+    }
+
+    /// The entries of the directory at `path`, `.` and `..` left out
+    pub fn read_dir(&self, pool: &mut Pool, path: &str) -> zfs::Result<Vec<DirEntry>> {
+        let reader = try!(pool.reader_and_mos()).0;
+        zpl::readdir(reader, &self.dataset, path).map_err(|_| zfs::Error::NoEntity)
+    }
+
+    /// Send the dataset, usually a snapshot, as a full replication stream (like `zfs send`) to
+    /// `out`
+    #[cfg(feature = "std")]
+    pub fn send<W: Write>(&self,
+                          pool: &mut Pool,
+                          options: &SendOptions,
+                          out: &mut W)
+                          -> zfs::Result<SendStats> {
+        let (reader, mos) = try!(pool.reader_and_mos());
+        dmu_send::send(reader, mos, &self.dataset, &self.name, options, out)
+    }
+
+    /// Send what changed since `from`, an earlier snapshot of the dataset, as an incremental
+    /// stream (like `zfs send -i`) to `out`. Fails with `Invalid` if `from` isn't one.
+    #[cfg(feature = "std")]
+    pub fn send_incremental<W: Write>(&self,
+                                      pool: &mut Pool,
+                                      from: &Dataset,
+                                      options: &SendOptions,
+                                      out: &mut W)
+                                      -> zfs::Result<SendStats> {
+        let (reader, mos) = try!(pool.reader_and_mos());
+        dmu_send::send_incremental(reader,
+                                   mos,
+                                   &from.dataset,
+                                   &self.dataset,
+                                   &self.name,
+                                   options,
+                                   out)
+    }
+
+    /// The target of the symlink at `path`
+    pub fn read_link(&self, pool: &mut Pool, path: &str) -> zfs::Result<String> {
+        let reader = try!(pool.reader_and_mos()).0;
+        zpl::readlink(reader, &self.dataset, path).map_err(|_| zfs::Error::NoEntity)
+    }
+}
+
+/// A snapshot of a dataset. Its files are read by opening it (see `open`).
+pub struct Snapshot {
+    /// The full name of the dataset it's a snapshot of
+    dataset: String,
+    snapshot: dsl_dataset::Snapshot,
+}
+
+impl Snapshot {
+    /// The full name, `pool/dataset@snapshot`
+    pub fn name(&self) -> String {
+        format!("{}@{}", self.dataset, self.snapshot.name)
+    }
+
+    pub fn guid(&self) -> u64 {
+        self.snapshot.guid
+    }
+
+    /// When the snapshot was taken, in seconds since 1970
+    pub fn creation_time(&self) -> u64 {
+        self.snapshot.creation_time
+    }
+
+    pub fn creation_txg(&self) -> u64 {
+        self.snapshot.creation_txg
+    }
+
+    /// Whether user holds or clones keep the snapshot from being destroyed
+    pub fn is_held(&self) -> bool {
+        self.snapshot.held
+    }
+
+    /// Open the snapshot to read its files, as they were when it was taken
+    pub fn open(&self, pool: &mut Pool) -> zfs::Result<Dataset> {
+        let (reader, mos) = try!(pool.reader_and_mos());
+        let dataset = try!(dsl_dataset::Dataset::open(reader, mos, self.snapshot.object, true)
+                               .map_err(|_| zfs::Error::Invalid));
+        Ok(Dataset {
+            name: self.name(),
+            dataset: dataset,
+        })
+    }
+}
+
+/// A regular file opened for reading
+pub struct File {
+    handle: FileHandle,
+}
+
+impl File {
+    /// The size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.handle.size
+    }
+
+    /// Read into `buf` from `offset`, like `pread`: returns how many bytes were read, which is
+    /// less than asked for only at the end of the file
+    pub fn read_at(&self, pool: &mut Pool, offset: u64, buf: &mut [u8]) -> zfs::Result<usize> {
+        let reader = try!(pool.reader_and_mos()).0;
+        self.handle.read_into(reader, offset, buf).map_err(|_| zfs::Error::Invalid)
+    }
+
+    /// Read the whole file
+    pub fn read_to_end(&self, pool: &mut Pool) -> zfs::Result<Vec<u8>> {
+        let reader = try!(pool.reader_and_mos()).0;
+        self.handle.read_all(reader).map_err(|_| zfs::Error::Invalid)
+    }
+
+    /// The holes of the file, as (offset, length) byte ranges
+    pub fn holes(&self, pool: &mut Pool) -> zfs::Result<Vec<(u64, u64)>> {
+        let reader = try!(pool.reader_and_mos()).0;
+        self.handle.holes(reader).map_err(|_| zfs::Error::Invalid)
+    }
+}
@@ -1,89 +1,495 @@
-use std::collections::{HashMap, VecDeque};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(not(feature = "djb2"))]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "djb2")]
+use std::hash::BuildHasherDefault;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 
+use super::block_ptr::BlockPtr;
 use super::dvaddr::DVAddr;
+use super::kstat;
+use super::spa_config;
+use super::vdev_io::MappedBlock;
+use super::l2arc::{L2Arc, L2Stats};
+use super::zfs;
 use super::zio;
+use super::zio_compress::Codec;
+#[cfg(feature = "async")]
+use super::zio_async::{AsyncReader, IoFuture};
+#[cfg(feature = "djb2")]
 use super::djb2::Djb2;
-use std::hash::BuildHasherDefault;
 
-/// MRU - Most Recently Used cache
-struct Mru {
-    map: HashMap<DVAddr, Vec<u8>, BuildHasherDefault<Djb2>>,
-    /// Oldest DVAddrs are at the end
-    queue: VecDeque<DVAddr>, 
-    /// Max mru cache size in blocks
-    size: usize,
-    /// Number of used blocks in mru cache
-    used: usize,
+/// The hasher of a cache's DVAs by default: SipHash, keyed at random for each cache so the DVAs
+/// written can't be picked to pile up in one shard. With the `djb2` feature, Djb2, which needs
+/// no source of randomness.
+#[cfg(not(feature = "djb2"))]
+pub type ArcHasher = RandomState;
+#[cfg(feature = "djb2")]
+pub type ArcHasher = BuildHasherDefault<Djb2>;
+
+/// Default size of the cache, in bytes of cached blocks
+pub const ARC_DEFAULT_SIZE: usize = 32 << 20;
+
+/// Shards a cache is split in by default
+pub const ARC_DEFAULT_SHARDS: usize = 16;
+
+/// Bytes of bookkeeping a fixed cache (see `ArCache::fixed`) sets aside for each block it can
+/// hold: a slot in each of the four lists' maps and in `verified`, and an entry of the lists'
+/// order
+pub const ARC_ENTRY_OVERHEAD: usize = 512;
+
+/// A block's data as the cache holds it, and hands it out: on the heap, or borrowed from the
+/// mapping of a device that lends its bytes out (see `Vdev::mapped`), which saves the copy. It
+/// derefs to the bytes, and clones share them.
+#[derive(Clone)]
+pub enum ArcBuf {
+    Heap(Arc<[u8]>),
+    Mapped(MappedBlock),
 }
 
-impl Mru {
-    pub fn new() -> Self {
-        Mru {
-            map: HashMap::with_hasher(Default::default()),
-            queue: VecDeque::new(),
-            size: 1000,
-            used: 0,
+impl ArcBuf {
+    /// Decompress the data with `codec`. Data that isn't compressed is passed on as it is.
+    fn decode(self, codec: Codec) -> zfs::Result<ArcBuf> {
+        if !codec.is_compressed() {
+            return Ok(self);
         }
+        codec.decode_from(&self).map(ArcBuf::from)
     }
+}
 
-    pub fn cache_block(&mut self, dva: &DVAddr, block: Vec<u8>) -> Result<Vec<u8>, &str> {
-        // If necessary, make room for the block in the cache
-        while self.used + (dva.asize() as usize) > self.size {
-            let last_dva = match self.queue.pop_back() {
-                Some(dva) => dva,
-                None => return Err("No more ARC MRU items to free"),
-            };
-            self.map.remove(&last_dva);
-            self.used -= last_dva.asize() as usize;
+impl Deref for ArcBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            ArcBuf::Heap(ref data) => data,
+            ArcBuf::Mapped(ref data) => data,
         }
+    }
+}
 
-        // Add the block to the cache
-        self.used += dva.asize() as usize;
-        self.map.insert(*dva, block);
-        self.queue.push_front(*dva);
-        Ok(self.map.get(dva).unwrap().clone())
+impl From<Vec<u8>> for ArcBuf {
+    fn from(data: Vec<u8>) -> Self {
+        ArcBuf::Heap(Arc::from(data))
     }
 }
 
-/// MFU - Most Frequently Used cache
-struct Mfu {
-    // TODO: Keep track of use counts. So mfu_map becomes (use_count: u64, Vec<u8>). Reset the use
-    // count every once in a while. For instance, every 1000 reads. This will probably end up being
-    // a knob for the user.
-    // TODO: Keep track of minimum frequency and corresponding DVA
-    map: HashMap<DVAddr, (u64, Vec<u8>), BuildHasherDefault<Djb2>>,
-    size: usize, // Max mfu cache size in blocks
-    used: usize, // Number of used bytes in mfu cache
+/// The data of a cached block
+#[derive(Clone)]
+struct Cached {
+    /// Shared with whoever read the block, so hits don't copy it
+    data: ArcBuf,
+    /// Whether it's been decompressed, rather than kept as it is on disk
+    decoded: bool,
 }
 
-impl Mfu {
-    pub fn new() -> Self {
-        Mfu {
-            map: HashMap::with_hasher(Default::default()),
-            size: 1000,
-            used: 0,
+/// One of the ARC's lists of blocks, by when they were last used. The ghost lists only keep
+/// track of blocks evicted from the cache, not their data.
+struct ArcList<S> {
+    /// Each block's last use, size and data
+    blocks: HashMap<DVAddr, (u64, usize, Option<Cached>), S>,
+    /// The blocks by their last use, least recently used first
+    order: BTreeMap<u64, DVAddr>,
+    /// Size of the blocks in bytes
+    bytes: usize,
+}
+
+impl<S: BuildHasher + Clone> ArcList<S> {
+    fn new(hasher: S) -> Self {
+        ArcList {
+            blocks: HashMap::with_hasher(hasher),
+            order: BTreeMap::new(),
+            bytes: 0,
         }
     }
 
-    pub fn cache_block(&mut self, dva: &DVAddr, block: Vec<u8>) -> Result<&[u8], &str> {
-        {
-            let mut lowest_freq = !0;
-            let mut lowest_dva  = Err("No valid DVA found.");
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
 
-            for (&dva_key, &(freq, _)) in self.map.iter() {
-                if freq < lowest_freq {
-                    lowest_freq = freq;
-                    lowest_dva = Ok(dva_key);
-                }
+    fn contains(&self, dva: &DVAddr) -> bool {
+        self.blocks.contains_key(dva)
+    }
+
+    /// Add a block, used at `tick`
+    fn insert(&mut self, dva: DVAddr, tick: u64, size: usize, data: Option<Cached>) {
+        self.remove(&dva);
+        self.blocks.insert(dva, (tick, size, data));
+        self.order.insert(tick, dva);
+        self.bytes += size;
+    }
+
+    /// Take a block off the list, returning its size and data
+    fn remove(&mut self, dva: &DVAddr) -> Option<(usize, Option<Cached>)> {
+        self.blocks.remove(dva).map(|(tick, size, data)| {
+            self.order.remove(&tick);
+            self.bytes -= size;
+            (size, data)
+        })
+    }
+
+    /// The data of a block, which is now the most recently used, used at `tick`
+    fn touch(&mut self, dva: &DVAddr, tick: u64) -> Option<Cached> {
+        let block = match self.blocks.get_mut(dva) {
+            Some(block) => block,
+            None => return None,
+        };
+        self.order.remove(&block.0);
+        self.order.insert(tick, *dva);
+        block.0 = tick;
+        block.2.clone()
+    }
+
+    /// Take the least recently used block off the list
+    fn pop_lru(&mut self) -> Option<(DVAddr, usize, Option<Cached>)> {
+        let dva = match self.order.values().next() {
+            Some(&dva) => dva,
+            None => return None,
+        };
+        self.remove(&dva).map(|(size, data)| (dva, size, data))
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+/// A snapshot of the ARC's statistics, like the `arcstats` kstat. The counters go up from the
+/// creation of the cache, the sizes are as of the snapshot.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ArcStats {
+    /// Reads served from the cache
+    pub hits: u64,
+    /// Reads that had to go to disk
+    pub misses: u64,
+    /// Hits on blocks read once before, which moved to the MFU list
+    pub mru_hits: u64,
+    /// Hits on blocks read more than once before
+    pub mfu_hits: u64,
+    /// Misses on blocks recently evicted from the MRU list
+    pub mru_ghost_hits: u64,
+    /// Misses on blocks recently evicted from the MFU list
+    pub mfu_ghost_hits: u64,
+    /// Blocks evicted to make room for others
+    pub evictions: u64,
+    pub evicted_bytes: u64,
+    /// Blocks read ahead into the cache
+    pub prefetched: u64,
+    /// Hits whose checksum was already verified
+    pub verify_skipped: u64,
+    /// Hits on blocks kept compressed, which had to be decompressed
+    pub decompressions: u64,
+    /// Size of the cached blocks in bytes
+    pub size: u64,
+    pub mru_size: u64,
+    pub mfu_size: u64,
+    /// Size of the blocks the ghost lists remember
+    pub mru_ghost_size: u64,
+    pub mfu_ghost_size: u64,
+    /// Size the cache is allowed to grow to (`c`)
+    pub target_size: u64,
+    /// Target size of the MRU list (`p`)
+    pub mru_target_size: u64,
+    /// Bookkeeping set aside by a fixed cache, on top of `target_size`
+    pub overhead: u64,
+}
+
+impl ArcStats {
+    /// Share of the reads served from the cache, 0 before the first read
+    pub fn hit_ratio(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+/// Blocks being read ahead by a background thread, or by an asynchronous read
+struct Prefetch {
+    rx: Receiver<(DVAddr, Arc<[u8]>)>,
+    /// Blocks that haven't arrived yet
+    dvas: HashSet<DVAddr>,
+    /// Whether the blocks were read ahead, rather than for someone waiting for them
+    read_ahead: bool,
+}
+
+/// One shard of the cache: an ARC of its own over the blocks whose DVAs hash to it
+struct ArcShard<S> {
+    mru: ArcList<S>,
+    mfu: ArcList<S>,
+    mru_ghost: ArcList<S>,
+    mfu_ghost: ArcList<S>,
+    /// Size of the shard in bytes (`c`)
+    size: usize,
+    /// Target size of the MRU list in bytes (`p`)
+    mru_target: usize,
+    /// Counts reads and insertions, to order the lists
+    tick: u64,
+    /// The checksums cached blocks have been verified against, so reading them again from the
+    /// cache doesn't need another checksum. Dropped whenever the block is fetched from disk.
+    verified: HashMap<DVAddr, [u64; 4], S>,
+    hits: u64,
+    misses: u64,
+    mru_hits: u64,
+    mfu_hits: u64,
+    mru_ghost_hits: u64,
+    mfu_ghost_hits: u64,
+    evictions: u64,
+    evicted_bytes: u64,
+    prefetched: u64,
+    verify_skipped: u64,
+    decompressions: u64,
+    /// Most blocks the shard keeps track of in each list, if it's fixed
+    max_blocks: Option<usize>,
+    /// Bookkeeping set aside by a fixed shard, on top of `size`
+    overhead: usize,
+    /// Whether evicted blocks are kept in `evicted`, for the L2ARC
+    spill: bool,
+    /// Blocks evicted since the last time they were taken, with their data
+    evicted: Vec<(DVAddr, Cached)>,
+}
+
+impl<S: BuildHasher + Clone> ArcShard<S> {
+    fn new(size: usize, hasher: &S) -> Self {
+        ArcShard {
+            mru: ArcList::new(hasher.clone()),
+            mfu: ArcList::new(hasher.clone()),
+            mru_ghost: ArcList::new(hasher.clone()),
+            mfu_ghost: ArcList::new(hasher.clone()),
+            size: size,
+            mru_target: 0,
+            tick: 0,
+            verified: HashMap::with_hasher(hasher.clone()),
+            hits: 0,
+            misses: 0,
+            mru_hits: 0,
+            mfu_hits: 0,
+            mru_ghost_hits: 0,
+            mfu_ghost_hits: 0,
+            evictions: 0,
+            evicted_bytes: 0,
+            prefetched: 0,
+            verify_skipped: 0,
+            decompressions: 0,
+            max_blocks: None,
+            overhead: 0,
+            spill: false,
+            evicted: Vec::new(),
+        }
+    }
+
+    fn is_cached(&self, dva: &DVAddr) -> bool {
+        self.mru.contains(dva) || self.mfu.contains(dva)
+    }
+
+    /// Cache a block just read from disk. If it's in a ghost list, it's been evicted too early:
+    /// the list it was evicted from gets a larger share of the cache, and the block goes straight
+    /// to the MFU list. A block read ahead rather than asked for is no sign of that. A block
+    /// another thread has cached in the meantime is left alone.
+    fn cache_block(&mut self, dva: &DVAddr, block: Cached, read_ahead: bool) {
+        let size = block.data.len();
+        if size > self.size || self.is_cached(dva) {
+            return;
+        }
+        let mut frequent = false;
+        let mut mfu_ghost_hit = false;
+        if self.mru_ghost.contains(dva) {
+            if !read_ahead {
+                let ratio = cmp::max(self.mfu_ghost.bytes / self.mru_ghost.bytes, 1);
+                self.mru_target = cmp::min(self.mru_target + ratio * size, self.size);
+                self.mru_ghost_hits += 1;
+                frequent = true;
+            }
+            self.mru_ghost.remove(dva);
+        } else if self.mfu_ghost.contains(dva) {
+            if !read_ahead {
+                let ratio = cmp::max(self.mru_ghost.bytes / self.mfu_ghost.bytes, 1);
+                self.mru_target = self.mru_target.saturating_sub(ratio * size);
+                self.mfu_ghost_hits += 1;
+                frequent = true;
+                mfu_ghost_hit = true;
+            }
+            self.mfu_ghost.remove(dva);
+        }
+
+        self.make_room(size, mfu_ghost_hit);
+        self.tick += 1;
+        if frequent {
+            self.mfu.insert(*dva, self.tick, size, Some(block));
+        } else {
+            self.mru.insert(*dva, self.tick, size, Some(block));
+        }
+        self.trim_ghosts();
+    }
+
+    /// Make the shard hold at most `max_blocks` blocks in each list, within `size` bytes
+    /// bookkeeping included, and allocate that bookkeeping now
+    fn fix(&mut self, size: usize, max_blocks: usize) {
+        let max_blocks = cmp::min(max_blocks, size / (ARC_ENTRY_OVERHEAD + 512));
+        for list in &mut [&mut self.mru, &mut self.mfu, &mut self.mru_ghost, &mut self.mfu_ghost] {
+            list.blocks.reserve(max_blocks);
+        }
+        self.verified.reserve(2 * max_blocks + 64);
+        self.max_blocks = Some(max_blocks);
+        self.overhead = max_blocks * ARC_ENTRY_OVERHEAD;
+        self.resize(size);
+    }
+
+    /// Whether `blocks` are more than a list of a fixed shard can hold
+    fn too_many(&self, blocks: usize) -> bool {
+        self.max_blocks.map_or(false, |max_blocks| blocks > max_blocks)
+    }
+
+    /// Evict blocks until there's room for `size` more bytes, to the ghost lists. In a fixed
+    /// shard, a block is evicted for every block added past the most it can hold.
+    fn make_room(&mut self, size: usize, mfu_ghost_hit: bool) {
+        let adding = (size > 0) as usize;
+        while self.mru.bytes + self.mfu.bytes + size > self.size ||
+              self.too_many(self.mru.len() + self.mfu.len() + adding) {
+            let mru_over = self.mru.bytes > self.mru_target ||
+                           mfu_ghost_hit && self.mru.bytes == self.mru_target;
+            let from_mru = self.mru.len() > 0 && (mru_over || self.mfu.len() == 0);
+            let (list, ghost) = if from_mru {
+                (&mut self.mru, &mut self.mru_ghost)
+            } else {
+                (&mut self.mfu, &mut self.mfu_ghost)
+            };
+            let (evicted, evicted_size, data) = match list.pop_lru() {
+                Some(evicted) => evicted,
+                None => break,
+            };
+            self.tick += 1;
+            ghost.insert(evicted, self.tick, evicted_size, None);
+            if self.spill {
+                self.evicted.push((evicted, data.unwrap()));
+            }
+            self.verified.remove(&evicted);
+            self.evictions += 1;
+            self.evicted_bytes += evicted_size as u64;
+        }
+    }
+
+    /// Forget the oldest evicted blocks: the MRU list and its ghosts together are kept within the
+    /// size of the shard, all four lists within twice that
+    fn trim_ghosts(&mut self) {
+        while self.mru.bytes + self.mru_ghost.bytes > self.size ||
+              self.too_many(self.mru_ghost.len()) {
+            if self.mru_ghost.pop_lru().is_none() {
+                break;
+            }
+        }
+        while self.mru.bytes + self.mfu.bytes + self.mru_ghost.bytes + self.mfu_ghost.bytes >
+              2 * self.size || self.too_many(self.mfu_ghost.len()) {
+            if self.mfu_ghost.pop_lru().is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Swap the data of cached block `dva` for `block`, e.g. for its decompressed form. It stays
+    /// in the list it's in.
+    fn replace(&mut self, dva: &DVAddr, block: Cached) {
+        let frequent = match self.mru.remove(dva) {
+            Some(_) => false,
+            None if self.mfu.remove(dva).is_some() => true,
+            None => return,
+        };
+        self.verified.remove(dva);
+        let size = block.data.len();
+        if size > self.size {
+            return;
+        }
+        self.make_room(size, false);
+        self.tick += 1;
+        if frequent {
+            self.mfu.insert(*dva, self.tick, size, Some(block));
+        } else {
+            self.mru.insert(*dva, self.tick, size, Some(block));
+        }
+    }
+
+    /// Make the shard `size` bytes, which for a fixed shard includes its bookkeeping
+    fn resize(&mut self, size: usize) {
+        let size = size.saturating_sub(self.overhead);
+        self.size = size;
+        self.mru_target = cmp::min(self.mru_target, size);
+        self.make_room(0, false);
+        self.trim_ghosts();
+    }
+
+    fn set_verified(&mut self, dva: &DVAddr, checksum: [u64; 4]) {
+        if !self.is_cached(dva) {
+            return;
+        }
+        // Forget about blocks that have been evicted since
+        if self.verified.len() >= 2 * (self.mru.len() + self.mfu.len()) + 64 {
+            let (mru, mfu) = (&self.mru, &self.mfu);
+            self.verified.retain(|dva, _| mru.contains(dva) || mfu.contains(dva));
+        }
+        self.verified.insert(*dva, checksum);
+    }
+
+    fn evict(&mut self, dva: &DVAddr) {
+        self.mru.remove(dva);
+        self.mfu.remove(dva);
+        self.verified.remove(dva);
+    }
+
+    /// Drop every cached block that `invalid` holds for, and forget them in the ghost lists too
+    /// Drop every block, and what's known of the ones evicted
+    fn clear(&mut self) {
+        for list in &mut [&mut self.mru, &mut self.mfu, &mut self.mru_ghost, &mut self.mfu_ghost] {
+            list.clear();
+        }
+        self.verified.clear();
+        self.evicted.clear();
+    }
+
+    fn evict_where(&mut self, invalid: &Fn(&DVAddr) -> bool) {
+        for list in &mut [&mut self.mru, &mut self.mfu, &mut self.mru_ghost, &mut self.mfu_ghost] {
+            let dvas: Vec<DVAddr> = list.blocks
+                                        .keys()
+                                        .filter(|dva| invalid(dva))
+                                        .cloned()
+                                        .collect();
+            for dva in dvas {
+                list.remove(&dva);
             }
+        }
+        self.verified.retain(|dva, _| !invalid(dva));
+    }
 
-            self.map.remove(&try!(lowest_dva));
+    /// The cached block at `dva`, if there is one. A miss is counted as such.
+    fn lookup(&mut self, dva: &DVAddr) -> Option<Cached> {
+        self.tick += 1;
+        if let Some((size, block)) = self.mru.remove(dva) {
+            // Read a second time, the block is frequently used now
+            let block = block.unwrap();
+            self.mfu.insert(*dva, self.tick, size, Some(block.clone()));
+            self.hits += 1;
+            self.mru_hits += 1;
+            return Some(block);
+        }
+        if let Some(block) = self.mfu.touch(dva, self.tick) {
+            self.hits += 1;
+            self.mfu_hits += 1;
+            return Some(block);
         }
 
-        // Add the block to the cache
-        self.used += dva.asize() as usize;
-        self.map.insert(*dva, (2, block));
-        Ok(&self.map.get(dva).unwrap().1)
+        self.misses += 1;
+        self.verified.remove(dva);
+        None
     }
 }
 
@@ -91,41 +497,1243 @@ impl Mfu {
 /// its buffer on the heap rather than in a private pool thing. This makes it much
 /// simpler to implement, but defers the fragmentation problem to the heap allocator.
 /// We named the type `ArCache` to avoid confusion with Rust's `Arc` reference type.
-pub struct ArCache {
-    mru: Mru,
-    mfu: Mfu,
+///
+/// Blocks read once are in the MRU list, blocks read again move to the MFU list. Blocks evicted
+/// from either are remembered in its ghost list. A read of a block in the MRU ghost list means
+/// the MRU list would have done with more room, and its target size `p` grows; one in the MFU
+/// ghost list makes it shrink. Eviction takes from the MRU list while it's above its target, and
+/// from the MFU list otherwise, so a scan of blocks read only once doesn't flush the blocks read
+/// over and over.
+///
+/// The cache can be shared by any number of threads. It's split in shards by DVA, each with its
+/// own lists and lock, and no lock is held while a block is read from disk, so threads only wait
+/// for each other when they use the same shard at the same moment.
+///
+/// With an L2ARC (see `with_l2arc`), the blocks evicted from the cache are written to its cache
+/// devices, and blocks that miss in the cache are looked for there before going to the pool.
+///
+/// A txg we sync doesn't make any cached block stale: a DVA holds the same data until it's freed
+/// and written again, and our writes drop it from the cache (see `invalidate_writes`). Those of
+/// another writer of the pool don't, so the cache is emptied once its txgs are seen (see
+/// `ZfsReader::revalidate`).
+///
+/// `S` hashes DVAs, to pick their shard and in the shards' maps (see `with_hasher`).
+pub struct ArCache<S = ArcHasher> {
+    shards: Vec<Mutex<ArcShard<S>>>,
+    hasher: S,
+    prefetches: Mutex<Vec<Prefetch>>,
+    /// Verify the checksum of every read, even of blocks that were verified before. Blocks kept
+    /// decompressed were verified on their way in, there's nothing to check them against after.
+    pub paranoid: bool,
+    /// Whether `read_block` keeps blocks as they are on disk (see `with_compression`)
+    compressed: bool,
+    l2: Option<L2Arc>,
+    /// The size a fixed cache was created with, which it never grows past
+    ceiling: Option<usize>,
 }
 
 impl ArCache {
     pub fn new() -> Self {
+        ArCache::with_size(ARC_DEFAULT_SIZE)
+    }
+
+    /// A cache of `size` bytes
+    pub fn with_size(size: usize) -> Self {
+        ArCache::with_shards(size, ARC_DEFAULT_SHARDS)
+    }
+
+    /// A cache of `size` bytes in `shards` shards of equal size
+    pub fn with_shards(size: usize, shards: usize) -> Self {
+        ArCache::with_hasher(size, shards, ArcHasher::default())
+    }
+
+    /// A cache of `size` bytes that keeps track of at most `max_blocks` blocks, and never takes
+    /// up more memory than that: its bookkeeping, counted in `size`, is allocated up front, and
+    /// it can't be resized past `size`. Blocks aren't prefetched, since they'd be held outside of
+    /// the cache until they're read. For embedded use, where memory has to be bounded exactly.
+    pub fn fixed(size: usize, max_blocks: usize) -> Self {
+        let mut arc = ArCache::with_size(size);
+        let count = arc.shards.len();
+        for (i, shard) in arc.shards.iter().enumerate() {
+            shard.lock().unwrap().fix(shard_size(size, count, i), shard_size(max_blocks, count, i));
+        }
+        arc.ceiling = Some(size);
+        arc
+    }
+}
+
+impl<S: BuildHasher + Clone> ArCache<S> {
+    /// A cache of `size` bytes in `shards` shards, hashing DVAs with `hasher`
+    pub fn with_hasher(size: usize, shards: usize, hasher: S) -> Self {
+        let shards = cmp::max(shards, 1);
         ArCache {
-            mru: Mru::new(),
-            mfu: Mfu::new(),
+            shards: (0..shards).map(|i| {
+                                   Mutex::new(ArcShard::new(shard_size(size, shards, i), &hasher))
+                               })
+                               .collect(),
+            hasher: hasher,
+            prefetches: Mutex::new(Vec::new()),
+            paranoid: false,
+            compressed: false,
+            l2: None,
+            ceiling: None,
         }
     }
 
-    pub fn read(&mut self, reader: &mut zio::Reader, dva: &DVAddr) -> Result<Vec<u8>, &str> {
-        if let Some(block) = self.mru.map.remove(dva) {
-            self.mfu.map.insert(*dva, (0, block.clone()));
+    /// The size a fixed cache was created with, None for a cache that isn't
+    pub fn ceiling(&self) -> Option<usize> {
+        self.ceiling
+    }
 
-            // Block is cached
-            return Ok(block);
+    /// Keep the blocks read with `read_block` compressed, as they are on disk, and decompress
+    /// them on every hit. Compressible data takes up a fraction of the room, for some CPU time
+    /// on each hit.
+    pub fn with_compression(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Write the blocks evicted from the cache to the cache devices of `l2`, and look for the
+    /// blocks that miss there before going to the pool
+    pub fn with_l2arc(mut self, l2: L2Arc) -> Self {
+        for shard in &self.shards {
+            shard.lock().unwrap().spill = true;
+        }
+        self.l2 = Some(l2);
+        self
+    }
+
+    /// The L2ARC's statistics, if there is one
+    pub fn l2_stats(&self) -> Option<L2Stats> {
+        self.l2.as_ref().map(L2Arc::stats)
+    }
+
+    fn shard<'a>(&'a self, dva: &DVAddr) -> MutexGuard<'a, ArcShard<S>> {
+        let mut hasher = self.hasher.build_hasher();
+        dva.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % self.shards.len()].lock().unwrap()
+    }
+
+    /// Run `f` on the shard of `dva`, then write the blocks it evicted to the L2ARC, with the
+    /// shard unlocked
+    fn update<F, R>(&self, dva: &DVAddr, f: F) -> R
+        where F: FnOnce(&mut ArcShard<S>) -> R
+    {
+        let (ret, evicted) = {
+            let mut shard = self.shard(dva);
+            let ret = f(&mut shard);
+            (ret, mem::replace(&mut shard.evicted, Vec::new()))
+        };
+        self.spill(evicted);
+        ret
+    }
+
+    fn spill(&self, evicted: Vec<(DVAddr, Cached)>) {
+        if let Some(ref l2) = self.l2 {
+            for (dva, block) in evicted {
+                // Mapped blocks are only a page fault away as it is
+                if let ArcBuf::Heap(ref data) = block.data {
+                    l2.spill(&dva, data, block.decoded);
+                }
+            }
+        }
+    }
+
+    /// The block at `dva` from the L2ARC, if it's there. One kept as it is on disk has to pass
+    /// `verify`, if it's given.
+    fn read_l2(&self, dva: &DVAddr, verify: Option<&Fn(&[u8]) -> bool>) -> Option<Cached> {
+        let l2 = match self.l2 {
+            Some(ref l2) => l2,
+            None => return None,
+        };
+        let (data, decoded) = match l2.read(dva) {
+            Some(block) => block,
+            None => return None,
+        };
+        if !decoded && !verify.map_or(true, |verify| verify(&data)) {
+            l2.evict(dva);
+            return None;
+        }
+        Some(Cached {
+            data: ArcBuf::from(data),
+            decoded: decoded,
+        })
+    }
+
+    /// Size of the cache in bytes: how large the cached blocks may grow to altogether, along
+    /// with the bookkeeping of a fixed cache
+    pub fn size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.size + shard.overhead
+            })
+            .sum()
+    }
+
+    /// Change the size of the cache to `size` bytes. If it shrinks, blocks are evicted right away
+    /// until they fit. A fixed cache doesn't grow past the size it was created with.
+    pub fn resize(&self, size: usize) {
+        let size = self.ceiling.map_or(size, |ceiling| cmp::min(size, ceiling));
+        let count = self.shards.len();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let evicted = {
+                let mut shard = shard.lock().unwrap();
+                shard.resize(shard_size(size, count, i));
+                mem::replace(&mut shard.evicted, Vec::new())
+            };
+            self.spill(evicted);
+        }
+    }
+
+    /// The cache's statistics as of now
+    pub fn stats(&self) -> ArcStats {
+        let mut stats = ArcStats::default();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.hits += shard.hits;
+            stats.misses += shard.misses;
+            stats.mru_hits += shard.mru_hits;
+            stats.mfu_hits += shard.mfu_hits;
+            stats.mru_ghost_hits += shard.mru_ghost_hits;
+            stats.mfu_ghost_hits += shard.mfu_ghost_hits;
+            stats.evictions += shard.evictions;
+            stats.evicted_bytes += shard.evicted_bytes;
+            stats.prefetched += shard.prefetched;
+            stats.verify_skipped += shard.verify_skipped;
+            stats.decompressions += shard.decompressions;
+            stats.size += (shard.mru.bytes + shard.mfu.bytes) as u64;
+            stats.mru_size += shard.mru.bytes as u64;
+            stats.mfu_size += shard.mfu.bytes as u64;
+            stats.mru_ghost_size += shard.mru_ghost.bytes as u64;
+            stats.mfu_ghost_size += shard.mfu_ghost.bytes as u64;
+            stats.target_size += shard.size as u64;
+            stats.mru_target_size += shard.mru_target as u64;
+            stats.overhead += shard.overhead as u64;
+        }
+        stats
+    }
+
+    /// Add the cache's statistics to `kstats`
+    pub fn kstats(&self, kstats: &mut kstat::Registry) {
+        let stats = self.stats();
+        kstats.counter("zfs_arc_hits_total", "Reads served from the ARC", stats.hits);
+        kstats.counter("zfs_arc_misses_total", "Reads that had to go to disk", stats.misses);
+        kstats.counter("zfs_arc_prefetch_blocks_total",
+                       "Blocks read ahead into the ARC",
+                       stats.prefetched);
+        kstats.counter("zfs_arc_verify_skipped_total",
+                       "Cache hits whose checksum was already verified",
+                       stats.verify_skipped);
+        kstats.counter("zfs_arc_decompressions_total",
+                       "Hits on blocks kept compressed, which had to be decompressed",
+                       stats.decompressions);
+        kstats.gauge("zfs_arc_compressed",
+                     "Whether the ARC keeps blocks compressed",
+                     self.compressed as u64);
+        kstats.counter("zfs_arc_mru_hits_total",
+                       "Hits on blocks read once before",
+                       stats.mru_hits);
+        kstats.counter("zfs_arc_mfu_hits_total",
+                       "Hits on blocks read more than once before",
+                       stats.mfu_hits);
+        kstats.counter("zfs_arc_evictions_total",
+                       "Blocks evicted from the ARC to make room",
+                       stats.evictions);
+        kstats.counter("zfs_arc_evicted_bytes_total",
+                       "Bytes of blocks evicted from the ARC to make room",
+                       stats.evicted_bytes);
+        kstats.counter("zfs_arc_mru_ghost_hits_total",
+                       "Misses on blocks recently evicted from the MRU list",
+                       stats.mru_ghost_hits);
+        kstats.counter("zfs_arc_mfu_ghost_hits_total",
+                       "Misses on blocks recently evicted from the MFU list",
+                       stats.mfu_ghost_hits);
+        let (mut mru_blocks, mut mfu_blocks) = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            mru_blocks += shard.mru.len() as u64;
+            mfu_blocks += shard.mfu.len() as u64;
+        }
+        kstats.gauge("zfs_arc_mru_blocks", "Blocks in the MRU list", mru_blocks);
+        kstats.gauge("zfs_arc_mfu_blocks", "Blocks in the MFU list", mfu_blocks);
+        kstats.gauge("zfs_arc_size_bytes", "Size of the blocks in the ARC", stats.size);
+        kstats.gauge("zfs_arc_c_bytes", "Size the ARC is allowed to grow to", stats.target_size);
+        kstats.gauge("zfs_arc_p_bytes",
+                     "Target size of the MRU list",
+                     stats.mru_target_size);
+        kstats.gauge("zfs_arc_overhead_bytes",
+                     "Bookkeeping set aside by a fixed ARC",
+                     stats.overhead);
+        if let Some(ref l2) = self.l2 {
+            l2.kstats(kstats);
+        }
+    }
+
+    fn is_cached(&self, dva: &DVAddr) -> bool {
+        self.shard(dva).is_cached(dva)
+    }
+
+    /// Whether the block at `dva` has to be checked against `checksum`. Blocks only need to be
+    /// verified once for as long as they stay in the cache.
+    pub fn needs_verify(&self, dva: &DVAddr, checksum: [u64; 4]) -> bool {
+        let mut shard = self.shard(dva);
+        if !self.paranoid && shard.verified.get(dva) == Some(&checksum) {
+            shard.verify_skipped += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Remember that the cached block at `dva` matches `checksum`
+    pub fn set_verified(&self, dva: &DVAddr, checksum: [u64; 4]) {
+        self.shard(dva).set_verified(dva, checksum);
+    }
+
+    /// Read the blocks of `dvas`, given as (DVA, size) pairs, into the cache in the background,
+    /// in disk order. The reads go through their own handle of the device, so the caller can
+    /// carry on with `reader` in the meantime.
+    pub fn prefetch(&self, reader: &zio::Reader, dvas: Vec<(DVAddr, u64)>) {
+        self.spawn_prefetch(reader, dvas, |reader, dva, &size| reader.read_dva(dva, size).ok());
+    }
+
+    /// Like `prefetch`, but for the blocks of `block_ptrs`, read as speculative zios: only
+    /// their first copy is tried, and only cached if it passes checksum verification. Holes,
+    /// embedded and gang blocks are left out, the cache doesn't keep them.
+    pub fn prefetch_blocks(&self, reader: &zio::Reader, block_ptrs: Vec<BlockPtr>) {
+        let blocks = block_ptrs.into_iter()
+                               .filter(|block_ptr| {
+                                   !block_ptr.is_hole() && !block_ptr.is_embedded() &&
+                                   !block_ptr.dvas[0].gang()
+                               })
+                               .map(|block_ptr| (block_ptr.dvas[0], block_ptr))
+                               .collect();
+        self.spawn_prefetch(reader, blocks, |reader, _, block_ptr| {
+            zio::Zio::read(reader, block_ptr)
+                .flag(zio::Flag::Raw)
+                .flag(zio::Flag::Speculative)
+                .execute()
+                .ok()
+                .map(|(data, _)| data)
+        });
+    }
+
+    /// Start a thread reading the blocks of `blocks` that aren't cached or on their way yet with
+    /// `read`, which gets whatever goes with each DVA. The first block `read` can't read ends it.
+    fn spawn_prefetch<T, F>(&self, reader: &zio::Reader, mut blocks: Vec<(DVAddr, T)>, read: F)
+        where T: Send + 'static,
+              F: Fn(&zio::Reader, &DVAddr, &T) -> Option<Vec<u8>> + Send + 'static
+    {
+        self.invalidate_writes(reader);
+        if self.ceiling.is_some() {
+            return;
+        }
+        let mut prefetches = self.prefetches.lock().unwrap();
+        blocks.retain(|&(ref dva, _)| {
+            !self.is_cached(dva) && !prefetches.iter().any(|prefetch| prefetch.dvas.contains(dva))
+        });
+        blocks.sort_by_key(|&(dva, _)| dva.sector());
+        blocks.dedup_by_key(|&mut (dva, _)| dva);
+        if blocks.is_empty() {
+            return;
+        }
+
+        let prefetch_reader = match reader.try_clone() {
+            Ok(prefetch_reader) => prefetch_reader,
+            // Prefetching is only a hint
+            Err(_) => return,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        prefetches.push(Prefetch {
+            rx: rx,
+            dvas: blocks.iter().map(|&(dva, _)| dva).collect(),
+            read_ahead: true,
+        });
+        thread::spawn(move || {
+            for (dva, with) in blocks {
+                let block = match read(&prefetch_reader, &dva, &with) {
+                    Some(block) => Arc::from(block),
+                    // Leave the rest to be read the normal way, which reports the error
+                    None => break,
+                };
+                if tx.send((dva, block)).is_err() {
+                    // The cache is gone
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Move the blocks that prefetch threads have read so far into the cache. If `wait_for` is
+    /// being prefetched, block until it arrives.
+    fn collect_prefetched(&self, wait_for: Option<&DVAddr>) {
+        let mut prefetches = self.prefetches.lock().unwrap();
+        let mut i = 0;
+        while i < prefetches.len() {
+            let mut done = false;
+            loop {
+                let wait = wait_for.map_or(false, |dva| prefetches[i].dvas.contains(dva));
+                let received = if wait {
+                    prefetches[i].rx.recv().map_err(|_| TryRecvError::Disconnected)
+                } else {
+                    prefetches[i].rx.try_recv()
+                };
+                match received {
+                    // Blocks invalidated on their way in aren't waited for, or cached
+                    Ok((dva, _)) if !prefetches[i].dvas.remove(&dva) => {}
+                    Ok((dva, block)) => {
+                        let read_ahead = prefetches[i].read_ahead;
+                        self.update(&dva, |shard| {
+                            if !shard.is_cached(&dva) {
+                                shard.verified.remove(&dva);
+                                let block = Cached {
+                                    data: ArcBuf::Heap(block),
+                                    decoded: false,
+                                };
+                                shard.cache_block(&dva, block, read_ahead);
+                                if read_ahead && shard.is_cached(&dva) {
+                                    shard.prefetched += 1;
+                                }
+                            }
+                        });
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        // Whatever didn't make it will be read the normal way
+                        done = true;
+                        break;
+                    }
+                }
+            }
+            if done || prefetches[i].dvas.is_empty() {
+                prefetches.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Drop a cached block, e.g. one that failed checksum verification, so the next read of it
+    /// goes to the disk again. It's dropped from the L2ARC too.
+    pub fn evict(&self, dva: &DVAddr) {
+        self.shard(dva).evict(dva);
+        if let Some(ref l2) = self.l2 {
+            l2.evict(dva);
+        }
+    }
+
+    /// Drop the block at `dva` from the cache and the L2ARC, e.g. once it's been overwritten. If
+    /// it's being prefetched, it's dropped when it arrives.
+    pub fn invalidate(&self, dva: &DVAddr) {
+        self.invalidate_where(&|cached| cached == dva);
+    }
+
+    /// Drop the blocks on top-level vdev `top` (on any of them for None) with any of their
+    /// sectors between bytes `start` and `end` of the vdev, counted like DVA offsets from the end
+    /// of the boot block
+    pub fn invalidate_range(&self, top: Option<u64>, start: u64, end: u64) {
+        self.invalidate_where(&|dva| {
+            let dva_start = dva.offset() << 9;
+            let dva_end = dva_start + (cmp::max(dva.asize(), 1) << 9);
+            top.map_or(true, |top| dva.vdev_id() == top) && dva_start < end && dva_end > start
+        });
+    }
+
+    /// Drop every block from the cache and the L2ARC. The statistics are kept.
+    pub fn clear(&self) {
+        for prefetch in self.prefetches.lock().unwrap().iter_mut() {
+            prefetch.dvas.clear();
+        }
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+        if let Some(ref l2) = self.l2 {
+            l2.evict_where(&|_| true);
+        }
+    }
+
+    /// Drop the blocks that the writes through `reader` since the last call have overwritten.
+    /// Reads through the cache do it first thing, so they don't return stale data.
+    pub fn invalidate_writes(&self, reader: &zio::Reader) {
+        for written in reader.take_writes() {
+            // Writes to the labels and the boot block don't reach any block
+            let end = written.end.saturating_sub(spa_config::VDEV_LABEL_START_SIZE);
+            if end > 0 {
+                let start = written.start.saturating_sub(spa_config::VDEV_LABEL_START_SIZE);
+                self.invalidate_range(written.top, start, end);
+            }
+        }
+    }
+
+    fn invalidate_where(&self, invalid: &Fn(&DVAddr) -> bool) {
+        {
+            let mut prefetches = self.prefetches.lock().unwrap();
+            for prefetch in prefetches.iter_mut() {
+                prefetch.dvas.retain(|dva| !invalid(dva));
+            }
+        }
+        for shard in &self.shards {
+            shard.lock().unwrap().evict_where(invalid);
+        }
+        if let Some(ref l2) = self.l2 {
+            l2.evict_where(invalid);
+        }
+    }
+
+    /// Read the `size` bytes of the block at `dva`, from the cache if it's there. The data is
+    /// shared with the cache, not copied out of it. A block that isn't cached is borrowed from
+    /// the mapping of its device if it lends it out (see `zio::Reader::mapped_dva`), and only
+    /// read otherwise.
+    pub fn read(&self, reader: &zio::Reader, dva: &DVAddr, size: u64) -> zfs::Result<ArcBuf> {
+        self.read_with(reader, dva, size, None)
+    }
+
+    /// Like `read`, but a block that isn't cached is read from a copy that passes `verify`
+    /// (see `zio::Reader::read_dva_verified`), and cached as verified against `checksum`
+    pub fn read_verified(&self,
+                         reader: &zio::Reader,
+                         dva: &DVAddr,
+                         size: u64,
+                         checksum: [u64; 4],
+                         verify: &Fn(&[u8]) -> bool)
+                         -> zfs::Result<ArcBuf> {
+        self.read_with(reader, dva, size, Some((checksum, verify)))
+    }
+
+    fn read_with(&self,
+                 reader: &zio::Reader,
+                 dva: &DVAddr,
+                 size: u64,
+                 verify: Option<([u64; 4], &Fn(&[u8]) -> bool)>)
+                 -> zfs::Result<ArcBuf> {
+        self.invalidate_writes(reader);
+        let cached = self.lookup(dva);
+        if let Some(Cached { ref data, decoded: false }) = cached {
+            return Ok(data.clone());
         }
-        if let Some(block) = self.mfu.map.get_mut(dva) {
-            // Block is cached
-            if block.0 > 1000 {
-                block.0 = 0;
+
+        // Block isn't cached, have to read it from the L2ARC or the disk. The shard isn't locked
+        // meanwhile.
+        let from_l2 = match self.read_l2(dva, verify.map(|(_, verify)| verify)) {
+            Some(Cached { data, decoded: false }) => Some(data),
+            _ => None,
+        };
+        let block = match (from_l2, verify) {
+            (Some(block), _) => block,
+            (None, verify) => try!(read_dva(reader, dva, size, verify.map(|(_, verify)| verify))),
+        };
+
+        // Or it's cached decompressed, and stays that way
+        if cached.is_none() {
+            self.update(dva, |shard| {
+                shard.cache_block(dva,
+                                  Cached {
+                                      data: block.clone(),
+                                      decoded: false,
+                                  },
+                                  false);
+                if let Some((checksum, _)) = verify {
+                    shard.set_verified(dva, checksum);
+                }
+            });
+        }
+        Ok(block)
+    }
+
+    /// Read the block at `dva` and decompress it with `codec`. Like `read_verified`, a block that
+    /// isn't cached is read from a copy that passes `verify`; one kept as it is on disk is checked
+    /// again on a hit if `needs_verify` says so, and read from disk again if it doesn't pass.
+    /// Unless the cache is compressed, blocks are kept decompressed.
+    pub fn read_block(&self,
+                      reader: &zio::Reader,
+                      dva: &DVAddr,
+                      size: u64,
+                      codec: Codec,
+                      checksum: [u64; 4],
+                      verify: &Fn(&[u8]) -> bool)
+                      -> zfs::Result<ArcBuf> {
+        self.invalidate_writes(reader);
+        if let Some(cached) = self.lookup(dva) {
+            if cached.decoded {
+                return Ok(cached.data);
+            }
+            if !self.needs_verify(dva, checksum) || verify(&cached.data) {
+                self.set_verified(dva, checksum);
+                return self.decode(dva, cached.data, codec);
+            }
+            // Don't keep serving the bad copy from the cache. Reading it again gets the copy of
+            // another mirror child if this one's bad.
+            self.evict(dva);
+        }
+
+        let block = match self.read_l2(dva, Some(verify)) {
+            Some(Cached { data, decoded: true }) => {
+                let block = Cached {
+                    data: data.clone(),
+                    decoded: true,
+                };
+                self.update(dva, |shard| shard.cache_block(dva, block, false));
+                return Ok(data);
+            }
+            Some(cached) => cached.data,
+            None => try!(read_dva(reader, dva, size, Some(verify))),
+        };
+        let decoded = try!(block.clone().decode(codec));
+        let compressed = self.compressed;
+        self.update(dva, |shard| {
+            if compressed || !codec.is_compressed() {
+                let block = Cached {
+                    data: block,
+                    decoded: false,
+                };
+                shard.cache_block(dva, block, false);
+                shard.set_verified(dva, checksum);
             } else {
-                block.0 += 1;
+                let block = Cached {
+                    data: decoded.clone(),
+                    decoded: true,
+                };
+                shard.cache_block(dva, block, false);
+            }
+        });
+        Ok(decoded)
+    }
+
+    /// Decompress a cached block kept as it is on disk. In a cache that isn't compressed, it's
+    /// kept decompressed from now on.
+    fn decode(&self, dva: &DVAddr, block: ArcBuf, codec: Codec) -> zfs::Result<ArcBuf> {
+        if !codec.is_compressed() {
+            return Ok(block);
+        }
+        let decoded = try!(block.decode(codec));
+        let compressed = self.compressed;
+        self.update(dva, |shard| {
+            shard.decompressions += 1;
+            if !compressed {
+                let block = Cached {
+                    data: decoded.clone(),
+                    decoded: true,
+                };
+                shard.replace(dva, block);
             }
+        });
+        Ok(decoded)
+    }
+
+    /// The cached block at `dva`, if there is one. A miss is counted as such, the caller is
+    /// expected to read the block from disk.
+    fn lookup(&self, dva: &DVAddr) -> Option<Cached> {
+        if !self.prefetches.lock().unwrap().is_empty() {
+            self.collect_prefetched(Some(dva));
+        }
+        self.shard(dva).lookup(dva)
+    }
+
+    /// Like `read`, but a block that isn't cached is read by one of `reader`'s workers instead
+    /// of the caller. It goes into the cache like a prefetched block, on the next read.
+    #[cfg(feature = "async")]
+    pub fn read_async(&self,
+                      reader: &AsyncReader,
+                      dva: &DVAddr,
+                      size: u64)
+                      -> IoFuture<zfs::Result<ArcBuf>> {
+        self.invalidate_writes(reader.reader());
+        let dva = *dva;
+        match self.lookup(&dva) {
+            Some(Cached { data, decoded: false }) => return IoFuture::ready(Ok(data)),
+            // Cached decompressed, the data as it is on disk is only on disk
+            Some(_) => {
+                return reader.spawn(move |reader| {
+                    Ok(ArcBuf::from(try!(reader.read_dva(&dva, size))))
+                })
+            }
+            None => {}
+        }
+        if let Some(Cached { data, decoded: false }) = self.read_l2(&dva, None) {
+            let block = Cached {
+                data: data.clone(),
+                decoded: false,
+            };
+            self.update(&dva, |shard| shard.cache_block(&dva, block, false));
+            return IoFuture::ready(Ok(data));
+        }
+
+        if let Some(block) = reader.reader().mapped_dva(&dva, size) {
+            let block = Cached {
+                data: ArcBuf::Mapped(block),
+                decoded: false,
+            };
+            self.update(&dva, |shard| shard.cache_block(&dva, block.clone(), false));
+            return IoFuture::ready(Ok(block.data));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut dvas = HashSet::new();
+        dvas.insert(dva);
+        self.prefetches.lock().unwrap().push(Prefetch {
+            rx: rx,
+            dvas: dvas,
+            read_ahead: false,
+        });
+        reader.spawn(move |reader| {
+            let block: Arc<[u8]> = Arc::from(try!(reader.read_dva(&dva, size)));
+            // The cache may be gone already, the block is still the reader's
+            let _ = tx.send((dva, block.clone()));
+            Ok(ArcBuf::Heap(block))
+        })
+    }
+}
+
+/// Read the `size` bytes of the block at `dva` from a copy that passes `verify`, if there's one,
+/// borrowing them from the mapping of its device if they're there (see
+/// `zio::Reader::mapped_dva`). A mapped copy that doesn't pass is read the normal way, so a
+/// mirror finds one that does.
+fn read_dva(reader: &zio::Reader,
+            dva: &DVAddr,
+            size: u64,
+            verify: Option<&Fn(&[u8]) -> bool>)
+            -> zfs::Result<ArcBuf> {
+    if let Some(block) = reader.mapped_dva(dva, size) {
+        if verify.map_or(true, |verify| verify(&block)) {
+            return Ok(ArcBuf::Mapped(block));
+        }
+    }
+    Ok(ArcBuf::from(match verify {
+        Some(verify) => try!(reader.read_dva_verified(dva, size, verify)),
+        None => try!(reader.read_dva(dva, size)),
+    }))
+}
+
+/// Size of shard `i` of `count` making up a cache of `size` bytes
+fn shard_size(size: usize, count: usize, i: usize) -> usize {
+    size / count + (i < size % count) as usize
+}
+
+#[test]
+fn test_prefetch() {
+    use std::fs::OpenOptions;
+    use super::test_util::TempFile;
+
+    let file = TempFile::new("zfs_test_arc_prefetch");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(file.path()).unwrap();
+    disk.set_len((0x2000 + 16) * 512).unwrap();
+    let mut reader = zio::Reader::new(disk);
+    let dvas: Vec<(DVAddr, u64)> = (0..4u64)
+                                       .map(|i| (DVAddr { vdev: 1, offset: i }, 512))
+                                       .collect();
+    for i in 0..4 {
+        reader.write_sectors(0x2000 + i, &[i as u8 + 1; 512]).unwrap();
+    }
+
+    let arc = ArCache::new();
+    arc.prefetch(&reader, dvas.clone());
+    for (i, &(ref dva, size)) in dvas.iter().enumerate() {
+        assert_eq!(arc.read(&mut reader, dva, size).unwrap().to_vec(), vec![i as u8 + 1; 512]);
+    }
+    let stats = arc.stats();
+    assert_eq!((stats.hits, stats.misses, stats.prefetched), (4, 0, 4));
+    // Cached blocks aren't read again
+    arc.prefetch(&reader, dvas);
+    assert!(arc.prefetches.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_prefetch_blocks() {
+    use super::checksum;
+    use super::from_bytes::FromBytes;
+
+    let mut reader = block_reader(4);
+    // One uncompressed sector, fletcher4. Block 2 went bad.
+    let block_ptr = |block: u64| {
+        let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+        block_ptr.dvas[0] = DVAddr { vdev: 1, offset: block };
+        block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+        block_ptr.checksum = checksum::compute(7, &[block as u8; 512], false).unwrap();
+        block_ptr
+    };
+    reader.write_sectors(0x2002, &[0xFF; 512]).unwrap();
+
+    let arc = ArCache::new();
+    let mut hole = block_ptr(3);
+    hole.dvas[0] = DVAddr { vdev: 0, offset: 0 };
+    arc.prefetch_blocks(&reader, vec![block_ptr(0), block_ptr(1), block_ptr(2), hole]);
+    for block in 0..2 {
+        let dva = DVAddr { vdev: 1, offset: block };
+        assert_eq!(arc.read(&mut reader, &dva, 512).unwrap().to_vec(), vec![block as u8; 512]);
+    }
+    let stats = arc.stats();
+    assert_eq!((stats.hits, stats.misses, stats.prefetched), (2, 0, 2));
+    // The block that failed verification is left to the read that needs it
+    assert!(!arc.is_cached(&DVAddr { vdev: 1, offset: 2 }));
+}
+
+#[test]
+fn test_verified_blocks() {
+    use std::fs::OpenOptions;
+    use super::test_util::TempFile;
+
+    let file = TempFile::new("zfs_test_arc_verified");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(file.path()).unwrap();
+    disk.set_len((0x2000 + 16) * 512).unwrap();
+    let mut reader = zio::Reader::new(disk);
+    let dva = DVAddr { vdev: 1, offset: 0 };
+    let checksum = [1, 2, 3, 4];
+
+    let mut arc = ArCache::new();
+    // Not cached yet
+    arc.set_verified(&dva, checksum);
+    assert!(arc.needs_verify(&dva, checksum));
+
+    arc.read(&mut reader, &dva, 512).unwrap();
+    assert!(arc.needs_verify(&dva, checksum));
+    arc.set_verified(&dva, checksum);
+    assert!(!arc.needs_verify(&dva, checksum));
+    assert!(arc.needs_verify(&dva, [1, 2, 3, 5]));
+
+    arc.paranoid = true;
+    assert!(arc.needs_verify(&dva, checksum));
+    arc.paranoid = false;
+
+    // Fetching the block from disk again throws the verification away
+    {
+        let mut shard = arc.shard(&dva);
+        shard.mru.clear();
+        shard.mfu.clear();
+    }
+    arc.read(&mut reader, &dva, 512).unwrap();
+    assert!(arc.needs_verify(&dva, checksum));
+    assert_eq!(arc.stats().verify_skipped, 1);
+}
+
+#[cfg(test)]
+fn block_reader(blocks: u64) -> zio::Reader {
+    use super::vdev_io::MemVdev;
+
+    let mut disk = vec![0; ((0x2000 + blocks) * 512) as usize];
+    for block in 0..blocks as usize {
+        let start = (0x2000 + block) * 512;
+        for b in &mut disk[start..start + 512] {
+            *b = block as u8;
+        }
+    }
+    zio::Reader::new(MemVdev::new(disk))
+}
 
-            return Ok(block.1.clone());
+#[test]
+fn test_arc_scan_resistance() {
+    let reader = block_reader(128);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let arc = ArCache::with_shards(8 * 512, 1);
+
+    // Blocks read over and over survive a scan of many more blocks, each read only once
+    for _ in 0..2 {
+        for block in 0..4 {
+            arc.read(&reader, &dva(block), 512).unwrap();
         }
+    }
+    for block in 4..128 {
+        assert_eq!(arc.read(&reader, &dva(block), 512).unwrap().to_vec(), vec![block as u8; 512]);
+    }
+    let misses = arc.stats().misses;
+    for block in 0..4 {
+        assert_eq!(arc.read(&reader, &dva(block), 512).unwrap().to_vec(), vec![block as u8; 512]);
+    }
+    let shard = arc.shards[0].lock().unwrap();
+    assert_eq!(shard.misses, misses);
+    assert_eq!(shard.mru_target, 0);
+    assert!(shard.mru.bytes + shard.mfu.bytes <= shard.size);
+    assert!(shard.mru.bytes + shard.mru_ghost.bytes <= shard.size);
+}
+
+#[test]
+fn test_arc_adaptation() {
+    let reader = block_reader(16);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let arc = ArCache::with_shards(4 * 512, 1);
+
+    // Two frequently used blocks, then enough new ones to evict the first of them to the ghosts
+    for &block in &[0, 1, 0, 1, 10, 11, 12] {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert!(arc.shards[0].lock().unwrap().mru_ghost.contains(&dva(10)));
+
+    // The block came back soon after being evicted: the MRU list should have been larger
+    arc.read(&reader, &dva(10), 512).unwrap();
+    {
+        let shard = arc.shards[0].lock().unwrap();
+        assert_eq!((shard.mru_ghost_hits, shard.mru_target), (1, 512));
+        assert!(shard.mfu.contains(&dva(10)) && shard.mru_ghost.contains(&dva(11)));
+    }
+
+    // With the MRU list at its target, a new block evicts from the MFU list, and when the block
+    // evicted comes back, the MFU list should have been larger
+    arc.read(&reader, &dva(13), 512).unwrap();
+    assert!(arc.shards[0].lock().unwrap().mfu_ghost.contains(&dva(0)));
+    arc.read(&reader, &dva(0), 512).unwrap();
+    let shard = arc.shards[0].lock().unwrap();
+    assert_eq!((shard.mfu_ghost_hits, shard.mru_target), (1, 0));
+    assert!(shard.mfu.contains(&dva(0)));
+    assert_eq!(shard.hits, 2);
+    assert!(shard.mru.bytes + shard.mfu.bytes <= shard.size);
+}
+
+#[test]
+fn test_arc_resize() {
+    let reader = block_reader(16);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let arc = ArCache::with_shards(16 * 512, 1);
+
+    for block in 0..16 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert_eq!(arc.stats().mru_size, 16 * 512);
+
+    // Shrinking evicts the least recently used blocks down to the new size at once
+    arc.resize(6 * 512 + 100);
+    assert_eq!(arc.size(), 6 * 512 + 100);
+    {
+        let shard = arc.shards[0].lock().unwrap();
+        assert_eq!(shard.mru.bytes + shard.mfu.bytes, 6 * 512);
+        assert!(shard.mru.contains(&dva(15)) && !shard.mru.contains(&dva(9)));
+        assert!(shard.mru.bytes + shard.mru_ghost.bytes <= shard.size);
+    }
+
+    // Growing leaves room for more
+    arc.resize(32 * 512);
+    for block in 0..16 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert_eq!(arc.stats().size, 16 * 512);
+
+    // Split in shards, the cache is still as large as asked for
+    let arc = ArCache::with_shards(100, 16);
+    assert_eq!(arc.size(), 100);
+    arc.resize(1000);
+    assert_eq!(arc.size(), 1000);
+}
+
+#[test]
+fn test_arc_stats() {
+    let reader = block_reader(8);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let arc = ArCache::with_shards(4 * 512, 1);
+    assert_eq!(arc.stats(), ArcStats { target_size: 2048, ..Default::default() });
+
+    for &block in &[0, 0, 0, 1, 2, 3, 4, 5] {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    let stats = arc.stats();
+    assert_eq!((stats.hits, stats.misses, stats.mru_hits, stats.mfu_hits), (2, 6, 1, 1));
+    assert_eq!((stats.evictions, stats.evicted_bytes), (2, 1024));
+    assert_eq!((stats.size, stats.mru_size, stats.mfu_size), (2048, 1536, 512));
+    assert_eq!(stats.mru_ghost_size, 512);
+    assert_eq!(stats.hit_ratio(), 0.25);
+
+    // A snapshot doesn't move with the cache
+    arc.read(&reader, &dva(2), 512).unwrap();
+    assert_eq!(arc.stats().mru_ghost_hits, 1);
+    assert_eq!(stats.mru_ghost_hits, 0);
+}
+
+#[test]
+fn test_arc_hasher() {
+    use std::hash::BuildHasherDefault;
+    use super::djb2::Djb2;
+
+    let reader = block_reader(64);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let blocks_of = |arc: &ArCache<BuildHasherDefault<Djb2>>| {
+        arc.shards.iter().map(|shard| shard.lock().unwrap().mru.len()).collect::<Vec<usize>>()
+    };
+
+    // Djb2 puts a DVA in the same shard of any cache, and spreads the DVAs over all of them
+    let arc = ArCache::with_hasher(64 * 512, 4, BuildHasherDefault::<Djb2>::default());
+    let other = ArCache::with_hasher(64 * 512, 4, BuildHasherDefault::<Djb2>::default());
+    for block in 0..64 {
+        assert_eq!(arc.read(&reader, &dva(block), 512).unwrap().to_vec(), vec![block as u8; 512]);
+        other.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert_eq!(blocks_of(&arc), blocks_of(&other));
+    assert!(blocks_of(&arc).iter().all(|&blocks| blocks > 0));
+    arc.read(&reader, &dva(7), 512).unwrap();
+    assert_eq!(arc.stats().hits, 1);
+
+    // The default hasher is keyed, and still finds what it's cached. However the blocks are
+    // spread, each shard has room for all of them.
+    let arc = ArCache::with_shards(4 * 64 * 512, 4);
+    for block in 0..64 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    arc.read(&reader, &dva(7), 512).unwrap();
+    assert_eq!((arc.stats().hits, arc.stats().misses), (1, 64));
+}
+
+#[test]
+fn test_arc_threads() {
+    use std::hash::BuildHasherDefault;
+    use std::sync::Arc;
+    use super::djb2::Djb2;
+
+    // Djb2 spreads the blocks evenly over the shards, so they all fit
+    let reader = Arc::new(block_reader(64));
+    let arc = Arc::new(ArCache::with_hasher(64 * 512,
+                                            ARC_DEFAULT_SHARDS,
+                                            BuildHasherDefault::<Djb2>::default()));
+
+    // Threads reading the same blocks at once all get them, and each is cached only once
+    let threads: Vec<_> = (0..8)
+                              .map(|_| {
+                                  let (reader, arc) = (reader.clone(), arc.clone());
+                                  thread::spawn(move || {
+                                      for _ in 0..4 {
+                                          for block in 0..64 {
+                                              let dva = DVAddr { vdev: 1, offset: block };
+                                              let data = arc.read(&reader, &dva, 512).unwrap();
+                                              assert_eq!(data.to_vec(), vec![block as u8; 512]);
+                                          }
+                                      }
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let stats = arc.stats();
+    assert_eq!(stats.hits + stats.misses, 8 * 4 * 64);
+    assert!(stats.misses >= 64 && stats.misses <= 8 * 64);
+    assert_eq!(stats.size, 64 * 512);
+    assert_eq!(stats.evictions, 0);
+
+    // Hits share the cached data rather than copy it
+    let dva = DVAddr { vdev: 1, offset: 0 };
+    let (first, second) = (arc.read(&reader, &dva, 512).unwrap(),
+                           arc.read(&reader, &dva, 512).unwrap());
+    assert_eq!(first.as_ptr(), second.as_ptr());
+}
 
-        // Block isn't cached, have to read it from disk
-        let block = reader.read(dva.sector() as usize, dva.asize() as usize);
+#[test]
+fn test_compressed_arc() {
+    use super::vdev_io::MemVdev;
+
+    // 1K of 0x42 lzjb compressed into a sector: a literal, then copies of it, 66 bytes at a time
+    let mut compressed = vec![0xFE, 0x42];
+    for copies in &[7, 8, 1] {
+        if compressed.len() > 2 {
+            compressed.push(0xFF);
+        }
+        for _ in 0..*copies {
+            compressed.extend_from_slice(&[0xFC, 0x01]);
+        }
+    }
+    let mut disk = vec![0; (0x2000 + 5) * 512];
+    for block in 0..5 {
+        let start = (0x2000 + block) * 512;
+        disk[start..start + compressed.len()].copy_from_slice(&compressed);
+    }
+    let reader = zio::Reader::new(MemVdev::new(disk));
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let codec = Codec {
+        compression: 3,
+        lsize: 1024,
+    };
+    let verify = |data: &[u8]| data[0] == 0xFE;
+
+    // Blocks are kept decompressed by default, hits share them
+    let arc = ArCache::with_shards(1 << 20, 1);
+    let data = arc.read_block(&reader, &dva(0), 512, codec, [1; 4], &verify).unwrap();
+    assert_eq!(data.to_vec(), vec![0x42; 1024]);
+    let hit = arc.read_block(&reader, &dva(0), 512, codec, [1; 4], &verify).unwrap();
+    assert_eq!(hit.as_ptr(), data.as_ptr());
+    assert_eq!((arc.stats().size, arc.stats().decompressions), (1024, 0));
+    // Blocks read ahead are kept as they are on disk until they're read
+    arc.prefetch(&reader, vec![(dva(1), 512)]);
+    assert_eq!(arc.read_block(&reader, &dva(1), 512, codec, [1; 4], &verify).unwrap().to_vec(),
+               vec![0x42; 1024]);
+    assert_eq!((arc.stats().size, arc.stats().decompressions), (2048, 1));
+
+    // A compressed cache fits twice as many of them
+    let arc = ArCache::with_shards(1 << 20, 1).with_compression(true);
+    for _ in 0..2 {
+        for block in 0..4 {
+            let data = arc.read_block(&reader, &dva(block), 512, codec, [1; 4], &verify).unwrap();
+            assert_eq!(data.to_vec(), vec![0x42; 1024]);
+        }
+    }
+    let stats = arc.stats();
+    assert_eq!((stats.hits, stats.size, stats.decompressions), (4, 4 * 512, 4));
+    // A copy that doesn't decompress isn't kept
+    let bad = Codec { compression: 15, ..codec };
+    assert_eq!(arc.read_block(&reader, &dva(4), 512, bad, [1; 4], &verify).err(),
+               Some(zfs::Error::Decompress));
+    assert_eq!(arc.stats().size, 4 * 512);
+}
+
+#[test]
+fn test_l2arc_spill() {
+    use std::cell::Cell;
+    use std::sync::atomic::Ordering;
+    use super::vdev_io::{MemVdev, Vdev};
+
+    let reader = block_reader(16);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let l2 = L2Arc::new(vec![Box::new(MemVdev::new(vec![0; 64 * 512])) as Box<Vdev>]).unwrap();
+    let arc = ArCache::with_shards(4 * 512, 1).with_l2arc(l2);
+
+    for block in 0..16 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert_eq!(arc.l2_stats().unwrap().writes, 12);
+
+    // The blocks evicted come back from the cache device, not the pool
+    let reads = reader.reads.load(Ordering::Relaxed);
+    for block in 0..8 {
+        assert_eq!(arc.read(&reader, &dva(block), 512).unwrap().to_vec(), vec![block as u8; 512]);
+    }
+    assert_eq!(reader.reads.load(Ordering::Relaxed), reads);
+    let stats = arc.l2_stats().unwrap();
+    assert_eq!(stats.hits, 8);
+    // Blocks 0 to 3 were evicted again, they're still on the device from the first time
+    assert_eq!(stats.writes, 16);
+
+    // A block that fails verification is read from the pool instead, and dropped
+    let calls = Cell::new(0);
+    let verify = |_: &[u8]| {
+        calls.set(calls.get() + 1);
+        calls.get() > 1
+    };
+    arc.read_verified(&reader, &dva(8), 512, [1; 4], &verify).unwrap();
+    assert_eq!(reader.reads.load(Ordering::Relaxed), reads + 1);
+    assert_eq!(arc.l2_stats().unwrap().size, 15 * 512);
+}
+
+#[test]
+fn test_arc_invalidation() {
+    let reader = block_reader(16);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let arc = ArCache::with_shards(1 << 20, 1);
+    for block in 0..16 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+
+    // Blocks written through the reader are read again
+    reader.write_dva(&dva(3), &[0x33; 512]).unwrap();
+    assert_eq!(arc.read(&reader, &dva(3), 512).unwrap().to_vec(), vec![0x33; 512]);
+    assert_eq!(arc.read(&reader, &dva(4), 512).unwrap().to_vec(), vec![4; 512]);
+    // Writes to the labels don't reach any block
+    reader.write_sectors(0, &[0; 512]).unwrap();
+    let misses = arc.stats().misses;
+    arc.read(&reader, &dva(0), 512).unwrap();
+    assert_eq!(arc.stats().misses, misses);
+
+    // Blocks 4 to 7 have bytes between 0x9FF and 0x1000
+    arc.invalidate_range(Some(0), 0x9FF, 0x1000);
+    arc.invalidate(&dva(10));
+    let misses = arc.stats().misses;
+    for block in 0..16 {
+        arc.read(&reader, &dva(block), 512).unwrap();
+    }
+    assert_eq!(arc.stats().misses, misses + 5);
+    arc.invalidate_range(Some(1), 0, 1 << 20);
+    assert_eq!(arc.stats().size, 16 * 512);
+
+    arc.clear();
+    assert_eq!(arc.stats().size, 0);
+    assert_eq!(arc.stats().mru_ghost_size, 0);
+
+    // Past the most writes the reader keeps track of, every block has to go
+    for _ in 0..zio::WRITE_LOG_MAX + 1 {
+        reader.write_sectors(0, &[0; 512]).unwrap();
+    }
+    let writes = reader.take_writes();
+    assert_eq!(writes.len(), zio::WRITE_LOG_MAX + 1);
+    assert_eq!(writes[zio::WRITE_LOG_MAX].end, u64::max_value());
+    assert!(reader.take_writes().is_empty());
+}
+
+#[test]
+fn test_fixed_arc() {
+    let reader = block_reader(128);
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    // Room for 6 sectors in each of the 16 shards, but only for the bookkeeping of 2 blocks
+    let arc = ArCache::fixed(16 * 4096, 32);
+    assert_eq!(arc.size(), 16 * 4096);
+    assert_eq!(arc.stats().overhead, 32 * ARC_ENTRY_OVERHEAD as u64);
+
+    for _ in 0..2 {
+        for block in 0..128 {
+            assert_eq!(arc.read(&reader, &dva(block), 512).unwrap().to_vec(),
+                       vec![block as u8; 512]);
+        }
+    }
+    for shard in &arc.shards {
+        let shard = shard.lock().unwrap();
+        assert!(shard.mru.len() + shard.mfu.len() <= 2);
+        assert!(shard.mru_ghost.len() <= 2 && shard.mfu_ghost.len() <= 2);
+        assert!(shard.verified.len() <= 2 * 2 + 64);
+    }
+    assert!(arc.stats().size <= 32 * 512);
+
+    // It doesn't grow, and doesn't read ahead
+    arc.resize(1 << 30);
+    assert_eq!(arc.size(), 16 * 4096);
+    arc.resize(16 * 2048);
+    assert_eq!(arc.size(), 16 * 2048);
+    arc.prefetch(&reader, (0..128).map(|block| (dva(block), 512)).collect());
+    assert_eq!(arc.stats().prefetched, 0);
+}
+
+#[test]
+fn test_mapped_arc() {
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use super::test_util::TempFile;
+    use super::vdev_io::{MemVdev, MmapVdev, Vdev};
+
+    let file = TempFile::new("zfs_test_arc_mapped");
+    let mut disk = vec![0; (0x2000 + 4) * 512];
+    for block in 0..4 {
+        let start = (0x2000 + block) * 512;
+        for b in &mut disk[start..start + 512] {
+            *b = block as u8 + 1;
+        }
+    }
+    File::create(file.path()).unwrap().write_all(&disk).unwrap();
+    let reader = zio::Reader::new(MmapVdev::open(file.path(), false).unwrap());
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+    let l2 = L2Arc::new(vec![Box::new(MemVdev::new(vec![0; 64 * 512])) as Box<Vdev>]).unwrap();
+    let arc = ArCache::with_shards(2 * 512, 1).with_l2arc(l2);
+
+    // The blocks are borrowed from the mapping rather than read, and aren't spilled to the L2ARC
+    // when they're evicted: they're in the page cache already
+    for block in 0..4 {
+        match arc.read(&reader, &dva(block), 512).unwrap() {
+            ArcBuf::Mapped(data) => assert_eq!(&*data, &[block as u8 + 1; 512][..]),
+            ArcBuf::Heap(_) => panic!("block {} was copied", block),
+        }
+    }
+    assert_eq!(arc.read(&reader, &dva(3), 512).unwrap().to_vec(), vec![4; 512]);
+    assert_eq!(arc.stats().hits, 1);
+    assert_eq!(reader.reads.load(Ordering::Relaxed), 0);
+    assert_eq!(arc.l2_stats().unwrap().writes, 0);
 
-        // Blocks start in MRU cache
-        self.mru.cache_block(dva, block)
+    // A mapped copy that fails verification is read the normal way instead
+    let calls = Cell::new(0);
+    let verify = |_: &[u8]| {
+        calls.set(calls.get() + 1);
+        calls.get() > 1
+    };
+    match arc.read_verified(&reader, &dva(0), 512, [1; 4], &verify).unwrap() {
+        ArcBuf::Heap(data) => assert_eq!(&data[..], &[1; 512][..]),
+        ArcBuf::Mapped(_) => panic!("block that failed verification was borrowed"),
     }
+    assert_eq!(reader.reads.load(Ordering::Relaxed), 1);
 }
@@ -1,3 +1,6 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
 use std::rc::Rc;
 
 pub struct Node<T> {
@@ -5,7 +8,9 @@ pub struct Node<T> {
     /// ID for left node
     left: Option<usize>,
     /// ID for right node
-    right: Option<usize>, 
+    right: Option<usize>,
+    /// Height of the subtree the node is the root of, 0 for a leaf
+    height: i64,
 }
 
 impl<T> Node<T> {
@@ -80,10 +85,14 @@ impl NodeId {
     }
 }
 
+/// An AVL tree of values ordered by a key computed from them (the key of `avl_create`'s compare
+/// function). Keys are unique. The nodes live in a vector and refer to each other by index, so
+/// removing a value leaves a slot for the next insertion to reuse.
 pub struct Tree<T, K> {
     root: Option<usize>, // Index of the root node
     nodes: Vec<Slot<T>>,
     free_list: Vec<usize>,
+    len: usize,
     key: Rc<Fn(&T) -> K>,
 }
 
@@ -93,15 +102,42 @@ impl<T, K: PartialOrd> Tree<T, K> {
             root: None,
             nodes: Vec::new(),
             free_list: Vec::new(),
+            len: 0,
             key: key,
         }
     }
 
+    /// Number of values in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     /// Inserts a value into the tree, keeping it balanced. Lesser values will be stored on
-    /// the left, while greater values will be stored on the right. No duplicates are allowed.
-    pub fn insert(&mut self, value: T) {
+    /// the left, while greater values will be stored on the right. No duplicates are allowed:
+    /// returns false, and drops `value`, if there's a value with the same key already.
+    pub fn insert(&mut self, value: T) -> bool {
         let root = self.root;
-        self.root = Some(self._insert(value, root));
+        let mut inserted = false;
+        self.root = Some(self._insert(value, root, &mut inserted));
+        if inserted {
+            self.len += 1;
+        }
+        inserted
+    }
+
+    /// Removes the value with key `key` from the tree, keeping it balanced, and returns it
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        let root = self.root;
+        let mut removed = None;
+        self.root = self._remove(&key, root, &mut removed);
+        removed.map(|index| {
+            self.len -= 1;
+            self.free_node(index).value
+        })
     }
 
     pub fn in_order<F: Fn(&Node<T>)>(&self, f: F) {
@@ -110,44 +146,193 @@ impl<T, K: PartialOrd> Tree<T, K> {
         }
     }
 
+    /// The values in key order
+    pub fn iter<'a>(&'a self) -> Iter<'a, T, K> {
+        let mut iter = Iter {
+            tree: self,
+            stack: Vec::new(),
+            end: None,
+        };
+        iter.push_left(self.root);
+        iter
+    }
+
+    /// The values with keys from `start` up to but not including `end`, in key order
+    pub fn range<'a>(&'a self, start: K, end: K) -> Iter<'a, T, K> {
+        let mut stack = Vec::new();
+        let mut node = self.root;
+        while let Some(n) = node {
+            if self.key_of(n) < start {
+                node = self.node(n).right;
+            } else {
+                stack.push(n);
+                node = self.node(n).left;
+            }
+        }
+        Iter {
+            tree: self,
+            stack: stack,
+            end: Some(end),
+        }
+    }
+
     /// Good ol' binary search. Returns immutable reference
     pub fn find(&self, key: K) -> Option<&T> {
-        let root = self.root;
-        self._find(key, root)
+        self.find_index(&key).map(|index| &self.node(index).value)
     }
 
-    /// Good ol' binary search. Returns a mutable reference
+    /// Good ol' binary search. Returns a mutable reference, through which the value's key must
+    /// not change.
     pub fn find_mut(&mut self, key: K) -> Option<&mut T> {
-        let root = self.root;
-        self._find_mut(key, root)
+        match self.find_index(&key) {
+            Some(index) => Some(&mut self.node_mut(index).value),
+            None => None,
+        }
+    }
+
+    /// The value with the greatest key up to `key` (`avl_nearest` with `AVL_BEFORE`, where
+    /// `avl_find` missed)
+    pub fn nearest_before(&self, key: K) -> Option<&T> {
+        let mut node = self.root;
+        let mut nearest = None;
+        while let Some(n) = node {
+            let node_key = self.key_of(n);
+            if node_key > key {
+                node = self.node(n).left;
+            } else if node_key < key {
+                nearest = Some(n);
+                node = self.node(n).right;
+            } else {
+                return Some(&self.node(n).value);
+            }
+        }
+        nearest.map(|n| &self.node(n).value)
+    }
+
+    /// The value with the least key from `key` on (`avl_nearest` with `AVL_AFTER`)
+    pub fn nearest_after(&self, key: K) -> Option<&T> {
+        let mut node = self.root;
+        let mut nearest = None;
+        while let Some(n) = node {
+            let node_key = self.key_of(n);
+            if node_key < key {
+                node = self.node(n).right;
+            } else if node_key > key {
+                nearest = Some(n);
+                node = self.node(n).left;
+            } else {
+                return Some(&self.node(n).value);
+            }
+        }
+        nearest.map(|n| &self.node(n).value)
+    }
+
+    /// The value with the least key
+    pub fn first(&self) -> Option<&T> {
+        let mut node = match self.root {
+            Some(root) => root,
+            None => return None,
+        };
+        while let Some(left) = self.node(node).left {
+            node = left;
+        }
+        Some(&self.node(node).value)
+    }
+
+    /// The value with the greatest key
+    pub fn last(&self) -> Option<&T> {
+        let mut node = match self.root {
+            Some(root) => root,
+            None => return None,
+        };
+        while let Some(right) = self.node(node).right {
+            node = right;
+        }
+        Some(&self.node(node).value)
     }
 
     // Implementation of insert
-    fn _insert(&mut self, value: T, node: Option<usize>) -> usize {
+    fn _insert(&mut self, value: T, node: Option<usize>, inserted: &mut bool) -> usize {
         let node = match node {
             Some(node) => {
                 // Node exists, check which way to branch.
-                if (self.key)(&value) == (self.key)(&self.node(node).value) {
-                    return node;
-                } else if (self.key)(&value) < (self.key)(&self.node(node).value) {
+                let key = (self.key)(&value);
+                let node_key = self.key_of(node);
+                if key < node_key {
                     let l = self.node(node).left;
-                    self.node_mut(node).left = Some(self._insert(value, l));
-                } else if (self.key)(&value) > (self.key)(&self.node(node).value) {
+                    let l = self._insert(value, l, inserted);
+                    self.node_mut(node).left = Some(l);
+                } else if key > node_key {
                     let r = self.node(node).right;
-                    self.node_mut(node).right = Some(self._insert(value, r));
+                    let r = self._insert(value, r, inserted);
+                    self.node_mut(node).right = Some(r);
+                } else {
+                    return node;
                 }
 
                 node
             }
             None => {
                 // The node doesn't exist, create it here.
-                self.allocate_node(value)
+                *inserted = true;
+                return self.allocate_node(value);
             }
         };
 
         self.rebalance(node)
     }
 
+    // Implementation of remove: returns the node to replace `node` with
+    fn _remove(&mut self,
+               key: &K,
+               node: Option<usize>,
+               removed: &mut Option<usize>)
+               -> Option<usize> {
+        let node = match node {
+            Some(node) => node,
+            None => return None,
+        };
+        let node_key = self.key_of(node);
+        if *key < node_key {
+            let l = self.node(node).left;
+            let l = self._remove(key, l, removed);
+            self.node_mut(node).left = l;
+        } else if *key > node_key {
+            let r = self.node(node).right;
+            let r = self._remove(key, r, removed);
+            self.node_mut(node).right = r;
+        } else {
+            // Found it: a child takes its place, or the least node of its right subtree if it
+            // has two
+            *removed = Some(node);
+            return match (self.node(node).left, self.node(node).right) {
+                (None, None) => None,
+                (Some(child), None) | (None, Some(child)) => Some(child),
+                (Some(l), Some(r)) => {
+                    let (r, successor) = self.remove_least(r);
+                    self.node_mut(successor).left = Some(l);
+                    self.node_mut(successor).right = r;
+                    Some(self.rebalance(successor))
+                }
+            };
+        }
+
+        Some(self.rebalance(node))
+    }
+
+    /// Takes the least node out of the subtree at `node`. Returns the node to replace `node`
+    /// with, and the least node.
+    fn remove_least(&mut self, node: usize) -> (Option<usize>, usize) {
+        match self.node(node).left {
+            Some(l) => {
+                let (l, least) = self.remove_least(l);
+                self.node_mut(node).left = l;
+                (Some(self.rebalance(node)), least)
+            }
+            None => (self.node(node).right, node),
+        }
+    }
+
     pub fn _in_order<F: Fn(&Node<T>)>(&self, f: &F, node: usize) {
         if let Some(l) = self.node(node).left {
             self._in_order(f, l);
@@ -158,37 +343,24 @@ impl<T, K: PartialOrd> Tree<T, K> {
         }
     }
 
-    pub fn _find(&self, key: K, node: Option<usize>) -> Option<&T> {
-        node.and_then(|n| {
-            if (self.key)(&self.node(n).value) < key {
-                let left = self.node(n).left;
-                self._find(key, left)
-            } else if (self.key)(&self.node(n).value) > key {
-                let right = self.node(n).right;
-                self._find(key, right)
+    fn find_index(&self, key: &K) -> Option<usize> {
+        let mut node = self.root;
+        while let Some(n) = node {
+            let node_key = self.key_of(n);
+            if *key < node_key {
+                node = self.node(n).left;
+            } else if *key > node_key {
+                node = self.node(n).right;
             } else {
                 // Found it!
-                Some(&self.node(n).value)
+                return Some(n);
             }
-        })
+        }
+        None
     }
 
-    pub fn _find_mut(&mut self, key: K, node: Option<usize>) -> Option<&mut T> {
-        match node {
-            Some(n) => {
-                if (self.key)(&self.node(n).value) < key {
-                    let left = self.node(n).left;
-                    self._find_mut(key, left)
-                } else if (self.key)(&self.node(n).value) > key {
-                    let right = self.node(n).right;
-                    self._find_mut(key, right)
-                } else {
-                    // Found it!
-                    Some(&mut self.node_mut(n).value)
-                }
-            }
-            None => None,
-        }
+    fn key_of(&self, node: usize) -> K {
+        (self.key)(&self.node(node).value)
     }
 
     /// Performs a left rotation on a tree/subtree.
@@ -202,6 +374,8 @@ impl<T, K: PartialOrd> Tree<T, K> {
         let ret = r;
         self.node_mut(node).right = rl;
         self.node_mut(ret).left = Some(node);
+        self.update_height(node);
+        self.update_height(ret);
 
         ret
     }
@@ -217,78 +391,71 @@ impl<T, K: PartialOrd> Tree<T, K> {
         let ret = l;
         self.node_mut(node).left = lr;
         self.node_mut(ret).right = Some(node);
+        self.update_height(node);
+        self.update_height(ret);
 
         ret
     }
 
-    /// Performs a left-right double rotation on a tree/subtree.
-    fn rotate_leftright(&mut self, node: usize) -> usize {
-        let l = self.node(node).left.unwrap();
-        let new_l = self.rotate_left(l); // Left node needs to exist
-        self.node_mut(node).left = Some(new_l);
-        self.rotate_right(node)
-    }
-
-    /// Performs a right-left double rotation on a tree/subtree.
-    fn rotate_rightleft(&mut self, node: usize) -> usize {
-        let r = self.node(node).right.unwrap();
-        let new_r = self.rotate_right(r); // Right node needs to exist
-        self.node_mut(node).right = Some(new_r);
-        self.rotate_left(node)
-    }
-
     /// Rebalances the provided node and returns the node to replace it with if rotations
-    /// occur
+    /// occur. The subtrees below it have to be balanced already.
     fn rebalance(&mut self, node: usize) -> usize {
-        let balance = self.height(self.node(node).left) - self.height(self.node(node).right);
-        if balance == 2 {
+        self.update_height(node);
+        let balance = self.balance(node);
+        if balance > 1 {
             // left
-            let lbalance = self.height(self.node(self.node(node).left.unwrap()).left) -
-                           self.height(self.node(self.node(node).left.unwrap()).right);
-            if lbalance == 0 || lbalance == 1 {
-                // left left - need to rotate right
-                return self.rotate_right(node);
-            } else if lbalance == -1 {
+            let l = self.node(node).left.unwrap();
+            if self.balance(l) < 0 {
                 // left right
-                return self.rotate_leftright(node); // function name is just a coincidence
+                let l = self.rotate_left(l);
+                self.node_mut(node).left = Some(l);
             }
-        } else if balance == -2 {
+            return self.rotate_right(node);
+        } else if balance < -1 {
             // right
-            let rbalance = self.height(self.node(self.node(node).right.unwrap()).left) -
-                           self.height(self.node(self.node(node).right.unwrap()).right);
-            if rbalance == 1 {
+            let r = self.node(node).right.unwrap();
+            if self.balance(r) > 0 {
                 // right left
-                return self.rotate_rightleft(node); // function name is just a coincidence
-            } else if rbalance == 0 || rbalance == -1 {
-                // right right - need to rotate left
-                return self.rotate_left(node);
+                let r = self.rotate_right(r);
+                self.node_mut(node).right = Some(r);
             }
+            return self.rotate_left(node);
         }
 
         node
     }
 
+    /// How much higher the left subtree of `node` is than the right one
+    fn balance(&self, node: usize) -> i64 {
+        self.height(self.node(node).left) - self.height(self.node(node).right)
+    }
+
     /// height gets the height of a tree or subtree
     fn height(&self, node: Option<usize>) -> i64 {
         match node {
-            Some(node) => {
-                let left_height = self.height(self.node(node).left);
-                let right_height = self.height(self.node(node).right);
-
-                if left_height > right_height {
-                    left_height + 1
-                } else {
-                    right_height + 1
-                }
-            }
+            Some(node) => self.node(node).height,
             None => -1,
         }
     }
 
+    fn update_height(&mut self, node: usize) {
+        let height = 1 + cmp::max(self.height(self.node(node).left),
+                                  self.height(self.node(node).right));
+        self.node_mut(node).height = height;
+    }
+
     fn allocate_node(&mut self, value: T) -> usize {
+        let node = Node {
+            value: value,
+            left: None,
+            right: None,
+            height: 0,
+        };
         match self.free_list.pop() {
             Some(index) => {
-                self.nodes[index].time_stamp += 1;
+                let ref mut slot = self.nodes[index];
+                slot.time_stamp += 1;
+                slot.node = Some(node);
                 index
             }
             None => {
@@ -296,11 +463,7 @@ impl<T, K: PartialOrd> Tree<T, K> {
                 let index = self.nodes.len();
                 self.nodes.push(Slot {
                     time_stamp: 0,
-                    node: Some(Node {
-                        value: value,
-                        left: None,
-                        right: None,
-                    }),
+                    node: Some(node),
                 });
                 index
             }
@@ -324,8 +487,113 @@ impl<T, K: PartialOrd> Tree<T, K> {
     }
 }
 
+/// In-order iteration over (a range of) a tree, see `Tree::iter` and `Tree::range`
+pub struct Iter<'a, T: 'a, K: 'a> {
+    tree: &'a Tree<T, K>,
+    /// The nodes still to visit, whose right subtrees haven't been looked at, the next one last
+    stack: Vec<usize>,
+    /// Where a range ends
+    end: Option<K>,
+}
+
+impl<'a, T, K: PartialOrd> Iter<'a, T, K> {
+    fn push_left(&mut self, mut node: Option<usize>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = self.tree.node(n).left;
+        }
+    }
+}
+
+impl<'a, T, K: PartialOrd> Iterator for Iter<'a, T, K> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = match self.stack.pop() {
+            Some(node) => node,
+            None => return None,
+        };
+        let tree = self.tree;
+        if let Some(ref end) = self.end {
+            if tree.key_of(node) >= *end {
+                self.stack.clear();
+                return None;
+            }
+        }
+        self.push_left(tree.node(node).right);
+        Some(&tree.node(node).value)
+    }
+}
 
 struct Slot<T> {
     time_stamp: u64,
     node: Option<Node<T>>,
 }
+
+#[test]
+fn test_avl() {
+    fn check<T, K: PartialOrd>(tree: &Tree<T, K>, node: Option<usize>) -> i64 {
+        match node {
+            Some(node) => {
+                let left = check(tree, tree.node(node).left);
+                let right = check(tree, tree.node(node).right);
+                assert!((left - right).abs() <= 1);
+                assert_eq!(tree.node(node).height, 1 + cmp::max(left, right));
+                1 + cmp::max(left, right)
+            }
+            None => -1,
+        }
+    }
+
+    let mut tree: Tree<(u64, u64), u64> = Tree::new(Rc::new(|x: &(u64, u64)| x.0));
+    assert!(tree.is_empty());
+    assert!(tree.first().is_none());
+    let keys: Vec<u64> = (0..1000u64).map(|i| (i * 7919) % 1000 * 2).collect();
+    for &key in &keys {
+        assert!(tree.insert((key, key + 1)));
+    }
+    assert!(!tree.insert((10, 0)));
+    assert_eq!(tree.len(), 1000);
+    check(&tree, tree.root);
+
+    let sorted: Vec<u64> = tree.iter().map(|x| x.0).collect();
+    assert_eq!(sorted, (0..1000).map(|i| i * 2).collect::<Vec<u64>>());
+    assert_eq!(tree.find(10), Some(&(10, 11)));
+    assert!(tree.find(11).is_none());
+    tree.find_mut(10).unwrap().1 = 5;
+    assert_eq!(tree.find(10), Some(&(10, 5)));
+    assert_eq!(tree.first(), Some(&(0, 1)));
+    assert_eq!(tree.last(), Some(&(1998, 1999)));
+
+    assert_eq!(tree.nearest_before(11).unwrap().0, 10);
+    assert_eq!(tree.nearest_before(12).unwrap().0, 12);
+    assert_eq!(tree.nearest_after(11).unwrap().0, 12);
+    assert!(tree.nearest_after(1999).is_none());
+    let range: Vec<u64> = tree.range(15, 24).map(|x| x.0).collect();
+    assert_eq!(range, vec![16, 18, 20, 22]);
+    assert_eq!(tree.range(1990, 5000).count(), 5);
+    assert_eq!(tree.range(30, 30).count(), 0);
+
+    // Remove every other value, and put some back in the freed slots
+    for key in (0..500).map(|i| i * 4) {
+        assert_eq!(tree.remove(key).map(|x| x.0), Some(key));
+        check(&tree, tree.root);
+    }
+    assert!(tree.remove(0).is_none());
+    assert_eq!(tree.len(), 500);
+    assert!(tree.find(8).is_none());
+    assert_eq!(tree.nearest_after(7).unwrap().0, 10);
+    assert!(tree.insert((8, 0)));
+    assert_eq!(tree.nodes.len(), 1000);
+    let sorted: Vec<u64> = tree.iter().map(|x| x.0).collect();
+    let mut expected: Vec<u64> = (0..500).map(|i| i * 4 + 2).collect();
+    expected.insert(2, 8);
+    assert_eq!(sorted, expected);
+    check(&tree, tree.root);
+
+    for key in expected {
+        assert!(tree.remove(key).is_some());
+    }
+    assert!(tree.is_empty());
+    assert!(tree.root.is_none());
+}
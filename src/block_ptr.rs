@@ -1,5 +1,38 @@
-use super::from_bytes::FromBytes;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem, slice};
+
+use super::checksum;
+use super::from_bytes::{ByteOrder, DecodeError, Decoder, FromBytes};
 use super::dvaddr::DVAddr;
+use super::zio_crypt;
+
+/// Embedded block pointers with this payload type hold the (compressed) block data
+pub const BP_EMBEDDED_TYPE_DATA: u64 = 0;
+
+/// An embedded block pointer can carry up to 112 bytes: all of it except the prop and birth words
+pub const BPE_PAYLOAD_SIZE: usize = 112;
+
+/// Object type of objset blocks, which have no MAC
+const DMU_OT_OBJSET: u64 = 11;
+
+/// Number of embedded payload types (data, reserved, redacted)
+const NUM_BP_EMBEDDED_TYPES: u64 = 3;
+
+/// Number of compression IDs, up to zstd
+const ZIO_COMPRESS_FUNCTIONS: u64 = 17;
+
+/// Largest block there can be (`SPA_MAXBLOCKSIZE`)
+pub const SPA_MAXBLOCKSIZE: u64 = 16 << 20;
+
+/// Number of (old style) DMU object types (`DMU_OT_NUMTYPES`)
+const DMU_OT_NUMTYPES: u64 = 54;
+
+/// Newer object types are described by flags instead: this bit, plus the byteswap function in the
+/// low bits
+const DMU_OT_NEWTYPE: u64 = 0x80;
+const DMU_OT_BYTESWAP_MASK: u64 = 0x1F;
+const DMU_BSWAP_NUMFUNCS: u64 = 10;
 
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
@@ -13,20 +46,152 @@ pub struct BlockPtr {
 }
 
 impl BlockPtr {
+    /// A hole of no particular size or birth txg
+    pub fn hole() -> Self {
+        let empty = DVAddr {
+            vdev: 0,
+            offset: 0,
+        };
+        BlockPtr {
+            dvas: [empty; 3],
+            flags_size: 0,
+            padding: [0; 3],
+            birth_txg: 0,
+            fill_count: 0,
+            checksum: [0; 4],
+        }
+    }
+
+    /// The block pointer as it's written to disk, in host byte order
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const BlockPtr as *const u8, mem::size_of::<BlockPtr>())
+        }
+    }
+
+    /// 1 if the block was written little endian, 0 if big endian
+    pub fn byte_order(&self) -> u64 {
+        self.flags_size >> 63
+    }
+
+    /// The byte order to decode the block's data in
+    pub fn data_order(&self) -> ByteOrder {
+        ByteOrder::from_bit(self.byte_order())
+    }
+
+    /// Whether the block was written by a little endian host
+    pub fn is_little_endian(&self) -> bool {
+        self.byte_order() == 1
+    }
+
+    /// Five bits: the crypt and dedup bits come right after it
     pub fn level(&self) -> u64 {
-        (self.flags_size >> 56) & 0x7F
+        (self.flags_size >> 56) & 0x1F
     }
 
     pub fn object_type(&self) -> u64 {
         (self.flags_size >> 48) & 0xFF
     }
 
+    /// Meaningless for embedded block pointers, which keep their payload type there
     pub fn checksum(&self) -> u64 {
         (self.flags_size >> 40) & 0xFF
     }
 
     pub fn compression(&self) -> u64 {
-        (self.flags_size >> 32) & 0xFF
+        (self.flags_size >> 32) & 0x7F
+    }
+
+    /// Whether the block is in the dedup table, which keeps count of the block pointers to it
+    pub fn dedup(&self) -> bool {
+        (self.flags_size >> 62) & 1 == 1
+    }
+
+    /// Whether the block belongs to an encrypted dataset (`BP_USES_CRYPT`): its data is encrypted,
+    /// or at least authenticated, and half its checksum is a MAC
+    pub fn uses_crypt(&self) -> bool {
+        (self.flags_size >> 61) & 1 == 1
+    }
+
+    /// Whether the block's data is encrypted (`BP_IS_ENCRYPTED`): level 0 blocks of the object
+    /// types holding user data. Their salt and IV take the place of the third DVA.
+    pub fn is_encrypted(&self) -> bool {
+        self.uses_crypt() && self.level() == 0 && zio_crypt::is_encrypted_type(self.object_type())
+    }
+
+    /// How many of the DVAs can be copies of the block: encrypted blocks only have room for two
+    pub fn max_copies(&self) -> usize {
+        if self.is_encrypted() { 2 } else { 3 }
+    }
+
+    /// The salt the key of an encrypted block was derived with
+    pub fn salt(&self) -> [u8; 8] {
+        let dvas = self.dvas;
+        dvas[2].vdev.to_le_bytes()
+    }
+
+    /// The IV of an encrypted block: the second word of its third DVA, then the upper half of its
+    /// fill count
+    pub fn iv(&self) -> [u8; 12] {
+        let dvas = self.dvas;
+        let mut iv = [0; 12];
+        iv[..8].copy_from_slice(&dvas[2].offset.to_le_bytes());
+        iv[8..].copy_from_slice(&((self.fill_count >> 32) as u32).to_le_bytes());
+        iv
+    }
+
+    /// The MAC of a block of an encrypted dataset, kept in the last two words of its checksum.
+    /// Objset blocks have none, their checksum is a whole one.
+    pub fn mac(&self) -> [u8; 16] {
+        if self.object_type() == DMU_OT_OBJSET {
+            return [0; 16];
+        }
+        let checksum = self.checksum;
+        let mut mac = [0; 16];
+        mac[..8].copy_from_slice(&checksum[2].to_be_bytes());
+        mac[8..].copy_from_slice(&checksum[3].to_be_bytes());
+        mac
+    }
+
+    /// Whether the block's data is stored in the block pointer itself (the embedded_data feature),
+    /// in place of the DVAs, the fill count and the checksum
+    pub fn is_embedded(&self) -> bool {
+        (self.flags_size >> 39) & 1 == 1
+    }
+
+    pub fn embedded_type(&self) -> u64 {
+        (self.flags_size >> 40) & 0xFF
+    }
+
+    /// Logical size of an embedded block in bytes
+    pub fn embedded_lsize(&self) -> u64 {
+        (self.flags_size & 0x1FFFFFF) + 1
+    }
+
+    /// Size of an embedded block's payload in bytes
+    pub fn embedded_psize(&self) -> u64 {
+        ((self.flags_size >> 25) & 0x7F) + 1
+    }
+
+    /// The payload of an embedded block pointer, still compressed. The payload bytes fill every
+    /// word but the prop and birth words, in order, least significant byte first.
+    pub fn embedded_payload(&self) -> Vec<u8> {
+        let dvas = self.dvas;
+        let padding = self.padding;
+        let checksum = self.checksum;
+        let words = [dvas[0].vdev, dvas[0].offset, dvas[1].vdev, dvas[1].offset, dvas[2].vdev,
+                     dvas[2].offset, padding[0], padding[1], padding[2], self.fill_count,
+                     checksum[0], checksum[1], checksum[2], checksum[3]];
+
+        let psize = cmp::min(self.embedded_psize() as usize, BPE_PAYLOAD_SIZE);
+        let mut payload = Vec::with_capacity(BPE_PAYLOAD_SIZE);
+        for word in &words {
+            for i in 0..8 {
+                payload.push((word >> (i * 8)) as u8);
+            }
+        }
+        payload.truncate(psize);
+        payload
     }
 
     pub fn lsize(&self) -> u64 {
@@ -36,21 +201,275 @@ impl BlockPtr {
     pub fn psize(&self) -> u64 {
         ((self.flags_size >> 16) & 0xFFFF) + 1
     }
+
+    /// How many blocks with data there are under this one: 1 for a level 0 block, the sum over
+    /// its children for an indirect block. Level 0 blocks of dnodes count the dnodes in use
+    /// instead. Embedded block pointers have no fill count, their data is one block. Encrypted
+    /// blocks share the word with their IV.
+    pub fn fill(&self) -> u64 {
+        if self.is_embedded() {
+            1
+        } else if self.is_hole() {
+            0
+        } else if self.is_encrypted() {
+            self.fill_count & 0xFFFFFFFF
+        } else {
+            self.fill_count
+        }
+    }
+
+    /// The txg the block pointer was written in
+    pub fn logical_birth(&self) -> u64 {
+        self.birth_txg
+    }
+
+    /// The txg the block's data was written in. Blocks the dedup table points to more than once
+    /// keep it apart, in the last padding word, when it's older than the block pointer.
+    pub fn physical_birth(&self) -> u64 {
+        let padding = self.padding;
+        if self.is_embedded() || padding[2] == 0 {
+            self.birth_txg
+        } else {
+            padding[2]
+        }
+    }
+
+    /// Whether the block's data is spread over a gang block
+    pub fn is_gang(&self) -> bool {
+        let dvas = self.dvas;
+        !self.is_embedded() && dvas[0].gang()
+    }
+
+    /// What the embedded checksums of the block's gang headers are verified against
+    /// (`zio_checksum_gang_verifier`): the first DVA, with its offset in bytes, for every copy,
+    /// and the physical birth. A stale header left at the same place doesn't pass.
+    pub fn gang_verifier(&self) -> [u64; 4] {
+        let dvas = self.dvas;
+        [dvas[0].vdev_id(), dvas[0].offset() << 9, self.physical_birth(), 0]
+    }
+
+    /// Number of copies of the block: the DVAs that are in use
+    pub fn ndvas(&self) -> usize {
+        if self.is_embedded() {
+            return 0;
+        }
+        let dvas = self.dvas;
+        dvas[..self.max_copies()].iter().filter(|dva| !dva.is_empty()).count()
+    }
+
+    /// Holes have no DVAs. With the hole_birth feature they still have a birth txg (and a size
+    /// and type), so that can't be used to tell them apart.
+    pub fn is_hole(&self) -> bool {
+        let dvas = self.dvas;
+        !self.is_embedded() && dvas[0].is_empty()
+    }
+}
+
+impl FromBytes for BlockPtr {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let dvas = [DVAddr::decode(decoder), DVAddr::decode(decoder), DVAddr::decode(decoder)];
+        let flags_size = decoder.u64();
+        let mut padding = [0; 3];
+        decoder.fill_u64(&mut padding);
+        let birth_txg = decoder.u64();
+        let fill_count = decoder.u64();
+        let mut checksum = [0; 4];
+        decoder.fill_u64(&mut checksum);
+        BlockPtr {
+            dvas: dvas,
+            flags_size: flags_size,
+            padding: padding,
+            birth_txg: birth_txg,
+            fill_count: fill_count,
+            checksum: checksum,
+        }
+    }
+
+    /// Catch block pointers that can't be right before anything follows them, like
+    /// `zfs_blkptr_verify`. Holes pass, they're all zeros but for the birth txg (and maybe the
+    /// type and size with hole_birth). Where the DVAs point is checked against the pool's vdevs
+    /// by `zio::Reader::verify_block_ptr`.
+    fn validate(&self) -> Result<(), DecodeError> {
+        let object_type = self.object_type();
+        let valid_type = if object_type & DMU_OT_NEWTYPE != 0 {
+            object_type & DMU_OT_BYTESWAP_MASK < DMU_BSWAP_NUMFUNCS
+        } else {
+            object_type < DMU_OT_NUMTYPES
+        };
+        if !valid_type {
+            return Err(DecodeError::BadObjectType(object_type));
+        }
+        if self.compression() >= ZIO_COMPRESS_FUNCTIONS {
+            return Err(DecodeError::BadCompression(self.compression()));
+        }
+
+        if self.is_embedded() {
+            if self.embedded_type() >= NUM_BP_EMBEDDED_TYPES {
+                return Err(DecodeError::BadEmbeddedType(self.embedded_type()));
+            }
+            if self.embedded_psize() > BPE_PAYLOAD_SIZE as u64 ||
+               self.embedded_lsize() > SPA_MAXBLOCKSIZE {
+                return Err(DecodeError::BadSize {
+                    lsize: self.embedded_lsize(),
+                    psize: self.embedded_psize(),
+                });
+            }
+        } else {
+            if checksum::info(self.checksum()).is_none() {
+                return Err(DecodeError::BadChecksum(self.checksum()));
+            }
+            let (lsize, psize) = (self.lsize() * 512, self.psize() * 512);
+            if lsize > SPA_MAXBLOCKSIZE || psize > SPA_MAXBLOCKSIZE {
+                return Err(DecodeError::BadSize {
+                    lsize: lsize,
+                    psize: psize,
+                });
+            }
+            if self.physical_birth() > self.logical_birth() {
+                return Err(DecodeError::BadBirth {
+                    logical: self.logical_birth(),
+                    physical: self.physical_birth(),
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
-impl FromBytes for BlockPtr {}
+/// Size of a gang header (`SPA_GANGBLOCKSIZE`)
+pub const GANG_HEADER_SIZE: usize = 512;
 
+/// The header of a gang block, which points at the fragments the block's data was split into
+/// when no contiguous space was left for it. Unused slots are holes.
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
 pub struct Gang {
     pub bps: [BlockPtr; 3],
-    pub padding: [u64; 14],
+    pub filler: [u64; 11],
+    /// Embedded checksum trailer (`zio_eck_t`)
     pub magic: u64,
-    pub checksum: u64,
+    pub checksum: [u64; 4],
+}
+
+impl FromBytes for Gang {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let bps = [BlockPtr::decode(decoder), BlockPtr::decode(decoder), BlockPtr::decode(decoder)];
+        let mut filler = [0; 11];
+        decoder.fill_u64(&mut filler);
+        let magic = decoder.u64();
+        let mut checksum = [0; 4];
+        decoder.fill_u64(&mut checksum);
+        Gang {
+            bps: bps,
+            filler: filler,
+            magic: magic,
+            checksum: checksum,
+        }
+    }
+
+    fn validate(&self) -> Result<(), DecodeError> {
+        let bps = self.bps;
+        for bp in &bps {
+            try!(bp.validate());
+        }
+        Ok(())
+    }
 }
 
-impl Gang {
-    pub fn magic() -> u64 {
-        return 0x117a0cb17ada1002;
+#[test]
+fn test_embedded_payload() {
+    let mut bytes = [0u8; 128];
+    for i in 0..128 {
+        bytes[i] = i as u8;
+    }
+    // Embedded data, lzjb compressed, 100 bytes of payload for 300 bytes
+    let flags_size: u64 = 1 << 39 | 3 << 32 | 99 << 25 | 299;
+    for i in 0..8 {
+        bytes[48 + i] = (flags_size >> (i * 8)) as u8;
     }
+    let block_ptr = BlockPtr::from_bytes(&bytes).unwrap();
+    assert!(block_ptr.is_embedded() && !block_ptr.is_hole());
+    assert_eq!(block_ptr.embedded_type(), BP_EMBEDDED_TYPE_DATA);
+    assert_eq!(block_ptr.compression(), 3);
+    assert_eq!((block_ptr.embedded_lsize(), block_ptr.embedded_psize()), (300, 100));
+
+    let payload = block_ptr.embedded_payload();
+    assert_eq!(payload.len(), 100);
+    // The prop word (bytes 48-55) and the birth word (bytes 80-87) are skipped
+    assert_eq!(&payload[..48], &bytes[..48]);
+    assert_eq!(&payload[48..72], &bytes[56..80]);
+    assert_eq!(&payload[72..], &bytes[88..116]);
+}
+
+#[test]
+fn test_big_endian_block_ptr() {
+    // A level 1 block pointer written by a big endian host
+    let mut bytes = [0u8; 128];
+    bytes[7] = 3; // vdev word of the first DVA: asize of 3 sectors
+    bytes[15] = 0x20; // offset
+    bytes[48..56].copy_from_slice(&[0x01, 0x14, 0x07, 0x02, 0, 0x03, 0, 0x1F]);
+    bytes[87] = 9; // birth txg
+
+    let block_ptr = BlockPtr::from_bytes_order(&bytes, ByteOrder::Big).unwrap();
+    let dvas = block_ptr.dvas;
+    assert_eq!((dvas[0].asize(), dvas[0].offset()), (3, 0x20));
+    assert_eq!(block_ptr.data_order(), ByteOrder::Big);
+    assert_eq!((block_ptr.level(), block_ptr.object_type()), (1, 0x14));
+    assert_eq!((block_ptr.lsize(), block_ptr.psize()), (0x20, 4));
+    let birth_txg = block_ptr.birth_txg;
+    assert_eq!(birth_txg, 9);
+}
+
+#[test]
+fn test_invalid_block_ptrs() {
+    let with_flags = |flags_size: u64| {
+        let mut bytes = [0u8; 128];
+        for i in 0..8 {
+            bytes[48 + i] = (flags_size >> (i * 8)) as u8;
+        }
+        BlockPtr::try_from_bytes(&bytes)
+    };
+    // A hole, and a fletcher4, lz4 compressed, 128K level 0 plain file block
+    assert!(with_flags(0).is_ok());
+    assert!(with_flags(1 << 63 | 19 << 48 | 7 << 40 | 15 << 32 | 31 << 16 | 255).is_ok());
+    // A new style (flag based) type
+    assert!(with_flags(0x84 << 48).is_ok());
+
+    assert_eq!(with_flags(60 << 48).unwrap_err(), DecodeError::BadObjectType(60));
+    assert_eq!(with_flags(0x9F << 48).unwrap_err(), DecodeError::BadObjectType(0x9F));
+    assert_eq!(with_flags(99 << 40).unwrap_err(), DecodeError::BadChecksum(99));
+    assert_eq!(with_flags(0x50 << 32).unwrap_err(), DecodeError::BadCompression(0x50));
+    assert_eq!(with_flags(0xFFFF).unwrap_err(),
+               DecodeError::BadSize {
+                   lsize: 32 << 20,
+                   psize: 512,
+               });
+    assert_eq!(with_flags(1 << 39 | 7 << 40).unwrap_err(), DecodeError::BadEmbeddedType(7));
+    assert_eq!(with_flags(1 << 39 | 120 << 25).unwrap_err(),
+               DecodeError::BadSize {
+                   lsize: 1,
+                   psize: 121,
+               });
+    // Data written after the block pointer to it
+    let mut bytes = [0u8; 128];
+    bytes[0] = 1; // a DVA, so it's not a hole
+    bytes[48 + 5] = 7; // fletcher4
+    bytes[72] = 10; // physical birth
+    bytes[80] = 5; // logical birth
+    assert_eq!(BlockPtr::try_from_bytes(&bytes).unwrap_err(),
+               DecodeError::BadBirth {
+                   logical: 5,
+                   physical: 10,
+               });
+    bytes[72] = 3;
+    let block_ptr = BlockPtr::try_from_bytes(&bytes).unwrap();
+    assert_eq!((block_ptr.logical_birth(), block_ptr.physical_birth()), (5, 3));
+    assert_eq!(block_ptr.ndvas(), 1);
+    assert!(!block_ptr.is_gang() && !block_ptr.is_little_endian());
+
+    assert_eq!(BlockPtr::try_from_bytes(&[0; 100]).unwrap_err(),
+               DecodeError::TooShort {
+                   needed: 128,
+                   got: 100,
+               });
 }
@@ -0,0 +1,122 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::mem;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::dmu;
+use super::dmu_objset::ObjectSet;
+use super::from_bytes::{Decoder, FromBytes};
+
+/// The bonus buffer of a block pointer object. Older pools have shorter ones: without the sub
+/// objects (24 bytes), or without the freed count (40 bytes). The fields they lack read as 0.
+#[repr(packed)]
+pub struct BpObjPhys {
+    pub num_blkptrs: u64,
+    pub bytes: u64,
+    pub comp: u64,
+    pub uncomp: u64,
+    pub subobjs: u64, // DMU_OT_BPOBJ_SUBOBJ, an array of bpobj object numbers
+    pub num_subobjs: u64,
+    pub num_freed: u64,
+}
+
+impl FromBytes for BpObjPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut fields = [0; 7];
+        decoder.fill_u64(&mut fields);
+        BpObjPhys {
+            num_blkptrs: fields[0],
+            bytes: fields[1],
+            comp: fields[2],
+            uncomp: fields[3],
+            subobjs: fields[4],
+            num_subobjs: fields[5],
+            num_freed: fields[6],
+        }
+    }
+}
+
+impl BpObjPhys {
+    /// Decode a bonus buffer of any of the versions
+    pub fn from_bonus(bonus: &[u8]) -> Result<Self, String> {
+        let mut data = bonus.to_vec();
+        data.resize(mem::size_of::<Self>(), 0);
+        Self::from_bytes(&data).map_err(String::from)
+    }
+}
+
+/// Allocated size of a block, all of its copies
+pub fn block_asize(block_ptr: &BlockPtr) -> u64 {
+    let dvas = block_ptr.dvas;
+    dvas[..block_ptr.max_copies()].iter().map(|dva| dva.asize() << 9).sum()
+}
+
+/// Space taken up by the blocks born after txg `after`, up to and including txg `through`
+pub fn space_born(blocks: &[BlockPtr], after: u64, through: u64) -> u64 {
+    blocks.iter()
+          .filter(|block_ptr| block_ptr.birth_txg > after && block_ptr.birth_txg <= through)
+          .map(block_asize)
+          .sum()
+}
+
+/// The block pointers of block pointer object `obj` of the MOS, those of its sub objects included
+pub fn blocks(reader: &mut ZfsReader, mos: &ObjectSet, obj: u64) -> Result<Vec<BlockPtr>, String> {
+    let dnode = try!(mos.dnode(reader, obj));
+    let phys = try!(BpObjPhys::from_bonus(dnode.get_bonus()));
+    let mut blocks = Vec::new();
+    if phys.num_blkptrs != 0 {
+        let size = mem::size_of::<BlockPtr>();
+        let data = try!(dmu::read(reader, &dnode, 0, phys.num_blkptrs as usize * size));
+        for bytes in data.chunks(size) {
+            blocks.push(try!(BlockPtr::from_bytes(bytes).map_err(String::from)));
+        }
+    }
+    if phys.num_subobjs != 0 {
+        let subobjs = try!(mos.dnode(reader, phys.subobjs));
+        let data = try!(dmu::read(reader, &subobjs, 0, phys.num_subobjs as usize * 8));
+        for bytes in data.chunks(8) {
+            let subobj = try!(u64::from_bytes(bytes).map_err(String::from));
+            blocks.extend(try!(self::blocks(reader, mos, subobj)));
+        }
+    }
+    Ok(blocks)
+}
+
+/// The MOS objects block pointer object `obj` is made of: itself, the array of its sub objects,
+/// and theirs
+pub fn objects(reader: &mut ZfsReader, mos: &ObjectSet, obj: u64) -> Result<Vec<u64>, String> {
+    let dnode = try!(mos.dnode(reader, obj));
+    let phys = try!(BpObjPhys::from_bonus(dnode.get_bonus()));
+    let mut objects = vec![obj];
+    if phys.num_subobjs != 0 {
+        objects.push(phys.subobjs);
+        let subobjs = try!(mos.dnode(reader, phys.subobjs));
+        let data = try!(dmu::read(reader, &subobjs, 0, phys.num_subobjs as usize * 8));
+        for bytes in data.chunks(8) {
+            let subobj = try!(u64::from_bytes(bytes).map_err(String::from));
+            objects.extend(try!(self::objects(reader, mos, subobj)));
+        }
+    }
+    Ok(objects)
+}
+
+#[test]
+fn test_space_born() {
+    let phys = BpObjPhys::from_bonus(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert_eq!((phys.num_blkptrs, phys.bytes, phys.num_subobjs), (3, 0x600, 0));
+
+    let mut blocks = Vec::new();
+    for &(birth_txg, sectors) in &[(10, 1), (20, 2), (30, 4)] {
+        let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+        block_ptr.birth_txg = birth_txg;
+        block_ptr.dvas[0].vdev = sectors;
+        // A ditto copy counts too
+        block_ptr.dvas[1].vdev = 1 << 32 | sectors;
+        blocks.push(block_ptr);
+    }
+    assert_eq!(block_asize(&blocks[2]), 4096);
+    assert_eq!(space_born(&blocks, 0, u64::max_value()), 7168);
+    assert_eq!(space_born(&blocks, 10, 20), 2048);
+    assert_eq!(space_born(&blocks, 30, 40), 0);
+}
@@ -0,0 +1,815 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use super::block_ptr::BlockPtr;
+use super::kstat;
+
+/// On-disk checksum function IDs, as stored in a `BlockPtr`'s checksum field
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChecksumType {
+    Inherit = 0,
+    On,
+    Off,
+    Label,
+    GangHeader,
+    Zilog,
+    Fletcher2,
+    Fletcher4,
+    Sha256,
+    Zilog2,
+    NoParity,
+    Sha512,
+    Skein,
+    Edonr,
+}
+
+impl ChecksumType {
+    pub fn from_u64(u: u64) -> Option<Self> {
+        match u {
+            0 => Some(ChecksumType::Inherit),
+            1 => Some(ChecksumType::On),
+            2 => Some(ChecksumType::Off),
+            3 => Some(ChecksumType::Label),
+            4 => Some(ChecksumType::GangHeader),
+            5 => Some(ChecksumType::Zilog),
+            6 => Some(ChecksumType::Fletcher2),
+            7 => Some(ChecksumType::Fletcher4),
+            8 => Some(ChecksumType::Sha256),
+            9 => Some(ChecksumType::Zilog2),
+            10 => Some(ChecksumType::NoParity),
+            11 => Some(ChecksumType::Sha512),
+            12 => Some(ChecksumType::Skein),
+            13 => Some(ChecksumType::Edonr),
+            _ => None,
+        }
+    }
+}
+
+/// A streaming checksum function producing the 256-bit value stored in block pointers
+pub trait Checksum {
+    /// Feed more data into the checksum
+    fn update(&mut self, data: &[u8]);
+
+    /// Finish the checksum and return its value
+    fn finish(&mut self) -> [u64; 4];
+}
+
+/// Everything we know about one on-disk checksum function
+pub struct ChecksumInfo {
+    pub name: &'static str,
+    /// Creates the checksum state, given whether the data needs byteswapping. `None` if we
+    /// don't implement this function (yet).
+    pub new: Option<fn(bool) -> Box<Checksum>>,
+    /// Whether the checksum lives in a trailer inside the block rather than in the block pointer
+    pub embedded: bool,
+    /// Whether it's strong enough for blocks with the same checksum to be taken for the same data
+    pub dedup: bool,
+}
+
+fn new_fletcher_2(byteswap: bool) -> Box<Checksum> {
+    Box::new(Fletcher2::new(byteswap))
+}
+
+fn new_fletcher_4(byteswap: bool) -> Box<Checksum> {
+    Box::new(Fletcher4::new(byteswap))
+}
+
+fn new_sha256(_: bool) -> Box<Checksum> {
+    Box::new(Sha256::new())
+}
+
+/// The checksum registry, indexed by on-disk checksum ID
+static CHECKSUM_TABLE: [ChecksumInfo; 14] = [
+    ChecksumInfo { name: "inherit", new: None, embedded: false, dedup: false },
+    ChecksumInfo { name: "on", new: None, embedded: false, dedup: false },
+    ChecksumInfo { name: "off", new: None, embedded: false, dedup: false },
+    ChecksumInfo { name: "label", new: Some(new_sha256), embedded: true, dedup: false },
+    ChecksumInfo { name: "gang_header", new: Some(new_sha256), embedded: true, dedup: false },
+    ChecksumInfo { name: "zilog", new: Some(new_fletcher_2), embedded: true, dedup: false },
+    ChecksumInfo { name: "fletcher2", new: Some(new_fletcher_2), embedded: false, dedup: false },
+    ChecksumInfo { name: "fletcher4", new: Some(new_fletcher_4), embedded: false, dedup: false },
+    ChecksumInfo { name: "sha256", new: Some(new_sha256), embedded: false, dedup: true },
+    ChecksumInfo { name: "zilog2", new: Some(new_fletcher_4), embedded: true, dedup: false },
+    ChecksumInfo { name: "noparity", new: None, embedded: false, dedup: false },
+    ChecksumInfo { name: "sha512", new: None, embedded: false, dedup: true },
+    ChecksumInfo { name: "skein", new: None, embedded: false, dedup: true },
+    ChecksumInfo { name: "edonr", new: None, embedded: false, dedup: false },
+];
+
+/// Look up a checksum function by its on-disk ID
+pub fn info(id: u64) -> Option<&'static ChecksumInfo> {
+    CHECKSUM_TABLE.get(id as usize)
+}
+
+/// Compute the checksum `id` over `data`, with the implementation picked by `benchmark`
+pub fn compute(id: u64, data: &[u8], byteswap: bool) -> Result<[u64; 4], &'static str> {
+    if let Some(implementation) = selected(id) {
+        return Ok((implementation.compute)(data, byteswap));
+    }
+    match info(id) {
+        Some(info) => {
+            match info.new {
+                Some(new) => {
+                    let mut checksum = new(byteswap);
+                    checksum.update(data);
+                    Ok(checksum.finish())
+                }
+                None => Err("Error: unsupported checksum type"),
+            }
+        }
+        None => Err("Error: invalid checksum type"),
+    }
+}
+
+/// One way of computing a checksum function over a whole buffer. All implementations of a
+/// function give the same result, they only differ in how fast they get there on a given CPU.
+pub struct ChecksumImpl {
+    pub name: &'static str,
+    pub compute: fn(&[u8], bool) -> [u64; 4],
+}
+
+static FLETCHER_2_IMPLS: [ChecksumImpl; 1] = [
+    ChecksumImpl { name: "scalar", compute: fletcher_2 },
+];
+
+static FLETCHER_4_IMPLS: [ChecksumImpl; 2] = [
+    ChecksumImpl { name: "scalar", compute: fletcher_4 },
+    ChecksumImpl { name: "superscalar", compute: fletcher_4_superscalar },
+];
+
+static SHA256_IMPLS: [ChecksumImpl; 1] = [
+    ChecksumImpl { name: "generic", compute: sha256_byteswap },
+];
+
+/// The implementations of the checksum `id`, empty if it has none of its own (the embedded
+/// checksums use the same implementations as the function they're based on)
+pub fn implementations(id: u64) -> &'static [ChecksumImpl] {
+    match ChecksumType::from_u64(id) {
+        Some(ChecksumType::Fletcher2) => &FLETCHER_2_IMPLS,
+        Some(ChecksumType::Fletcher4) => &FLETCHER_4_IMPLS,
+        Some(ChecksumType::Sha256) => &SHA256_IMPLS,
+        _ => &[],
+    }
+}
+
+/// Index in `implementations(id)` of the implementation `compute` uses, by checksum ID. The
+/// first one until a benchmark says otherwise.
+static SELECTED: [AtomicUsize; 14] = [AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// The measurements of the last benchmark run
+static BENCH_RESULTS: Mutex<Vec<BenchResult>> = Mutex::new(Vec::new());
+
+/// The implementation `compute` uses for the checksum `id`
+pub fn selected(id: u64) -> Option<&'static ChecksumImpl> {
+    let index = match SELECTED.get(id as usize) {
+        Some(index) => index.load(Ordering::Relaxed),
+        None => return None,
+    };
+    implementations(id).get(index)
+}
+
+/// Make `compute` use the implementation called `name` for the checksum `id`, e.g. to override
+/// the benchmark's pick. Returns false if there's no such implementation.
+pub fn select(id: u64, name: &str) -> bool {
+    match implementations(id).iter().position(|implementation| implementation.name == name) {
+        Some(index) => {
+            SELECTED[id as usize].store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// How fast one implementation of a checksum function went in a benchmark run
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchResult {
+    pub checksum: &'static str,
+    pub implementation: &'static str,
+    pub bytes_per_sec: f64,
+    /// Whether it was the fastest implementation of its checksum function
+    pub selected: bool,
+}
+
+/// Time every implementation of every checksum function over a `size` byte buffer, `rounds`
+/// times each, and have `compute` use the fastest implementation of each function from now on.
+/// Meant to run once at startup: the fastest implementation depends on the CPU (how well it
+/// overlaps independent additions, for Fletcher-4), so it can't be picked in advance.
+pub fn benchmark(size: usize, rounds: usize) -> Vec<BenchResult> {
+    // Any data will do, but not all zeros, in case some implementation shortcuts those
+    let data: Vec<u8> = (0..size).map(|i| (i * 31 + 7) as u8).collect();
+
+    let mut results = Vec::new();
+    for (id, info) in CHECKSUM_TABLE.iter().enumerate() {
+        let implementations = implementations(id as u64);
+        let first = results.len();
+        let mut fastest: Option<(f64, usize)> = None;
+        for (index, implementation) in implementations.iter().enumerate() {
+            let begin = Instant::now();
+            for _ in 0..rounds {
+                (implementation.compute)(&data, false);
+            }
+            let elapsed = begin.elapsed();
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            // Too fast to measure is as fast as it gets
+            let bytes_per_sec = if secs > 0.0 {
+                (size * rounds) as f64 / secs
+            } else {
+                ::std::f64::INFINITY
+            };
+            if fastest.map_or(true, |(fastest, _)| bytes_per_sec > fastest) {
+                fastest = Some((bytes_per_sec, index));
+            }
+            results.push(BenchResult {
+                checksum: info.name,
+                implementation: implementation.name,
+                bytes_per_sec: bytes_per_sec,
+                selected: false,
+            });
+        }
+
+        if let Some((_, index)) = fastest {
+            SELECTED[id].store(index, Ordering::Relaxed);
+            results[first + index].selected = true;
+        }
+    }
+
+    *BENCH_RESULTS.lock().unwrap() = results.clone();
+    results
+}
+
+/// Add the measurements of the last benchmark run to `kstats`
+pub fn kstats(kstats: &mut kstat::Registry) {
+    for result in BENCH_RESULTS.lock().unwrap().iter() {
+        let labels = vec![("checksum".to_owned(), result.checksum.to_owned()),
+                          ("implementation".to_owned(), result.implementation.to_owned())];
+        kstats.add("zfs_checksum_bench_bytes_per_second",
+                   "Throughput of a checksum implementation in the startup benchmark",
+                   kstat::KstatKind::Gauge,
+                   labels.clone(),
+                   result.bytes_per_sec);
+        kstats.add("zfs_checksum_selected",
+                   "Whether a checksum implementation is the one in use",
+                   kstat::KstatKind::Gauge,
+                   labels,
+                   if result.selected { 1.0 } else { 0.0 });
+    }
+}
+
+/// Fletcher-2 over pairs of 64-bit words
+pub fn fletcher_2(data: &[u8], byteswap: bool) -> [u64; 4] {
+    let mut fletcher = Fletcher2::new(byteswap);
+    fletcher.update(data);
+    fletcher.finish()
+}
+
+/// Fletcher-4 over 32-bit words, accumulated in 64-bit sums
+pub fn fletcher_4(data: &[u8], byteswap: bool) -> [u64; 4] {
+    let mut fletcher = Fletcher4::new(byteswap);
+    fletcher.update(data);
+    fletcher.finish()
+}
+
+/// Fletcher-4 over two interleaved streams of words, each with its own sums, combined at the
+/// end. The two streams don't depend on each other, so a superscalar CPU can run both at once.
+pub fn fletcher_4_superscalar(data: &[u8], byteswap: bool) -> [u64; 4] {
+    let mut a = [0u64; 2];
+    let mut b = [0u64; 2];
+    let mut c = [0u64; 2];
+    let mut d = [0u64; 2];
+    let whole = data.len() - data.len() % 8;
+    for chunk in data[..whole].chunks(8) {
+        for lane in 0..2 {
+            let w = read_u32(&chunk[lane * 4..], byteswap) as u64;
+            a[lane] = a[lane].wrapping_add(w);
+            b[lane] = b[lane].wrapping_add(a[lane]);
+            c[lane] = c[lane].wrapping_add(b[lane]);
+            d[lane] = d[lane].wrapping_add(c[lane]);
+        }
+    }
+
+    // Each lane saw every other word, weigh their sums back into the sums of a single stream
+    let mut fletcher = Fletcher4::new(byteswap);
+    fletcher.sums[0] = a[0].wrapping_add(a[1]);
+    fletcher.sums[1] = b[0].wrapping_mul(2)
+                           .wrapping_add(b[1].wrapping_mul(2))
+                           .wrapping_sub(a[1]);
+    fletcher.sums[2] = c[0].wrapping_mul(4)
+                           .wrapping_sub(b[0])
+                           .wrapping_add(c[1].wrapping_mul(4))
+                           .wrapping_sub(b[1].wrapping_mul(3));
+    fletcher.sums[3] = d[0].wrapping_mul(8)
+                           .wrapping_sub(c[0].wrapping_mul(4))
+                           .wrapping_add(d[1].wrapping_mul(8))
+                           .wrapping_sub(c[1].wrapping_mul(8))
+                           .wrapping_add(b[1]);
+    // An odd word at the end goes through the plain version
+    fletcher.update(&data[whole..]);
+    fletcher.finish()
+}
+
+/// SHA-256 of `data`. ZFS stores the digest as four big-endian 64-bit words.
+pub fn sha256(data: &[u8]) -> [u64; 4] {
+    sha256_words(&Sha256::digest(data))
+}
+
+/// SHA-256 works on bytes, so there's nothing to swap
+fn sha256_byteswap(data: &[u8], _: bool) -> [u64; 4] {
+    sha256(data)
+}
+
+fn sha256_words(digest: &[u8; 32]) -> [u64; 4] {
+    let mut words = [0u64; 4];
+    for (i, word) in words.iter_mut().enumerate() {
+        for b in &digest[i * 8..i * 8 + 8] {
+            *word = (*word << 8) | (*b as u64);
+        }
+    }
+    words
+}
+
+/// Whether blocks written with checksum `id` can be verified at all. Those written with
+/// `checksum=off`, or with `noparity` (like the blocks of dump devices), have nothing to check
+/// against: they always pass.
+pub fn is_verifiable(id: u64) -> bool {
+    match ChecksumType::from_u64(id) {
+        Some(ChecksumType::Off) |
+        Some(ChecksumType::NoParity) => false,
+        _ => true,
+    }
+}
+
+/// Object type of objset blocks, whose checksums aren't shared with a MAC
+const DMU_OT_OBJSET: u64 = 11;
+
+/// Verify `data` (the physical, still compressed, block contents) against the checksum stored in
+/// `block_ptr`.
+pub fn verify(block_ptr: &BlockPtr, data: &[u8]) -> Result<(), &'static str> {
+    let psize = (block_ptr.psize() * 512) as usize;
+    if data.len() < psize {
+        return Err("Error: block is shorter than its psize");
+    }
+    let data = &data[..psize];
+
+    if !is_verifiable(block_ptr.checksum()) {
+        return Ok(());
+    }
+
+    // Embedded checksums are verified by whoever knows the block layout (labels, gang headers,
+    // log blocks)
+    if info(block_ptr.checksum()).map(|info| info.embedded).unwrap_or(false) {
+        return Ok(());
+    }
+
+    // Blocks written on a host of the other endianness need their words swapped
+    let byteswap = block_ptr.byte_order() != host_byte_order();
+
+    let mut expected = block_ptr.checksum;
+    let mut actual = try!(compute(block_ptr.checksum(), data, byteswap));
+
+    // In encrypted datasets, half the checksum is the block's MAC, which decryption checks. The
+    // other half is the checksum, folded in two unless it's a dedup checksum. Objsets keep
+    // their MACs in the block, and the whole checksum in the block pointer.
+    if block_ptr.uses_crypt() && block_ptr.object_type() != DMU_OT_OBJSET {
+        if !info(block_ptr.checksum()).map(|info| info.dedup).unwrap_or(false) {
+            actual[0] ^= actual[2];
+            actual[1] ^= actual[3];
+        }
+        actual[2] = 0;
+        actual[3] = 0;
+        expected[2] = 0;
+        expected[3] = 0;
+    }
+
+    if actual == expected {
+        Ok(())
+    } else {
+        Err("Error: checksum mismatch")
+    }
+}
+
+/// Magic number of an embedded checksum trailer (`zio_eck_t`)
+pub const ZEC_MAGIC: u64 = 0x210da7ab10c7a11;
+
+/// Size of the embedded checksum trailer: the magic followed by the checksum
+const ECK_SIZE: usize = 40;
+
+/// Seal `data` with an embedded SHA-256 checksum, stored in its last 40 bytes. The checksum is
+/// computed with the verifier in place of the checksum, so it also protects against the data
+/// showing up at the wrong place (for labels, the verifier is the offset on disk).
+pub fn embed(data: &mut [u8], verifier: [u64; 4]) {
+    let eck = data.len() - ECK_SIZE;
+    write_eck(&mut data[eck..], verifier);
+    let checksum = sha256(data);
+    write_eck(&mut data[eck..], checksum);
+}
+
+/// Like `embed`, with checksum `id` and the trailer at `eck` (log blocks keep it in their
+/// header)
+pub fn embed_at(id: u64,
+                data: &mut [u8],
+                eck: usize,
+                verifier: [u64; 4])
+                -> Result<(), &'static str> {
+    write_eck(&mut data[eck..eck + ECK_SIZE], verifier);
+    let checksum = try!(compute(id, data, false));
+    write_eck(&mut data[eck..eck + ECK_SIZE], checksum);
+    Ok(())
+}
+
+/// Check the embedded checksum of `data` against `verifier`
+pub fn verify_embedded(data: &[u8], verifier: [u64; 4]) -> Result<(), &'static str> {
+    if data.len() < ECK_SIZE {
+        return Err("Error: too short for an embedded checksum");
+    }
+    verify_eck(data, data.len() - ECK_SIZE, verifier, &|data, _| Ok(sha256(data)))
+}
+
+/// Check the embedded checksum `id` of `data`, with its trailer at `eck`, against `verifier`
+pub fn verify_embedded_at(id: u64,
+                          data: &[u8],
+                          eck: usize,
+                          verifier: [u64; 4])
+                          -> Result<(), &'static str> {
+    if data.len() < eck + ECK_SIZE {
+        return Err("Error: too short for an embedded checksum");
+    }
+    verify_eck(data, eck, verifier, &|data, byteswap| compute(id, data, byteswap))
+}
+
+fn verify_eck(data: &[u8],
+              eck: usize,
+              verifier: [u64; 4],
+              checksum: &Fn(&[u8], bool) -> Result<[u64; 4], &'static str>)
+              -> Result<(), &'static str> {
+    let magic = read_u64(&data[eck..], false);
+    let byteswap = if magic == ZEC_MAGIC {
+        false
+    } else if magic == ZEC_MAGIC.swap_bytes() {
+        true
+    } else {
+        return Err("Error: bad embedded checksum magic");
+    };
+
+    let mut expected = [0; 4];
+    for (i, word) in expected.iter_mut().enumerate() {
+        *word = read_u64(&data[eck + 8 + i * 8..], byteswap);
+    }
+
+    let mut copy = data.to_vec();
+    for (i, word) in verifier.iter().enumerate() {
+        let word = if byteswap { word.swap_bytes() } else { *word };
+        copy[eck + 8 + i * 8..eck + 16 + i * 8].copy_from_slice(&word.to_ne_bytes());
+    }
+
+    if try!(checksum(&copy, byteswap)) == expected {
+        Ok(())
+    } else {
+        Err("Error: embedded checksum mismatch")
+    }
+}
+
+fn write_eck(eck: &mut [u8], checksum: [u64; 4]) {
+    eck[..8].copy_from_slice(&ZEC_MAGIC.to_ne_bytes());
+    for (i, word) in checksum.iter().enumerate() {
+        eck[8 + i * 8..16 + i * 8].copy_from_slice(&word.to_ne_bytes());
+    }
+}
+
+/// The byte order of this host, encoded the way the BlockPtr byteorder bit is (1 = little endian)
+pub fn host_byte_order() -> u64 {
+    if cfg!(target_endian = "little") { 1 } else { 0 }
+}
+
+fn read_u32(bytes: &[u8], byteswap: bool) -> u32 {
+    let mut w = [0u8; 4];
+    w.copy_from_slice(&bytes[..4]);
+    let w = u32::from_ne_bytes(w);
+    if byteswap { w.swap_bytes() } else { w }
+}
+
+fn read_u64(bytes: &[u8], byteswap: bool) -> u64 {
+    let mut w = [0u8; 8];
+    w.copy_from_slice(&bytes[..8]);
+    let w = u64::from_ne_bytes(w);
+    if byteswap { w.swap_bytes() } else { w }
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct Fletcher2 {
+    sums: [u64; 4],
+    byteswap: bool,
+    // Bytes left over from the last update that don't make a full pair of words yet
+    pending: [u8; 16],
+    pending_len: usize,
+}
+
+impl Fletcher2 {
+    pub fn new(byteswap: bool) -> Self {
+        Fletcher2 {
+            sums: [0; 4],
+            byteswap: byteswap,
+            pending: [0; 16],
+            pending_len: 0,
+        }
+    }
+
+    fn add(&mut self, chunk: &[u8]) {
+        let w0 = read_u64(&chunk[0..8], self.byteswap);
+        let w1 = read_u64(&chunk[8..16], self.byteswap);
+        self.sums[0] = self.sums[0].wrapping_add(w0);
+        self.sums[1] = self.sums[1].wrapping_add(w1);
+        self.sums[2] = self.sums[2].wrapping_add(self.sums[0]);
+        self.sums[3] = self.sums[3].wrapping_add(self.sums[1]);
+    }
+}
+
+impl Checksum for Fletcher2 {
+    fn update(&mut self, mut data: &[u8]) {
+        if self.pending_len > 0 {
+            let n = ::std::cmp::min(16 - self.pending_len, data.len());
+            self.pending[self.pending_len..self.pending_len + n].copy_from_slice(&data[..n]);
+            self.pending_len += n;
+            data = &data[n..];
+            if self.pending_len < 16 {
+                return;
+            }
+            let pending = self.pending;
+            self.add(&pending);
+            self.pending_len = 0;
+        }
+
+        let whole = data.len() - data.len() % 16;
+        for chunk in data[..whole].chunks(16) {
+            self.add(chunk);
+        }
+        self.pending[..data.len() - whole].copy_from_slice(&data[whole..]);
+        self.pending_len = data.len() - whole;
+    }
+
+    fn finish(&mut self) -> [u64; 4] {
+        self.sums
+    }
+}
+
+pub struct Fletcher4 {
+    sums: [u64; 4],
+    byteswap: bool,
+    // Bytes left over from the last update that don't make a full word yet
+    pending: [u8; 4],
+    pending_len: usize,
+}
+
+impl Fletcher4 {
+    pub fn new(byteswap: bool) -> Self {
+        Fletcher4 {
+            sums: [0; 4],
+            byteswap: byteswap,
+            pending: [0; 4],
+            pending_len: 0,
+        }
+    }
+
+    fn add(&mut self, chunk: &[u8]) {
+        let w = read_u32(chunk, self.byteswap) as u64;
+        self.sums[0] = self.sums[0].wrapping_add(w);
+        self.sums[1] = self.sums[1].wrapping_add(self.sums[0]);
+        self.sums[2] = self.sums[2].wrapping_add(self.sums[1]);
+        self.sums[3] = self.sums[3].wrapping_add(self.sums[2]);
+    }
+}
+
+impl Checksum for Fletcher4 {
+    fn update(&mut self, mut data: &[u8]) {
+        if self.pending_len > 0 {
+            let n = ::std::cmp::min(4 - self.pending_len, data.len());
+            self.pending[self.pending_len..self.pending_len + n].copy_from_slice(&data[..n]);
+            self.pending_len += n;
+            data = &data[n..];
+            if self.pending_len < 4 {
+                return;
+            }
+            let pending = self.pending;
+            self.add(&pending);
+            self.pending_len = 0;
+        }
+
+        let whole = data.len() - data.len() % 4;
+        for chunk in data[..whole].chunks(4) {
+            self.add(chunk);
+        }
+        self.pending[..data.len() - whole].copy_from_slice(&data[whole..]);
+        self.pending_len = data.len() - whole;
+    }
+
+    fn finish(&mut self) -> [u64; 4] {
+        self.sums
+    }
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const SHA256_H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Sha256 {
+            state: SHA256_H0,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn digest(data: &[u8]) -> [u8; 32] {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        sha.finish()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        // Top up a partially filled block first
+        if self.block_len > 0 {
+            let n = ::std::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+
+        while data.len() >= 64 {
+            let mut block = [0; 64];
+            block.copy_from_slice(&data[..64]);
+            self.compress(&block);
+            data = &data[64..];
+        }
+
+        self.block[..data.len()].copy_from_slice(data);
+        self.block_len = data.len();
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        // Append the 1 bit, pad with zeros, then append the message length in bits
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let pad_len = if self.block_len < 56 { 56 - self.block_len } else { 120 - self.block_len };
+        for i in 0..8 {
+            padding[pad_len + i] = (bit_len >> (56 - i * 8)) as u8;
+        }
+        let total_len = self.total_len;
+        self.update(&padding[..pad_len + 8]);
+        self.total_len = total_len;
+
+        let mut out = [0u8; 32];
+        for (i, s) in self.state.iter().enumerate() {
+            out[i * 4] = (s >> 24) as u8;
+            out[i * 4 + 1] = (s >> 16) as u8;
+            out[i * 4 + 2] = (s >> 8) as u8;
+            out[i * 4 + 3] = *s as u8;
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = ((block[i * 4] as u32) << 24) | ((block[i * 4 + 1] as u32) << 16) |
+                   ((block[i * 4 + 2] as u32) << 8) | (block[i * 4 + 3] as u32);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let mut h = self.state;
+        for i in 0..64 {
+            let s1 = h[4].rotate_right(6) ^ h[4].rotate_right(11) ^ h[4].rotate_right(25);
+            let ch = (h[4] & h[5]) ^ (!h[4] & h[6]);
+            let t1 = h[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = h[0].rotate_right(2) ^ h[0].rotate_right(13) ^ h[0].rotate_right(22);
+            let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            h[7] = h[6];
+            h[6] = h[5];
+            h[5] = h[4];
+            h[4] = h[3].wrapping_add(t1);
+            h[3] = h[2];
+            h[2] = h[1];
+            h[1] = h[0];
+            h[0] = t1.wrapping_add(t2);
+        }
+
+        for i in 0..8 {
+            self.state[i] = self.state[i].wrapping_add(h[i]);
+        }
+    }
+}
+
+impl Checksum for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Sha256::update(self, data);
+    }
+
+    fn finish(&mut self) -> [u64; 4] {
+        sha256_words(&self.clone().finish())
+    }
+}
+
+#[test]
+fn test_checksum_implementations() {
+    let data: Vec<u8> = (0..4096 + 4).map(|i| (i * 131 + 17) as u8).collect();
+    // Every implementation agrees with the streaming version, including on an odd word at the end
+    for id in 0..CHECKSUM_TABLE.len() as u64 {
+        for implementation in implementations(id) {
+            for &byteswap in &[false, true] {
+                for &len in &[4096, 4096 + 4, 0] {
+                    let mut checksum = (CHECKSUM_TABLE[id as usize].new.unwrap())(byteswap);
+                    checksum.update(&data[..len]);
+                    assert_eq!((implementation.compute)(&data[..len], byteswap),
+                               checksum.finish(),
+                               "{} {}",
+                               CHECKSUM_TABLE[id as usize].name,
+                               implementation.name);
+                }
+            }
+        }
+    }
+
+    let results = benchmark(4096, 4);
+    assert_eq!(results.len(), 4);
+    for name in &["fletcher2", "fletcher4", "sha256"] {
+        assert_eq!(results.iter().filter(|r| r.checksum == *name && r.selected).count(), 1);
+    }
+    let fastest = results.iter().find(|r| r.checksum == "fletcher4" && r.selected).unwrap();
+    assert_eq!(selected(ChecksumType::Fletcher4 as u64).unwrap().name, fastest.implementation);
+    assert_eq!(compute(7, &data, false), Ok(fletcher_4(&data, false)));
+
+    let mut registry = kstat::Registry::new();
+    kstats(&mut registry);
+    assert_eq!(registry.stats().len(), 8);
+
+    assert!(!select(ChecksumType::Fletcher4 as u64, "avx512"));
+    assert!(select(ChecksumType::Fletcher4 as u64, "superscalar"));
+    assert_eq!(selected(ChecksumType::Fletcher4 as u64).unwrap().name, "superscalar");
+    assert!(selected(ChecksumType::Label as u64).is_none());
+}
+
+#[test]
+fn test_checksum_known_answers() {
+    // FIPS 180-2 examples, including one spanning two blocks
+    assert_eq!(sha256(b""),
+               [0xe3b0c44298fc1c14, 0x9afbf4c8996fb924, 0x27ae41e4649b934c, 0xa495991b7852b855]);
+    assert_eq!(sha256(b"abc"),
+               [0xba7816bf8f01cfea, 0x414140de5dae2223, 0xb00361a396177a9c, 0xb410ff61f20015ad]);
+    assert_eq!(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+               [0x248d6a61d20638b8, 0xe5c026930c3e6039, 0xa33ce45964ff2167, 0xf6ecedd419db06c1]);
+
+    // Little endian words 1, 2, 3, 4: a = 1+2+3+4, b = 1+3+6+10, c = 1+4+10+20, d = 1+5+15+35
+    let data = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0];
+    assert_eq!(fletcher_4(&data, cfg!(target_endian = "big")), [10, 20, 35, 56]);
+    assert_eq!(fletcher_4_superscalar(&data, cfg!(target_endian = "big")), [10, 20, 35, 56]);
+    // Read as big endian, the words are 1 << 24, 2 << 24, ...
+    assert_eq!(fletcher_4(&data, cfg!(target_endian = "little")),
+               [10 << 24, 20 << 24, 35 << 24, 56 << 24]);
+}
@@ -0,0 +1,667 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+
+// The primitives native encryption is built from: AES in GCM and CCM mode for the data and the
+// wrapped keys, SHA-512 for deriving the per-block keys (HKDF), SHA-1 for deriving wrapping keys
+// from passphrases (PBKDF2)
+
+/// Size of an AES block, and of the MACs (tags) of GCM and CCM as ZFS uses them
+pub const AES_BLOCK_LEN: usize = 16;
+
+/// A hash function HMAC can be built from
+pub trait Hash {
+    /// Size of the blocks the function consumes, which HMAC pads its key to
+    const BLOCK_LEN: usize;
+
+    fn new() -> Self;
+
+    fn update(&mut self, data: &[u8]);
+
+    fn finish(self) -> Vec<u8>;
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+const SHA1_H0: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha1 {
+    pub fn digest(data: &[u8]) -> Vec<u8> {
+        let mut sha = <Sha1 as Hash>::new();
+        sha.update(data);
+        sha.finish()
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = ((block[i * 4] as u32) << 24) | ((block[i * 4 + 1] as u32) << 16) |
+                   ((block[i * 4 + 2] as u32) << 8) | (block[i * 4 + 3] as u32);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let mut h = self.state;
+        for i in 0..80 {
+            let (f, k) = match i / 20 {
+                0 => ((h[1] & h[2]) | (!h[1] & h[3]), 0x5A827999),
+                1 => (h[1] ^ h[2] ^ h[3], 0x6ED9EBA1),
+                2 => ((h[1] & h[2]) | (h[1] & h[3]) | (h[2] & h[3]), 0x8F1BBCDC),
+                _ => (h[1] ^ h[2] ^ h[3], 0xCA62C1D6),
+            };
+            let t = h[0].rotate_left(5).wrapping_add(f).wrapping_add(h[4]).wrapping_add(k)
+                        .wrapping_add(w[i]);
+            h[4] = h[3];
+            h[3] = h[2];
+            h[2] = h[1].rotate_left(30);
+            h[1] = h[0];
+            h[0] = t;
+        }
+
+        for i in 0..5 {
+            self.state[i] = self.state[i].wrapping_add(h[i]);
+        }
+    }
+}
+
+impl Hash for Sha1 {
+    const BLOCK_LEN: usize = 64;
+
+    fn new() -> Self {
+        Sha1 {
+            state: SHA1_H0,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.total_len += data.len() as u64;
+        let (mut block, mut block_len) = (self.block, self.block_len);
+        feed(&mut block, &mut block_len, data, &mut |block| self.compress(block));
+        self.block = block;
+        self.block_len = block_len;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let padding = padding(self.total_len as u128, self.block_len, 64);
+        let total_len = self.total_len;
+        self.update(&padding);
+        self.total_len = total_len;
+        self.state.iter().flat_map(|s| s.to_be_bytes().to_vec()).collect()
+    }
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+const SHA512_K: [u64; 80] = [
+    0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+    0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+    0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+    0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+    0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+    0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+    0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+    0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30,
+    0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+    0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+    0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178,
+    0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+    0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+];
+
+const SHA512_H0: [u64; 8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+#[derive(Clone)]
+pub struct Sha512 {
+    state: [u64; 8],
+    block: [u8; 128],
+    block_len: usize,
+    total_len: u128,
+}
+
+impl Sha512 {
+    pub fn digest(data: &[u8]) -> Vec<u8> {
+        let mut sha = <Sha512 as Hash>::new();
+        sha.update(data);
+        sha.finish()
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u64; 80];
+        for i in 0..16 {
+            let mut word = [0; 8];
+            word.copy_from_slice(&block[i * 8..i * 8 + 8]);
+            w[i] = u64::from_be_bytes(word);
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let mut h = self.state;
+        for i in 0..80 {
+            let s1 = h[4].rotate_right(14) ^ h[4].rotate_right(18) ^ h[4].rotate_right(41);
+            let ch = (h[4] & h[5]) ^ (!h[4] & h[6]);
+            let t1 = h[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA512_K[i])
+                         .wrapping_add(w[i]);
+            let s0 = h[0].rotate_right(28) ^ h[0].rotate_right(34) ^ h[0].rotate_right(39);
+            let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            h[7] = h[6];
+            h[6] = h[5];
+            h[5] = h[4];
+            h[4] = h[3].wrapping_add(t1);
+            h[3] = h[2];
+            h[2] = h[1];
+            h[1] = h[0];
+            h[0] = t1.wrapping_add(t2);
+        }
+
+        for i in 0..8 {
+            self.state[i] = self.state[i].wrapping_add(h[i]);
+        }
+    }
+}
+
+impl Hash for Sha512 {
+    const BLOCK_LEN: usize = 128;
+
+    fn new() -> Self {
+        Sha512 {
+            state: SHA512_H0,
+            block: [0; 128],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.total_len += data.len() as u128;
+        let (mut block, mut block_len) = (self.block, self.block_len);
+        feed(&mut block, &mut block_len, data, &mut |block| self.compress(block));
+        self.block = block;
+        self.block_len = block_len;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let padding = padding(self.total_len, self.block_len, 128);
+        let total_len = self.total_len;
+        self.update(&padding);
+        self.total_len = total_len;
+        self.state.iter().flat_map(|s| s.to_be_bytes().to_vec()).collect()
+    }
+}
+
+/// Hand `data` to `compress` a whole block at a time, after topping up the partial `block`
+/// left from before. What's left over stays in `block`.
+fn feed(block: &mut [u8], block_len: &mut usize, mut data: &[u8], compress: &mut FnMut(&[u8])) {
+    let size = block.len();
+    if *block_len > 0 {
+        let n = cmp::min(size - *block_len, data.len());
+        block[*block_len..*block_len + n].copy_from_slice(&data[..n]);
+        *block_len += n;
+        data = &data[n..];
+        if *block_len < size {
+            return;
+        }
+        compress(block);
+        *block_len = 0;
+    }
+    while data.len() >= size {
+        compress(&data[..size]);
+        data = &data[size..];
+    }
+    block[..data.len()].copy_from_slice(data);
+    *block_len = data.len();
+}
+
+/// The padding of the MD family: a 1 bit, zeros, then the message length in bits, big endian,
+/// in the last 8 bytes of a block (16 for SHA-512)
+fn padding(total_len: u128, block_len: usize, block_size: usize) -> Vec<u8> {
+    let len_size = block_size / 8;
+    let end = block_size - len_size;
+    let pad_len = if block_len < end { end - block_len } else { block_size + end - block_len };
+    let mut padding = vec![0; pad_len];
+    padding[0] = 0x80;
+    let bits = total_len.wrapping_mul(8).to_be_bytes();
+    padding.extend_from_slice(&bits[16 - len_size..]);
+    padding
+}
+
+/// HMAC of `data` under `key`, with hash function `H`
+pub fn hmac<H: Hash>(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = if key.len() > H::BLOCK_LEN {
+        let mut hash = H::new();
+        hash.update(key);
+        hash.finish()
+    } else {
+        key.to_vec()
+    };
+    block.resize(H::BLOCK_LEN, 0);
+
+    let mut inner = H::new();
+    inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(data);
+    let inner = inner.finish();
+    let mut outer = H::new();
+    outer.update(&block.iter().map(|b| b ^ 0x5C).collect::<Vec<u8>>());
+    outer.update(&inner);
+    outer.finish()
+}
+
+/// PBKDF2 with HMAC-SHA1, as user space turns passphrases into wrapping keys
+pub fn pbkdf2_sha1(passphrase: &[u8], salt: &[u8], iterations: u64, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut index: u32 = 1;
+    while out.len() < len {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&index.to_be_bytes());
+        let mut u = hmac::<Sha1>(passphrase, &input);
+        let mut t = u.clone();
+        for _ in 1..iterations {
+            u = hmac::<Sha1>(passphrase, &u);
+            for (t, u) in t.iter_mut().zip(&u) {
+                *t ^= u;
+            }
+        }
+        let n = cmp::min(len - out.len(), t.len());
+        out.extend_from_slice(&t[..n]);
+        index += 1;
+    }
+    out
+}
+
+/// HKDF with HMAC-SHA512 (RFC 5869): `len` bytes of key from `key`, for `info`
+pub fn hkdf_sha512(key: &[u8], salt: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let prk = hmac::<Sha512>(salt, key);
+    let mut out = Vec::with_capacity(len);
+    let mut t = Vec::new();
+    let mut counter = 1u8;
+    while out.len() < len {
+        let mut input = t;
+        input.extend_from_slice(info);
+        input.push(counter);
+        t = hmac::<Sha512>(&prk, &input);
+        let n = cmp::min(len - out.len(), t.len());
+        out.extend_from_slice(&t[..n]);
+        counter += 1;
+    }
+    out
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// Multiply by x in GF(2^8)
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1B } else { 0 }
+}
+
+/// An expanded AES key. Both GCM and CCM only ever run the cipher forwards, so there's no
+/// decryption schedule.
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+}
+
+impl Aes {
+    /// Expand a 16, 24 or 32 byte key. None for keys of any other length.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+        let mut words: Vec<[u8; 4]> = key.chunks(4)
+                                         .map(|w| [w[0], w[1], w[2], w[3]])
+                                         .collect();
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut w = words[i - 1];
+            if i % nk == 0 {
+                w = [AES_SBOX[w[1] as usize] ^ rcon,
+                     AES_SBOX[w[2] as usize],
+                     AES_SBOX[w[3] as usize],
+                     AES_SBOX[w[0] as usize]];
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                w = [AES_SBOX[w[0] as usize],
+                     AES_SBOX[w[1] as usize],
+                     AES_SBOX[w[2] as usize],
+                     AES_SBOX[w[3] as usize]];
+            }
+            let prev = words[i - nk];
+            words.push([prev[0] ^ w[0], prev[1] ^ w[1], prev[2] ^ w[2], prev[3] ^ w[3]]);
+        }
+
+        let round_keys = words.chunks(4)
+                              .map(|ws| {
+                                  let mut key = [0; 16];
+                                  for (i, w) in ws.iter().enumerate() {
+                                      key[i * 4..i * 4 + 4].copy_from_slice(w);
+                                  }
+                                  key
+                              })
+                              .collect();
+        Some(Aes { round_keys: round_keys })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let rounds = self.round_keys.len() - 1;
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..rounds + 1 {
+            // SubBytes and ShiftRows: byte r of column c comes from column c + r
+            let mut shifted = [0; 16];
+            for c in 0..4 {
+                for r in 0..4 {
+                    shifted[c * 4 + r] = AES_SBOX[block[((c + r) % 4) * 4 + r] as usize];
+                }
+            }
+            if round < rounds {
+                for c in 0..4 {
+                    let col = [shifted[c * 4], shifted[c * 4 + 1], shifted[c * 4 + 2],
+                               shifted[c * 4 + 3]];
+                    let all = col[0] ^ col[1] ^ col[2] ^ col[3];
+                    for r in 0..4 {
+                        shifted[c * 4 + r] = col[r] ^ all ^ xtime(col[r] ^ col[(r + 1) % 4]);
+                    }
+                }
+            }
+            *block = shifted;
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+}
+
+fn add_round_key(block: &mut [u8; 16], key: &[u8; 16]) {
+    for (b, k) in block.iter_mut().zip(key) {
+        *b ^= k;
+    }
+}
+
+/// Whether two MACs are the same, taking as long whatever their difference
+fn mac_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// XOR `data` with the key stream of counter mode, starting at counter block `counter`. Only
+/// the low 32 bits of the counter are incremented, as GCM does; CCM's counters never get that
+/// far for a block.
+fn ctr(aes: &Aes, counter: &[u8; 16], data: &mut [u8]) {
+    let mut counter = *counter;
+    for chunk in data.chunks_mut(16) {
+        let mut stream = counter;
+        aes.encrypt_block(&mut stream);
+        for (b, s) in chunk.iter_mut().zip(&stream) {
+            *b ^= s;
+        }
+        let mut low = [0; 4];
+        low.copy_from_slice(&counter[12..]);
+        counter[12..].copy_from_slice(&u32::from_be_bytes(low).wrapping_add(1).to_be_bytes());
+    }
+}
+
+/// Multiply `x` by `h` in GCM's GF(2^128)
+fn gf_mul(x: u128, h: u128) -> u128 {
+    let (mut z, mut v) = (0u128, h);
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ (0xE1 << 120) } else { v >> 1 };
+    }
+    z
+}
+
+fn u128_at(block: &[u8]) -> u128 {
+    let mut bytes = [0; 16];
+    bytes[..block.len()].copy_from_slice(block);
+    u128::from_be_bytes(bytes)
+}
+
+/// GHASH of `aad` and `ciphertext`, each zero padded to whole blocks, then their lengths
+fn ghash(h: u128, aad: &[u8], ciphertext: &[u8]) -> u128 {
+    let mut y = 0;
+    for chunk in aad.chunks(16).chain(ciphertext.chunks(16)) {
+        y = gf_mul(y ^ u128_at(chunk), h);
+    }
+    let lens = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+    gf_mul(y ^ lens, h)
+}
+
+/// The GCM tag of `ciphertext` and `aad`, for a 12 byte IV
+fn gcm_tag(aes: &Aes, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut h = [0; 16];
+    aes.encrypt_block(&mut h);
+    let s = ghash(u128::from_be_bytes(h), aad, ciphertext);
+    let mut j0 = [0; 16];
+    j0[..12].copy_from_slice(iv);
+    j0[15] = 1;
+    aes.encrypt_block(&mut j0);
+    (u128::from_be_bytes(j0) ^ s).to_be_bytes()
+}
+
+fn gcm_counter(iv: &[u8]) -> [u8; 16] {
+    let mut counter = [0; 16];
+    counter[..12].copy_from_slice(iv);
+    counter[15] = 2;
+    counter
+}
+
+/// Encrypt `data` in place with AES-GCM under `key`, with a 12 byte IV. Returns the tag.
+pub fn gcm_encrypt(key: &[u8], iv: &[u8], aad: &[u8], data: &mut [u8]) -> Option<[u8; 16]> {
+    let aes = match Aes::new(key) {
+        Some(aes) if iv.len() == 12 => aes,
+        _ => return None,
+    };
+    ctr(&aes, &gcm_counter(iv), data);
+    Some(gcm_tag(&aes, iv, aad, data))
+}
+
+/// Decrypt `data` in place with AES-GCM, if it and `aad` match `tag`. Returns whether they did;
+/// if not, `data` is left alone.
+pub fn gcm_decrypt(key: &[u8], iv: &[u8], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+    let aes = match Aes::new(key) {
+        Some(aes) => aes,
+        None => return false,
+    };
+    if iv.len() != 12 || !mac_eq(&gcm_tag(&aes, iv, aad, data), tag) {
+        return false;
+    }
+    ctr(&aes, &gcm_counter(iv), data);
+    true
+}
+
+/// Counter block `i` of CCM for `nonce`
+fn ccm_counter(nonce: &[u8], i: u32) -> [u8; 16] {
+    let q = 15 - nonce.len();
+    let mut block = [0; 16];
+    block[0] = (q - 1) as u8;
+    block[1..1 + nonce.len()].copy_from_slice(nonce);
+    let count = i.to_be_bytes();
+    for j in 0..cmp::min(q, 4) {
+        block[15 - j] = count[3 - j];
+    }
+    block
+}
+
+/// CBC-MAC of CCM over the plaintext and `aad`, before it's encrypted into the tag
+fn ccm_mac(aes: &Aes, nonce: &[u8], aad: &[u8], plaintext: &[u8], tag_len: usize) -> [u8; 16] {
+    let q = 15 - nonce.len();
+    let mut b0 = [0; 16];
+    b0[0] = (if aad.is_empty() { 0 } else { 0x40 }) | ((((tag_len - 2) / 2) as u8) << 3) |
+            (q - 1) as u8;
+    b0[1..1 + nonce.len()].copy_from_slice(nonce);
+    let len = (plaintext.len() as u64).to_be_bytes();
+    for j in 0..cmp::min(q, 8) {
+        b0[15 - j] = len[7 - j];
+    }
+
+    let mut x = b0;
+    aes.encrypt_block(&mut x);
+    let absorb = |data: &[u8], x: &mut [u8; 16]| {
+        for chunk in data.chunks(16) {
+            for (x, b) in x.iter_mut().zip(chunk) {
+                *x ^= b;
+            }
+            aes.encrypt_block(x);
+        }
+    };
+    if !aad.is_empty() {
+        // The AAD is prefixed with its length, in 2 bytes if that's enough
+        let mut prefixed = if aad.len() < 0xFF00 {
+            (aad.len() as u16).to_be_bytes().to_vec()
+        } else {
+            let mut prefix = vec![0xFF, 0xFE];
+            prefix.extend_from_slice(&(aad.len() as u32).to_be_bytes());
+            prefix
+        };
+        prefixed.extend_from_slice(aad);
+        absorb(&prefixed, &mut x);
+    }
+    absorb(plaintext, &mut x);
+    x
+}
+
+/// Encrypt `data` in place with AES-CCM under `key`. Returns the tag, `tag_len` bytes of it.
+pub fn ccm_encrypt(key: &[u8],
+                   nonce: &[u8],
+                   aad: &[u8],
+                   data: &mut [u8],
+                   tag_len: usize)
+                   -> Option<Vec<u8>> {
+    let aes = match Aes::new(key) {
+        Some(aes) => aes,
+        None => return None,
+    };
+    if nonce.len() < 7 || nonce.len() > 13 || tag_len < 4 || tag_len > 16 || tag_len % 2 != 0 {
+        return None;
+    }
+    let mut tag = ccm_mac(&aes, nonce, aad, data, tag_len);
+    ctr(&aes, &ccm_counter(nonce, 0), &mut tag);
+    ctr(&aes, &ccm_counter(nonce, 1), data);
+    Some(tag[..tag_len].to_vec())
+}
+
+/// Decrypt `data` in place with AES-CCM, if it and `aad` match `tag`. Returns whether they did;
+/// if not, `data` is left alone.
+pub fn ccm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+    let aes = match Aes::new(key) {
+        Some(aes) => aes,
+        None => return false,
+    };
+    if nonce.len() < 7 || nonce.len() > 13 || tag.len() < 4 || tag.len() > 16 {
+        return false;
+    }
+    let mut plaintext = data.to_vec();
+    ctr(&aes, &ccm_counter(nonce, 1), &mut plaintext);
+    let mut expected = ccm_mac(&aes, nonce, aad, &plaintext, tag.len());
+    ctr(&aes, &ccm_counter(nonce, 0), &mut expected);
+    if !mac_eq(&expected[..tag.len()], tag) {
+        return false;
+    }
+    data.copy_from_slice(&plaintext);
+    true
+}
+
+#[test]
+fn test_crypto() {
+    let hex = |s: &str| -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    };
+
+    assert_eq!(Sha512::digest(b"abc"),
+               hex("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                    2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"));
+    // Across blocks
+    assert_eq!(Sha512::digest(&[b'a'; 200]),
+               hex("4b11459c33f52a22ee8236782714c150a3b2c60994e9acee17fe68947a3e6789\
+                    f31e7668394592da7bef827cddca88c4e6f86e4df7ed1ae6cba71f3e98faee9f"));
+    assert_eq!(Sha1::digest(&[b'a'; 100]), hex("7f9000257a4918d7072655ea468540cdcbd42e0c"));
+    assert_eq!(hmac::<Sha512>(b"Jefe", b"what do ya want for nothing?"),
+               hex("164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554\
+                    9758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"));
+    assert_eq!(pbkdf2_sha1(b"password", b"salt", 2, 32),
+               hex("ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957cae93136266537a8d7bf4b76"));
+    let key: Vec<u8> = (0..32).collect();
+    let salt: Vec<u8> = (0..8).collect();
+    assert_eq!(hkdf_sha512(&key, &[], &salt, 32),
+               hex("a86b00dfaf898f8fd5ef9a8a39466dd92d01b85d92f5be3532f28b8f150db5ca"));
+
+    // FIPS-197, appendix C.1
+    let aes = Aes::new(&hex("000102030405060708090a0b0c0d0e0f")).unwrap();
+    let mut block = [0; 16];
+    block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+    aes.encrypt_block(&mut block);
+    assert_eq!(block.to_vec(), hex("69c4e0d86a7b0430d8cdb78070b4c55a"));
+    assert!(Aes::new(&[0; 20]).is_none());
+
+    let iv: Vec<u8> = (100..112).collect();
+    let plaintext: Vec<u8> = (0..40).map(|i| (i * 7) as u8).collect();
+    let mut data = plaintext.clone();
+    let tag = gcm_encrypt(&key, &iv, b"zfs aad", &mut data).unwrap();
+    assert_eq!(data, hex("481cd07365ca7caf065d19a58e3e089432b5788f07ff69d30f7e1af53f687791\
+                          740eae35b0119115"));
+    assert_eq!(tag.to_vec(), hex("905bb6fae1aa456b54a425c480b37303"));
+    assert!(!gcm_decrypt(&key, &iv, b"zfs aaD", &mut data, &tag));
+    assert!(gcm_decrypt(&key, &iv, b"zfs aad", &mut data, &tag));
+    assert_eq!(data, plaintext);
+
+    let tag = ccm_encrypt(&key, &iv, b"zfs aad", &mut data, 16).unwrap();
+    assert_eq!(data, hex("31e7a66e6a8ef2c22a8ed87dc53aef4cd385bb3d177535b8be2b7ce9052f9d50\
+                          8d59c63f7494014c"));
+    assert_eq!(tag, hex("9e613168fa221ea79ee2f799e7a586b4"));
+    data[0] ^= 1;
+    assert!(!ccm_decrypt(&key, &iv, b"zfs aad", &mut data, &tag));
+    data[0] ^= 1;
+    assert!(ccm_decrypt(&key, &iv, b"zfs aad", &mut data, &tag));
+    assert_eq!(data, plaintext);
+    // SP 800-38C, example 1
+    let mut data = hex("20212223");
+    let tag = ccm_encrypt(&hex("404142434445464748494a4b4c4d4e4f"),
+                          &hex("10111213141516"),
+                          &hex("0001020304050607"),
+                          &mut data,
+                          4);
+    assert_eq!((data, tag.unwrap()), (hex("7162015b"), hex("4dac255d")));
+}
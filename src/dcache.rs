@@ -0,0 +1,163 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+use super::djb2::Djb2;
+use super::dnode::DNodePhys;
+use super::kstat;
+
+/// Most entries the dentry cache keeps by default
+pub const DCACHE_MAX_ENTRIES: usize = 16384;
+
+struct Dentry {
+    /// The directory entry's ZAP value: object number and file type
+    value: u64,
+    /// `dir_txg` of the directory when the entry was looked up
+    dir_txg: u64,
+    /// When the entry was last used, in lookups
+    used: u64,
+}
+
+/// The txg the directory `dnode` last changed in: the newest birth txg of its block pointers.
+/// Any change to the ZAP below them changes it too, since blocks are copied on write all the way
+/// up.
+pub fn dir_txg(dnode: &DNodePhys) -> u64 {
+    (0..dnode.nblkptr as usize).map(|i| dnode.get_blockptr(i).birth_txg).max().unwrap_or(0)
+}
+
+/// Directory entries looked up by name, so resolving the same paths again doesn't read the
+/// directories' ZAP blocks. Entries are keyed by dataset (its MOS object number), directory and
+/// name, and only served while the directory is at the txg they were looked up at: a directory
+/// that changed has a new `dir_txg`. Txgs synced by another writer drop them all though (see
+/// `ZfsReader::revalidate`), the dnode they go by may be read from a block that was written over.
+pub struct DentryCache {
+    entries: HashMap<(u64, u64, String), Dentry, BuildHasherDefault<Djb2>>,
+    /// Past this many entries, the least recently used half is dropped
+    pub max_entries: usize,
+    lookups: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DentryCache {
+    pub fn new() -> Self {
+        DentryCache {
+            entries: HashMap::with_hasher(Default::default()),
+            max_entries: DCACHE_MAX_ENTRIES,
+            lookups: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The ZAP value of `name` in directory `dir` of `dataset`, if it's cached and the directory
+    /// is still at `dir_txg`
+    pub fn lookup(&mut self, dataset: u64, dir: u64, dir_txg: u64, name: &str) -> Option<u64> {
+        self.lookups += 1;
+        let lookups = self.lookups;
+        match self.entries.get_mut(&(dataset, dir, name.to_owned())) {
+            Some(ref mut dentry) if dentry.dir_txg == dir_txg => {
+                dentry.used = lookups;
+                self.hits += 1;
+                Some(dentry.value)
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the ZAP value of `name` in directory `dir` of `dataset`, at `dir_txg`
+    pub fn insert(&mut self, dataset: u64, dir: u64, dir_txg: u64, name: &str, value: u64) {
+        if self.entries.len() >= self.max_entries {
+            self.shrink();
+        }
+        self.entries.insert((dataset, dir, name.to_owned()),
+                            Dentry {
+                                value: value,
+                                dir_txg: dir_txg,
+                                used: self.lookups,
+                            });
+    }
+
+    /// Drop the least recently used half of the entries
+    fn shrink(&mut self) {
+        let mut by_use: Vec<_> = self.entries
+                                     .iter()
+                                     .map(|(key, dentry)| (dentry.used, key.clone()))
+                                     .collect();
+        by_use.sort();
+        for (_, key) in by_use.into_iter().take(self.entries.len() / 2 + 1) {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Forget the entries of directory `dir` of `dataset`, e.g. once it's been changed
+    pub fn invalidate_dir(&mut self, dataset: u64, dir: u64) {
+        self.entries.retain(|&(entry_dataset, entry_dir, _), _| {
+            (entry_dataset, entry_dir) != (dataset, dir)
+        });
+    }
+
+    /// Forget every entry of `dataset`, e.g. once it's been rolled back or destroyed
+    pub fn invalidate_dataset(&mut self, dataset: u64) {
+        self.entries.retain(|&(entry_dataset, _, _), _| entry_dataset != dataset);
+    }
+
+    /// Forget every entry, e.g. once another writer may have changed any directory
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn kstats(&self, kstats: &mut kstat::Registry) {
+        kstats.counter("zfs_dcache_hits_total",
+                       "Directory lookups served from the dentry cache",
+                       self.hits);
+        kstats.counter("zfs_dcache_misses_total",
+                       "Directory lookups that had to read the directory",
+                       self.misses);
+        kstats.gauge("zfs_dcache_entries",
+                     "Entries in the dentry cache",
+                     self.entries.len() as u64);
+    }
+}
+
+#[test]
+fn test_dentry_cache() {
+    let mut dcache = DentryCache::new();
+    dcache.insert(10, 4, 100, "etc", 8 << 60 | 5);
+    assert_eq!(dcache.lookup(10, 4, 100, "etc"), Some(8 << 60 | 5));
+    assert_eq!(dcache.lookup(11, 4, 100, "etc"), None);
+    // The directory changed since
+    assert_eq!(dcache.lookup(10, 4, 101, "etc"), None);
+
+    dcache.insert(10, 4, 100, "usr", 6);
+    dcache.insert(10, 5, 100, "passwd", 7);
+    dcache.invalidate_dir(10, 4);
+    assert_eq!(dcache.lookup(10, 4, 100, "usr"), None);
+    assert_eq!(dcache.lookup(10, 5, 100, "passwd"), Some(7));
+
+    // The entries used last survive shrinking
+    dcache.max_entries = 5;
+    for object in 0..4 {
+        dcache.insert(10, 6, 100, &object.to_string(), object);
+    }
+    assert_eq!(dcache.lookup(10, 6, 100, "3"), Some(3));
+    assert_eq!(dcache.lookup(10, 5, 100, "passwd"), Some(7));
+    dcache.insert(10, 6, 100, "4", 4);
+    assert!(dcache.len() < 4);
+    assert_eq!(dcache.lookup(10, 6, 100, "3"), Some(3));
+    assert_eq!(dcache.lookup(10, 5, 100, "passwd"), Some(7));
+    assert_eq!(dcache.lookup(10, 6, 100, "4"), Some(4));
+    assert_eq!(dcache.lookup(10, 6, 100, "0"), None);
+
+    let mut kstats = kstat::Registry::new();
+    dcache.kstats(&mut kstats);
+    assert_eq!(kstats.get("zfs_dcache_hits_total").unwrap().value, 7.0);
+}
@@ -0,0 +1,917 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fmt, mem};
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum;
+use super::dmu;
+use super::dmu_objset::{DMU_POOL_DIRECTORY_OBJECT, ObjectSet};
+use super::dmu_tx::Tx;
+use super::dnode::{DNodePhys, ObjectType};
+use super::dsl_pool::{DslPool, MOS_OBJSET};
+use super::dvaddr::DVAddr;
+use super::from_bytes::{ByteOrder, Decoder, FromBytes};
+use super::util;
+use super::zap;
+use super::zfs;
+use super::zio_compress;
+
+// Object types of the tables and the statistics ZAP
+const DMU_OT_DDT_ZAP: u8 = 42;
+const DMU_OT_DDT_STATS: u8 = 43;
+
+/// Tables have 4K blocks (`ddt_zap_leaf_blockshift`)
+const DDT_ZAP_BLOCK_SHIFT: u32 = 12;
+
+/// The MOS directory entry of the ZAP holding the histogram of each dedup table, by table name
+pub const DMU_POOL_DDT_STATS: &'static str = "DDT-statistics";
+
+/// Dedup tables are MOS directory entries named `DDT-<checksum>-<type>-<class>`
+const DDT_PREFIX: &'static str = "DDT-";
+
+/// The only type of table there is: a fat ZAP with integer keys
+const DDT_TYPE_ZAP: &'static str = "zap";
+
+/// An entry keeps track of a block written with each of 0 (ditto), 1, 2 and 3 copies
+pub const DDT_PHYS_TYPES: usize = 4;
+
+/// The entry's block written with extra copies
+pub const DDT_PHYS_DITTO: usize = 0;
+
+/// The entry's block written with a single copy
+pub const DDT_PHYS_SINGLE: usize = 1;
+
+const DDT_PHYS_SIZE: usize = 64;
+
+/// Keys are the checksum (4 words) and the sizes and compression of the block
+const DDT_KEY_WORDS: usize = 5;
+
+/// The first byte of an entry's value says how the rest is compressed, and (in the top bit)
+/// whether it was written little endian
+const DDT_COMPRESS_BYTEORDER_MASK: u8 = 0x80;
+const DDT_COMPRESS_FUNCTION_MASK: u8 = 0x7F;
+
+/// Histograms have a bucket for each power of two of references
+pub const DDT_HISTOGRAM_BUCKETS: usize = 64;
+
+/// Tables are split by how many times their blocks are referenced
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum DdtClass {
+    /// Referenced so often they were written with extra copies (`dedupditto`)
+    Ditto,
+    /// Referenced more than once
+    Duplicate,
+    /// Referenced once
+    Unique,
+}
+
+impl DdtClass {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DdtClass::Ditto => "ditto",
+            DdtClass::Duplicate => "duplicate",
+            DdtClass::Unique => "unique",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ditto" => Some(DdtClass::Ditto),
+            "duplicate" => Some(DdtClass::Duplicate),
+            "unique" => Some(DdtClass::Unique),
+            _ => None,
+        }
+    }
+}
+
+/// What deduplicated blocks are looked up by: their checksum, sizes and compression
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct DdtKey {
+    pub checksum: [u64; 4],
+    pub prop: u64,
+}
+
+impl DdtKey {
+    /// The key of the block `block_ptr` points at
+    pub fn of(block_ptr: &BlockPtr) -> Self {
+        DdtKey {
+            checksum: block_ptr.checksum,
+            prop: block_ptr.flags_size & 0x7F_FFFF_FFFF,
+        }
+    }
+
+    fn from_words(words: &[u64]) -> zfs::Result<Self> {
+        if words.len() != DDT_KEY_WORDS {
+            return Err(zfs::Error::Invalid);
+        }
+        Ok(DdtKey {
+            checksum: [words[0], words[1], words[2], words[3]],
+            prop: words[4],
+        })
+    }
+
+    /// The key as it's stored in the tables
+    pub fn words(&self) -> [u64; DDT_KEY_WORDS] {
+        let checksum = self.checksum;
+        [checksum[0], checksum[1], checksum[2], checksum[3], self.prop]
+    }
+
+    /// Logical size in bytes
+    pub fn lsize(&self) -> u64 {
+        ((self.prop & 0xFFFF) + 1) << 9
+    }
+
+    /// Physical (compressed) size in bytes
+    pub fn psize(&self) -> u64 {
+        (((self.prop >> 16) & 0xFFFF) + 1) << 9
+    }
+
+    pub fn compression(&self) -> u64 {
+        (self.prop >> 32) & 0x7F
+    }
+}
+
+/// One written copy of a deduplicated block: where it is, and how many block pointers point at
+/// it. It's unused if `phys_birth` is 0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DdtPhys {
+    pub dvas: [DVAddr; 3],
+    pub refcnt: u64,
+    pub phys_birth: u64,
+}
+
+impl DdtPhys {
+    /// Bytes allocated to it, over all of its DVAs
+    pub fn dsize(&self) -> u64 {
+        let dvas = self.dvas;
+        dvas.iter().map(|dva| dva.asize() * 512).sum()
+    }
+
+    /// The phys as it's stored in an entry, little endian
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut words = Vec::new();
+        for dva in &self.dvas {
+            words.push(dva.vdev);
+            words.push(dva.offset);
+        }
+        words.push(self.refcnt);
+        words.push(self.phys_birth);
+        words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect()
+    }
+}
+
+impl FromBytes for DdtPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let dvas = [DVAddr::decode(decoder), DVAddr::decode(decoder), DVAddr::decode(decoder)];
+        let refcnt = decoder.u64();
+        let phys_birth = decoder.u64();
+        DdtPhys {
+            dvas: dvas,
+            refcnt: refcnt,
+            phys_birth: phys_birth,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DdtEntry {
+    pub key: DdtKey,
+    pub phys: [DdtPhys; DDT_PHYS_TYPES],
+}
+
+impl DdtEntry {
+    /// Decode the entry from its ZAP key and value
+    pub fn from_zap(key: &[u64], value: &[u8]) -> zfs::Result<Self> {
+        let key = try!(DdtKey::from_words(key));
+        let (&version, data) = try!(value.split_first().ok_or(zfs::Error::Invalid));
+        let compression = (version & DDT_COMPRESS_FUNCTION_MASK) as u64;
+        let data = try!(zio_compress::decompress(compression,
+                                                 data.to_vec(),
+                                                 DDT_PHYS_TYPES * DDT_PHYS_SIZE));
+        let order = if version & DDT_COMPRESS_BYTEORDER_MASK != 0 {
+            ByteOrder::Little
+        } else {
+            ByteOrder::Big
+        };
+        let mut phys = [try!(DdtPhys::from_bytes_order(&data, order)); DDT_PHYS_TYPES];
+        for (i, phys) in phys.iter_mut().enumerate().skip(1) {
+            *phys = try!(DdtPhys::from_bytes_order(&data[i * DDT_PHYS_SIZE..], order));
+        }
+        Ok(DdtEntry {
+            key: key,
+            phys: phys,
+        })
+    }
+
+    /// The entry's value in its table, uncompressed and little endian (`ddt_zap_update`)
+    pub fn to_zap(&self) -> Vec<u8> {
+        let mut value = vec![DDT_COMPRESS_BYTEORDER_MASK | zio_compress::ZIO_COMPRESS_OFF as u8];
+        for phys in &self.phys {
+            value.extend(phys.to_bytes());
+        }
+        value
+    }
+
+    /// The class of the table the entry goes in (`ddt_class`)
+    pub fn class(&self) -> DdtClass {
+        if self.phys[DDT_PHYS_DITTO].phys_birth != 0 {
+            DdtClass::Ditto
+        } else if self.refcnt() > 1 {
+            DdtClass::Duplicate
+        } else {
+            DdtClass::Unique
+        }
+    }
+
+    /// How many block pointers point at the block, over all of its copies
+    pub fn refcnt(&self) -> u64 {
+        self.phys.iter().map(|phys| phys.refcnt).sum()
+    }
+
+    /// The statistics of the entry alone
+    pub fn stat(&self) -> DdtStat {
+        let (lsize, psize) = (self.key.lsize(), self.key.psize());
+        let mut stat = DdtStat::default();
+        for phys in self.phys.iter().filter(|phys| phys.phys_birth != 0) {
+            let dsize = phys.dsize();
+            stat.blocks += 1;
+            stat.lsize += lsize;
+            stat.psize += psize;
+            stat.dsize += dsize;
+            stat.ref_blocks += phys.refcnt;
+            stat.ref_lsize += lsize * phys.refcnt;
+            stat.ref_psize += psize * phys.refcnt;
+            stat.ref_dsize += dsize * phys.refcnt;
+        }
+        stat
+    }
+}
+
+/// Sizes of deduplicated blocks, in bytes: as they're allocated, once per block, and as they're
+/// referenced, once per block pointer
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DdtStat {
+    pub blocks: u64,
+    pub lsize: u64,
+    pub psize: u64,
+    pub dsize: u64,
+    pub ref_blocks: u64,
+    pub ref_lsize: u64,
+    pub ref_psize: u64,
+    pub ref_dsize: u64,
+}
+
+impl DdtStat {
+    fn from_ints(ints: &[u64]) -> Self {
+        DdtStat {
+            blocks: ints[0],
+            lsize: ints[1],
+            psize: ints[2],
+            dsize: ints[3],
+            ref_blocks: ints[4],
+            ref_lsize: ints[5],
+            ref_psize: ints[6],
+            ref_dsize: ints[7],
+        }
+    }
+
+    fn to_ints(&self) -> [u64; 8] {
+        [self.blocks,
+         self.lsize,
+         self.psize,
+         self.dsize,
+         self.ref_blocks,
+         self.ref_lsize,
+         self.ref_psize,
+         self.ref_dsize]
+    }
+
+    pub fn add(&mut self, other: &DdtStat) {
+        self.blocks += other.blocks;
+        self.lsize += other.lsize;
+        self.psize += other.psize;
+        self.dsize += other.dsize;
+        self.ref_blocks += other.ref_blocks;
+        self.ref_lsize += other.ref_lsize;
+        self.ref_psize += other.ref_psize;
+        self.ref_dsize += other.ref_dsize;
+    }
+
+    /// How much space dedup saves: referenced over allocated
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.ref_dsize, self.dsize)
+    }
+
+    pub fn compress_ratio(&self) -> f64 {
+        ratio(self.ref_lsize, self.ref_psize)
+    }
+
+    /// How much the extra copies (and parity) take
+    pub fn copies_ratio(&self) -> f64 {
+        ratio(self.ref_dsize, self.ref_psize)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 { 1.0 } else { a as f64 / b as f64 }
+}
+
+/// The statistics of a set of entries, bucketed by how many references they have: bucket `i`
+/// holds the entries with 2^i to 2^(i+1)-1 references
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DdtHistogram {
+    pub buckets: [DdtStat; DDT_HISTOGRAM_BUCKETS],
+}
+
+impl DdtHistogram {
+    pub fn new() -> Self {
+        DdtHistogram { buckets: [DdtStat::default(); DDT_HISTOGRAM_BUCKETS] }
+    }
+
+    /// Decode a histogram as it's stored in the statistics ZAP
+    fn from_ints(ints: &[u64]) -> zfs::Result<Self> {
+        if ints.len() != DDT_HISTOGRAM_BUCKETS * 8 {
+            return Err(zfs::Error::Invalid);
+        }
+        let mut histogram = DdtHistogram::new();
+        for (bucket, ints) in histogram.buckets.iter_mut().zip(ints.chunks(8)) {
+            *bucket = DdtStat::from_ints(ints);
+        }
+        Ok(histogram)
+    }
+
+    /// The histogram as it's stored in the statistics ZAP
+    fn to_ints(&self) -> Vec<u64> {
+        self.buckets.iter().flat_map(|bucket| bucket.to_ints().to_vec()).collect()
+    }
+
+    pub fn add_entry(&mut self, entry: &DdtEntry) {
+        let stat = entry.stat();
+        if stat.ref_blocks != 0 {
+            self.buckets[util::highbit64(stat.ref_blocks) as usize].add(&stat);
+        }
+    }
+
+    pub fn add(&mut self, other: &DdtHistogram) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            bucket.add(other);
+        }
+    }
+
+    /// The sum of all buckets
+    pub fn total(&self) -> DdtStat {
+        let mut total = DdtStat::default();
+        for bucket in self.buckets.iter() {
+            total.add(bucket);
+        }
+        total
+    }
+}
+
+/// A table like `zdb -DD` prints it, the buckets that have entries and their total
+impl fmt::Display for DdtHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "{:>8} {:>8} {:>12} {:>12} {:>12} {:>8} {:>12} {:>12} {:>12}",
+                      "refcnt",
+                      "blocks",
+                      "LSIZE",
+                      "PSIZE",
+                      "DSIZE",
+                      "blocks",
+                      "LSIZE",
+                      "PSIZE",
+                      "DSIZE"));
+        let total = self.total();
+        let rows = self.buckets.iter().enumerate().filter(|&(_, stat)| stat.blocks != 0);
+        for (refcnt, stat) in rows.map(|(i, stat)| ((1u64 << i).to_string(), stat))
+                                  .chain(Some(("Total".to_owned(), &total))) {
+            try!(writeln!(f,
+                          "{:>8} {:>8} {:>12} {:>12} {:>12} {:>8} {:>12} {:>12} {:>12}",
+                          refcnt,
+                          stat.blocks,
+                          stat.lsize,
+                          stat.psize,
+                          stat.dsize,
+                          stat.ref_blocks,
+                          stat.ref_lsize,
+                          stat.ref_psize,
+                          stat.ref_dsize));
+        }
+        write!(f,
+               "dedup = {:.2}, compress = {:.2}, copies = {:.2}",
+               total.dedup_ratio(),
+               total.compress_ratio(),
+               total.copies_ratio())
+    }
+}
+
+/// One of the dedup tables of the pool: the entries of blocks of one class, written with one
+/// checksum
+#[derive(Clone, Debug)]
+pub struct DdtTable {
+    /// The name of its MOS directory entry
+    pub name: String,
+    pub checksum: u64,
+    pub class: DdtClass,
+    /// Its MOS object, a ZAP
+    pub object: u64,
+    pub entries: u64,
+    pub histogram: DdtHistogram,
+}
+
+/// The name of the MOS directory entry of the table of `class` for checksum `checksum`
+fn table_name(checksum: u64, class: DdtClass) -> String {
+    let checksum = checksum::info(checksum).map_or("", |info| info.name);
+    format!("{}{}-{}-{}", DDT_PREFIX, checksum, DDT_TYPE_ZAP, class.name())
+}
+
+fn parse_name(name: &str) -> Option<(u64, DdtClass)> {
+    let mut parts = name[DDT_PREFIX.len()..].split('-');
+    let (checksum, table_type, class) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(checksum), Some(table_type), Some(class)) => (checksum, table_type, class),
+        _ => return None,
+    };
+    if table_type != DDT_TYPE_ZAP || parts.next().is_some() {
+        return None;
+    }
+    let checksum = (0..).take_while(|&id| checksum::info(id).is_some())
+                        .find(|&id| checksum::info(id).unwrap().name == checksum);
+    match (checksum, DdtClass::from_name(class)) {
+        (Some(checksum), Some(class)) => Some((checksum, class)),
+        _ => None,
+    }
+}
+
+/// The dedup tables of the pool, with their histograms. The histograms are the ones the pool
+/// keeps in `DDT-statistics`; a table that has none there has its entries read to compute it.
+pub fn tables(reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<Vec<DdtTable>> {
+    let directory: Vec<_> = try!(zap::iter(reader, mos, DMU_POOL_DIRECTORY_OBJECT)
+                                     .map_err(|_| zfs::Error::Invalid))
+                                .collect();
+    let stats = directory.iter()
+                         .find(|&&(ref name, _)| name == DMU_POOL_DDT_STATS)
+                         .map(|&(_, object)| object);
+
+    let mut tables = Vec::new();
+    for (name, object) in directory {
+        if !name.starts_with(DDT_PREFIX) || name == DMU_POOL_DDT_STATS {
+            continue;
+        }
+        let (checksum, class) = try!(parse_name(&name).ok_or(zfs::Error::Invalid));
+        let entries = try!(zap::count(reader, mos, object).map_err(|_| zfs::Error::Invalid));
+        let mut table = DdtTable {
+            name: name,
+            checksum: checksum,
+            class: class,
+            object: object,
+            entries: entries,
+            histogram: DdtHistogram::new(),
+        };
+
+        let stored = match stats {
+            Some(stats) => {
+                match zap::lookup_value(reader, mos, stats, &table.name) {
+                    Ok(value) => Some(try!(value.as_ints().ok_or(zfs::Error::Invalid)).to_vec()),
+                    Err(_) => None,
+                }
+            }
+            None => None,
+        };
+        table.histogram = match stored {
+            Some(ints) => try!(DdtHistogram::from_ints(&ints)),
+            None => {
+                let mut histogram = DdtHistogram::new();
+                for entry in try!(self::entries(reader, mos, &table)) {
+                    histogram.add_entry(&entry);
+                }
+                histogram
+            }
+        };
+        tables.push(table);
+    }
+    Ok(tables)
+}
+
+/// The entries of `table`. The duplicate class has those of the blocks referenced more than
+/// once.
+pub fn entries(reader: &mut ZfsReader,
+               mos: &ObjectSet,
+               table: &DdtTable)
+               -> zfs::Result<Vec<DdtEntry>> {
+    if table.entries == 0 {
+        return Ok(Vec::new());
+    }
+    let entries = try!(zap::iter_uint64_keys(reader, mos, table.object)
+                           .map_err(|_| zfs::Error::Invalid));
+    entries.map(|(key, value)| DdtEntry::from_zap(&key, &value)).collect()
+}
+
+/// The dedup table of the blocks written with one checksum, all classes together, kept in memory
+/// as blocks are written and freed. What changes is written back to the tables in the MOS as
+/// the txg syncs (`sync`).
+pub struct Ddt {
+    pub checksum: u64,
+    entries: BTreeMap<DdtKey, DdtEntry>,
+    /// The MOS objects of the tables of each class the pool has
+    tables: BTreeMap<DdtClass, u64>,
+    /// The class of the table each entry is in on the disk
+    stored: BTreeMap<DdtKey, DdtClass>,
+    /// The entries changed since the last sync
+    dirty: BTreeSet<DdtKey>,
+}
+
+impl Ddt {
+    /// An empty table for blocks written with `checksum`, which has to be strong enough to
+    /// dedup with (and one we can compute)
+    pub fn new(checksum: u64) -> zfs::Result<Self> {
+        match checksum::info(checksum) {
+            Some(info) if info.dedup && info.new.is_some() => {
+                Ok(Ddt {
+                    checksum: checksum,
+                    entries: BTreeMap::new(),
+                    tables: BTreeMap::new(),
+                    stored: BTreeMap::new(),
+                    dirty: BTreeSet::new(),
+                })
+            }
+            Some(_) => Err(zfs::Error::UnsupportedFeature),
+            None => Err(zfs::Error::Invalid),
+        }
+    }
+
+    /// The table of the pool for blocks written with `checksum`, with the entries of its tables
+    /// of every class
+    pub fn load(reader: &mut ZfsReader, mos: &ObjectSet, checksum: u64) -> zfs::Result<Self> {
+        let mut ddt = try!(Ddt::new(checksum));
+        for table in try!(tables(reader, mos)).iter().filter(|table| table.checksum == checksum) {
+            ddt.tables.insert(table.class, table.object);
+            for entry in try!(entries(reader, mos, table)) {
+                ddt.stored.insert(entry.key, table.class);
+                ddt.entries.insert(entry.key, entry);
+            }
+        }
+        Ok(ddt)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn lookup(&self, key: &DdtKey) -> Option<&DdtEntry> {
+        self.entries.get(key)
+    }
+
+    /// Add a reference to the single copy of the block with `key`. Returns where it is, or None
+    /// if there's no such block yet.
+    pub fn add_ref(&mut self, key: &DdtKey) -> Option<DdtPhys> {
+        let phys = match self.entries.get_mut(key) {
+            Some(entry) => &mut entry.phys[DDT_PHYS_SINGLE],
+            None => return None,
+        };
+        if phys.phys_birth == 0 {
+            return None;
+        }
+        phys.refcnt += 1;
+        self.dirty.insert(*key);
+        Some(*phys)
+    }
+
+    /// Add the block with `key`, written in `txg` to `dva` as a single copy, with one reference
+    pub fn insert(&mut self, key: DdtKey, dva: DVAddr, txg: u64) {
+        let empty = DVAddr {
+            vdev: 0,
+            offset: 0,
+        };
+        let unused = DdtPhys {
+            dvas: [empty; 3],
+            refcnt: 0,
+            phys_birth: 0,
+        };
+        let entry = self.entries.entry(key).or_insert_with(|| {
+            DdtEntry {
+                key: key,
+                phys: [unused; DDT_PHYS_TYPES],
+            }
+        });
+        entry.phys[DDT_PHYS_SINGLE] = DdtPhys {
+            dvas: [dva, empty, empty],
+            refcnt: 1,
+            phys_birth: txg,
+        };
+        self.dirty.insert(key);
+    }
+
+    /// Drop the reference of `block_ptr`, a block pointer to a block of the table. Returns
+    /// whether that was the last one, so the block is to be freed. A block the table doesn't
+    /// know is never freed, since other block pointers could still point at it.
+    pub fn release(&mut self, block_ptr: &BlockPtr) -> bool {
+        let key = DdtKey::of(block_ptr);
+        let dvas = block_ptr.dvas;
+        let freed = match self.entries.get_mut(&key) {
+            Some(entry) => {
+                match entry.phys.iter_mut().find(|phys| phys.phys_birth != 0 &&
+                                                        phys.dvas[0] == dvas[0]) {
+                    Some(phys) if phys.refcnt > 1 => {
+                        phys.refcnt -= 1;
+                        false
+                    }
+                    Some(phys) => {
+                        phys.refcnt = 0;
+                        phys.phys_birth = 0;
+                        true
+                    }
+                    None => return false,
+                }
+            }
+            None => return false,
+        };
+        self.dirty.insert(key);
+        if self.entries[&key].phys.iter().all(|phys| phys.phys_birth == 0) {
+            self.entries.remove(&key);
+        }
+        freed
+    }
+
+    /// Write the entries changed since the last sync to the tables in the MOS, through `tx`, a
+    /// transaction of the syncing txg (`ddt_sync_table`). Each entry goes in the table of its
+    /// class, out of the one it was in. Tables are created with their first entry and destroyed
+    /// with their last, and their histograms in `DDT-statistics` are brought up to date.
+    pub fn sync(&mut self,
+                reader: &mut ZfsReader,
+                dsl_pool: &mut DslPool,
+                mos: &ObjectSet,
+                tx: &Tx)
+                -> zfs::Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        for key in mem::replace(&mut self.dirty, BTreeSet::new()) {
+            let entry = self.entries.get(&key).map(|entry| (entry.class(), entry.to_zap()));
+            let stored = self.stored.get(&key).cloned();
+            if let Some(class) = stored {
+                if entry.as_ref().map(|&(class, _)| class) != stored {
+                    let table = self.tables[&class];
+                    try!(zap::remove_uint64(reader, dsl_pool, mos, table, &key.words(), tx));
+                    self.stored.remove(&key);
+                }
+            }
+            let (class, value) = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let table = match self.tables.get(&class) {
+                Some(&table) => table,
+                None => try!(self.create_table(reader, dsl_pool, mos, class, tx)),
+            };
+            let value: Vec<u64> = value.iter().map(|&byte| byte as u64).collect();
+            try!(zap::update_uint64(reader, dsl_pool, mos, table, &key.words(), 1, &value, tx));
+            self.stored.insert(key, class);
+        }
+
+        let stats = try!(stats_object(reader, dsl_pool, mos, tx));
+        let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+        for (class, table) in self.tables.clone() {
+            let name = table_name(self.checksum, class);
+            let mut histogram = DdtHistogram::new();
+            for (key, _) in self.stored.iter().filter(|&(_, &stored)| stored == class) {
+                histogram.add_entry(&self.entries[key]);
+            }
+            if histogram.total().blocks != 0 {
+                try!(zap::update(reader, dsl_pool, mos, stats, &name, 8, &histogram.to_ints(), tx));
+                continue;
+            }
+            // The table is empty (`ddt_object_destroy`)
+            dsl_pool.free_object(txg, MOS_OBJSET, table);
+            try!(zap::remove(reader, dsl_pool, mos, DMU_POOL_DIRECTORY_OBJECT, &name, tx));
+            match zap::remove(reader, dsl_pool, mos, stats, &name, tx) {
+                Ok(_) | Err(zfs::Error::NoEntity) => {}
+                Err(e) => return Err(e),
+            }
+            self.tables.remove(&class);
+        }
+        Ok(())
+    }
+
+    /// Create the table of `class`, an empty ZAP keyed by the entries' keys, whose first word is
+    /// their hash already (`ddt_object_create`)
+    fn create_table(&mut self,
+                    reader: &mut ZfsReader,
+                    dsl_pool: &mut DslPool,
+                    mos: &ObjectSet,
+                    class: DdtClass,
+                    tx: &Tx)
+                    -> zfs::Result<u64> {
+        let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+        let flags = zap::ZAP_FLAG_HASH64 | zap::ZAP_FLAG_UINT64_KEY | zap::ZAP_FLAG_PRE_HASHED_KEY;
+        let table = try!(create_object(reader, dsl_pool, mos, DMU_OT_DDT_ZAP, tx));
+        try!(zap::create_flags(dsl_pool,
+                               txg,
+                               MOS_OBJSET,
+                               table,
+                               DMU_OT_DDT_ZAP,
+                               flags,
+                               DDT_ZAP_BLOCK_SHIFT));
+        let name = table_name(self.checksum, class);
+        try!(zap::add(reader, dsl_pool, mos, DMU_POOL_DIRECTORY_OBJECT, &name, table, tx));
+        self.tables.insert(class, table);
+        Ok(table)
+    }
+
+    /// The histogram of the table as it is now
+    pub fn histogram(&self) -> DdtHistogram {
+        let mut histogram = DdtHistogram::new();
+        for entry in self.entries.values() {
+            histogram.add_entry(entry);
+        }
+        histogram
+    }
+}
+
+/// The `DDT-statistics` ZAP of the pool, created if it has none yet
+fn stats_object(reader: &mut ZfsReader,
+                dsl_pool: &mut DslPool,
+                mos: &ObjectSet,
+                tx: &Tx)
+                -> zfs::Result<u64> {
+    let directory = try!(zap::pending_entries(reader,
+                                              dsl_pool,
+                                              mos,
+                                              MOS_OBJSET,
+                                              DMU_POOL_DIRECTORY_OBJECT));
+    if let Some(&(_, stats)) = directory.iter().find(|&&(ref name, _)| name == DMU_POOL_DDT_STATS) {
+        return Ok(stats);
+    }
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    let stats = try!(create_object(reader, dsl_pool, mos, DMU_OT_DDT_STATS, tx));
+    try!(zap::create(dsl_pool, txg, MOS_OBJSET, stats, DMU_OT_DDT_STATS, &[]));
+    try!(zap::add(reader, dsl_pool, mos, DMU_POOL_DIRECTORY_OBJECT, DMU_POOL_DDT_STATS, stats, tx));
+    Ok(stats)
+}
+
+/// Take a free object of the MOS for an object of type `object_type`, which is made after
+fn create_object(reader: &mut ZfsReader,
+                 dsl_pool: &mut DslPool,
+                 mos: &ObjectSet,
+                 object_type: u8,
+                 tx: &Tx)
+                 -> zfs::Result<u64> {
+    let mut taken = DNodePhys::new(ObjectType::None, 512, ObjectType::None, &[]);
+    taken.object_type = object_type;
+    dmu::create_object(reader, dsl_pool, mos, taken, tx)
+}
+
+/// The histogram of all the dedup tables together, whose total gives the pool's dedup ratio
+pub fn histogram(tables: &[DdtTable]) -> DdtHistogram {
+    let mut histogram = DdtHistogram::new();
+    for table in tables {
+        histogram.add(&table.histogram);
+    }
+    histogram
+}
+
+#[test]
+fn test_ddt_entry() {
+    // A 4K block compressed to 1K, with one copy of 2 sectors referenced 3 times
+    let key = [1, 2, 3, 4, 2 << 32 | 1 << 16 | 7];
+    let mut phys = vec![0u8; DDT_PHYS_TYPES * DDT_PHYS_SIZE];
+    let single = &mut phys[DDT_PHYS_SIZE..2 * DDT_PHYS_SIZE];
+    single[0] = 2; // asize
+    single[8] = 100; // offset
+    single[48] = 3; // refcnt
+    single[56] = 9; // phys_birth
+
+    // Zero-length encoded: the leading zeros, the DVA, zeros, then the counts
+    let mut value = vec![DDT_COMPRESS_BYTEORDER_MASK | zio_compress::ZIO_COMPRESS_ZLE as u8];
+    value.extend_from_slice(&[64 + 63, 8, 2, 0, 0, 0, 0, 0, 0, 0, 100]);
+    value.extend_from_slice(&[64 + 38, 8, 3, 0, 0, 0, 0, 0, 0, 0, 9]);
+    value.push(64 + 134);
+    let entry = DdtEntry::from_zap(&key, &value).unwrap();
+    assert_eq!(entry.key.lsize(), 4096);
+    assert_eq!(entry.key.psize(), 1024);
+    assert_eq!(entry.key.compression(), 2);
+    assert_eq!(entry.phys[1].dvas[0].offset(), 100);
+    assert_eq!((entry.phys[1].dsize(), entry.refcnt()), (1024, 3));
+    assert_eq!(entry.phys[0].phys_birth, 0);
+
+    // Stored as it is, big endian
+    let mut value = vec![zio_compress::ZIO_COMPRESS_OFF as u8];
+    value.extend_from_slice(&phys);
+    for word in value[1..].chunks_mut(8) {
+        word.reverse();
+    }
+    assert_eq!(DdtEntry::from_zap(&key, &value).unwrap(), entry);
+    assert!(DdtEntry::from_zap(&key[..4], &value).is_err());
+    assert!(DdtEntry::from_zap(&key, &value[..100]).is_err());
+
+    let mut histogram = DdtHistogram::new();
+    histogram.add_entry(&entry);
+    histogram.add_entry(&entry);
+    let stat = DdtStat {
+        blocks: 2,
+        lsize: 8192,
+        psize: 2048,
+        dsize: 2048,
+        ref_blocks: 6,
+        ref_lsize: 24576,
+        ref_psize: 6144,
+        ref_dsize: 6144,
+    };
+    assert_eq!(histogram.buckets[1], stat);
+    assert_eq!(histogram.total(), stat);
+    let total = histogram.total();
+    assert_eq!((total.dedup_ratio(), total.compress_ratio(), total.copies_ratio()),
+               (3.0, 4.0, 1.0));
+    assert!(histogram.to_string().ends_with("dedup = 3.00, compress = 4.00, copies = 1.00"));
+
+    assert_eq!(parse_name("DDT-sha256-zap-duplicate"), Some((8, DdtClass::Duplicate)));
+    assert_eq!(parse_name("DDT-sha256-zap"), None);
+    assert_eq!(parse_name("DDT-crc-zap-unique"), None);
+}
+
+#[test]
+fn test_ddt_sync() {
+    use super::dsl_dataset::Dataset;
+    use super::spa;
+    use super::test_util;
+    use super::zpl;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_ddt_sync");
+    let object = spa.create_dataset("tank/home").unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // Writes `f` through the pool's dedup table, as it's on the disk
+    let write = |spa: &mut spa::Spa, f: &Fn(&mut ZfsReader, &mut DslPool, &ObjectSet, &Dataset)| {
+        let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+        {
+            let (reader, mos) = spa.reader_and_mos().unwrap();
+            dsl_pool.set_ddt(Ddt::load(reader, mos, 8).unwrap()); // sha256
+            let dataset = DslPool::open(reader, mos)
+                              .unwrap()
+                              .open_dataset(reader, mos, "tank/home", false)
+                              .unwrap();
+            dsl_pool.set_dedup(object, true);
+            f(reader, &mut dsl_pool, mos, &dataset);
+        }
+        *spa.dsl_pool_mut() = dsl_pool;
+    };
+    // The tables of the pool, and the class and refcnt of the entries of the file's blocks. The
+    // dataset's metadata is deduplicated too.
+    let tables = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let tables = tables(reader, mos).unwrap();
+        let ddt = Ddt::load(reader, mos, 8).unwrap();
+        assert_eq!(tables.iter().map(|table| table.entries).sum::<u64>(), ddt.len() as u64);
+        let file: BTreeMap<_, _> = ddt.entries
+                                      .values()
+                                      .filter(|entry| entry.key.lsize() == 128 << 10)
+                                      .map(|entry| (entry.key, (entry.class(), entry.refcnt())))
+                                      .collect();
+        (tables, file)
+    };
+
+    // Two blocks the same and one other
+    let mut data = vec![1; 256 << 10];
+    data.extend_from_slice(&[2; 128 << 10]);
+    write(&mut spa, &|reader, dsl_pool, mos, dataset| {
+        let file = zpl::create(reader, dsl_pool, mos, dataset, "/file", 0o644).unwrap();
+        zpl::write(reader, dsl_pool, dataset, file, 0, &data).unwrap();
+    });
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let (found, file) = tables(&mut spa);
+    let mut classes: Vec<_> = file.values().cloned().collect();
+    classes.sort();
+    assert_eq!(classes, vec![(DdtClass::Duplicate, 2), (DdtClass::Unique, 1)]);
+    let duplicate = found.iter().find(|table| table.class == DdtClass::Duplicate).unwrap();
+    assert_eq!((duplicate.entries, duplicate.histogram.total().ref_blocks), (1, 2));
+
+    // With a block of ones overwritten by the other block, the two change classes
+    write(&mut spa, &|reader, dsl_pool, _, dataset| {
+        let file = zpl::lookup(reader, dataset, "/file", true).unwrap();
+        zpl::write(reader, dsl_pool, dataset, file, 0, &[2; 128 << 10]).unwrap();
+    });
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let (_, moved) = tables(&mut spa);
+    assert_eq!(moved.len(), 2);
+    for (key, &(class, refcnt)) in &file {
+        let swapped = match class {
+            DdtClass::Unique => DdtClass::Duplicate,
+            _ => DdtClass::Unique,
+        };
+        assert_eq!(moved[key], (swapped, 3 - refcnt));
+    }
+
+    // Once the file is gone, so are its entries, and the table of duplicates with them
+    write(&mut spa, &|reader, dsl_pool, _, dataset| {
+        zpl::unlink(reader, dsl_pool, dataset, "/file").unwrap();
+    });
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let (found, file) = tables(&mut spa);
+    assert!(file.is_empty());
+    assert!(found.iter().all(|table| table.class == DdtClass::Unique));
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    let directory: Vec<_> = zap::iter(reader, mos, DMU_POOL_DIRECTORY_OBJECT).unwrap().collect();
+    assert!(!directory.iter().any(|&(ref name, _)| name == "DDT-sha256-zap-duplicate"));
+}
@@ -0,0 +1,461 @@
+use std::io::Write;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::dmu_objset::ObjectSet;
+use super::dnode::{DNodePhys, DNODE_FLAG_USED_BYTES};
+use super::nvpair::{NvList, NvValue};
+use super::spa_config;
+use super::traverse::{self, ObjectFilter};
+use super::uberblock::Uberblock;
+use super::zap::{self, ZapValue};
+use super::zfs;
+use super::zio;
+
+// Names of the on-disk ids, as zdb prints them
+const OBJECT_TYPES: &'static [&'static str] = &["unallocated",
+                                                "object directory",
+                                                "object array",
+                                                "packed nvlist",
+                                                "packed nvlist size",
+                                                "bpobj",
+                                                "bpobj header",
+                                                "SPA space map header",
+                                                "SPA space map",
+                                                "ZIL intent log",
+                                                "DMU dnode",
+                                                "DMU objset",
+                                                "DSL directory",
+                                                "DSL directory child map",
+                                                "DSL dataset snap map",
+                                                "DSL props",
+                                                "DSL dataset",
+                                                "ZFS znode",
+                                                "ZFS V0 ACL",
+                                                "ZFS plain file",
+                                                "ZFS directory",
+                                                "ZFS master node",
+                                                "ZFS delete queue",
+                                                "zvol object",
+                                                "zvol prop",
+                                                "other uint8[]",
+                                                "other uint64[]",
+                                                "other ZAP",
+                                                "persistent error log",
+                                                "SPA history",
+                                                "SPA history offsets",
+                                                "Pool properties",
+                                                "DSL permissions",
+                                                "ZFS ACL",
+                                                "ZFS SYSACL",
+                                                "FUID table",
+                                                "FUID table size",
+                                                "DSL dataset next clones",
+                                                "scan work queue",
+                                                "ZFS user/group used",
+                                                "ZFS user/group quota",
+                                                "snapshot refcount tags",
+                                                "DDT ZAP algorithm",
+                                                "DDT statistics",
+                                                "System attributes",
+                                                "SA master node",
+                                                "SA attr registration",
+                                                "SA attr layouts",
+                                                "scan translations",
+                                                "deduplicated block",
+                                                "DSL deadlist map",
+                                                "DSL deadlist map hdr",
+                                                "DSL dir clones",
+                                                "bpobj subobj"];
+// Newer object types are named after their byteswap function
+const BYTESWAP_FUNCTIONS: &'static [&'static str] = &["uint8", "uint16", "uint32", "uint64",
+                                                      "zap", "dnode", "objset", "znode",
+                                                      "oldacl", "acl"];
+const CHECKSUMS: &'static [&'static str] = &["inherit", "on", "off", "label", "gang_header",
+                                             "zilog", "fletcher2", "fletcher4", "sha256",
+                                             "zilog2", "noparity", "sha512", "skein", "edonr"];
+const COMPRESSIONS: &'static [&'static str] = &["inherit", "on", "uncompressed", "lzjb",
+                                                "empty", "gzip-1", "gzip-2", "gzip-3", "gzip-4",
+                                                "gzip-5", "gzip-6", "gzip-7", "gzip-8", "gzip-9",
+                                                "zle", "lz4", "zstd"];
+
+/// Bit of new style object types (`DMU_OT_NEWTYPE`), and the byteswap function in their low bits
+const DMU_OT_NEWTYPE: u64 = 0x80;
+const DMU_OT_BYTESWAP_MASK: u64 = 0x1F;
+
+fn name(names: &[&str], id: u64) -> String {
+    match names.get(id as usize) {
+        Some(name) => name.to_string(),
+        None => format!("unknown ({})", id),
+    }
+}
+
+pub fn object_type_name(object_type: u64) -> String {
+    if object_type & DMU_OT_NEWTYPE != 0 {
+        name(BYTESWAP_FUNCTIONS, object_type & DMU_OT_BYTESWAP_MASK)
+    } else {
+        name(OBJECT_TYPES, object_type)
+    }
+}
+
+/// Sizes the way zdb prints them: 512, 1.50K, 128K, ...
+fn nicenum(num: u64) -> String {
+    let units = ["", "K", "M", "G", "T", "P", "E"];
+    let mut unit = 0;
+    while unit + 1 < units.len() && num >= 1024 << (unit * 10) {
+        unit += 1;
+    }
+    if unit == 0 {
+        num.to_string()
+    } else if num % (1 << (unit * 10)) == 0 {
+        format!("{}{}", num >> (unit * 10), units[unit])
+    } else {
+        format!("{:.2}{}", num as f64 / (1u64 << (unit * 10)) as f64, units[unit])
+    }
+}
+
+/// A block pointer on one line, in the notation of zdb (`snprintf_blkptr`). DVAs are
+/// `<vdev:offset:asize>`, with the offset and the allocated size in bytes, and sizes are given
+/// logical (`L`) and physical (`P`).
+pub fn format_block_ptr(block_ptr: &BlockPtr) -> String {
+    let kind = format!("[L{} {}]", block_ptr.level(), object_type_name(block_ptr.object_type()));
+    if block_ptr.is_embedded() {
+        return format!("EMBEDDED et={} {} {} size={:x}L/{:x}P birth={}L",
+                       block_ptr.embedded_type(),
+                       kind,
+                       name(COMPRESSIONS, block_ptr.compression()),
+                       block_ptr.embedded_lsize(),
+                       block_ptr.embedded_psize(),
+                       { block_ptr.birth_txg });
+    }
+    if block_ptr.is_hole() {
+        return format!("HOLE {} size={:x}L birth={}L",
+                       kind,
+                       block_ptr.lsize() * 512,
+                       { block_ptr.birth_txg });
+    }
+
+    let mut line = String::new();
+    let dvas = block_ptr.dvas;
+    let mut copies = 0;
+    for (i, dva) in dvas[..block_ptr.max_copies()]
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, dva)| !dva.is_empty()) {
+        line.push_str(&format!("DVA[{}]=<{}:{:x}:{:x}> ",
+                               i,
+                               dva.vdev_id(),
+                               dva.offset() << 9,
+                               dva.asize() << 9));
+        copies += 1;
+    }
+    // The third DVA of encrypted blocks holds their salt and IV
+    if block_ptr.is_encrypted() {
+        line.push_str(&format!("salt={:x} iv={:x}:{:x} ",
+                               { dvas[2].vdev },
+                               { dvas[2].offset },
+                               block_ptr.fill_count >> 32));
+    }
+    let phys_birth = block_ptr.physical_birth();
+    let checksum = block_ptr.checksum;
+    line.push_str(&format!("{} {} {} {} {} {} {} size={:x}L/{:x}P birth={}L/{}P fill={} \
+                            cksum={:x}:{:x}:{:x}:{:x}",
+                           kind,
+                           name(CHECKSUMS, block_ptr.checksum()),
+                           name(COMPRESSIONS, block_ptr.compression()),
+                           if block_ptr.byte_order() == 1 { "LE" } else { "BE" },
+                           if dvas[0].gang() { "gang" } else { "contiguous" },
+                           if block_ptr.dedup() { "dedup" } else { "unique" },
+                           ["single", "double", "triple"][copies - 1],
+                           block_ptr.lsize() * 512,
+                           block_ptr.psize() * 512,
+                           { block_ptr.birth_txg },
+                           phys_birth,
+                           block_ptr.fill(),
+                           checksum[0],
+                           checksum[1],
+                           checksum[2],
+                           checksum[3]));
+    line
+}
+
+pub fn dump_uberblock(out: &mut Write, uberblock: &Uberblock) -> zfs::Result<()> {
+    try!(writeln!(out, "    magic = {:016x}", { uberblock.magic }));
+    try!(writeln!(out, "    version = {}", uberblock.pool_version()));
+    try!(writeln!(out, "    txg = {}", { uberblock.txg }));
+    try!(writeln!(out, "    guid_sum = {}", { uberblock.guid_sum }));
+    try!(writeln!(out, "    timestamp = {}", { uberblock.timestamp }));
+    if uberblock.mmp_valid() {
+        try!(writeln!(out,
+                      "    mmp_delay = {}, mmp_interval = {:?}, mmp_seq = {:?}",
+                      { uberblock.mmp_delay },
+                      uberblock.mmp_interval(),
+                      uberblock.mmp_seq()));
+    }
+    if uberblock.checkpoint_txg != 0 {
+        try!(writeln!(out, "    checkpoint_txg = {}", { uberblock.checkpoint_txg }));
+    }
+    try!(writeln!(out, "    rootbp = {}", format_block_ptr(&uberblock.rootbp)));
+    Ok(())
+}
+
+/// Every valid uberblock in the uberblock rings of the four labels of `zio`'s device, with the
+/// one the pool would be imported from marked active
+pub fn dump_uberblocks(out: &mut Write, zio: &zio::Reader) -> zfs::Result<()> {
+    let active = zio.uber_search().ok().map(|search| search.uberblock);
+    let is_active = |uberblock: &Uberblock| {
+        active.map_or(false, |active| {
+            (active.txg, active.timestamp, active.mmp_seq()) ==
+            (uberblock.txg, uberblock.timestamp, uberblock.mmp_seq())
+        })
+    };
+    for label in 0..zio::VDEV_LABELS {
+        match zio.label_uberblocks(label) {
+            Some(uberblocks) => {
+                try!(writeln!(out, "Label {}: {} uberblocks", label, uberblocks.len()));
+                for (slot, uberblock) in uberblocks {
+                    let marker = if is_active(&uberblock) { " (active)" } else { "" };
+                    try!(writeln!(out, "  Uberblock[{}]{}", slot, marker));
+                    try!(dump_uberblock(out, &uberblock));
+                }
+            }
+            None => try!(writeln!(out, "Label {}: not on the device", label)),
+        }
+    }
+    Ok(())
+}
+
+fn nv_value(value: &NvValue) -> String {
+    fn join<T: ToString>(values: &[T]) -> String {
+        values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(" ")
+    }
+    match *value {
+        NvValue::Boolean => "true".to_owned(),
+        NvValue::BooleanValue(v) => v.to_string(),
+        NvValue::Byte(v) | NvValue::Uint8(v) => v.to_string(),
+        NvValue::Int8(v) => v.to_string(),
+        NvValue::Int16(v) => v.to_string(),
+        NvValue::Uint16(v) => v.to_string(),
+        NvValue::Int32(v) => v.to_string(),
+        NvValue::Uint32(v) => v.to_string(),
+        NvValue::Int64(v) | NvValue::HrTime(v) => v.to_string(),
+        NvValue::Uint64(v) => v.to_string(),
+        NvValue::String(ref v) => format!("'{}'", v),
+        NvValue::ByteArray(ref v) | NvValue::Uint8Array(ref v) => join(v),
+        NvValue::Int8Array(ref v) => join(v),
+        NvValue::Int16Array(ref v) => join(v),
+        NvValue::Uint16Array(ref v) => join(v),
+        NvValue::Int32Array(ref v) => join(v),
+        NvValue::Uint32Array(ref v) => join(v),
+        NvValue::Int64Array(ref v) => join(v),
+        NvValue::Uint64Array(ref v) => join(v),
+        NvValue::BooleanArray(ref v) => join(v),
+        NvValue::StringArray(ref v) => {
+            v.iter().map(|v| format!("'{}'", v)).collect::<Vec<_>>().join(" ")
+        }
+        NvValue::NvList(_) | NvValue::NvListArray(_) | NvValue::Unknown => "?".to_owned(),
+    }
+}
+
+/// An nvlist, one pair per line, with nested nvlists indented under their names the way zdb
+/// prints labels
+pub fn dump_nv_list(out: &mut Write, nv_list: &NvList, indent: usize) -> zfs::Result<()> {
+    for &(ref name, ref value) in &nv_list.pairs {
+        match *value {
+            NvValue::NvList(ref nv_list) => {
+                try!(writeln!(out, "{:2$}{}:", "", name, indent));
+                try!(dump_nv_list(out, nv_list, indent + 4));
+            }
+            NvValue::NvListArray(ref nv_lists) => {
+                for (i, nv_list) in nv_lists.iter().enumerate() {
+                    try!(writeln!(out, "{:3$}{}[{}]:", "", name, i, indent));
+                    try!(dump_nv_list(out, nv_list, indent + 4));
+                }
+            }
+            _ => try!(writeln!(out, "{:3$}{}: {}", "", name, nv_value(value), indent)),
+        }
+    }
+    Ok(())
+}
+
+/// The config nvlists of the four labels of `zio`'s device. Labels that can't be read or
+/// decoded are said so, and the others still dumped.
+pub fn dump_labels(out: &mut Write, zio: &zio::Reader) -> zfs::Result<()> {
+    for label in 0..zio::VDEV_LABELS {
+        try!(writeln!(out, "LABEL {}", label));
+        match spa_config::read_label_nv_list(zio, label) {
+            Ok(nv_list) => try!(dump_nv_list(out, &nv_list, 4)),
+            Err(e) => try!(writeln!(out, "    failed to read label {}: {}", label, e)),
+        }
+    }
+    Ok(())
+}
+
+/// The entries of the MOS object directory (object 1), which point at everything else in the
+/// pool: the root dataset, the config, the feature ZAPs, ...
+pub fn dump_object_directory(out: &mut Write,
+                             reader: &mut ZfsReader,
+                             mos: &ObjectSet)
+                             -> zfs::Result<()> {
+    try!(writeln!(out, "Object directory:"));
+    let entries = match zap::iter_values(reader, mos, 1) {
+        Ok(entries) => entries,
+        Err(e) => {
+            try!(writeln!(out, "    failed to read the object directory: {}", e));
+            return Ok(());
+        }
+    };
+    for (name, value) in entries {
+        let value = match value {
+            ZapValue::Int(value) => value.to_string(),
+            ZapValue::Array(values) => {
+                values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(" ")
+            }
+            ZapValue::String(value) => format!("'{}'", value),
+            ZapValue::Bytes(bytes) => format!("{:?}", bytes),
+        };
+        try!(writeln!(out, "    {} = {}", name, value));
+    }
+    Ok(())
+}
+
+/// Object `object` of `objset`: its dnode, and its block pointers if `blocks` is set
+pub fn dump_object(out: &mut Write,
+                   reader: &mut ZfsReader,
+                   objset: &ObjectSet,
+                   object: u64,
+                   blocks: bool)
+                   -> zfs::Result<()> {
+    let dnode = match objset.dnode(reader, object) {
+        Ok(dnode) => dnode,
+        Err(e) => {
+            try!(writeln!(out, "failed to read object {}: {}", object, e));
+            return Ok(());
+        }
+    };
+    try!(dump_dnode(out, object, &dnode));
+    if blocks {
+        try!(dump_block_tree(out, &mut reader.zio, object, &dnode));
+    }
+    Ok(())
+}
+
+/// A dnode in zdb's object table layout: levels, block sizes, space used, how full it is and
+/// its type, then the bonus buffer's type and length
+pub fn dump_dnode(out: &mut Write, object: u64, dnode: &DNodePhys) -> zfs::Result<()> {
+    let used = if dnode.flags & DNODE_FLAG_USED_BYTES != 0 {
+        dnode.used
+    } else {
+        dnode.used * 512
+    };
+    let lsize = (dnode.maxblkid + 1) * dnode.data_block_size();
+    let full = if dnode.nlevels == 0 {
+        0.0
+    } else {
+        dnode.fill_count() as f64 * 100.0 / (dnode.maxblkid + 1) as f64
+    };
+    try!(writeln!(out,
+                  "{:>10} {:>4} {:>6} {:>6} {:>6} {:>6} {:>7} {}",
+                  "Object",
+                  "lvl",
+                  "iblk",
+                  "dblk",
+                  "dsize",
+                  "lsize",
+                  "%full",
+                  "type"));
+    try!(writeln!(out,
+                  "{:>10} {:>4} {:>6} {:>6} {:>6} {:>6} {:>7.2} {}",
+                  object,
+                  dnode.nlevels,
+                  nicenum(1 << dnode.indblkshift),
+                  nicenum(dnode.data_block_size()),
+                  nicenum(used),
+                  nicenum(lsize),
+                  full,
+                  object_type_name(dnode.object_type as u64)));
+    try!(writeln!(out,
+                  "{:>10} bonus {} ({} bytes), checksum {}, compression {}, {} blkptrs",
+                  "",
+                  object_type_name(dnode.bonus_type as u64),
+                  { dnode.bonus_len },
+                  name(CHECKSUMS, dnode.checksum as u64),
+                  name(COMPRESSIONS, dnode.compress as u64),
+                  dnode.nblkptr));
+    Ok(())
+}
+
+/// Every block pointer under `dnode` that isn't a hole, top level first, each indented by its
+/// level and led by the offset of the object's data it covers. Stops at the first indirect
+/// block that can't be read.
+pub fn dump_block_tree(out: &mut Write,
+                       reader: &mut zio::Reader,
+                       object: u64,
+                       dnode: &DNodePhys)
+                       -> zfs::Result<()> {
+    let (epbs, block_size, nlevels) = (dnode.epbs(), dnode.data_block_size(), dnode.nlevels);
+    let mut result = Ok(());
+    try!(traverse::traverse_object(reader,
+                                   dnode,
+                                   object,
+                                   ObjectFilter::All,
+                                   &mut |_, bookmark, block_ptr| {
+        if result.is_err() {
+            return;
+        }
+        let offset = (bookmark.blkid << (bookmark.level * epbs)) * block_size;
+        let indent = (nlevels as u64).saturating_sub(bookmark.level + 1) as usize;
+        result = writeln!(out,
+                          "{:>12x} {:3$}{}",
+                          offset,
+                          "",
+                          format_block_ptr(block_ptr),
+                          indent * 2);
+    }));
+    try!(result);
+    Ok(())
+}
+
+#[test]
+fn test_format_block_ptr() {
+    use super::dvaddr::DVAddr;
+
+    // An L0 dnode block, lz4 compressed from 16K to 512 bytes, with two copies
+    let mut block_ptr = BlockPtr::hole();
+    block_ptr.dvas[0] = DVAddr {
+        vdev: 1,
+        offset: 0x10D,
+    };
+    block_ptr.dvas[1] = DVAddr {
+        vdev: 0x0000000100000001,
+        offset: 0x8,
+    };
+    block_ptr.flags_size = 0x800A_070F_0000_001F;
+    block_ptr.birth_txg = 5;
+    block_ptr.fill_count = 3;
+    block_ptr.checksum = [0xa, 0xb, 0xc, 0xd];
+    assert_eq!(format_block_ptr(&block_ptr),
+               "DVA[0]=<0:21a00:200> DVA[1]=<1:1000:200> [L0 DMU dnode] fletcher4 lz4 LE \
+                contiguous unique double size=4000L/200P birth=5L/5P fill=3 cksum=a:b:c:d");
+
+    let hole = BlockPtr::hole();
+    assert_eq!(format_block_ptr(&hole), "HOLE [L0 unallocated] size=200L birth=0L");
+    assert_eq!(object_type_name(0x80 | 4), "zap");
+    assert_eq!((nicenum(512), nicenum(128 << 10), nicenum(1536)),
+               ("512".to_owned(), "128K".to_owned(), "1.50K".to_owned()));
+
+    let mut child = NvList::new(0);
+    child.add("type".to_owned(), NvValue::String("disk".to_owned()));
+    child.add("id".to_owned(), NvValue::Uint64(0));
+    let mut vdev_tree = NvList::new(0);
+    vdev_tree.add("ashift".to_owned(), NvValue::Uint64(9));
+    vdev_tree.add("children".to_owned(), NvValue::NvListArray(vec![child]));
+    let mut label = NvList::new(0);
+    label.add("name".to_owned(), NvValue::String("tank".to_owned()));
+    label.add("vdev_tree".to_owned(), NvValue::NvList(vdev_tree));
+    let mut out = Vec::new();
+    dump_nv_list(&mut out, &label, 0).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "name: 'tank'\nvdev_tree:\n    ashift: 9\n    children[0]:\n        \
+                type: 'disk'\n        id: 0\n");
+}
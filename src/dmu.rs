@@ -0,0 +1,660 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+
+use super::ZfsReader;
+use super::arcache::ArcBuf;
+use super::block_ptr::BlockPtr;
+use super::dmu_objset::ObjectSet;
+use super::dmu_tx::Tx;
+use super::dnode::{DNODE_FLAG_SPILL_BLKPTR, DNODE_MAX_SLOTS, DNODE_SIZE, DNodePhys};
+use super::dsl_pool::DslPool;
+use super::from_bytes::{self, ByteOrder, FromBytes};
+use super::zfs;
+use super::zio;
+
+enum Lookup {
+    Block(BlockPtr),
+    /// A hole in an indirect block covers the whole subtree below it, down to level 0
+    Hole { level: u64 },
+}
+
+fn lookup(reader: &mut ZfsReader, dnode: &DNodePhys, blkid: u64) -> Result<Lookup, String> {
+    let path = try!(dnode.block_path(blkid).ok_or(format!("Block {} out of range", blkid)));
+
+    let mut level = path.len() as u64 - 1;
+    let mut block_ptr = dnode.get_blockptr(path[0]);
+    for &index in &path[1..] {
+        if block_ptr.is_hole() {
+            return Ok(Lookup::Hole { level: level });
+        }
+        block_ptr = try!(reader.read_type_array(&block_ptr, index));
+        level -= 1;
+    }
+
+    if block_ptr.is_hole() {
+        Ok(Lookup::Hole { level: 0 })
+    } else {
+        Ok(Lookup::Block(block_ptr))
+    }
+}
+
+/// The block pointer of level 0 block `blkid` of the object described by `dnode`, `None` if the
+/// block is a hole
+pub fn block_ptr(reader: &mut ZfsReader,
+                 dnode: &DNodePhys,
+                 blkid: u64)
+                 -> Result<Option<BlockPtr>, String> {
+    match try!(lookup(reader, dnode, blkid)) {
+        Lookup::Block(block_ptr) => Ok(Some(block_ptr)),
+        Lookup::Hole { .. } => Ok(None),
+    }
+}
+
+/// If level 0 block `blkid` is a hole, the number of blocks from `blkid` on that are known to be
+/// holes without looking any further, i.e. up to the end of the biggest hole subtree it's in
+fn hole_span(reader: &mut ZfsReader, dnode: &DNodePhys, blkid: u64) -> Result<Option<u64>, String> {
+    match try!(lookup(reader, dnode, blkid)) {
+        Lookup::Block(_) => Ok(None),
+        Lookup::Hole { level } => {
+            let span = 1 << (dnode.epbs() * level);
+            Ok(Some(span - blkid % span))
+        }
+    }
+}
+
+/// The holes of an object, as (offset, length) byte ranges in ascending order. Only the blocks up
+/// to the last allocated one are looked at, everything past `allocated_size` is a hole too.
+pub struct Holes<'a> {
+    reader: &'a mut ZfsReader,
+    dnode: &'a DNodePhys,
+    blkid: u64,
+    end: u64,
+}
+
+impl<'a> Iterator for Holes<'a> {
+    type Item = Result<(u64, u64), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut hole: Option<(u64, u64)> = None;
+        while self.blkid < self.end {
+            match hole_span(self.reader, self.dnode, self.blkid) {
+                Ok(Some(span)) => {
+                    let end = cmp::min(self.blkid + span, self.end);
+                    hole = Some((hole.map(|(start, _)| start).unwrap_or(self.blkid), end));
+                    self.blkid = end;
+                }
+                Ok(None) => {
+                    self.blkid += 1;
+                    if hole.is_some() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    self.blkid = self.end;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let block_size = self.dnode.data_block_size();
+        hole.map(|(start, end)| Ok((start * block_size, (end - start) * block_size)))
+    }
+}
+
+/// Iterate over the holes of the object described by `dnode`
+pub fn holes<'a>(reader: &'a mut ZfsReader, dnode: &'a DNodePhys) -> Holes<'a> {
+    Holes {
+        reader: reader,
+        dnode: dnode,
+        blkid: 0,
+        end: if dnode.nlevels == 0 { 0 } else { dnode.maxblkid + 1 },
+    }
+}
+
+/// Read level 0 block `blkid` of the object described by `dnode`
+pub fn read_block(reader: &mut ZfsReader,
+                  dnode: &DNodePhys,
+                  blkid: u64)
+                  -> Result<Vec<u8>, String> {
+    read_block_order(reader, dnode, blkid).map(|(data, _)| data)
+}
+
+/// Read level 0 block `blkid` of the object described by `dnode`, along with the byte order it
+/// was written in
+pub fn read_block_order(reader: &mut ZfsReader,
+                        dnode: &DNodePhys,
+                        blkid: u64)
+                        -> Result<(Vec<u8>, ByteOrder), String> {
+    read_block_shared(reader, dnode, blkid).map(|(data, order)| (data.to_vec(), order))
+}
+
+/// Like `read_block_order`, but the data is shared with the ARC rather than copied out of it
+pub fn read_block_shared(reader: &mut ZfsReader,
+                         dnode: &DNodePhys,
+                         blkid: u64)
+                         -> Result<(ArcBuf, ByteOrder), String> {
+    match try!(block_ptr(reader, dnode, blkid)) {
+        Some(block_ptr) => {
+            let (data, _) = try!(reader.read_block_shared(&block_ptr));
+            Ok((data, block_ptr.data_order()))
+        }
+        // Holes read back as zeros
+        None => Ok((ArcBuf::from(vec![0; dnode.data_block_size() as usize]), ByteOrder::host())),
+    }
+}
+
+/// Tell the prefetcher about a read of the `nblks` level 0 blocks from `blkid` on of the object
+/// described by `dnode`, and if it's part of a sequential stream, start reading ahead of it
+fn zfetch(reader: &mut ZfsReader, dnode: &DNodePhys, blkid: u64, nblks: u64) {
+    let (start, end) = match reader.zfetch.access(dnode, blkid, nblks) {
+        Some(range) => range,
+        None => return,
+    };
+    let mut block_ptrs = Vec::new();
+    for blkid in start..end {
+        // The indirect blocks on the way are read synchronously. Prefetching is only a hint, the
+        // reads that need the blocks report the errors.
+        match block_ptr(reader, dnode, blkid) {
+            Ok(Some(block_ptr)) => block_ptrs.push(block_ptr),
+            Ok(None) => {}
+            Err(_) => break,
+        }
+    }
+    reader.arc.prefetch_blocks(&reader.zio, block_ptrs);
+}
+
+/// Read `len` bytes at `offset` of the object described by `dnode`. Sequential reads of an object
+/// are read ahead of (see `dmu_zfetch`).
+pub fn read(reader: &mut ZfsReader,
+            dnode: &DNodePhys,
+            offset: u64,
+            len: usize)
+            -> Result<Vec<u8>, String> {
+    let block_size = dnode.data_block_size();
+    if block_size == 0 {
+        return Err("Object has no data blocks".to_owned());
+    }
+
+    let mut data = Vec::with_capacity(len);
+    let mut pos = offset;
+    let end = offset + len as u64;
+    if len > 0 {
+        let blkid = offset / block_size;
+        zfetch(reader, dnode, blkid, (end - 1) / block_size + 1 - blkid);
+    }
+    while pos < end {
+        let (block, _) = try!(read_block_shared(reader, dnode, pos / block_size));
+        let start = (pos % block_size) as usize;
+        let count = cmp::min(end - pos, block_size - start as u64) as usize;
+        if block.len() < start + count {
+            return Err(format!("Short block at offset {}", pos));
+        }
+        data.extend_from_slice(&block[start..start + count]);
+        pos += count as u64;
+    }
+
+    Ok(data)
+}
+
+/// Read `len` bytes at `offset` of object `object` in `objset`. Objects bigger than a block keep
+/// their data behind up to 6 levels of indirect blocks, which are resolved through the ARC.
+pub fn read_object(reader: &mut ZfsReader,
+                   objset: &ObjectSet,
+                   object: u64,
+                   offset: u64,
+                   len: usize)
+                   -> Result<Vec<u8>, String> {
+    let dnode = try!(objset.dnode(reader, object));
+    read(reader, &dnode, offset, len)
+}
+
+/// Write `data` at `offset` of object `object` of `objset`, in the txg `tx` is assigned to. The
+/// write has to be covered by one of `tx`'s holds. The blocks are only dirtied in `dsl_pool`:
+/// they go to the disk when the txg is synced. Blocks written in part are read first, from the
+/// txgs in flight or else from the disk.
+pub fn write(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             objset: &ObjectSet,
+             object: u64,
+             offset: u64,
+             data: &[u8],
+             tx: &Tx)
+             -> zfs::Result<()> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    if !tx.holds_write(object, offset, data.len() as u64) {
+        return Err(zfs::Error::Invalid);
+    }
+    let dnode = match dsl_pool.pending_dnode(tx.objset(), object) {
+        Some(dnode) => dnode,
+        None => try!(objset.dnode(reader, object).map_err(|_| zfs::Error::NoEntity)),
+    };
+    let block_size = dnode.data_block_size();
+    if block_size == 0 {
+        return Err(zfs::Error::Invalid);
+    }
+
+    let mut pos = offset;
+    let end = offset + data.len() as u64;
+    while pos < end {
+        let blkid = pos / block_size;
+        let start = pos % block_size;
+        let count = cmp::min(end - pos, block_size - start);
+        let mut block = if count == block_size {
+            vec![0; block_size as usize]
+        } else if let Some(block) = dsl_pool.pending_block(tx.objset(), object, blkid) {
+            block.to_vec()
+        } else if dnode.nlevels > 0 && blkid <= dnode.maxblkid {
+            try!(read_block(reader, &dnode, blkid).map_err(|_| zfs::Error::Invalid))
+        } else {
+            vec![0; block_size as usize]
+        };
+        block.resize(block_size as usize, 0);
+        let from = (pos - offset) as usize;
+        block[start as usize..(start + count) as usize]
+            .copy_from_slice(&data[from..from + count as usize]);
+        dsl_pool.dirty_block(txg, tx.objset(), object, blkid, block);
+        pos += count;
+    }
+    Ok(())
+}
+
+/// Free `len` bytes at `offset` of object `object` of `objset`, in the txg `tx` is assigned to
+/// (`dmu_free_range`). The whole blocks of the range become holes (see `DslPool::dirty_hole`),
+/// the parts of the blocks at either end are written over with zeros. The blocks have to be
+/// covered by holds of `tx`, as far as the object has any, be they on the disk or in the txgs in
+/// flight.
+pub fn free(reader: &mut ZfsReader,
+            dsl_pool: &mut DslPool,
+            objset: &ObjectSet,
+            object: u64,
+            offset: u64,
+            len: u64,
+            tx: &Tx)
+            -> zfs::Result<()> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    let dnode = match dsl_pool.pending_dnode(tx.objset(), object) {
+        Some(dnode) => dnode,
+        None => try!(objset.dnode(reader, object).map_err(|_| zfs::Error::NoEntity)),
+    };
+    // The blocks the txgs in flight write count as allocated too
+    let mut extent = dnode;
+    if let Some(blkid) = dsl_pool.pending_max_blkid(tx.objset(), object) {
+        extent.maxblkid = cmp::max(dnode.maxblkid, blkid);
+    }
+    let range = free_range(&extent, offset, len);
+    let block_size = dnode.data_block_size();
+    for &(blkid, start, len) in &range.partial {
+        try!(write(reader,
+                   dsl_pool,
+                   objset,
+                   object,
+                   blkid * block_size + start,
+                   &vec![0; len as usize],
+                   tx));
+    }
+    for blkid in range.blkids.0..range.blkids.1 {
+        if !tx.holds_write(object, blkid * block_size, block_size) {
+            return Err(zfs::Error::Invalid);
+        }
+        let on_disk = dnode.nlevels > 0 && blkid <= dnode.maxblkid &&
+                      try!(block_ptr(reader, &dnode, blkid).map_err(|_| zfs::Error::Invalid))
+                          .is_some();
+        if on_disk || dsl_pool.pending_block(tx.objset(), object, blkid).is_some() {
+            dsl_pool.dirty_hole(txg, tx.objset(), object, blkid);
+        }
+    }
+    Ok(())
+}
+
+/// Create an object described by `dnode` in `objset`, in the txg `tx` is assigned to, at the
+/// lowest object number that's free in the txgs in flight as well as on the disk
+/// (`dmu_object_alloc`). Returns the object number.
+pub fn create_object(reader: &mut ZfsReader,
+                     dsl_pool: &mut DslPool,
+                     objset: &ObjectSet,
+                     dnode: DNodePhys,
+                     tx: &Tx)
+                     -> zfs::Result<u64> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    let meta_dnode = &objset.phys.meta_dnode;
+    let on_disk = if meta_dnode.nlevels > 0 { allocated_size(meta_dnode) / DNODE_SIZE } else { 0 };
+    let mut object = 1;
+    loop {
+        let used = match dsl_pool.pending_dnode(tx.objset(), object) {
+            Some(used) => used,
+            None if object < on_disk => {
+                try!(objset.dnode(reader, object).map_err(|_| zfs::Error::Invalid))
+            }
+            None => break,
+        };
+        if used.object_type == 0 {
+            break;
+        }
+        // A large dnode takes up the slots after it too
+        object += 1 + used.extra_slots as u64;
+    }
+    dsl_pool.dirty_dnode(txg, tx.objset(), object, dnode);
+    Ok(object)
+}
+
+/// Number of bytes covered by the object's allocated blocks. The real (ZPL) size of a file is
+/// usually smaller.
+pub fn allocated_size(dnode: &DNodePhys) -> u64 {
+    let maxblkid = dnode.maxblkid;
+    (maxblkid + 1) * dnode.data_block_size()
+}
+
+/// Read dnode `object` of the object set whose meta dnode is `meta_dnode`
+pub fn read_dnode(reader: &mut ZfsReader,
+                  meta_dnode: &DNodePhys,
+                  object: u64)
+                  -> Result<DNodePhys, String> {
+    read_dnode_extra(reader, meta_dnode, object).map(|(dnode, _)| dnode)
+}
+
+/// Like `read_dnode`, along with the slots after the first one a large dnode takes up, which
+/// its bonus buffer and spill block pointer go on into (see `DNodePhys::large_bonus`). There
+/// are none for dnodes of a single slot. A dnode running past the end of its block is an error.
+pub fn read_dnode_extra(reader: &mut ZfsReader,
+                        meta_dnode: &DNodePhys,
+                        object: u64)
+                        -> Result<(DNodePhys, Vec<u8>), String> {
+    let (blkid, slot) = meta_dnode.dnode_location(object);
+    let (block, order) = try!(read_block_order(reader, meta_dnode, blkid));
+    let offset = slot * mem::size_of::<DNodePhys>();
+    if offset >= block.len() {
+        return Err(format!("Object {} out of range", object));
+    }
+    let dnode = try!(DNodePhys::from_bytes_order(&block[offset..], order));
+    let end = offset + dnode.slots() as usize * DNODE_SIZE as usize;
+    if dnode.slots() > DNODE_MAX_SLOTS || end > block.len() {
+        return Err(format!("Object {} takes up {} slots, past its block", object, dnode.slots()));
+    }
+    let mut extra = block[offset + DNODE_SIZE as usize..end].to_vec();
+    if order == ByteOrder::Big && dnode.flags & DNODE_FLAG_SPILL_BLKPTR != 0 && !extra.is_empty() {
+        let spill = extra.len() - 128;
+        from_bytes::swap_u64s(&mut extra[spill..]);
+    }
+    Ok((dnode, extra))
+}
+
+/// Start reading the blocks holding the dnodes of `objects` into the ARC in the background, so a
+/// burst of dnode lookups (e.g. stat'ing every entry of a directory) is served from the cache
+pub fn prefetch_dnodes(reader: &mut ZfsReader,
+                       meta_dnode: &DNodePhys,
+                       objects: &[u64])
+                       -> Result<(), String> {
+    let mut blkids: Vec<u64> = objects.iter()
+                                      .map(|&object| meta_dnode.dnode_location(object).0)
+                                      .collect();
+    blkids.sort();
+    blkids.dedup();
+
+    let mut dvas = Vec::with_capacity(blkids.len());
+    for blkid in blkids {
+        // The indirect blocks on the way are read synchronously
+        match try!(block_ptr(reader, meta_dnode, blkid)) {
+            // Embedded blocks have nothing on disk to prefetch
+            Some(ref block_ptr) if !block_ptr.is_embedded() => {
+                dvas.push((block_ptr.dvas[0], block_ptr.psize() << zio::SPA_MINBLOCKSHIFT))
+            }
+            _ => {}
+        }
+    }
+
+    reader.arc.prefetch(&reader.zio, dvas);
+    Ok(())
+}
+
+/// What freeing a byte range of an object (punching a hole, truncating) comes down to: whole
+/// blocks that are dropped from the block tree, and the parts of the blocks at either end of the
+/// range that have to be zeroed and rewritten
+#[derive(Debug, PartialEq)]
+pub struct FreeRange {
+    /// Level 0 block ids of the whole blocks, as a `[start, end)` range
+    pub blkids: (u64, u64),
+    /// (blkid, offset in the block, length) of the partially covered blocks
+    pub partial: Vec<(u64, u64, u64)>,
+}
+
+/// The blocks of the object described by `dnode` affected by freeing `len` bytes at `offset`.
+/// Nothing past the last allocated block needs freeing.
+pub fn free_range(dnode: &DNodePhys, offset: u64, len: u64) -> FreeRange {
+    let block_size = dnode.data_block_size();
+    let end = cmp::min(offset.saturating_add(len), allocated_size(dnode));
+    let mut range = FreeRange {
+        blkids: (0, 0),
+        partial: Vec::new(),
+    };
+    if block_size == 0 || offset >= end {
+        return range;
+    }
+
+    let first = (offset + block_size - 1) / block_size;
+    let last = end / block_size;
+    if first > last {
+        // The range is inside of a single block
+        range.partial.push((offset / block_size, offset % block_size, end - offset));
+        return range;
+    }
+    if offset % block_size != 0 {
+        range.partial.push((first - 1, offset % block_size, block_size - offset % block_size));
+    }
+    range.blkids = (first, last);
+    if end % block_size != 0 {
+        range.partial.push((last, 0, end % block_size));
+    }
+    range
+}
+
+/// The range to free when the object described by `dnode` is truncated to `size` bytes
+pub fn truncate_range(dnode: &DNodePhys, size: u64) -> FreeRange {
+    free_range(dnode, size, u64::MAX - size)
+}
+
+/// The block pointers of the whole blocks of `range`, which are what gets killed: freed or put on
+/// the dataset's deadlist (see `DslDatasetPhys::block_kill`). Along with them go the indirect
+/// blocks they leave with nothing but holes under them, each after the blocks under it. Holes
+/// have nothing to free.
+pub fn freed_blocks(reader: &mut ZfsReader,
+                    dnode: &DNodePhys,
+                    range: &FreeRange)
+                    -> Result<Vec<BlockPtr>, String> {
+    let mut block_ptrs = Vec::new();
+    if dnode.nlevels == 0 || range.blkids.0 >= range.blkids.1 {
+        return Ok(block_ptrs);
+    }
+    let top = dnode.nlevels as u64 - 1;
+    for index in 0..dnode.nblkptr as u64 {
+        try!(freed_under(reader,
+                         dnode,
+                         &dnode.get_blockptr(index as usize),
+                         top,
+                         index,
+                         range.blkids,
+                         &mut block_ptrs));
+    }
+    Ok(block_ptrs)
+}
+
+/// Add what freeing the level 0 blocks `blkids` frees of the tree under `block_ptr`, block
+/// `index` at `level`, to `freed`. Returns whether it's left with nothing but holes, which goes
+/// for a hole too.
+fn freed_under(reader: &mut ZfsReader,
+               dnode: &DNodePhys,
+               block_ptr: &BlockPtr,
+               level: u64,
+               index: u64,
+               blkids: (u64, u64),
+               freed: &mut Vec<BlockPtr>)
+               -> Result<bool, String> {
+    if block_ptr.is_hole() {
+        return Ok(true);
+    }
+    let epbs = dnode.epbs();
+    let first = index << (epbs * level);
+    let end = (index + 1) << (epbs * level);
+    if end <= blkids.0 || first >= blkids.1 {
+        return Ok(false);
+    }
+    let mut emptied = true;
+    if level > 0 {
+        let data = try!(reader.read_block(block_ptr));
+        for (i, entry) in data.chunks(128).enumerate() {
+            let child = try!(BlockPtr::from_bytes_order(entry, block_ptr.data_order()));
+            emptied &= try!(freed_under(reader,
+                                        dnode,
+                                        &child,
+                                        level - 1,
+                                        index << epbs | i as u64,
+                                        blkids,
+                                        freed));
+        }
+    }
+    if emptied {
+        freed.push(*block_ptr);
+    }
+    Ok(emptied)
+}
+
+#[test]
+fn test_free_range() {
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.nlevels = 2;
+    dnode.indblkshift = 17;
+    dnode.data_blk_sz_sec = 8; // 4K blocks
+    dnode.maxblkid = 9;
+
+    // Punching from the middle of block 1 to the middle of block 4
+    assert_eq!(free_range(&dnode, 6144, 12288),
+               FreeRange {
+                   blkids: (2, 4),
+                   partial: vec![(1, 2048, 2048), (4, 0, 2048)],
+               });
+    // Block aligned
+    assert_eq!(free_range(&dnode, 8192, 8192),
+               FreeRange {
+                   blkids: (2, 4),
+                   partial: vec![],
+               });
+    // Inside of a single block
+    assert_eq!(free_range(&dnode, 100, 200),
+               FreeRange {
+                   blkids: (0, 0),
+                   partial: vec![(0, 100, 200)],
+               });
+    // Truncating drops everything from the first whole block on
+    assert_eq!(truncate_range(&dnode, 5000),
+               FreeRange {
+                   blkids: (2, 10),
+                   partial: vec![(1, 904, 3192)],
+               });
+    assert_eq!(truncate_range(&dnode, 1 << 20).partial, vec![]);
+}
+
+#[test]
+fn test_holes() {
+    use std::fs::OpenOptions;
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::test_util::TempFile;
+
+    let file = TempFile::new("zfs_test_dmu_holes");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(file.path()).unwrap();
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(disk),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+
+    // Blocks 0 and 2 are holes, the second one with a birth txg as with hole_birth
+    let mut bytes = [0u8; 512];
+    bytes[64 + 128 + 8] = 1; // DVA offset of block 1
+    bytes[64 + 128 + 80] = 5; // birth txg of block 1
+    bytes[64 + 256 + 80] = 7; // birth txg of block 2
+    let mut dnode = DNodePhys::from_bytes(&bytes).unwrap();
+    dnode.nlevels = 1;
+    dnode.nblkptr = 3;
+    dnode.indblkshift = 17;
+    dnode.data_blk_sz_sec = 2;
+    dnode.maxblkid = 2;
+
+    assert!(block_ptr(&mut reader, &dnode, 1).unwrap().is_some());
+    assert!(block_ptr(&mut reader, &dnode, 2).unwrap().is_none());
+    assert_eq!(read_block(&mut reader, &dnode, 2).unwrap(), vec![0; 1024]);
+    let found: Result<Vec<_>, _> = holes(&mut reader, &dnode).collect();
+    assert_eq!(found.unwrap(), vec![(0, 1024), (2048, 1024)]);
+    let freed = freed_blocks(&mut reader, &dnode, &truncate_range(&dnode, 0)).unwrap();
+    assert_eq!(freed.len(), 1);
+
+    // A hole in an indirect block covers everything below it
+    dnode.nlevels = 2;
+    dnode.nblkptr = 1;
+    dnode.maxblkid = 1000;
+    let found: Result<Vec<_>, _> = holes(&mut reader, &dnode).collect();
+    assert_eq!(found.unwrap(), vec![(0, 1001 * 1024)]);
+}
+
+#[test]
+fn test_large_dnode() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::ObjectType;
+    use super::dnode_sync;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+
+    // Object 1 is a 1K dnode whose bonus buffer runs on into its second slot, up to the spill
+    // block pointer at the end of it. Object 3 claims more slots than the block has left.
+    let mut block = vec![0; 16 << 10];
+    block[512] = ObjectType::PlainFileContents as u8;
+    block[512 + 3] = 1; // nblkptr
+    block[512 + 7] = DNODE_FLAG_SPILL_BLKPTR;
+    block[512 + 10..512 + 12].copy_from_slice(&[0xBC, 0x02]); // bonus_len 700
+    block[512 + 12] = 1; // extra_slots
+    for byte in &mut block[512 + 192..1024] {
+        *byte = 1;
+    }
+    for byte in &mut block[1024..1024 + 384] {
+        *byte = 2;
+    }
+    block[1024 + 384 + 80] = 9; // spill birth txg
+    block[3 * 512] = ObjectType::PlainFileContents as u8;
+    block[3 * 512 + 12] = 31;
+    let block_ptr = dnode_sync::write_block(&mut reader,
+                                            &mut alloc,
+                                            1,
+                                            ObjectType::DNode as u8,
+                                            0,
+                                            2,
+                                            &block)
+                        .unwrap();
+    let mut meta_dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    meta_dnode.nlevels = 1;
+    meta_dnode.nblkptr = 1;
+    meta_dnode.indblkshift = 17;
+    meta_dnode.data_blk_sz_sec = 32;
+    meta_dnode.set_blockptr(0, &block_ptr);
+
+    let (dnode, extra) = read_dnode_extra(&mut reader, &meta_dnode, 1).unwrap();
+    assert_eq!((dnode.slots(), extra.len()), (2, 512));
+    let bonus = dnode.large_bonus(&extra);
+    assert_eq!(bonus.len(), 320 + 384);
+    assert!(bonus[..320].iter().all(|&x| x == 1) && bonus[320..].iter().all(|&x| x == 2));
+    let spill_birth = dnode.large_spill_blkptr(&extra).birth_txg;
+    assert_eq!(spill_birth, 9);
+    assert!(read_dnode_extra(&mut reader, &meta_dnode, 3).is_err());
+    assert_eq!(read_dnode_extra(&mut reader, &meta_dnode, 5).unwrap().1.len(), 0);
+}
@@ -1,20 +1,451 @@
-use super::from_bytes::FromBytes;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{mem, slice};
+use std::collections::{BTreeMap, BTreeSet};
 
-use super::dnode::DNodePhys;
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::ddt::Ddt;
+use super::dmu;
+use super::dnode_sync;
+use super::from_bytes::{ByteOrder, Decoder, FromBytes};
+use super::metaslab::Allocator;
+use super::traverse::{self, ObjectFilter};
+use super::zap;
+use super::zfs;
+use super::zio_compress::ZIO_COMPRESS_OFF;
+
+use super::dnode::{DNODE_FLAG_USED_BYTES, DNODE_SIZE, DNodePhys};
 use super::zil_header::ZilHeader;
 
+/// Object number of the MOS object directory
+pub const DMU_POOL_DIRECTORY_OBJECT: u64 = 1;
+
+/// Objsets written since user/group space accounting was added are 2K, with the accounting
+/// dnodes following the original 1K
+const OBJSET_PHYS_SIZE_V2: usize = 2048;
+
+/// `ObjectSetPhys::flags`: the user/group space accounting covers every object
+pub const OBJSET_FLAG_USERACCOUNTING_COMPLETE: u64 = 1 << 0;
+/// The user/group object counts cover every object (`userobj_accounting` feature)
+pub const OBJSET_FLAG_USEROBJACCOUNTING_COMPLETE: u64 = 1 << 1;
+/// The project accounting covers every object (`project_quota` feature)
+pub const OBJSET_FLAG_PROJECTQUOTA_COMPLETE: u64 = 1 << 2;
+
+/// Prefix of the object count keys in the user/group used ZAPs (`DMU_OBJACCT_PREFIX`)
+const OBJACCT_PREFIX: &'static str = "obj-";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ObjectSetType {
+    None,
+    Meta,
+    Zfs,
+    Zvol,
+    Other, // For testing only
+    Any, // Be careful!
+}
+
+impl ObjectSetType {
+    pub fn from_u64(u: u64) -> Option<Self> {
+        match u {
+            0 => Some(ObjectSetType::None),
+            1 => Some(ObjectSetType::Meta),
+            2 => Some(ObjectSetType::Zfs),
+            3 => Some(ObjectSetType::Zvol),
+            4 => Some(ObjectSetType::Other),
+            5 => Some(ObjectSetType::Any),
+            _ => None,
+        }
+    }
+}
+
 #[repr(packed)]
 pub struct ObjectSetPhys {
     pub meta_dnode: DNodePhys,
     pub zil_header: ZilHeader,
-    pub os_type: u64, // pad: [u8; 360],
+    pub os_type: u64,
+    pub flags: u64,
+    pub portable_mac: [u8; 32],
+    pub local_mac: [u8; 32],
+    pad: [u8; 240],
+}
+
+impl FromBytes for ObjectSetPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let meta_dnode = DNodePhys::decode(decoder);
+        let zil_header = ZilHeader::decode(decoder);
+        let os_type = decoder.u64();
+        let flags = decoder.u64();
+        let mut portable_mac = [0; 32];
+        decoder.fill(&mut portable_mac);
+        let mut local_mac = [0; 32];
+        decoder.fill(&mut local_mac);
+        let mut pad = [0; 240];
+        decoder.fill(&mut pad);
+        ObjectSetPhys {
+            meta_dnode: meta_dnode,
+            zil_header: zil_header,
+            os_type: os_type,
+            flags: flags,
+            portable_mac: portable_mac,
+            local_mac: local_mac,
+            pad: pad,
+        }
+    }
+}
+
+impl ObjectSetPhys {
+    /// The objset as it's written to disk, in host byte order
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const ObjectSetPhys as *const u8,
+                                  mem::size_of::<ObjectSetPhys>())
+        }
+    }
 }
 
-impl FromBytes for ObjectSetPhys {}
+/// What a txg changes of an objset: the dnodes it changes (including the objects it creates),
+/// the level 0 blocks it writes, by object and block id, and the header of its intent log
+#[derive(Default)]
+pub struct DirtyObjset {
+    /// Set if the txg creates the objset (see `ObjectSet::create`), replacing the one there was
+    pub created: Option<ObjectSetType>,
+    pub dnodes: BTreeMap<u64, DNodePhys>,
+    /// The level 0 blocks written, by object and block id. An empty one is a hole punched in the
+    /// object (see `DslPool::dirty_hole`).
+    pub blocks: BTreeMap<u64, BTreeMap<u64, Vec<u8>>>,
+    /// Objects the txg frees: the blocks they have on the disk go along with them
+    pub freed: BTreeSet<u64>,
+    /// Set if the txg destroys the objset along with its dataset: rather than written, it's freed
+    pub destroyed: bool,
+    /// Set if the intent log moved on (see `zil::Zilog::sync`)
+    pub zil_header: Option<ZilHeader>,
+}
+
+impl DirtyObjset {
+    pub fn is_empty(&self) -> bool {
+        self.created.is_none() && self.dnodes.is_empty() && self.blocks.is_empty() &&
+        self.freed.is_empty() && !self.destroyed && self.zil_header.is_none()
+    }
+}
 
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 
-pub struct ObjectSet;
+/// An object set (the MOS, a filesystem, a zvol, ...): a collection of objects addressed by
+/// object number, all described by dnodes stored in the object set's meta dnode.
+pub struct ObjectSet {
+    pub phys: ObjectSetPhys,
+    pub userused_dnode: Option<DNodePhys>,
+    pub groupused_dnode: Option<DNodePhys>,
+}
+
+impl ObjectSet {
+    /// Open the object set rooted at `block_ptr`, e.g. the MOS from the uberblock's rootbp
+    pub fn open(reader: &mut ZfsReader, block_ptr: &BlockPtr) -> Result<Self, String> {
+        let data = try!(reader.read_block(block_ptr));
+        let order = block_ptr.data_order();
+        let phys = try!(ObjectSetPhys::from_bytes_order(&data, order));
+
+        let (userused_dnode, groupused_dnode) = if data.len() >= OBJSET_PHYS_SIZE_V2 {
+            let dnode_size = mem::size_of::<DNodePhys>();
+            let userused = mem::size_of::<ObjectSetPhys>();
+            (DNodePhys::from_bytes_order(&data[userused..], order).ok(),
+             DNodePhys::from_bytes_order(&data[userused + dnode_size..], order).ok())
+        } else {
+            (None, None)
+        };
+
+        Ok(ObjectSet {
+            phys: phys,
+            userused_dnode: userused_dnode,
+            groupused_dnode: groupused_dnode,
+        })
+    }
+
+    /// A new object set of type `os_type`, without any objects. It has no block until it's
+    /// synced.
+    pub fn create(os_type: ObjectSetType) -> Self {
+        let mut phys = ObjectSetPhys::from_bytes(&[0; 1024]).unwrap();
+        phys.os_type = os_type as u64;
+        phys.meta_dnode.object_type = ObjectType::DmuOtDnode as u8;
+        phys.meta_dnode.indblkshift = 14;
+        phys.meta_dnode.nblkptr = 3;
+        phys.meta_dnode.data_blk_sz_sec = 32; // 16K blocks of dnodes
+        phys.meta_dnode.flags = DNODE_FLAG_USED_BYTES;
+        ObjectSet {
+            phys: phys,
+            userused_dnode: None,
+            groupused_dnode: None,
+        }
+    }
+
+    pub fn os_type(&self) -> Option<ObjectSetType> {
+        ObjectSetType::from_u64(self.phys.os_type)
+    }
+
+    /// Look up `name` in the MOS object directory
+    pub fn directory_lookup(&self, reader: &mut ZfsReader, name: &str) -> Result<u64, String> {
+        zap::lookup(reader, self, DMU_POOL_DIRECTORY_OBJECT, name)
+    }
+
+    /// Fetch the dnode of object `object`
+    pub fn dnode(&self, reader: &mut ZfsReader, object: u64) -> Result<DNodePhys, String> {
+        dmu::read_dnode(reader, &self.phys.meta_dnode, object)
+    }
+
+    /// Dnode `object` along with the slots after its first one, see `dmu::read_dnode_extra`
+    pub fn dnode_extra(&self,
+                       reader: &mut ZfsReader,
+                       object: u64)
+                       -> Result<(DNodePhys, Vec<u8>), String> {
+        dmu::read_dnode_extra(reader, &self.phys.meta_dnode, object)
+    }
+
+    /// Read the dnodes of `objects` into the ARC in the background
+    pub fn prefetch_dnodes(&self, reader: &mut ZfsReader, objects: &[u64]) -> Result<(), String> {
+        dmu::prefetch_dnodes(reader, &self.phys.meta_dnode, objects)
+    }
+
+    /// Read `len` bytes at `offset` of object `object`
+    pub fn read(&self,
+                reader: &mut ZfsReader,
+                object: u64,
+                offset: u64,
+                len: usize)
+                -> Result<Vec<u8>, String> {
+        dmu::read_object(reader, self, object, offset, len)
+    }
+
+    /// Write out the changes of `dirty` in txg `txg`, copy on write: the blocks of the objects,
+    /// their dnodes, and the objset block on top of it all. Returns the block pointer to the new
+    /// objset block. The blocks left behind, including the whole trees of the objects it frees,
+    /// are added to `freed`, but for the old objset block.
+    /// The blocks of the objects are compressed with `compression` where it pays off, and
+    /// deduplicated through `ddt`, if there's one.
+    pub fn sync(&mut self,
+                reader: &mut ZfsReader,
+                alloc: &mut Allocator,
+                mut ddt: Option<&mut Ddt>,
+                compression: u64,
+                txg: u64,
+                dirty: DirtyObjset,
+                freed: &mut Vec<BlockPtr>)
+                -> zfs::Result<BlockPtr> {
+        let DirtyObjset { mut dnodes, blocks, freed: freed_objects, zil_header, .. } = dirty;
+        if let Some(zil_header) = zil_header {
+            self.phys.zil_header = zil_header;
+        }
+        // The trees of freed objects go, even if the txg creates new objects in their place
+        let on_disk = if self.phys.meta_dnode.nlevels > 0 {
+            dmu::allocated_size(&self.phys.meta_dnode) / DNODE_SIZE
+        } else {
+            0
+        };
+        for &object in freed_objects.iter().filter(|&&object| object < on_disk) {
+            let dnode = try!(self.dnode(reader, object).map_err(|_| zfs::Error::Invalid));
+            try!(traverse::traverse_object(&mut reader.zio,
+                                           &dnode,
+                                           object,
+                                           ObjectFilter::All,
+                                           &mut |_, _, block_ptr| freed.push(*block_ptr)));
+        }
+        for &object in blocks.keys() {
+            if !dnodes.contains_key(&object) {
+                let dnode = try!(self.dnode(reader, object).map_err(|_| zfs::Error::NoEntity));
+                dnodes.insert(object, dnode);
+            }
+        }
+
+        // The objects first, then the blocks of dnodes they land in
+        let mut meta_dnode = self.phys.meta_dnode;
+        let block_size = meta_dnode.data_block_size() as usize;
+        let mut dnode_blocks: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        for (object, mut dnode) in dnodes {
+            if let Some(blocks) = blocks.get(&object) {
+                let ddt = ddt.as_mut().map(|ddt| &mut **ddt);
+                try!(dnode_sync::sync_dnode(reader,
+                                            alloc,
+                                            ddt,
+                                            compression,
+                                            txg,
+                                            &mut dnode,
+                                            blocks,
+                                            freed));
+            }
+            let (blkid, slot) = meta_dnode.dnode_location(object);
+            if !dnode_blocks.contains_key(&blkid) {
+                let data = if meta_dnode.nlevels > 0 && blkid <= meta_dnode.maxblkid {
+                    let (data, order) = try!(dmu::read_block_order(reader, &meta_dnode, blkid)
+                                                 .map_err(|_| zfs::Error::Invalid));
+                    if order == ByteOrder::host() {
+                        data
+                    } else {
+                        // Written the other way around: every dnode of the block is rewritten
+                        // in host byte order along with this one
+                        let mut swapped = Vec::with_capacity(data.len());
+                        for dnode in data.chunks(DNODE_SIZE as usize) {
+                            let dnode = try!(DNodePhys::from_bytes_order(dnode, order));
+                            swapped.extend_from_slice(dnode.as_bytes());
+                        }
+                        swapped
+                    }
+                } else {
+                    vec![0; block_size]
+                };
+                dnode_blocks.insert(blkid, data);
+            }
+            let data = dnode_blocks.get_mut(&blkid).unwrap();
+            let offset = slot * DNODE_SIZE as usize;
+            data[offset..offset + DNODE_SIZE as usize].copy_from_slice(dnode.as_bytes());
+        }
+        try!(dnode_sync::sync_dnode(reader,
+                                    alloc,
+                                    None,
+                                    ZIO_COMPRESS_OFF,
+                                    txg,
+                                    &mut meta_dnode,
+                                    &dnode_blocks,
+                                    freed));
+        self.phys.meta_dnode = meta_dnode;
+
+        let mut data = self.phys.as_bytes().to_vec();
+        if let (Some(ref userused), Some(ref groupused)) = (self.userused_dnode,
+                                                             self.groupused_dnode) {
+            data.extend_from_slice(userused.as_bytes());
+            data.extend_from_slice(groupused.as_bytes());
+        }
+        dnode_sync::write_block(reader, alloc, txg, ObjectType::DmuOtJset as u8, 0, 1, &data)
+    }
+
+    /// Whether the user/group object counts have to be regenerated before they can be trusted:
+    /// `userobj_accounting` was enabled after the object set was written (so it was never
+    /// flagged complete), or the flag was cleared because the accounting went wrong
+    pub fn userobj_accounting_needs_rebuild(&self, feature_enabled: bool) -> bool {
+        let flags = self.phys.flags;
+        feature_enabled &&
+        (flags & OBJSET_FLAG_USEROBJACCOUNTING_COMPLETE == 0 || self.userused_dnode.is_none())
+    }
+
+    /// Regenerate the user/group accounting by scanning every object. `owner` gives the (user,
+    /// group) an object, by number and dnode, is charged to, or None for objects that aren't
+    /// charged to anyone (only the object set's type knows where the owner is kept).
+    pub fn rebuild_user_accounting<F>(&self,
+                                      reader: &mut ZfsReader,
+                                      mut owner: F)
+                                      -> Result<UserAccounting, String>
+        where F: FnMut(&mut ZfsReader, u64, &DNodePhys) -> Option<(u64, u64)>
+    {
+        let mut accounting = UserAccounting::default();
+        let meta_dnode = &self.phys.meta_dnode;
+        let objects = dmu::allocated_size(meta_dnode) / DNODE_SIZE;
+        // Object 0 is the meta dnode itself
+        let mut object = 1;
+        while object < objects {
+            let dnode = try!(self.dnode(reader, object));
+            // Large dnodes take up the following slots too
+            let this = object;
+            object += dnode.slots();
+            if dnode.object_type == 0 {
+                continue;
+            }
+            if let Some((user, group)) = owner(reader, this, &dnode) {
+                accounting.charge(user, group, &dnode);
+            }
+        }
+        Ok(accounting)
+    }
+
+    /// Read the user and group used ZAPs. Object sets without them (ZPL version 3 and before, or
+    /// volumes) have no accounting to read.
+    pub fn user_accounting(&self, reader: &mut ZfsReader) -> Result<UserAccounting, String> {
+        Ok(UserAccounting {
+            users: try!(read_used_zap(reader, &self.userused_dnode)),
+            groups: try!(read_used_zap(reader, &self.groupused_dnode)),
+        })
+    }
+}
+
+fn read_used_zap(reader: &mut ZfsReader,
+                 dnode: &Option<DNodePhys>)
+                 -> Result<BTreeMap<u64, Usage>, String> {
+    match *dnode {
+        Some(ref dnode) if dnode.object_type != 0 && dnode.nlevels > 0 => {
+            let entries: Vec<_> = try!(zap::iter_dnode(reader, dnode)).collect();
+            Ok(UserAccounting::from_zap_entries(&entries))
+        }
+        _ => Ok(BTreeMap::new()),
+    }
+}
+
+/// The space and objects charged to one user or group
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.objects += 1;
+    }
+}
+
+/// The contents of the user and group used ZAPs (`DMU_USERUSED_OBJECT` and
+/// `DMU_GROUPUSED_OBJECT`), by user and group ID
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserAccounting {
+    pub users: BTreeMap<u64, Usage>,
+    pub groups: BTreeMap<u64, Usage>,
+}
+
+impl UserAccounting {
+    /// Charge the object described by `dnode` to `user` and `group`. Like the accounting kept as
+    /// objects change, an object is charged its dnode along with its blocks.
+    pub fn charge(&mut self, user: u64, group: u64, dnode: &DNodePhys) {
+        let used = dnode.used;
+        let used = if dnode.flags & DNODE_FLAG_USED_BYTES != 0 {
+            used
+        } else {
+            used << 9
+        };
+        self.users.entry(user).or_insert_with(Usage::default).add(DNODE_SIZE + used);
+        self.groups.entry(group).or_insert_with(Usage::default).add(DNODE_SIZE + used);
+    }
+
+    /// The usage by ID in the entries of a used ZAP (see `zap_entries`). Entries of other IDs,
+    /// like those of the project used ZAP, are left out.
+    pub fn from_zap_entries(entries: &[(String, u64)]) -> BTreeMap<u64, Usage> {
+        let mut usage = BTreeMap::new();
+        for &(ref name, value) in entries {
+            let (id, objects) = if name.starts_with(OBJACCT_PREFIX) {
+                (&name[OBJACCT_PREFIX.len()..], true)
+            } else {
+                (&name[..], false)
+            };
+            if let Ok(id) = u64::from_str_radix(id, 16) {
+                let entry = usage.entry(id).or_insert_with(Usage::default);
+                if objects {
+                    entry.objects = value;
+                } else {
+                    entry.bytes = value;
+                }
+            }
+        }
+        usage
+    }
+
+    /// The entries of a used ZAP for `usage`: the bytes under the ID in hex, the object count
+    /// under the ID with the `obj-` prefix
+    pub fn zap_entries(usage: &BTreeMap<u64, Usage>) -> Vec<(String, u64)> {
+        let mut entries = Vec::new();
+        for (id, usage) in usage {
+            entries.push((format!("{:x}", id), usage.bytes));
+            entries.push((format!("{}{:x}", OBJACCT_PREFIX, id), usage.objects));
+        }
+        entries
+    }
+}
 
 pub enum ObjectType {
     DmuOtNone,
@@ -1961,3 +2392,51 @@ pub enum ObjectType {
 // return (0);
 // }
 //
+
+#[test]
+fn test_user_accounting() {
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    let mut accounting = UserAccounting::default();
+    dnode.used = 4;
+    accounting.charge(1000, 100, &dnode);
+    dnode.flags = DNODE_FLAG_USED_BYTES;
+    dnode.used = 131072;
+    accounting.charge(1000, 0, &dnode);
+    accounting.charge(0, 0, &dnode);
+
+    assert_eq!(accounting.users[&1000],
+               Usage {
+                   bytes: 512 + 2048 + 512 + 131072,
+                   objects: 2,
+               });
+    assert_eq!(accounting.groups[&0],
+               Usage {
+                   bytes: 2 * (512 + 131072),
+                   objects: 2,
+               });
+    let entries = UserAccounting::zap_entries(&accounting.users);
+    assert_eq!(entries,
+               vec![("0".to_owned(), 512 + 131072),
+                    ("obj-0".to_owned(), 1),
+                    ("3e8".to_owned(), 512 + 2048 + 512 + 131072),
+                    ("obj-3e8".to_owned(), 2)]);
+    assert_eq!(UserAccounting::from_zap_entries(&entries), accounting.users);
+
+    let mut objset = ObjectSet {
+        phys: ObjectSetPhys::from_bytes(&[0; 1024]).unwrap(),
+        userused_dnode: Some(DNodePhys::from_bytes(&[0; 512]).unwrap()),
+        groupused_dnode: Some(DNodePhys::from_bytes(&[0; 512]).unwrap()),
+    };
+    assert!(objset.userobj_accounting_needs_rebuild(true));
+    assert!(!objset.userobj_accounting_needs_rebuild(false));
+    objset.phys.flags = OBJSET_FLAG_USERACCOUNTING_COMPLETE |
+                        OBJSET_FLAG_USEROBJACCOUNTING_COMPLETE;
+    assert!(!objset.userobj_accounting_needs_rebuild(true));
+}
+
+#[test]
+fn test_objset_phys_size() {
+    // Must match the on-disk objset_phys_t
+    assert_eq!(mem::size_of::<ZilHeader>(), 192);
+    assert_eq!(mem::size_of::<ObjectSetPhys>(), 1024);
+}
@@ -0,0 +1,1423 @@
+use std::{cmp, fmt, io};
+use std::io::Read;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum::{self, Checksum, Fletcher4};
+use super::dmu;
+use super::dmu_objset::{ObjectSet, ObjectSetType};
+use super::dmu_tx::Tx;
+use super::dnode::{DNODE_FLAG_USED_BYTES, DNODE_SIZE, DNodePhys, ObjectType};
+use super::dsl_dataset::{Dataset, DslDatasetPhys, Snapshot};
+use super::dsl_pool::DslPool;
+use super::from_bytes::{ByteOrder, Decoder, FromBytes};
+use super::metaslab::Allocator;
+use super::nvpair::{NvList, NvValue};
+use super::spa;
+use super::uberblock::Uberblock;
+use super::util;
+use super::zap::{self, ZapValue};
+use super::zfs;
+use super::zio_compress;
+
+/// Magic number of the BEGIN record, which also gives away the byte order of the stream
+pub const DMU_BACKUP_MAGIC: u64 = 0x2F5BACBAC;
+
+/// Every record starts with a `dmu_replay_record_t` of this size, followed by its payload
+pub const DRR_SIZE: usize = 312;
+/// The last 32 bytes of a record (except BEGIN) hold the checksum of the stream before them
+pub const DRR_CHECKSUM_OFFSET: usize = DRR_SIZE - 32;
+
+/// Stream header types, in the low two bits of the BEGIN record's version info
+pub const DMU_SUBSTREAM: u64 = 1;
+
+// Stream feature flags, in the rest of the version info
+pub const DMU_BACKUP_FEATURE_EMBED_DATA: u64 = 1 << 16;
+pub const DMU_BACKUP_FEATURE_LARGE_BLOCKS: u64 = 1 << 19;
+pub const DMU_BACKUP_FEATURE_RESUMING: u64 = 1 << 20;
+pub const DMU_BACKUP_FEATURE_COMPRESSED: u64 = 1 << 22;
+/// Blocks are sent as they're on disk, encrypted, along with what the receiver needs to write
+/// them as they are (`send -w`)
+pub const DMU_BACKUP_FEATURE_RAW: u64 = 1 << 24;
+
+pub const DRR_BEGIN: u32 = 0;
+pub const DRR_OBJECT: u32 = 1;
+pub const DRR_FREEOBJECTS: u32 = 2;
+pub const DRR_WRITE: u32 = 3;
+pub const DRR_FREE: u32 = 4;
+pub const DRR_END: u32 = 5;
+pub const DRR_WRITE_BYREF: u32 = 6;
+pub const DRR_SPILL: u32 = 7;
+pub const DRR_WRITE_EMBEDDED: u32 = 8;
+pub const DRR_OBJECT_RANGE: u32 = 9;
+pub const DRR_REDACT: u32 = 10;
+
+/// The data of a raw record was written on a host of the other endianness
+pub const DRR_RAW_BYTESWAP: u8 = 1 << 1;
+
+/// Most bonus buffer a dnode has room for, with a single block pointer (`DN_MAX_BONUSLEN`)
+const DN_MAX_BONUSLEN: usize = 320;
+
+/// Received objects get 128K indirect blocks (`DN_MAX_INDBLKSHIFT`)
+const DN_MAX_INDBLKSHIFT: u8 = 17;
+
+// Where the receive-resume state is kept in the ZAP of the dataset being received into
+const DS_FIELD_RESUME_FROMGUID: &'static str = "com.delphix:resume_fromguid";
+const DS_FIELD_RESUME_TONAME: &'static str = "com.delphix:resume_toname";
+const DS_FIELD_RESUME_TOGUID: &'static str = "com.delphix:resume_toguid";
+const DS_FIELD_RESUME_OBJECT: &'static str = "com.delphix:resume_object";
+const DS_FIELD_RESUME_OFFSET: &'static str = "com.delphix:resume_offset";
+const DS_FIELD_RESUME_BYTES: &'static str = "com.delphix:resume_bytes";
+const DS_FIELD_RESUME_LARGEBLOCK: &'static str = "com.delphix:resume_largeblockok";
+const DS_FIELD_RESUME_EMBEDOK: &'static str = "com.delphix:resume_embedok";
+const DS_FIELD_RESUME_COMPRESSOK: &'static str = "com.delphix:resume_compressok";
+const DS_FIELD_RESUME_RAWOK: &'static str = "com.datto:resume_rawok";
+
+/// Why a stream couldn't be received
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecvError {
+    /// Reading the stream failed, or it ended early
+    Io(io::ErrorKind),
+    BadMagic(u64),
+    /// A compound (`send -R`) stream, which is made of several substreams
+    Unsupported(u64),
+    /// The stream doesn't match its checksum at the given record
+    Checksum { record: u64 },
+    /// A record of unknown type, or one that isn't allowed where it is
+    UnexpectedRecord(u32),
+    /// A full stream into a dataset that exists and can't be overwritten
+    DestinationExists,
+    /// The destination already has the snapshot the stream creates
+    SnapshotExists(String),
+    /// The destination has no snapshot with the incremental source's guid
+    NoMatchingSnapshot(u64),
+    /// The incremental source isn't the destination's latest snapshot, and there's no force to
+    /// roll back to it
+    NotLatestSnapshot(String),
+    /// The destination was written to since its latest snapshot, and there's no force to roll
+    /// back
+    DestinationModified,
+    /// The stream doesn't continue the interrupted receive
+    ResumeMismatch,
+    /// Applying a record to the destination failed
+    Apply(zfs::Error),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Io(kind) => write!(f, "Failed to read stream: {:?}", kind),
+            RecvError::BadMagic(magic) => write!(f, "Bad stream magic {:X}", magic),
+            RecvError::Unsupported(info) => write!(f, "Unsupported stream version {:X}", info),
+            RecvError::Checksum { record } => write!(f, "Checksum mismatch at record {}", record),
+            RecvError::UnexpectedRecord(kind) => write!(f, "Unexpected record of type {}", kind),
+            RecvError::DestinationExists => f.write_str("Destination exists"),
+            RecvError::SnapshotExists(ref name) => write!(f, "Destination has snapshot {}", name),
+            RecvError::NoMatchingSnapshot(guid) => {
+                write!(f, "Destination has no snapshot with guid {:X}", guid)
+            }
+            RecvError::NotLatestSnapshot(ref name) => {
+                write!(f, "Incremental source {} isn't the latest snapshot", name)
+            }
+            RecvError::DestinationModified => f.write_str("Destination has been modified"),
+            RecvError::ResumeMismatch => f.write_str("Stream doesn't resume the receive"),
+            RecvError::Apply(e) => write!(f, "Failed to apply record: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RecvError {
+    fn from(e: io::Error) -> Self {
+        RecvError::Io(e.kind())
+    }
+}
+
+/// The BEGIN record, which names the snapshot the stream recreates and the one it's
+/// incremental from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Begin {
+    pub version_info: u64,
+    pub creation_time: u64,
+    pub objset_type: u32,
+    pub flags: u32,
+    pub toguid: u64,
+    /// 0 for full streams
+    pub fromguid: u64,
+    /// Full name of the snapshot, e.g. `tank/fs@monday`
+    pub toname: String,
+}
+
+impl Begin {
+    pub fn features(&self) -> u64 {
+        self.version_info >> 2
+    }
+
+    pub fn is_incremental(&self) -> bool {
+        self.fromguid != 0
+    }
+
+    /// The part of `toname` after the `@`
+    pub fn snapshot_name(&self) -> &str {
+        self.toname.splitn(2, '@').nth(1).unwrap_or("")
+    }
+}
+
+/// What a raw stream carries along with an encrypted block (or dnode block) to write it as it
+/// was: the salt and IV it was encrypted with, and its MAC
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CryptParams {
+    pub byteswap: bool,
+    pub salt: [u8; 8],
+    pub iv: [u8; 12],
+    pub mac: [u8; 16],
+}
+
+impl CryptParams {
+    /// Those of `block_ptr`. Blocks that are only authenticated have a MAC but no salt or IV.
+    pub fn of(block_ptr: &BlockPtr) -> Self {
+        let encrypted = block_ptr.is_encrypted();
+        CryptParams {
+            byteswap: block_ptr.byte_order() != checksum::host_byte_order(),
+            salt: if encrypted { block_ptr.salt() } else { [0; 8] },
+            iv: if encrypted { block_ptr.iv() } else { [0; 12] },
+            mac: if block_ptr.uses_crypt() { block_ptr.mac() } else { [0; 16] },
+        }
+    }
+
+    fn decode(decoder: &mut Decoder, flags: u8) -> Self {
+        let mut params = CryptParams {
+            byteswap: flags & DRR_RAW_BYTESWAP != 0,
+            salt: [0; 8],
+            iv: [0; 12],
+            mac: [0; 16],
+        };
+        params.salt.copy_from_slice(decoder.bytes(8));
+        params.iv.copy_from_slice(decoder.bytes(12));
+        params.mac.copy_from_slice(decoder.bytes(16));
+        params
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordHeader {
+    Begin(Begin),
+    Object {
+        object: u64,
+        object_type: u32,
+        bonus_type: u32,
+        block_size: u32,
+        bonus_len: u32,
+        /// In raw streams, the whole bonus area is sent encrypted, past `bonus_len`. 0 if the
+        /// dnode has no bonus buffer, or the stream isn't raw.
+        raw_bonus_len: u32,
+    },
+    FreeObjects { first: u64, count: u64 },
+    Write {
+        object: u64,
+        offset: u64,
+        length: u64,
+        compression: u8,
+        /// Raw streams only: the block is sent encrypted, as it's on disk
+        crypt: Option<CryptParams>,
+    },
+    /// Raw streams only: the objects of the next dnode block, and how it was encrypted. The
+    /// OBJECT records of its objects follow.
+    ObjectRange {
+        first: u64,
+        count: u64,
+        crypt: CryptParams,
+    },
+    /// `length` is `u64::MAX` for everything from `offset` on
+    Free { object: u64, offset: u64, length: u64 },
+    WriteByRef { object: u64, offset: u64, length: u64 },
+    Spill { object: u64, length: u64 },
+    WriteEmbedded { object: u64, offset: u64, length: u64 },
+    End { toguid: u64 },
+    /// Records that don't change the data (redactions)
+    Other(u32),
+}
+
+/// A record of a send stream, along with its payload: the bonus buffer of OBJECT records, the
+/// data of WRITE records and so on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub header: RecordHeader,
+    pub payload: Vec<u8>,
+}
+
+/// Decode the record header `drr` of a stream that's `raw` or not, returning it along with the
+/// length of its payload
+fn decode_header(drr: &[u8],
+                 order: ByteOrder,
+                 raw: bool)
+                 -> Result<(RecordHeader, usize), RecvError> {
+    let mut decoder = Decoder::new(drr, order);
+    let kind = decoder.u32();
+    let payload_len = decoder.u32() as usize;
+    let mut u64s = [0; 8];
+    let header = match kind {
+        DRR_BEGIN => {
+            decoder.u64(); // magic, checked by the caller
+            let version_info = decoder.u64();
+            let creation_time = decoder.u64();
+            let objset_type = decoder.u32();
+            let flags = decoder.u32();
+            let toguid = decoder.u64();
+            let fromguid = decoder.u64();
+            let toname = decoder.bytes(256);
+            let toname = &toname[..toname.iter().position(|&b| b == 0).unwrap_or(256)];
+            let begin = Begin {
+                version_info: version_info,
+                creation_time: creation_time,
+                objset_type: objset_type,
+                flags: flags,
+                toguid: toguid,
+                fromguid: fromguid,
+                toname: String::from_utf8_lossy(toname).into_owned(),
+            };
+            return Ok((RecordHeader::Begin(begin), payload_len));
+        }
+        DRR_OBJECT => {
+            let object = decoder.u64();
+            let object_type = decoder.u32();
+            let bonus_type = decoder.u32();
+            let block_size = decoder.u32();
+            let bonus_len = decoder.u32();
+            decoder.bytes(4); // checksum, compression, dnode slots, flags
+            let raw_bonus_len = decoder.u32();
+            let header = RecordHeader::Object {
+                object: object,
+                object_type: object_type,
+                bonus_type: bonus_type,
+                block_size: block_size,
+                bonus_len: bonus_len,
+                raw_bonus_len: raw_bonus_len,
+            };
+            let payload_len = if raw_bonus_len != 0 {
+                raw_bonus_len as u64
+            } else {
+                util::round_up(bonus_len as u64, 8)
+            };
+            return Ok((header, payload_len as usize));
+        }
+        DRR_WRITE => {
+            let object = decoder.u64();
+            decoder.u64(); // type, padding
+            let offset = decoder.u64();
+            let length = decoder.u64();
+            decoder.u64(); // toguid
+            decoder.u8(); // checksum type
+            let flags = decoder.u8();
+            let compression = decoder.u8();
+            decoder.bytes(5 + 40); // padding, dedup key
+            let compressed_size = decoder.u64();
+            // Compressed (`send -c`) and raw writes carry the block as it's on disk
+            let payload_len = if (compression != 0 || raw) && compressed_size != 0 {
+                compressed_size
+            } else {
+                length
+            };
+            let header = RecordHeader::Write {
+                object: object,
+                offset: offset,
+                length: length,
+                compression: compression,
+                crypt: if raw { Some(CryptParams::decode(&mut decoder, flags)) } else { None },
+            };
+            return Ok((header, payload_len as usize));
+        }
+        DRR_WRITE_EMBEDDED => {
+            let object = decoder.u64();
+            let offset = decoder.u64();
+            let length = decoder.u64();
+            decoder.bytes(16); // toguid, compression, embedded type, padding
+            decoder.u32(); // logical size
+            let psize = decoder.u32();
+            let header = RecordHeader::WriteEmbedded {
+                object: object,
+                offset: offset,
+                length: length,
+            };
+            return Ok((header, util::p2_round_up(psize as u64, 8) as usize));
+        }
+        DRR_OBJECT_RANGE => {
+            let first = decoder.u64();
+            let count = decoder.u64();
+            decoder.u64(); // toguid
+            let mut crypt = CryptParams::decode(&mut decoder, 0);
+            crypt.byteswap = decoder.u8() & DRR_RAW_BYTESWAP != 0;
+            let header = RecordHeader::ObjectRange {
+                first: first,
+                count: count,
+                crypt: crypt,
+            };
+            return Ok((header, payload_len));
+        }
+        _ => {
+            decoder.fill_u64(&mut u64s);
+            u64s
+        }
+    };
+    let (header, payload_len) = match kind {
+        DRR_FREEOBJECTS => {
+            (RecordHeader::FreeObjects {
+                first: header[0],
+                count: header[1],
+            },
+             0)
+        }
+        DRR_FREE => {
+            (RecordHeader::Free {
+                object: header[0],
+                offset: header[1],
+                length: header[2],
+            },
+             0)
+        }
+        DRR_WRITE_BYREF => {
+            (RecordHeader::WriteByRef {
+                object: header[0],
+                offset: header[1],
+                length: header[2],
+            },
+             0)
+        }
+        DRR_SPILL => {
+            // Raw spill blocks are sent as they're on disk
+            let length = if header[4] != 0 { header[4] } else { header[1] };
+            (RecordHeader::Spill {
+                object: header[0],
+                length: header[1],
+            },
+             length as usize)
+        }
+        DRR_END => (RecordHeader::End { toguid: header[4] }, 0),
+        DRR_REDACT => (RecordHeader::Other(kind), payload_len),
+        _ => return Err(RecvError::UnexpectedRecord(kind)),
+    };
+    Ok((header, payload_len))
+}
+
+/// Reads the records of a send stream, checking them against the running checksum the sender
+/// puts at the end of each record
+pub struct StreamReader<R: Read> {
+    input: R,
+    order: ByteOrder,
+    /// Whether the BEGIN record says the stream is raw, which changes the layout of the records
+    raw: bool,
+    /// The payload of the BEGIN record: an nvlist, for raw streams with the dataset's key
+    begin_payload: Vec<u8>,
+    checksum: Fletcher4,
+    records: u64,
+    bytes: u64,
+    ended: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(input: R) -> Self {
+        StreamReader {
+            input: input,
+            order: ByteOrder::host(),
+            raw: false,
+            begin_payload: Vec::new(),
+            checksum: Fletcher4::new(false),
+            records: 0,
+            bytes: 0,
+            ended: false,
+        }
+    }
+
+    /// How many bytes of the stream were read so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), RecvError> {
+        try!(self.input.read_exact(buf));
+        self.bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Read the BEGIN record the stream starts with
+    pub fn begin(&mut self) -> Result<Begin, RecvError> {
+        match try!(self.next_record()) {
+            Some(Record { header: RecordHeader::Begin(begin), payload }) => {
+                self.begin_payload = payload;
+                Ok(begin)
+            }
+            _ => Err(RecvError::UnexpectedRecord(DRR_BEGIN)),
+        }
+    }
+
+    /// The packed nvlist the BEGIN record carries, empty if there's none. That of a raw stream
+    /// holds the wrapped key of the dataset as `crypt_keydata`, in the sender's encoding.
+    pub fn begin_payload(&self) -> &[u8] {
+        &self.begin_payload
+    }
+
+    /// The next record, or `None` once the END record was read
+    pub fn next_record(&mut self) -> Result<Option<Record>, RecvError> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        let mut drr = [0; DRR_SIZE];
+        try!(self.read_exact(&mut drr));
+        if self.records == 0 {
+            // The stream is in the byte order of the sender, which the magic gives away
+            let magic = Decoder::new(&drr[8..], ByteOrder::Little).u64();
+            self.order = if magic == DMU_BACKUP_MAGIC {
+                ByteOrder::Little
+            } else if magic == DMU_BACKUP_MAGIC.swap_bytes() {
+                ByteOrder::Big
+            } else {
+                return Err(RecvError::BadMagic(magic));
+            };
+            self.checksum = Fletcher4::new(self.order != ByteOrder::host());
+        }
+
+        let (header, payload_len) = try!(decode_header(&drr, self.order, self.raw));
+        match header {
+            RecordHeader::Begin(ref begin) => {
+                if self.records != 0 {
+                    return Err(RecvError::UnexpectedRecord(DRR_BEGIN));
+                }
+                if begin.version_info & 3 != DMU_SUBSTREAM {
+                    return Err(RecvError::Unsupported(begin.version_info));
+                }
+                self.raw = begin.features() & DMU_BACKUP_FEATURE_RAW != 0;
+            }
+            _ if self.records == 0 => {
+                return Err(RecvError::UnexpectedRecord(Decoder::new(&drr, self.order).u32()))
+            }
+            _ => {}
+        }
+
+        // END also carries the checksum of the whole stream before it
+        if let RecordHeader::End { .. } = header {
+            let mut expected = [0; 4];
+            Decoder::new(&drr[8..], self.order).fill_u64(&mut expected);
+            if expected != [0; 4] && expected != self.checksum.finish() {
+                return Err(RecvError::Checksum { record: self.records });
+            }
+        }
+
+        // Every record but BEGIN carries the checksum of the stream up to its checksum field.
+        // Streams from before that was added carry zeros.
+        self.checksum.update(&drr[..DRR_CHECKSUM_OFFSET]);
+        if self.records != 0 {
+            let mut expected = [0; 4];
+            Decoder::new(&drr[DRR_CHECKSUM_OFFSET..], self.order).fill_u64(&mut expected);
+            if expected != [0; 4] && expected != self.checksum.finish() {
+                return Err(RecvError::Checksum { record: self.records });
+            }
+        }
+        self.checksum.update(&drr[DRR_CHECKSUM_OFFSET..]);
+
+        let mut payload = vec![0; payload_len];
+        try!(self.read_exact(&mut payload));
+        self.checksum.update(&payload);
+
+        self.records += 1;
+        if let RecordHeader::End { .. } = header {
+            self.ended = true;
+        }
+        Ok(Some(Record {
+            header: header,
+            payload: payload,
+        }))
+    }
+}
+
+/// What receiving a stream into a dataset takes
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecvPlan {
+    /// A full stream, into a new dataset or (with force) over one that has no snapshots
+    Full { overwrite: bool },
+    /// An incremental stream on top of snapshot `from`. With `rollback`, the changes made to
+    /// the dataset since `from` are thrown away first, along with the snapshots in `destroy`.
+    Incremental {
+        from: String,
+        rollback: bool,
+        destroy: Vec<String>,
+    },
+}
+
+/// The state of the dataset a stream is received into
+pub struct Target {
+    pub snapshots: Vec<Snapshot>,
+    /// Whether the dataset was written to since its latest snapshot
+    pub modified: bool,
+}
+
+impl Target {
+    pub fn of(reader: &mut ZfsReader, mos: &ObjectSet, dataset: &Dataset) -> Result<Self, String> {
+        let snapshots = try!(dataset.snapshots(reader, mos));
+        let modified = snapshots.last().map_or(true, |latest| dataset.modified_since(latest));
+        Ok(Target {
+            snapshots: snapshots,
+            modified: modified,
+        })
+    }
+}
+
+/// Work out how to receive the stream starting with `begin` into `target` (`None` if the
+/// dataset doesn't exist yet). `force` is `recv -F`: roll the dataset back to the incremental
+/// source, destroying any later snapshots.
+pub fn plan(begin: &Begin, target: Option<&Target>, force: bool) -> Result<RecvPlan, RecvError> {
+    let target = match target {
+        Some(target) => target,
+        None if begin.is_incremental() => return Err(RecvError::NoMatchingSnapshot(begin.fromguid)),
+        None => return Ok(RecvPlan::Full { overwrite: false }),
+    };
+
+    if let Some(existing) = target.snapshots.iter().find(|snap| snap.guid == begin.toguid) {
+        return Err(RecvError::SnapshotExists(existing.name.clone()));
+    }
+
+    if !begin.is_incremental() {
+        // Overwriting a dataset would lose its snapshots
+        if force && target.snapshots.is_empty() {
+            return Ok(RecvPlan::Full { overwrite: true });
+        }
+        return Err(RecvError::DestinationExists);
+    }
+
+    let from = match target.snapshots.iter().position(|snap| snap.guid == begin.fromguid) {
+        Some(from) => from,
+        None => return Err(RecvError::NoMatchingSnapshot(begin.fromguid)),
+    };
+    let latest = from + 1 == target.snapshots.len();
+    if !force {
+        if !latest {
+            return Err(RecvError::NotLatestSnapshot(target.snapshots[from].name.clone()));
+        }
+        if target.modified {
+            return Err(RecvError::DestinationModified);
+        }
+    }
+
+    Ok(RecvPlan::Incremental {
+        from: target.snapshots[from].name.clone(),
+        rollback: !latest || target.modified,
+        destroy: target.snapshots[from + 1..].iter().map(|snap| snap.name.clone()).collect(),
+    })
+}
+
+/// How far an interrupted receive got, so that `send -t` can pick up from there. Full receives
+/// keep it in the ZAP of the dataset being received, incremental ones in that of its `%recv`
+/// clone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumeState {
+    pub fromguid: u64,
+    pub toguid: u64,
+    pub toname: String,
+    /// The last block written: the sender starts over from it
+    pub object: u64,
+    pub offset: u64,
+    /// Bytes of the stream received so far
+    pub bytes: u64,
+    pub large_block_ok: bool,
+    pub embed_ok: bool,
+    pub compress_ok: bool,
+    pub raw_ok: bool,
+}
+
+impl ResumeState {
+    pub fn new(begin: &Begin) -> Self {
+        let features = begin.features();
+        ResumeState {
+            fromguid: begin.fromguid,
+            toguid: begin.toguid,
+            toname: begin.toname.clone(),
+            object: 0,
+            offset: 0,
+            bytes: 0,
+            large_block_ok: features & DMU_BACKUP_FEATURE_LARGE_BLOCKS != 0,
+            embed_ok: features & DMU_BACKUP_FEATURE_EMBED_DATA != 0,
+            compress_ok: features & DMU_BACKUP_FEATURE_COMPRESSED != 0,
+            raw_ok: features & DMU_BACKUP_FEATURE_RAW != 0,
+        }
+    }
+
+    /// Whether the stream starting with `begin` continues this receive
+    pub fn is_resumed_by(&self, begin: &Begin) -> bool {
+        begin.features() & DMU_BACKUP_FEATURE_RESUMING != 0 && begin.toguid == self.toguid &&
+        begin.fromguid == self.fromguid
+    }
+
+    /// The entries to keep in the dataset's ZAP
+    pub fn zap_entries(&self) -> Vec<(String, ZapValue)> {
+        let mut entries = Vec::new();
+        if self.fromguid != 0 {
+            entries.push((DS_FIELD_RESUME_FROMGUID.to_owned(), ZapValue::Int(self.fromguid)));
+        }
+        entries.push((DS_FIELD_RESUME_TOGUID.to_owned(), ZapValue::Int(self.toguid)));
+        entries.push((DS_FIELD_RESUME_TONAME.to_owned(), ZapValue::String(self.toname.clone())));
+        entries.push((DS_FIELD_RESUME_OBJECT.to_owned(), ZapValue::Int(self.object)));
+        entries.push((DS_FIELD_RESUME_OFFSET.to_owned(), ZapValue::Int(self.offset)));
+        entries.push((DS_FIELD_RESUME_BYTES.to_owned(), ZapValue::Int(self.bytes)));
+        // The flags are there or not, their value doesn't matter
+        for &(name, set) in &[(DS_FIELD_RESUME_LARGEBLOCK, self.large_block_ok),
+                              (DS_FIELD_RESUME_EMBEDOK, self.embed_ok),
+                              (DS_FIELD_RESUME_COMPRESSOK, self.compress_ok),
+                              (DS_FIELD_RESUME_RAWOK, self.raw_ok)] {
+            if set {
+                entries.push((name.to_owned(), ZapValue::Int(0)));
+            }
+        }
+        entries
+    }
+
+    /// The state kept in a dataset's ZAP, if a receive into it was interrupted
+    pub fn from_zap<I: IntoIterator<Item = (String, ZapValue)>>(entries: I) -> Option<Self> {
+        let mut state = ResumeState {
+            fromguid: 0,
+            toguid: 0,
+            toname: String::new(),
+            object: 0,
+            offset: 0,
+            bytes: 0,
+            large_block_ok: false,
+            embed_ok: false,
+            compress_ok: false,
+            raw_ok: false,
+        };
+        let mut found = false;
+        for (name, value) in entries {
+            match &name[..] {
+                DS_FIELD_RESUME_FROMGUID => state.fromguid = value.as_int().unwrap_or(0),
+                DS_FIELD_RESUME_TOGUID => {
+                    state.toguid = value.as_int().unwrap_or(0);
+                    found = true;
+                }
+                DS_FIELD_RESUME_TONAME => {
+                    state.toname = value.as_str().unwrap_or("").to_owned();
+                }
+                DS_FIELD_RESUME_OBJECT => state.object = value.as_int().unwrap_or(0),
+                DS_FIELD_RESUME_OFFSET => state.offset = value.as_int().unwrap_or(0),
+                DS_FIELD_RESUME_BYTES => state.bytes = value.as_int().unwrap_or(0),
+                DS_FIELD_RESUME_LARGEBLOCK => state.large_block_ok = true,
+                DS_FIELD_RESUME_EMBEDOK => state.embed_ok = true,
+                DS_FIELD_RESUME_COMPRESSOK => state.compress_ok = true,
+                DS_FIELD_RESUME_RAWOK => state.raw_ok = true,
+                _ => {}
+            }
+        }
+        if found { Some(state) } else { None }
+    }
+
+    /// The nvlist `zfs send -t` takes, as the receive_resume_token property holds it (there,
+    /// compressed and hex encoded)
+    pub fn token_nvlist(&self) -> NvList {
+        let mut nv_list = NvList::new(0);
+        if self.fromguid != 0 {
+            nv_list.add("fromguid".to_owned(), NvValue::Uint64(self.fromguid));
+        }
+        nv_list.add("object".to_owned(), NvValue::Uint64(self.object));
+        nv_list.add("offset".to_owned(), NvValue::Uint64(self.offset));
+        nv_list.add("bytes".to_owned(), NvValue::Uint64(self.bytes));
+        nv_list.add("toguid".to_owned(), NvValue::Uint64(self.toguid));
+        nv_list.add("toname".to_owned(), NvValue::String(self.toname.clone()));
+        for &(name, set) in &[("largeblockok", self.large_block_ok),
+                              ("embedok", self.embed_ok),
+                              ("compressok", self.compress_ok),
+                              ("rawok", self.raw_ok)] {
+            if set {
+                nv_list.add(name.to_owned(), NvValue::Boolean);
+            }
+        }
+        nv_list
+    }
+}
+
+/// The resume state of an interrupted receive into the dataset with MOS object number `object`.
+/// Only datasets with the extensible_dataset feature have a ZAP to keep it in.
+pub fn load_resume_state(reader: &mut ZfsReader,
+                         mos: &ObjectSet,
+                         object: u64)
+                         -> Result<Option<ResumeState>, String> {
+    let dnode = try!(mos.dnode(reader, object));
+    if dnode.object_type == ObjectType::DslObjSet as u8 {
+        return Ok(None);
+    }
+    Ok(ResumeState::from_zap(try!(zap::iter_values(reader, mos, object))))
+}
+
+/// Where the records of a received stream go
+pub trait RecvSink {
+    /// Apply a record of the stream to the dataset being received into
+    fn apply(&mut self, record: &Record) -> zfs::Result<()>;
+}
+
+/// Receive the rest of `stream`, whose BEGIN record was read already, into `sink`. `resume`
+/// tracks the records that were applied; if the receive fails part way, it's what to keep in
+/// the dataset so the stream can be resumed.
+pub fn receive<R: Read, S: RecvSink + ?Sized>(stream: &mut StreamReader<R>,
+                                              sink: &mut S,
+                                              resume: &mut ResumeState)
+                                              -> Result<(), RecvError> {
+    // A resumed stream only carries what's left, its bytes add to those received before
+    let start_bytes = resume.bytes;
+    while let Some(record) = try!(stream.next_record()) {
+        match record.header {
+            RecordHeader::Begin(_) => return Err(RecvError::UnexpectedRecord(DRR_BEGIN)),
+            RecordHeader::End { toguid } if toguid != resume.toguid => {
+                return Err(RecvError::ResumeMismatch);
+            }
+            _ => {}
+        }
+        try!(sink.apply(&record).map_err(RecvError::Apply));
+
+        match record.header {
+            RecordHeader::Write { object, offset, .. } |
+            RecordHeader::WriteByRef { object, offset, .. } |
+            RecordHeader::WriteEmbedded { object, offset, .. } => {
+                resume.object = object;
+                resume.offset = offset;
+            }
+            _ => {}
+        }
+        resume.bytes = start_bytes + stream.bytes();
+    }
+    Ok(())
+}
+
+/// The objset of the dataset with MOS object number `dataset`, as it's on the disk
+fn dataset_objset(reader: &mut ZfsReader, mos: &ObjectSet, dataset: u64) -> zfs::Result<ObjectSet> {
+    let dnode = try!(mos.dnode(reader, dataset).map_err(|_| zfs::Error::NoEntity));
+    let phys = try!(DslDatasetPhys::from_bytes(dnode.get_bonus()));
+    let block_ptr = phys.bp;
+    ObjectSet::open(reader, &block_ptr).map_err(|_| zfs::Error::Invalid)
+}
+
+/// Bytes of the blocks of the object described by `dnode`, 0 if it has none yet
+fn allocated_size(dnode: &DNodePhys) -> u64 {
+    if dnode.nlevels == 0 { 0 } else { dmu::allocated_size(dnode) }
+}
+
+/// Receives a stream into the objset of a dataset, through DMU transactions on the pool's open
+/// txg: the records are applied in memory, and go to the disk as the txgs are synced. A txg too
+/// full to take a record is synced first, `finish` syncs the rest.
+pub struct DatasetSink<'a> {
+    reader: &'a mut ZfsReader,
+    dsl_pool: &'a mut DslPool,
+    mos: &'a mut ObjectSet,
+    uberblock: &'a mut Uberblock,
+    alloc: &'a mut Allocator,
+    /// MOS object number of the dataset
+    dataset: u64,
+    /// The dataset's objset, as of the last txg synced
+    objset: ObjectSet,
+}
+
+impl<'a> DatasetSink<'a> {
+    /// Receive the stream starting with `begin` into the dataset with MOS object number
+    /// `dataset`. A full stream starts the dataset over with a new objset, an incremental one
+    /// goes on top of what's there.
+    ///
+    /// Raw streams aren't received: their blocks would have to be written as they are, which
+    /// syncing doesn't do.
+    pub fn new(reader: &'a mut ZfsReader,
+               dsl_pool: &'a mut DslPool,
+               mos: &'a mut ObjectSet,
+               uberblock: &'a mut Uberblock,
+               alloc: &'a mut Allocator,
+               dataset: u64,
+               begin: &Begin)
+               -> zfs::Result<Self> {
+        if begin.features() & DMU_BACKUP_FEATURE_RAW != 0 {
+            return Err(zfs::Error::UnsupportedFeature);
+        }
+        let (objset, created) = if begin.is_incremental() {
+            (try!(dataset_objset(reader, mos, dataset)), None)
+        } else {
+            let os_type = try!(ObjectSetType::from_u64(begin.objset_type as u64)
+                                   .ok_or(zfs::Error::Invalid));
+            (ObjectSet::create(os_type), Some(os_type))
+        };
+        let mut sink = DatasetSink {
+            reader: reader,
+            dsl_pool: dsl_pool,
+            mos: mos,
+            uberblock: uberblock,
+            alloc: alloc,
+            dataset: dataset,
+            objset: objset,
+        };
+        if let Some(os_type) = created {
+            let mut tx = Tx::create(dataset);
+            let txg = try!(sink.assign(&mut tx));
+            sink.dsl_pool.create_objset(txg, dataset, os_type);
+            tx.commit(sink.dsl_pool);
+        }
+        Ok(sink)
+    }
+
+    /// Sync everything received, once the stream is done
+    pub fn finish(mut self) -> zfs::Result<()> {
+        self.sync()
+    }
+
+    fn sync(&mut self) -> zfs::Result<()> {
+        while try!(spa::sync_txg(self.reader, self.dsl_pool, self.mos, self.uberblock, self.alloc))
+                  .is_some() {}
+        self.objset = try!(dataset_objset(self.reader, self.mos, self.dataset));
+        Ok(())
+    }
+
+    /// Assign `tx` to the open txg, syncing first if the txgs in flight are too full to take it
+    fn assign(&mut self, tx: &mut Tx) -> zfs::Result<u64> {
+        match tx.assign(self.dsl_pool) {
+            Err(zfs::Error::NoSpace) => {
+                try!(self.sync());
+                tx.assign(self.dsl_pool)
+            }
+            result => result,
+        }
+    }
+
+    /// The dnode of `object` as received so far, None if the object is free
+    fn dnode(&mut self, object: u64) -> zfs::Result<Option<DNodePhys>> {
+        let dnode = match self.dsl_pool.pending_dnode(self.dataset, object) {
+            Some(dnode) => dnode,
+            None => {
+                let meta_dnode = self.objset.phys.meta_dnode;
+                if object >= allocated_size(&meta_dnode) / DNODE_SIZE {
+                    return Ok(None);
+                }
+                try!(self.objset.dnode(self.reader, object).map_err(|_| zfs::Error::Invalid))
+            }
+        };
+        Ok(if dnode.object_type == 0 { None } else { Some(dnode) })
+    }
+
+    fn dirty_dnode(&mut self, object: u64, dnode: DNodePhys) -> zfs::Result<()> {
+        let mut tx = Tx::create(self.dataset);
+        let txg = try!(self.assign(&mut tx));
+        self.dsl_pool.dirty_dnode(txg, self.dataset, object, dnode);
+        tx.commit(self.dsl_pool);
+        Ok(())
+    }
+
+    /// Create `object`, or change its type and bonus buffer. An object that's there already
+    /// with another block size starts over empty.
+    // TODO: Free the blocks of the object it replaces
+    fn object(&mut self,
+              object: u64,
+              object_type: u32,
+              bonus_type: u32,
+              block_size: u32,
+              bonus: &[u8])
+              -> zfs::Result<()> {
+        if block_size == 0 || block_size % 512 != 0 || bonus.len() > DN_MAX_BONUSLEN {
+            return Err(zfs::Error::Invalid);
+        }
+        let nblkptr = (1 + (DN_MAX_BONUSLEN - bonus.len()) / 128) as u8;
+        let mut dnode = match try!(self.dnode(object)) {
+            Some(dnode) if dnode.data_block_size() == block_size as u64 &&
+                           dnode.nblkptr == nblkptr => dnode,
+            _ => {
+                let mut dnode = try!(DNodePhys::from_bytes(&[0; 512]));
+                dnode.indblkshift = DN_MAX_INDBLKSHIFT;
+                dnode.nblkptr = nblkptr;
+                dnode.data_blk_sz_sec = (block_size / 512) as u16;
+                dnode.flags = DNODE_FLAG_USED_BYTES;
+                dnode
+            }
+        };
+        dnode.object_type = object_type as u8;
+        dnode.bonus_type = bonus_type as u8;
+        dnode.bonus_len = bonus.len() as u16;
+        for (i, byte) in dnode.bonus_mut().iter_mut().enumerate() {
+            *byte = bonus.get(i).cloned().unwrap_or(0);
+        }
+        self.dirty_dnode(object, dnode)
+    }
+
+    fn free_objects(&mut self, first: u64, count: u64) -> zfs::Result<()> {
+        let meta_dnode = self.objset.phys.meta_dnode;
+        let end = cmp::min(first.saturating_add(count),
+                           allocated_size(&meta_dnode) / DNODE_SIZE);
+        for object in first..end {
+            if try!(self.dnode(object)).is_some() {
+                try!(self.dirty_dnode(object, try!(DNodePhys::from_bytes(&[0; 512]))));
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, object: u64, offset: u64, data: &[u8]) -> zfs::Result<()> {
+        let mut tx = Tx::create(self.dataset);
+        tx.hold_write(object, offset, data.len() as u64);
+        try!(self.assign(&mut tx));
+        let result = dmu::write(self.reader,
+                                self.dsl_pool,
+                                &self.objset,
+                                object,
+                                offset,
+                                data,
+                                &tx);
+        tx.commit(self.dsl_pool);
+        result
+    }
+
+    /// Free `length` bytes at `offset` of `object`. The blocks of the range are written over
+    /// with zeros, holes stay holes.
+    // TODO: Punch holes instead, once syncing can write them
+    fn free(&mut self, object: u64, offset: u64, length: u64) -> zfs::Result<()> {
+        let dnode = match try!(self.dnode(object)) {
+            Some(dnode) => dnode,
+            None => return Ok(()),
+        };
+        let end = cmp::min(offset.saturating_add(length), allocated_size(&dnode));
+        if offset >= end {
+            return Ok(());
+        }
+        let mut tx = Tx::create(self.dataset);
+        tx.hold_write(object, offset, end - offset);
+        try!(self.assign(&mut tx));
+        let result = dmu::free(self.reader,
+                               self.dsl_pool,
+                               &self.objset,
+                               object,
+                               offset,
+                               end - offset,
+                               &tx);
+        tx.commit(self.dsl_pool);
+        result
+    }
+}
+
+impl<'a> RecvSink for DatasetSink<'a> {
+    fn apply(&mut self, record: &Record) -> zfs::Result<()> {
+        match record.header {
+            RecordHeader::Object { object, object_type, bonus_type, block_size, bonus_len, .. } => {
+                let bonus = try!(record.payload
+                                       .get(..bonus_len as usize)
+                                       .ok_or(zfs::Error::Invalid));
+                self.object(object, object_type, bonus_type, block_size, bonus)
+            }
+            RecordHeader::FreeObjects { first, count } => self.free_objects(first, count),
+            RecordHeader::Write { crypt: Some(_), .. } => Err(zfs::Error::UnsupportedFeature),
+            RecordHeader::Write { object, offset, length, compression, crypt: None } => {
+                if compression == 0 {
+                    self.write(object, offset, &record.payload)
+                } else {
+                    let data = try!(zio_compress::decompress(compression as u64,
+                                                             record.payload.clone(),
+                                                             length as usize));
+                    self.write(object, offset, &data)
+                }
+            }
+            RecordHeader::Free { object, offset, length } => self.free(object, offset, length),
+            RecordHeader::Begin(_) |
+            RecordHeader::End { .. } |
+            RecordHeader::ObjectRange { .. } |
+            RecordHeader::Other(_) => Ok(()),
+            // Deduplicated (`send -D`), spill and embedded blocks take more than the DMU has
+            RecordHeader::WriteByRef { .. } |
+            RecordHeader::Spill { .. } |
+            RecordHeader::WriteEmbedded { .. } => Err(zfs::Error::Invalid),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------//
+
+#[cfg(test)]
+fn test_record(kind: u32, fields: &[u64], payload: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut drr = vec![0; DRR_SIZE];
+    drr[..4].copy_from_slice(&[kind as u8, 0, 0, 0]);
+    drr[4] = payload.len() as u8;
+    drr[5] = (payload.len() >> 8) as u8;
+    for (i, field) in fields.iter().enumerate() {
+        for b in 0..8 {
+            drr[8 + i * 8 + b] = (field >> (b * 8)) as u8;
+        }
+    }
+    (drr, payload.to_vec())
+}
+
+/// A little endian stream of `records`, with the running checksums filled in
+#[cfg(test)]
+fn test_stream(records: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+    let mut checksum = Fletcher4::new(ByteOrder::host() != ByteOrder::Little);
+    let mut stream = Vec::new();
+    for (i, (mut drr, payload)) in records.into_iter().enumerate() {
+        checksum.update(&drr[..DRR_CHECKSUM_OFFSET]);
+        if i != 0 {
+            let sums = checksum.finish();
+            for (word, sum) in drr[DRR_CHECKSUM_OFFSET..].chunks_mut(8).zip(sums.iter()) {
+                for b in 0..8 {
+                    word[b] = (sum >> (b * 8)) as u8;
+                }
+            }
+        }
+        checksum.update(&drr[DRR_CHECKSUM_OFFSET..]);
+        checksum.update(&payload);
+        stream.extend_from_slice(&drr);
+        stream.extend_from_slice(&payload);
+    }
+    stream
+}
+
+#[cfg(test)]
+fn test_begin(toguid: u64, fromguid: u64) -> (Vec<u8>, Vec<u8>) {
+    let (mut drr, payload) = test_record(DRR_BEGIN,
+                                         &[DMU_BACKUP_MAGIC,
+                                           DMU_SUBSTREAM | DMU_BACKUP_FEATURE_LARGE_BLOCKS << 2,
+                                           0,
+                                           2,
+                                           toguid,
+                                           fromguid],
+                                         &[]);
+    drr[56..69].copy_from_slice(b"tank/fs@snap2");
+    (drr, payload)
+}
+
+#[cfg(test)]
+struct TestSink {
+    writes: Vec<(u64, u64, Vec<u8>)>,
+    fail_at: usize,
+}
+
+#[cfg(test)]
+impl RecvSink for TestSink {
+    fn apply(&mut self, record: &Record) -> zfs::Result<()> {
+        if let RecordHeader::Write { object, offset, .. } = record.header {
+            if self.writes.len() == self.fail_at {
+                return Err(zfs::Error::NoSpace);
+            }
+            self.writes.push((object, offset, record.payload.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_receive_stream() {
+    let records = vec![test_begin(0x22, 0x11),
+                       test_record(DRR_OBJECT, &[5, 19 | 44 << 32, 512 | 3 << 32], &[1, 2, 3]),
+                       test_record(DRR_WRITE, &[5, 19, 0, 4], &[7; 4]),
+                       test_record(DRR_WRITE, &[5, 19, 512, 4], &[8; 4]),
+                       test_record(DRR_END, &[0, 0, 0, 0, 0x22], &[])];
+    // The bonus buffer is padded to 8 bytes
+    let mut records = records;
+    records[1].1.resize(8, 0);
+    let bytes = test_stream(records);
+
+    let mut stream = StreamReader::new(&bytes[..]);
+    let begin = stream.begin().unwrap();
+    assert_eq!((begin.toguid, begin.fromguid), (0x22, 0x11));
+    assert_eq!(begin.snapshot_name(), "snap2");
+    let mut resume = ResumeState::new(&begin);
+    assert!(resume.large_block_ok && !resume.compress_ok);
+
+    let mut sink = TestSink {
+        writes: Vec::new(),
+        fail_at: 100,
+    };
+    receive(&mut stream, &mut sink, &mut resume).unwrap();
+    assert_eq!(sink.writes, vec![(5, 0, vec![7; 4]), (5, 512, vec![8; 4])]);
+    assert_eq!(resume.bytes, bytes.len() as u64);
+
+    // A flipped bit shows up at the next record's checksum
+    let mut corrupt = bytes.clone();
+    corrupt[DRR_SIZE + DRR_SIZE + 2] ^= 1;
+    let mut stream = StreamReader::new(&corrupt[..]);
+    stream.begin().unwrap();
+    let mut resume = ResumeState::new(&begin);
+    assert_eq!(receive(&mut stream, &mut sink, &mut resume),
+               Err(RecvError::Checksum { record: 2 }));
+
+    // A failed write leaves the state at the last one that went through
+    let mut stream = StreamReader::new(&bytes[..]);
+    stream.begin().unwrap();
+    let mut resume = ResumeState::new(&begin);
+    let mut sink = TestSink {
+        writes: Vec::new(),
+        fail_at: 1,
+    };
+    assert_eq!(receive(&mut stream, &mut sink, &mut resume),
+               Err(RecvError::Apply(zfs::Error::NoSpace)));
+    assert_eq!((resume.object, resume.offset), (5, 0));
+
+    // So does a stream that's cut short
+    let mut stream = StreamReader::new(&bytes[..bytes.len() - 10]);
+    stream.begin().unwrap();
+    let mut resume = ResumeState::new(&begin);
+    let mut sink = TestSink {
+        writes: Vec::new(),
+        fail_at: 100,
+    };
+    assert_eq!(receive(&mut stream, &mut sink, &mut resume),
+               Err(RecvError::Io(io::ErrorKind::UnexpectedEof)));
+    assert_eq!((resume.object, resume.offset), (5, 512));
+
+    let entries = resume.zap_entries();
+    assert_eq!(ResumeState::from_zap(entries), Some(resume.clone()));
+    assert_eq!(resume.token_nvlist().get::<u64>("offset"), Some(512));
+    assert_eq!(ResumeState::from_zap(Vec::new()), None);
+}
+
+#[test]
+fn test_receive_raw_stream() {
+    let mut begin = test_begin(0x22, 0);
+    begin.0[19] |= (DMU_BACKUP_FEATURE_RAW << 2 >> 24) as u8;
+    begin.1 = b"keydata\0".to_vec();
+    begin.0[4] = 8;
+    // Salt, IV and MAC after the toguid, then the flags
+    let mut range = test_record(DRR_OBJECT_RANGE, &[32, 32, 0], &[]);
+    for i in 0..36 {
+        range.0[32 + i] = i as u8 + 1;
+    }
+    range.0[68] = DRR_RAW_BYTESWAP;
+    // The whole bonus area is sent
+    let object = test_record(DRR_OBJECT,
+                             &[33, 19 | 44 << 32, 512 | 3 << 32, 64 << 32],
+                             &[5; 64]);
+    // LZ4 compressed to 96 bytes, with the same parameters
+    let mut write = test_record(DRR_WRITE,
+                                &[33, 19, 0, 512, 0, 15 << 16, 0, 0, 0, 0, 0, 96],
+                                &[6; 96]);
+    for i in 0..36 {
+        write.0[104 + i] = i as u8 + 1;
+    }
+    let bytes = test_stream(vec![begin,
+                                 range,
+                                 object,
+                                 write,
+                                 test_record(DRR_END, &[0, 0, 0, 0, 0x22], &[])]);
+
+    let mut stream = StreamReader::new(&bytes[..]);
+    let begin = stream.begin().unwrap();
+    assert_eq!(stream.begin_payload(), b"keydata\0");
+    assert!(ResumeState::new(&begin).raw_ok);
+    let mut headers = Vec::new();
+    while let Some(record) = stream.next_record().unwrap() {
+        headers.push(record.header);
+    }
+    let mut crypt = CryptParams {
+        byteswap: true,
+        salt: [0; 8],
+        iv: [0; 12],
+        mac: [0; 16],
+    };
+    for i in 0..8 {
+        crypt.salt[i] = i as u8 + 1;
+    }
+    for i in 0..12 {
+        crypt.iv[i] = i as u8 + 9;
+    }
+    for i in 0..16 {
+        crypt.mac[i] = i as u8 + 21;
+    }
+    let mut unswapped = crypt;
+    unswapped.byteswap = false;
+    assert_eq!(headers,
+               vec![RecordHeader::ObjectRange {
+                        first: 32,
+                        count: 32,
+                        crypt: crypt,
+                    },
+                    RecordHeader::Object {
+                        object: 33,
+                        object_type: 19,
+                        bonus_type: 44,
+                        block_size: 512,
+                        bonus_len: 3,
+                        raw_bonus_len: 64,
+                    },
+                    RecordHeader::Write {
+                        object: 33,
+                        offset: 0,
+                        length: 512,
+                        compression: 15,
+                        crypt: Some(unswapped),
+                    },
+                    RecordHeader::End { toguid: 0x22 }]);
+}
+
+#[test]
+fn test_receive_plan() {
+    let snapshot = |name: &str, guid, txg| {
+        Snapshot {
+            name: name.to_owned(),
+            object: guid,
+            guid: guid,
+            creation_time: 0,
+            creation_txg: txg,
+            objset_txg: txg,
+            held: false,
+        }
+    };
+    let begin = |toguid, fromguid| {
+        Begin {
+            version_info: DMU_SUBSTREAM,
+            creation_time: 0,
+            objset_type: 2,
+            flags: 0,
+            toguid: toguid,
+            fromguid: fromguid,
+            toname: "tank/fs@d".to_owned(),
+        }
+    };
+    let target = Target {
+        snapshots: vec![snapshot("a", 0x10, 5), snapshot("b", 0x11, 9), snapshot("c", 0x12, 12)],
+        modified: false,
+    };
+
+    assert_eq!(plan(&begin(0x20, 0x12), Some(&target), false),
+               Ok(RecvPlan::Incremental {
+                   from: "c".to_owned(),
+                   rollback: false,
+                   destroy: vec![],
+               }));
+    assert_eq!(plan(&begin(0x20, 0x12), None, false),
+               Err(RecvError::NoMatchingSnapshot(0x12)));
+    assert_eq!(plan(&begin(0x20, 0x99), Some(&target), true),
+               Err(RecvError::NoMatchingSnapshot(0x99)));
+    assert_eq!(plan(&begin(0x11, 0x12), Some(&target), true),
+               Err(RecvError::SnapshotExists("b".to_owned())));
+
+    // From an older snapshot, which takes -F
+    assert_eq!(plan(&begin(0x20, 0x10), Some(&target), false),
+               Err(RecvError::NotLatestSnapshot("a".to_owned())));
+    assert_eq!(plan(&begin(0x20, 0x10), Some(&target), true),
+               Ok(RecvPlan::Incremental {
+                   from: "a".to_owned(),
+                   rollback: true,
+                   destroy: vec!["b".to_owned(), "c".to_owned()],
+               }));
+
+    // Changes since the latest snapshot are only thrown away with -F
+    let modified = Target {
+        snapshots: target.snapshots.clone(),
+        modified: true,
+    };
+    assert_eq!(plan(&begin(0x20, 0x12), Some(&modified), false),
+               Err(RecvError::DestinationModified));
+    assert_eq!(plan(&begin(0x20, 0x12), Some(&modified), true),
+               Ok(RecvPlan::Incremental {
+                   from: "c".to_owned(),
+                   rollback: true,
+                   destroy: vec![],
+               }));
+
+    // Full streams only overwrite datasets without snapshots
+    assert_eq!(plan(&begin(0x20, 0), None, false),
+               Ok(RecvPlan::Full { overwrite: false }));
+    assert_eq!(plan(&begin(0x20, 0), Some(&target), true),
+               Err(RecvError::DestinationExists));
+    let empty = Target {
+        snapshots: vec![],
+        modified: true,
+    };
+    assert_eq!(plan(&begin(0x20, 0), Some(&empty), false),
+               Err(RecvError::DestinationExists));
+    assert_eq!(plan(&begin(0x20, 0), Some(&empty), true),
+               Ok(RecvPlan::Full { overwrite: true }));
+}
+
+#[test]
+fn test_dataset_sink() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::dsl_pool::MOS_OBJSET;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let mut mos = ObjectSet::create(ObjectSetType::Meta);
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0c, 0xb1, 0xba, 0, 0, 0, 0, 0]);
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // version 5000
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+
+    // Dataset 5 of the MOS, which has no objset yet
+    let mut dsl_pool = DslPool::new();
+    let mut dataset = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dataset.object_type = ObjectType::DslObjSet as u8;
+    dataset.bonus_type = ObjectType::DslObjSet as u8;
+    dataset.bonus_len = 320;
+    dataset.nblkptr = 1;
+    dataset.indblkshift = 14;
+    dataset.data_blk_sz_sec = 1;
+    let mut tx = Tx::create(MOS_OBJSET);
+    let txg = tx.assign(&mut dsl_pool).unwrap();
+    dsl_pool.dirty_dnode(txg, MOS_OBJSET, 5, dataset);
+    tx.commit(&mut dsl_pool);
+    spa::sync_txg(&mut reader, &mut dsl_pool, &mut mos, &mut uberblock, &mut alloc).unwrap();
+    // Small enough that every write takes a sync first
+    dsl_pool.dirty_data_max = 600;
+
+    let mut receive_into = |records: Vec<(Vec<u8>, Vec<u8>)>| {
+        let bytes = test_stream(records);
+        let mut stream = StreamReader::new(&bytes[..]);
+        let begin = stream.begin().unwrap();
+        let mut resume = ResumeState::new(&begin);
+        let mut sink = DatasetSink::new(&mut reader,
+                                        &mut dsl_pool,
+                                        &mut mos,
+                                        &mut uberblock,
+                                        &mut alloc,
+                                        5,
+                                        &begin)
+                           .unwrap();
+        receive(&mut stream, &mut sink, &mut resume).unwrap();
+        sink.finish().unwrap();
+        let objset = dataset_objset(&mut reader, &mos, 5).unwrap();
+        let dnode = objset.dnode(&mut reader, 2).unwrap();
+        let data = dmu::read(&mut reader, &dnode, 0, 1536).unwrap();
+        let hole = dmu::block_ptr(&mut reader, &dnode, 1).unwrap().is_none();
+        (dnode, data, hole, objset.dnode(&mut reader, 3).unwrap().object_type)
+    };
+
+    // A file of three blocks, the middle one a hole
+    let mut object = test_record(DRR_OBJECT, &[2, 19 | 44 << 32, 512 | 3 << 32], &[1, 2, 3]);
+    object.1.resize(8, 0);
+    let (dnode, data, hole, _) = receive_into(vec![test_begin(0x22, 0),
+                                             object.clone(),
+                                             test_record(DRR_FREE, &[2, 1536, !0], &[]),
+                                             test_record(DRR_WRITE, &[2, 19, 0, 512], &[7; 512]),
+                                             test_record(DRR_WRITE, &[2, 19, 1024, 512], &[8; 512]),
+                                             test_record(DRR_OBJECT, &[3, 19, 512], &[]),
+                                             test_record(DRR_END, &[0, 0, 0, 0, 0x22], &[])]);
+    let (object_type, maxblkid) = (dnode.object_type, dnode.maxblkid);
+    assert_eq!((object_type, maxblkid), (19, 2));
+    assert_eq!(&dnode.get_bonus()[..3], &[1, 2, 3]);
+    assert!(hole);
+    let mut expected = vec![7; 512];
+    expected.extend_from_slice(&[0; 512]);
+    expected.extend_from_slice(&[8; 512]);
+    assert_eq!(data, expected);
+
+    // An incremental on top of it frees the first block and object 3, and writes into the hole
+    let (_, data, _, freed_type) = receive_into(vec![test_begin(0x33, 0x22),
+                                                  test_record(DRR_FREE, &[2, 0, 512], &[]),
+                                                  test_record(DRR_WRITE, &[2, 19, 512, 4], &[9; 4]),
+                                                  test_record(DRR_FREEOBJECTS, &[3, 5], &[]),
+                                                  test_record(DRR_END, &[0, 0, 0, 0, 0x33], &[])]);
+    expected[..512].copy_from_slice(&[0; 512]);
+    expected[512..516].copy_from_slice(&[9; 4]);
+    assert_eq!(data, expected);
+    assert_eq!(freed_type, 0);
+
+    // The END record checks the stream as a whole
+    let bytes = test_stream(vec![test_begin(0x22, 0),
+                                 object,
+                                 test_record(DRR_END, &[1, 2, 3, 4, 0x22], &[])]);
+    let mut stream = StreamReader::new(&bytes[..]);
+    stream.begin().unwrap();
+    stream.next_record().unwrap();
+    assert_eq!(stream.next_record(), Err(RecvError::Checksum { record: 2 }));
+}
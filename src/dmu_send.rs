@@ -0,0 +1,765 @@
+use std::{cmp, mem};
+use std::io::Write;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum::{Checksum, Fletcher4};
+use super::dmu_objset::ObjectSet;
+use super::dmu_recv::{CryptParams, DMU_BACKUP_FEATURE_COMPRESSED,
+                      DMU_BACKUP_FEATURE_LARGE_BLOCKS, DMU_BACKUP_FEATURE_RAW, DMU_BACKUP_MAGIC,
+                      DMU_SUBSTREAM, DRR_BEGIN, DRR_CHECKSUM_OFFSET, DRR_END, DRR_FREE,
+                      DRR_FREEOBJECTS, DRR_OBJECT, DRR_OBJECT_RANGE, DRR_RAW_BYTESWAP, DRR_SIZE,
+                      DRR_WRITE};
+use super::dnode::{DNODE_FLAG_SPILL_BLKPTR, DNODE_SIZE, DNodePhys};
+use super::dsl_crypt;
+use super::dsl_dataset::{self, Dataset};
+use super::dsl_dir::DslDir;
+use super::from_bytes::{ByteOrder, FromBytes};
+use super::nvpair::{NvList, NvValue};
+use super::nvstream;
+use super::util;
+use super::xdr;
+use super::zfs;
+
+/// Largest block receivers without large block support take (`SPA_OLD_MAXBLOCKSIZE`)
+const OLD_MAX_BLOCK_SIZE: u64 = 128 << 10;
+
+/// How to send a snapshot
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Send blocks bigger than 128K as they are (`zfs send -L`). Otherwise they're split up into
+    /// 128K writes, which any receiver takes.
+    pub large_blocks: bool,
+    /// Send blocks as they're on disk, compressed, and encrypted if the dataset is (`zfs send
+    /// -w`). The receiver gets an encrypted dataset without ever seeing the key, which doesn't
+    /// have to be loaded.
+    pub raw: bool,
+}
+
+/// Counters of a send
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SendStats {
+    pub records: u64,
+    /// Bytes of the whole stream, headers included
+    pub bytes: u64,
+    /// Bytes of file data sent in WRITE records
+    pub data_bytes: u64,
+}
+
+/// The fields of the BEGIN record
+struct Begin<'a> {
+    features: u64,
+    creation_time: u64,
+    objset_type: u32,
+    toguid: u64,
+    fromguid: u64,
+    toname: &'a str,
+    /// The packed nvlist sent along, empty but for raw streams
+    payload: Vec<u8>,
+}
+
+/// Writes the records of a stream in host byte order, with the running checksum of the stream
+/// at the end of each of them
+struct StreamWriter<'a, W: 'a + Write> {
+    out: &'a mut W,
+    /// Whether the stream is raw, with encryption parameters in its records
+    raw: bool,
+    checksum: Fletcher4,
+    stats: SendStats,
+}
+
+impl<'a, W: Write> StreamWriter<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        StreamWriter {
+            out: out,
+            raw: false,
+            checksum: Fletcher4::new(false),
+            stats: SendStats::default(),
+        }
+    }
+
+    /// Write a record of type `kind`: `fields` are the rest of its header, `payload` follows it
+    fn record(&mut self, kind: u32, fields: &[u8], payload: &[u8]) -> zfs::Result<()> {
+        let padded = util::round_up(payload.len() as u64, 8) as usize;
+        let mut drr = Vec::with_capacity(DRR_SIZE);
+        put_u32(&mut drr, kind);
+        put_u32(&mut drr, padded as u32);
+        drr.extend_from_slice(fields);
+        drr.resize(DRR_SIZE, 0);
+
+        // The checksum covers the stream up to the checksum field, BEGIN has none
+        self.checksum.update(&drr[..DRR_CHECKSUM_OFFSET]);
+        if kind != DRR_BEGIN {
+            let sums = self.checksum.finish();
+            drr.truncate(DRR_CHECKSUM_OFFSET);
+            for &sum in &sums {
+                put_u64(&mut drr, sum);
+            }
+        }
+        self.checksum.update(&drr[DRR_CHECKSUM_OFFSET..]);
+        try!(self.out.write_all(&drr));
+
+        let mut payload = payload.to_vec();
+        payload.resize(padded, 0);
+        self.checksum.update(&payload);
+        try!(self.out.write_all(&payload));
+
+        self.stats.records += 1;
+        self.stats.bytes += (DRR_SIZE + padded) as u64;
+        Ok(())
+    }
+
+    fn begin(&mut self, begin: &Begin) -> zfs::Result<()> {
+        let mut fields = Vec::new();
+        put_u64(&mut fields, DMU_BACKUP_MAGIC);
+        put_u64(&mut fields, DMU_SUBSTREAM | begin.features << 2);
+        put_u64(&mut fields, begin.creation_time);
+        put_u32(&mut fields, begin.objset_type);
+        put_u32(&mut fields, 0); // flags
+        put_u64(&mut fields, begin.toguid);
+        put_u64(&mut fields, begin.fromguid);
+        let name = begin.toname.as_bytes();
+        fields.extend_from_slice(&name[..cmp::min(name.len(), 255)]);
+        self.raw = begin.features & DMU_BACKUP_FEATURE_RAW != 0;
+        self.record(DRR_BEGIN, &fields, &begin.payload)
+    }
+
+    /// `extra` is the slots after the first one of a large dnode, which its bonus buffer goes on
+    /// into. `raw_bonus` is, for raw streams, the whole bonus area of the dnode as it's on disk
+    /// along with whether the block it's in is in the other byte order.
+    fn object(&mut self,
+              object: u64,
+              dnode: &DNodePhys,
+              extra: &[u8],
+              block_size: u64,
+              raw_bonus: Option<(&[u8], bool)>)
+              -> zfs::Result<()> {
+        let bonus = dnode.large_bonus(extra);
+        let bonus_len = cmp::min(dnode.bonus_len as usize, bonus.len());
+        let (flags, payload) = match raw_bonus {
+            // The bonus buffer is encrypted as a whole, past its length too
+            Some((bonus, byteswap)) if bonus_len != 0 => {
+                (if byteswap { DRR_RAW_BYTESWAP } else { 0 }, bonus)
+            }
+            Some((_, byteswap)) => (if byteswap { DRR_RAW_BYTESWAP } else { 0 }, &[][..]),
+            None => (0, &bonus[..bonus_len]),
+        };
+        let raw_bonus_len = if raw_bonus.is_some() { payload.len() } else { 0 };
+        let mut fields = Vec::new();
+        put_u64(&mut fields, object);
+        put_u32(&mut fields, dnode.object_type as u32);
+        put_u32(&mut fields, dnode.bonus_type as u32);
+        put_u32(&mut fields, block_size as u32);
+        put_u32(&mut fields, bonus_len as u32);
+        fields.extend_from_slice(&[dnode.checksum, dnode.compress, 1 + dnode.extra_slots, flags]);
+        put_u32(&mut fields, raw_bonus_len as u32);
+        put_u64(&mut fields, 0); // toguid
+        fields.extend_from_slice(&[dnode.indblkshift, dnode.nlevels, dnode.nblkptr, 0, 0, 0, 0, 0]);
+        put_u64(&mut fields, dnode.maxblkid);
+        self.record(DRR_OBJECT, &fields, payload)
+    }
+
+    /// The objects of a dnode block of a raw stream, with how the block was encrypted
+    fn object_range(&mut self, first: u64, count: u64, crypt: &CryptParams) -> zfs::Result<()> {
+        let mut fields = Vec::new();
+        put_u64(&mut fields, first);
+        put_u64(&mut fields, count);
+        put_u64(&mut fields, 0); // toguid
+        put_crypt_params(&mut fields, crypt);
+        fields.push(if crypt.byteswap { DRR_RAW_BYTESWAP } else { 0 });
+        self.record(DRR_OBJECT_RANGE, &fields, &[])
+    }
+
+    fn free_objects(&mut self, first: u64, count: u64) -> zfs::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut fields = Vec::new();
+        put_u64(&mut fields, first);
+        put_u64(&mut fields, count);
+        self.record(DRR_FREEOBJECTS, &fields, &[])
+    }
+
+    /// `length` is `u64::MAX` for everything from `offset` on
+    fn free(&mut self, object: u64, offset: u64, length: u64) -> zfs::Result<()> {
+        let mut fields = Vec::new();
+        put_u64(&mut fields, object);
+        put_u64(&mut fields, offset);
+        put_u64(&mut fields, length);
+        self.record(DRR_FREE, &fields, &[])
+    }
+
+    fn write(&mut self,
+             object: u64,
+             object_type: u8,
+             offset: u64,
+             checksum: u8,
+             data: &[u8])
+             -> zfs::Result<()> {
+        let mut fields = Vec::new();
+        put_u64(&mut fields, object);
+        put_u32(&mut fields, object_type as u32);
+        put_u32(&mut fields, 0);
+        put_u64(&mut fields, offset);
+        put_u64(&mut fields, data.len() as u64);
+        put_u64(&mut fields, 0); // toguid
+        // Checksum type, flags, compression (none), padding and the dedup key, then the
+        // compressed size: the data is sent as it reads
+        fields.extend_from_slice(&[checksum, 0, 0]);
+        fields.resize(fields.len() + 5 + 40 + 8, 0);
+        try!(self.record(DRR_WRITE, &fields, data));
+        self.stats.data_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write the block `block_ptr` points to as it's on disk, `data`, whole. Only raw streams
+    /// carry how it was encrypted.
+    fn write_raw(&mut self,
+                 object: u64,
+                 object_type: u8,
+                 offset: u64,
+                 block_ptr: &BlockPtr,
+                 data: &[u8])
+                 -> zfs::Result<()> {
+        let crypt = CryptParams::of(block_ptr);
+        let flags = if self.raw && crypt.byteswap { DRR_RAW_BYTESWAP } else { 0 };
+        let mut fields = Vec::new();
+        put_u64(&mut fields, object);
+        put_u32(&mut fields, object_type as u32);
+        put_u32(&mut fields, 0);
+        put_u64(&mut fields, offset);
+        put_u64(&mut fields, block_ptr.lsize() * 512);
+        put_u64(&mut fields, 0); // toguid
+        fields.extend_from_slice(&[block_ptr.checksum() as u8,
+                                   flags,
+                                   block_ptr.compression() as u8]);
+        fields.resize(fields.len() + 5 + 40, 0);
+        put_u64(&mut fields, data.len() as u64);
+        if self.raw {
+            put_crypt_params(&mut fields, &crypt);
+        }
+        try!(self.record(DRR_WRITE, &fields, data));
+        self.stats.data_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// The END record, carrying the checksum of the whole stream before it
+    fn end(&mut self, toguid: u64) -> zfs::Result<SendStats> {
+        let mut fields = Vec::new();
+        for &sum in &self.checksum.finish() {
+            put_u64(&mut fields, sum);
+        }
+        put_u64(&mut fields, toguid);
+        try!(self.record(DRR_END, &fields, &[]));
+        try!(self.out.flush());
+        Ok(self.stats)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_crypt_params(buf: &mut Vec<u8>, crypt: &CryptParams) {
+    buf.extend_from_slice(&crypt.salt);
+    buf.extend_from_slice(&crypt.iv);
+    buf.extend_from_slice(&crypt.mac);
+}
+
+/// A run of level 0 blocks of an object born after a txg: one block with its block pointer, or
+/// a hole covering `count` blocks
+struct Changed {
+    blkid: u64,
+    count: u64,
+    block_ptr: Option<BlockPtr>,
+}
+
+/// The level 0 blocks of the object described by `dnode` born after `from_txg`, in block id
+/// order. Subtrees born before it are skipped without being read: nothing under them changed.
+/// Holes are only listed if they were punched after it, the hole was there all along otherwise.
+fn changed_blocks(reader: &mut ZfsReader,
+                  dnode: &DNodePhys,
+                  from_txg: u64)
+                  -> zfs::Result<Vec<Changed>> {
+    let mut changed = Vec::new();
+    if dnode.nlevels == 0 {
+        return Ok(changed);
+    }
+    let epbs = dnode.epbs();
+    let level = dnode.nlevels as u64 - 1;
+    for i in 0..dnode.nblkptr as usize {
+        try!(changed_under(reader,
+                           &dnode.get_blockptr(i),
+                           level,
+                           i as u64,
+                           epbs,
+                           from_txg,
+                           &mut changed));
+    }
+    Ok(changed)
+}
+
+fn changed_under(reader: &mut ZfsReader,
+                 block_ptr: &BlockPtr,
+                 level: u64,
+                 index: u64,
+                 epbs: u64,
+                 from_txg: u64,
+                 changed: &mut Vec<Changed>)
+                 -> zfs::Result<()> {
+    if block_ptr.birth_txg <= from_txg {
+        return Ok(());
+    }
+    let shift = (level * epbs) as u32;
+    if block_ptr.is_hole() {
+        changed.push(Changed {
+            blkid: index << shift,
+            count: 1 << shift,
+            block_ptr: None,
+        });
+    } else if level == 0 {
+        changed.push(Changed {
+            blkid: index,
+            count: 1,
+            block_ptr: Some(*block_ptr),
+        });
+    } else {
+        let data = try!(reader.read_block(block_ptr));
+        for (i, chunk) in data.chunks(mem::size_of::<BlockPtr>()).enumerate() {
+            let child = try!(BlockPtr::from_bytes_order(chunk, block_ptr.data_order()));
+            try!(changed_under(reader,
+                               &child,
+                               level - 1,
+                               (index << epbs) + i as u64,
+                               epbs,
+                               from_txg,
+                               changed));
+        }
+    }
+    Ok(())
+}
+
+/// Send the object `object` described by `dnode` (and `extra`, see `StreamWriter::object`): its
+/// dnode, then the blocks written after `from_txg`
+fn send_object<W: Write>(stream: &mut StreamWriter<W>,
+                         reader: &mut ZfsReader,
+                         object: u64,
+                         dnode: &DNodePhys,
+                         extra: &[u8],
+                         raw_bonus: Option<(&[u8], bool)>,
+                         from_txg: u64,
+                         options: &SendOptions)
+                         -> zfs::Result<()> {
+    let block_size = dnode.data_block_size();
+    // Big blocks are split up for receivers that can't take them, which the object's block
+    // size has to agree with. Blocks sent as they're on disk can't be.
+    let split = if options.large_blocks || options.raw {
+        block_size
+    } else {
+        cmp::min(block_size, OLD_MAX_BLOCK_SIZE)
+    };
+    try!(stream.object(object, dnode, extra, split, raw_bonus));
+    // Whatever the receiver has past the end of the object goes
+    try!(stream.free(object, (dnode.maxblkid + 1) * block_size, u64::max_value()));
+
+    for changed in try!(changed_blocks(reader, dnode, from_txg)) {
+        let offset = changed.blkid * block_size;
+        match changed.block_ptr {
+            Some(block_ptr) if options.raw && !block_ptr.is_embedded() => {
+                let data = try!(reader.zio.read_block_raw(&block_ptr));
+                try!(stream.write_raw(object, dnode.object_type, offset, &block_ptr, &data));
+            }
+            Some(block_ptr) => {
+                let data = try!(reader.read_block(&block_ptr));
+                for (i, chunk) in data.chunks(cmp::max(split, 1) as usize).enumerate() {
+                    try!(stream.write(object,
+                                      dnode.object_type,
+                                      offset + i as u64 * split,
+                                      block_ptr.checksum() as u8,
+                                      chunk));
+                }
+            }
+            None => try!(stream.free(object, offset, changed.count * block_size)),
+        }
+    }
+    Ok(())
+}
+
+/// Send `objset` as a stream recreating it, to `out`. Only what changed after `from_txg` is
+/// sent: the dnodes of the dnode blocks written since, and the data blocks written since.
+fn send_objset<W: Write>(reader: &mut ZfsReader,
+                         objset: &ObjectSet,
+                         begin: &Begin,
+                         from_txg: u64,
+                         options: &SendOptions,
+                         out: &mut W)
+                         -> zfs::Result<SendStats> {
+    let mut stream = StreamWriter::new(out);
+    try!(stream.begin(begin));
+
+    let meta_dnode = objset.phys.meta_dnode;
+    let per_block = meta_dnode.dnodes_per_block();
+    // The run of free objects not sent yet
+    let mut free = (0, 0);
+    for changed in try!(changed_blocks(reader, &meta_dnode, from_txg)) {
+        let first = changed.blkid * per_block;
+        let block_ptr = match changed.block_ptr {
+            Some(block_ptr) => block_ptr,
+            None => {
+                // A whole range of dnode blocks freed
+                if free.0 + free.1 != first {
+                    try!(stream.free_objects(free.0, free.1));
+                    free = (first, 0);
+                }
+                free.1 += changed.count * per_block;
+                continue;
+            }
+        };
+        // Dnode blocks of encrypted datasets aren't compressed, only their bonus buffers are
+        // encrypted: they're sent as they are, and their dnodes still read
+        let raw = stream.raw && block_ptr.is_encrypted();
+        let data = if raw {
+            try!(stream.object_range(first, per_block, &CryptParams::of(&block_ptr)));
+            try!(reader.zio.read_block_raw(&block_ptr))
+        } else {
+            try!(reader.read_block(&block_ptr))
+        };
+        let byteswap = block_ptr.data_order() != ByteOrder::host();
+        let mut slot = 0;
+        while slot < per_block as usize && (slot + 1) * DNODE_SIZE as usize <= data.len() {
+            let object = first + slot as u64;
+            let offset = slot * DNODE_SIZE as usize;
+            let dnode = try!(DNodePhys::from_bytes_order(&data[offset..],
+                                                         block_ptr.data_order()));
+            slot += 1 + dnode.extra_slots as usize;
+            if object == 0 {
+                // The slot of the meta dnode itself
+                continue;
+            }
+            if dnode.object_type == 0 {
+                if free.0 + free.1 != object {
+                    try!(stream.free_objects(free.0, free.1));
+                    free = (object, 0);
+                }
+                free.1 += 1;
+                continue;
+            }
+            try!(stream.free_objects(free.0, free.1));
+            free = (object + 1 + dnode.extra_slots as u64, 0);
+            let raw_bonus = if raw {
+                let spill = if dnode.flags & DNODE_FLAG_SPILL_BLKPTR != 0 { 128 } else { 0 };
+                let start = offset + 64 + 128 * dnode.nblkptr as usize;
+                let end = offset + (1 + dnode.extra_slots as usize) * DNODE_SIZE as usize - spill;
+                Some((try!(data.get(start..end).ok_or(zfs::Error::Invalid)), byteswap))
+            } else {
+                None
+            };
+            let extra_start = offset + DNODE_SIZE as usize;
+            let extra_end = offset + dnode.slots() as usize * DNODE_SIZE as usize;
+            let extra = try!(data.get(extra_start..extra_end).ok_or(zfs::Error::Invalid));
+            try!(send_object(&mut stream,
+                             reader,
+                             object,
+                             &dnode,
+                             extra,
+                             raw_bonus,
+                             from_txg,
+                             options));
+        }
+    }
+    try!(stream.free_objects(free.0, free.1));
+    stream.end(begin.toguid)
+}
+
+/// The BEGIN record of a stream recreating `snapshot`, named `toname`, from snapshot `from` if
+/// it's incremental. Raw streams of encrypted datasets carry their key, still wrapped.
+fn begin<'a>(reader: &mut ZfsReader,
+             mos: &ObjectSet,
+             snapshot: &Dataset,
+             toname: &'a str,
+             from: Option<&Dataset>,
+             options: &SendOptions)
+             -> zfs::Result<Begin<'a>> {
+    let mut features = 0;
+    if options.large_blocks || options.raw {
+        features |= DMU_BACKUP_FEATURE_LARGE_BLOCKS;
+    }
+    let mut payload = Vec::new();
+    if options.raw {
+        features |= DMU_BACKUP_FEATURE_COMPRESSED;
+        let dir = try!(DslDir::open(reader, mos, snapshot.phys.dir_obj)
+                           .map_err(|_| zfs::Error::Invalid));
+        match dsl_crypt::key_nvlist(reader,
+                                    mos,
+                                    &dir,
+                                    snapshot.object,
+                                    from.map(|from| from.object),
+                                    &snapshot.objset) {
+            Ok(keydata) => {
+                features |= DMU_BACKUP_FEATURE_RAW;
+                let mut nv = NvList::new(1);
+                nv.add("crypt_keydata".to_owned(), NvValue::NvList(keydata));
+                payload = vec![0; nvstream::encoded_size(&nv)];
+                try!(nvstream::encode_nv_list(&mut xdr::MemOps::new(&mut payload), &nv)
+                         .map_err(|_| zfs::Error::Invalid));
+            }
+            // Not encrypted, then it's only sent compressed
+            Err(zfs::Error::NoEntity) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Begin {
+        features: features,
+        creation_time: snapshot.phys.creation_time,
+        objset_type: snapshot.objset.phys.os_type as u32,
+        toguid: snapshot.phys.guid,
+        fromguid: from.map_or(0, |from| from.phys.guid),
+        toname: toname,
+        payload: payload,
+    })
+}
+
+/// Send `snapshot` as a full replication stream (like `zfs send`) named `toname`, e.g.
+/// `tank/fs@monday`, to `out`. The dataset is read as it was opened, so a pinned head dataset
+/// can be sent too. Objects are sent in order, each followed by the blocks of its data.
+pub fn send<W: Write>(reader: &mut ZfsReader,
+                      mos: &ObjectSet,
+                      snapshot: &Dataset,
+                      toname: &str,
+                      options: &SendOptions,
+                      out: &mut W)
+                      -> zfs::Result<SendStats> {
+    let begin = try!(begin(reader, mos, snapshot, toname, None, options));
+    send_objset(reader, &snapshot.objset, &begin, 0, options, out)
+}
+
+/// Send what changed from snapshot `from` to `snapshot`, a later snapshot of the same dataset (or
+/// a clone of it), as an incremental stream (like `zfs send -i`) to `out`. It only takes a
+/// receiver that has `from`. Blocks born before `from` was taken aren't sent. What was deleted
+/// since is, as FREEOBJECTS for the objects freed and FREE for the end of each object, and for
+/// the holes punched since (which only pools with `hole_birth` know the txg of).
+pub fn send_incremental<W: Write>(reader: &mut ZfsReader,
+                                  mos: &ObjectSet,
+                                  from: &Dataset,
+                                  snapshot: &Dataset,
+                                  toname: &str,
+                                  options: &SendOptions,
+                                  out: &mut W)
+                                  -> zfs::Result<SendStats> {
+    if !try!(dsl_dataset::is_snapshot_before(reader, mos, from.object, snapshot.object)
+                 .map_err(|_| zfs::Error::Invalid)) {
+        return Err(zfs::Error::Invalid);
+    }
+    let begin = try!(begin(reader, mos, snapshot, toname, Some(from), options));
+    send_objset(reader,
+                &snapshot.objset,
+                &begin,
+                from.phys.creation_txg,
+                options,
+                out)
+}
+
+#[test]
+fn test_send() {
+    use std::collections::BTreeMap;
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_objset::ObjectSetPhys;
+    use super::dmu_recv::{RecordHeader, StreamReader};
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::ObjectType;
+    use super::dnode_sync;
+    use super::test_util::Bump;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+    use super::zio_compress;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = Bump(0);
+    let new_dnode = |object_type: ObjectType, sectors: u16| {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = object_type as u8;
+        dnode.nblkptr = 1;
+        dnode.indblkshift = 10;
+        dnode.data_blk_sz_sec = sectors;
+        dnode
+    };
+    let mut sync = |reader: &mut ZfsReader,
+                    txg: u64,
+                    dnode: &mut DNodePhys,
+                    blocks: Vec<(u64, Vec<u8>)>| {
+        let blocks: BTreeMap<u64, Vec<u8>> = blocks.into_iter().collect();
+        let mut freed = Vec::new();
+        dnode_sync::sync_dnode(reader,
+                               &mut alloc,
+                               None,
+                               zio_compress::ZIO_COMPRESS_OFF,
+                               txg,
+                               dnode,
+                               &blocks,
+                               &mut freed)
+            .unwrap();
+    };
+
+    // Object 2 is a file with a hole at block 1, object 3 is free, object 4 a file with a bonus
+    let mut file = new_dnode(ObjectType::PlainFileContents, 1);
+    sync(&mut reader, 5, &mut file, vec![(0, vec![1; 512]), (2, vec![3; 512])]);
+    let mut other = new_dnode(ObjectType::PlainFileContents, 2);
+    other.bonus_type = ObjectType::ZNode as u8;
+    other.bonus_len = 3;
+    other.bonus_mut()[..3].copy_from_slice(b"abc");
+    sync(&mut reader, 5, &mut other, vec![(0, vec![4; 1024])]);
+    let mut dnodes = vec![0; 4096];
+    dnodes[2 * 512..3 * 512].copy_from_slice(file.as_bytes());
+    dnodes[4 * 512..5 * 512].copy_from_slice(other.as_bytes());
+    let mut objset = ObjectSet {
+        phys: ObjectSetPhys::from_bytes(&[0; 1024]).unwrap(),
+        userused_dnode: None,
+        groupused_dnode: None,
+    };
+    let mut meta_dnode = new_dnode(ObjectType::DNode, 8);
+    sync(&mut reader, 5, &mut meta_dnode, vec![(0, dnodes.clone())]);
+    objset.phys.meta_dnode = meta_dnode;
+    objset.phys.os_type = 2;
+
+    let begin = Begin {
+        features: 0,
+        creation_time: 1000,
+        objset_type: 2,
+        toguid: 0x77,
+        fromguid: 0,
+        toname: "tank/fs@snap",
+        payload: Vec::new(),
+    };
+    let mut out = Vec::new();
+    let stats = send_objset(&mut reader,
+                            &objset,
+                            &begin,
+                            0,
+                            &SendOptions::default(),
+                            &mut out)
+                    .unwrap();
+    assert_eq!((stats.bytes, stats.data_bytes), (out.len() as u64, 2048));
+
+    // The receiving side reads it back, checksums and all
+    let mut stream = StreamReader::new(&out[..]);
+    let begin = stream.begin().unwrap();
+    assert_eq!((&begin.toname[..], begin.toguid, begin.is_incremental()),
+               ("tank/fs@snap", 0x77, false));
+    let mut records = Vec::new();
+    while let Some(record) = stream.next_record().unwrap() {
+        records.push(record);
+    }
+    let headers: Vec<RecordHeader> = records.iter().map(|record| record.header.clone()).collect();
+    let object = |object: u64, block_size: u32, bonus_len: u32| {
+        RecordHeader::Object {
+            object: object,
+            object_type: ObjectType::PlainFileContents as u32,
+            bonus_type: if bonus_len == 0 { 0 } else { ObjectType::ZNode as u32 },
+            block_size: block_size,
+            bonus_len: bonus_len,
+            raw_bonus_len: 0,
+        }
+    };
+    let write = |object: u64, offset: u64, length: u64| {
+        RecordHeader::Write {
+            object: object,
+            offset: offset,
+            length: length,
+            compression: 0,
+            crypt: None,
+        }
+    };
+    let free_rest = |object: u64, offset: u64| {
+        RecordHeader::Free {
+            object: object,
+            offset: offset,
+            length: u64::max_value(),
+        }
+    };
+    assert_eq!(headers,
+               vec![RecordHeader::FreeObjects { first: 1, count: 1 },
+                    object(2, 512, 0),
+                    free_rest(2, 3 * 512),
+                    write(2, 0, 512),
+                    write(2, 2 * 512, 512),
+                    RecordHeader::FreeObjects { first: 3, count: 1 },
+                    object(4, 1024, 3),
+                    free_rest(4, 1024),
+                    write(4, 0, 1024),
+                    RecordHeader::FreeObjects { first: 5, count: 3 },
+                    RecordHeader::End { toguid: 0x77 }]);
+    assert_eq!(&records[6].payload[..], b"abc\0\0\0\0\0");
+    assert_eq!(records[4].payload, vec![3; 512]);
+
+    // In txg 7 the last block of object 2 is rewritten and object 4 freed. Sent incrementally
+    // from txg 5, that's all there is to it.
+    sync(&mut reader, 7, &mut file, vec![(2, vec![5; 512])]);
+    dnodes[2 * 512..3 * 512].copy_from_slice(file.as_bytes());
+    for byte in &mut dnodes[4 * 512..5 * 512] {
+        *byte = 0;
+    }
+    sync(&mut reader, 7, &mut meta_dnode, vec![(0, dnodes)]);
+    objset.phys.meta_dnode = meta_dnode;
+    let begin = Begin {
+        features: 0,
+        creation_time: 1000,
+        objset_type: 2,
+        toguid: 0x78,
+        fromguid: 0x77,
+        toname: "tank/fs@snap2",
+        payload: Vec::new(),
+    };
+    let mut out = Vec::new();
+    let stats = send_objset(&mut reader,
+                            &objset,
+                            &begin,
+                            5,
+                            &SendOptions::default(),
+                            &mut out)
+                    .unwrap();
+    assert_eq!(stats.data_bytes, 512);
+    let mut stream = StreamReader::new(&out[..]);
+    assert_eq!(stream.begin().unwrap().fromguid, 0x77);
+    let mut headers = Vec::new();
+    while let Some(record) = stream.next_record().unwrap() {
+        headers.push(record.header);
+    }
+    assert_eq!(headers,
+               vec![RecordHeader::FreeObjects { first: 1, count: 1 },
+                    object(2, 512, 0),
+                    free_rest(2, 3 * 512),
+                    write(2, 2 * 512, 512),
+                    RecordHeader::FreeObjects { first: 3, count: 5 },
+                    RecordHeader::End { toguid: 0x78 }]);
+
+    // Sent as it's on disk, the block goes as it was compressed
+    let options = SendOptions {
+        raw: true,
+        ..SendOptions::default()
+    };
+    let mut out = Vec::new();
+    send_objset(&mut reader, &objset, &begin, 5, &options, &mut out).unwrap();
+    let mut stream = StreamReader::new(&out[..]);
+    stream.begin().unwrap();
+    let mut writes = Vec::new();
+    while let Some(record) = stream.next_record().unwrap() {
+        if let RecordHeader::Write { offset, length, compression, crypt, .. } = record.header {
+            writes.push((offset, length, compression, crypt, record.payload));
+        }
+    }
+    assert_eq!(writes.len(), 1);
+    let (offset, length, compression, crypt, ref payload) = writes[0];
+    assert_eq!((offset, length, crypt), (2 * 512, 512, None));
+    assert_eq!(zio_compress::decompress(compression as u64, payload.clone(), 512).unwrap(),
+               vec![5; 512]);
+}
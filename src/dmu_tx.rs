@@ -0,0 +1,234 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use super::dsl_pool::DslPool;
+use super::zfs;
+
+/// Dirty data a hold on a ZAP counts for: a leaf and a block of the pointer table, at the
+/// largest block size of a fat ZAP
+const ZAP_HOLD_SIZE: u64 = 2 * (128 << 10);
+
+/// Something a transaction is going to change
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxHold {
+    /// `len` bytes at `offset` of an object
+    Write { object: u64, offset: u64, len: u64 },
+    /// An entry of a ZAP object, added or else changed or removed, `name` if it's known
+    Zap {
+        object: u64,
+        add: bool,
+        name: Option<String>,
+    },
+}
+
+/// A DMU transaction: changes to the objects of one objset that go into the same txg. What it's
+/// going to change is declared first (`hold_write`, `hold_zap`), so the txg can be checked for
+/// room. Then it's assigned to the open txg, the changes are made (e.g. `dmu::write`), and it's
+/// committed, which lets the txg be synced.
+pub struct Tx {
+    objset: u64,
+    holds: Vec<TxHold>,
+    txg: Option<u64>,
+    /// Assigned in syncing context, where anything may be changed without a hold
+    anyobj: bool,
+}
+
+impl Tx {
+    /// A transaction changing objset `objset` of the pool: `dsl_pool::MOS_OBJSET`, or the MOS
+    /// object of a dataset
+    pub fn create(objset: u64) -> Self {
+        Tx {
+            objset: objset,
+            holds: Vec::new(),
+            txg: None,
+            anyobj: false,
+        }
+    }
+
+    /// A transaction of syncing txg `txg`, which changes objset `objset` as it's being synced
+    /// (`dmu_tx_create_assigned`). It needs no holds, and isn't committed.
+    pub fn create_assigned(objset: u64, txg: u64) -> Self {
+        Tx {
+            objset: objset,
+            holds: Vec::new(),
+            txg: Some(txg),
+            anyobj: true,
+        }
+    }
+
+    pub fn objset(&self) -> u64 {
+        self.objset
+    }
+
+    /// The txg the transaction is assigned to, if it is yet
+    pub fn txg(&self) -> Option<u64> {
+        self.txg
+    }
+
+    pub fn holds(&self) -> &[TxHold] {
+        &self.holds
+    }
+
+    /// The transaction is going to write `len` bytes at `offset` of object `object`
+    pub fn hold_write(&mut self, object: u64, offset: u64, len: u64) {
+        assert!(self.txg.is_none(), "holds come before the transaction is assigned");
+        self.holds.push(TxHold::Write {
+            object: object,
+            offset: offset,
+            len: len,
+        });
+    }
+
+    /// The transaction is going to add an entry to the ZAP object `object` (`add`), or change or
+    /// remove one. `name` is the entry's, if it's known yet.
+    pub fn hold_zap(&mut self, object: u64, add: bool, name: Option<&str>) {
+        assert!(self.txg.is_none(), "holds come before the transaction is assigned");
+        self.holds.push(TxHold::Zap {
+            object: object,
+            add: add,
+            name: name.map(|name| name.to_owned()),
+        });
+    }
+
+    /// Most bytes of dirty data the holds can add
+    pub fn space_needed(&self) -> u64 {
+        self.holds
+            .iter()
+            .map(|hold| {
+                match *hold {
+                    TxHold::Write { len, .. } => len,
+                    TxHold::Zap { .. } => ZAP_HOLD_SIZE,
+                }
+            })
+            .sum()
+    }
+
+    /// Whether writing `len` bytes at `offset` of `object` is covered by a hold
+    pub fn holds_write(&self, object: u64, offset: u64, len: u64) -> bool {
+        self.anyobj ||
+        self.holds.iter().any(|hold| {
+            match *hold {
+                TxHold::Write { object: held, offset: start, len: held_len } => {
+                    held == object && start <= offset && offset + len <= start + held_len
+                }
+                TxHold::Zap { .. } => false,
+            }
+        })
+    }
+
+    /// Whether changing an entry of the ZAP object `object` is covered by a hold
+    pub fn holds_zap(&self, object: u64) -> bool {
+        self.anyobj ||
+        self.holds.iter().any(|hold| {
+            match *hold {
+                TxHold::Zap { object: held, .. } => held == object,
+                TxHold::Write { .. } => false,
+            }
+        })
+    }
+
+    /// Assign the transaction to the open txg of `dsl_pool`, which is held open until the
+    /// transaction is committed. Fails with `NoSpace` if the txgs in flight are too full of
+    /// dirty data to take the holds: there's no waiting here, sync a txg and try again.
+    pub fn assign(&mut self, dsl_pool: &mut DslPool) -> zfs::Result<u64> {
+        if self.txg.is_some() {
+            return Err(zfs::Error::Invalid);
+        }
+        // A transaction bigger than the limit still goes through on its own
+        if dsl_pool.dp_dirty_total > 0 && dsl_pool.is_over_dirty_max(self.space_needed()) {
+            return Err(zfs::Error::NoSpace);
+        }
+        let txg = dsl_pool.tx.hold_open();
+        self.txg = Some(txg);
+        Ok(txg)
+    }
+
+    /// The changes are all made: release the txg
+    pub fn commit(self, dsl_pool: &mut DslPool) {
+        match self.txg {
+            Some(txg) if !self.anyobj => dsl_pool.tx.rele(txg),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_tx() {
+    use super::ZfsReader;
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu;
+    use super::dmu_objset::{ObjectSet, ObjectSetPhys};
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::{DNodePhys, ObjectType};
+    use super::dsl_pool::MOS_OBJSET;
+    use super::from_bytes::FromBytes;
+    use super::metaslab::VdevAllocator;
+    use super::uberblock::Uberblock;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let mut phys = ObjectSetPhys::from_bytes(&[0; 1024]).unwrap();
+    phys.meta_dnode.object_type = ObjectType::DNode as u8;
+    phys.meta_dnode.nblkptr = 3;
+    phys.meta_dnode.indblkshift = 14;
+    phys.meta_dnode.data_blk_sz_sec = 32;
+    let mut mos = ObjectSet {
+        phys: phys,
+        userused_dnode: None,
+        groupused_dnode: None,
+    };
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0c, 0xb1, 0xba, 0, 0, 0, 0, 0]);
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // version 5000
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+
+    // Object 5 with 512 byte blocks
+    let mut dsl_pool = DslPool::new();
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.object_type = ObjectType::PlainFileContents as u8;
+    dnode.nblkptr = 1;
+    dnode.indblkshift = 14;
+    dnode.data_blk_sz_sec = 1;
+    let mut tx = Tx::create(MOS_OBJSET);
+    tx.hold_write(5, 100, 1000);
+    tx.hold_zap(1, true, Some("file"));
+    assert_eq!(tx.space_needed(), 1000 + ZAP_HOLD_SIZE);
+    let txg = tx.assign(&mut dsl_pool).unwrap();
+    dsl_pool.dirty_dnode(txg, MOS_OBJSET, 5, dnode);
+
+    // Writes have to be held
+    let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    assert!(dmu::write(&mut reader, &mut dsl_pool, &mos, 5, 0, &data, &tx).is_err());
+    dmu::write(&mut reader, &mut dsl_pool, &mos, 5, 100, &data, &tx).unwrap();
+    dmu::write(&mut reader, &mut dsl_pool, &mos, 5, 1000, &[0xff; 100], &tx).unwrap();
+    assert_eq!(dsl_pool.dirty_bytes(txg), 512 + 3 * 512);
+    tx.commit(&mut dsl_pool);
+    uberblock = dsl_pool.sync(&mut reader, &mut mos, &uberblock, &mut alloc).unwrap().unwrap();
+    dsl_pool.sync_done();
+    let rootbp = uberblock.rootbp;
+    mos = ObjectSet::open(&mut reader, &rootbp).unwrap();
+    let mut expected = vec![0; 100];
+    expected.extend_from_slice(&data[..900]);
+    expected.extend_from_slice(&[0xff; 100]);
+    assert_eq!(mos.read(&mut reader, 5, 0, 1100).unwrap(), expected);
+
+    // Part of a block on the disk is read back to be written with the rest of it
+    let mut tx = Tx::create(MOS_OBJSET);
+    tx.hold_write(5, 510, 4);
+    tx.assign(&mut dsl_pool).unwrap();
+    dmu::write(&mut reader, &mut dsl_pool, &mos, 5, 510, &[7; 4], &tx).unwrap();
+    tx.commit(&mut dsl_pool);
+    uberblock = dsl_pool.sync(&mut reader, &mut mos, &uberblock, &mut alloc).unwrap().unwrap();
+    let rootbp = uberblock.rootbp;
+    mos = ObjectSet::open(&mut reader, &rootbp).unwrap();
+    expected[510..514].copy_from_slice(&[7; 4]);
+    assert_eq!(mos.read(&mut reader, 5, 0, 1100).unwrap(), expected);
+}
@@ -0,0 +1,173 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+
+use super::dnode::DNodePhys;
+use super::dvaddr::DVAddr;
+use super::kstat;
+
+/// Most streams followed at once by default, like `zfetch_max_streams`
+pub const ZFETCH_MAX_STREAMS: usize = 8;
+
+/// Most bytes read ahead of a stream by default, like `zfetch_max_distance`
+pub const ZFETCH_MAX_DISTANCE: u64 = 8 << 20;
+
+/// Sequential reads of one object
+struct Stream {
+    /// The object's first block pointer, which no other object shares
+    object: DVAddr,
+    /// The block the next read starts at, if the object is still read sequentially
+    next: u64,
+    /// The blocks before this one have been prefetched already
+    prefetched: u64,
+    /// Number of blocks read ahead of `next`, doubled with every sequential read
+    distance: u64,
+    /// When the stream was last used, in accesses
+    used: u64,
+}
+
+/// Counters of the prefetcher, from its creation
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ZfetchStats {
+    /// Reads that carried on a stream
+    pub hits: u64,
+    /// Reads that didn't, and started a new one
+    pub misses: u64,
+    /// Blocks handed out to be prefetched
+    pub blocks: u64,
+}
+
+/// The DMU's sequential prefetcher. It follows the reads of each object, and once an object is
+/// read from where the last read of it ended, hands out the blocks to read ahead of the reader.
+/// The further a stream goes, the further ahead it reads, up to `max_distance` bytes.
+pub struct Zfetch {
+    streams: Vec<Stream>,
+    pub max_streams: usize,
+    pub max_distance: u64,
+    accesses: u64,
+    stats: ZfetchStats,
+}
+
+impl Zfetch {
+    pub fn new() -> Self {
+        Zfetch {
+            streams: Vec::new(),
+            max_streams: ZFETCH_MAX_STREAMS,
+            max_distance: ZFETCH_MAX_DISTANCE,
+            accesses: 0,
+            stats: ZfetchStats::default(),
+        }
+    }
+
+    /// Note a read of the `nblks` level 0 blocks from `blkid` on of the object described by
+    /// `dnode`. Returns the blocks to prefetch, as a `[start, end)` range of block ids, if the
+    /// read is sequential and there's anything left to read ahead.
+    pub fn access(&mut self, dnode: &DNodePhys, blkid: u64, nblks: u64) -> Option<(u64, u64)> {
+        let block_size = dnode.data_block_size();
+        if block_size == 0 || dnode.maxblkid == 0 || nblks == 0 {
+            // Nothing past a single block to read ahead
+            return None;
+        }
+        self.accesses += 1;
+        let object = dnode.get_blockptr(0).dvas[0];
+        let max_blocks = cmp::max(self.max_distance / block_size, 1);
+        let end = dnode.maxblkid + 1;
+
+        let i = match self.streams.iter().position(|s| s.object == object && s.next == blkid) {
+            Some(i) => i,
+            None => {
+                self.stats.misses += 1;
+                let stream = Stream {
+                    object: object,
+                    next: blkid + nblks,
+                    prefetched: blkid + nblks,
+                    distance: 0,
+                    used: self.accesses,
+                };
+                if self.streams.len() < self.max_streams {
+                    self.streams.push(stream);
+                } else if let Some(oldest) = self.streams.iter_mut().min_by_key(|s| s.used) {
+                    *oldest = stream;
+                }
+                return None;
+            }
+        };
+        self.stats.hits += 1;
+
+        let stream = &mut self.streams[i];
+        stream.used = self.accesses;
+        stream.next = blkid + nblks;
+        stream.distance = cmp::min(cmp::max(stream.distance * 2, nblks), max_blocks);
+        let start = cmp::max(stream.prefetched, stream.next);
+        let stop = cmp::min(stream.next + stream.distance, end);
+        if start >= stop {
+            return None;
+        }
+        stream.prefetched = stop;
+        self.stats.blocks += stop - start;
+        Some((start, stop))
+    }
+
+    pub fn stats(&self) -> ZfetchStats {
+        self.stats
+    }
+
+    /// Add the prefetcher's statistics to `kstats`
+    pub fn kstats(&self, kstats: &mut kstat::Registry) {
+        kstats.counter("zfs_zfetch_hits_total",
+                       "Object reads that carried on a sequential stream",
+                       self.stats.hits);
+        kstats.counter("zfs_zfetch_misses_total",
+                       "Object reads that didn't carry on a sequential stream",
+                       self.stats.misses);
+        kstats.counter("zfs_zfetch_blocks_total",
+                       "Blocks read ahead of sequential streams",
+                       self.stats.blocks);
+        kstats.gauge("zfs_zfetch_streams",
+                     "Sequential streams followed",
+                     self.streams.len() as u64);
+    }
+}
+
+#[test]
+fn test_zfetch() {
+    use super::from_bytes::FromBytes;
+
+    let object = |offset: u8| {
+        let mut bytes = [0u8; 512];
+        bytes[64 + 8] = offset; // DVA offset of the first block pointer
+        let mut dnode = DNodePhys::from_bytes(&bytes).unwrap();
+        dnode.nblkptr = 1;
+        dnode.data_blk_sz_sec = 2;
+        dnode.maxblkid = 99;
+        dnode
+    };
+    let (dnode, other) = (object(1), object(2));
+
+    let mut zfetch = Zfetch::new();
+    zfetch.max_distance = 8 << 10;
+    // The first read only starts a stream, the next ones read further and further ahead
+    assert_eq!(zfetch.access(&dnode, 0, 1), None);
+    assert_eq!(zfetch.access(&dnode, 1, 1), Some((2, 3)));
+    assert_eq!(zfetch.access(&dnode, 2, 1), Some((3, 5)));
+    assert_eq!(zfetch.access(&dnode, 3, 1), Some((5, 8)));
+    // Up to `max_distance`
+    assert_eq!(zfetch.access(&dnode, 4, 1), Some((8, 13)));
+    assert_eq!(zfetch.access(&dnode, 5, 4), Some((13, 17)));
+
+    // Streams of other objects, and random reads, don't get in the way
+    assert_eq!(zfetch.access(&other, 9, 1), None);
+    assert_eq!(zfetch.access(&dnode, 50, 1), None);
+    assert_eq!(zfetch.access(&dnode, 9, 1), Some((17, 18)));
+
+    // Not past the end of the object
+    assert_eq!(zfetch.access(&dnode, 95, 2), None);
+    assert_eq!(zfetch.access(&dnode, 97, 1), Some((98, 99)));
+    assert_eq!(zfetch.access(&dnode, 98, 2), None);
+    assert_eq!(zfetch.stats(),
+               ZfetchStats {
+                   hits: 8,
+                   misses: 4,
+                   blocks: 17,
+               });
+}
@@ -1,12 +1,13 @@
-use std::fmt;
-use std::mem;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, fmt, mem, slice};
 
 use super::block_ptr::BlockPtr;
-use super::from_bytes::FromBytes;
-use super::zil_header::ZilHeader;
+use super::from_bytes::{self, ByteOrder, Decoder, FromBytes};
+use super::znode::ZNodePhys;
 
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ObjectType {
     None,
     ObjectDirectory,
@@ -35,9 +36,48 @@ pub enum ObjectType {
     ZVolProp,
 }
 
+/// A dnode takes up at least one 512 byte slot. Large dnodes (`dnodesize=auto`) span several.
+pub const DNODE_SHIFT: u64 = 9;
+pub const DNODE_SIZE: u64 = 1 << DNODE_SHIFT;
+/// Most slots a dnode can take up, 16K of them (`DNODE_MAX_SIZE`)
+pub const DNODE_MAX_SLOTS: u64 = 32;
+
+/// Block pointers are 128 bytes
+const BLKPTR_SHIFT: u64 = 7;
+
+/// Largest bonus buffer of a single slot dnode, taking up all but one block pointer
+const DN_MAX_BONUSLEN: usize = 320;
+/// Indirect blocks of new objects are 128K
+const DN_MAX_INDBLKSHIFT: u8 = 17;
+
+/// `used` is in bytes rather than sectors
+pub const DNODE_FLAG_USED_BYTES: u8 = 1 << 0;
+
+/// The dnode's last block pointer slot points at a spill block with more attributes
+pub const DNODE_FLAG_SPILL_BLKPTR: u8 = 1 << 2;
+
+/// How many bytes at the start of a bonus buffer of type `bonus_type` are u64s, which is what
+/// lets them be brought to little endian without knowing the layout
+fn bonus_u64s_len(bonus_type: u8, bonus_len: usize) -> usize {
+    if bonus_type == ObjectType::ZNode as u8 {
+        // The ACL (and symlink target) after the znode isn't made of u64s
+        cmp::min(bonus_len, mem::size_of::<ZNodePhys>())
+    } else if [ObjectType::NvListSize,
+                ObjectType::SpaceMapHeader,
+                ObjectType::DataSet, // DSL directory
+                ObjectType::DslObjSet] // DSL dataset
+                   .iter()
+                   .any(|&object_type| bonus_type == object_type as u8) {
+        bonus_len
+    } else {
+        0
+    }
+}
+
+#[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct DNodePhys {
-    pub object_type: ObjectType,
+    pub object_type: u8, // ObjectType, or a newer type this doesn't know about
     pub indblkshift: u8, // ln2(indirect block size)
     pub nlevels: u8, // 1=blkptr->data blocks
     pub nblkptr: u8, // length of blkptr
@@ -47,7 +87,8 @@ pub struct DNodePhys {
     pub flags: u8, // DNODE_FLAG_*
     pub data_blk_sz_sec: u16, // data block size in 512b sectors
     pub bonus_len: u16, // length of bonus
-    pub pad2: [u8; 4],
+    pub extra_slots: u8, // number of slots beyond the first one this dnode occupies
+    pub pad2: [u8; 3],
 
     // accounting is protected by dirty_mtx
     pub maxblkid: u64, // largest allocated block ID
@@ -59,21 +100,200 @@ pub struct DNodePhys {
 }
 
 impl DNodePhys {
-    pub fn get_blockptr<'a>(&self, i: usize) -> &'a BlockPtr {
-        unsafe { mem::transmute(&self.blkptr_bonus[i * 128]) }
+    /// A new object of type `object_type` with data blocks of `block_size` bytes, and `bonus` as
+    /// its bonus buffer of type `bonus_type`. The block pointers the bonus buffer leaves room for
+    /// are holes until its blocks are synced.
+    pub fn new(object_type: ObjectType,
+               block_size: u64,
+               bonus_type: ObjectType,
+               bonus: &[u8])
+               -> Self {
+        assert!(bonus.len() <= DN_MAX_BONUSLEN, "bonus buffer too large for a dnode");
+        assert!(block_size >= 512 && block_size % 512 == 0);
+        let mut dnode = DNodePhys::from_bytes(&[0; DNODE_SIZE as usize]).unwrap();
+        dnode.object_type = object_type as u8;
+        dnode.indblkshift = DN_MAX_INDBLKSHIFT;
+        dnode.nblkptr = (1 + (DN_MAX_BONUSLEN - bonus.len()) / 128) as u8;
+        dnode.data_blk_sz_sec = (block_size / 512) as u16;
+        dnode.flags = DNODE_FLAG_USED_BYTES;
+        dnode.bonus_type = bonus_type as u8;
+        dnode.bonus_len = bonus.len() as u16;
+        dnode.bonus_mut()[..bonus.len()].copy_from_slice(bonus);
+        dnode
+    }
+
+    pub fn get_blockptr(&self, i: usize) -> BlockPtr {
+        BlockPtr::from_bytes(&self.blkptr_bonus[i * 128..]).unwrap()
+    }
+
+    pub fn set_blockptr(&mut self, i: usize, block_ptr: &BlockPtr) {
+        self.blkptr_bonus[i * 128..(i + 1) * 128].copy_from_slice(block_ptr.as_bytes());
+    }
+
+    /// The spill block pointer, which takes up the last 128 bytes of the dnode
+    pub fn get_spill_blkptr(&self) -> BlockPtr {
+        BlockPtr::from_bytes(&self.blkptr_bonus[self.blkptr_bonus.len() - 128..]).unwrap()
     }
 
     pub fn get_bonus(&self) -> &[u8] {
         &self.blkptr_bonus[(self.nblkptr as usize) * 128..]
     }
+
+    /// How many 512 byte slots the dnode takes up
+    pub fn slots(&self) -> u64 {
+        1 + self.extra_slots as u64
+    }
+
+    /// The bonus buffer of a large dnode, which goes on from the first slot into the ones after
+    /// it: `extra` holds those (see `dmu::read_dnode_extra`). It ends where the spill block
+    /// pointer starts, if there is one. Single slot dnodes have no `extra`, it's `get_bonus`.
+    pub fn large_bonus(&self, extra: &[u8]) -> Vec<u8> {
+        let mut bonus = self.get_bonus().to_vec();
+        if extra.is_empty() {
+            return bonus;
+        }
+        bonus.extend_from_slice(extra);
+        if self.flags & DNODE_FLAG_SPILL_BLKPTR != 0 {
+            let len = bonus.len().saturating_sub(128);
+            bonus.truncate(len);
+        }
+        bonus
+    }
+
+    /// The spill block pointer of a large dnode, at the end of its last slot
+    pub fn large_spill_blkptr(&self, extra: &[u8]) -> BlockPtr {
+        if extra.len() < 128 {
+            return self.get_spill_blkptr();
+        }
+        BlockPtr::from_bytes(&extra[extra.len() - 128..]).unwrap()
+    }
+
+    pub fn bonus_mut(&mut self) -> &mut [u8] {
+        &mut self.blkptr_bonus[(self.nblkptr as usize) * 128..]
+    }
+
+    /// The dnode as it's written to disk, in host byte order
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const DNodePhys as *const u8,
+                                  mem::size_of::<DNodePhys>())
+        }
+    }
+
+    /// How many data blocks the object has, from the fill counts of the dnode's own block
+    /// pointers: no indirect block is read. For a meta dnode, that's how many dnodes are in use.
+    pub fn fill_count(&self) -> u64 {
+        (0..self.nblkptr as usize).map(|i| self.get_blockptr(i).fill()).sum()
+    }
+
+    /// Size of the object's data blocks in bytes
+    pub fn data_block_size(&self) -> u64 {
+        self.data_blk_sz_sec as u64 * 512
+    }
+
+    /// log2 of the number of block pointers in one of the object's indirect blocks
+    pub fn epbs(&self) -> u64 {
+        self.indblkshift as u64 - BLKPTR_SHIFT
+    }
+
+    /// Number of dnode slots in each data block of a meta dnode. Object numbers count slots, so a
+    /// large dnode uses up several object numbers.
+    pub fn dnodes_per_block(&self) -> u64 {
+        self.data_block_size() >> DNODE_SHIFT
+    }
+
+    /// Where object `object` of this meta dnode lives: the level 0 block id and the slot index
+    /// inside of that block
+    pub fn dnode_location(&self, object: u64) -> (u64, usize) {
+        let per_block = self.dnodes_per_block();
+        (object / per_block, (object % per_block) as usize)
+    }
+
+    /// The block pointer index to follow at each level, starting from the dnode's own block
+    /// pointers, to get to level 0 block `blkid`. `None` if the object isn't that big.
+    pub fn block_path(&self, blkid: u64) -> Option<Vec<usize>> {
+        let nlevels = self.nlevels as u64;
+        if nlevels == 0 {
+            return None;
+        }
+        let epbs = self.epbs();
+
+        // The block pointers in the dnode itself cover `nblkptr` subtrees
+        let top = blkid.checked_shr((epbs * (nlevels - 1)) as u32).unwrap_or(0);
+        if top >= self.nblkptr as u64 {
+            return None;
+        }
+
+        let mut path = vec![top as usize];
+        for level in (1..nlevels).rev() {
+            path.push(((blkid >> (epbs * (level - 1))) & ((1 << epbs) - 1)) as usize);
+        }
+        Some(path)
+    }
 }
 
-impl FromBytes for DNodePhys {}
+impl FromBytes for DNodePhys {
+    /// The block pointers and bonus buffer are kept as bytes. If the dnode was written big endian,
+    /// the block pointers (and bonus buffers made of u64s) are brought to little endian, so they
+    /// can be read like any other. System attributes tell their byte order themselves.
+    fn decode(decoder: &mut Decoder) -> Self {
+        let object_type = decoder.u8();
+        let indblkshift = decoder.u8();
+        let nlevels = decoder.u8();
+        let nblkptr = decoder.u8();
+        let bonus_type = decoder.u8();
+        let checksum = decoder.u8();
+        let compress = decoder.u8();
+        let flags = decoder.u8();
+        let data_blk_sz_sec = decoder.u16();
+        let bonus_len = decoder.u16();
+        let extra_slots = decoder.u8();
+        let mut pad2 = [0; 3];
+        decoder.fill(&mut pad2);
+        let maxblkid = decoder.u64();
+        let used = decoder.u64();
+        let mut pad3 = [0; 4];
+        decoder.fill_u64(&mut pad3);
+        let mut blkptr_bonus = [0; 448];
+        decoder.fill(&mut blkptr_bonus);
+
+        if decoder.order() == ByteOrder::Big {
+            let blkptrs_len = cmp::min(nblkptr as usize * 128, blkptr_bonus.len());
+            from_bytes::swap_u64s(&mut blkptr_bonus[..blkptrs_len]);
+            if flags & DNODE_FLAG_SPILL_BLKPTR != 0 {
+                let spill = blkptr_bonus.len() - 128;
+                from_bytes::swap_u64s(&mut blkptr_bonus[spill..]);
+            }
+            let bonus_u64s_end = blkptrs_len + bonus_u64s_len(bonus_type, bonus_len as usize);
+            let bonus_u64s_end = cmp::min(bonus_u64s_end, blkptr_bonus.len());
+            from_bytes::swap_u64s(&mut blkptr_bonus[blkptrs_len..bonus_u64s_end]);
+        }
+
+        DNodePhys {
+            object_type: object_type,
+            indblkshift: indblkshift,
+            nlevels: nlevels,
+            nblkptr: nblkptr,
+            bonus_type: bonus_type,
+            checksum: checksum,
+            compress: compress,
+            flags: flags,
+            data_blk_sz_sec: data_blk_sz_sec,
+            bonus_len: bonus_len,
+            extra_slots: extra_slots,
+            pad2: pad2,
+            maxblkid: maxblkid,
+            used: used,
+            pad3: pad3,
+            blkptr_bonus: blkptr_bonus,
+        }
+    }
+}
 
 impl fmt::Debug for DNodePhys {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f,
-                    "DNodePhys {{ object_type: {:?}, nlevels: {:X}, nblkptr: {:X}, bonus_type: \
+                    "DNodePhys {{ object_type: {:X}, nlevels: {:X}, nblkptr: {:X}, bonus_type: \
                      {:X}, bonus_len: {:X}}}\n",
                     self.object_type,
                     self.nlevels,
@@ -83,3 +303,76 @@ impl fmt::Debug for DNodePhys {
         Ok(())
     }
 }
+
+#[test]
+fn test_block_path() {
+    let mut meta_dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    meta_dnode.indblkshift = 17; // 128K indirect blocks, 1024 block pointers each
+    meta_dnode.nlevels = 3;
+    meta_dnode.nblkptr = 1;
+    meta_dnode.data_blk_sz_sec = 32; // 16K of dnodes per block
+
+    assert_eq!(meta_dnode.dnodes_per_block(), 32);
+    assert_eq!(meta_dnode.dnode_location(1), (0, 1));
+    assert_eq!(meta_dnode.dnode_location(70), (2, 6));
+    // A 1K dnode (dnodesize=auto) at object 64 uses up object 65 too, 66 is the next one
+    assert_eq!(meta_dnode.dnode_location(66), (2, 2));
+
+    assert_eq!(meta_dnode.block_path(0), Some(vec![0, 0, 0]));
+    assert_eq!(meta_dnode.block_path(1500), Some(vec![0, 1, 476]));
+    assert_eq!(meta_dnode.block_path(1 << 20), None);
+
+    meta_dnode.nlevels = 1;
+    assert_eq!(meta_dnode.block_path(0), Some(vec![0]));
+    assert_eq!(meta_dnode.block_path(1), None);
+}
+
+#[test]
+fn test_big_endian_dnode() {
+    // A file dnode written by a big endian host: one block pointer and a znode
+    let mut bytes = [0u8; 512];
+    bytes[..8].copy_from_slice(&[19, 17, 1, 1, 17, 7, 2, 0]);
+    bytes[8..12].copy_from_slice(&[0, 8, 0x01, 0x08]);
+    bytes[23] = 3; // maxblkid
+    bytes[64 + 15] = 0x20; // DVA offset
+    bytes[64 + 87] = 9; // birth txg
+    bytes[192 + 8 * 9 + 7] = 0xA4; // znode mode
+    bytes[192 + 8 * 9 + 6] = 0x81;
+
+    let dnode = DNodePhys::from_bytes_order(&bytes, ByteOrder::Big).unwrap();
+    assert_eq!(dnode.object_type, ObjectType::PlainFileContents as u8);
+    assert_eq!((dnode.data_block_size(), dnode.bonus_len), (4096, 0x108));
+    let maxblkid = dnode.maxblkid;
+    assert_eq!(maxblkid, 3);
+
+    let block_ptr = dnode.get_blockptr(0);
+    let dvas = block_ptr.dvas;
+    assert_eq!((dvas[0].offset(), block_ptr.birth_txg), (0x20, 9));
+    assert_eq!(block_ptr.data_order(), ByteOrder::Big);
+    let mode = u64::from_bytes(&dnode.get_bonus()[8 * 9..]).unwrap();
+    assert_eq!(mode, 0o100644);
+}
+
+#[test]
+fn test_fill_count() {
+    let mut bytes = [0u8; 512];
+    bytes[3] = 3; // nblkptr
+    let mut put = |offset: usize, value: u64| {
+        for i in 0..8 {
+            bytes[offset + i] = (value >> (i * 8)) as u8;
+        }
+    };
+    // An indirect block over 5 data blocks, a hole that still has a fill count, and an
+    // embedded block pointer, whose payload takes up the fill count
+    put(64, 1);
+    put(64 + 8, 0x2000);
+    put(64 + 88, 5);
+    put(192 + 88, 7);
+    put(320 + 48, 1 << 39);
+    put(320 + 88, 0x1234);
+
+    let dnode = DNodePhys::from_bytes(&bytes).unwrap();
+    let fills: Vec<u64> = (0..3).map(|i| dnode.get_blockptr(i).fill()).collect();
+    assert_eq!(fills, vec![5, 0, 1]);
+    assert_eq!(dnode.fill_count(), 6);
+}
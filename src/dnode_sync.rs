@@ -0,0 +1,591 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+use std::collections::BTreeMap;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum;
+use super::ddt::{Ddt, DdtKey};
+use super::dnode::{DNODE_FLAG_USED_BYTES, DNODE_SIZE, DNodePhys, ObjectType};
+use super::from_bytes::FromBytes;
+use super::metaslab::Allocator;
+use super::zfs;
+use super::zio_compress::{self, ZIO_COMPRESS_OFF};
+
+/// Blocks are written with fletcher 4 checksums (`ZIO_CHECKSUM_FLETCHER_4`)
+const ZIO_CHECKSUM_FLETCHER_4: u64 = 7;
+
+/// Blocks in the dedup table have this bit set in their block pointers
+const BP_DEDUP: u64 = 1 << 62;
+
+/// Write `data` to a new block born in `txg`, as a block of an object of type `object_type` at
+/// `level` of its tree, with `fill` blocks of data under it. Returns the block pointer to it.
+/// The block is stored uncompressed, see `write_compressed_block`.
+pub fn write_block(reader: &mut ZfsReader,
+                   alloc: &mut Allocator,
+                   txg: u64,
+                   object_type: u8,
+                   level: u64,
+                   fill: u64,
+                   data: &[u8])
+                   -> zfs::Result<BlockPtr> {
+    write_compressed_block(reader, alloc, txg, object_type, level, fill, ZIO_COMPRESS_OFF, data)
+}
+
+/// Like `write_block`, with the data compressed with `compression` if that saves enough of it
+/// (see `zio_compress::compress`)
+pub fn write_compressed_block(reader: &mut ZfsReader,
+                              alloc: &mut Allocator,
+                              txg: u64,
+                              object_type: u8,
+                              level: u64,
+                              fill: u64,
+                              compression: u64,
+                              data: &[u8])
+                              -> zfs::Result<BlockPtr> {
+    let (padded, compression, physical) = encode(compression, data);
+    let mut block_ptr = try!(new_block_ptr(txg,
+                                           object_type,
+                                           level,
+                                           fill,
+                                           ZIO_CHECKSUM_FLETCHER_4,
+                                           compression,
+                                           padded.len(),
+                                           &physical));
+    let dva = try!(alloc.alloc(physical.len() as u64));
+    try!(reader.zio.write_dva(&dva, &physical));
+    block_ptr.dvas[0] = dva;
+    Ok(block_ptr)
+}
+
+/// Like `write_block` for a level 0 block, deduplicated through `ddt`: the block is checksummed
+/// with the table's checksum and looked up in it. If the same data was written before, the
+/// block pointer points at that block, which gets one more reference, rather than at a new one.
+/// It's compressed with `compression` first, so only blocks compressed the same way match.
+pub fn write_dedup_block(reader: &mut ZfsReader,
+                         alloc: &mut Allocator,
+                         ddt: &mut Ddt,
+                         txg: u64,
+                         object_type: u8,
+                         fill: u64,
+                         compression: u64,
+                         data: &[u8])
+                         -> zfs::Result<BlockPtr> {
+    let (padded, compression, physical) = encode(compression, data);
+    let mut block_ptr = try!(new_block_ptr(txg,
+                                           object_type,
+                                           0,
+                                           fill,
+                                           ddt.checksum,
+                                           compression,
+                                           padded.len(),
+                                           &physical));
+    block_ptr.flags_size |= BP_DEDUP;
+    let key = DdtKey::of(&block_ptr);
+    match ddt.add_ref(&key) {
+        Some(phys) => {
+            block_ptr.dvas = phys.dvas;
+            // The physical birth: when the data was written, rather than referenced
+            block_ptr.padding[2] = phys.phys_birth;
+        }
+        None => {
+            let dva = try!(alloc.alloc(physical.len() as u64));
+            try!(reader.zio.write_dva(&dva, &physical));
+            block_ptr.dvas[0] = dva;
+            ddt.insert(key, dva, txg);
+        }
+    }
+    Ok(block_ptr)
+}
+
+/// `data` padded with zeros to whole sectors, at least one
+fn pad(data: &[u8]) -> Vec<u8> {
+    let sectors = cmp::max((data.len() as u64 + 511) / 512, 1);
+    let mut padded = data.to_vec();
+    padded.resize((sectors * 512) as usize, 0);
+    padded
+}
+
+/// The sectors of a block of `data` as they're written: the padded data, compressed with
+/// `compression` if that makes for fewer sectors. Returns the padded data, the compression
+/// the written sectors are in, and the sectors.
+fn encode(compression: u64, data: &[u8]) -> (Vec<u8>, u64, Vec<u8>) {
+    let padded = pad(data);
+    if compression != ZIO_COMPRESS_OFF {
+        if let Some(compressed) = zio_compress::compress(compression, &padded) {
+            let physical = pad(&compressed);
+            if physical.len() < padded.len() {
+                return (padded, zio_compress::compress_select(compression), physical);
+            }
+        }
+    }
+    let physical = padded.clone();
+    (padded, ZIO_COMPRESS_OFF, physical)
+}
+
+/// A block pointer to the sectors `physical`, `lsize` bytes once decompressed, without a DVA yet
+fn new_block_ptr(txg: u64,
+                 object_type: u8,
+                 level: u64,
+                 fill: u64,
+                 checksum: u64,
+                 compression: u64,
+                 lsize: usize,
+                 physical: &[u8])
+                 -> zfs::Result<BlockPtr> {
+    let lsectors = lsize as u64 / 512;
+    let psectors = physical.len() as u64 / 512;
+    let mut block_ptr = BlockPtr::hole();
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | level << 56 |
+                           (object_type as u64) << 48 | checksum << 40 |
+                           compression << 32 | (psectors - 1) << 16 |
+                           (lsectors - 1);
+    block_ptr.birth_txg = txg;
+    block_ptr.fill_count = fill;
+    block_ptr.checksum = try!(checksum::compute(checksum, physical, false)
+                                  .map_err(|_| zfs::Error::Invalid));
+    Ok(block_ptr)
+}
+
+/// Bytes allocated to `block_ptr`, over all of its copies
+fn allocated(block_ptr: &BlockPtr) -> u64 {
+    let dvas = block_ptr.dvas;
+    dvas[..block_ptr.max_copies()].iter().map(|dva| dva.asize() * 512).sum()
+}
+
+/// The block pointer of block `index` at `level` of the tree of `dnode`, None if it's a hole
+fn block_at(reader: &mut ZfsReader,
+            dnode: &DNodePhys,
+            level: u64,
+            index: u64)
+            -> zfs::Result<Option<BlockPtr>> {
+    let nlevels = dnode.nlevels as u64;
+    if level >= nlevels {
+        return Ok(None);
+    }
+    let epbs = dnode.epbs();
+    let top = index.checked_shr((epbs * (nlevels - 1 - level)) as u32).unwrap_or(0);
+    if top >= dnode.nblkptr as u64 {
+        return Ok(None);
+    }
+
+    let mut block_ptr = dnode.get_blockptr(top as usize);
+    for parent in (level + 1..nlevels).rev() {
+        if block_ptr.is_hole() {
+            return Ok(None);
+        }
+        let child = (index >> (epbs * (parent - 1 - level))) & ((1 << epbs) - 1);
+        block_ptr = try!(reader.read_type_array(&block_ptr, child as usize));
+    }
+    Ok(if block_ptr.is_hole() { None } else { Some(block_ptr) })
+}
+
+/// The block pointers of the indirect block at `block_ptr`, or of a new one of `size` bytes
+fn indirect_entries(reader: &mut ZfsReader,
+                    block_ptr: Option<&BlockPtr>,
+                    size: usize)
+                    -> zfs::Result<Vec<BlockPtr>> {
+    let mut entries = vec![BlockPtr::hole(); size / 128];
+    if let Some(block_ptr) = block_ptr {
+        let data = try!(reader.read_block(block_ptr));
+        for (i, entry) in entries.iter_mut().enumerate().take(data.len() / 128) {
+            *entry = try!(BlockPtr::from_bytes_order(&data[i * 128..], block_ptr.data_order()));
+        }
+    }
+    Ok(entries)
+}
+
+fn write_indirect(reader: &mut ZfsReader,
+                  alloc: &mut Allocator,
+                  txg: u64,
+                  dnode: &DNodePhys,
+                  level: u64,
+                  entries: &[BlockPtr])
+                  -> zfs::Result<BlockPtr> {
+    let fill = entries.iter().map(|entry| entry.fill()).sum();
+    if fill == 0 {
+        return Ok(BlockPtr::hole());
+    }
+    let mut data = Vec::with_capacity(entries.len() * 128);
+    for entry in entries {
+        data.extend_from_slice(entry.as_bytes());
+    }
+    write_block(reader, alloc, txg, dnode.object_type, level, fill, &data)
+}
+
+/// Add a level to the top of the tree of `dnode`, to make room for more blocks: an indirect
+/// block holding the block pointers the dnode had
+fn grow(reader: &mut ZfsReader,
+        alloc: &mut Allocator,
+        txg: u64,
+        dnode: &mut DNodePhys)
+        -> zfs::Result<()> {
+    let mut entries = vec![BlockPtr::hole(); (1 << dnode.indblkshift) / 128];
+    for i in 0..dnode.nblkptr as usize {
+        entries[i] = dnode.get_blockptr(i);
+        dnode.set_blockptr(i, &BlockPtr::hole());
+    }
+    let level = dnode.nlevels as u64;
+    let top = try!(write_indirect(reader, alloc, txg, dnode, level, &entries));
+    dnode.set_blockptr(0, &top);
+    dnode.nlevels += 1;
+    Ok(())
+}
+
+/// How many blocks of data a level 0 block of an object of `object_type` holds: the dnodes in
+/// use for a block of dnodes, 1 for anything else
+fn data_fill(object_type: u8, data: &[u8]) -> u64 {
+    if object_type != ObjectType::DNode as u8 {
+        return 1;
+    }
+    data.chunks(DNODE_SIZE as usize).filter(|dnode| dnode[0] != 0).count() as u64
+}
+
+/// Write the level 0 blocks `blocks` (by block id) of the object described by `dnode`, and new
+/// copies of the indirect blocks above them, all born in `txg`. `dnode` is updated to point at
+/// the new tree, growing it by a level if needs be, and the blocks that aren't part of it any
+/// more are added to `freed`. The level 0 blocks are compressed with `compression` where it
+/// pays off, and with a dedup table, deduplicated through it (see `write_dedup_block`).
+///
+/// Empty blocks are holes punched in the object (see `DslPool::dirty_hole`): what was there is
+/// freed, and so are the indirect blocks left with nothing but holes under them, which become
+/// holes in turn. Holes past the end of the object are there already.
+pub fn sync_dnode(reader: &mut ZfsReader,
+                  alloc: &mut Allocator,
+                  mut ddt: Option<&mut Ddt>,
+                  compression: u64,
+                  txg: u64,
+                  dnode: &mut DNodePhys,
+                  blocks: &BTreeMap<u64, Vec<u8>>,
+                  freed: &mut Vec<BlockPtr>)
+                  -> zfs::Result<()> {
+    let written_end = blocks.iter()
+                            .filter(|&(_, data)| !data.is_empty())
+                            .map(|(&blkid, _)| blkid + 1)
+                            .next_back()
+                            .unwrap_or(0);
+    let allocated_end = if dnode.nlevels == 0 { 0 } else { dnode.maxblkid + 1 };
+    let blocks = blocks.range(..cmp::max(written_end, allocated_end));
+    let last = match blocks.clone().next_back() {
+        Some((&last, _)) => last,
+        None => return Ok(()),
+    };
+    if dnode.nblkptr == 0 || dnode.indblkshift <= 7 {
+        return Err(zfs::Error::Invalid);
+    }
+    if dnode.nlevels == 0 {
+        dnode.nlevels = 1;
+    }
+    let maxblkid = cmp::max(dnode.maxblkid, last);
+    while dnode.block_path(maxblkid).is_none() {
+        try!(grow(reader, alloc, txg, dnode));
+    }
+
+    let epbs = dnode.epbs();
+    let indirect_size = 1 << dnode.indblkshift;
+    let (mut written, mut freed_bytes) = (0, 0);
+    let mut replaced = |block_ptr: Option<BlockPtr>, freed: &mut Vec<BlockPtr>| {
+        if let Some(block_ptr) = block_ptr {
+            freed_bytes += allocated(&block_ptr);
+            freed.push(block_ptr);
+        }
+    };
+
+    // Level by level, the new block pointers of the blocks written at that level
+    let mut level_ptrs = BTreeMap::new();
+    for (&blkid, data) in blocks {
+        replaced(try!(block_at(reader, dnode, 0, blkid)), freed);
+        if data.is_empty() {
+            level_ptrs.insert(blkid, BlockPtr::hole());
+            continue;
+        }
+        let fill = data_fill(dnode.object_type, data);
+        let object_type = dnode.object_type;
+        let block_ptr = match ddt {
+            Some(ref mut ddt) => {
+                try!(write_dedup_block(reader,
+                                       alloc,
+                                       ddt,
+                                       txg,
+                                       object_type,
+                                       fill,
+                                       compression,
+                                       data))
+            }
+            None => {
+                try!(write_compressed_block(reader,
+                                            alloc,
+                                            txg,
+                                            object_type,
+                                            0,
+                                            fill,
+                                            compression,
+                                            data))
+            }
+        };
+        written += allocated(&block_ptr);
+        level_ptrs.insert(blkid, block_ptr);
+    }
+    for level in 1..dnode.nlevels as u64 {
+        let mut parents: BTreeMap<u64, Vec<(u64, BlockPtr)>> = BTreeMap::new();
+        for (&index, block_ptr) in &level_ptrs {
+            parents.entry(index >> epbs).or_insert_with(Vec::new).push((index, *block_ptr));
+        }
+        let mut parent_ptrs = BTreeMap::new();
+        for (parent, children) in parents {
+            let old = try!(block_at(reader, dnode, level, parent));
+            let mut entries = try!(indirect_entries(reader, old.as_ref(), indirect_size));
+            for (index, block_ptr) in children {
+                entries[(index & ((1 << epbs) - 1)) as usize] = block_ptr;
+            }
+            replaced(old, freed);
+            let block_ptr = try!(write_indirect(reader, alloc, txg, dnode, level, &entries));
+            written += allocated(&block_ptr);
+            parent_ptrs.insert(parent, block_ptr);
+        }
+        level_ptrs = parent_ptrs;
+    }
+    for (index, block_ptr) in level_ptrs {
+        dnode.set_blockptr(index as usize, &block_ptr);
+    }
+
+    dnode.maxblkid = maxblkid;
+    let unit = if dnode.flags & DNODE_FLAG_USED_BYTES != 0 { 1 } else { 512 };
+    dnode.used = (dnode.used + written / unit).saturating_sub(freed_bytes / unit);
+    Ok(())
+}
+
+#[test]
+fn test_sync_dnode() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu;
+    use super::dmu_zfetch::Zfetch;
+    use super::test_util::Bump;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = Bump(0);
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.object_type = ObjectType::PlainFileContents as u8;
+    dnode.nblkptr = 1;
+    dnode.indblkshift = 10; // 8 block pointers per indirect block
+    dnode.data_blk_sz_sec = 1;
+    dnode.flags = DNODE_FLAG_USED_BYTES;
+
+    let mut blocks = BTreeMap::new();
+    blocks.insert(0, vec![1; 512]);
+    let mut freed = Vec::new();
+    sync_dnode(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 5, &mut dnode, &blocks, &mut freed)
+        .unwrap();
+    assert_eq!((dnode.nlevels, dnode.maxblkid, dnode.used), (1, 0, 512));
+
+    // Block 20 takes two more levels, the first block stays where it is
+    blocks.insert(20, vec![20; 512]);
+    blocks.remove(&0);
+    sync_dnode(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 6, &mut dnode, &blocks, &mut freed)
+        .unwrap();
+    assert_eq!((dnode.nlevels, dnode.maxblkid), (3, 20));
+    assert_eq!(dmu::read_block(&mut reader, &dnode, 0).unwrap(), vec![1; 512]);
+    assert_eq!(dmu::read_block(&mut reader, &dnode, 20).unwrap(), vec![20; 512]);
+    assert!(dmu::block_ptr(&mut reader, &dnode, 9).unwrap().is_none());
+    assert_eq!(dnode.fill_count(), 2);
+
+    // Rewriting a block frees the old copy, and the indirect blocks above it
+    let before = freed.len();
+    blocks.insert(20, vec![21; 512]);
+    sync_dnode(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 7, &mut dnode, &blocks, &mut freed)
+        .unwrap();
+    assert_eq!(freed.len() - before, 3);
+    assert_eq!(dmu::read_block(&mut reader, &dnode, 20).unwrap(), vec![21; 512]);
+    let birth_txg = dmu::block_ptr(&mut reader, &dnode, 20).unwrap().unwrap().birth_txg;
+    assert_eq!(birth_txg, 7);
+
+    // A hole frees the block, and the indirect block left with nothing but holes under it
+    let range = dmu::free_range(&dnode, 20 * 512, 512);
+    assert_eq!(dmu::freed_blocks(&mut reader, &dnode, &range).unwrap().len(), 2);
+    let range = dmu::truncate_range(&dnode, 0);
+    assert_eq!(dmu::freed_blocks(&mut reader, &dnode, &range).unwrap().len(), 5);
+    let (before, used) = (freed.len(), dnode.used);
+    blocks.clear();
+    blocks.insert(20, Vec::new());
+    sync_dnode(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 8, &mut dnode, &blocks, &mut freed)
+        .unwrap();
+    assert_eq!(freed.len() - before, 3);
+    assert_eq!(used - dnode.used, 512 + 1024);
+    assert!(dmu::block_ptr(&mut reader, &dnode, 20).unwrap().is_none());
+    assert_eq!(dmu::read_block(&mut reader, &dnode, 0).unwrap(), vec![1; 512]);
+    assert_eq!((dnode.maxblkid, dnode.fill_count()), (20, 1));
+
+    // Holes past the end are there already, and punching the last block empties the tree
+    blocks.insert(0, Vec::new());
+    blocks.insert(30, Vec::new());
+    sync_dnode(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 9, &mut dnode, &blocks, &mut freed)
+        .unwrap();
+    assert_eq!((dnode.nlevels, dnode.maxblkid, dnode.used), (3, 20, 0));
+    assert!(dnode.get_blockptr(0).is_hole());
+}
+
+#[test]
+fn test_dedup() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu;
+    use super::dmu_zfetch::Zfetch;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    assert_eq!(Ddt::new(ZIO_CHECKSUM_FLETCHER_4).err(), Some(zfs::Error::UnsupportedFeature));
+    let mut ddt = Ddt::new(8).unwrap(); // sha256
+
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.object_type = ObjectType::PlainFileContents as u8;
+    dnode.nblkptr = 3;
+    dnode.indblkshift = 14;
+    dnode.data_blk_sz_sec = 1;
+
+    // Blocks 0 and 2 have the same data, so they share a block
+    let mut blocks = BTreeMap::new();
+    blocks.insert(0, vec![1; 512]);
+    blocks.insert(1, vec![2; 512]);
+    blocks.insert(2, vec![1; 512]);
+    let mut freed = Vec::new();
+    sync_dnode(&mut reader,
+               &mut alloc,
+               Some(&mut ddt),
+               ZIO_COMPRESS_OFF,
+               5,
+               &mut dnode,
+               &blocks,
+               &mut freed)
+        .unwrap();
+    let block_ptrs: Vec<_> = (0..3)
+                                 .map(|blkid| {
+                                     dmu::block_ptr(&mut reader, &dnode, blkid).unwrap().unwrap()
+                                 })
+                                 .collect();
+    assert!(block_ptrs.iter().all(|block_ptr| block_ptr.dedup() && block_ptr.checksum() == 8));
+    let dvas: Vec<_> = block_ptrs.iter().map(|block_ptr| block_ptr.dvas[0]).collect();
+    assert!(dvas[0] == dvas[2] && dvas[0] != dvas[1]);
+    assert_eq!(dmu::read_block(&mut reader, &dnode, 2).unwrap(), vec![1; 512]);
+    assert_eq!(ddt.len(), 2);
+    assert_eq!(ddt.lookup(&DdtKey::of(&block_ptrs[0])).unwrap().refcnt(), 2);
+    assert_eq!(ddt.histogram().total().dedup_ratio(), 1.5);
+
+    // Rewriting block 0 drops a reference, the shared block is only freed with the last one
+    blocks.clear();
+    blocks.insert(0, vec![3; 512]);
+    sync_dnode(&mut reader,
+               &mut alloc,
+               Some(&mut ddt),
+               ZIO_COMPRESS_OFF,
+               6,
+               &mut dnode,
+               &blocks,
+               &mut freed)
+        .unwrap();
+    assert_eq!(freed.len(), 1);
+    assert!(!ddt.release(&freed[0]));
+    assert!(ddt.release(&block_ptrs[2]));
+    assert!(ddt.lookup(&DdtKey::of(&block_ptrs[0])).is_none());
+    assert!(!ddt.release(&block_ptrs[2]));
+}
+
+#[test]
+fn test_compressed_write() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu;
+    use super::dmu_zfetch::Zfetch;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+    use super::zio_compress::{ZIO_COMPRESS_LZJB, ZIO_COMPRESS_ON, ZIO_COMPRESS_ZLE};
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+
+    // Text compresses, a run of zeros mostly too, pseudo-random bytes don't
+    let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog. "
+                            .iter()
+                            .cycle()
+                            .take(4096)
+                            .cloned()
+                            .collect();
+    let mut sparse = vec![0; 4096];
+    sparse[100..110].copy_from_slice(b"0123456789");
+    let mut seed = 12345u32;
+    let noise: Vec<u8> = (0..4096)
+                             .map(|_| {
+                                 seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                                 (seed >> 16) as u8
+                             })
+                             .collect();
+
+    for &compression in &[ZIO_COMPRESS_ON, ZIO_COMPRESS_LZJB, ZIO_COMPRESS_ZLE] {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = ObjectType::PlainFileContents as u8;
+        dnode.nblkptr = 3;
+        dnode.indblkshift = 14;
+        dnode.data_blk_sz_sec = 8;
+        let mut blocks = BTreeMap::new();
+        blocks.insert(0, text.clone());
+        blocks.insert(1, sparse.clone());
+        blocks.insert(2, noise.clone());
+        sync_dnode(&mut reader,
+                   &mut alloc,
+                   None,
+                   compression,
+                   5,
+                   &mut dnode,
+                   &blocks,
+                   &mut Vec::new())
+            .unwrap();
+
+        for (&blkid, data) in &blocks {
+            let block_ptr = dmu::block_ptr(&mut reader, &dnode, blkid).unwrap().unwrap();
+            assert_eq!(dmu::read_block(&mut reader, &dnode, blkid).unwrap(), *data);
+            assert_eq!(block_ptr.lsize(), 8);
+            let compressed = block_ptr.compression();
+            if compressed == ZIO_COMPRESS_OFF {
+                assert_eq!(block_ptr.psize(), 8);
+            } else {
+                // At least 12.5% smaller, and what "on" stands for
+                assert!(block_ptr.psize() <= 7);
+                assert_eq!(compressed, zio_compress::compress_select(compression));
+            }
+        }
+        let mut compression_of = |blkid| {
+            dmu::block_ptr(&mut reader, &dnode, blkid).unwrap().unwrap().compression()
+        };
+        // ZLE only compresses runs of zeros
+        let text_compression = if compression == ZIO_COMPRESS_ZLE {
+            ZIO_COMPRESS_OFF
+        } else {
+            ZIO_COMPRESS_LZJB
+        };
+        assert_eq!(compression_of(0), text_compression);
+        assert!(compression_of(1) != ZIO_COMPRESS_OFF);
+        assert_eq!(compression_of(2), ZIO_COMPRESS_OFF);
+    }
+}
@@ -0,0 +1,331 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use super::ZfsReader;
+use super::crypto;
+use super::dmu_objset::ObjectSet;
+use super::dsl_dir::DslDir;
+use super::dsl_pool::DslPool;
+use super::nvpair::{NvList, NvValue};
+use super::zap;
+use super::zfs;
+use super::zio_crypt::{self, CryptSuite, CryptoKey, MASTER_KEY_MAX_LEN, SHA512_HMAC_KEYLEN,
+                       WRAPPING_IV_LEN, WRAPPING_KEY_LEN};
+
+/// Field of a (zapified) dsl_dir holding the object number of its DSL crypto key object
+pub const DD_FIELD_CRYPTO_KEY_OBJ: &'static str = "com.datto:crypto_key_obj";
+
+const DSL_CRYPTO_KEY_CRYPTO_SUITE: &'static str = "DSL_CRYPTO_SUITE";
+const DSL_CRYPTO_KEY_GUID: &'static str = "DSL_CRYPTO_GUID";
+const DSL_CRYPTO_KEY_VERSION: &'static str = "DSL_CRYPTO_VERSION";
+const DSL_CRYPTO_KEY_MASTER_KEY: &'static str = "DSL_CRYPTO_MASTER_KEY_1";
+const DSL_CRYPTO_KEY_HMAC_KEY: &'static str = "DSL_CRYPTO_HMAC_KEY_1";
+const DSL_CRYPTO_KEY_IV: &'static str = "DSL_CRYPTO_IV";
+const DSL_CRYPTO_KEY_MAC: &'static str = "DSL_CRYPTO_MAC";
+const DSL_CRYPTO_KEY_ROOT_DDOBJ: &'static str = "DSL_CRYPTO_ROOT_DDOBJ";
+
+/// Field of a dataset with the guid of the IV set its blocks were encrypted under
+const DS_FIELD_IVSET_GUID: &'static str = "com.datto:ivset_guid";
+
+/// The wrapping key properties, only set on the key object of the encryption root
+const KEYFORMAT: &'static str = "keyformat";
+const PBKDF2_SALT: &'static str = "pbkdf2salt";
+const PBKDF2_ITERS: &'static str = "pbkdf2iters";
+
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+pub const MAX_PASSPHRASE_LEN: usize = 512;
+
+/// How the user gives the wrapping key (the `keyformat` property)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyFormat {
+    /// 32 bytes, as they are
+    Raw = 1,
+    /// 64 hex digits
+    Hex,
+    /// A passphrase the key is derived from with PBKDF2
+    Passphrase,
+}
+
+/// Where the wrapping key of an encryption root comes from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WrappingParams {
+    pub format: KeyFormat,
+    /// PBKDF2 salt and iterations, for passphrases
+    pub salt: u64,
+    pub iterations: u64,
+}
+
+impl WrappingParams {
+    /// The parameters kept on DSL crypto key object `object`
+    pub fn open(reader: &mut ZfsReader, mos: &ObjectSet, object: u64) -> zfs::Result<Self> {
+        let format = match try!(lookup_int(reader, mos, object, KEYFORMAT)) {
+            1 => KeyFormat::Raw,
+            2 => KeyFormat::Hex,
+            3 => KeyFormat::Passphrase,
+            _ => return Err(zfs::Error::Invalid),
+        };
+        let (salt, iterations) = if format == KeyFormat::Passphrase {
+            (try!(lookup_int(reader, mos, object, PBKDF2_SALT)),
+             try!(lookup_int(reader, mos, object, PBKDF2_ITERS)))
+        } else {
+            (0, 0)
+        };
+        Ok(WrappingParams {
+            format: format,
+            salt: salt,
+            iterations: iterations,
+        })
+    }
+
+    /// Turn the key the user gave into the wrapping key. Fails with `WrongKey` if it doesn't
+    /// even have the right format.
+    pub fn wrapping_key(&self, key: &[u8]) -> zfs::Result<Vec<u8>> {
+        match self.format {
+            KeyFormat::Raw if key.len() == WRAPPING_KEY_LEN => Ok(key.to_vec()),
+            KeyFormat::Hex if key.len() == WRAPPING_KEY_LEN * 2 &&
+                              key.iter().all(|byte| byte.is_ascii_hexdigit()) => {
+                let digit = |byte: u8| (byte as char).to_digit(16).unwrap_or(0) as u8;
+                Ok(key.chunks(2).map(|pair| digit(pair[0]) << 4 | digit(pair[1])).collect())
+            }
+            KeyFormat::Passphrase if key.len() >= MIN_PASSPHRASE_LEN &&
+                                     key.len() <= MAX_PASSPHRASE_LEN => {
+                Ok(crypto::pbkdf2_sha1(key,
+                                       &self.salt.to_le_bytes(),
+                                       self.iterations,
+                                       WRAPPING_KEY_LEN))
+            }
+            _ => Err(zfs::Error::WrongKey),
+        }
+    }
+}
+
+/// The master key of an encrypted dataset as it's kept in the MOS (a DSL crypto key object):
+/// wrapped, i.e. encrypted with the wrapping key of its encryption root
+pub struct DslCryptoKey {
+    pub object: u64,
+    pub suite: CryptSuite,
+    pub guid: u64,
+    pub version: u64,
+    /// The dsl_dir of the encryption root, the dataset whose wrapping key this one shares
+    pub root_ddobj: u64,
+    /// `MASTER_KEY_MAX_LEN` bytes, of which the suite uses the first `suite.key_len()`
+    pub master: Vec<u8>,
+    pub hmac: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub mac: Vec<u8>,
+}
+
+impl DslCryptoKey {
+    /// The key object of the dataset of `dir`. Fails with `NoEntity` if it isn't encrypted.
+    pub fn open(reader: &mut ZfsReader, mos: &ObjectSet, dir: &DslDir) -> zfs::Result<Self> {
+        let object = try!(zap::lookup(reader, mos, dir.object, DD_FIELD_CRYPTO_KEY_OBJ)
+                              .map_err(|_| zfs::Error::NoEntity));
+        let suite = try!(lookup_int(reader, mos, object, DSL_CRYPTO_KEY_CRYPTO_SUITE));
+        Ok(DslCryptoKey {
+            object: object,
+            suite: try!(CryptSuite::from_u64(suite).ok_or(zfs::Error::UnsupportedFeature)),
+            guid: try!(lookup_int(reader, mos, object, DSL_CRYPTO_KEY_GUID)),
+            // Keys from before the version was recorded are version 0
+            version: lookup_int(reader, mos, object, DSL_CRYPTO_KEY_VERSION).unwrap_or(0),
+            root_ddobj: try!(lookup_int(reader, mos, object, DSL_CRYPTO_KEY_ROOT_DDOBJ)),
+            master: try!(lookup_bytes(reader, mos, object, DSL_CRYPTO_KEY_MASTER_KEY,
+                                      MASTER_KEY_MAX_LEN)),
+            hmac: try!(lookup_bytes(reader, mos, object, DSL_CRYPTO_KEY_HMAC_KEY,
+                                    SHA512_HMAC_KEYLEN)),
+            iv: try!(lookup_bytes(reader, mos, object, DSL_CRYPTO_KEY_IV, WRAPPING_IV_LEN)),
+            mac: try!(lookup_bytes(reader, mos, object, DSL_CRYPTO_KEY_MAC,
+                                   zio_crypt::ZIO_DATA_MAC_LEN)),
+        })
+    }
+
+    /// Decrypt the master and HMAC keys with `wrapping_key`. Fails with `WrongKey` if they don't
+    /// match their MAC, which is what a wrong key (or passphrase) does.
+    pub fn unwrap(&self, wrapping_key: &[u8]) -> zfs::Result<CryptoKey> {
+        let key_len = self.suite.key_len();
+        let mut keys = self.master[..key_len].to_vec();
+        keys.extend_from_slice(&self.hmac);
+        if !zio_crypt::decrypt(self.suite.mode(),
+                               wrapping_key,
+                               &self.iv,
+                               &self.aad(),
+                               &mut keys,
+                               &self.mac) {
+            return Err(zfs::Error::WrongKey);
+        }
+        let hmac = keys.split_off(key_len);
+        Ok(CryptoKey {
+            suite: self.suite,
+            version: self.version,
+            guid: self.guid,
+            master: keys,
+            hmac: hmac,
+        })
+    }
+
+    /// What's authenticated along with the keys: the guid, and from version 1 on the suite and
+    /// the version too
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = self.guid.to_le_bytes().to_vec();
+        if self.version > 0 {
+            aad.extend_from_slice(&(self.suite as u64).to_le_bytes());
+            aad.extend_from_slice(&self.version.to_le_bytes());
+        }
+        aad
+    }
+}
+
+/// Load the key of the encryption root of dataset `name` from the user's `key` into `reader`,
+/// along with those of the datasets that inherit it, like `zfs load-key`. Returns how many keys
+/// weren't loaded already.
+pub fn load_keys(reader: &mut ZfsReader,
+                 mos: &ObjectSet,
+                 dsl_pool: &DslPool,
+                 pool_name: &str,
+                 name: &str,
+                 key: &[u8])
+                 -> zfs::Result<usize> {
+    let dir = try!(dsl_pool.lookup_dir(reader, mos, name));
+    let root_ddobj = try!(DslCryptoKey::open(reader, mos, &dir)).root_ddobj;
+    let root_key = try!(zap::lookup(reader, mos, root_ddobj, DD_FIELD_CRYPTO_KEY_OBJ)
+                            .map_err(|_| zfs::Error::Invalid));
+    let params = try!(WrappingParams::open(reader, mos, root_key));
+    let wrapping_key = try!(params.wrapping_key(key));
+
+    let mut loaded = 0;
+    for (_, dir) in try!(dsl_pool.datasets(reader, mos, pool_name)) {
+        let crypto_key = match DslCryptoKey::open(reader, mos, &dir) {
+            Ok(crypto_key) => crypto_key,
+            Err(zfs::Error::NoEntity) => continue,
+            Err(e) => return Err(e),
+        };
+        if crypto_key.root_ddobj != root_ddobj {
+            continue;
+        }
+        let key = try!(crypto_key.unwrap(&wrapping_key));
+        if !reader.zio.keys.iter().any(|other| other.guid == key.guid) {
+            reader.zio.keys.push(key);
+            loaded += 1;
+        }
+    }
+    Ok(loaded)
+}
+
+/// The `crypt_keydata` nvlist of the BEGIN record of a raw send of `dataset` (with objset
+/// `objset`, in `dir`): its key as it's wrapped, along with what a receiver needs to check the
+/// objset it's sent with. `from_dataset` is the snapshot an incremental send is from, if any.
+pub fn key_nvlist(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  dir: &DslDir,
+                  dataset: u64,
+                  from_dataset: Option<u64>,
+                  objset: &ObjectSet)
+                  -> zfs::Result<NvList> {
+    let key = try!(DslCryptoKey::open(reader, mos, dir));
+    let root_key = try!(zap::lookup(reader, mos, key.root_ddobj, DD_FIELD_CRYPTO_KEY_OBJ)
+                            .map_err(|_| zfs::Error::Invalid));
+    let params = try!(WrappingParams::open(reader, mos, root_key));
+    // Datasets from before IV set guids were recorded have none
+    let mut ivset_guid = |object: u64| {
+        zap::lookup(reader, mos, object, DS_FIELD_IVSET_GUID).unwrap_or(0)
+    };
+    let to_ivset_guid = ivset_guid(dataset);
+    let from_ivset_guid = from_dataset.map_or(0, ivset_guid);
+
+    let meta_dnode = objset.phys.meta_dnode;
+    let mut nv = NvList::new(1);
+    for &(name, value) in &[(DSL_CRYPTO_KEY_CRYPTO_SUITE, key.suite as u64),
+                            (DSL_CRYPTO_KEY_GUID, key.guid),
+                            (DSL_CRYPTO_KEY_VERSION, key.version),
+                            (KEYFORMAT, params.format as u64),
+                            (PBKDF2_SALT, params.salt),
+                            (PBKDF2_ITERS, params.iterations),
+                            ("mdn_checksum", meta_dnode.checksum as u64),
+                            ("mdn_compress", meta_dnode.compress as u64),
+                            ("mdn_nlevels", meta_dnode.nlevels as u64),
+                            ("mdn_blksz", meta_dnode.data_block_size()),
+                            ("mdn_indblkshift", meta_dnode.indblkshift as u64),
+                            ("mdn_nblkptr", meta_dnode.nblkptr as u64),
+                            ("mdn_maxblkid", meta_dnode.maxblkid),
+                            ("to_ivset_guid", to_ivset_guid),
+                            ("from_ivset_guid", from_ivset_guid)] {
+        nv.add(name.to_owned(), NvValue::Uint64(value));
+    }
+    for &(name, ref bytes) in &[(DSL_CRYPTO_KEY_MASTER_KEY, &key.master[..]),
+                                (DSL_CRYPTO_KEY_HMAC_KEY, &key.hmac[..]),
+                                (DSL_CRYPTO_KEY_IV, &key.iv[..]),
+                                (DSL_CRYPTO_KEY_MAC, &key.mac[..]),
+                                ("portable_mac", &objset.phys.portable_mac[..])] {
+        nv.add(name.to_owned(), NvValue::Uint8Array(bytes.to_vec()));
+    }
+    Ok(nv)
+}
+
+fn lookup_int(reader: &mut ZfsReader, mos: &ObjectSet, obj: u64, name: &str) -> zfs::Result<u64> {
+    let value = try!(zap::lookup_value(reader, mos, obj, name).map_err(|_| zfs::Error::Invalid));
+    value.as_int().ok_or(zfs::Error::Invalid)
+}
+
+/// A byte array entry, which has to be `len` bytes long
+fn lookup_bytes(reader: &mut ZfsReader,
+                mos: &ObjectSet,
+                obj: u64,
+                name: &str,
+                len: usize)
+                -> zfs::Result<Vec<u8>> {
+    let value = try!(zap::lookup_value(reader, mos, obj, name).map_err(|_| zfs::Error::Invalid));
+    match value.as_bytes() {
+        Some(bytes) if bytes.len() == len => Ok(bytes.to_vec()),
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+#[test]
+fn test_unwrap_key() {
+    let params = WrappingParams {
+        format: KeyFormat::Passphrase,
+        salt: 0x0123456789ABCDEF,
+        iterations: 1000,
+    };
+    let wrapping_key = params.wrapping_key(b"correct horse").unwrap();
+    assert_eq!(wrapping_key.len(), WRAPPING_KEY_LEN);
+    assert_eq!(params.wrapping_key(b"short"), Err(zfs::Error::WrongKey));
+
+    let hex = WrappingParams { format: KeyFormat::Hex, ..params };
+    let hex_key = hex.wrapping_key(&[b'a'; 64]).unwrap();
+    assert_eq!(hex_key, vec![0xAA; 32]);
+    assert_eq!(hex.wrapping_key(&[b'g'; 64]), Err(zfs::Error::WrongKey));
+
+    for &(suite, version) in &[(CryptSuite::Aes256Gcm, 1), (CryptSuite::Aes128Ccm, 0)] {
+        let mut crypto_key = DslCryptoKey {
+            object: 0,
+            suite: suite,
+            guid: 0xFEEDFACE,
+            version: version,
+            root_ddobj: 0,
+            master: vec![0x11; MASTER_KEY_MAX_LEN],
+            hmac: vec![0x22; SHA512_HMAC_KEYLEN],
+            iv: vec![0x33; WRAPPING_IV_LEN],
+            mac: vec![],
+        };
+        let mut keys = crypto_key.master[..suite.key_len()].to_vec();
+        keys.extend_from_slice(&crypto_key.hmac);
+        let mac = zio_crypt::encrypt(suite.mode(),
+                                     &wrapping_key,
+                                     &crypto_key.iv,
+                                     &crypto_key.aad(),
+                                     &mut keys)
+                      .unwrap();
+        crypto_key.mac = mac.to_vec();
+        crypto_key.master[..suite.key_len()].copy_from_slice(&keys[..suite.key_len()]);
+        crypto_key.hmac = keys[suite.key_len()..].to_vec();
+
+        let key = crypto_key.unwrap(&wrapping_key).unwrap();
+        assert_eq!(key.master, vec![0x11; suite.key_len()]);
+        assert_eq!(key.hmac, vec![0x22; SHA512_HMAC_KEYLEN]);
+        assert_eq!(key.guid, 0xFEEDFACE);
+        assert_eq!(crypto_key.unwrap(&hex_key).err(), Some(zfs::Error::WrongKey));
+        // The guid is authenticated
+        crypto_key.guid += 1;
+        assert_eq!(crypto_key.unwrap(&wrapping_key).err(), Some(zfs::Error::WrongKey));
+    }
+}
@@ -1,5 +1,18 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{mem, slice};
+use std::collections::BTreeMap;
+
+use super::ZfsReader;
 use super::block_ptr::BlockPtr;
-use super::from_bytes::FromBytes;
+use super::bpobj;
+use super::dmu_objset::{ObjectSet, Usage};
+use super::dsl_dir::DslDir;
+use super::dsl_prop::{self, PropSource, PropValue};
+use super::from_bytes::{Decoder, FromBytes};
+use super::zap;
+use super::zfs;
+use super::zil;
 
 #[repr(packed)]
 pub struct DslDatasetPhys {
@@ -34,7 +47,454 @@ pub struct DslDatasetPhys {
     pad: [u64; 5], // pad out to 320 bytes for good measure
 }
 
-impl FromBytes for DslDatasetPhys {}
+impl FromBytes for DslDatasetPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut head = [0; 16];
+        decoder.fill_u64(&mut head);
+        let bp = BlockPtr::decode(decoder);
+        let next_clones_obj = decoder.u64();
+        let props_obj = decoder.u64();
+        let userrefs_obj = decoder.u64();
+        let mut pad = [0; 5];
+        decoder.fill_u64(&mut pad);
+        DslDatasetPhys {
+            dir_obj: head[0],
+            prev_snap_obj: head[1],
+            prev_snap_txg: head[2],
+            next_snap_obj: head[3],
+            snapnames_zapobj: head[4],
+            num_children: head[5],
+            creation_time: head[6],
+            creation_txg: head[7],
+            deadlist_obj: head[8],
+            referenced_bytes: head[9],
+            compressed_bytes: head[10],
+            uncompressed_bytes: head[11],
+            unique_bytes: head[12],
+            fsid_guid: head[13],
+            guid: head[14],
+            flags: head[15],
+            bp: bp,
+            next_clones_obj: next_clones_obj,
+            props_obj: props_obj,
+            userrefs_obj: userrefs_obj,
+            pad: pad,
+        }
+    }
+}
+
+/// What happens to a block the dataset stops referencing, e.g. because the file it belonged to
+/// was truncated
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlockKill {
+    /// Nothing else references the block, its space goes back to the pool's freeing tree
+    Free,
+    /// The latest snapshot still references the block, so it goes on the dataset's deadlist until
+    /// that snapshot is destroyed
+    Deadlist,
+}
+
+impl DslDatasetPhys {
+    /// The dataset as it's written to its dnode's bonus buffer, in host byte order
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const DslDatasetPhys as *const u8,
+                                  mem::size_of::<DslDatasetPhys>())
+        }
+    }
+
+    /// Blocks born after the latest snapshot can't be in any snapshot
+    pub fn block_kill(&self, block_ptr: &BlockPtr) -> BlockKill {
+        if block_ptr.birth_txg > self.prev_snap_txg {
+            BlockKill::Free
+        } else {
+            BlockKill::Deadlist
+        }
+    }
+}
+
+/// A snapshot of a dataset, as listed in the dataset's snapshot names ZAP
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub object: u64, // MOS object number of the snapshot's dsl_dataset
+    pub guid: u64,
+    pub creation_time: u64, // seconds since 1970
+    pub creation_txg: u64,
+    /// Birth txg of the snapshot's objset
+    pub objset_txg: u64,
+    /// Whether user holds or clones keep the snapshot from being destroyed
+    pub held: bool,
+}
+
+/// How a snapshot's space is shared, like `zfs list -o used,written,clones` shows it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotSpace {
+    /// Space only the snapshot references: what destroying it would free
+    pub unique: u64,
+    /// Space it references that the snapshot before it didn't
+    pub written: u64,
+    /// MOS object numbers of the datasets cloned from it
+    pub clones: Vec<u64>,
+}
+
+/// How much a dataset may still grow, as of when it was read: its refquota against the space it
+/// references, and the quota of its DSL directory and of each one above it against the space
+/// used under them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpaceLimits {
+    pub refquota: u64, // 0 for none
+    pub referenced: u64,
+    /// (quota, used bytes) of the directories that have a quota
+    pub quotas: Vec<(u64, u64)>,
+}
+
+impl SpaceLimits {
+    fn read(reader: &mut ZfsReader,
+            mos: &ObjectSet,
+            phys: &DslDatasetPhys)
+            -> Result<SpaceLimits, String> {
+        let mut dir = try!(DslDir::open(reader, mos, phys.dir_obj));
+        let props_zapobj = dir.phys.props_zapobj;
+        let refquota = try!(dsl_prop::stored_u64(reader, mos, props_zapobj, "refquota"));
+        let mut quotas = Vec::new();
+        loop {
+            let (quota, used, parent_obj) = (dir.phys.quota, dir.phys.used_bytes,
+                                             dir.phys.parent_obj);
+            if quota != 0 {
+                quotas.push((quota, used));
+            }
+            if parent_obj == 0 {
+                break;
+            }
+            dir = try!(DslDir::open(reader, mos, parent_obj));
+        }
+        Ok(SpaceLimits {
+            refquota: refquota.unwrap_or(0),
+            referenced: phys.referenced_bytes,
+            quotas: quotas,
+        })
+    }
+
+    /// Whether `bytes` more fit: `QuotaExceeded` if they don't
+    pub fn check(&self, bytes: u64) -> zfs::Result<()> {
+        if self.refquota != 0 && self.referenced + bytes > self.refquota {
+            return Err(zfs::Error::QuotaExceeded);
+        }
+        if self.quotas.iter().any(|&(quota, used)| used + bytes > quota) {
+            return Err(zfs::Error::QuotaExceeded);
+        }
+        Ok(())
+    }
+}
+
+/// The blocks on deadlist `obj`. Deadlists used to be a single block pointer object; now they're
+/// a ZAP of them, keyed by the txg of the snapshot that was the latest when their blocks died.
+fn deadlist_blocks(reader: &mut ZfsReader,
+                   mos: &ObjectSet,
+                   obj: u64)
+                   -> Result<Vec<BlockPtr>, String> {
+    if obj == 0 {
+        return Ok(Vec::new());
+    }
+    let bpobjs: Vec<u64> = match zap::iter(reader, mos, obj) {
+        Ok(entries) => entries.map(|(_, bpobj)| bpobj).collect(),
+        Err(_) => vec![obj],
+    };
+    let mut blocks = Vec::new();
+    for bpobj in bpobjs {
+        blocks.extend(try!(bpobj::blocks(reader, mos, bpobj)));
+    }
+    Ok(blocks)
+}
+
+/// The MOS objects deadlist `obj` is made of: the ZAP, if it is one, and the block pointer
+/// objects in it
+pub fn deadlist_objects(reader: &mut ZfsReader,
+                        mos: &ObjectSet,
+                        obj: u64)
+                        -> Result<Vec<u64>, String> {
+    if obj == 0 {
+        return Ok(Vec::new());
+    }
+    let (mut objects, bpobjs): (Vec<u64>, Vec<u64>) = match zap::iter(reader, mos, obj) {
+        Ok(entries) => (vec![obj], entries.map(|(_, bpobj)| bpobj).collect()),
+        Err(_) => (Vec::new(), vec![obj]),
+    };
+    for bpobj in bpobjs {
+        objects.extend(try!(bpobj::objects(reader, mos, bpobj)));
+    }
+    Ok(objects)
+}
+
+/// Space written to dataset `new` since its earlier snapshot `old`, whether `new` is a later
+/// snapshot, the head, or a clone of `old`: the growth in referenced space, plus whatever `old`
+/// referenced that the datasets in between let go of since.
+pub fn written_between(reader: &mut ZfsReader,
+                       mos: &ObjectSet,
+                       old: u64,
+                       new: u64)
+                       -> Result<u64, String> {
+    let old_phys = try!(Dataset::read_phys(reader, mos, old));
+    let new_phys = try!(Dataset::read_phys(reader, mos, new));
+    let mut written = new_phys.referenced_bytes as i64 - old_phys.referenced_bytes as i64;
+    let mut object = new;
+    while object != old {
+        if object == 0 {
+            return Err(format!("Dataset {} is not a snapshot before {}", old, new));
+        }
+        let phys = try!(Dataset::read_phys(reader, mos, object));
+        let dead = try!(deadlist_blocks(reader, mos, phys.deadlist_obj));
+        written += bpobj::space_born(&dead, 0, old_phys.creation_txg) as i64;
+        object = phys.prev_snap_obj;
+    }
+    Ok(written as u64)
+}
+
+/// Whether dataset `old` is a snapshot taken before dataset `new`, going back from `new` through
+/// the snapshots before it (and, for a clone, its origin and the ones before that)
+pub fn is_snapshot_before(reader: &mut ZfsReader,
+                          mos: &ObjectSet,
+                          old: u64,
+                          new: u64)
+                          -> Result<bool, String> {
+    let mut object = new;
+    while object != 0 {
+        object = try!(Dataset::read_phys(reader, mos, object)).prev_snap_obj;
+        if object == old {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+//------------------------------------------------------------------------------------------------//
+
+/// An open dataset and its objset.
+///
+/// A pinned dataset keeps reading through the objset block pointer it was opened with, so a long
+/// running reader (send, archive) sees the dataset exactly as it was at that txg, even while new
+/// txgs get synced. Since blocks are never overwritten in place, the old tree stays valid as long
+/// as nothing frees it.
+pub struct Dataset {
+    pub object: u64, // MOS object number of the dsl_dataset
+    pub phys: DslDatasetPhys,
+    pub objset: ObjectSet,
+    /// What the intent log changes on top of `objset`, if the pool wasn't exported cleanly
+    pub zil: zil::Replay,
+    /// The quotas that apply to writes to the dataset
+    pub limits: SpaceLimits,
+    pinned: bool,
+    /// The pool generation `phys` was read at (see `zio::Reader::generation`)
+    generation: u64,
+}
+
+impl Dataset {
+    /// Open the dataset with MOS object number `object`. If `pin` is set, the dataset will stay at
+    /// the txg it was opened at.
+    pub fn open(reader: &mut ZfsReader,
+                mos: &ObjectSet,
+                object: u64,
+                pin: bool)
+                -> Result<Self, String> {
+        let generation = reader.zio.generation();
+        let phys = try!(Self::read_phys(reader, mos, object));
+        let objset = try!(ObjectSet::open(reader, &phys.bp));
+        let zil = Self::replay_zil(reader, &phys, &objset);
+        let limits = try!(SpaceLimits::read(reader, mos, &phys));
+
+        Ok(Dataset {
+            object: object,
+            phys: phys,
+            objset: objset,
+            zil: zil,
+            limits: limits,
+            pinned: pin,
+            generation: generation,
+        })
+    }
+
+    fn read_phys(reader: &mut ZfsReader,
+                 mos: &ObjectSet,
+                 object: u64)
+                 -> Result<DslDatasetPhys, String> {
+        let dnode = try!(mos.dnode(reader, object));
+        DslDatasetPhys::from_bytes(dnode.get_bonus()).map_err(String::from)
+    }
+
+    /// Replay the intent log of a dataset's objset. Snapshots have none, their log is left
+    /// behind with the head dataset.
+    fn replay_zil(reader: &mut ZfsReader,
+                  phys: &DslDatasetPhys,
+                  objset: &ObjectSet)
+                  -> zil::Replay {
+        if phys.num_children != 0 {
+            return zil::Replay::default();
+        }
+        zil::Replay::open(reader, objset, phys.bp.birth_txg)
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// The txg the objset we are reading was born in
+    pub fn txg(&self) -> u64 {
+        self.phys.bp.birth_txg
+    }
+
+    /// Catch up with the latest synced state of the dataset. Txgs another writer synced are
+    /// looked for first (see `ZfsReader::revalidate`), and if there are any, `mos` is opened
+    /// again from the newest uberblock. Pinned datasets never move, and nothing of the dataset is
+    /// read unless the pool's generation moved since the last time. Returns whether the objset
+    /// changed.
+    pub fn refresh(&mut self,
+                   reader: &mut ZfsReader,
+                   mos: &mut ObjectSet)
+                   -> Result<bool, String> {
+        if self.pinned {
+            return Ok(false);
+        }
+        if let Some(uberblock) = try!(reader.revalidate()) {
+            *mos = try!(ObjectSet::open(reader, &uberblock.rootbp));
+        }
+        let generation = reader.zio.generation();
+        if generation == self.generation {
+            return Ok(false);
+        }
+
+        let phys = try!(Self::read_phys(reader, mos, self.object));
+        self.generation = generation;
+        self.limits = try!(SpaceLimits::read(reader, mos, &phys));
+        if phys.bp.birth_txg == self.phys.bp.birth_txg {
+            return Ok(false);
+        }
+
+        self.objset = try!(ObjectSet::open(reader, &phys.bp));
+        self.zil = Self::replay_zil(reader, &phys, &self.objset);
+        self.phys = phys;
+        Ok(true)
+    }
+
+    /// The effective value of property `name` and where it comes from, with the root dataset
+    /// named `pool_name` (see `dsl_prop::get_property`)
+    pub fn get_property(&self,
+                        reader: &mut ZfsReader,
+                        mos: &ObjectSet,
+                        pool_name: &str,
+                        name: &str)
+                        -> Result<Option<(PropValue, PropSource)>, String> {
+        dsl_prop::get_property(reader,
+                               mos,
+                               pool_name,
+                               self.phys.dir_obj,
+                               self.phys.props_obj,
+                               name)
+    }
+
+    /// Space and objects charged to each user ID, from the user used ZAP of the objset (`zfs
+    /// userspace`). Empty if the objset keeps no accounting.
+    pub fn space_by_user(&self, reader: &mut ZfsReader) -> Result<BTreeMap<u64, Usage>, String> {
+        Ok(try!(self.objset.user_accounting(reader)).users)
+    }
+
+    /// Like `space_by_user`, for group IDs (`zfs groupspace`)
+    pub fn space_by_group(&self, reader: &mut ZfsReader) -> Result<BTreeMap<u64, Usage>, String> {
+        Ok(try!(self.objset.user_accounting(reader)).groups)
+    }
+
+    /// The snapshots of the dataset, oldest first
+    pub fn snapshots(&self,
+                     reader: &mut ZfsReader,
+                     mos: &ObjectSet)
+                     -> Result<Vec<Snapshot>, String> {
+        if self.phys.snapnames_zapobj == 0 {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for (name, object) in try!(zap::iter(reader, mos, self.phys.snapnames_zapobj)) {
+            let phys = try!(Self::read_phys(reader, mos, object));
+            // A snapshot's children are its clones, and the next snapshot of its head
+            let held = phys.num_children > 1 ||
+                       (phys.userrefs_obj != 0 &&
+                        try!(zap::iter(reader, mos, phys.userrefs_obj)).len() != 0);
+            snapshots.push(Snapshot {
+                name: name,
+                object: object,
+                guid: phys.guid,
+                creation_time: phys.creation_time,
+                creation_txg: phys.creation_txg,
+                objset_txg: phys.bp.birth_txg,
+                held: held,
+            });
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.creation_txg);
+        Ok(snapshots)
+    }
+
+    /// Open the snapshot of the dataset called `name` (the part after the `@`), to read the
+    /// dataset as it was when the snapshot was taken. Snapshots never change, so it's pinned.
+    pub fn open_snapshot(&self,
+                         reader: &mut ZfsReader,
+                         mos: &ObjectSet,
+                         name: &str)
+                         -> Result<Dataset, String> {
+        if self.phys.snapnames_zapobj == 0 {
+            return Err(format!("No snapshot named {}", name));
+        }
+        let object = try!(zap::lookup(reader, mos, self.phys.snapnames_zapobj, name));
+        Dataset::open(reader, mos, object, true)
+    }
+
+    /// Space written to the dataset since its latest snapshot, or its origin for a clone
+    pub fn written(&self, reader: &mut ZfsReader, mos: &ObjectSet) -> Result<u64, String> {
+        if self.phys.prev_snap_obj == 0 {
+            return Ok(self.phys.referenced_bytes);
+        }
+        written_between(reader, mos, self.phys.prev_snap_obj, self.object)
+    }
+
+    /// How the space of each of the dataset's snapshots, oldest first, is shared with the
+    /// snapshots around it and its clones. Worked out from the deadlists and the birth txgs of
+    /// the blocks on them, not the accounting kept in the datasets.
+    pub fn snapshot_space(&self,
+                          reader: &mut ZfsReader,
+                          mos: &ObjectSet)
+                          -> Result<Vec<(Snapshot, SnapshotSpace)>, String> {
+        let mut space = Vec::new();
+        for snapshot in try!(self.snapshots(reader, mos)) {
+            let phys = try!(Self::read_phys(reader, mos, snapshot.object));
+            // Blocks born while the snapshot was the head, which died before the next snapshot
+            // was taken, are referenced by this snapshot alone
+            let next = try!(Self::read_phys(reader, mos, phys.next_snap_obj));
+            let dead = try!(deadlist_blocks(reader, mos, next.deadlist_obj));
+            let unique = bpobj::space_born(&dead, phys.prev_snap_txg, u64::max_value());
+            let written = if phys.prev_snap_obj == 0 {
+                phys.referenced_bytes
+            } else {
+                try!(written_between(reader, mos, phys.prev_snap_obj, snapshot.object))
+            };
+            let clones = if phys.next_clones_obj == 0 {
+                Vec::new()
+            } else {
+                try!(zap::iter(reader, mos, phys.next_clones_obj)).map(|(_, obj)| obj).collect()
+            };
+            let snapshot_space = SnapshotSpace {
+                unique: unique,
+                written: written,
+                clones: clones,
+            };
+            space.push((snapshot, snapshot_space));
+        }
+        Ok(space)
+    }
+
+    /// Whether anything was written to the dataset since `snapshot` of it was taken. A snapshot
+    /// shares the objset of its head until the head is written to again.
+    pub fn modified_since(&self, snapshot: &Snapshot) -> bool {
+        self.phys.bp.birth_txg != snapshot.objset_txg
+    }
+}
 
 //------------------------------------------------------------------------------------------------//
 
@@ -90,3 +550,55 @@ impl FromBytes for DslDatasetPhys {}
 // Protected by ds_lock, keep at end of struct for better locality
 // char ds_snapname[MAXNAMELEN],
 // }
+
+#[test]
+fn test_block_kill() {
+    let mut phys = DslDatasetPhys::from_bytes(&[0; 320]).unwrap();
+    phys.prev_snap_txg = 100;
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    block_ptr.birth_txg = 101;
+    assert_eq!(phys.block_kill(&block_ptr), BlockKill::Free);
+    block_ptr.birth_txg = 100;
+    assert_eq!(phys.block_kill(&block_ptr), BlockKill::Deadlist);
+}
+
+#[test]
+fn test_refresh() {
+    use std::mem;
+    use super::dsl_pool::DslPool;
+    use super::spa;
+    use super::test_util;
+    use super::zpl;
+
+    let (mut writer, mut alloc, device) = test_util::pool("zfs_test_refresh");
+    let path = device.path();
+
+    // Another import of the pool, reading along
+    let mut spa = spa::import(path).unwrap();
+    let uberblock = *spa.uberblock().unwrap();
+    let reader = spa.reader_mut().unwrap();
+    let mut mos = ObjectSet::open(reader, &uberblock.rootbp).unwrap();
+    let dsl_pool = DslPool::open(reader, &mos).unwrap();
+    let mut dataset = dsl_pool.open_dataset(reader, &mos, "tank", false).unwrap();
+    assert!(zpl::lookup(reader, &dataset, "/notes", true).is_err());
+    assert!(!dataset.refresh(reader, &mut mos).unwrap());
+
+    let written = {
+        let (reader, mos) = writer.reader_and_mos().unwrap();
+        let dataset = DslPool::open(reader, mos).unwrap().open_dataset(reader, mos, "tank", false);
+        dataset.unwrap()
+    };
+    let mut dsl_pool = mem::replace(writer.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = writer.reader_and_mos().unwrap();
+        let file = zpl::create(reader, &mut dsl_pool, mos, &written, "/notes", 0o644).unwrap();
+        zpl::write(reader, &mut dsl_pool, &written, file, 0, b"hello").unwrap();
+    }
+    *writer.dsl_pool_mut() = dsl_pool;
+    writer.sync(&mut alloc).unwrap().unwrap();
+
+    // The txg the other import synced shows up, through the new MOS
+    assert!(dataset.refresh(reader, &mut mos).unwrap());
+    let read = zpl::open(reader, &dataset, "/notes").unwrap().read_all(reader);
+    assert_eq!(read.unwrap(), b"hello");
+}
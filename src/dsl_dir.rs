@@ -1,4 +1,11 @@
-use super::from_bytes::FromBytes;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{mem, slice};
+
+use super::ZfsReader;
+use super::dmu_objset::ObjectSet;
+use super::from_bytes::{Decoder, FromBytes};
+use super::zap;
 
 const DD_USED_NUM: usize = 5; // The number of variants in DslDirUsed
 
@@ -34,4 +41,81 @@ pub struct DslDirPhys {
     pub pad: [u64; 13], // pad out to 256 bytes for good measure
 }
 
-impl FromBytes for DslDirPhys {}
+impl FromBytes for DslDirPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut head = [0; 13];
+        decoder.fill_u64(&mut head);
+        let mut used_breakdown = [0; DD_USED_NUM];
+        decoder.fill_u64(&mut used_breakdown);
+        let clones = decoder.u64();
+        let mut pad = [0; 13];
+        decoder.fill_u64(&mut pad);
+        DslDirPhys {
+            creation_time: head[0],
+            head_dataset_obj: head[1],
+            parent_obj: head[2],
+            origin_obj: head[3],
+            child_dir_zapobj: head[4],
+            used_bytes: head[5],
+            compressed_bytes: head[6],
+            uncompressed_bytes: head[7],
+            quota: head[8],
+            reserved: head[9],
+            props_zapobj: head[10],
+            deleg_zapobj: head[11],
+            flags: head[12],
+            used_breakdown: used_breakdown,
+            clones: clones,
+            pad: pad,
+        }
+    }
+}
+
+impl DslDirPhys {
+    /// The directory as it's written to its dnode's bonus buffer, in host byte order
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const DslDirPhys as *const u8,
+                                  mem::size_of::<DslDirPhys>())
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------//
+
+/// A DSL directory: a node of the dataset namespace. It holds the head dataset (the filesystem or
+/// volume itself) along with the properties and space accounting of it and its children.
+pub struct DslDir {
+    pub object: u64, // MOS object number of the dsl_dir
+    pub phys: DslDirPhys,
+}
+
+impl DslDir {
+    pub fn open(reader: &mut ZfsReader, mos: &ObjectSet, object: u64) -> Result<Self, String> {
+        let dnode = try!(mos.dnode(reader, object));
+        let phys = try!(DslDirPhys::from_bytes(dnode.get_bonus()));
+        Ok(DslDir {
+            object: object,
+            phys: phys,
+        })
+    }
+
+    /// MOS object number of the head dsl_dataset, 0 if there's none
+    pub fn head_dataset_obj(&self) -> u64 {
+        self.phys.head_dataset_obj
+    }
+
+    /// Names and dsl_dir object numbers of the child directories. The pool's internal directories
+    /// ($MOS, $FREE, $ORIGIN) are left out.
+    pub fn children(&self,
+                    reader: &mut ZfsReader,
+                    mos: &ObjectSet)
+                    -> Result<Vec<(String, u64)>, String> {
+        let child_dir_zapobj = self.phys.child_dir_zapobj;
+        if child_dir_zapobj == 0 {
+            return Ok(Vec::new());
+        }
+        let children = try!(zap::iter(reader, mos, child_dir_zapobj));
+        Ok(children.filter(|&(ref name, _)| !name.starts_with('$')).collect())
+    }
+}
@@ -1,10 +1,64 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::bpobj;
+use super::ddt::Ddt;
+use super::dmu;
+use super::dmu_objset::{DirtyObjset, ObjectSet, ObjectSetType};
+use super::dmu_tx::Tx;
+use super::dnode::{DNODE_SIZE, DNodePhys, ObjectType};
+use super::dvaddr::DVAddr;
+use super::dsl_dataset::{self, BlockKill, Dataset, DslDatasetPhys};
+use super::dsl_dir::{DslDir, DslDirPhys, DslDirUsed};
+use super::dsl_prop::{self, PropChange};
+use super::from_bytes::FromBytes;
+use super::metaslab::Allocator;
 use super::spa;
+use super::traverse::{self, ObjectFilter};
+use super::txg::{TXG_MASK, TXG_SIZE, TxState};
+use super::uberblock::Uberblock;
+use super::zap;
 use super::zfs;
+use super::zil::Zilog;
+use super::zio_compress::ZIO_COMPRESS_OFF;
+use super::zpl;
+
+/// Most dirty data kept in memory by default, like `zfs_dirty_data_max`
+pub const ZFS_DIRTY_DATA_MAX: u64 = 4 << 30;
+
+/// A txg is synced without waiting any longer once this share of `dirty_data_max` is dirty, like
+/// `zfs_dirty_data_sync_percent`
+pub const ZFS_DIRTY_DATA_SYNC_PERCENT: u64 = 20;
+
+/// Objset number of the MOS in the dirty sets of a txg, the datasets go by their MOS object
+pub const MOS_OBJSET: u64 = 0;
 
 pub struct DslPool {
     // Immutable
     root_dir_obj: u64,
-    pub dp_dirty_total: u32,
+    /// Bytes of dirty data of all the txgs in flight
+    pub dp_dirty_total: u64,
+    /// Bytes of dirty data of each txg in flight, by `TXG_MASK` slot
+    dirty_pertxg: [u64; TXG_SIZE],
+    /// Most bytes of dirty data there can be, see `is_over_dirty_max`
+    pub dirty_data_max: u64,
+    /// What each txg in flight changes of each objset (see `MOS_OBJSET`), by `TXG_MASK` slot
+    dirty: Vec<BTreeMap<u64, DirtyObjset>>,
+    pub tx: TxState,
+    /// The dedup table the blocks of the objsets in `dedup` are written through
+    ddt: Option<Ddt>,
+    dedup: BTreeSet<u64>,
+    /// What the blocks of each objset are compressed with, for those that are
+    compression: BTreeMap<u64, u64>,
+    /// The intent logs of the objsets changed since the pool was opened
+    pub zil: BTreeMap<u64, Zilog>,
+    /// How many times each object is held open, by objset and object (see `zpl::hold_file`)
+    open_objects: BTreeMap<(u64, u64), u64>,
 }
 
 impl DslPool {
@@ -13,16 +67,1087 @@ impl DslPool {
     }
 
     fn open_impl(spa: &mut spa::Spa, txg: u64) -> zfs::Result<Self> {
-        Ok(DslPool {
-            root_dir_obj: 0,
-            dp_dirty_total: 0,
-        })
+        let (reader, mos) = try!(spa.reader_and_mos().ok_or(zfs::Error::Invalid));
+        let mut dsl_pool = try!(Self::open(reader, mos));
+        dsl_pool.tx = TxState::new(txg);
+        Ok(dsl_pool)
+    }
+
+    /// Open the DSL pool whose MOS is `mos`. Its txgs count from 0, `init` starts them from the
+    /// pool's.
+    pub fn open(reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<Self> {
+        let root_dir_obj = try!(mos.directory_lookup(reader, "root_dataset")
+                                   .map_err(|_| zfs::Error::NoEntity));
+        let mut dsl_pool = DslPool::new();
+        dsl_pool.root_dir_obj = root_dir_obj;
+        Ok(dsl_pool)
+    }
+
+    /// MOS object number of the root DSL directory
+    pub fn root_dir_obj(&self) -> u64 {
+        self.root_dir_obj
+    }
+
+    pub fn root_dir(&self, reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<DslDir> {
+        open_dir(reader, mos, self.root_dir_obj)
+    }
+
+    /// Every dataset of the pool, parents before their children, as (full name, dsl_dir) pairs.
+    /// The root dataset is named after the pool.
+    pub fn datasets(&self,
+                    reader: &mut ZfsReader,
+                    mos: &ObjectSet,
+                    pool_name: &str)
+                    -> zfs::Result<Vec<(String, DslDir)>> {
+        let mut datasets = Vec::new();
+        let mut stack = vec![(pool_name.to_owned(), self.root_dir_obj)];
+        while let Some((name, object)) = stack.pop() {
+            let dir = try!(open_dir(reader, mos, object));
+            let mut children = try!(dir.children(reader, mos).map_err(|_| zfs::Error::Invalid));
+            // Popped in reverse, so the listing comes out sorted
+            children.sort();
+            for (child, child_object) in children.into_iter().rev() {
+                stack.push((format!("{}/{}", name, child), child_object));
+            }
+            datasets.push((name, dir));
+        }
+        Ok(datasets)
+    }
+
+    /// Find the directory of the dataset called `name`, e.g. `tank/home/alice`. The first
+    /// component is the pool's name and isn't checked.
+    pub fn lookup_dir(&self,
+                      reader: &mut ZfsReader,
+                      mos: &ObjectSet,
+                      name: &str)
+                      -> zfs::Result<DslDir> {
+        let mut dir = try!(self.root_dir(reader, mos));
+        for component in name.split('/').skip(1).filter(|c| !c.is_empty()) {
+            let child_dir_zapobj = dir.phys.child_dir_zapobj;
+            let object = try!(zap::lookup(reader, mos, child_dir_zapobj, component)
+                                  .map_err(|_| zfs::Error::NoEntity));
+            dir = try!(open_dir(reader, mos, object));
+        }
+        Ok(dir)
+    }
+
+    /// Open the head dataset (and its objset) of the dataset called `name`, or the snapshot
+    /// of it if `name` is `dataset@snapshot`
+    pub fn open_dataset(&self,
+                        reader: &mut ZfsReader,
+                        mos: &ObjectSet,
+                        name: &str,
+                        pin: bool)
+                        -> zfs::Result<Dataset> {
+        let mut parts = name.splitn(2, '@');
+        let dir = try!(self.lookup_dir(reader, mos, parts.next().unwrap_or("")));
+        if dir.head_dataset_obj() == 0 {
+            // No head dataset
+            return Err(zfs::Error::NoEntity);
+        }
+        let head = try!(Dataset::open(reader, mos, dir.head_dataset_obj(), pin)
+                            .map_err(|_| zfs::Error::Invalid));
+        match parts.next() {
+            Some(snapshot) => {
+                head.open_snapshot(reader, mos, snapshot).map_err(|_| zfs::Error::NoEntity)
+            }
+            None => Ok(head),
+        }
+    }
+
+    /// Create the filesystem `name`, e.g. `tank/home/alice`, with nothing in it (`zfs create`).
+    /// Its parent has to exist; the first component is the pool's name and isn't checked. The
+    /// DSL directory and dataset are created in a transaction of their own, which goes to the
+    /// disk with the open txg. Returns the MOS object of the new dataset.
+    pub fn create_dataset(&mut self,
+                          reader: &mut ZfsReader,
+                          mos: &ObjectSet,
+                          name: &str)
+                          -> zfs::Result<u64> {
+        let (parent_name, component) = match name.rfind('/') {
+            Some(slash) => (&name[..slash], &name[slash + 1..]),
+            // The root dataset comes with the pool
+            None => return Err(zfs::Error::Exists),
+        };
+        if !is_valid_dataset_name(name) {
+            return Err(zfs::Error::Invalid);
+        }
+        let parent = try!(self.pending_lookup_dir(reader, mos, parent_name));
+        let child_dir_zapobj = parent.phys.child_dir_zapobj;
+        let children = try!(zap::pending_entries(reader, self, mos, MOS_OBJSET, child_dir_zapobj));
+        if children.iter().any(|&(ref child, _)| child == component) {
+            return Err(zfs::Error::Exists);
+        }
+
+        let mut tx = Tx::create(MOS_OBJSET);
+        tx.hold_zap(child_dir_zapobj, true, Some(component));
+        let txg = try!(tx.assign(self));
+        let result = self.create_dataset_tx(reader, mos, &parent, component, &tx, txg);
+        tx.commit(self);
+        result
+    }
+
+    fn create_dataset_tx(&mut self,
+                         reader: &mut ZfsReader,
+                         mos: &ObjectSet,
+                         parent: &DslDir,
+                         component: &str,
+                         tx: &Tx,
+                         txg: u64)
+                         -> zfs::Result<u64> {
+        // The objects are taken first, `create_dir` fills them in
+        let taken = DNodePhys::new(ObjectType::DataSet, 512, ObjectType::None, &[]);
+        let dir = try!(dmu::create_object(reader, self, mos, taken, tx));
+        let child_dir_zapobj = parent.phys.child_dir_zapobj;
+        if let Err(e) = zap::add(reader, self, mos, child_dir_zapobj, component, dir, tx) {
+            self.free_object(txg, MOS_OBJSET, dir);
+            return Err(e);
+        }
+        let mut objects = [dir, 0, 0, 0, 0];
+        for object in &mut objects[1..] {
+            *object = try!(dmu::create_object(reader, self, mos, taken, tx));
+        }
+        try!(self.create_dir(txg, parent.object, objects));
+        Ok(objects[3])
+    }
+
+    /// Dirty a new DSL directory under directory `parent` (0 for the root), with its head
+    /// dataset, an empty filesystem, in txg `txg`. `objects` are the MOS objects to make them of:
+    /// the directory, its child directory and property ZAPs, the dataset and its snapshot name
+    /// ZAP.
+    pub fn create_dir(&mut self, txg: u64, parent: u64, objects: [u64; 5]) -> zfs::Result<()> {
+        let [dir_obj, child_dir_zapobj, props_zapobj, dataset_obj, snapnames_zapobj] = objects;
+        let now = SystemTime::now()
+                      .duration_since(UNIX_EPOCH)
+                      .map(|time| time.as_secs())
+                      .unwrap_or(0);
+        for &(object, object_type) in &[(child_dir_zapobj, ObjectType::DataSetChildMap),
+                                        (props_zapobj, ObjectType::DslProps),
+                                        (snapnames_zapobj, ObjectType::ObjSetSnapMap)] {
+            try!(zap::create(self, txg, MOS_OBJSET, object, object_type as u8, &[]));
+        }
+
+        let mut dir = try!(DslDirPhys::from_bytes(&[0; 256]));
+        dir.creation_time = now;
+        dir.head_dataset_obj = dataset_obj;
+        dir.parent_obj = parent;
+        dir.child_dir_zapobj = child_dir_zapobj;
+        dir.props_zapobj = props_zapobj;
+        let dnode = DNodePhys::new(ObjectType::DataSet, 512, ObjectType::DataSet, dir.as_bytes());
+        self.dirty_dnode(txg, MOS_OBJSET, dir_obj, dnode);
+
+        // Its objset is the filesystem, pointed at once it's synced
+        let mut dataset = try!(DslDatasetPhys::from_bytes(&[0; 320]));
+        dataset.dir_obj = dir_obj;
+        dataset.snapnames_zapobj = snapnames_zapobj;
+        dataset.creation_time = now;
+        dataset.creation_txg = txg;
+        dataset.fsid_guid = spa::generate_guid() & ((1 << 56) - 1);
+        dataset.guid = spa::generate_guid();
+        let dnode = DNodePhys::new(ObjectType::DslObjSet,
+                                   512,
+                                   ObjectType::DslObjSet,
+                                   dataset.as_bytes());
+        self.dirty_dnode(txg, MOS_OBJSET, dataset_obj, dnode);
+        zpl::create_fs(self, txg, dataset_obj);
+        Ok(())
+    }
+
+    /// Destroy the filesystem `name` and its DSL directory (`zfs destroy`), in a transaction of
+    /// its own in the open txg. It can't have children (`Exists`) or snapshots (`Busy`), and the
+    /// root dataset can't be destroyed at all.
+    pub fn destroy_dataset(&mut self,
+                           reader: &mut ZfsReader,
+                           mos: &ObjectSet,
+                           name: &str)
+                           -> zfs::Result<()> {
+        let slash = try!(name.rfind('/').ok_or(zfs::Error::Invalid));
+        let parent = try!(self.pending_lookup_dir(reader, mos, &name[..slash]));
+        let dir = try!(self.pending_lookup_dir(reader, mos, name));
+        let children = try!(zap::pending_entries(reader,
+                                                 self,
+                                                 mos,
+                                                 MOS_OBJSET,
+                                                 dir.phys.child_dir_zapobj));
+        if !children.is_empty() {
+            return Err(zfs::Error::Exists);
+        }
+        let dataset_obj = dir.head_dataset_obj();
+        let dataset = match self.pending_dnode(MOS_OBJSET, dataset_obj) {
+            Some(dnode) => dnode,
+            None => try!(mos.dnode(reader, dataset_obj).map_err(|_| zfs::Error::Invalid)),
+        };
+        let dataset = try!(DslDatasetPhys::from_bytes(dataset.get_bonus()));
+        if dataset.snapnames_zapobj != 0 &&
+           !try!(zap::pending_entries(reader, self, mos, MOS_OBJSET, dataset.snapnames_zapobj))
+                .is_empty() {
+            return Err(zfs::Error::Busy);
+        }
+        // Without snapshots, the blocks on the deadlist are the origin's, which keeps them
+        let deadlist = try!(dsl_dataset::deadlist_objects(reader, mos, dataset.deadlist_obj)
+                                .map_err(|_| zfs::Error::Invalid));
+
+        let (child_dir_zapobj, component) = (parent.phys.child_dir_zapobj, &name[slash + 1..]);
+        let mut tx = Tx::create(MOS_OBJSET);
+        tx.hold_zap(child_dir_zapobj, false, Some(component));
+        let txg = try!(tx.assign(self));
+        let result = zap::remove(reader, self, mos, child_dir_zapobj, component, &tx);
+        if result.is_ok() {
+            // What the txg was going to change of the objset goes with it, and what's on the
+            // disk of it is freed when the txg syncs
+            self.undirty_objset(txg, dataset_obj);
+            self.dirty_objset(txg, dataset_obj).destroyed = true;
+            self.zil.remove(&dataset_obj);
+            let objects = [dir.object,
+                           dir.phys.child_dir_zapobj,
+                           dir.phys.props_zapobj,
+                           dir.phys.deleg_zapobj,
+                           dataset_obj,
+                           dataset.snapnames_zapobj];
+            for &object in objects.iter().chain(&deadlist).filter(|&&object| object != 0) {
+                self.free_object(txg, MOS_OBJSET, object);
+            }
+        }
+        tx.commit(self);
+        result.map(|_| ())
+    }
+
+    /// Make `change` to property `prop` of dataset `name` (`zfs set`, `zfs inherit`), in a
+    /// transaction of its own in the open txg. Native properties go in the props ZAP of the
+    /// dataset's DSL directory, along with what was received for them. The quota and
+    /// reservation live in the directory itself; there, received values count as local ones.
+    pub fn set_property(&mut self,
+                        reader: &mut ZfsReader,
+                        mos: &ObjectSet,
+                        name: &str,
+                        prop: &str,
+                        change: &PropChange)
+                        -> zfs::Result<()> {
+        let value = match *change {
+            PropChange::Set(ref value) |
+            PropChange::Receive(ref value) => try!(dsl_prop::encode(prop, value)),
+            PropChange::Inherit |
+            PropChange::RevertToReceived => {
+                try!(dsl_prop::settable(prop));
+                0
+            }
+        };
+        let dir = try!(self.pending_lookup_dir(reader, mos, name));
+        let props_zapobj = dir.phys.props_zapobj;
+        if props_zapobj == 0 {
+            return Err(zfs::Error::Invalid);
+        }
+
+        let mut tx = Tx::create(MOS_OBJSET);
+        tx.hold_zap(props_zapobj, true, Some(prop));
+        let txg = try!(tx.assign(self));
+        let result = if prop == "quota" || prop == "reservation" {
+            self.set_dir_limit(reader, mos, dir, prop, value, txg)
+        } else {
+            dsl_prop::set_sync(reader, self, mos, props_zapobj, prop, change, &tx)
+        };
+        tx.commit(self);
+        result
+    }
+
+    /// Set the quota or the reservation of DSL directory `dir` in its phys
+    fn set_dir_limit(&mut self,
+                     reader: &mut ZfsReader,
+                     mos: &ObjectSet,
+                     mut dir: DslDir,
+                     prop: &str,
+                     value: u64,
+                     txg: u64)
+                     -> zfs::Result<()> {
+        if prop == "quota" {
+            dir.phys.quota = value;
+        } else {
+            dir.phys.reserved = value;
+        }
+        let mut dnode = match self.pending_dnode(MOS_OBJSET, dir.object) {
+            Some(dnode) => dnode,
+            None => try!(mos.dnode(reader, dir.object).map_err(|_| zfs::Error::Invalid)),
+        };
+        let bytes = dir.phys.as_bytes();
+        dnode.bonus_mut()[..bytes.len()].copy_from_slice(bytes);
+        self.dirty_dnode(txg, MOS_OBJSET, dir.object, dnode);
+        Ok(())
+    }
+
+    /// Like `lookup_dir`, with what the txgs in flight change of the directories
+    fn pending_lookup_dir(&self,
+                          reader: &mut ZfsReader,
+                          mos: &ObjectSet,
+                          name: &str)
+                          -> zfs::Result<DslDir> {
+        let mut dir = try!(self.pending_dir(reader, mos, self.root_dir_obj));
+        for component in name.split('/').skip(1).filter(|c| !c.is_empty()) {
+            let children = try!(zap::pending_entries(reader,
+                                                     self,
+                                                     mos,
+                                                     MOS_OBJSET,
+                                                     dir.phys.child_dir_zapobj));
+            let object = try!(children.into_iter()
+                                      .find(|&(ref child, _)| child == component)
+                                      .map(|(_, object)| object)
+                                      .ok_or(zfs::Error::NoEntity));
+            dir = try!(self.pending_dir(reader, mos, object));
+        }
+        Ok(dir)
+    }
+
+    fn pending_dir(&self,
+                   reader: &mut ZfsReader,
+                   mos: &ObjectSet,
+                   object: u64)
+                   -> zfs::Result<DslDir> {
+        match self.pending_dnode(MOS_OBJSET, object) {
+            Some(dnode) => {
+                Ok(DslDir {
+                    object: object,
+                    phys: try!(DslDirPhys::from_bytes(dnode.get_bonus())),
+                })
+            }
+            None => open_dir(reader, mos, object),
+        }
     }
 
     pub fn new() -> DslPool {
         DslPool {
             root_dir_obj: 0,
             dp_dirty_total: 0,
+            dirty_pertxg: [0; TXG_SIZE],
+            dirty_data_max: ZFS_DIRTY_DATA_MAX,
+            dirty: (0..TXG_SIZE).map(|_| BTreeMap::new()).collect(),
+            tx: TxState::new(0),
+            ddt: None,
+            dedup: BTreeSet::new(),
+            compression: BTreeMap::new(),
+            zil: BTreeMap::new(),
+            open_objects: BTreeMap::new(),
+        }
+    }
+
+    /// Deduplicate the blocks written to objset `objset` from the next sync on, or stop doing so,
+    /// like the `dedup` property. They're deduplicated through the dedup table `set_ddt` gives
+    /// the pool, and written with its checksum.
+    pub fn set_dedup(&mut self, objset: u64, dedup: bool) {
+        assert!(objset != MOS_OBJSET, "the MOS is never deduplicated");
+        if dedup {
+            self.dedup.insert(objset);
+        } else {
+            self.dedup.remove(&objset);
+        }
+    }
+
+    /// Compress the blocks of data written to objset `objset` from the next sync on with
+    /// `compression`, a value of the `compression` property, where it saves at least 12.5% of
+    /// a block (see `zio_compress::compress`). Blocks that don't are stored uncompressed.
+    pub fn set_compression(&mut self, objset: u64, compression: u64) {
+        assert!(objset != MOS_OBJSET, "the MOS is written uncompressed");
+        if compression == ZIO_COMPRESS_OFF {
+            self.compression.remove(&objset);
+        } else {
+            self.compression.insert(objset, compression);
+        }
+    }
+
+    /// Use `ddt`, the pool's table for its checksum (see `Ddt::load`), to deduplicate blocks.
+    /// What the txgs change of it is written to its tables in the MOS as they sync.
+    pub fn set_ddt(&mut self, ddt: Ddt) {
+        self.ddt = Some(ddt);
+    }
+
+    pub fn ddt(&self) -> Option<&Ddt> {
+        self.ddt.as_ref()
+    }
+
+    /// The intent log of objset `objset`, which changes to it are logged to
+    pub fn zil(&mut self, objset: u64) -> &mut Zilog {
+        assert!(objset != MOS_OBJSET, "the MOS has no intent log");
+        self.zil.entry(objset).or_insert_with(|| Zilog::new(objset))
+    }
+
+    /// Hold object `object` of objset `objset` open once more
+    pub fn hold_object(&mut self, objset: u64, object: u64) {
+        *self.open_objects.entry((objset, object)).or_insert(0) += 1;
+    }
+
+    /// Let go of a hold `hold_object` took. Returns whether it was the last one.
+    pub fn release_object(&mut self, objset: u64, object: u64) -> bool {
+        let last = match self.open_objects.get_mut(&(objset, object)) {
+            Some(holds) => {
+                *holds -= 1;
+                *holds == 0
+            }
+            None => return false,
+        };
+        if last {
+            self.open_objects.remove(&(objset, object));
+        }
+        last
+    }
+
+    /// Whether object `object` of objset `objset` is held open
+    pub fn is_held(&self, objset: u64, object: u64) -> bool {
+        self.open_objects.contains_key(&(objset, object))
+    }
+
+    fn dirty_objset(&mut self, txg: u64, objset: u64) -> &mut DirtyObjset {
+        self.dirty[(txg & TXG_MASK) as usize].entry(objset).or_insert_with(DirtyObjset::default)
+    }
+
+    fn dirty_space(&mut self, txg: u64, added: u64, removed: u64) {
+        let dirty = &mut self.dirty_pertxg[(txg & TXG_MASK) as usize];
+        *dirty = (*dirty + added).saturating_sub(removed);
+        self.dp_dirty_total = (self.dp_dirty_total + added).saturating_sub(removed);
+    }
+
+    /// Change object `object` of objset `objset` to `dnode` in txg `txg`, e.g. to create it or
+    /// to update its bonus buffer
+    pub fn dirty_dnode(&mut self, txg: u64, objset: u64, object: u64, dnode: DNodePhys) {
+        let old = self.dirty_objset(txg, objset).dnodes.insert(object, dnode);
+        if old.is_none() {
+            self.dirty_space(txg, DNODE_SIZE, 0);
         }
     }
+
+    /// Create objset `objset` in txg `txg` as a new one of type `os_type`, without any objects:
+    /// the dataset it belongs to points at it once the txg is synced
+    pub fn create_objset(&mut self, txg: u64, objset: u64, os_type: ObjectSetType) {
+        assert!(objset != MOS_OBJSET, "the MOS is never replaced");
+        self.dirty_objset(txg, objset).created = Some(os_type);
+    }
+
+    /// Write `data` as level 0 block `blkid` of object `object` of objset `objset` in txg `txg`
+    pub fn dirty_block(&mut self, txg: u64, objset: u64, object: u64, blkid: u64, data: Vec<u8>) {
+        let added = data.len() as u64;
+        let old = self.dirty_objset(txg, objset)
+                      .blocks
+                      .entry(object)
+                      .or_insert_with(BTreeMap::new)
+                      .insert(blkid, data);
+        self.dirty_space(txg, added, old.map_or(0, |old| old.len() as u64));
+    }
+
+    /// Punch a hole at level 0 block `blkid` of object `object` of objset `objset` in txg `txg`:
+    /// the block is freed when the txg syncs, along with the indirect blocks it leaves with
+    /// nothing but holes under them. Until then it's pending as an empty block, which reads as
+    /// zeros.
+    pub fn dirty_hole(&mut self, txg: u64, objset: u64, object: u64, blkid: u64) {
+        self.dirty_block(txg, objset, object, blkid, Vec::new());
+    }
+
+    /// Free object `object` of objset `objset` in txg `txg`: its dnode is zeroed, what the txg
+    /// was going to write to it is dropped, and the blocks it has on the disk are killed when the
+    /// txg syncs
+    pub fn free_object(&mut self, txg: u64, objset: u64, object: u64) {
+        let dirty = self.dirty_objset(txg, objset);
+        dirty.freed.insert(object);
+        let removed = dirty.blocks
+                           .remove(&object)
+                           .map_or(0, |blocks| blocks.values().map(|b| b.len() as u64).sum());
+        self.dirty_space(txg, 0, removed);
+        let zeroed = DNodePhys::from_bytes(&[0; DNODE_SIZE as usize]).unwrap();
+        self.dirty_dnode(txg, objset, object, zeroed);
+    }
+
+    /// Drop whatever txg `txg` was going to change of objset `objset`
+    fn undirty_objset(&mut self, txg: u64, objset: u64) {
+        let removed = self.dirty[(txg & TXG_MASK) as usize]
+                          .remove(&objset)
+                          .map_or(0, |dirty| dirty_size(&dirty));
+        self.dirty_space(txg, 0, removed);
+    }
+
+    /// The newest change to object `object` of objset `objset` that isn't on the disk yet, in
+    /// any of the txgs in flight
+    pub fn pending_dnode(&self, objset: u64, object: u64) -> Option<DNodePhys> {
+        self.in_flight()
+            .filter_map(|dirty| dirty.get(&objset).and_then(|dirty| dirty.dnodes.get(&object)))
+            .next()
+            .cloned()
+    }
+
+    /// Like `pending_dnode`, for level 0 block `blkid` of the object
+    pub fn pending_block(&self, objset: u64, object: u64, blkid: u64) -> Option<&[u8]> {
+        self.in_flight()
+            .filter_map(|dirty| {
+                dirty.get(&objset)
+                     .and_then(|dirty| dirty.blocks.get(&object))
+                     .and_then(|blocks| blocks.get(&blkid))
+            })
+            .next()
+            .map(|block| &block[..])
+    }
+
+    /// The highest level 0 block of object `object` of objset `objset` the txgs in flight write
+    pub fn pending_max_blkid(&self, objset: u64, object: u64) -> Option<u64> {
+        self.in_flight()
+            .filter_map(|dirty| {
+                dirty.get(&objset)
+                     .and_then(|dirty| dirty.blocks.get(&object))
+                     .and_then(|blocks| blocks.keys().next_back().cloned())
+            })
+            .max()
+    }
+
+    /// The changes of the txgs in flight, newest first
+    fn in_flight<'a>(&'a self) -> Box<Iterator<Item = &'a BTreeMap<u64, DirtyObjset>> + 'a> {
+        let txgs = self.tx.synced_txg() + 1..self.tx.open_txg() + 1;
+        Box::new(txgs.rev().map(move |txg| &self.dirty[(txg & TXG_MASK) as usize]))
+    }
+
+    /// Check that the dirty data of `dataset` in the txgs in flight, and `bytes` more, fit in
+    /// its quotas (`dsl_dir_tempreserve_space`). Like the dirty data limit, this is as if every
+    /// dirty block took up new space.
+    pub fn check_space(&self, dataset: &Dataset, bytes: u64) -> zfs::Result<()> {
+        let pending: u64 = self.in_flight()
+                               .filter_map(|dirty| dirty.get(&dataset.object))
+                               .map(dirty_size)
+                               .sum();
+        dataset.limits.check(pending + bytes)
+    }
+
+    /// Bytes of dirty data of txg `txg`
+    pub fn dirty_bytes(&self, txg: u64) -> u64 {
+        self.dirty_pertxg[(txg & TXG_MASK) as usize]
+    }
+
+    /// Whether there's enough dirty data to sync the open txg now, rather than wait for the
+    /// next sync to come around
+    pub fn need_sync(&self) -> bool {
+        self.dp_dirty_total >= self.dirty_data_max * ZFS_DIRTY_DATA_SYNC_PERCENT / 100
+    }
+
+    /// Whether adding `bytes` of dirty data would go over `dirty_data_max`, so changes have to
+    /// wait for a txg to sync first
+    pub fn is_over_dirty_max(&self, bytes: u64) -> bool {
+        self.dp_dirty_total + bytes > self.dirty_data_max
+    }
+
+    /// Sync the next txg, closing the open one if no other is quiescing: write out what it
+    /// changes of the datasets' objsets, then of the MOS, with the datasets pointing at their new
+    /// objsets. Blocks that aren't referenced any more are freed to `alloc`, and so are the log
+    /// blocks whose records the txg syncs. Returns the uberblock of the txg, pointing at the new
+    /// MOS, for the caller to write out. The txg stays syncing until it's on the disk
+    /// (`sync_done`). Returns None if there's no txg to sync: the one quiescing is still held, or
+    /// it doesn't change anything.
+    pub fn sync(&mut self,
+                reader: &mut ZfsReader,
+                mos: &mut ObjectSet,
+                uberblock: &Uberblock,
+                alloc: &mut Allocator)
+                -> zfs::Result<Option<Uberblock>> {
+        if self.tx.quiescing_txg().is_none() && self.tx.syncing_txg().is_none() {
+            self.tx.quiesce();
+        }
+        let txg = match self.tx.sync_start() {
+            Some(txg) => txg,
+            None => return Ok(None),
+        };
+        let slot = (txg & TXG_MASK) as usize;
+        let mut dirty = mem::replace(&mut self.dirty[slot], BTreeMap::new());
+        self.dp_dirty_total -= cmp::min(self.dirty_pertxg[slot], self.dp_dirty_total);
+        self.dirty_pertxg[slot] = 0;
+        // The intent logs move their headers past the blocks the txg makes obsolete
+        for (&objset, zilog) in &mut self.zil {
+            if let Some(header) = zilog.sync(txg, alloc) {
+                dirty.entry(objset).or_insert_with(DirtyObjset::default).zil_header = Some(header);
+            }
+        }
+        if dirty.values().all(|objset| objset.is_empty()) {
+            self.tx.sync_done();
+            return Ok(None);
+        }
+
+        let mut mos_dirty = dirty.remove(&MOS_OBJSET).unwrap_or_default();
+        let mut freed = Vec::new();
+        for (object, changes) in dirty {
+            if changes.destroyed {
+                try!(destroy_objset(reader, mos, &mut mos_dirty, object, &mut freed));
+                continue;
+            }
+            let mut dnode = match mos_dirty.dnodes.get(&object) {
+                Some(dnode) => *dnode,
+                None => try!(mos.dnode(reader, object).map_err(|_| zfs::Error::NoEntity)),
+            };
+            let mut phys = try!(DslDatasetPhys::from_bytes(dnode.get_bonus()));
+            let old_bp = phys.bp;
+            let mut objset = match changes.created {
+                Some(os_type) => ObjectSet::create(os_type),
+                None => try!(ObjectSet::open(reader, &old_bp).map_err(|_| zfs::Error::Invalid)),
+            };
+            let mut objset_freed = vec![old_bp];
+            let ddt = if self.dedup.contains(&object) { self.ddt.as_mut() } else { None };
+            let mut counter = AllocCounter {
+                alloc: alloc,
+                allocated: 0,
+            };
+            let compression = self.compression.get(&object).cloned().unwrap_or(ZIO_COMPRESS_OFF);
+            phys.bp = try!(objset.sync(reader,
+                                       &mut counter,
+                                       ddt,
+                                       compression,
+                                       txg,
+                                       changes,
+                                       &mut objset_freed));
+            // What the latest snapshot still references is only freed along with it
+            let (born, mut killed, mut deadlisted) = (counter.allocated, 0, 0);
+            for block_ptr in objset_freed {
+                let asize = if block_ptr.is_embedded() {
+                    0
+                } else {
+                    bpobj::block_asize(&block_ptr)
+                };
+                killed += asize;
+                if phys.block_kill(&block_ptr) == BlockKill::Free {
+                    freed.push(block_ptr);
+                } else {
+                    deadlisted += asize;
+                }
+            }
+            phys.referenced_bytes = (phys.referenced_bytes + born).saturating_sub(killed);
+            try!(charge_dirs(reader, mos, &mut mos_dirty, phys.dir_obj, born, killed, deadlisted));
+
+            let bytes = phys.as_bytes();
+            dnode.bonus_mut()[..bytes.len()].copy_from_slice(bytes);
+            mos_dirty.dnodes.insert(object, dnode);
+        }
+
+        // Deduplicated blocks are only freed with their last reference. The dedup table goes in
+        // the MOS with what that changes of it.
+        {
+            let ddt = &mut self.ddt;
+            freed.retain(|block_ptr| {
+                !block_ptr.dedup() || ddt.as_mut().map_or(false, |ddt| ddt.release(block_ptr))
+            });
+        }
+        let mos_dirty = try!(self.sync_ddt(reader, mos, txg, mos_dirty));
+
+        freed.push(uberblock.rootbp);
+        let rootbp = try!(mos.sync(reader,
+                                   alloc,
+                                   None,
+                                   ZIO_COMPRESS_OFF,
+                                   txg,
+                                   mos_dirty,
+                                   &mut freed));
+        for block_ptr in freed.iter().filter(|block_ptr| !block_ptr.is_hole()) {
+            free_block(alloc, block_ptr, txg);
+        }
+
+        let mut synced = *uberblock;
+        synced.txg = txg;
+        synced.rootbp = rootbp;
+        synced.timestamp = SystemTime::now()
+                               .duration_since(UNIX_EPOCH)
+                               .map(|time| time.as_secs())
+                               .unwrap_or(uberblock.timestamp);
+        Ok(Some(synced))
+    }
+
+    /// Write what syncing txg `txg` changes of the dedup table to its tables in the MOS, on top
+    /// of `mos_dirty`, what the txg changes of the MOS so far
+    fn sync_ddt(&mut self,
+                reader: &mut ZfsReader,
+                mos: &ObjectSet,
+                txg: u64,
+                mos_dirty: DirtyObjset)
+                -> zfs::Result<DirtyObjset> {
+        let mut ddt = match self.ddt.take() {
+            Some(ddt) => ddt,
+            None => return Ok(mos_dirty),
+        };
+        // The ZAPs are changed as they are in open context, as dirty data of the txg, which is
+        // taken back out without counting towards the dirty data limit
+        let slot = (txg & TXG_MASK) as usize;
+        let dirty_total = self.dp_dirty_total;
+        self.dirty[slot].insert(MOS_OBJSET, mos_dirty);
+        let result = ddt.sync(reader, self, mos, &Tx::create_assigned(MOS_OBJSET, txg));
+        self.ddt = Some(ddt);
+        let mos_dirty = self.dirty[slot].remove(&MOS_OBJSET).unwrap_or_default();
+        self.dp_dirty_total = dirty_total;
+        self.dirty_pertxg[slot] = 0;
+        try!(result);
+        Ok(mos_dirty)
+    }
+
+    /// The syncing txg's uberblock is on the disk
+    pub fn sync_done(&mut self) {
+        self.tx.sync_done();
+    }
+}
+
+/// Counts the bytes allocated through it, over all of the copies of the blocks
+struct AllocCounter<'a> {
+    alloc: &'a mut Allocator,
+    allocated: u64,
+}
+
+impl<'a> Allocator for AllocCounter<'a> {
+    fn alloc(&mut self, psize: u64) -> zfs::Result<DVAddr> {
+        let dva = try!(self.alloc.alloc(psize));
+        self.allocated += dva.asize() << 9;
+        Ok(dva)
+    }
+
+    fn free(&mut self, dva: &DVAddr, txg: u64) {
+        self.alloc.free(dva, txg)
+    }
+}
+
+/// Free what's on the disk of the objset of dataset `object`, destroyed in the txg being synced
+/// (`dsl_destroy_head_sync_impl`): the blocks born since its latest snapshot, its origin for a
+/// clone, are added to `freed`. The directories above its own stop being charged for its space.
+/// Its dataset and directory were freed as MOS objects, so they're read from the disk; there's
+/// nothing to free of one that never made it there.
+fn destroy_objset(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  mos_dirty: &mut DirtyObjset,
+                  object: u64,
+                  freed: &mut Vec<BlockPtr>)
+                  -> zfs::Result<()> {
+    let dnode = match mos.dnode(reader, object) {
+        Ok(ref dnode) if dnode.object_type == ObjectType::DslObjSet as u8 => *dnode,
+        _ => return Ok(()),
+    };
+    let phys = try!(DslDatasetPhys::from_bytes(dnode.get_bonus()));
+    if phys.bp.is_hole() {
+        return Ok(());
+    }
+    let objset = try!(ObjectSet::open(reader, &phys.bp).map_err(|_| zfs::Error::Invalid));
+    freed.push(phys.bp);
+    try!(traverse::traverse_objset_since(&mut reader.zio,
+                                         &objset.phys,
+                                         phys.prev_snap_txg,
+                                         ObjectFilter::All,
+                                         &mut |_, _, block_ptr| freed.push(*block_ptr)));
+    for accounting in objset.userused_dnode.iter().chain(&objset.groupused_dnode) {
+        try!(traverse::traverse_object(&mut reader.zio,
+                                       accounting,
+                                       0,
+                                       ObjectFilter::All,
+                                       &mut |_, _, block_ptr| {
+            if phys.block_kill(block_ptr) == BlockKill::Free {
+                freed.push(*block_ptr);
+            }
+        }));
+    }
+
+    let dir = try!(mos.dnode(reader, phys.dir_obj).map_err(|_| zfs::Error::Invalid));
+    let dir = try!(DslDirPhys::from_bytes(dir.get_bonus()));
+    let used = dir.used_bytes;
+    update_dirs(reader, mos, mos_dirty, dir.parent_obj, &mut |_, dir| {
+        let child = DslDirUsed::Child as usize;
+        dir.used_bytes = dir.used_bytes.saturating_sub(used);
+        dir.used_breakdown[child] = dir.used_breakdown[child].saturating_sub(used);
+    })
+}
+
+/// Account what the sync of a dataset's objset did in its DSL directory `dir_obj` and the ones
+/// above it (`dsl_dir_diduse_space`): `born` bytes were written, and `killed` bytes stopped being
+/// referenced, of which the latest snapshot still holds on to `deadlisted`. The directories are
+/// changed in `mos_dirty`.
+fn charge_dirs(reader: &mut ZfsReader,
+               mos: &ObjectSet,
+               mos_dirty: &mut DirtyObjset,
+               dir_obj: u64,
+               born: u64,
+               killed: u64,
+               deadlisted: u64)
+               -> zfs::Result<()> {
+    let add = |value: u64, added: u64, removed: u64| (value + added).saturating_sub(removed);
+    let freed = killed - deadlisted;
+    update_dirs(reader, mos, mos_dirty, dir_obj, &mut |object, dir| {
+        dir.used_bytes = add(dir.used_bytes, born, freed);
+        if object == dir_obj {
+            let head = DslDirUsed::Head as usize;
+            let snap = DslDirUsed::Snap as usize;
+            dir.used_breakdown[head] = add(dir.used_breakdown[head], born, killed);
+            dir.used_breakdown[snap] += deadlisted;
+        } else {
+            let child = DslDirUsed::Child as usize;
+            dir.used_breakdown[child] = add(dir.used_breakdown[child], born, freed);
+        }
+    })
+}
+
+/// Change DSL directory `dir_obj` and each of the ones above it with `update`, in `mos_dirty`
+fn update_dirs(reader: &mut ZfsReader,
+               mos: &ObjectSet,
+               mos_dirty: &mut DirtyObjset,
+               dir_obj: u64,
+               update: &mut FnMut(u64, &mut DslDirPhys))
+               -> zfs::Result<()> {
+    let mut object = dir_obj;
+    while object != 0 {
+        let mut dnode = match mos_dirty.dnodes.get(&object) {
+            Some(dnode) => *dnode,
+            None => try!(mos.dnode(reader, object).map_err(|_| zfs::Error::NoEntity)),
+        };
+        let mut dir = try!(DslDirPhys::from_bytes(dnode.get_bonus()));
+        update(object, &mut dir);
+        let parent_obj = dir.parent_obj;
+        let bytes = dir.as_bytes();
+        dnode.bonus_mut()[..bytes.len()].copy_from_slice(bytes);
+        mos_dirty.dnodes.insert(object, dnode);
+        object = parent_obj;
+    }
+    Ok(())
+}
+
+/// Bytes of dirty data a txg has for an objset
+fn dirty_size(dirty: &DirtyObjset) -> u64 {
+    let blocks = dirty.blocks.values().flat_map(|blocks| blocks.values());
+    dirty.dnodes.len() as u64 * DNODE_SIZE + blocks.map(|b| b.len() as u64).sum::<u64>()
+}
+
+/// Free every copy of `block_ptr`
+fn free_block(alloc: &mut Allocator, block_ptr: &BlockPtr, txg: u64) {
+    let dvas = block_ptr.dvas;
+    for dva in dvas[..block_ptr.max_copies()].iter().filter(|dva| !dva.is_empty()) {
+        alloc.free(dva, txg);
+    }
+}
+
+/// Open a DSL directory of the pool. Its dnode and bonus buffer are read as part of the MOS, any
+/// trouble there means the pool's metadata is broken.
+fn open_dir(reader: &mut ZfsReader, mos: &ObjectSet, object: u64) -> zfs::Result<DslDir> {
+    DslDir::open(reader, mos, object).map_err(|_| zfs::Error::Invalid)
+}
+
+/// Whether `name` can name a dataset (`dataset_namecheck`): components of letters, digits and
+/// `-_.: `, separated by slashes, 255 bytes in all at most
+fn is_valid_dataset_name(name: &str) -> bool {
+    name.len() < 256 &&
+    name.split('/').all(|component| {
+        !component.is_empty() &&
+        component.chars().all(|c| c.is_ascii_alphanumeric() || "-_.: ".contains(c))
+    })
+}
+
+#[test]
+fn test_sync() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_objset::ObjectSetPhys;
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::ObjectType;
+    use super::test_util::Bump;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let new_objset = || {
+        let mut phys = ObjectSetPhys::from_bytes(&[0; 1024]).unwrap();
+        phys.meta_dnode.object_type = ObjectType::DNode as u8;
+        phys.meta_dnode.nblkptr = 3;
+        phys.meta_dnode.indblkshift = 14;
+        phys.meta_dnode.data_blk_sz_sec = 32;
+        ObjectSet {
+            phys: phys,
+            userused_dnode: None,
+            groupused_dnode: None,
+        }
+    };
+    let new_dnode = |object_type: ObjectType| {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = object_type as u8;
+        dnode.nblkptr = 1;
+        dnode.indblkshift = 14;
+        dnode.data_blk_sz_sec = 1;
+        dnode
+    };
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = Bump(0);
+
+    // An empty dataset as MOS object 3
+    let mut phys = DslDatasetPhys::from_bytes(&[0; 512]).unwrap();
+    phys.bp = new_objset().sync(&mut reader,
+                                &mut alloc,
+                                None,
+                                ZIO_COMPRESS_OFF,
+                                1,
+                                DirtyObjset::default(),
+                                &mut vec![])
+                          .unwrap();
+    let mut dataset = new_dnode(ObjectType::DataSet);
+    dataset.bonus_mut()[..mem::size_of::<DslDatasetPhys>()].copy_from_slice(phys.as_bytes());
+
+    let mut dsl_pool = DslPool::new();
+    let txg = dsl_pool.tx.hold_open();
+    dsl_pool.dirty_dnode(txg, MOS_OBJSET, 3, dataset);
+    dsl_pool.dirty_dnode(txg, 3, 5, new_dnode(ObjectType::PlainFileContents));
+    dsl_pool.dirty_block(txg, 3, 5, 0, vec![1; 512]);
+    dsl_pool.dirty_block(txg, 3, 5, 0, vec![7; 512]);
+    assert_eq!(dsl_pool.dirty_bytes(txg), 2 * DNODE_SIZE + 512);
+    assert!(!dsl_pool.need_sync());
+
+    // Still held
+    let mut mos = new_objset();
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0c, 0xb1, 0xba, 0, 0, 0, 0, 0]);
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // version 5000
+    let uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    assert!(dsl_pool.sync(&mut reader, &mut mos, &uberblock, &mut alloc).unwrap().is_none());
+    dsl_pool.tx.rele(txg);
+    let synced = dsl_pool.sync(&mut reader, &mut mos, &uberblock, &mut alloc).unwrap().unwrap();
+    let (synced_txg, rootbp) = (synced.txg, synced.rootbp);
+    assert_eq!((synced_txg, dsl_pool.dp_dirty_total), (txg, 0));
+    dsl_pool.sync_done();
+    assert_eq!(dsl_pool.tx.synced_txg(), txg);
+
+    // The new MOS leads to the dataset, and the dataset to the file
+    let mos = ObjectSet::open(&mut reader, &rootbp).unwrap();
+    let dataset = mos.dnode(&mut reader, 3).unwrap();
+    let bp = DslDatasetPhys::from_bytes(dataset.get_bonus()).unwrap().bp;
+    let objset = ObjectSet::open(&mut reader, &bp).unwrap();
+    assert_eq!(objset.read(&mut reader, 5, 0, 512).unwrap(), vec![7; 512]);
+
+    // Nothing to sync in the next txg
+    let mut mos = mos;
+    assert!(dsl_pool.sync(&mut reader, &mut mos, &synced, &mut alloc).unwrap().is_none());
+    assert_eq!(dsl_pool.tx.synced_txg(), txg + 1);
+}
+
+#[test]
+fn test_create_dataset() {
+    use super::test_util;
+    use super::zpl;
+
+    let (mut spa, mut alloc, device) = test_util::pool("zfs_test_create_dataset");
+    let path = device.path();
+
+    // A parent created in the same txg is found there
+    let home = spa.create_dataset("tank/home").unwrap();
+    let alice = spa.create_dataset("tank/home/alice").unwrap();
+    assert!(home != alice);
+    assert_eq!(spa.create_dataset("tank/home"), Err(zfs::Error::Exists));
+    assert_eq!(spa.create_dataset("tank"), Err(zfs::Error::Exists));
+    assert_eq!(spa.create_dataset("tank/none/bob"), Err(zfs::Error::NoEntity));
+    assert_eq!(spa.create_dataset("tank/home/bob@monday"), Err(zfs::Error::Invalid));
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let names = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let datasets = dsl_pool.datasets(reader, mos, "tank").unwrap();
+        datasets.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+    };
+    let mut spa = spa::import(path).unwrap();
+    assert_eq!(names(&mut spa), vec!["tank", "tank/home", "tank/home/alice"]);
+    let dataset = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let dataset = dsl_pool.open_dataset(reader, mos, "tank/home/alice", false).unwrap();
+        assert_eq!(dataset.object, alice);
+        assert!(zpl::readdir(reader, &dataset, "/").unwrap().is_empty());
+        dataset
+    };
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let file = zpl::create(reader, &mut dsl_pool, mos, &dataset, "/file", 0o644).unwrap();
+        zpl::write(reader, &mut dsl_pool, &dataset, file, 0, &[7; 256 << 10]).unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let allocated = alloc.allocated;
+
+    // Only datasets without children go, and never the root
+    assert_eq!(spa.destroy_dataset("tank/home"), Err(zfs::Error::Exists));
+    assert_eq!(spa.destroy_dataset("tank"), Err(zfs::Error::Invalid));
+    spa.destroy_dataset("tank/home/alice").unwrap();
+    assert_eq!(spa.destroy_dataset("tank/home/alice"), Err(zfs::Error::NoEntity));
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let mut spa = spa::import(path).unwrap();
+    assert_eq!(names(&mut spa), vec!["tank", "tank/home"]);
+
+    // Its blocks are freed, and tank/home is left with its own space
+    assert!(alloc.allocated + (256 << 10) <= allocated);
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    let dsl_pool = DslPool::open(reader, mos).unwrap();
+    let home = dsl_pool.lookup_dir(reader, mos, "tank/home").unwrap().phys;
+    let (used_bytes, used_breakdown) = (home.used_bytes, home.used_breakdown);
+    assert_eq!(used_breakdown[DslDirUsed::Child as usize], 0);
+    assert_eq!(used_bytes, used_breakdown[DslDirUsed::Head as usize]);
+}
+
+#[test]
+fn test_space_accounting() {
+    use super::dsl_prop::{PropChange, PropValue};
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_space_accounting");
+    spa.create_dataset("tank/home").unwrap();
+    let quota = PropChange::Set(PropValue::Number(1 << 20));
+    spa.set_property("tank/home", "quota", &quota).unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // (dataset, used bytes of tank/home, used bytes of the root)
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let dataset = dsl_pool.open_dataset(reader, mos, "tank/home", false).unwrap();
+        let home = dsl_pool.lookup_dir(reader, mos, "tank/home").unwrap().phys;
+        let root = dsl_pool.lookup_dir(reader, mos, "tank").unwrap().phys;
+        let (used_breakdown, home_used, root_used) = (home.used_breakdown, home.used_bytes,
+                                                      root.used_bytes);
+        assert_eq!(used_breakdown[DslDirUsed::Head as usize], home_used);
+        (dataset, home_used, root_used)
+    };
+    let write = |spa: &mut spa::Spa, f: &mut FnMut(&mut ZfsReader, &mut DslPool, &ObjectSet)| {
+        let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+        {
+            let (reader, mos) = spa.reader_and_mos().unwrap();
+            f(reader, &mut dsl_pool, mos);
+        }
+        mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    };
+    let (dataset, home_used, root_used) = open(&mut spa);
+    assert_eq!(dataset.limits.quotas, vec![(1 << 20, home_used)]);
+    let data: Vec<u8> = (0..256 << 10).map(|i| (i * 7 / 5) as u8).collect();
+    let mut file = 0;
+    write(&mut spa, &mut |reader, dsl_pool, mos| {
+        file = zpl::create(reader, dsl_pool, mos, &dataset, "/file", 0o644).unwrap();
+        zpl::write(reader, dsl_pool, &dataset, file, 0, &data).unwrap();
+    });
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // The space shows up in the dataset and in both directories
+    let (dataset, new_home_used, new_root_used) = open(&mut spa);
+    let written = new_home_used - home_used;
+    assert!(written >= 256 << 10);
+    assert_eq!(new_root_used - root_used, written);
+    assert!(dataset.phys.referenced_bytes >= 256 << 10);
+    write(&mut spa, &mut |reader, dsl_pool, _| {
+        let more = vec![1; 800 << 10];
+        assert_eq!(zpl::write(reader, dsl_pool, &dataset, file, 256 << 10, &more),
+                   Err(zfs::Error::QuotaExceeded));
+        zpl::truncate(reader, dsl_pool, &dataset, file, 0).unwrap();
+    });
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // The blocks truncated off are freed, the same space given back to both directories
+    let (dataset, home_used, root_used) = open(&mut spa);
+    assert!(new_home_used - home_used >= 200 << 10);
+    assert_eq!(new_root_used - root_used, new_home_used - home_used);
+    let referenced = dataset.phys.referenced_bytes;
+    let refquota = PropChange::Set(PropValue::Number(referenced + (100 << 10)));
+    spa.set_property("tank/home", "refquota", &refquota).unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // The refquota is now the lower limit
+    let (dataset, _, _) = open(&mut spa);
+    write(&mut spa, &mut |reader, dsl_pool, _| {
+        assert_eq!(zpl::write(reader, dsl_pool, &dataset, file, 0, &data[..200 << 10]),
+                   Err(zfs::Error::QuotaExceeded));
+        zpl::write(reader, dsl_pool, &dataset, file, 0, &data[..8 << 10]).unwrap();
+    });
 }
@@ -0,0 +1,801 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use super::ZfsReader;
+use super::dmu_objset::ObjectSet;
+use super::dmu_tx::Tx;
+use super::dsl_dir::{DslDir, DslDirPhys};
+use super::dsl_pool::DslPool;
+use super::zap::{self, ZapValue};
+use super::zfs;
+
+// Suffixes of the special entries of a props ZAP
+const INHERIT_SUFFIX: &'static str = "$inherit"; // locally inherited, overriding a received value
+const RECVD_SUFFIX: &'static str = "$recvd"; // received with `zfs receive`
+
+/// Most levels of DSL directories there are above a dataset, against loops in a damaged pool
+const MAX_DEPTH: usize = 256;
+
+/// How the value of a property is stored and displayed
+#[derive(Copy, Clone)]
+pub enum PropType {
+    Number,
+    /// Stored as a number, displayed as one of the names
+    Index(&'static [(&'static str, u64)]),
+    String,
+}
+
+const ON_OFF: &'static [(&'static str, u64)] = &[("off", 0), ("on", 1)];
+const CANMOUNT: &'static [(&'static str, u64)] = &[("off", 0), ("on", 1), ("noauto", 2)];
+const CHECKSUM: &'static [(&'static str, u64)] = &[("on", 1),
+                                                   ("off", 2),
+                                                   ("fletcher2", 6),
+                                                   ("fletcher4", 7),
+                                                   ("sha256", 8),
+                                                   ("noparity", 10),
+                                                   ("sha512", 11),
+                                                   ("skein", 12),
+                                                   ("edonr", 13)];
+const COMPRESSION: &'static [(&'static str, u64)] = &[("on", 1),
+                                                      ("off", 2),
+                                                      ("lzjb", 3),
+                                                      ("empty", 4),
+                                                      ("gzip-1", 5),
+                                                      ("gzip-2", 6),
+                                                      ("gzip-3", 7),
+                                                      ("gzip-4", 8),
+                                                      ("gzip-5", 9),
+                                                      ("gzip-6", 10),
+                                                      ("gzip-7", 11),
+                                                      ("gzip-8", 12),
+                                                      ("gzip-9", 13),
+                                                      ("zle", 14),
+                                                      ("lz4", 15)];
+const SYNC: &'static [(&'static str, u64)] = &[("standard", 0), ("always", 1), ("disabled", 2)];
+const LOGBIAS: &'static [(&'static str, u64)] = &[("latency", 0), ("throughput", 1)];
+const CACHE: &'static [(&'static str, u64)] = &[("none", 0), ("metadata", 1), ("all", 2)];
+const SNAPDIR: &'static [(&'static str, u64)] = &[("hidden", 0), ("visible", 1)];
+const XATTR: &'static [(&'static str, u64)] = &[("off", 0), ("on", 1), ("sa", 2)];
+const ACLINHERIT: &'static [(&'static str, u64)] = &[("discard", 0),
+                                                     ("noallow", 1),
+                                                     ("restricted", 4),
+                                                     ("passthrough", 3),
+                                                     ("passthrough-x", 5)];
+const REDUNDANT_METADATA: &'static [(&'static str, u64)] = &[("all", 0), ("most", 1)];
+
+/// The native dataset properties kept in the props ZAP of a DSL directory, as (name, type,
+/// default, inheritable). The default mountpoint is "/<dataset>", see `resolve`.
+pub const PROPS: &'static [(&'static str, PropType, &'static str, bool)] = &[
+    ("aclinherit", PropType::Index(ACLINHERIT), "restricted", true),
+    ("atime", PropType::Index(ON_OFF), "on", true),
+    ("canmount", PropType::Index(CANMOUNT), "on", false),
+    ("checksum", PropType::Index(CHECKSUM), "on", true),
+    ("compression", PropType::Index(COMPRESSION), "off", true),
+    ("copies", PropType::Number, "1", true),
+    ("devices", PropType::Index(ON_OFF), "on", true),
+    ("exec", PropType::Index(ON_OFF), "on", true),
+    ("logbias", PropType::Index(LOGBIAS), "latency", true),
+    ("mountpoint", PropType::String, "", true),
+    ("primarycache", PropType::Index(CACHE), "all", true),
+    ("readonly", PropType::Index(ON_OFF), "off", true),
+    ("recordsize", PropType::Number, "131072", true),
+    ("redundant_metadata", PropType::Index(REDUNDANT_METADATA), "all", true),
+    ("refquota", PropType::Number, "0", false),
+    ("refreservation", PropType::Number, "0", false),
+    ("secondarycache", PropType::Index(CACHE), "all", true),
+    ("setuid", PropType::Index(ON_OFF), "on", true),
+    ("sharenfs", PropType::String, "off", true),
+    ("sharesmb", PropType::String, "off", true),
+    ("snapdir", PropType::Index(SNAPDIR), "hidden", true),
+    ("sync", PropType::Index(SYNC), "standard", true),
+    ("xattr", PropType::Index(XATTR), "on", true),
+];
+
+/// Where the effective value of a property comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropSource {
+    Local,
+    Received,
+    /// Inherited from the named ancestor
+    Inherited(String),
+    Default,
+}
+
+impl PropSource {
+    fn to_str(&self) -> &'static str {
+        match *self {
+            PropSource::Local => "LOCAL",
+            PropSource::Received => "RECEIVED",
+            PropSource::Inherited(_) => "INHERITED",
+            PropSource::Default => "DEFAULT",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub value: String,
+    pub source: PropSource,
+}
+
+/// The value of a property, typed after its `PropType`
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropValue {
+    Number(u64),
+    /// The name of the value of an index property, and the number it's stored as
+    Index(&'static str, u64),
+    String(String),
+}
+
+impl PropValue {
+    /// The value of a number or index property
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            PropValue::Number(value) | PropValue::Index(_, value) => Some(value),
+            PropValue::String(_) => None,
+        }
+    }
+
+    /// The value of a string property, or the name of an index property's value
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            PropValue::Index(name, _) => Some(name),
+            PropValue::String(ref value) => Some(value),
+            PropValue::Number(_) => None,
+        }
+    }
+
+    fn from_zap(value: &ZapValue, prop_type: PropType) -> Self {
+        match (value, prop_type) {
+            (&ZapValue::Int(value), PropType::Index(names)) => {
+                match names.iter().find(|&&(_, index)| index == value) {
+                    Some(&(name, _)) => PropValue::Index(name, value),
+                    None => PropValue::Number(value),
+                }
+            }
+            (&ZapValue::Int(value), _) => PropValue::Number(value),
+            (&ZapValue::String(ref value), _) => PropValue::String(value.clone()),
+            (value, prop_type) => PropValue::String(display(value, prop_type)),
+        }
+    }
+
+    fn from_default(default: &str, prop_type: PropType) -> Self {
+        match prop_type {
+            PropType::Number => PropValue::Number(default.parse().unwrap_or(0)),
+            PropType::Index(names) => {
+                match names.iter().find(|&&(name, _)| name == default) {
+                    Some(&(name, index)) => PropValue::Index(name, index),
+                    None => PropValue::String(default.to_owned()),
+                }
+            }
+            PropType::String => PropValue::String(default.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for PropValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PropValue::Number(value) => write!(f, "{}", value),
+            PropValue::Index(name, _) => write!(f, "{}", name),
+            PropValue::String(ref value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A change to a property of a dataset, see `DslPool::set_property`
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropChange {
+    /// Set it locally (`zfs set`), over any received value
+    Set(PropValue),
+    /// The value `zfs receive` got with the stream, in effect unless it's set locally
+    Receive(PropValue),
+    /// Drop the local value and inherit (`zfs inherit`), even over a received value
+    Inherit,
+    /// Drop the local value and go back to the received one (`zfs inherit -S`)
+    RevertToReceived,
+}
+
+/// The number a props ZAP holds for `value` of native property `prop`. Fails with `Invalid` for
+/// unknown properties and values of the wrong type. String values are `UnsupportedFeature`:
+/// ZAP entries are only added with single integers for values.
+pub fn encode(prop: &str, value: &PropValue) -> zfs::Result<u64> {
+    match (try!(settable(prop)), value) {
+        (PropType::Number, &PropValue::Number(value)) => Ok(value),
+        (PropType::Index(names), &PropValue::Index(name, _)) => {
+            names.iter()
+                 .find(|&&(index_name, _)| index_name == name)
+                 .map(|&(_, index)| index)
+                 .ok_or(zfs::Error::Invalid)
+        }
+        (PropType::String, &PropValue::String(_)) => Err(zfs::Error::UnsupportedFeature),
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// The type of property `prop`, if it can be set
+pub fn settable(prop: &str) -> zfs::Result<PropType> {
+    match PROPS.iter().find(|&&(name, ..)| name == prop) {
+        Some(&(_, prop_type, ..)) => Ok(prop_type),
+        // TODO: user properties, once ZAP entries can be added with string values
+        None if prop.contains(':') => Err(zfs::Error::UnsupportedFeature),
+        None if prop == "quota" || prop == "reservation" => Ok(PropType::Number),
+        None => Err(zfs::Error::Invalid),
+    }
+}
+
+/// Make `change` to property `prop` in props ZAP `zapobj` (`dsl_prop_set_sync`). The changes
+/// have to be covered by a hold of `tx` on the ZAP.
+pub fn set_sync(reader: &mut ZfsReader,
+                dsl_pool: &mut DslPool,
+                mos: &ObjectSet,
+                zapobj: u64,
+                prop: &str,
+                change: &PropChange,
+                tx: &Tx)
+                -> zfs::Result<()> {
+    let inherit_entry = format!("{}{}", prop, INHERIT_SUFFIX);
+    let recvd_entry = format!("{}{}", prop, RECVD_SUFFIX);
+    let entries = try!(zap::pending_entries(reader, dsl_pool, mos, tx.objset(), zapobj));
+    let has = |name: &str| entries.iter().any(|&(ref entry, _)| entry == name);
+    let mut remove = Vec::new();
+    let mut add = Vec::new();
+    match *change {
+        PropChange::Set(ref value) => {
+            add.push((prop, try!(encode(prop, value))));
+            remove.push(prop);
+            remove.push(&inherit_entry);
+        }
+        PropChange::Receive(ref value) => {
+            add.push((&recvd_entry, try!(encode(prop, value))));
+            remove.push(&recvd_entry);
+        }
+        PropChange::Inherit => {
+            remove.push(prop);
+            // Received values are masked for as long as they're there
+            if has(&recvd_entry) && !has(&inherit_entry) {
+                add.push((&inherit_entry, 0));
+            }
+        }
+        PropChange::RevertToReceived => {
+            remove.push(prop);
+            remove.push(&inherit_entry);
+        }
+    }
+
+    for name in remove.into_iter().filter(|&name| has(name)) {
+        try!(zap::remove(reader, dsl_pool, mos, zapobj, name, tx));
+    }
+    for (name, value) in add {
+        try!(zap::add(reader, dsl_pool, mos, zapobj, name, value, tx));
+    }
+    Ok(())
+}
+
+/// The effective properties of one dataset
+pub struct DatasetProps {
+    pub name: String,
+    pub properties: Vec<Property>,
+}
+
+impl DatasetProps {
+    pub fn get(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+}
+
+/// The effective properties of every dataset in the pool, parents before their children. The
+/// datasets are visited once, in a single walk of the DSL directory tree: every props ZAP is read
+/// exactly once and inherited values are taken from the already resolved parent.
+pub fn all_properties(reader: &mut ZfsReader,
+                      mos: &ObjectSet,
+                      pool: &DslPool,
+                      pool_name: &str)
+                      -> Result<Vec<DatasetProps>, String> {
+    let datasets = try!(pool.datasets(reader, mos, pool_name));
+    let mut all: Vec<DatasetProps> = Vec::with_capacity(datasets.len());
+    let mut index = HashMap::new();
+    for (name, dir) in datasets {
+        let props_zapobj = dir.phys.props_zapobj;
+        let stored: Vec<(String, ZapValue)> = if props_zapobj == 0 {
+            Vec::new()
+        } else {
+            try!(zap::iter_values(reader, mos, props_zapobj)).collect()
+        };
+
+        let properties = {
+            let parent = name.rfind('/').and_then(|i| index.get(&name[..i])).map(|&i| &all[i]);
+            resolve(&name, &dir.phys, &stored, parent)
+        };
+        index.insert(name.clone(), all.len());
+        all.push(DatasetProps {
+            name: name,
+            properties: properties,
+        });
+    }
+    Ok(all)
+}
+
+/// The effective value of property `prop` of the dataset in DSL directory `dir_obj`, and where it
+/// comes from, like `zfs get` has it. Only the props ZAPs from the dataset's up to the one the
+/// property is set in are read. `snap_props` is the props ZAP of a snapshot, 0 for a head
+/// dataset; the root dataset is named `pool_name`. None for a user property set nowhere.
+pub fn get_property(reader: &mut ZfsReader,
+                    mos: &ObjectSet,
+                    pool_name: &str,
+                    dir_obj: u64,
+                    snap_props: u64,
+                    prop: &str)
+                    -> Result<Option<(PropValue, PropSource)>, String> {
+    let (prop_type, default, inherit) = match PROPS.iter().find(|&&(name, ..)| name == prop) {
+        Some(&(_, prop_type, default, inherit)) => (prop_type, Some(default), inherit),
+        None if prop.contains(':') => (PropType::String, None, true),
+        None if prop == "quota" || prop == "reservation" => {
+            // These live in the DSL directory itself
+            let dir = try!(DslDir::open(reader, mos, dir_obj));
+            let value = if prop == "quota" { dir.phys.quota } else { dir.phys.reserved };
+            let source = if value == 0 { PropSource::Default } else { PropSource::Local };
+            return Ok(Some((PropValue::Number(value), source)));
+        }
+        None => return Err(format!("{}: no such property", prop)),
+    };
+
+    if snap_props != 0 {
+        let stored: Vec<_> = try!(zap::iter_values(reader, mos, snap_props)).collect();
+        if let Some((value, source)) = stored_value(&stored, prop) {
+            return Ok(Some((PropValue::from_zap(value, prop_type), source)));
+        }
+    }
+    let dirs = try!(ancestors(reader, mos, pool_name, dir_obj));
+    let name = &dirs[0].0;
+    for (i, &(ref dir_name, ref dir)) in dirs.iter().enumerate() {
+        let props_zapobj = dir.phys.props_zapobj;
+        let stored: Vec<_> = if props_zapobj == 0 {
+            Vec::new()
+        } else {
+            try!(zap::iter_values(reader, mos, props_zapobj)).collect()
+        };
+        if let Some((value, source)) = stored_value(&stored, prop) {
+            let value = PropValue::from_zap(value, prop_type);
+            if i == 0 {
+                return Ok(Some((value, source)));
+            }
+            let value = if prop == "mountpoint" {
+                PropValue::String(mountpoint(&value.to_string(), &name[dir_name.len()..]))
+            } else {
+                value
+            };
+            return Ok(Some((value, PropSource::Inherited(dir_name.clone()))));
+        }
+        if !inherit {
+            break;
+        }
+    }
+
+    Ok(default.map(|default| {
+        let value = if prop == "mountpoint" {
+            PropValue::String(format!("/{}", name))
+        } else {
+            PropValue::from_default(default, prop_type)
+        };
+        (value, PropSource::Default)
+    }))
+}
+
+/// The DSL directory `dir_obj` and its ancestors up to the root, with their full names
+fn ancestors(reader: &mut ZfsReader,
+             mos: &ObjectSet,
+             pool_name: &str,
+             dir_obj: u64)
+             -> Result<Vec<(String, DslDir)>, String> {
+    let mut dirs = vec![try!(DslDir::open(reader, mos, dir_obj))];
+    loop {
+        let parent_obj = dirs[dirs.len() - 1].phys.parent_obj;
+        if parent_obj == 0 {
+            break;
+        }
+        if dirs.len() > MAX_DEPTH {
+            return Err(format!("dsl_dir {}: too deep", dir_obj));
+        }
+        dirs.push(try!(DslDir::open(reader, mos, parent_obj)));
+    }
+
+    // Each directory is named in its parent's child map
+    let mut components = Vec::new();
+    for pair in dirs.windows(2) {
+        let (child, parent) = (&pair[0], &pair[1]);
+        let children = try!(parent.children(reader, mos));
+        match children.into_iter().find(|&(_, object)| object == child.object) {
+            Some((component, _)) => components.push(component),
+            None => return Err(format!("dsl_dir {}: not in its parent", child.object)),
+        }
+    }
+    let mut name = pool_name.to_owned();
+    let mut names = vec![name.clone()];
+    for component in components.iter().rev() {
+        name = format!("{}/{}", name, component);
+        names.push(name.clone());
+    }
+    Ok(names.into_iter().rev().zip(dirs).collect())
+}
+
+/// Work out the effective properties of dataset `name` from its stored props and its parent's
+/// effective properties
+fn resolve(name: &str,
+           dir: &DslDirPhys,
+           stored: &[(String, ZapValue)],
+           parent: Option<&DatasetProps>)
+           -> Vec<Property> {
+    let mut properties = Vec::new();
+
+    for &(prop, prop_type, default, inherit) in PROPS {
+        let default = if prop == "mountpoint" {
+            format!("/{}", name)
+        } else {
+            default.to_owned()
+        };
+        properties.push(resolve_one(name, prop, prop_type, inherit, default, stored, parent));
+    }
+
+    // The quota and reservation live in the DSL directory itself
+    let quota = dir.quota;
+    let reserved = dir.reserved;
+    for &(prop, value) in &[("quota", quota), ("reservation", reserved)] {
+        properties.push(Property {
+            name: prop.to_owned(),
+            value: value.to_string(),
+            source: if value == 0 {
+                PropSource::Default
+            } else {
+                PropSource::Local
+            },
+        });
+    }
+
+    // User properties (`module:property`) are inheritable strings without a default
+    let mut user_props: Vec<String> = stored.iter()
+                                            .map(|&(ref prop, _)| base_name(prop).to_owned())
+                                            .filter(|prop| prop.contains(':'))
+                                            .collect();
+    if let Some(parent) = parent {
+        user_props.extend(parent.properties
+                                .iter()
+                                .filter(|prop| prop.name.contains(':'))
+                                .map(|prop| prop.name.clone()));
+    }
+    user_props.sort();
+    user_props.dedup();
+    for prop in user_props {
+        let property = resolve_one(name,
+                                   &prop,
+                                   PropType::String,
+                                   true,
+                                   String::new(),
+                                   stored,
+                                   parent);
+        if property.source != PropSource::Default {
+            properties.push(property);
+        }
+    }
+
+    properties.sort_by(|a, b| a.name.cmp(&b.name));
+    properties
+}
+
+fn resolve_one(name: &str,
+               prop: &str,
+               prop_type: PropType,
+               inherit: bool,
+               default: String,
+               stored: &[(String, ZapValue)],
+               parent: Option<&DatasetProps>)
+               -> Property {
+    let (value, source) = if let Some((value, source)) = stored_value(stored, prop) {
+        (display(value, prop_type), source)
+    } else {
+        match parent.and_then(|parent| parent.get(prop).map(|p| (parent, p))) {
+            Some((parent, parent_prop)) if inherit &&
+                                           parent_prop.source != PropSource::Default => {
+                let from = match parent_prop.source {
+                    PropSource::Inherited(ref from) => from.clone(),
+                    _ => parent.name.clone(),
+                };
+                let value = if prop == "mountpoint" {
+                    mountpoint(&parent_prop.value, &name[parent.name.len()..])
+                } else {
+                    parent_prop.value.clone()
+                };
+                (value, PropSource::Inherited(from))
+            }
+            _ => (default, PropSource::Default),
+        }
+    };
+
+    Property {
+        name: prop.to_owned(),
+        value: value,
+        source: source,
+    }
+}
+
+/// The number property `prop` is set to in props ZAP `zapobj` (0 for none), locally or else
+/// received, without looking at what it inherits
+pub fn stored_u64(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  zapobj: u64,
+                  prop: &str)
+                  -> Result<Option<u64>, String> {
+    if zapobj == 0 {
+        return Ok(None);
+    }
+    let stored: Vec<_> = try!(zap::iter_values(reader, mos, zapobj)).collect();
+    Ok(match stored_value(&stored, prop) {
+        Some((&ZapValue::Int(value), _)) => Some(value),
+        _ => None,
+    })
+}
+
+/// The value of `prop` in a props ZAP, set locally or else received
+fn stored_value<'a>(stored: &'a [(String, ZapValue)],
+                    prop: &str)
+                    -> Option<(&'a ZapValue, PropSource)> {
+    let find = |key: &str| stored.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v);
+    if let Some(value) = find(prop) {
+        Some((value, PropSource::Local))
+    } else if let (None, Some(value)) = (find(&format!("{}{}", prop, INHERIT_SUFFIX)),
+                                         find(&format!("{}{}", prop, RECVD_SUFFIX))) {
+        Some((value, PropSource::Received))
+    } else {
+        None
+    }
+}
+
+/// The name of the property a props ZAP entry belongs to
+fn base_name(entry: &str) -> &str {
+    entry.find('$').map(|i| &entry[..i]).unwrap_or(entry)
+}
+
+/// An inherited mountpoint gets the relative path of the child appended
+fn mountpoint(parent: &str, relative: &str) -> String {
+    if parent == "none" || parent == "legacy" {
+        return parent.to_owned();
+    }
+    format!("{}{}", parent.trim_right_matches('/'), relative)
+}
+
+fn display(value: &ZapValue, prop_type: PropType) -> String {
+    match (value, prop_type) {
+        (&ZapValue::Int(value), PropType::Index(names)) => {
+            match names.iter().find(|&&(_, index)| index == value) {
+                Some(&(name, _)) => name.to_owned(),
+                None => value.to_string(),
+            }
+        }
+        (&ZapValue::Int(value), _) => value.to_string(),
+        (&ZapValue::String(ref value), _) => value.clone(),
+        (&ZapValue::Array(ref values), _) => {
+            values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(",")
+        }
+        (&ZapValue::Bytes(ref bytes), _) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Render the properties of every dataset as JSON, in the layout of `zfs get -j`
+pub fn render_json(datasets: &[DatasetProps]) -> String {
+    let mut out = String::new();
+    out.push_str("{\"datasets\":{");
+    for (i, dataset) in datasets.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out,
+                       "{}:{{\"name\":{},\"properties\":{{",
+                       json_string(&dataset.name),
+                       json_string(&dataset.name));
+        for (j, prop) in dataset.properties.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let data = match prop.source {
+                PropSource::Inherited(ref from) => &from[..],
+                _ => "-",
+            };
+            let _ = write!(out,
+                           "{}:{{\"value\":{},\"source\":{{\"type\":\"{}\",\"data\":{}}}}}",
+                           json_string(&prop.name),
+                           json_string(&prop.value),
+                           prop.source.to_str(),
+                           json_string(data));
+        }
+        out.push_str("}}");
+    }
+    out.push_str("}}\n");
+    out
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_property_inheritance() {
+    use super::from_bytes::FromBytes;
+
+    let dir = DslDirPhys::from_bytes(&[0; 256]).unwrap();
+    let root_stored = vec![("compression".to_owned(), ZapValue::Int(15)),
+                           ("mountpoint".to_owned(), ZapValue::String("/".to_owned())),
+                           ("com.example:owner".to_owned(), ZapValue::String("ops".to_owned())),
+                           ("canmount".to_owned(), ZapValue::Int(0))];
+    let root = DatasetProps {
+        name: "tank".to_owned(),
+        properties: resolve("tank", &dir, &root_stored, None),
+    };
+    assert_eq!(root.get("compression").unwrap().value, "lz4");
+    assert_eq!(root.get("atime").unwrap().source, PropSource::Default);
+
+    let child_stored = vec![("atime".to_owned(), ZapValue::Int(0)),
+                            ("copies$recvd".to_owned(), ZapValue::Int(2)),
+                            ("sync$recvd".to_owned(), ZapValue::Int(2)),
+                            ("sync$inherit".to_owned(), ZapValue::Int(0))];
+    let child = DatasetProps {
+        name: "tank/home".to_owned(),
+        properties: resolve("tank/home", &dir, &child_stored, Some(&root)),
+    };
+    let inherited = PropSource::Inherited("tank".to_owned());
+    assert_eq!(child.get("compression").unwrap(),
+               &Property {
+                   name: "compression".to_owned(),
+                   value: "lz4".to_owned(),
+                   source: inherited.clone(),
+               });
+    assert_eq!(child.get("mountpoint").unwrap().value, "/home");
+    assert_eq!(child.get("com.example:owner").unwrap().source, inherited);
+    assert_eq!(child.get("atime").unwrap().value, "off");
+    assert_eq!(child.get("copies").unwrap().source, PropSource::Received);
+    // Locally inherited props ignore the received value
+    assert_eq!(child.get("sync").unwrap().source, PropSource::Default);
+    // canmount is never inherited
+    assert_eq!(child.get("canmount").unwrap().value, "on");
+
+    let grandchild = DatasetProps {
+        name: "tank/home/alice".to_owned(),
+        properties: resolve("tank/home/alice", &dir, &[], Some(&child)),
+    };
+    assert_eq!(grandchild.get("compression").unwrap().source, inherited);
+    assert_eq!(grandchild.get("mountpoint").unwrap().value, "/home/alice");
+    assert_eq!(grandchild.get("atime").unwrap().source,
+               PropSource::Inherited("tank/home".to_owned()));
+
+    let json = render_json(&[grandchild]);
+    assert!(json.contains("\"atime\":{\"value\":\"off\",\"source\":{\"type\":\"INHERITED\",\
+                           \"data\":\"tank/home\"}}"));
+}
+
+#[test]
+fn test_get_property() {
+    use std::mem;
+    use super::dmu_tx::Tx;
+    use super::dsl_pool::MOS_OBJSET;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_get_property");
+    spa.create_dataset("tank/home").unwrap();
+    spa.create_dataset("tank/home/alice").unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // compression=lz4 and canmount=off on the root, atime=off on tank/home
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let root = dsl_pool.lookup_dir(reader, mos, "tank").unwrap().phys.props_zapobj;
+        let home = dsl_pool.lookup_dir(reader, mos, "tank/home").unwrap().phys.props_zapobj;
+        let mut tx = Tx::create(MOS_OBJSET);
+        tx.hold_zap(root, true, None);
+        tx.hold_zap(home, true, None);
+        tx.assign(&mut dsl_pool).unwrap();
+        for &(object, name, value) in &[(root, "compression", 15),
+                                        (root, "canmount", 0),
+                                        (home, "atime", 0)] {
+            zap::add(reader, &mut dsl_pool, mos, object, name, value, &tx).unwrap();
+        }
+        tx.commit(&mut dsl_pool);
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    let dsl_pool = DslPool::open(reader, mos).unwrap();
+    let alice = dsl_pool.open_dataset(reader, mos, "tank/home/alice", false).unwrap();
+    let mut get = |name| alice.get_property(reader, mos, "tank", name).unwrap();
+    assert_eq!(get("compression"),
+               Some((PropValue::Index("lz4", 15), PropSource::Inherited("tank".to_owned()))));
+    assert_eq!(get("atime"),
+               Some((PropValue::Index("off", 0), PropSource::Inherited("tank/home".to_owned()))));
+    // canmount is never inherited
+    assert_eq!(get("canmount"), Some((PropValue::Index("on", 1), PropSource::Default)));
+    assert_eq!(get("recordsize"), Some((PropValue::Number(128 << 10), PropSource::Default)));
+    assert_eq!(get("mountpoint").unwrap().0.as_str(), Some("/tank/home/alice"));
+    assert_eq!(get("quota"), Some((PropValue::Number(0), PropSource::Default)));
+    assert_eq!(get("com.example:owner"), None);
+    assert!(alice.get_property(reader, mos, "tank", "colour").is_err());
+}
+
+#[test]
+fn test_set_property() {
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_set_property");
+    let get = |spa: &mut spa::Spa, name: &str, prop| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let dataset = dsl_pool.open_dataset(reader, mos, name, false).unwrap();
+        dataset.get_property(reader, mos, "tank", prop).unwrap().unwrap()
+    };
+
+    // Set on a dataset created in the same txg
+    spa.create_dataset("tank/home").unwrap();
+    let lz4 = PropValue::Index("lz4", 15);
+    spa.set_property("tank", "compression", &PropChange::Set(lz4.clone())).unwrap();
+    spa.set_property("tank/home", "copies", &PropChange::Receive(PropValue::Number(2))).unwrap();
+    spa.set_property("tank/home", "atime", &PropChange::Set(PropValue::Index("off", 0))).unwrap();
+    spa.set_property("tank/home", "quota", &PropChange::Set(PropValue::Number(1 << 30))).unwrap();
+    assert_eq!(spa.set_property("tank", "copies", &PropChange::Set(lz4.clone())),
+               Err(zfs::Error::Invalid));
+    assert_eq!(spa.set_property("tank", "colour", &PropChange::Inherit),
+               Err(zfs::Error::Invalid));
+    let legacy = PropValue::String("legacy".to_owned());
+    assert_eq!(spa.set_property("tank", "mountpoint", &PropChange::Set(legacy)),
+               Err(zfs::Error::UnsupportedFeature));
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let inherited = PropSource::Inherited("tank".to_owned());
+    assert_eq!(get(&mut spa, "tank/home", "compression"), (lz4.clone(), inherited.clone()));
+    assert_eq!(get(&mut spa, "tank/home", "copies"),
+               (PropValue::Number(2), PropSource::Received));
+    assert_eq!(get(&mut spa, "tank/home", "atime"),
+               (PropValue::Index("off", 0), PropSource::Local));
+    assert_eq!(get(&mut spa, "tank/home", "quota"),
+               (PropValue::Number(1 << 30), PropSource::Local));
+
+    // A local value hides the received one until it's inherited back to it, and a set value
+    // replaces the one before
+    spa.set_property("tank/home", "copies", &PropChange::Set(PropValue::Number(3))).unwrap();
+    spa.set_property("tank/home", "atime", &PropChange::Set(PropValue::Index("on", 1))).unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+    assert_eq!(get(&mut spa, "tank/home", "copies"),
+               (PropValue::Number(3), PropSource::Local));
+    assert_eq!(get(&mut spa, "tank/home", "atime"),
+               (PropValue::Index("on", 1), PropSource::Local));
+    spa.set_property("tank/home", "copies", &PropChange::Inherit).unwrap();
+    spa.set_property("tank/home", "quota", &PropChange::Inherit).unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+    assert_eq!(get(&mut spa, "tank/home", "copies"),
+               (PropValue::Number(1), PropSource::Default));
+    assert_eq!(get(&mut spa, "tank/home", "quota"),
+               (PropValue::Number(0), PropSource::Default));
+    spa.set_property("tank/home", "copies", &PropChange::RevertToReceived).unwrap();
+    spa.set_property("tank", "compression", &PropChange::Inherit).unwrap();
+    spa.sync(&mut alloc).unwrap().unwrap();
+    assert_eq!(get(&mut spa, "tank/home", "copies"),
+               (PropValue::Number(2), PropSource::Received));
+    assert_eq!(get(&mut spa, "tank/home", "compression"),
+               (PropValue::Index("off", 2), PropSource::Default));
+}
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use super::from_bytes::{Decoder, FromBytes};
+
 #[derive(Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[repr(packed)]
 pub struct DVAddr {
@@ -13,20 +15,47 @@ impl DVAddr {
         self.offset() + 0x2000
     }
 
+    /// Unused DVA slots in a block pointer are all zeros
+    pub fn is_empty(&self) -> bool {
+        self.vdev == 0 && self.offset == 0
+    }
+
+    /// Whether the DVA points at a gang header rather than at the data
     pub fn gang(&self) -> bool {
-        if self.offset & 0x8000000000000000 == 1 {
-            true
-        } else {
-            false
-        }
+        self.offset & 0x8000000000000000 != 0
     }
 
+    /// The top-level vdev the DVA is on
+    pub fn vdev_id(&self) -> u64 {
+        self.vdev >> 32
+    }
+
+    /// The RAID-Z layout of the allocation (its "grid"), 0 everywhere else
+    pub fn grid(&self) -> u8 {
+        (self.vdev >> 24) as u8
+    }
+
+    /// Offset on the vdev in sectors, past the labels and boot block. The gang bit isn't part of
+    /// it.
     pub fn offset(&self) -> u64 {
         self.offset & 0x7FFFFFFFFFFFFFFF
     }
 
+    /// Allocated size in sectors. Unlike the sizes in a block pointer, it's stored without a bias
+    /// of one: a one sector allocation is stored as 1, and 0 means nothing was allocated.
     pub fn asize(&self) -> u64 {
-        (self.vdev & 0xFFFFFF) + 1
+        self.vdev & 0xFFFFFF
+    }
+}
+
+impl FromBytes for DVAddr {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let vdev = decoder.u64();
+        let offset = decoder.u64();
+        DVAddr {
+            vdev: vdev,
+            offset: offset,
+        }
     }
 }
 
@@ -40,3 +69,42 @@ impl fmt::Debug for DVAddr {
         Ok(())
     }
 }
+
+#[test]
+fn test_dva_encoding() {
+    // DVAs in zdb's notation, <vdev:offset:asize> with the offset and asize in bytes, along with
+    // the two words they're stored as
+    let dvas = [((0, 0x21a00, 0x200), 0x0000000000000001, 0x000000000000010D),
+                ((0, 0x3fa000, 0x1000), 0x0000000000000008, 0x0000000000001FD0),
+                ((1, 0x4a2c00, 0x20000), 0x0000000100000100, 0x0000000000002516),
+                ((3, 0x1f0000000, 0x1fffffe00), 0x0000000300FFFFFF, 0x0000000000F80000)];
+    for &((vdev, offset, asize), word0, word1) in &dvas {
+        let dva = DVAddr {
+            vdev: word0,
+            offset: word1,
+        };
+        assert_eq!((dva.vdev_id(), dva.offset() << 9, dva.asize() << 9), (vdev, offset, asize));
+        assert_eq!((dva.grid(), dva.gang(), dva.is_empty()), (0, false, false));
+        assert_eq!(dva.sector(), (offset >> 9) + 0x2000);
+    }
+
+    // A gang header: the gang bit is the top bit of the offset word and doesn't count towards
+    // the offset, and the asize is the header's
+    let dva = DVAddr {
+        vdev: 0x0000000200000001,
+        offset: 0x8000000000000480,
+    };
+    assert_eq!((dva.vdev_id(), dva.offset(), dva.asize(), dva.gang()), (2, 0x480, 1, true));
+    assert_eq!(dva.sector(), 0x2480);
+
+    // A RAID-Z grid byte sits between the vdev and the asize, and is in neither
+    let dva = DVAddr {
+        vdev: 0x00000001_2A_000003,
+        offset: 0x10,
+    };
+    assert_eq!((dva.vdev_id(), dva.grid(), dva.asize()), (1, 0x2A, 3));
+
+    let empty = DVAddr::from_bytes(&[0; 16]).unwrap();
+    assert!(empty.is_empty());
+    assert_eq!(empty.asize(), 0);
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::api::{Dataset, File, Pool};
+use super::spa::{ImportOptions, Rewind};
+use super::zpl::FileType;
+
+/// How much of a file is read from the pool at a time
+const EXTRACT_CHUNK_SIZE: usize = 1 << 20;
+
+const USAGE: &'static str = concat!("Usage: zfs extract [<option>...] <image>... datasets\n",
+                                    "       zfs extract [<option>...] <image>... ls [<path>]\n",
+                                    "       zfs extract [<option>...] <image>... ",
+                                    "get <path> <dest>\n",
+                                    "Options: -d <dataset>, -T <txg>, --rewind-to-checkpoint");
+
+/// What `zfs extract` was asked to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// List the datasets of the pool
+    Datasets,
+    /// List a directory
+    Ls(String),
+    /// Copy a file, symlink or directory tree out of the pool
+    Get(String, String),
+}
+
+/// The command line of `zfs extract`, the arguments after it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Args {
+    /// The pool's devices or image files
+    pub images: Vec<String>,
+    /// The dataset to read from, the root dataset if there's none
+    pub dataset: Option<String>,
+    /// Read the pool as it was at an earlier txg, or at its checkpoint
+    pub rewind: Option<Rewind>,
+    pub command: Command,
+}
+
+impl Args {
+    /// Options first, then the images up to the command
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut dataset = None;
+        let mut rewind = None;
+        let mut i = 0;
+        while i < args.len() && args[i].starts_with('-') {
+            match &args[i][..] {
+                "-d" if i + 1 < args.len() => {
+                    dataset = Some(args[i + 1].clone());
+                    i += 2;
+                }
+                "-T" if i + 1 < args.len() => {
+                    let txg = try!(args[i + 1].parse().map_err(|_| USAGE.to_owned()));
+                    rewind = Some(Rewind::Txg(txg));
+                    i += 2;
+                }
+                "--rewind-to-checkpoint" => {
+                    rewind = Some(Rewind::Checkpoint);
+                    i += 1;
+                }
+                _ => return Err(USAGE.to_owned()),
+            }
+        }
+
+        let command_at = try!(args.iter()
+                                  .skip(i)
+                                  .position(|arg| ["datasets", "ls", "get"].contains(&&arg[..]))
+                                  .map(|at| at + i)
+                                  .ok_or(USAGE.to_owned()));
+        let command = match (&args[command_at][..], &args[command_at + 1..]) {
+            ("datasets", &[]) => Command::Datasets,
+            ("ls", &[ref path]) => Command::Ls(path.clone()),
+            ("ls", &[]) => Command::Ls("/".to_owned()),
+            ("get", &[ref path, ref dest]) => Command::Get(path.clone(), dest.clone()),
+            _ => return Err(USAGE.to_owned()),
+        };
+        if command_at == i {
+            return Err(USAGE.to_owned());
+        }
+        Ok(Args {
+            images: args[i..command_at].to_vec(),
+            dataset: dataset,
+            rewind: rewind,
+            command: command,
+        })
+    }
+}
+
+/// Totals of a `get`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExtractStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+    /// What couldn't be copied, with why: devices, fifos and sockets aren't, and neither is what
+    /// can't be read from the pool or written to the destination
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Run `zfs extract` with the arguments after it, writing what it lists and its report to
+/// `out`
+pub fn run(args: &[String], out: &mut Write) -> Result<(), String> {
+    let args = try!(Args::parse(args));
+    let images: Vec<&str> = args.images.iter().map(|image| &image[..]).collect();
+    let options = ImportOptions {
+        read_only: true,
+        rewind: args.rewind,
+        ..ImportOptions::default()
+    };
+    let mut pool = try!(Pool::import_with(&images, &options)
+                            .map_err(|e| format!("Import failed: {}", e)));
+    let name = args.dataset.clone().unwrap_or_else(|| pool.name().to_owned());
+
+    match args.command {
+        Command::Datasets => {
+            for dataset in try!(pool.datasets().map_err(|e| e.to_string())) {
+                try!(writeln!(out, "{}", dataset).map_err(|e| e.to_string()));
+            }
+        }
+        Command::Ls(ref path) => {
+            let dataset = try!(pool.dataset(&name).map_err(|e| format!("{}: {}", name, e)));
+            let entries = try!(dataset.read_dir(&mut pool, path)
+                                      .map_err(|e| format!("{}: {}", path, e)));
+            for entry in entries {
+                let suffix = if entry.file_type == FileType::Directory { "/" } else { "" };
+                try!(writeln!(out, "{}{}", entry.name, suffix).map_err(|e| e.to_string()));
+            }
+        }
+        Command::Get(ref path, ref dest) => {
+            let dataset = try!(pool.dataset(&name).map_err(|e| format!("{}: {}", name, e)));
+            let mut stats = ExtractStats::default();
+            try!(extract(&mut pool, &dataset, path, Path::new(dest), &mut stats)
+                     .map_err(|e| format!("{}: {}", path, e)));
+            for &(ref path, ref why) in &stats.skipped {
+                try!(writeln!(out, "Skipped {}: {}", path, why).map_err(|e| e.to_string()));
+            }
+            try!(writeln!(out,
+                          "{} files, {} directories, {} symlinks, {} bytes",
+                          stats.files,
+                          stats.directories,
+                          stats.symlinks,
+                          stats.bytes)
+                     .map_err(|e| e.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Copy `path` of `dataset` to `dest`: a file, a symlink, or a directory along with everything
+/// under it. Only `path` itself has to be there; what under it can't be copied is skipped, and
+/// the rest copied all the same. Permissions are kept, owners and times aren't.
+pub fn extract(pool: &mut Pool,
+               dataset: &Dataset,
+               path: &str,
+               dest: &Path,
+               stats: &mut ExtractStats)
+               -> Result<(), String> {
+    let stat = try!(dataset.stat(pool, path).map_err(|e| e.to_string()));
+    match FileType::from_mode(stat.mode) {
+        FileType::Regular => {
+            let file = try!(dataset.open(pool, path).map_err(|e| e.to_string()));
+            let bytes = try!(copy_file(pool, &file, dest));
+            stats.files += 1;
+            stats.bytes += bytes;
+        }
+        FileType::Directory => {
+            match fs::create_dir(dest) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && dest.is_dir() => {}
+                Err(e) => return Err(e.to_string()),
+            }
+            stats.directories += 1;
+            let entries = try!(dataset.read_dir(pool, path).map_err(|e| e.to_string()));
+            for entry in entries {
+                let child = format!("{}/{}", path.trim_right_matches('/'), entry.name);
+                if let Err(e) = extract(pool, dataset, &child, &dest.join(&entry.name), stats) {
+                    stats.skipped.push((child, e));
+                }
+            }
+        }
+        FileType::Symlink => {
+            let target = try!(dataset.read_link(pool, path).map_err(|e| e.to_string()));
+            try!(symlink(&target, dest).map_err(|e| e.to_string()));
+            stats.symlinks += 1;
+        }
+        file_type => {
+            stats.skipped.push((path.to_owned(), format!("{:?}", file_type)));
+            return Ok(());
+        }
+    }
+    set_mode(dest, stat.mode);
+    Ok(())
+}
+
+/// Copy `file` to a new file at `dest`, a chunk at a time. Returns the bytes copied.
+fn copy_file(pool: &mut Pool, file: &File, dest: &Path) -> Result<u64, String> {
+    let mut out = try!(fs::File::create(dest).map_err(|e| e.to_string()));
+    let mut buf = vec![0; EXTRACT_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < file.len() {
+        let read = try!(file.read_at(pool, offset, &mut buf).map_err(|e| e.to_string()));
+        if read == 0 {
+            break;
+        }
+        try!(out.write_all(&buf[..read]).map_err(|e| e.to_string()));
+        offset += read as u64;
+    }
+    Ok(offset)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, dest: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, dest)
+}
+
+#[cfg(not(unix))]
+fn symlink(_: &str, _: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Symlinks aren't supported here"))
+}
+
+/// Give `dest` the permission bits of `mode`. Symlinks have none of their own.
+#[cfg(unix)]
+fn set_mode(dest: &Path, mode: u64) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if FileType::from_mode(mode) != FileType::Symlink {
+        let _ = fs::set_permissions(dest, fs::Permissions::from_mode(mode as u32 & 0o7777));
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u64) {}
+
+#[test]
+fn test_extract_args() {
+    let args = |line: &str| {
+        let args: Vec<String> = line.split(' ').map(|arg| arg.to_owned()).collect();
+        Args::parse(&args)
+    };
+
+    assert_eq!(args("a.img b.img datasets"),
+               Ok(Args {
+                   images: vec!["a.img".to_owned(), "b.img".to_owned()],
+                   dataset: None,
+                   rewind: None,
+                   command: Command::Datasets,
+               }));
+    let get = args("-d tank/home a.img get /ticki/notes out").unwrap();
+    assert_eq!(get.dataset, Some("tank/home".to_owned()));
+    assert_eq!(get.command, Command::Get("/ticki/notes".to_owned(), "out".to_owned()));
+    assert_eq!(args("a.img ls").unwrap().command, Command::Ls("/".to_owned()));
+    assert_eq!(args("-T 120 a.img datasets").unwrap().rewind, Some(Rewind::Txg(120)));
+    assert_eq!(args("--rewind-to-checkpoint -d tank a.img datasets").unwrap().rewind,
+               Some(Rewind::Checkpoint));
+
+    // No images, an unknown option, too many arguments
+    assert!(args("ls /").is_err());
+    assert!(args("-x a.img datasets").is_err());
+    assert!(args("-T last a.img datasets").is_err());
+    assert!(args("a.img get /a").is_err());
+}
@@ -1,14 +1,179 @@
-use std::{mem, ptr};
+use std::mem;
 
-pub trait FromBytes: Sized {
-    fn from_bytes(data: &[u8]) -> Result<Self, &str> {
-        if data.len() >= mem::size_of::<Self>() {
-            let s = unsafe { ptr::read(data.as_ptr() as *const Self) };
-            Ok(s)
+use super::zfs;
+pub use super::zfs::DecodeError;
+
+/// The byte order an on-disk structure was written in. Pools are written in the byte order of
+/// the host writing them, and every block pointer records which one that was.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// From the byte order bit of a block pointer: 1 for little endian, 0 for big endian
+    pub fn from_bit(bit: u64) -> Self {
+        if bit == 1 {
+            ByteOrder::Little
+        } else {
+            ByteOrder::Big
+        }
+    }
+
+    pub fn host() -> Self {
+        if cfg!(target_endian = "little") {
+            ByteOrder::Little
         } else {
-            Err("Buffer not long enough.")
+            ByteOrder::Big
         }
     }
 }
 
-impl FromBytes for u64 {}
+/// Reads the fields of an on-disk structure one after the other, in the given byte order
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    order: ByteOrder,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8], order: ByteOrder) -> Self {
+        Decoder {
+            data: data,
+            pos: 0,
+            order: order,
+        }
+    }
+
+    pub fn order(&self) -> ByteOrder {
+        self.order
+    }
+
+    pub fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        bytes
+    }
+
+    fn uint(&mut self, len: usize) -> u64 {
+        let bytes = self.bytes(len);
+        match self.order {
+            ByteOrder::Little => bytes.iter().rev().fold(0, |value, &b| (value << 8) | b as u64),
+            ByteOrder::Big => bytes.iter().fold(0, |value, &b| (value << 8) | b as u64),
+        }
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        self.uint(2) as u16
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        self.uint(4) as u32
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        self.uint(8)
+    }
+
+    /// Fill a byte array field
+    pub fn fill(&mut self, out: &mut [u8]) {
+        let len = out.len();
+        out.copy_from_slice(self.bytes(len));
+    }
+
+    /// Fill a u64 array field
+    pub fn fill_u64(&mut self, out: &mut [u64]) {
+        for word in out {
+            *word = self.u64();
+        }
+    }
+}
+
+/// Swap the bytes of every u64 in `data`, e.g. to bring an array of u64s written big endian to
+/// little endian. A trailing partial word is left alone.
+pub fn swap_u64s(data: &mut [u8]) {
+    for word in data.chunks_mut(8).filter(|word| word.len() == 8) {
+        word.reverse();
+    }
+}
+
+/// An on-disk structure. Its in-memory layout mirrors the on-disk one (`size_of` is its on-disk
+/// size), but it's decoded field by field, so it's right whichever byte order the pool was
+/// written in and whatever the bytes hold. Structures with fields that can be sanity checked
+/// (magic numbers, enumerations, sizes) are refused if they don't pass.
+pub trait FromBytes: Sized {
+    /// Decode the fields in on-disk order. There are always `size_of::<Self>()` bytes left.
+    fn decode(decoder: &mut Decoder) -> Self;
+
+    /// Check the decoded fields hold values that make sense
+    fn validate(&self) -> Result<(), DecodeError> {
+        Ok(())
+    }
+
+    fn try_from_bytes_order(data: &[u8], order: ByteOrder) -> Result<Self, DecodeError> {
+        let needed = mem::size_of::<Self>();
+        if data.len() < needed {
+            return Err(DecodeError::TooShort {
+                needed: needed,
+                got: data.len(),
+            });
+        }
+        let value = Self::decode(&mut Decoder::new(data, order));
+        try!(value.validate());
+        Ok(value)
+    }
+
+    /// Decode a structure written little endian
+    fn try_from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        Self::try_from_bytes_order(data, ByteOrder::Little)
+    }
+
+    fn from_bytes_order(data: &[u8], order: ByteOrder) -> zfs::Result<Self> {
+        Ok(try!(Self::try_from_bytes_order(data, order)))
+    }
+
+    fn from_bytes(data: &[u8]) -> zfs::Result<Self> {
+        Ok(try!(Self::try_from_bytes(data)))
+    }
+}
+
+impl FromBytes for u16 {
+    fn decode(decoder: &mut Decoder) -> Self {
+        decoder.u16()
+    }
+}
+
+impl FromBytes for u32 {
+    fn decode(decoder: &mut Decoder) -> Self {
+        decoder.u32()
+    }
+}
+
+impl FromBytes for u64 {
+    fn decode(decoder: &mut Decoder) -> Self {
+        decoder.u64()
+    }
+}
+
+#[test]
+fn test_decode_byte_order() {
+    let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    assert_eq!(u64::from_bytes(&bytes), Ok(0x0807060504030201));
+    assert_eq!(u64::from_bytes_order(&bytes, ByteOrder::Big), Ok(0x0102030405060708));
+    assert!(u64::from_bytes(&bytes[3..]).is_err());
+
+    let mut decoder = Decoder::new(&bytes, ByteOrder::Big);
+    assert_eq!((decoder.u8(), decoder.u16(), decoder.u32()), (1, 0x0203, 0x04050607));
+    let mut rest = [0; 3];
+    decoder.fill(&mut rest);
+    assert_eq!(rest, [8, 9, 10]);
+
+    let mut words = bytes;
+    swap_u64s(&mut words);
+    assert_eq!(words, [8, 7, 6, 5, 4, 3, 2, 1, 9, 10]);
+}
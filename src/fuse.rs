@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::{self, Read, SeekFrom, Write};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use super::ZfsReader;
+use super::dsl_dataset::Dataset;
+use super::mount_opts::MountOptions;
+use super::zap;
+use super::zpl::{self, DirEntry, FileType, Stat};
+use super::zpl_handle::{HandleTable, OpenFlags};
+
+/// The version of the FUSE protocol we speak. Kernels with an older minor version get replies
+/// in their layout.
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+/// Largest read the kernel is told to ask for. Requests are read into a buffer with room for
+/// that much, plus the headers.
+const FUSE_MAX_READ: usize = 128 << 10;
+const FUSE_BUFFER_SIZE: usize = FUSE_MAX_READ + 4096;
+
+/// How long the kernel may keep the names and attributes it's given, in seconds. A pinned
+/// dataset doesn't change.
+const FUSE_TTL: u64 = 3600;
+
+/// The node ID the kernel knows the root directory by
+const FUSE_ROOT_ID: u64 = 1;
+
+// Opcodes of the requests we answer (`enum fuse_opcode`)
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// Requests that change the file system, all refused: the mount is read-only
+const FUSE_WRITES: &'static [u32] = &[4, // SETATTR
+                                      6, // SYMLINK
+                                      8, // MKNOD
+                                      9, // MKDIR
+                                      10, // UNLINK
+                                      11, // RMDIR
+                                      12, // RENAME
+                                      13, // LINK
+                                      16, // WRITE
+                                      21, // SETXATTR
+                                      24, // REMOVEXATTR
+                                      35, // CREATE
+                                      43, // FALLOCATE
+                                      45]; // RENAME2
+
+/// The data doesn't change, the kernel can keep it cached across opens
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Size of `fuse_in_header` and `fuse_out_header`
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+/// Size of `fuse_init_out` for protocol versions before 7.23, and since
+const INIT_OUT_COMPAT_SIZE: usize = 24;
+const INIT_OUT_SIZE: usize = 64;
+
+// errno values, as Linux numbers them
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const ENODEV: i32 = 19;
+const EROFS: i32 = 30;
+const ENOSYS: i32 = 38;
+const EPROTO: i32 = 71;
+
+// mount(2) flags
+const MS_RDONLY: c_ulong = 1;
+const MS_NOSUID: c_ulong = 2;
+const MS_NODEV: c_ulong = 4;
+const MS_NOATIME: c_ulong = 1024;
+
+extern "C" {
+    #[link_name = "mount"]
+    fn sys_mount(source: *const c_char,
+                 target: *const c_char,
+                 fstype: *const c_char,
+                 flags: c_ulong,
+                 data: *const c_void)
+                 -> c_int;
+    fn getuid() -> u32;
+    fn getgid() -> u32;
+}
+
+/// A request from the kernel: its `fuse_in_header`, and the opcode specific part after it
+#[derive(Debug, PartialEq)]
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < IN_HEADER_SIZE || u32_at(data, 0) as usize != data.len() {
+            return None;
+        }
+        Some(Request {
+            opcode: u32_at(data, 4),
+            unique: u64_at(data, 8),
+            nodeid: u64_at(data, 16),
+            body: &data[IN_HEADER_SIZE..],
+        })
+    }
+
+    /// The NUL terminated name in the body of a LOOKUP
+    fn name(&self) -> Option<&'a str> {
+        let end = self.body.iter().position(|&b| b == 0).unwrap_or(self.body.len());
+        ::std::str::from_utf8(&self.body[..end]).ok()
+    }
+}
+
+// FUSE structs are in host byte order, their fields naturally aligned
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    if let Some(field) = data.get(offset..offset + 4) {
+        bytes.copy_from_slice(field);
+    }
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    if let Some(field) = data.get(offset..offset + 8) {
+        bytes.copy_from_slice(field);
+    }
+    u64::from_ne_bytes(bytes)
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+/// A whole reply: the `fuse_out_header`, then `body`. Errors have no body.
+fn reply(unique: u64, result: Result<Vec<u8>, i32>) -> Vec<u8> {
+    let (error, body) = match result {
+        Ok(body) => (0, body),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut out = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+    put_u32(&mut out, (OUT_HEADER_SIZE + body.len()) as u32);
+    put_u32(&mut out, error as u32);
+    put_u64(&mut out, unique);
+    out.extend_from_slice(&body);
+    out
+}
+
+/// The reply to INIT: a kernel speaking a newer major version is asked for ours, one speaking an
+/// older one isn't served
+fn init(body: &[u8]) -> Result<Vec<u8>, i32> {
+    let (major, minor, max_readahead) = (u32_at(body, 0), u32_at(body, 4), u32_at(body, 8));
+    if major < FUSE_KERNEL_VERSION {
+        return Err(EPROTO);
+    }
+    let mut out = Vec::with_capacity(INIT_OUT_SIZE);
+    put_u32(&mut out, FUSE_KERNEL_VERSION);
+    if major > FUSE_KERNEL_VERSION {
+        put_u32(&mut out, FUSE_KERNEL_MINOR_VERSION);
+        out.resize(INIT_OUT_COMPAT_SIZE, 0);
+        return Ok(out);
+    }
+    let minor = if minor < FUSE_KERNEL_MINOR_VERSION { minor } else { FUSE_KERNEL_MINOR_VERSION };
+    put_u32(&mut out, minor);
+    put_u32(&mut out, max_readahead);
+    put_u32(&mut out, 0); // No optional capabilities
+    put_u32(&mut out, 0); // Default max_background and congestion_threshold
+    put_u32(&mut out, FUSE_MAX_READ as u32); // max_write, not that anything is written
+    put_u32(&mut out, 1); // Timestamps are to the nanosecond
+    out.resize(if minor < 23 { INIT_OUT_COMPAT_SIZE } else { INIT_OUT_SIZE }, 0);
+    Ok(out)
+}
+
+/// The `DT_*` type of a directory entry, the file type bits of its mode
+fn dirent_type(file_type: FileType) -> u32 {
+    match file_type {
+        FileType::Fifo => 1,
+        FileType::CharDevice => 2,
+        FileType::Directory => 4,
+        FileType::BlockDevice => 6,
+        FileType::Regular => 8,
+        FileType::Symlink => 10,
+        FileType::Socket => 12,
+        FileType::Unknown => 0,
+    }
+}
+
+/// Append a `fuse_dirent` for `entry` to a READDIR reply, unless that takes it past `size`.
+/// `next` is the offset to carry on from after it. Returns whether it fit.
+fn push_dirent(out: &mut Vec<u8>, size: usize, entry: &DirEntry, next: u64) -> bool {
+    let len = 24 + entry.name.len();
+    let padded = (len + 7) & !7;
+    if out.len() + padded > size {
+        return false;
+    }
+    put_u64(out, entry.object);
+    put_u64(out, next);
+    put_u32(out, entry.name.len() as u32);
+    put_u32(out, dirent_type(entry.file_type));
+    out.extend_from_slice(entry.name.as_bytes());
+    let padding = padded - len;
+    out.extend_from_slice(&[0; 8][..padding]);
+    true
+}
+
+/// `stat` as a `fuse_attr`, with the owners and mode `options` present it with. Under `noatime`
+/// the access time is the modification time.
+fn attr(stat: &Stat, options: &MountOptions) -> Vec<u8> {
+    let times = &stat.times;
+    let atime = if options.noatime { &times.mtime } else { &times.atime };
+    // Linux's `new_encode_dev`
+    let (major, minor) = stat.device().unwrap_or((0, 0));
+    let rdev = (minor & 0xFF) | (major << 8) | ((minor & !0xFF) << 12);
+
+    let mut out = Vec::with_capacity(88);
+    put_u64(&mut out, stat.object);
+    put_u64(&mut out, stat.size);
+    put_u64(&mut out, (stat.size + 511) / 512);
+    put_u64(&mut out, atime.secs);
+    put_u64(&mut out, times.mtime.secs);
+    put_u64(&mut out, times.ctime.secs);
+    put_u32(&mut out, atime.nsecs as u32);
+    put_u32(&mut out, times.mtime.nsecs as u32);
+    put_u32(&mut out, times.ctime.nsecs as u32);
+    put_u32(&mut out, options.map_mode(stat.mode));
+    put_u32(&mut out, stat.links as u32);
+    put_u32(&mut out, options.map_uid(stat.uid));
+    put_u32(&mut out, options.map_gid(stat.gid));
+    put_u32(&mut out, rdev as u32);
+    put_u32(&mut out, 128 << 10); // blksize, the default recordsize
+    put_u32(&mut out, 0);
+    out
+}
+
+/// A read-only file system served to the kernel over FUSE, from a ZPL dataset. Node IDs are the
+/// object numbers of the files, but for the root directory's, which the kernel knows as 1: the
+/// ZPL's object 1 is the master node, never a file.
+pub struct FuseFs {
+    dataset: Arc<Dataset>,
+    /// How the files are presented
+    options: MountOptions,
+    /// The object number of the root directory
+    root: u64,
+    handles: HandleTable,
+    /// The path each node was looked up by, to open it by
+    paths: HashMap<u64, String>,
+}
+
+impl FuseFs {
+    /// Serve `dataset`, which should have been opened pinned, with its mount options
+    pub fn new(reader: &mut ZfsReader,
+               dataset: Arc<Dataset>,
+               options: MountOptions)
+               -> Result<Self, String> {
+        let root = try!(zap::lookup(reader, &dataset.objset, zpl::MASTER_NODE_OBJ, "ROOT"));
+        let mut paths = HashMap::new();
+        paths.insert(FUSE_ROOT_ID, "/".to_owned());
+        Ok(FuseFs {
+            dataset: dataset,
+            options: options,
+            root: root,
+            handles: HandleTable::new(),
+            paths: paths,
+        })
+    }
+
+    fn object(&self, nodeid: u64) -> u64 {
+        if nodeid == FUSE_ROOT_ID { self.root } else { nodeid }
+    }
+
+    fn nodeid(&self, object: u64) -> u64 {
+        if object == self.root { FUSE_ROOT_ID } else { object }
+    }
+
+    fn path(&self, nodeid: u64) -> Result<&str, i32> {
+        self.paths.get(&nodeid).map(|path| &path[..]).ok_or(ENOENT)
+    }
+
+    fn stat(&self, reader: &mut ZfsReader, nodeid: u64) -> Result<Stat, i32> {
+        zpl::stat_object(reader, &self.dataset, self.object(nodeid)).map_err(|_| ENOENT)
+    }
+
+    /// Answer the request in `data`, as read from the FUSE device. Returns the reply to write
+    /// back, None for requests that don't get one (FORGET, INTERRUPT, and requests we can't
+    /// make sense of).
+    pub fn handle(&mut self, reader: &mut ZfsReader, data: &[u8]) -> Option<Vec<u8>> {
+        let request = match Request::parse(data) {
+            Some(request) => request,
+            None => return None,
+        };
+        let result = match request.opcode {
+            FUSE_INIT => init(request.body),
+            FUSE_LOOKUP => self.lookup(reader, &request),
+            FUSE_GETATTR => {
+                self.stat(reader, request.nodeid).map(|stat| {
+                    let mut out = Vec::new();
+                    put_u64(&mut out, FUSE_TTL);
+                    put_u64(&mut out, 0);
+                    out.extend_from_slice(&attr(&stat, &self.options));
+                    out
+                })
+            }
+            FUSE_READLINK => {
+                zpl::readlink_object(reader, &self.dataset, self.object(request.nodeid))
+                    .map(|target| target.into_bytes())
+                    .map_err(|_| EIO)
+            }
+            FUSE_OPEN | FUSE_OPENDIR => self.open(reader, &request),
+            FUSE_READ => self.read(reader, &request),
+            FUSE_READDIR => self.readdir(reader, &request),
+            FUSE_RELEASE | FUSE_RELEASEDIR => {
+                if self.handles.close(u64_at(request.body, 0)) {
+                    Ok(Vec::new())
+                } else {
+                    Err(EBADF)
+                }
+            }
+            FUSE_STATFS => {
+                // No space to speak of: nothing can be written
+                let mut out = vec![0; 40];
+                put_u32(&mut out, 512); // bsize
+                put_u32(&mut out, 255); // namelen
+                put_u32(&mut out, 512); // frsize
+                out.resize(80, 0);
+                Ok(out)
+            }
+            FUSE_DESTROY => Ok(Vec::new()),
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            opcode if FUSE_WRITES.contains(&opcode) => Err(EROFS),
+            // The kernel doesn't ask again for what we don't do
+            _ => Err(ENOSYS),
+        };
+        Some(reply(request.unique, result))
+    }
+
+    fn lookup(&mut self, reader: &mut ZfsReader, request: &Request) -> Result<Vec<u8>, i32> {
+        let name = try!(request.name().ok_or(ENOENT));
+        let parent = try!(self.path(request.nodeid)).trim_right_matches('/').to_owned();
+        let path = format!("{}/{}", parent, name);
+        // The kernel follows symlinks itself
+        let object = try!(zpl::lookup(reader, &self.dataset, &path, false).map_err(|_| ENOENT));
+        let stat = try!(zpl::stat_object(reader, &self.dataset, object).map_err(|_| ENOENT));
+        let nodeid = self.nodeid(object);
+        self.paths.insert(nodeid, path);
+
+        let mut out = Vec::with_capacity(128);
+        put_u64(&mut out, nodeid);
+        put_u64(&mut out, stat.gen);
+        put_u64(&mut out, FUSE_TTL); // entry_valid
+        put_u64(&mut out, FUSE_TTL); // attr_valid
+        put_u64(&mut out, 0);
+        out.extend_from_slice(&attr(&stat, &self.options));
+        Ok(out)
+    }
+
+    /// Opening for writing is refused: with EACCES when the mode presented has no write bits
+    /// (a `ro` mount, or the umask), as the kernel would, EROFS otherwise
+    fn open(&mut self, reader: &mut ZfsReader, request: &Request) -> Result<Vec<u8>, i32> {
+        let bits = u32_at(request.body, 0);
+        let path = try!(self.path(request.nodeid)).to_owned();
+        let mut flags = match OpenFlags::from_bits(bits) {
+            Ok(flags) => flags,
+            Err(_) => {
+                let stat = try!(self.stat(reader, request.nodeid));
+                let writable = self.options.map_mode(stat.mode) & 0o222 != 0;
+                return Err(if writable { EROFS } else { EACCES });
+            }
+        };
+        flags.directory = request.opcode == FUSE_OPENDIR;
+        let handle = try!(self.handles
+                              .open(reader, &self.dataset, &path, flags)
+                              .map_err(|_| ENOENT));
+        let mut out = Vec::with_capacity(16);
+        put_u64(&mut out, handle);
+        put_u32(&mut out, FOPEN_KEEP_CACHE);
+        put_u32(&mut out, 0);
+        Ok(out)
+    }
+
+    fn read(&mut self, reader: &mut ZfsReader, request: &Request) -> Result<Vec<u8>, i32> {
+        let file = try!(self.handles.get(u64_at(request.body, 0)).ok_or(EBADF));
+        let (offset, size) = (u64_at(request.body, 8), u32_at(request.body, 16) as usize);
+        file.read_at(reader, offset, size).map_err(|_| EIO)
+    }
+
+    /// Entries from the offset of the request on, as many as fit. Offsets are entry indices.
+    fn readdir(&mut self, reader: &mut ZfsReader, request: &Request) -> Result<Vec<u8>, i32> {
+        let dir = try!(self.handles.get(u64_at(request.body, 0)).ok_or(EBADF));
+        let (offset, size) = (u64_at(request.body, 8), u32_at(request.body, 16) as usize);
+        try!(dir.seek(SeekFrom::Start(offset)).map_err(|_| EIO));
+        // Entries take up at least 32 bytes each
+        let entries = try!(dir.readdir(reader, size / 32).map_err(|_| EIO));
+        let mut out = Vec::with_capacity(size);
+        for (i, entry) in entries.iter().enumerate() {
+            if !push_dirent(&mut out, size, entry, offset + i as u64 + 1) {
+                break;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Answer the requests read from `dev`, the FUSE device of a mount, until the file system is
+/// unmounted
+pub fn serve<D: Read + Write>(dev: &mut D,
+                              reader: &mut ZfsReader,
+                              fs: &mut FuseFs)
+                              -> io::Result<()> {
+    let mut buf = vec![0; FUSE_BUFFER_SIZE];
+    loop {
+        // Every read is a whole request
+        let len = match dev.read(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if e.raw_os_error() == Some(ENODEV) => return Ok(()),
+            // The request was interrupted before we got to read it
+            Err(ref e) if e.raw_os_error() == Some(ENOENT) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let destroy = Request::parse(&buf[..len]).map_or(false, |r| r.opcode == FUSE_DESTROY);
+        if let Some(reply) = fs.handle(reader, &buf[..len]) {
+            // The request may have been interrupted since, then its reply is refused
+            match dev.write(&reply) {
+                Err(ref e) if e.raw_os_error() == Some(ENOENT) => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+        if destroy {
+            return Ok(());
+        }
+    }
+}
+
+/// Mount `dataset` read-only at `mountpoint` with `options` and serve it until it's unmounted
+/// (`umount <mountpoint>`). Mounting without `fusermount` takes root.
+pub fn mount(reader: &mut ZfsReader,
+             dataset: Arc<Dataset>,
+             options: MountOptions,
+             mountpoint: &str)
+             -> Result<(), String> {
+    let flags = MS_RDONLY | MS_NOSUID | MS_NODEV | if options.noatime { MS_NOATIME } else { 0 };
+    let mut fs = try!(FuseFs::new(reader, dataset, options));
+    let mut dev = try!(OpenOptions::new()
+                           .read(true)
+                           .write(true)
+                           .open("/dev/fuse")
+                           .map_err(|e| format!("/dev/fuse: {}", e)));
+    let (uid, gid) = unsafe { (getuid(), getgid()) };
+    let options = format!("fd={},rootmode=40000,user_id={},group_id={},default_permissions,\
+                           allow_other,max_read={}",
+                          dev.as_raw_fd(),
+                          uid,
+                          gid,
+                          FUSE_MAX_READ);
+    let source = CString::new("zfs").unwrap();
+    let fstype = CString::new("fuse").unwrap();
+    let target = try!(CString::new(mountpoint).map_err(|e| e.to_string()));
+    let options = CString::new(options).unwrap();
+    let mounted = unsafe {
+        sys_mount(source.as_ptr(),
+                  target.as_ptr(),
+                  fstype.as_ptr(),
+                  flags,
+                  options.as_ptr() as *const c_void)
+    };
+    if mounted != 0 {
+        return Err(format!("Failed to mount {}: {}", mountpoint, io::Error::last_os_error()));
+    }
+    serve(&mut dev, reader, &mut fs).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_fuse_protocol() {
+    // INIT from a 7.27 kernel
+    let mut request = Vec::new();
+    put_u32(&mut request, 56);
+    put_u32(&mut request, FUSE_INIT);
+    put_u64(&mut request, 1);
+    put_u64(&mut request, 0);
+    request.extend_from_slice(&[0; 16]);
+    for &word in &[7, 27, 128 << 10, 0] {
+        put_u32(&mut request, word);
+    }
+    let parsed = Request::parse(&request).unwrap();
+    assert_eq!((parsed.opcode, parsed.unique, parsed.body.len()), (FUSE_INIT, 1, 16));
+    let out = reply(parsed.unique, init(parsed.body));
+    assert_eq!(out.len(), OUT_HEADER_SIZE + INIT_OUT_SIZE);
+    assert_eq!((u32_at(&out, 0) as usize, u32_at(&out, 4), u64_at(&out, 8)), (out.len(), 0, 1));
+    assert_eq!((u32_at(&out, 16), u32_at(&out, 20), u32_at(&out, 24)), (7, 27, 128 << 10));
+    // A newer major version is asked for ours, an older one refused
+    let mut newer = Vec::new();
+    put_u32(&mut newer, 8);
+    put_u32(&mut newer, 0);
+    assert_eq!(init(&newer).unwrap().len(), INIT_OUT_COMPAT_SIZE);
+    let mut old = Vec::new();
+    put_u32(&mut old, 6);
+    let out = reply(2, init(&old));
+    assert_eq!((u32_at(&out, 0), u32_at(&out, 4) as i32, u64_at(&out, 8)), (16, -EPROTO, 2));
+
+    // Cut short
+    assert_eq!(Request::parse(&request[..50]), None);
+
+    let entry = DirEntry {
+        name: "notes".to_owned(),
+        object: 9,
+        file_type: FileType::Regular,
+    };
+    let mut out = Vec::new();
+    assert!(push_dirent(&mut out, 64, &entry, 1));
+    assert_eq!(out.len(), 32);
+    assert_eq!((u64_at(&out, 0), u64_at(&out, 8), u32_at(&out, 16), u32_at(&out, 20)),
+               (9, 1, 5, 8));
+    assert_eq!(&out[24..29], b"notes");
+    assert!(!push_dirent(&mut out, 63, &entry, 2));
+
+    use super::znode::{Timestamp, Times};
+
+    let time = |secs| Timestamp { secs: secs, nsecs: 5 };
+    let stat = Stat {
+        object: 9,
+        mode: 0o100664,
+        uid: 0,
+        gid: 20,
+        size: 5,
+        links: 1,
+        parent: 4,
+        gen: 7,
+        rdev: 0,
+        flags: 0,
+        times: Times {
+            atime: time(1),
+            mtime: time(2),
+            ctime: time(3),
+            crtime: time(0),
+        },
+        blocks: 1,
+    };
+    let out = attr(&stat, &MountOptions::new());
+    assert_eq!(out.len(), 88);
+    assert_eq!((u64_at(&out, 24), u32_at(&out, 60)), (1, 0o100664));
+    assert_eq!((u32_at(&out, 68), u32_at(&out, 72)), (0, 20));
+    let mut options = MountOptions::new();
+    options.apply("noatime,ro,umask=007,uid=0:1000,gid=20:100").unwrap();
+    let out = attr(&stat, &options);
+    assert_eq!((u64_at(&out, 24), u32_at(&out, 60)), (2, 0o100440));
+    assert_eq!((u32_at(&out, 68), u32_at(&out, 72)), (1000, 100));
+}
@@ -0,0 +1,247 @@
+use std::{cmp, str};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+use super::ZfsReader;
+use super::dsl_dataset::Dataset;
+use super::zpl;
+
+/// How much of a file is read and sent at a time
+const HTTP_CHUNK_SIZE: usize = 128 << 10;
+
+/// Longest request line or header we take
+const HTTP_MAX_LINE: usize = 8 << 10;
+
+/// The byte range a `Range` header asks for, with the end inclusive like in the header
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ByteRange {
+    /// `bytes=start-end`, or `bytes=start-` to the end of the file
+    From(u64, Option<u64>),
+    /// `bytes=-len`: the last `len` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// A `Range` header's value. Only single ranges are taken, a server is free to ignore the
+    /// header and send the whole file instead.
+    pub fn parse(value: &str) -> Option<Self> {
+        let range = value.trim();
+        if !range.starts_with("bytes=") || range.contains(',') {
+            return None;
+        }
+        let mut bounds = range[6..].splitn(2, '-');
+        let start = bounds.next().unwrap_or("").trim();
+        let end = bounds.next().unwrap_or("").trim();
+        match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) if start <= end => Some(ByteRange::From(start, Some(end))),
+            (Ok(start), Err(_)) if end.is_empty() => Some(ByteRange::From(start, None)),
+            (Err(_), Ok(len)) if start.is_empty() => Some(ByteRange::Suffix(len)),
+            _ => None,
+        }
+    }
+
+    /// The `start..end` bytes of a file of `size` bytes the range covers, None if it's past the
+    /// end of the file
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::From(start, end) => {
+                (start, end.map(|end| cmp::min(end.saturating_add(1), size)).unwrap_or(size))
+            }
+            ByteRange::Suffix(len) => (size - cmp::min(len, size), size),
+        };
+        if start < end { Some((start, end)) } else { None }
+    }
+}
+
+/// The parts of a request we look at
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The path in the dataset, percent-decoded
+    pub path: String,
+    pub range: Option<ByteRange>,
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    try!(Read::take(&mut *input, HTTP_MAX_LINE as u64).read_until(b'\n', &mut line));
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+    }
+    String::from_utf8(line)
+        .map(|line| line.trim_right().to_owned())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request isn't UTF-8"))
+}
+
+/// `%XX` escapes of a URL path decoded. The query string, if any, is dropped.
+fn percent_decode(path: &str) -> Option<String> {
+    let path = path.split('?').next().unwrap_or("");
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.bytes();
+    while let Some(b) = rest.next() {
+        if b == b'%' {
+            let hex = [rest.next().unwrap_or(0), rest.next().unwrap_or(0)];
+            // from_str_radix takes a sign too
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            match str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(b) => bytes.push(b),
+                None => return None,
+            }
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Read a request's head from `input`. Requests we can't make sense of are an `InvalidData`
+/// error.
+pub fn parse_request<R: BufRead>(input: &mut R) -> io::Result<Request> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad request");
+    let line = try!(read_line(input));
+    let mut parts = line.split(' ');
+    let method = try!(parts.next().ok_or_else(&invalid)).to_owned();
+    let target = try!(parts.next().ok_or_else(&invalid));
+    let path = try!(percent_decode(target).ok_or_else(&invalid));
+
+    let mut range = None;
+    loop {
+        let header = try!(read_line(input));
+        if header.is_empty() {
+            break;
+        }
+        let mut field = header.splitn(2, ':');
+        let name = field.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("range") {
+            range = field.next().and_then(ByteRange::parse);
+        }
+    }
+    Ok(Request {
+        method: method,
+        path: path,
+        range: range,
+    })
+}
+
+fn write_head<W: Write>(out: &mut W, status: &str, headers: &[(&str, String)]) -> io::Result<()> {
+    try!(write!(out, "HTTP/1.1 {}\r\nConnection: close\r\n", status));
+    for &(name, ref value) in headers {
+        try!(write!(out, "{}: {}\r\n", name, value));
+    }
+    write!(out, "\r\n")
+}
+
+/// Answer `request` with the file it names in `dataset`, or the part of it it asks for
+pub fn respond<W: Write>(reader: &mut ZfsReader,
+                         dataset: &Dataset,
+                         request: &Request,
+                         out: &mut W)
+                         -> io::Result<()> {
+    let head = request.method == "HEAD";
+    if request.method != "GET" && !head {
+        return write_head(out,
+                          "405 Method Not Allowed",
+                          &[("Allow", "GET, HEAD".to_owned()),
+                            ("Content-Length", "0".to_owned())]);
+    }
+    let file = match zpl::open(reader, dataset, &request.path) {
+        Ok(file) => file,
+        Err(_) => return write_head(out, "404 Not Found", &[("Content-Length", "0".to_owned())]),
+    };
+
+    let (status, start, end) = match request.range {
+        None => ("200 OK", 0, file.size),
+        Some(range) => {
+            match range.resolve(file.size) {
+                Some((start, end)) => ("206 Partial Content", start, end),
+                None => {
+                    return write_head(out,
+                                      "416 Range Not Satisfiable",
+                                      &[("Content-Range", format!("bytes */{}", file.size)),
+                                        ("Content-Length", "0".to_owned())]);
+                }
+            }
+        }
+    };
+    let mut headers = vec![("Accept-Ranges", "bytes".to_owned()),
+                           ("Content-Type", "application/octet-stream".to_owned()),
+                           ("Content-Length", (end - start).to_string())];
+    if request.range.is_some() {
+        headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, file.size)));
+    }
+    try!(write_head(out, status, &headers));
+    if head {
+        return Ok(());
+    }
+
+    // The head is out already, a failed read can only cut the body short
+    let mut buf = vec![0; HTTP_CHUNK_SIZE];
+    let mut offset = start;
+    while offset < end {
+        let len = cmp::min(buf.len() as u64, end - offset) as usize;
+        let read = try!(file.read_into(reader, offset, &mut buf[..len])
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank"));
+        }
+        try!(out.write_all(&buf[..read]));
+        offset += read as u64;
+    }
+    out.flush()
+}
+
+/// Serve the files of `dataset` to the clients connecting to `listener`, read only, one
+/// connection and one request at a time. Only returns if accepting a connection fails.
+pub fn serve(listener: &TcpListener, reader: &mut ZfsReader, dataset: &Dataset) -> io::Result<()> {
+    loop {
+        let (conn, _) = try!(listener.accept());
+        let mut input = BufReader::new(try!(conn.try_clone()));
+        let mut out = conn;
+        // A client going away is no reason to stop serving the others
+        let _ = match parse_request(&mut input) {
+            Ok(request) => respond(reader, dataset, &request, &mut out),
+            Err(_) => {
+                write_head(&mut out, "400 Bad Request", &[("Content-Length", "0".to_owned())])
+            }
+        };
+    }
+}
+
+#[test]
+fn test_http_request() {
+    let mut input = &b"GET /backups/disk%20image.raw?x=1 HTTP/1.1\r\nHost: tank\r\n\
+                       range: bytes=100-\r\n\r\n"[..];
+    assert_eq!(parse_request(&mut input).unwrap(),
+               Request {
+                   method: "GET".to_owned(),
+                   path: "/backups/disk image.raw".to_owned(),
+                   range: Some(ByteRange::From(100, None)),
+               });
+    let mut input = &b"GET /%zz HTTP/1.1\r\n\r\n"[..];
+    assert!(parse_request(&mut input).is_err());
+    let mut input = &b"GET /%+F HTTP/1.1\r\n\r\n"[..];
+    assert!(parse_request(&mut input).is_err());
+    // The largest end there is, which is past the end of any file
+    let mut input = &b"GET / HTTP/1.1\r\nRange: bytes=0-18446744073709551615\r\n\r\n"[..];
+    let range = parse_request(&mut input).unwrap().range.unwrap();
+    assert_eq!(range, ByteRange::From(0, Some(u64::max_value())));
+    assert_eq!(range.resolve(1000), Some((0, 1000)));
+    // Cut off before the end of the head
+    let mut input = &b"GET / HTTP/1.1\r\nRange: bytes=0-1"[..];
+    assert!(parse_request(&mut input).is_err());
+
+    assert_eq!(ByteRange::parse("bytes=0-499"), Some(ByteRange::From(0, Some(499))));
+    assert_eq!(ByteRange::parse("bytes=-500"), Some(ByteRange::Suffix(500)));
+    assert_eq!(ByteRange::parse("bytes=500-499"), None);
+    assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+    assert_eq!(ByteRange::parse("lines=0-1"), None);
+
+    assert_eq!(ByteRange::From(0, Some(499)).resolve(1000), Some((0, 500)));
+    assert_eq!(ByteRange::From(900, Some(2000)).resolve(1000), Some((900, 1000)));
+    assert_eq!(ByteRange::From(1000, None).resolve(1000), None);
+    assert_eq!(ByteRange::Suffix(500).resolve(1000), Some((500, 1000)));
+    assert_eq!(ByteRange::Suffix(5000).resolve(1000), Some((0, 1000)));
+    assert_eq!(ByteRange::Suffix(0).resolve(1000), None);
+}
@@ -0,0 +1,108 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::fmt::Write;
+
+/// How a statistic behaves over time
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KstatKind {
+    /// Only ever goes up
+    Counter,
+    /// Can go up and down
+    Gauge,
+}
+
+impl KstatKind {
+    fn to_str(self) -> &'static str {
+        match self {
+            KstatKind::Counter => "counter",
+            KstatKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// A single named statistic, optionally qualified by labels (e.g. the vdev it belongs to)
+#[derive(Clone, Debug)]
+pub struct Kstat {
+    pub name: String,
+    pub help: &'static str,
+    pub kind: KstatKind,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// A snapshot of statistics gathered from the various subsystems (ARC, zio, vdevs, scrub).
+/// Subsystems add their numbers with `counter`/`gauge`; consumers render the whole thing at once.
+pub struct Registry {
+    stats: Vec<Kstat>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry { stats: Vec::new() }
+    }
+
+    pub fn counter(&mut self, name: &str, help: &'static str, value: u64) {
+        self.add(name, help, KstatKind::Counter, Vec::new(), value as f64);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &'static str, value: u64) {
+        self.add(name, help, KstatKind::Gauge, Vec::new(), value as f64);
+    }
+
+    pub fn add(&mut self,
+               name: &str,
+               help: &'static str,
+               kind: KstatKind,
+               labels: Vec<(String, String)>,
+               value: f64) {
+        self.stats.push(Kstat {
+            name: name.to_owned(),
+            help: help,
+            kind: kind,
+            labels: labels,
+            value: value,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Kstat> {
+        self.stats.iter().find(|stat| stat.name == name)
+    }
+
+    pub fn stats(&self) -> &[Kstat] {
+        &self.stats
+    }
+
+    /// Render every statistic in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut described: Vec<&str> = Vec::new();
+
+        for stat in &self.stats {
+            // HELP and TYPE are only written once per metric family
+            if !described.contains(&&stat.name[..]) {
+                described.push(&stat.name);
+                let _ = writeln!(out, "# HELP {} {}", stat.name, stat.help);
+                let _ = writeln!(out, "# TYPE {} {}", stat.name, stat.kind.to_str());
+            }
+
+            out.push_str(&stat.name);
+            if !stat.labels.is_empty() {
+                out.push('{');
+                for (i, &(ref key, ref value)) in stat.labels.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    let _ = write!(out, "{}=\"{}\"", key, escape_label(value));
+                }
+                out.push('}');
+            }
+            let _ = writeln!(out, " {}", stat.value);
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
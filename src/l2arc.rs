@@ -0,0 +1,310 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::checksum;
+use super::djb2::Djb2;
+use super::dvaddr::DVAddr;
+use super::kstat;
+use super::util;
+use super::vdev_io::{self, Vdev};
+
+/// Most bytes written to the cache devices per second by default, like `l2arc_write_max`
+pub const L2ARC_WRITE_MAX: u64 = 8 << 20;
+
+/// Where a block is on the cache devices
+#[derive(Copy, Clone)]
+struct L2Entry {
+    device: usize,
+    offset: u64,
+    size: usize,
+    /// Whether it's the decompressed block, rather than the block as it is on disk
+    decoded: bool,
+    /// Fletcher 4 of the data as it was written, so a block that's been overwritten since, or
+    /// damaged, isn't served
+    checksum: [u64; 4],
+}
+
+/// The room on a cache device. Blocks are written one after the other, and when the end of the
+/// device is reached, from the start again over the oldest ones.
+struct L2Space {
+    size: u64,
+    /// Where the next block goes
+    hand: u64,
+    /// The blocks on the device by offset
+    blocks: BTreeMap<u64, DVAddr>,
+}
+
+/// A snapshot of the L2ARC's statistics. The counters go up from its creation, the size is as
+/// of the snapshot.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct L2Stats {
+    /// ARC misses served from the cache devices
+    pub hits: u64,
+    /// ARC misses that had to go to the pool
+    pub misses: u64,
+    /// Blocks evicted from the ARC written to the cache devices
+    pub writes: u64,
+    pub write_bytes: u64,
+    /// Blocks evicted from the ARC not written, to keep within `write_max`
+    pub throttled: u64,
+    /// Blocks that didn't read back the way they were written
+    pub checksum_errors: u64,
+    /// Failed reads and writes of the cache devices
+    pub io_errors: u64,
+    /// Size of the blocks on the cache devices
+    pub size: u64,
+}
+
+struct L2State {
+    index: HashMap<DVAddr, L2Entry, BuildHasherDefault<Djb2>>,
+    spaces: Vec<L2Space>,
+    /// The device the next block goes to, they take turns
+    next_device: usize,
+    /// Bytes written since `window_start`, within the last second
+    window_start: Instant,
+    window_bytes: u64,
+    stats: L2Stats,
+}
+
+impl L2State {
+    fn remove(&mut self, dva: &DVAddr) {
+        if let Some(entry) = self.index.remove(dva) {
+            self.spaces[entry.device].blocks.remove(&entry.offset);
+            self.stats.size -= entry.size as u64;
+        }
+    }
+
+    /// Count `size` bytes against the write budget of the current second, if they fit
+    fn charge_write(&mut self, size: u64, write_max: u64) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        if self.window_bytes + size > write_max {
+            return false;
+        }
+        self.window_bytes += size;
+        true
+    }
+}
+
+/// The second level ARC: blocks evicted from the ARC are written to cache devices (a spare SSD,
+/// or a file on one), so reading them again doesn't go to the pool. Which blocks are where is
+/// only kept in memory, the devices start out empty each time.
+pub struct L2Arc {
+    devices: Vec<Box<Vdev>>,
+    state: Mutex<L2State>,
+    /// Most bytes written to the cache devices per second. Devices wear out with writes, and
+    /// evictions can come much faster than that: the blocks past it aren't kept.
+    pub write_max: u64,
+}
+
+impl L2Arc {
+    pub fn new(devices: Vec<Box<Vdev>>) -> io::Result<Self> {
+        let mut spaces = Vec::new();
+        for device in &devices {
+            spaces.push(L2Space {
+                size: util::p2_align(try!(device.size()), 512),
+                hand: 0,
+                blocks: BTreeMap::new(),
+            });
+        }
+        Ok(L2Arc {
+            devices: devices,
+            state: Mutex::new(L2State {
+                index: HashMap::with_hasher(Default::default()),
+                spaces: spaces,
+                next_device: 0,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                stats: L2Stats::default(),
+            }),
+            write_max: L2ARC_WRITE_MAX,
+        })
+    }
+
+    /// Keep the block at `dva`, just evicted from the ARC, on the next device's turn. It
+    /// overwrites whichever blocks were written there a full round of the device ago.
+    pub fn spill(&self, dva: &DVAddr, data: &[u8], decoded: bool) {
+        let mut state = self.state.lock().unwrap();
+        match state.index.get(dva) {
+            // Still there from the last time it was evicted
+            Some(entry) if entry.decoded == decoded => return,
+            Some(_) => state.remove(dva),
+            None => {}
+        }
+        let size = util::p2_round_up(data.len() as u64, 512);
+        if !state.charge_write(size, self.write_max) {
+            state.stats.throttled += 1;
+            return;
+        }
+
+        for _ in 0..self.devices.len() {
+            let device = state.next_device;
+            state.next_device = (device + 1) % self.devices.len();
+            let start = {
+                let space = &mut state.spaces[device];
+                if size > space.size {
+                    continue;
+                }
+                if space.hand + size > space.size {
+                    space.hand = 0;
+                }
+                space.hand
+            };
+            let overwritten: Vec<DVAddr> = state.spaces[device]
+                                               .blocks
+                                               .range(start..start + size)
+                                               .map(|(_, dva)| *dva)
+                                               .collect();
+            for dva in overwritten {
+                state.remove(&dva);
+            }
+            state.spaces[device].hand = start + size;
+
+            if self.devices[device].write_at(start, data).is_err() {
+                state.stats.io_errors += 1;
+                return;
+            }
+            state.index.insert(*dva,
+                               L2Entry {
+                                   device: device,
+                                   offset: start,
+                                   size: data.len(),
+                                   decoded: decoded,
+                                   checksum: checksum::fletcher_4(data, false),
+                               });
+            state.spaces[device].blocks.insert(start, *dva);
+            state.stats.writes += 1;
+            state.stats.write_bytes += data.len() as u64;
+            state.stats.size += data.len() as u64;
+            return;
+        }
+    }
+
+    /// The block at `dva` and whether it's decompressed, if it's on a cache device and reads
+    /// back the way it was written. The device is read without holding up other threads.
+    pub fn read(&self, dva: &DVAddr) -> Option<(Vec<u8>, bool)> {
+        let entry = {
+            let mut state = self.state.lock().unwrap();
+            match state.index.get(dva) {
+                Some(&entry) => entry,
+                None => {
+                    state.stats.misses += 1;
+                    return None;
+                }
+            }
+        };
+        let mut data = vec![0; entry.size];
+        let read = vdev_io::read_exact_at(&*self.devices[entry.device], entry.offset, &mut data);
+
+        let mut state = self.state.lock().unwrap();
+        match read {
+            Ok(()) if checksum::fletcher_4(&data, false) == entry.checksum => {
+                state.stats.hits += 1;
+                return Some((data, entry.decoded));
+            }
+            Ok(()) => state.stats.checksum_errors += 1,
+            Err(_) => state.stats.io_errors += 1,
+        }
+        // Unless it's been written again in the meantime, it's no good
+        if state.index.get(dva).map(|current| current.offset) == Some(entry.offset) {
+            state.remove(dva);
+        }
+        state.stats.misses += 1;
+        None
+    }
+
+    /// Forget the block at `dva`, e.g. once it's failed checksum verification
+    pub fn evict(&self, dva: &DVAddr) {
+        self.state.lock().unwrap().remove(dva);
+    }
+
+    /// Forget every block that `invalid` holds for
+    pub fn evict_where(&self, invalid: &Fn(&DVAddr) -> bool) {
+        let mut state = self.state.lock().unwrap();
+        let dvas: Vec<DVAddr> = state.index.keys().filter(|dva| invalid(dva)).cloned().collect();
+        for dva in dvas {
+            state.remove(&dva);
+        }
+    }
+
+    pub fn stats(&self) -> L2Stats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Add the L2ARC's statistics to `kstats`
+    pub fn kstats(&self, kstats: &mut kstat::Registry) {
+        let stats = self.stats();
+        kstats.counter("zfs_l2arc_hits_total",
+                       "ARC misses served from the cache devices",
+                       stats.hits);
+        kstats.counter("zfs_l2arc_misses_total",
+                       "ARC misses that had to go to the pool",
+                       stats.misses);
+        kstats.counter("zfs_l2arc_writes_total",
+                       "Evicted blocks written to the cache devices",
+                       stats.writes);
+        kstats.counter("zfs_l2arc_write_bytes_total",
+                       "Bytes of evicted blocks written to the cache devices",
+                       stats.write_bytes);
+        kstats.counter("zfs_l2arc_throttled_total",
+                       "Evicted blocks not written to keep within the write limit",
+                       stats.throttled);
+        kstats.counter("zfs_l2arc_checksum_errors_total",
+                       "Blocks that didn't read back from the cache devices as written",
+                       stats.checksum_errors);
+        kstats.counter("zfs_l2arc_io_errors_total",
+                       "Failed IOs to the cache devices",
+                       stats.io_errors);
+        kstats.gauge("zfs_l2arc_size_bytes",
+                     "Size of the blocks on the cache devices",
+                     stats.size);
+    }
+}
+
+#[test]
+fn test_l2arc() {
+    use super::vdev_io::MemVdev;
+
+    let devices = [MemVdev::new(vec![0; 4 * 512]), MemVdev::new(vec![0; 4 * 512])];
+    let l2 = L2Arc::new(devices.iter().map(|device| Box::new(device.clone()) as Box<Vdev>)
+                               .collect())
+                 .unwrap();
+    let dva = |block: u64| DVAddr { vdev: 1, offset: block };
+
+    // The devices take turns
+    for block in 0..4 {
+        l2.spill(&dva(block), &[block as u8; 1000], block == 3);
+    }
+    assert_eq!(&devices[0].to_vec()[1024..1030], &[2; 6]);
+    assert_eq!(&devices[1].to_vec()[1024..1030], &[3; 6]);
+    assert_eq!(l2.read(&dva(1)), Some((vec![1; 1000], false)));
+    assert_eq!(l2.read(&dva(3)), Some((vec![3; 1000], true)));
+
+    // Past the end of a device, the oldest blocks on it are overwritten
+    l2.spill(&dva(4), &[4; 512], false);
+    assert_eq!(l2.read(&dva(0)), None);
+    assert_eq!(l2.read(&dva(4)), Some((vec![4; 512], false)));
+    assert_eq!(l2.stats().size, 3 * 1000 + 512);
+
+    // Blocks that don't read back as written are dropped
+    devices[1].write_at(0, &[0xFF]).unwrap();
+    assert_eq!(l2.read(&dva(1)), None);
+    assert_eq!(l2.stats().checksum_errors, 1);
+    assert_eq!(l2.read(&dva(3)), Some((vec![3; 1000], true)));
+
+    let mut l2 = L2Arc::new(vec![Box::new(MemVdev::new(vec![0; 1 << 20]))]).unwrap();
+    l2.write_max = 4096;
+    for block in 0..10 {
+        l2.spill(&dva(block), &[0; 1024], false);
+    }
+    let stats = l2.stats();
+    assert_eq!((stats.writes, stats.throttled), (4, 6));
+}
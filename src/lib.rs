@@ -0,0 +1,247 @@
+//! Reading and writing ZFS pools. `Pool`, `Dataset`, `Snapshot` and `File` (see the `api`
+//! module, and its prelude) are the way in; the modules below them are named after, and work
+//! like, their counterparts in the reference implementation.
+//!
+//! Without the `std` feature, the crate builds on core and alloc. What's left out is what needs
+//! files or streams: opening a pool by path, sending and receiving, and the file backed `Vdev`s.
+//! Pools are imported from devices of the backend's own instead, with `Pool::import_vdevs`, and
+//! the time is whatever the clock given to `set_clock` says.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+mod nostd;
+#[cfg(not(feature = "std"))]
+use nostd as std;
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::mem;
+use std::sync::atomic::Ordering;
+
+use arcache::{ArCache, ArcBuf};
+use block_ptr::BlockPtr;
+use dcache::DentryCache;
+use dmu_zfetch::Zfetch;
+use from_bytes::FromBytes;
+use uberblock::Uberblock;
+use zio_compress::Codec;
+
+pub use zfs::{DecodeError, Error, PoolState, Result, SpaLoadState};
+pub use api::{Dataset, File, Pool, Snapshot, prelude};
+#[cfg(not(feature = "std"))]
+pub use nostd::time::set_clock;
+
+pub mod api;
+pub mod arcache;
+pub mod avl;
+pub mod block_ptr;
+pub mod bpobj;
+pub mod checksum;
+pub mod crypto;
+pub mod dcache;
+pub mod ddt;
+#[cfg(feature = "std")]
+pub mod debug;
+pub mod dmu;
+#[cfg(feature = "std")]
+pub mod dmu_recv;
+#[cfg(feature = "std")]
+pub mod dmu_send;
+pub mod dmu_objset;
+pub mod dmu_tx;
+pub mod dmu_zfetch;
+pub mod dnode;
+pub mod dnode_sync;
+pub mod dsl_crypt;
+pub mod dsl_dataset;
+pub mod dsl_dir;
+pub mod dsl_pool;
+pub mod dsl_prop;
+pub mod dvaddr;
+#[cfg(feature = "std")]
+pub mod extract;
+pub mod from_bytes;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "http")]
+pub mod http_export;
+pub mod kstat;
+pub mod l2arc;
+pub mod lzjb;
+pub mod metaslab;
+pub mod mmp;
+#[cfg(feature = "std")]
+pub mod mount_opts;
+pub mod nvpair;
+pub mod nvstream;
+pub mod range_tree;
+#[cfg(feature = "redox")]
+pub mod redox;
+#[cfg(feature = "std")]
+pub mod replication;
+pub mod resilver;
+pub mod retention;
+pub mod sa;
+pub mod scrub;
+pub mod spa;
+pub mod spa_config;
+pub mod space_map;
+pub mod taskq;
+#[cfg(test)]
+mod test_util;
+pub mod traverse;
+pub mod txg;
+pub mod u8_textprep;
+pub mod uberblock;
+pub mod util;
+pub mod vdev;
+#[cfg(feature = "std")]
+pub mod vdev_crash;
+pub mod vdev_file;
+pub mod vdev_indirect;
+#[cfg(feature = "std")]
+pub mod vdev_inject;
+pub mod vdev_io;
+pub mod vdev_mirror;
+pub mod vdev_queue;
+pub mod vdev_raidz;
+pub mod vdev_root;
+pub mod vdev_trim;
+pub mod version;
+pub mod xdr;
+pub mod zap;
+pub mod zfeature;
+pub mod zfs;
+pub mod zil;
+pub mod zil_header;
+pub mod zio;
+#[cfg(feature = "async")]
+pub mod zio_async;
+pub mod zio_compress;
+pub mod zio_crypt;
+pub mod znode;
+pub mod zpl;
+#[cfg(feature = "std")]
+pub mod zpl_handle;
+pub mod djb2;
+
+pub struct ZfsReader {
+    pub zio: zio::Reader,
+    pub arc: ArCache,
+    pub dcache: DentryCache,
+    pub zfetch: Zfetch,
+}
+
+impl ZfsReader {
+    pub fn read_block(&mut self, block_ptr: &BlockPtr) -> zfs::Result<Vec<u8>> {
+        self.read_block_copy(block_ptr).map(|(data, _)| data)
+    }
+
+    /// Read a block through the ARC, from the first of its copies that passes checksum
+    /// verification. Returns the data along with the index of the copy it came from. If no copy
+    /// is good, it's up to the pool's failure mode.
+    pub fn read_block_copy(&mut self, block_ptr: &BlockPtr) -> zfs::Result<(Vec<u8>, usize)> {
+        self.read_block_shared(block_ptr).map(|(data, copy)| (data.to_vec(), copy))
+    }
+
+    /// Like `read_block_copy`, but the data is shared with the ARC rather than copied out of it,
+    /// unless the ARC keeps the block compressed
+    pub fn read_block_shared(&mut self, block_ptr: &BlockPtr) -> zfs::Result<(ArcBuf, usize)> {
+        if block_ptr.is_hole() {
+            // Nothing on disk to read, holes read back as zeros
+            return Ok((ArcBuf::from(vec![0; (block_ptr.lsize() * 512) as usize]), 0));
+        }
+        if block_ptr.is_embedded() || block_ptr.dvas[0].gang() || block_ptr.is_encrypted() {
+            // Embedded blocks are too small to be worth caching. The ARC caches whole blocks by
+            // DVA, but a gang DVA only has the header behind it. Encrypted blocks aren't cached
+            // decrypted, so they're no longer readable once their key is unloaded.
+            let (data, copy) = try!(self.zio.read_block_copy(block_ptr));
+            return Ok((ArcBuf::from(data), copy));
+        }
+
+        loop {
+            match self.read_copies(block_ptr) {
+                Ok(read) => {
+                    self.zio.suspended.store(false, Ordering::Relaxed);
+                    return Ok(read);
+                }
+                // Another try won't decompress it any better
+                Err(e @ zfs::Error::Decompress) => return Err(e),
+                Err(e) => try!(self.zio.io_failure(e)),
+            }
+        }
+    }
+
+    fn read_copies(&mut self, block_ptr: &BlockPtr) -> zfs::Result<(ArcBuf, usize)> {
+        let dvas = block_ptr.dvas;
+        let codec = Codec::of(block_ptr);
+        // A block without copies has nothing to read
+        let mut error = zfs::Error::NoEntity;
+        let verify = |data: &[u8]| checksum::verify(block_ptr, data).is_ok();
+        let size = block_ptr.psize() << zio::SPA_MINBLOCKSHIFT;
+        for (copy, dva) in dvas[..block_ptr.max_copies()]
+                               .iter()
+                               .enumerate()
+                               .filter(|&(_, dva)| !dva.is_empty()) {
+            match self.arc.read_block(&self.zio, dva, size, codec, block_ptr.checksum, &verify) {
+                Ok(data) => {
+                    if copy > 0 {
+                        self.zio.ditto_failovers.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Ok((data, copy));
+                }
+                Err(e @ zfs::Error::Decompress) => return Err(e),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Look for txgs synced since the last look, by us or by another writer of the pool, going by
+    /// the newest uberblock on the disk. If there are any, a new generation of the pool starts
+    /// (see `zio::Reader::observe_txg`), and the ARC and the dentry cache are emptied: another
+    /// writer may have freed their blocks and written something else in their place. Returns
+    /// the newest uberblock if it's new, to open the MOS again from.
+    pub fn revalidate(&mut self) -> zfs::Result<Option<Uberblock>> {
+        let uberblock = try!(self.zio.uber());
+        if !self.zio.observe_txg(uberblock.txg) {
+            return Ok(None);
+        }
+        self.arc.clear();
+        self.dcache.clear();
+        Ok(Some(uberblock))
+    }
+
+    /// Gather the statistics of the whole read path
+    pub fn kstats(&self) -> kstat::Registry {
+        let mut kstats = kstat::Registry::new();
+        self.arc.kstats(&mut kstats);
+        self.dcache.kstats(&mut kstats);
+        self.zfetch.kstats(&mut kstats);
+        self.zio.kstats(&mut kstats);
+        checksum::kstats(&mut kstats);
+        kstats
+    }
+
+    pub fn read_type<T: FromBytes>(&mut self, block_ptr: &BlockPtr) -> zfs::Result<T> {
+        let data = try!(self.read_block(block_ptr));
+        T::from_bytes_order(&data, block_ptr.data_order())
+    }
+
+    pub fn read_type_array<T: FromBytes>(&mut self,
+                                         block_ptr: &BlockPtr,
+                                         offset: usize)
+        -> zfs::Result<T> {
+            let data = try!(self.read_block(block_ptr));
+            T::from_bytes_order(&data[offset * mem::size_of::<T>()..], block_ptr.data_order())
+        }
+
+    pub fn uber(&mut self, _: &[u8]) -> zfs::Result<Uberblock> {
+        self.zio.uber()
+    }
+}
@@ -1,5 +1,7 @@
+#[cfg(feature = "std")]
 use std::io::{Read, Error as IOError, ErrorKind};
 use std::fmt::{Formatter, Display, Error as FmtError};
+#[cfg(feature = "std")]
 use std::error::Error;
 
 const NBBY: usize = 8; // Number of bits per byte
@@ -14,17 +16,19 @@ pub struct LzjbEncoder<'a> {
 }
 
 impl<'a> LzjbEncoder<'a> {
-    fn new(src: &'a [u8]) -> LzjbEncoder<'a> {
+    /// Reading from the encoder compresses all of `src` into the buffer at once. It returns the
+    /// length of `src` if it doesn't fit.
+    pub fn new(src: &'a [u8]) -> LzjbEncoder<'a> {
         LzjbEncoder {
             src: src,
         }
     }
 }
 
-impl<'a> Read for LzjbEncoder<'a> {
+impl<'a> LzjbEncoder<'a> {
 
     /// LZJB compress the bytes in `src` into `dst`
-    fn read(&mut self, dst: &mut [u8]) -> Result<usize, IOError> {
+    pub fn encode(&self, dst: &mut [u8]) -> usize {
         let mut src_i = 0; // Current index in src
         let mut dst_i = 0; // Current index in dst
 
@@ -48,7 +52,7 @@ impl<'a> Read for LzjbEncoder<'a> {
                 // We've reached the end of our 8-byte cycle
                 if dst_i >= dst.len() - 1 - 2 * NBBY {
                     // If we've reached the last two bytes, we're done
-                    return Ok(self.src.len());
+                    return self.src.len();
                 }
                 // Not done yet, reset the cycle
                 copymask = 1;
@@ -117,7 +121,14 @@ impl<'a> Read for LzjbEncoder<'a> {
                 }
         }
 
-        Ok(dst_i)
+        dst_i
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Read for LzjbEncoder<'a> {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize, IOError> {
+        Ok(self.encode(dst))
     }
 }
 
@@ -142,6 +153,7 @@ impl Display for DecoderError {
     }
 }
 
+#[cfg(feature = "std")]
 impl Error for DecoderError {
     fn description(&self) -> &str {
         "Failed to decode. The data is likely corrupted."
@@ -150,10 +162,10 @@ impl Error for DecoderError {
 }
 
 
-impl<'a> Read for LzjbDecoder<'a> {
+impl<'a> LzjbDecoder<'a> {
 
-    /// LZJB compress the bytes in `src` into `dst`
-    fn read(&mut self, dst: &mut [u8]) -> Result<usize, IOError> {
+    /// LZJB decompress the bytes in `src` into `dst`
+    pub fn decode(&self, dst: &mut [u8]) -> Result<usize, DecoderError> {
         let mut src_i = 0;
         let mut dst_i = 0;
         let mut copymap: u8 = 0;
@@ -164,10 +176,19 @@ impl<'a> Read for LzjbDecoder<'a> {
             if copymask == (1 << NBBY) {
                 // Finished another 8-byte loop, repeat
                 copymask = 1; // Reset the copy mask
+                if src_i >= self.src.len() {
+                    // Ran out of source before the destination is full
+                    return Err(DecoderError);
+                }
                 copymap = self.src[src_i]; // Current byte is the new copymap
                 src_i += 1;
             }
-            if (copymap & (copymask as u8)) != 0 {
+            let is_copy = (copymap & (copymask as u8)) != 0;
+            // A copy item takes two bytes, a literal one
+            if src_i + is_copy as usize >= self.src.len() {
+                return Err(DecoderError);
+            }
+            if is_copy {
                 // Found a copy item
                 let mlen = ((self.src[src_i] as usize) >> (NBBY - MATCH_BITS)) + MATCH_MIN;
                 let offset = (((self.src[src_i] as usize) << NBBY) | (self.src[src_i + 1] as usize)) &
@@ -175,7 +196,7 @@ impl<'a> Read for LzjbDecoder<'a> {
                 src_i += 2;
                 if dst_i < offset {
                     // Copy item points to invalid index, error
-                    return Err(IOError::new(ErrorKind::Other, DecoderError));
+                    return Err(DecoderError);
                 }
                 let mut cpy = dst_i - offset;
                 for _ in 0..mlen {
@@ -197,3 +218,10 @@ impl<'a> Read for LzjbDecoder<'a> {
         Ok(dst.len())
     }
 }
+
+#[cfg(feature = "std")]
+impl<'a> Read for LzjbDecoder<'a> {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize, IOError> {
+        self.decode(dst).map_err(|e| IOError::new(ErrorKind::Other, e))
+    }
+}
@@ -1,20 +1,36 @@
 // To use this, please install zfs-fuse
-use std::{mem, str};
+extern crate zfs;
+
+use std::{env, process, str};
 use std::fs::File;
-use std::io::{Read, Write, stdin, stdout};
+use std::io::{Read, Write, stderr, stdin, stdout};
+use std::net::TcpListener;
 use std::rc::Rc;
+#[cfg(any(feature = "fuse", feature = "redox"))]
+use std::sync::Arc;
 
-use self::arcache::ArCache;
-use self::dnode::{DNodePhys, ObjectType};
-use self::dmu_objset::ObjectSetPhys;
-use self::block_ptr::BlockPtr;
-use self::dsl_dataset::DslDatasetPhys;
-use self::dsl_dir::DslDirPhys;
-use self::from_bytes::FromBytes;
-use self::nvpair::NvValue;
-use self::space_map::SpaceMapPhys;
-use self::uberblock::Uberblock;
-use self::vdev::VdevLabel;
+use zfs::{avl, checksum, debug, dmu_recv, dsl_crypt, dsl_prop, extract, metaslab, mount_opts,
+          nvstream, replication, retention, scrub, space_map, spa, spa_config, traverse, xdr,
+          zap, zio, znode, zpl};
+#[cfg(feature = "fuse")]
+use zfs::fuse;
+#[cfg(feature = "http")]
+use zfs::http_export;
+#[cfg(feature = "redox")]
+use zfs::redox;
+use zfs::ZfsReader;
+use zfs::arcache::ArCache;
+use zfs::dcache::DentryCache;
+use zfs::dnode::{DNodePhys, ObjectType};
+use zfs::dmu_objset::ObjectSet;
+use zfs::dmu_zfetch::Zfetch;
+use zfs::dsl_dataset::Dataset;
+use zfs::dsl_pool::DslPool;
+use zfs::from_bytes::FromBytes;
+use zfs::nvpair::NvValue;
+use zfs::space_map::SpaceMapPhys;
+use zfs::uberblock::Uberblock;
+use zfs::vdev::VdevLabel;
 
 macro_rules! readln {
     () => ({
@@ -26,106 +42,6 @@ macro_rules! readln {
     });
 }
 
-pub mod arcache;
-pub mod avl;
-pub mod block_ptr;
-pub mod dmu_objset;
-pub mod dnode;
-pub mod dsl_dataset;
-pub mod dsl_dir;
-pub mod dsl_pool;
-pub mod dvaddr;
-pub mod from_bytes;
-pub mod lzjb;
-pub mod metaslab;
-pub mod nvpair;
-pub mod nvstream;
-pub mod spa;
-pub mod space_map;
-pub mod taskq;
-pub mod txg;
-pub mod uberblock;
-pub mod util;
-pub mod vdev;
-pub mod vdev_file;
-pub mod xdr;
-pub mod zap;
-pub mod zfs;
-pub mod zil_header;
-pub mod zio;
-pub mod djb2;
-
-pub struct ZfsReader {
-    pub zio: zio::Reader,
-    pub arc: ArCache,
-}
-
-impl ZfsReader {
-    pub fn read_block(&mut self, block_ptr: &BlockPtr) -> Result<Vec<u8>, &str> {
-        let data = self.arc.read(&mut self.zio, &block_ptr.dvas[0]);
-        match block_ptr.compression() {
-            2 => {
-                // compression off
-                data
-            }
-            1 | 3 => {
-                // lzjb compression
-                let mut decompressed = vec![0; (block_ptr.lsize()*512) as usize];
-                lzjb::LzjbDecoder::new(&match data {
-                                     Ok(data) => data,
-                                     Err(e) => return Err(e),
-                                 }).read(&mut decompressed);
-                Ok(decompressed)
-            }
-            u => Err("Error: Unknown compression type"),
-        }
-    }
-
-    pub fn read_type<T: FromBytes>(&mut self, block_ptr: &BlockPtr) -> Result<T, String> {
-        self.read_block(block_ptr).map_err(|x| x.to_owned()).and_then(|data| T::from_bytes(&data[..]).map_err(|x| x.to_owned()))
-    }
-
-    pub fn read_type_array<T: FromBytes>(&mut self,
-                                         block_ptr: &BlockPtr,
-                                         offset: usize)
-        -> Result<T, String> {
-            self.read_block(block_ptr).map_err(|x| x.to_owned()).and_then(|data| T::from_bytes(&data[offset * mem::size_of::<T>()..]).map_err(|x| x.to_owned()))
-        }
-
-    pub fn uber(&mut self, _: &[u8]) -> Result<Uberblock, &str> {
-        let mut newest_uberblock: Option<Uberblock> = None;
-        for i in 0..128 {
-            // let ub_len = 2*512;
-            // let ub_start = i * ub_len;
-            // let ub_end = ub_start + ub_len;
-            // if let Ok(uberblock) = Uberblock::from_bytes(&uberblocks[ub_start..ub_end]) {
-            if let Ok(uberblock) = Uberblock::from_bytes(&self.zio.read(256 + i * 2, 2)) {
-                let newest = match newest_uberblock {
-                    Some(previous) => {
-                        if uberblock.txg > previous.txg {
-                            // Found a newer uberblock
-                            true
-                        } else {
-                            false
-                        }
-                    }
-                    // No uberblock yet, so first one we find is the newest
-                    None => true,
-                };
-
-                if newest {
-                    newest_uberblock = Some(uberblock);
-                }
-            }
-        }
-
-        match newest_uberblock {
-            Some(uberblock) => Ok(uberblock),
-            None => Err("Failed to find valid uberblock"),
-        }
-    }
-}
-
 #[derive(Copy, Clone, PartialEq)]
 pub enum ZfsTraverse {
     ThisDir,
@@ -135,18 +51,23 @@ pub enum ZfsTraverse {
 pub struct Zfs {
     pub reader: ZfsReader,
     pub uberblock: Uberblock, // The active uberblock
-    pub mos: ObjectSetPhys,
-    fs_objset: ObjectSetPhys,
+    pub mos: ObjectSet,
+    pub dsl_pool: DslPool,
+    pub dataset: Dataset, // The root dataset, pinned at the txg of `uberblock`
+    pub mount_config: mount_opts::MountConfig, // Per-dataset overrides for the frontend
     master_node: DNodePhys,
     root: u64,
 }
 
 impl Zfs {
-    pub fn new(disk: File) -> Result<Zfs, String> {
+    pub fn new(zio: zio::Reader) -> Result<Zfs, String> {
         let mut zfs_reader = ZfsReader {
-            zio: zio::Reader { disk: disk },
+            zio: zio,
             arc: ArCache::new(),
+            dcache: DentryCache::new(),
+            zfetch: Zfetch::new(),
         };
+        zfs_reader.zio.detect_sector_shift();
 
         // Read vdev label
         // let vdev_label = Box::new(try!(VdevLabel::from_bytes(&zfs_reader.zio.read(0, 256 * 2))));
@@ -175,99 +96,58 @@ impl Zfs {
         let uberblock = try!(zfs_reader.uber(&[]));
 
         // let mos_dva = uberblock.rootbp.dvas[0];
-        let mos: ObjectSetPhys = try!(zfs_reader.read_type(&uberblock.rootbp));
-        let mos_bp1 = mos.meta_dnode.get_blockptr(0);
-
-        // 2nd dnode in MOS points at the root dataset zap
-        let dnode1: DNodePhys = try!(zfs_reader.read_type_array(&mos_bp1, 1));
+        let mos = try!(ObjectSet::open(&mut zfs_reader, &uberblock.rootbp));
 
-        let root_ds_bp = dnode1.get_blockptr(0);
-        let root_ds: zap::MZapWrapper = try!(zfs_reader.read_type(root_ds_bp));
-
-        let root_ds_dnode: DNodePhys =
-            try!(zfs_reader.read_type_array(&mos_bp1, root_ds.chunks[0].value as usize));
-
-        let dsl_dir = try!(DslDirPhys::from_bytes(root_ds_dnode.get_bonus()));
-        let head_ds_dnode: DNodePhys =
-            try!(zfs_reader.read_type_array(&mos_bp1, dsl_dir.head_dataset_obj as usize));
-
-        let root_dataset = try!(DslDatasetPhys::from_bytes(head_ds_dnode.get_bonus()));
-
-        let fs_objset: ObjectSetPhys = try!(zfs_reader.read_type(&root_dataset.bp));
-
-        let mut indirect: BlockPtr = try!(zfs_reader.read_type_array(fs_objset.meta_dnode
-                                                                              .get_blockptr(0),
-                                                                     0));
-        while indirect.level() > 0 {
-            indirect = try!(zfs_reader.read_type_array(&indirect, 0));
-        }
+        // The MOS object directory points at the root dataset's DSL directory
+        let dsl_pool = try!(DslPool::open(&mut zfs_reader, &mos));
+        let root_dir = try!(dsl_pool.root_dir(&mut zfs_reader, &mos));
+        let dataset = try!(Dataset::open(&mut zfs_reader, &mos, root_dir.head_dataset_obj(), true));
 
         // Master node is always the second object in the object set
-        let master_node: DNodePhys = try!(zfs_reader.read_type_array(&indirect, 1));
-        let master_node_zap: zap::MZapWrapper =
-            try!(zfs_reader.read_type(master_node.get_blockptr(0)));
-
-        // Find the ROOT zap entry
-        let mut root = None;
-        for chunk in &master_node_zap.chunks {
-            if chunk.name() == Some("ROOT") {
-                root = Some(chunk.value);
-                break;
-            }
-        }
-
-        let root = match root {
-            Some(root) => Ok(root),
-            None => Err("Error: failed to get the ROOT"),
-        };
+        let master_node: DNodePhys = try!(dataset.objset.dnode(&mut zfs_reader, 1));
+        let root = try!(zap::lookup(&mut zfs_reader, &dataset.objset, 1, "ROOT"));
 
         Ok(Zfs {
             reader: zfs_reader,
             uberblock: uberblock,
             mos: mos,
-            fs_objset: fs_objset,
+            dsl_pool: dsl_pool,
+            dataset: dataset,
+            mount_config: mount_opts::MountConfig::new(),
             master_node: master_node,
-            root: try!(root),
+            root: root,
         })
     }
 
+    /// Read dnode `object` of the mounted dataset
+    pub fn read_dnode(&mut self, object: u64) -> Result<DNodePhys, String> {
+        self.dataset.objset.dnode(&mut self.reader, object)
+    }
+
     pub fn traverse<F, T>(&mut self, mut f: F) -> Option<T>
         where F: FnMut(&mut Self,
                        &str,
                        usize,
                        &mut DNodePhys,
-                       &BlockPtr,
                        &mut Option<T>)
                        -> Option<ZfsTraverse>
     {
-        // Given the fs_objset and the object id of the root directory, we can traverse the
+        // Given the dataset's objset and the object id of the root directory, we can traverse the
         // directory tree.
         // TODO: Cache object id of paths
-        // TODO: Calculate path through objset blockptr tree to use
-        let mut indirect: BlockPtr = self.reader
-                                         .read_type_array(self.fs_objset
-                                                              .meta_dnode
-                                                              .get_blockptr(0),
-                                                          0)
-                                         .unwrap();
-        while indirect.level() > 0 {
-            indirect = self.reader.read_type_array(&indirect, 0).unwrap();
-        }
-        // Set the cur_node to the root node, located at an L0 indirect block
         let root = self.root as usize;
-        let mut cur_node: DNodePhys = self.reader
-                                          .read_type_array(&indirect, self.root as usize)
-                                          .unwrap();
+        let mut cur_node: DNodePhys = self.read_dnode(self.root).unwrap();
         let mut result = None;
-        if f(self, "", root, &mut cur_node, &indirect, &mut result) == Some(ZfsTraverse::Done) {
+        if f(self, "", root, &mut cur_node, &mut result) == Some(ZfsTraverse::Done) {
             return result;
         }
         'traverse: loop {
             // Directory dnodes point at zap objects. File/directory names are mapped to their
-            // fs_objset object ids.
-            let dir_contents: zap::MZapWrapper = self.reader
-                                                     .read_type(cur_node.get_blockptr(0))
-                                                     .unwrap();
+            // objset object ids.
+            let block_ptr = cur_node.get_blockptr(0);
+            let data = self.reader.read_block(&block_ptr).unwrap();
+            let dir_contents = zap::MZapWrapper::from_bytes(&data, block_ptr.data_order())
+                                   .unwrap();
             let mut next_dir = None;
             for chunk in &dir_contents.chunks {
                 match chunk.name() {
@@ -281,7 +161,6 @@ impl Zfs {
                                          chunk_name,
                                          chunk.value as usize,
                                          &mut cur_node,
-                                         &indirect,
                                          &mut result);
                         if let Some(traverse) = traverse {
                             match traverse {
@@ -310,40 +189,35 @@ impl Zfs {
     }
 
     pub fn read_file(&mut self, path: &str) -> Option<Vec<u8>> {
+        let file = match zpl::open(&mut self.reader, &self.dataset, path) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        file.read_all(&mut self.reader).ok()
+    }
+
+    /// Find the dnode of the file or directory at `path`
+    pub fn lookup(&mut self, path: &str) -> Option<DNodePhys> {
         let path = path.trim_matches('/'); // Robust against different url styles
         let path_end_index = path.rfind('/').map(|i| i + 1).unwrap_or(0);
         let path_end = &path[path_end_index..];
         let mut folder_iter = path.split('/');
         let mut folder = folder_iter.next();
 
-        let file_contents = self.traverse(|zfs, name, node_id, node, indirect, result| {
+        self.traverse(|zfs, name, node_id, node, result| {
             let mut this_dir = false;
             if let Some(folder) = folder {
                 if name == folder {
-                    *node = zfs.reader
-                               .read_type_array(indirect, node_id as usize)
-                               .unwrap();
+                    *node = zfs.read_dnode(node_id as u64).unwrap();
                     if name == path_end {
-                        if node.object_type != ObjectType::PlainFileContents {
-                            // Not a file
-                            return Some(ZfsTraverse::Done);
-                        }
-                        // Found the file
-                        let file_contents = zfs.reader
-                                               .read_block(node.get_blockptr(0))
-                                               .unwrap();
-                        // TODO: Read file size from ZPL rather than look for terminating 0
-                        let file_contents: Vec<u8> = file_contents.into_iter()
-                                                                  .take_while(|c| *c != 0)
-                                                                  .collect();
-                        *result = Some(file_contents);
+                        *result = zfs.read_dnode(node_id as u64).ok();
                         return Some(ZfsTraverse::Done);
                     }
                     this_dir = true;
                 }
             }
             if this_dir {
-                if node.object_type != ObjectType::DirectoryContents {
+                if node.object_type != ObjectType::DirectoryContents as u8 {
                     // Not a folder
                     return Some(ZfsTraverse::Done);
                 }
@@ -351,61 +225,146 @@ impl Zfs {
                 return Some(ZfsTraverse::ThisDir);
             }
             None
-        });
+        })
+    }
 
-        file_contents
+    /// Get the access, modification, change and creation times of the file at `path`
+    pub fn times(&mut self, path: &str) -> Option<znode::Times> {
+        zpl::stat(&mut self.reader, &self.dataset, path).ok().map(|stat| stat.times)
     }
 
     pub fn ls(&mut self, path: &str) -> Option<Vec<String>> {
-        let path = path.trim_matches('/'); // Robust against different url styles
-        let path_end_index = path.rfind('/').map(|i| i + 1).unwrap_or(0);
-        let path_end = &path[path_end_index..];
-        let mut folder_iter = path.split('/');
-        let mut folder = folder_iter.next();
+        let entries = match zpl::readdir(&mut self.reader, &self.dataset, path) {
+            Ok(entries) => entries,
+            Err(_) => return None,
+        };
+        Some(entries.into_iter()
+                    .map(|entry| {
+                        if entry.file_type == zpl::FileType::Directory {
+                            entry.name + "/"
+                        } else {
+                            entry.name
+                        }
+                    })
+                    .collect())
+    }
 
-        let file_contents = self.traverse(|zfs, name, node_id, node, indirect, result| {
-            let mut this_dir = false;
-            if let Some(folder) = folder {
-                if name == folder {
-                    if folder == path_end {
-                        *node = zfs.reader
-                            .read_type_array(indirect, node_id as usize)
-                            .unwrap();
-                        let dir_contents: zap::MZapWrapper = zfs.reader
-                            .read_type(node.get_blockptr(0))
-                            .unwrap();
-
-                        let ls: Vec<String> = dir_contents.chunks
-                            .iter()
-                            .map(|x| {
-                                if x.value & 0xF000000000000000 == 0x4000000000000000 {
-                                    x.name().unwrap().to_owned() + "/"
-                                } else {
-                                    x.name().unwrap().to_owned()
-                                }
-                            })
-                            .take_while(|x| !x.is_empty())
-                            .collect();
-                        *result = Some(ls);
-                        return Some(ZfsTraverse::Done);
-                    }
-                    this_dir = true;
-                }
+    /// Check the send stream `stream` and work out how it would be received into the dataset:
+    /// the plan, and how many records and bytes of it would be applied
+    pub fn recv_dry_run<R: Read>(&mut self, stream: R, force: bool) -> Result<String, String> {
+        struct Count(u64);
+        impl dmu_recv::RecvSink for Count {
+            fn apply(&mut self, _: &dmu_recv::Record) -> zfs::Result<()> {
+                self.0 += 1;
+                Ok(())
             }
-            if this_dir {
-                folder = folder_iter.next();
-                return Some(ZfsTraverse::ThisDir);
-            }
-            None
-        });
+        }
+
+        let mut stream = dmu_recv::StreamReader::new(stream);
+        let begin = try!(stream.begin().map_err(|e| e.to_string()));
+        let target = try!(dmu_recv::Target::of(&mut self.reader, &self.mos, &self.dataset));
+        let plan = try!(dmu_recv::plan(&begin, Some(&target), force).map_err(|e| e.to_string()));
+
+        let mut report = format!("{} from {:X}: {:?}\n", begin.toname, begin.fromguid, plan);
+        let dataset = self.dataset.object;
+        let interrupted = try!(dmu_recv::load_resume_state(&mut self.reader, &self.mos, dataset));
+        if let Some(resume) = interrupted {
+            report.push_str(&format!("Interrupted receive: {:?}\n", resume));
+        }
+        let mut resume = dmu_recv::ResumeState::new(&begin);
+        let mut count = Count(0);
+        try!(dmu_recv::receive(&mut stream, &mut count, &mut resume).map_err(|e| e.to_string()));
+        report.push_str(&format!("{} records, {} bytes\n", count.0, resume.bytes));
+        Ok(report)
+    }
 
-        file_contents
+    /// `recv_dry_run` on the stream of the next sender to connect to `address`, which has to
+    /// know `secret` if there is one
+    pub fn recv_listen(&mut self,
+                       address: &str,
+                       secret: Option<&[u8]>,
+                       force: bool)
+                       -> Result<String, String> {
+        let listener = try!(TcpListener::bind(address).map_err(|e| e.to_string()));
+        let (mut conn, peer) = try!(listener.accept().map_err(|e| e.to_string()));
+        let auth: Box<replication::Authenticator> = match secret {
+            Some(secret) => Box::new(replication::SharedSecret::new(secret)),
+            None => Box::new(replication::NoAuth),
+        };
+        let mut report = String::new();
+        try!(replication::accept(&mut conn,
+                                 &*auth,
+                                 replication::REPL_DEFAULT_WINDOW,
+                                 &mut |stream| {
+                                     report = try!(self.recv_dry_run(stream, force));
+                                     Ok(())
+                                 })
+                 .map_err(|e| e.to_string()));
+        Ok(format!("From {}: {}", peer, report))
     }
 }
 
+/// Serve the files of the open dataset over HTTP at `address`, until accepting a connection fails
+#[cfg(feature = "http")]
+fn serve_http(zfs: &mut Zfs, address: &str) -> Result<(), String> {
+    let listener = try!(TcpListener::bind(address).map_err(|e| e.to_string()));
+    http_export::serve(&listener, &mut zfs.reader, &zfs.dataset).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(_: &mut Zfs, _: &str) -> Result<(), String> {
+    Err("Built without the http feature".to_owned())
+}
+
+/// Mount the open dataset read-only at `mountpoint` through FUSE with its options from the mount
+/// config, until it's unmounted
+#[cfg(feature = "fuse")]
+fn mount_fuse(zfs: &mut Zfs, mountpoint: &str) -> Result<(), String> {
+    // The open dataset is the pool's root dataset, named after the pool
+    let name = try!(spa_config::read_label_config(&zfs.reader.zio, 0).map_err(|e| e.to_string()))
+                   .name;
+    let options = zfs.mount_config.options(&name);
+    let dataset = try!(Dataset::open(&mut zfs.reader, &zfs.mos, zfs.dataset.object, true));
+    fuse::mount(&mut zfs.reader, Arc::new(dataset), options, mountpoint)
+}
+
+#[cfg(not(feature = "fuse"))]
+fn mount_fuse(_: &mut Zfs, _: &str) -> Result<(), String> {
+    Err("Built without the fuse feature".to_owned())
+}
+
+/// Serve the open dataset read-only under the `zfs:` scheme, until the scheme is closed
+#[cfg(feature = "redox")]
+fn serve_scheme(zfs: &mut Zfs) -> Result<(), String> {
+    let dataset = try!(Dataset::open(&mut zfs.reader, &zfs.mos, zfs.dataset.object, true));
+    redox::mount(&mut zfs.reader, Arc::new(dataset))
+}
+
+#[cfg(not(feature = "redox"))]
+fn serve_scheme(_: &mut Zfs) -> Result<(), String> {
+    Err("Built without the redox feature".to_owned())
+}
+
+/// Buffer size and number of runs of each implementation in the startup checksum benchmark
+const CHECKSUM_BENCH_SIZE: usize = 16 * 1024;
+const CHECKSUM_BENCH_ROUNDS: usize = 64;
+
 // TODO: Find a way to remove all the to_string's
 fn main() {
     let mut stdout = stdout();
+    // Pick the fastest checksum implementations for this CPU before anything gets verified
+    checksum::benchmark(CHECKSUM_BENCH_SIZE, CHECKSUM_BENCH_ROUNDS);
+
+    // `zfs extract ...` gets files out of a pool image without the shell
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.get(0).map(|arg| &arg[..]) == Some("extract") {
+        if let Err(e) = extract::run(&args[1..], &mut stdout) {
+            writeln!(stderr(), "{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     stdout.write(b"Type open zfs.img to open the image file\n");
 
     let mut zfs_option: Option<Zfs> = None;
@@ -432,14 +391,81 @@ fn main() {
                             writeln!(stdout, "ROOTBP[0] {:?}", uberblock.rootbp.dvas[0]);
                             writeln!(stdout, "ROOTBP[1] {:?}", uberblock.rootbp.dvas[1]);
                             writeln!(stdout, "ROOTBP[2] {:?}", uberblock.rootbp.dvas[2]);
+                            if let Ok(search) = zfs.reader.zio.uber_search() {
+                                writeln!(stdout, "Found in label {}", search.label);
+                                for label in &search.damaged_labels {
+                                    writeln!(stdout, "Label {} is damaged", label);
+                                }
+                            }
                         } else if command == "spa_import" {
-                            let mut nvpairs_buffer = zfs.reader.zio.read(32, 224);
-                            let mut xdr = xdr::MemOps::new(&mut nvpairs_buffer);
-                            let nv_list = nvstream::decode_nv_list(&mut xdr).unwrap();
-                            let name = nv_list.get::<&String>("name").unwrap().clone();
-                            let spa = spa::Spa::import(name, nv_list).unwrap();
+                            // spa_import <device>...: every device of the pool
+                            let paths: Vec<&str> = args[1..].iter().map(|arg| &arg[..]).collect();
+                            match args.get(1) {
+                                Some(_) => {
+                                    match spa::import_devices(&paths) {
+                                        Ok(spa) => {
+                                            writeln!(stdout, "Imported pool {}", spa.name());
+                                            if spa.is_read_only() {
+                                                writeln!(stdout,
+                                                         "Pool is read-only: {}",
+                                                         spa.feature_report());
+                                            }
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to import pool: {:?}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "No path given");
+                                }
+                            }
+                        } else if command == "spa_reguid" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    let reguid = |mut spa: spa::Spa| {
+                                        let mut alloc = try!(spa.allocator());
+                                        spa.reguid(&mut alloc)
+                                    };
+                                    match spa::import(arg).and_then(reguid) {
+                                        Ok(guid) => {
+                                            writeln!(stdout, "New pool guid {:X}", guid);
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to reguid pool: {:?}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "No path given");
+                                }
+                            }
+                        } else if command == "spa_expand" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    let expand = |mut spa: spa::Spa| {
+                                        let mut alloc = try!(spa.allocator());
+                                        spa.expand(&mut alloc)
+                                    };
+                                    match spa::import(arg).and_then(expand) {
+                                        Ok(Some(asize)) => {
+                                            writeln!(stdout, "Expanded pool to {} bytes", asize);
+                                        }
+                                        Ok(None) => {
+                                            writeln!(stdout, "No new space to expand into");
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to expand pool: {:?}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "No path given");
+                                }
+                            }
                         } else if command == "vdev_label" {
-                            match VdevLabel::from_bytes(&zfs.reader.zio.read(0, 256 * 2)) {
+                            let label = zfs.reader.zio.read(0, 256 * 2).map_err(From::from);
+                            match label.and_then(|label| VdevLabel::from_bytes(&label)) {
                                 Ok(ref mut vdev_label) => {
                                     let mut xdr = xdr::MemOps::new(&mut vdev_label.nv_pairs);
                                     let nv_list = nvstream::decode_nv_list(&mut xdr).unwrap();
@@ -461,30 +487,23 @@ fn main() {
                                                     if let NvValue::Uint64(metaslab_array) =
                                                            *metaslab_array {
                                                         // Get metaslab array dnode
-                                                        let metaslab_array = metaslab_array as usize;
-                                                        let ma_dnode: Result<DNodePhys, _> =
-                                                            zfs.reader.read_type_array(zfs.mos
-                                                                      .meta_dnode
-                                                                      .get_blockptr(0),
-                                                                    metaslab_array);
+                                                        let ma_dnode =
+                                                            zfs.mos.dnode(&mut zfs.reader,
+                                                                          metaslab_array);
                                                         let ma_dnode = ma_dnode.unwrap(); // TODO
 
                                                         // Get a spacemap object id
                                                         let sm_id: Result<u64, _> =
-                                                            zfs.reader.read_type_array(ma_dnode.get_blockptr(0), 0);
+                                                            zfs.reader.read_type_array(&ma_dnode.get_blockptr(0), 0);
                                                         let sm_id = sm_id.unwrap(); // TODO
 
-                                                        let sm_dnode: Result<DNodePhys, _> =
-                                                            zfs.reader
-                                                               .read_type_array(zfs.mos
-                                                                                   .meta_dnode
-                                                                                   .get_blockptr(0),
-                                                                                sm_id as usize);
+                                                        let sm_dnode =
+                                                            zfs.mos.dnode(&mut zfs.reader, sm_id);
                                                         let sm_dnode = sm_dnode.unwrap(); // TODO
                                                         let space_map_phys = SpaceMapPhys::from_bytes(sm_dnode.get_bonus()).unwrap(); // TODO
                                                         let space_map: Result<Vec<u8>, _> =
                                                             zfs.reader
-                                                               .read_block(sm_dnode.get_blockptr(0));
+                                                               .read_block(&sm_dnode.get_blockptr(0));
 
                                                         writeln!(stdout, "got space map id: {:?}", sm_id);
                                                         writeln!(stdout, "got space map dnode: {:?}", sm_dnode);
@@ -536,6 +555,87 @@ fn main() {
                                     writeln!(stdout, "Usage: file <path>");
                                 }
                             }
+                        } else if command == "times" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    match zfs.times(arg) {
+                                        Some(times) => {
+                                            writeln!(stdout, "atime  {}.{:09}", times.atime.secs, times.atime.nsecs);
+                                            writeln!(stdout, "mtime  {}.{:09}", times.mtime.secs, times.mtime.nsecs);
+                                            writeln!(stdout, "ctime  {}.{:09}", times.ctime.secs, times.ctime.nsecs);
+                                            writeln!(stdout, "crtime {}.{:09}", times.crtime.secs, times.crtime.nsecs);
+                                        }
+                                        None => {
+                                            writeln!(stdout, "Failed to read times");
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: times <path>");
+                                }
+                            }
+                        } else if command == "stat" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    match zpl::lstat(&mut zfs.reader, &zfs.dataset, arg) {
+                                        Ok(stat) => {
+                                            writeln!(stdout, "object {}", stat.object);
+                                            writeln!(stdout, "type   {:?}", stat.file_type());
+                                            writeln!(stdout, "mode   {:o}", stat.mode & 0o7777);
+                                            writeln!(stdout, "uid    {}", stat.uid);
+                                            writeln!(stdout, "gid    {}", stat.gid);
+                                            writeln!(stdout, "size   {}", stat.size);
+                                            writeln!(stdout, "links  {}", stat.links);
+                                            writeln!(stdout, "blocks {}", stat.blocks);
+                                            if let Some((major, minor)) = stat.device() {
+                                                writeln!(stdout, "device {},{}", major, minor);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to stat: {}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: stat <path>");
+                                }
+                            }
+                        } else if command == "readlink" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    match zpl::readlink(&mut zfs.reader, &zfs.dataset, arg) {
+                                        Ok(target) => {
+                                            writeln!(stdout, "{}", target);
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to read link: {}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: readlink <path>");
+                                }
+                            }
+                        } else if command == "holes" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    let holes = zpl::open(&mut zfs.reader, &zfs.dataset, arg)
+                                                    .and_then(|file| file.holes(&mut zfs.reader));
+                                    match holes {
+                                        Ok(holes) => {
+                                            for (offset, len) in holes {
+                                                writeln!(stdout, "{:X}+{:X}", offset, len);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to find holes: {}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: holes <path>");
+                                }
+                            }
                         } else if command == "ls" {
                             match args.get(1) {
                                 Some(arg) => {
@@ -555,24 +655,236 @@ fn main() {
                                     writeln!(stdout, "Usage: ls <path>");
                                 }
                             }
+                        } else if command == "datasets" {
+                            let pool_name = spa_config::read_label_config(&mut zfs.reader.zio, 0)
+                                                .map(|config| config.name)
+                                                .unwrap_or_else(|_| "pool".to_owned());
+                            match zfs.dsl_pool.datasets(&mut zfs.reader, &zfs.mos, &pool_name) {
+                                Ok(datasets) => {
+                                    for (name, dir) in datasets {
+                                        let used = dir.phys.used_bytes;
+                                        writeln!(stdout, "{}\t{}", name, used);
+                                    }
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Failed to list datasets: {}", e);
+                                }
+                            }
+                        } else if command == "load_key" {
+                            // load_key <dataset> <key>: the passphrase, hex or raw key of the
+                            // dataset's encryption root
+                            if let (Some(name), Some(key)) = (args.get(1), args.get(2)) {
+                                let pool_name = spa_config::read_label_config(&mut zfs.reader.zio,
+                                                                              0)
+                                                    .map(|config| config.name)
+                                                    .unwrap_or_else(|_| "pool".to_owned());
+                                match dsl_crypt::load_keys(&mut zfs.reader,
+                                                           &zfs.mos,
+                                                           &zfs.dsl_pool,
+                                                           &pool_name,
+                                                           name,
+                                                           key.as_bytes()) {
+                                    Ok(loaded) => writeln!(stdout, "Loaded {} keys", loaded),
+                                    Err(e) => writeln!(stdout, "Failed to load the key: {}", e),
+                                };
+                            } else {
+                                writeln!(stdout, "Usage: load_key <dataset> <key>");
+                            }
+                        } else if command == "list" {
+                            // Like `zpool list -v`: the space of each top-level vdev, in bytes
+                            let space = spa_config::read_label_config(&mut zfs.reader.zio, 0)
+                                            .and_then(|config| {
+                                                metaslab::pool_space(&mut zfs.reader,
+                                                                     &zfs.mos,
+                                                                     &config.vdev_tree)
+                                            });
+                            match space {
+                                Ok(vdevs) => {
+                                    writeln!(stdout, "VDEV\tSIZE\tALLOC\tFREE");
+                                    for vdev in &vdevs {
+                                        writeln!(stdout,
+                                                 "{}{}\t{}\t{}\t{}",
+                                                 vdev.id,
+                                                 if vdev.is_log { " (log)" } else { "" },
+                                                 vdev.size,
+                                                 vdev.alloc,
+                                                 vdev.free());
+                                    }
+                                    let size: u64 = vdevs.iter().map(|vdev| vdev.size).sum();
+                                    let alloc: u64 = vdevs.iter().map(|vdev| vdev.alloc).sum();
+                                    writeln!(stdout,
+                                             "total\t{}\t{}\t{}",
+                                             size,
+                                             alloc,
+                                             size - alloc);
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Failed to read the space maps: {}", e);
+                                }
+                            }
+                        } else if command == "userspace" {
+                            // Counted from scratch, the on-disk accounting may be incomplete
+                            match zpl::rebuild_user_accounting(&mut zfs.reader, &zfs.dataset) {
+                                Ok(accounting) => {
+                                    for (kind, usage) in vec![("user", &accounting.users),
+                                                              ("group", &accounting.groups)] {
+                                        for (id, usage) in usage {
+                                            writeln!(stdout,
+                                                     "{}\t{}\t{}\t{}",
+                                                     kind,
+                                                     id,
+                                                     usage.bytes,
+                                                     usage.objects);
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Failed to count user space: {}", e);
+                                }
+                            }
+                        } else if command == "prune" {
+                            // prune [last=N] [prefix=P] <period>=N...: the pool is read only, so
+                            // this only lists the snapshots the policy would destroy
+                            let mut policy = retention::Policy::default();
+                            let mut valid = true;
+                            for arg in &args[1..] {
+                                if arg.starts_with("last=") {
+                                    match arg[5..].parse() {
+                                        Ok(last) => policy.keep_last = last,
+                                        Err(_) => valid = false,
+                                    }
+                                } else if arg.starts_with("prefix=") {
+                                    policy.prefix = arg[7..].to_owned();
+                                } else if let Some(rule) = retention::Rule::parse(arg) {
+                                    policy.rules.push(rule);
+                                } else {
+                                    valid = false;
+                                }
+                            }
+                            if !valid || (policy.rules.is_empty() && policy.keep_last == 0) {
+                                writeln!(stdout,
+                                         "Usage: prune [last=N] [prefix=P] \
+                                          hourly|daily|weekly|monthly|yearly=N...");
+                            } else {
+                                match zfs.dataset.snapshots(&mut zfs.reader, &zfs.mos) {
+                                    Ok(snapshots) => {
+                                        let plan = policy.plan(&snapshots);
+                                        for &(ref snapshot, reason) in &plan.keep {
+                                            writeln!(stdout, "keep\t{}\t{}", snapshot.name, reason);
+                                        }
+                                        for snapshot in &plan.destroy {
+                                            writeln!(stdout, "destroy\t{}", snapshot.name);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        writeln!(stdout, "Failed to list snapshots: {}", e);
+                                    }
+                                }
+                            }
+                        } else if command == "snapshots" {
+                            // Like `zfs list -t snapshot -o name,used,written,clones`
+                            match zfs.dataset.snapshot_space(&mut zfs.reader, &zfs.mos) {
+                                Ok(snapshots) => {
+                                    for (snapshot, space) in snapshots {
+                                        let clones: Vec<String> = space.clones
+                                                                       .iter()
+                                                                       .map(u64::to_string)
+                                                                       .collect();
+                                        writeln!(stdout,
+                                                 "{}\t{}\t{}\t{}",
+                                                 snapshot.name,
+                                                 space.unique,
+                                                 space.written,
+                                                 clones.join(","));
+                                    }
+                                    match zfs.dataset.written(&mut zfs.reader, &zfs.mos) {
+                                        Ok(written) => writeln!(stdout, "written\t{}", written),
+                                        Err(e) => {
+                                            writeln!(stdout, "Failed to read deadlists: {}", e)
+                                        }
+                                    };
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Failed to list snapshots: {}", e);
+                                }
+                            }
+                        } else if command == "get" {
+                            // get -o json: every property of every dataset
+                            let pool_name = spa_config::read_label_config(&mut zfs.reader.zio, 0)
+                                                .map(|config| config.name)
+                                                .unwrap_or_else(|_| "pool".to_owned());
+                            match dsl_prop::all_properties(&mut zfs.reader,
+                                                           &zfs.mos,
+                                                           &zfs.dsl_pool,
+                                                           &pool_name) {
+                                Ok(datasets) => {
+                                    if args.get(1).map(|arg| &arg[..]) == Some("-o") &&
+                                       args.get(2).map(|arg| &arg[..]) == Some("json") {
+                                        write!(stdout, "{}", dsl_prop::render_json(&datasets));
+                                    } else {
+                                        for dataset in datasets {
+                                            for prop in dataset.properties {
+                                                writeln!(stdout,
+                                                         "{}\t{}\t{}\t{:?}",
+                                                         dataset.name,
+                                                         prop.name,
+                                                         prop.value,
+                                                         prop.source);
+                                            }
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Failed to read properties: {}", e);
+                                }
+                            }
+                        } else if command == "mount_config" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    match mount_opts::MountConfig::load(arg) {
+                                        Ok(config) => zfs.mount_config = config,
+                                        Err(e) => {
+                                            writeln!(stdout, "{}", e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: mount_config <file>");
+                                }
+                            }
+                        } else if command == "mount_opts" {
+                            match args.get(1) {
+                                Some(arg) => {
+                                    writeln!(stdout, "{:?}", zfs.mount_config.options(arg));
+                                }
+                                None => {
+                                    writeln!(stdout, "Usage: mount_opts <dataset>");
+                                }
+                            }
                         } else if command == "dump" {
                             match args.get(1) {
                                 Some(arg) => {
                                     if let Ok(sector) = arg.parse::<usize>() {
                                         writeln!(stdout, "Dump sector: {}", sector);
 
-                                        let data = zfs.reader.zio.read(sector, 1);
-                                        for i in 0..data.len() {
-                                            if i % 32 == 0 {
-                                                write!(stdout, "\n{:X}:", i);
+                                        match zfs.reader.zio.read(sector, 1) {
+                                            Ok(data) => {
+                                                for i in 0..data.len() {
+                                                    if i % 32 == 0 {
+                                                        write!(stdout, "\n{:X}:", i);
+                                                    }
+                                                    if let Some(byte) = data.get(i) {
+                                                        write!(stdout, " {:X}", *byte);
+                                                    } else {
+                                                        writeln!(stdout, " !");
+                                                    }
+                                                }
+                                                write!(stdout, "\n");
                                             }
-                                            if let Some(byte) = data.get(i) {
-                                                write!(stdout, " {:X}", *byte);
-                                            } else {
-                                                writeln!(stdout, " !");
+                                            Err(e) => {
+                                                writeln!(stdout, "Failed to read sector: {}", e);
                                             }
                                         }
-                                        write!(stdout, "\n");
                                     } else {
                                         writeln!(stdout, "Sector not a number");
                                     }
@@ -581,37 +893,183 @@ fn main() {
                                     writeln!(stdout, "No sector specified!");
                                 }
                             }
+                        } else if command == "zdb" {
+                            // zdb uberblocks|labels|objdir, zdb object <object> [blocks] [mos]
+                            let object = args.get(2).and_then(|arg| arg.parse::<u64>().ok());
+                            let blocks = args.iter().skip(2).any(|arg| arg == "blocks");
+                            let objset = if args.iter().skip(2).any(|arg| arg == "mos") {
+                                &zfs.mos
+                            } else {
+                                &zfs.dataset.objset
+                            };
+                            let reader = &mut zfs.reader;
+                            let dumped = match (args.get(1).map(|arg| &arg[..]), object) {
+                                (Some("uberblocks"), _) => {
+                                    debug::dump_uberblocks(&mut stdout, &reader.zio)
+                                }
+                                (Some("labels"), _) => debug::dump_labels(&mut stdout, &reader.zio),
+                                (Some("objdir"), _) => {
+                                    debug::dump_object_directory(&mut stdout, reader, &zfs.mos)
+                                }
+                                (Some("object"), Some(object)) => {
+                                    debug::dump_object(&mut stdout, reader, objset, object, blocks)
+                                }
+                                _ => {
+                                    writeln!(stdout,
+                                             "Usage: zdb uberblocks|labels|objdir, \
+                                              zdb object <object> [blocks] [mos]");
+                                    Ok(())
+                                }
+                            };
+                            if let Err(e) = dumped {
+                                writeln!(stdout, "Error: {}", e);
+                            }
+                        } else if command == "scrub" {
+                            // scrub [metadata|data] [dataset...] [-dataset...]
+                            let mut filter = traverse::Filter::new();
+                            for arg in &args[1..] {
+                                if arg == "metadata" {
+                                    filter = filter.objects(traverse::ObjectFilter::Metadata);
+                                } else if arg == "data" {
+                                    filter = filter.objects(traverse::ObjectFilter::Data);
+                                } else if arg.starts_with('-') {
+                                    filter = filter.exclude(&arg[1..]);
+                                } else if !arg.is_empty() {
+                                    filter = filter.include(arg);
+                                }
+                            }
+
+                            let pool_name = spa_config::read_label_config(&mut zfs.reader.zio, 0)
+                                                .map(|config| config.name)
+                                                .unwrap_or_else(|_| "pool".to_owned());
+                            let datasets = [(traverse::MOS_NAME, &zfs.mos.phys),
+                                            (&pool_name[..], &zfs.dataset.objset.phys)];
+                            match scrub::scrub(&mut zfs.reader.zio, &datasets, &filter, false) {
+                                Ok(report) => {
+                                    let stats = report.stats;
+                                    writeln!(stdout, "Scrubbed {} blocks", stats.blocks);
+                                    writeln!(stdout, "{} bad copies", stats.bad_copies);
+                                    writeln!(stdout, "{} unreadable blocks", stats.unreadable_blocks);
+                                    writeln!(stdout,
+                                             "{} unverifiable blocks",
+                                             stats.unverified_blocks);
+                                    // Only the files of the mounted dataset can be named
+                                    let reader = &mut zfs.reader;
+                                    let dataset = &zfs.dataset;
+                                    write!(stdout, "{}", report.render(&mut |name, object| {
+                                        if name != pool_name {
+                                            return None;
+                                        }
+                                        zpl::object_path(reader, dataset, object).ok()
+                                    }));
+                                }
+                                Err(e) => {
+                                    writeln!(stdout, "Scrub failed: {}", e);
+                                }
+                            }
+                        } else if command == "recv" {
+                            // recv [-F] <stream> | recv [-F] -l <address> [secret]: the pool is
+                            // read only, so this only checks the stream (from a file or the
+                            // next sender to connect) and says what receiving it would take
+                            let force = args.get(1).map(|arg| &arg[..]) == Some("-F");
+                            let first = if force { 2 } else { 1 };
+                            let result = if args.get(first).map(|arg| &arg[..]) == Some("-l") {
+                                args.get(first + 1)
+                                    .ok_or("No address specified".to_owned())
+                                    .and_then(|address| {
+                                        let secret = args.get(first + 2).map(|s| s.as_bytes());
+                                        zfs.recv_listen(address, secret, force)
+                                    })
+                            } else {
+                                args.get(first)
+                                    .ok_or("No stream specified".to_owned())
+                                    .and_then(|path| File::open(path).map_err(|e| e.to_string()))
+                                    .and_then(|file| zfs.recv_dry_run(file, force))
+                            };
+                            match result {
+                                Ok(report) => write!(stdout, "{}", report),
+                                Err(e) => writeln!(stdout, "Failed to receive: {}", e),
+                            };
+                        } else if command == "http" {
+                            // http <address>: serve the dataset's files, with range requests
+                            match args.get(1) {
+                                Some(address) => {
+                                    writeln!(stdout, "Serving on {}", address);
+                                    stdout.flush();
+                                    if let Err(e) = serve_http(zfs, address) {
+                                        writeln!(stdout, "Failed to serve: {}", e);
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "No address given");
+                                }
+                            }
+                        } else if command == "fuse" {
+                            // fuse <mountpoint>: mount the dataset, serving it until unmounted
+                            match args.get(1) {
+                                Some(mountpoint) => {
+                                    if let Err(e) = mount_fuse(zfs, mountpoint) {
+                                        writeln!(stdout, "Failed to mount: {}", e);
+                                    }
+                                }
+                                None => {
+                                    writeln!(stdout, "No mountpoint given");
+                                }
+                            }
+                        } else if command == "scheme" {
+                            // scheme: serve the dataset as `zfs:`, on Redox
+                            if let Err(e) = serve_scheme(zfs) {
+                                writeln!(stdout, "Failed to serve the scheme: {}", e);
+                            }
+                        } else if command == "arc_size" {
+                            // arc_size [bytes]: show or change the size of the ARC
+                            match args.get(1).map(|arg| arg.parse::<usize>()) {
+                                Some(Ok(size)) => zfs.reader.arc.resize(size),
+                                Some(Err(_)) => {
+                                    writeln!(stdout, "Usage: arc_size [bytes]");
+                                }
+                                None => {}
+                            }
+                            writeln!(stdout, "{}", zfs.reader.arc.size());
+                        } else if command == "metrics" {
+                            write!(stdout, "{}", zfs.reader.kstats().render_prometheus());
                         } else if command == "close" {
                             writeln!(stdout, "Closing");
                             close = true;
                         } else if command == "exit" {
                             break 'reading;
                         } else {
-                            writeln!(stdout, "Commands: uber vdev_label file ls dump close exit");
+                            writeln!(stdout, "Commands: uber vdev_label file times stat readlink ls datasets snapshots get mount_config mount_opts dump zdb scrub metrics arc_size userspace load_key recv prune http fuse scheme close exit");
                         }
                     }
                     None => {
                         if command == "open" {
-                            match args.get(1) {
-                                Some(arg) => {
-                                    match File::open(arg) {
-                                        Ok(file) => {
-                                            let zfs = Zfs::new(file);
-                                            if let Err(ref e) = zfs {
-                                                writeln!(stdout, "Error: {:?}", e);
-                                            } else {
-                                                writeln!(stdout, "Open: {}", arg);
-                                            }
-                                            zfs_option = zfs.ok();
-                                        }
-                                        Err(err) => {
-                                            writeln!(stdout, "Failed to open {}: {}", arg, err);
-                                        }
+                            // Several files are the children of a mirror or raidz, or the
+                            // top-level vdevs of a striped pool
+                            let mut children = Vec::new();
+                            for arg in &args[1..] {
+                                match File::open(arg) {
+                                    Ok(file) => children.push(file),
+                                    Err(err) => {
+                                        writeln!(stdout, "Failed to open {}: {}", arg, err);
                                     }
                                 }
-                                None => {
-                                    writeln!(stdout, "No file specified!");
+                            }
+                            if args.len() < 2 {
+                                writeln!(stdout, "No file specified!");
+                            } else if children.len() == args.len() - 1 {
+                                let children = children.into_iter()
+                                                       .map(|file| Box::new(file) as _)
+                                                       .collect();
+                                let zfs = spa::open_devices(children)
+                                              .map_err(|e| e.to_string())
+                                              .and_then(|(reader, _)| Zfs::new(reader));
+                                if let Err(ref e) = zfs {
+                                    writeln!(stdout, "Error: {:?}", e);
+                                } else {
+                                    writeln!(stdout, "Open: {}", args[1..].join(" "));
                                 }
+                                zfs_option = zfs.ok();
                             }
                         } else if command == "exit" {
                             break 'reading;
@@ -1,14 +1,25 @@
-use std::cmp;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+use std::collections::BTreeMap;
 use std::rc::Rc;
 
+use super::ZfsReader;
 use super::avl;
+use super::dmu;
 use super::dmu_objset::ObjectSet;
+use super::dvaddr::DVAddr;
+use super::from_bytes::FromBytes;
+use super::range_tree::RangeTree;
 use super::space_map::{self, Segment, SpaceMap};
+use super::spa_config::VdevConfig;
 use super::taskq::{self, Taskq};
 use super::txg;
 use util;
 use super::vdev;
+use super::vdev_raidz;
 use super::zfs;
+use super::zio;
 
 // A metaslab class encompasses a category of allocatable top-level vdevs.
 // Each top-level vdev is associated with a metaslab group which defines
@@ -285,15 +296,14 @@ impl Metaslab {
         }
     }
 
-    pub fn init(mos: &mut ObjectSet,
-                vdev: &mut vdev::Vdev,
+    /// Set up metaslab `id` of the top-level vdev `vdev_top`, whose space map is MOS object
+    /// `object` (0 if it doesn't have one yet). The space map isn't read here, see `load`.
+    pub fn init(vdev_top: &mut vdev::Top,
+                ashift: u64,
                 id: u64,
                 object: u64,
                 txg: u64)
                 -> zfs::Result<Self> {
-        // We assume this is a top-level vdev
-        let vdev_top = try!(vdev.top.as_mut().ok_or(zfs::Error::Invalid));
-
         // mutex_init(&ms.lock, NULL, MUTEX_DEFAULT, NULL);
         // cv_init(&ms->ms_load_cv, NULL, CV_DEFAULT, NULL);
         let start = id << vdev_top.ms_shift;
@@ -302,11 +312,7 @@ impl Metaslab {
         // We only open space map objects that already exist. All others
         // will be opened when we finally allocate an object for it.
         let space_map = if object != 0 {
-            Some(try!(SpaceMap::open(mos,
-                                     object,
-                                     start,
-                                     size,
-                                     vdev.ashift as u8 /* , &ms.lock */)))
+            Some(try!(SpaceMap::open(object, start, size, ashift as u8 /* , &ms.lock */)))
         } else {
             None
         };
@@ -345,8 +351,14 @@ impl Metaslab {
         Ok(metaslab)
     }
 
-    pub fn load(&mut self) -> zfs::Result<()> {
-        let mut result = Ok(());
+    /// Whether the free segments have been read from the space map yet
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// Read the space map into the in-core free trees. This is only done for metaslabs that are
+    /// allocated from, so opening a pool doesn't read the space maps of the whole pool.
+    pub fn load(&mut self, reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<()> {
         // assert!(MUTEX_HELD(&self.lock));
         assert!(!self.loaded);
         assert!(!self.loading);
@@ -356,13 +368,23 @@ impl Metaslab {
         // If the space map has not been allocated yet, then treat
         // all the space in the metaslab as free and add it to the
         // tree.
-        if let Some(ref mut space_map) = self.space_map {
-            // result = space_map.load(&mut self.tree, space_map::AllocType::Free);
-        } else {
-            self.tree.insert(Segment {
-                start: self.start,
-                size: self.size,
-            });
+        let result = match self.space_map {
+            Some(ref space_map) => {
+                space_map.load(reader, mos).map(|entries| space_map.free_segments(&entries))
+            }
+            None => Ok(vec![(self.start, self.size)]),
+        };
+        if let Ok(ref segments) = result {
+            for &(start, size) in segments {
+                self.tree.insert(Segment {
+                    start: start,
+                    size: size,
+                });
+                self.size_tree.insert(Segment {
+                    start: start,
+                    size: size,
+                });
+            }
         }
 
         self.loaded = result.is_ok();
@@ -374,7 +396,21 @@ impl Metaslab {
             }
         }
         // cv_broadcast(&self.load_cv);
-        result
+        result.map(|_| ())
+    }
+
+    /// Allocate `size` bytes from the metaslab, loading its space map first if this is the first
+    /// allocation from it
+    pub fn alloc(&mut self,
+                 reader: &mut ZfsReader,
+                 mos: &ObjectSet,
+                 size: u64)
+                 -> zfs::Result<u64> {
+        if !self.loaded {
+            try!(self.load(reader, mos));
+        }
+        let alloc = self.ops.alloc;
+        Ok(alloc(self, size))
     }
 
     pub fn load_wait(&self) {
@@ -409,10 +445,329 @@ impl Metaslab {
 
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// The space of a metaslab, as its space map accounts for it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MetaslabSpace {
+    pub id: u64,
+    pub start: u64,
+    pub size: u64,
+    /// Bytes allocated from it
+    pub alloc: u64,
+    /// The MOS object of its space map, 0 if nothing has been allocated from it yet
+    pub object: u64,
+}
+
+/// The space of a top-level vdev: the sum of its metaslabs'
+#[derive(Clone, Debug, PartialEq)]
+pub struct VdevSpace {
+    pub id: u64,
+    pub guid: u64,
+    pub is_log: bool,
+    pub size: u64,
+    pub alloc: u64,
+    pub metaslabs: Vec<MetaslabSpace>,
+}
+
+impl VdevSpace {
+    pub fn free(&self) -> u64 {
+        self.size - self.alloc
+    }
+}
+
+/// Read the space accounting of the metaslabs of the top-level vdev `vdev`: the metaslab array
+/// names their space maps, and the header of each says how much is allocated. The space maps'
+/// entries aren't read.
+pub fn vdev_space(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  vdev: &VdevConfig)
+                  -> zfs::Result<VdevSpace> {
+    let ms_array = try!(vdev.metaslab_array.ok_or(zfs::Error::Invalid));
+    let ms_shift = try!(vdev.metaslab_shift.ok_or(zfs::Error::Invalid));
+    let asize = try!(vdev.asize.ok_or(zfs::Error::Invalid));
+    if ms_shift == 0 || ms_shift >= 64 {
+        return Err(zfs::Error::Invalid);
+    }
+
+    // The array can be shorter than the vdev, of a pool whose expansion never made it to the MOS
+    let array = try!(mos.dnode(reader, ms_array).map_err(|_| zfs::Error::Invalid));
+    let count = cmp::min(asize >> ms_shift,
+                         dmu::allocated_size(&array) / mem::size_of::<u64>() as u64);
+    let objects = try!(dmu::read(reader, &array, 0, count as usize * mem::size_of::<u64>())
+                           .map_err(|_| zfs::Error::Invalid));
+
+    let mut space = VdevSpace {
+        id: vdev.id,
+        guid: vdev.guid,
+        is_log: vdev.is_log,
+        size: 0,
+        alloc: 0,
+        metaslabs: Vec::with_capacity(count as usize),
+    };
+    for (id, object) in objects.chunks(mem::size_of::<u64>()).enumerate() {
+        let object = try!(u64::from_bytes(object));
+        let (start, size) = ((id as u64) << ms_shift, 1 << ms_shift);
+        let alloc = if object == 0 {
+            0
+        } else {
+            let ashift = vdev.ashift.unwrap_or(9) as u8;
+            let space_map = try!(SpaceMap::open(object, start, size, ashift));
+            try!(space_map.phys(reader, mos)).alloc()
+        };
+        space.size += size;
+        space.alloc += alloc;
+        space.metaslabs.push(MetaslabSpace {
+            id: id as u64,
+            start: start,
+            size: size,
+            alloc: alloc,
+            object: object,
+        });
+    }
+    Ok(space)
+}
+
+/// The space of every top-level vdev under `vdev_tree`, which is either the root of the pool's
+/// vdev tree or, as in a label, a top-level vdev itself. Holes left by removed vdevs have none.
+pub fn pool_space(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  vdev_tree: &VdevConfig)
+                  -> zfs::Result<Vec<VdevSpace>> {
+    if vdev_tree.vdev_type != "root" {
+        return vdev_space(reader, mos, vdev_tree).map(|space| vec![space]);
+    }
+    vdev_tree.children
+             .iter()
+             .filter(|vdev| vdev.vdev_type != "hole")
+             .map(|vdev| vdev_space(reader, mos, vdev))
+             .collect()
+}
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
 pub struct MetaslabOps {
     pub alloc: fn(ms: &mut Metaslab, size: u64) -> u64,
 }
 
+/// Where the blocks a txg sync writes go
+pub trait Allocator {
+    /// Find room for a block of `psize` bytes
+    fn alloc(&mut self, psize: u64) -> zfs::Result<DVAddr>;
+
+    /// Take back the room of a block that's no longer referenced as of `txg`
+    fn free(&mut self, dva: &DVAddr, txg: u64);
+
+    /// Whether `dva` is on the space this allocates from, and can be freed to it
+    fn owns(&self, _dva: &DVAddr) -> bool {
+        true
+    }
+}
+
+/// Most sectors a DVA can say were allocated: its asize is 24 bits
+const DVA_ASIZE_MAX: u64 = (1 << 24) - 1;
+
+/// First-fit allocation from the free space of one top-level vdev, as DVAs that OpenZFS would
+/// write: allocations are whole sectors of `1 << ashift` bytes, and the asize of a DVA on raidz
+/// includes the parity sectors and the padding that goes with them.
+pub struct VdevAllocator {
+    id: u64,
+    ashift: u64,
+    /// Number of children and of parity children, for a raidz vdev
+    raidz: Option<(usize, usize)>,
+    /// Free segments, by start, to their end. Offsets are in bytes, past the labels.
+    free: BTreeMap<u64, u64>,
+    /// Where the next search for a free segment starts
+    cursor: u64,
+    /// Frees that wait `txg::DEFER_SIZE` txgs before the space is handed out again, so the
+    /// pool can still be rolled back that far: (txg, offset, size)
+    deferred: Vec<(u64, u64, u64)>,
+    /// Bytes allocated since the vdev was opened, minus the frees
+    pub allocated: i64,
+    /// Keep track of the space frees give back, to discard it (see `take_trims`)
+    pub autotrim: bool,
+    /// The space given back since the last `take_trims` that hasn't been allocated again
+    trims: RangeTree,
+}
+
+impl VdevAllocator {
+    /// An allocator for top-level vdev `id` with `size` bytes all free
+    pub fn new(id: u64, ashift: u64, size: u64, raidz: Option<(usize, usize)>) -> Self {
+        let mut free = BTreeMap::new();
+        let size = util::p2_align(size, 1 << ashift);
+        if size > 0 {
+            free.insert(0, size);
+        }
+        VdevAllocator {
+            id: id,
+            ashift: ashift,
+            raidz: raidz,
+            free: free,
+            cursor: 0,
+            deferred: Vec::new(),
+            allocated: 0,
+            autotrim: false,
+            trims: RangeTree::new(),
+        }
+    }
+
+    /// An allocator for the top-level vdev `vdev` of a pool, with the free space of each of its
+    /// metaslabs read from the space maps
+    pub fn open(reader: &mut ZfsReader,
+                mos: &ObjectSet,
+                vdev: &VdevConfig)
+                -> zfs::Result<Self> {
+        let ashift = vdev.ashift.unwrap_or(zio::SPA_MINBLOCKSHIFT);
+        let raidz = if vdev.vdev_type == "raidz" {
+            let nparity = vdev.nparity.unwrap_or(1) as usize;
+            if nparity == 0 || nparity >= vdev.children.len() {
+                return Err(zfs::Error::Invalid);
+            }
+            Some((vdev.children.len(), nparity))
+        } else {
+            None
+        };
+
+        let space = try!(vdev_space(reader, mos, vdev));
+        let mut allocator = VdevAllocator::new(vdev.id, ashift, 0, raidz);
+        for metaslab in &space.metaslabs {
+            let segments = if metaslab.object == 0 {
+                vec![(metaslab.start, metaslab.size)]
+            } else {
+                let space_map = try!(SpaceMap::open(metaslab.object,
+                                                    metaslab.start,
+                                                    metaslab.size,
+                                                    ashift as u8));
+                let entries = try!(space_map.load(reader, mos));
+                space_map.free_segments(&entries)
+            };
+            for (start, size) in segments {
+                allocator.add_free(start, size);
+            }
+        }
+        Ok(allocator)
+    }
+
+    /// What the vdev allocates for a block of `psize` bytes (`vdev_psize_to_asize`)
+    pub fn asize(&self, psize: u64) -> u64 {
+        match self.raidz {
+            Some((children, nparity)) => {
+                vdev_raidz::asize(cmp::max(psize, 1), self.ashift, children, nparity)
+            }
+            None => util::p2_round_up(cmp::max(psize, 1), 1 << self.ashift),
+        }
+    }
+
+    /// The top-level vdev the allocator is for
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Bytes free, not counting the deferred frees
+    pub fn free_space(&self) -> u64 {
+        self.free.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// The free segments, as their starts and sizes, not counting the deferred frees
+    pub fn free_segments(&self) -> Vec<(u64, u64)> {
+        self.free.iter().map(|(&start, &end)| (start, end - start)).collect()
+    }
+
+    /// The segments frees have given back since the last call, with `autotrim` set, less what's
+    /// been allocated again since. Adjacent frees come as one segment.
+    pub fn take_trims(&mut self) -> Vec<(u64, u64)> {
+        let mut trims = Vec::with_capacity(self.trims.len());
+        self.trims.vacate(Some(&mut |start, size| trims.push((start, size))));
+        trims
+    }
+
+    /// Txg `txg` is on the disk: the space freed `txg::DEFER_SIZE` txgs before it can be used
+    /// again
+    pub fn sync_done(&mut self, txg: u64) {
+        let deferred = mem::replace(&mut self.deferred, Vec::new());
+        for (freed_txg, start, size) in deferred {
+            if freed_txg + txg::DEFER_SIZE as u64 <= txg {
+                self.add_free(start, size);
+                if self.autotrim {
+                    self.trims.clear(start, size);
+                    self.trims.add(start, size);
+                }
+            } else {
+                self.deferred.push((freed_txg, start, size));
+            }
+        }
+    }
+
+    /// Add `[start, start + size)` to the free segments, merging it with its neighbours
+    fn add_free(&mut self, mut start: u64, size: u64) {
+        let mut end = start + size;
+        let before = self.free.range(..start + 1).next_back().map(|(&s, &e)| (s, e));
+        if let Some((before_start, before_end)) = before {
+            if before_end >= start {
+                self.free.remove(&before_start);
+                start = before_start;
+                end = cmp::max(end, before_end);
+            }
+        }
+        let after = self.free.range(start..).next().map(|(&s, &e)| (s, e));
+        if let Some((after_start, after_end)) = after {
+            if after_start <= end {
+                self.free.remove(&after_start);
+                end = cmp::max(end, after_end);
+            }
+        }
+        self.free.insert(start, end);
+    }
+
+    /// The first free segment at or after `from` with room for `size` bytes, and where in it
+    fn first_fit(&self, from: u64, size: u64) -> Option<(u64, u64)> {
+        let before = self.free.range(..from).next_back();
+        before.into_iter()
+              .chain(self.free.range(from..))
+              .map(|(&start, &end)| (start, cmp::max(start, from), end))
+              .find(|&(_, offset, end)| offset + size <= end)
+              .map(|(start, offset, _)| (start, offset))
+    }
+}
+
+impl Allocator for VdevAllocator {
+    fn alloc(&mut self, psize: u64) -> zfs::Result<DVAddr> {
+        let asize = self.asize(psize);
+        if asize >> zio::SPA_MINBLOCKSHIFT > DVA_ASIZE_MAX {
+            return Err(zfs::Error::Invalid);
+        }
+        let cursor = self.cursor;
+        let (start, offset) = try!(self.first_fit(cursor, asize)
+                                       .or_else(|| self.first_fit(0, asize))
+                                       .ok_or(zfs::Error::NoSpace));
+
+        let end = self.free.remove(&start).unwrap();
+        if offset > start {
+            self.free.insert(start, offset);
+        }
+        if offset + asize < end {
+            self.free.insert(offset + asize, end);
+        }
+        self.cursor = offset + asize;
+        self.allocated += asize as i64;
+        if !self.trims.is_empty() {
+            self.trims.clear(offset, asize);
+        }
+        Ok(DVAddr {
+            vdev: self.id << 32 | asize >> zio::SPA_MINBLOCKSHIFT,
+            offset: offset >> zio::SPA_MINBLOCKSHIFT,
+        })
+    }
+
+    fn free(&mut self, dva: &DVAddr, txg: u64) {
+        let size = dva.asize() << zio::SPA_MINBLOCKSHIFT;
+        self.deferred.push((txg, dva.offset() << zio::SPA_MINBLOCKSHIFT, size));
+        self.allocated -= size as i64;
+    }
+
+    fn owns(&self, dva: &DVAddr) -> bool {
+        dva.vdev_id() == self.id
+    }
+}
+
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 
 // The first-fit block allocator
@@ -585,3 +940,38 @@ static metaslab_lba_weighting_enabled: bool = true;
 static metaslab_bias_enabled: bool = true;
 
 // static uint64_t metaslab_fragmentation(metaslab_t *);
+
+#[test]
+fn test_vdev_allocator() {
+    // The asizes zdb shows for blocks of pools made by OpenZFS
+    let disk = VdevAllocator::new(0, 9, 0, None);
+    assert_eq!((disk.asize(512), disk.asize(1000)), (512, 1024));
+    let disk = VdevAllocator::new(0, 12, 0, None);
+    assert_eq!((disk.asize(512), disk.asize(128 << 10)), (4 << 10, 128 << 10));
+    let raidz1 = VdevAllocator::new(0, 12, 0, Some((3, 1)));
+    assert_eq!((raidz1.asize(512), raidz1.asize(16 << 10)), (8 << 10, 24 << 10));
+    let raidz1 = VdevAllocator::new(0, 9, 0, Some((5, 1)));
+    assert_eq!(raidz1.asize(128 << 10), 160 << 10);
+    let raidz2 = VdevAllocator::new(0, 12, 0, Some((6, 2)));
+    assert_eq!((raidz2.asize(4 << 10), raidz2.asize(128 << 10)), (12 << 10, 192 << 10));
+
+    let mut alloc = VdevAllocator::new(1, 12, 1 << 20, None);
+    let first = alloc.alloc(512).unwrap();
+    let second = alloc.alloc(5000).unwrap();
+    assert_eq!((first.vdev_id(), first.offset(), first.asize()), (1, 0, 8));
+    assert_eq!((second.offset(), second.asize()), (8, 16));
+    assert_eq!(alloc.free_space(), (1 << 20) - (12 << 10));
+
+    // Freed space comes back once the free is old enough for no rollback to need it
+    alloc.free(&first, 5);
+    alloc.sync_done(6);
+    assert_eq!(alloc.free_space(), (1 << 20) - (12 << 10));
+    alloc.sync_done(7);
+    assert_eq!((alloc.free_space(), alloc.allocated), ((1 << 20) - (8 << 10), 8 << 10));
+
+    // First fit from the last allocation on, then from the start again
+    let big = alloc.alloc((1 << 20) - (12 << 10)).unwrap();
+    assert_eq!(big.offset(), 24);
+    assert_eq!(alloc.alloc(4096).unwrap().offset(), 0);
+    assert_eq!(alloc.alloc(4096).err(), Some(zfs::Error::NoSpace));
+}
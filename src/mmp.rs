@@ -0,0 +1,140 @@
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::spa_config::PoolConfig;
+use super::uberblock::Uberblock;
+use super::zfs;
+
+/// How many of the other host's MMP write intervals an import watches the pool for, at least
+/// (`zfs_multihost_import_intervals`)
+pub const MMP_IMPORT_INTERVALS: u64 = 20;
+/// The MMP write interval to go by when the uberblock doesn't have it, in milliseconds
+/// (`zfs_multihost_interval`)
+pub const MMP_DEFAULT_INTERVAL: u64 = 1000;
+/// The shortest MMP write interval, in milliseconds
+const MMP_MIN_INTERVAL: u64 = 100;
+/// How much longer than the other host could go without writing an import waits, in percent
+const MMP_IMPORT_SAFETY_FACTOR: u64 = 200;
+
+/// The id of this host, the one that goes in the labels of the pools it imports: from
+/// `/etc/hostid`, 0 if there's none. Without std there's no file to read it from, and it's 0.
+#[cfg(feature = "std")]
+pub fn hostid() -> u64 {
+    let mut bytes = [0; 4];
+    match File::open("/etc/hostid").and_then(|mut file| file.read_exact(&mut bytes)) {
+        Ok(()) => u32::from_le_bytes(bytes) as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub fn hostid() -> u64 {
+    0
+}
+
+/// Whether importing the pool has to make sure no other host has it imported first
+/// (`spa_activity_check_required`). Only pools last written with multihost protection on need
+/// it, unless they were exported, or it was this host that had them imported: `config` is the
+/// pool's label config, with its state and the id of the host that had it last.
+pub fn activity_check_required(uberblock: &Uberblock, config: &PoolConfig, hostid: u64) -> bool {
+    is_required(uberblock, config.state, config.hostid, hostid)
+}
+
+fn is_required(uberblock: &Uberblock, state: u64, pool_hostid: Option<u64>, hostid: u64) -> bool {
+    if state == zfs::PoolState::Exported as u64 || !uberblock.multihost() {
+        return false;
+    }
+    match pool_hostid {
+        Some(pool_hostid) => hostid == 0 || pool_hostid != hostid,
+        None => true,
+    }
+}
+
+/// How long to watch the uberblocks for writes of another host (`spa_activity_check_duration`):
+/// long enough for it to have written several times over, even if it's slow to write, going
+/// by the interval and the missed writes it tolerates that its uberblock tells
+pub fn activity_check_duration(uberblock: &Uberblock) -> Duration {
+    let interval = match uberblock.mmp_interval() {
+        Some(interval) => interval,
+        // Uberblocks without it have the average delay between the host's writes
+        None => {
+            let delay = uberblock.mmp_delay / 1_000_000;
+            if delay > MMP_DEFAULT_INTERVAL { delay } else { MMP_DEFAULT_INTERVAL }
+        }
+    };
+    let interval = if interval > MMP_MIN_INTERVAL { interval } else { MMP_MIN_INTERVAL };
+    let intervals = match uberblock.mmp_fail_intervals() {
+        Some(fail_intervals) if fail_intervals > 0 => {
+            let intervals = fail_intervals * MMP_IMPORT_SAFETY_FACTOR / 100;
+            if intervals > MMP_IMPORT_INTERVALS { intervals } else { MMP_IMPORT_INTERVALS }
+        }
+        _ => MMP_IMPORT_INTERVALS,
+    };
+    Duration::from_millis(intervals * interval)
+}
+
+/// Watch the pool's best uberblock for `duration`, reading it again with `read` every `poll`:
+/// if it changes from `uberblock`, another host is writing to the pool, and the import fails
+/// with `InUse` (`spa_activity_check`)
+pub fn activity_check(uberblock: &Uberblock,
+                      duration: Duration,
+                      poll: Duration,
+                      read: &mut FnMut() -> zfs::Result<Uberblock>)
+                      -> zfs::Result<()> {
+    let key = |uberblock: &Uberblock| (uberblock.txg, uberblock.timestamp, uberblock.mmp_seq());
+    let started = Instant::now();
+    loop {
+        let elapsed = started.elapsed();
+        if elapsed >= duration {
+            return Ok(());
+        }
+        thread::sleep(if duration - elapsed < poll { duration - elapsed } else { poll });
+        let newest = try!(read());
+        if key(&newest) != key(uberblock) {
+            return Err(zfs::Error::InUse);
+        }
+    }
+}
+
+#[test]
+fn test_activity_check() {
+    use super::from_bytes::FromBytes;
+    use super::uberblock::MMP_MAGIC;
+
+    let mut bytes = [0u8; 1024];
+    bytes[..10].copy_from_slice(&[0x0C, 0xB1, 0xBA, 0, 0, 0, 0, 0, 0x88, 0x13]);
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    uberblock.txg = 100;
+    uberblock.mmp_magic = MMP_MAGIC;
+    uberblock.mmp_delay = 2_000_000_000;
+    // 500ms interval, 15 intervals to fail, sequence 1
+    uberblock.mmp_config = 0x000F_0001_0001_F407;
+
+    let (active, exported) = (zfs::PoolState::Active as u64, zfs::PoolState::Exported as u64);
+    assert!(is_required(&uberblock, active, Some(0x1234), 0x5678));
+    assert!(!is_required(&uberblock, active, Some(0x1234), 0x1234));
+    assert!(!is_required(&uberblock, exported, Some(0x1234), 0x5678));
+    uberblock.mmp_delay = 0;
+    assert!(!is_required(&uberblock, active, Some(0x1234), 0x5678));
+    uberblock.mmp_delay = 2_000_000_000;
+
+    assert_eq!(activity_check_duration(&uberblock), Duration::from_millis(30 * 500));
+    uberblock.mmp_config = 0;
+    assert_eq!(activity_check_duration(&uberblock), Duration::from_millis(20 * 2000));
+
+    // Nobody else writing
+    let (duration, poll) = (Duration::from_millis(20), Duration::from_millis(5));
+    let quiet = uberblock;
+    activity_check(&uberblock, duration, poll, &mut || Ok(quiet)).unwrap();
+
+    // An MMP write in the same txg and second
+    uberblock.mmp_config = 0x0000_0001_0001_F407;
+    let mut written = uberblock;
+    written.mmp_config = 0x0000_0002_0001_F407;
+    assert_eq!(activity_check(&uberblock, duration, poll, &mut || Ok(written)),
+               Err(zfs::Error::InUse));
+}
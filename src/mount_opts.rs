@@ -0,0 +1,197 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::traverse;
+
+/// How to present the files of a dataset through the filesystem frontend
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountOptions {
+    /// Don't update (or report) access times
+    pub noatime: bool,
+    pub readonly: bool,
+    /// Bits to clear from every file mode
+    pub umask: u32,
+    /// (zfs id, local id) pairs. Ids without a mapping are passed through.
+    pub uid_map: Vec<(u64, u32)>,
+    pub gid_map: Vec<(u64, u32)>,
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        MountOptions {
+            noatime: false,
+            readonly: false,
+            umask: 0,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+        }
+    }
+
+    /// Apply a comma separated option list, e.g. `noatime,ro,umask=027,uid=1000:501`, on top of
+    /// these options
+    pub fn apply(&mut self, options: &str) -> Result<(), String> {
+        for option in options.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            let (key, value) = match option.find('=') {
+                Some(i) => (&option[..i], Some(&option[i + 1..])),
+                None => (option, None),
+            };
+            match (key, value) {
+                ("noatime", None) => self.noatime = true,
+                ("atime", None) => self.noatime = false,
+                ("ro", None) | ("readonly", None) => self.readonly = true,
+                ("rw", None) => self.readonly = false,
+                ("umask", Some(value)) => {
+                    self.umask = try!(u32::from_str_radix(value, 8)
+                                          .map_err(|_| format!("Bad umask `{}`", value)));
+                }
+                ("uid", Some(value)) => {
+                    let (from, to) = try!(parse_id_mapping(value));
+                    set_mapping(&mut self.uid_map, from, to);
+                }
+                ("gid", Some(value)) => {
+                    let (from, to) = try!(parse_id_mapping(value));
+                    set_mapping(&mut self.gid_map, from, to);
+                }
+                _ => return Err(format!("Unknown mount option `{}`", option)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn map_uid(&self, uid: u64) -> u32 {
+        map_id(&self.uid_map, uid)
+    }
+
+    pub fn map_gid(&self, gid: u64) -> u32 {
+        map_id(&self.gid_map, gid)
+    }
+
+    /// The mode to present for a file with mode `mode`
+    pub fn map_mode(&self, mode: u64) -> u32 {
+        let mut mode = mode as u32 & !self.umask;
+        if self.readonly {
+            // Keep the file type and the special bits, drop every write bit
+            mode &= !0o222;
+        }
+        mode
+    }
+}
+
+// `zfs:local`, e.g. `1000:501`
+fn parse_id_mapping(value: &str) -> Result<(u64, u32), String> {
+    let mut ids = value.splitn(2, ':');
+    let from = ids.next().and_then(|id| id.parse().ok());
+    let to = ids.next().and_then(|id| id.parse().ok());
+    match (from, to) {
+        (Some(from), Some(to)) => Ok((from, to)),
+        _ => Err(format!("Bad id mapping `{}`, expected <zfs id>:<local id>", value)),
+    }
+}
+
+fn set_mapping(map: &mut Vec<(u64, u32)>, from: u64, to: u32) {
+    map.retain(|&(id, _)| id != from);
+    map.push((from, to));
+}
+
+fn map_id(map: &[(u64, u32)], id: u64) -> u32 {
+    map.iter().find(|&&(from, _)| from == id).map(|&(_, to)| to).unwrap_or(id as u32)
+}
+
+/// Per-dataset mount options. A dataset gets the options of every entry for itself or one of its
+/// ancestors, applied from the pool down, so more specific entries override general ones. The
+/// `*` entry applies to every dataset.
+#[derive(Clone, Debug)]
+pub struct MountConfig {
+    entries: Vec<(String, String)>,
+}
+
+impl MountConfig {
+    pub fn new() -> Self {
+        MountConfig { entries: Vec::new() }
+    }
+
+    /// Parse a config with one `<dataset> <options>` entry per line. Blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut mount_config = MountConfig::new();
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let dataset = fields.next().unwrap();
+            let options = try!(fields.next().ok_or(format!("Line {}: no options", i + 1)));
+            if fields.next().is_some() {
+                return Err(format!("Line {}: trailing garbage", i + 1));
+            }
+            try!(mount_config.add(dataset, options).map_err(|e| format!("Line {}: {}", i + 1, e)));
+        }
+        Ok(mount_config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let mut config = String::new();
+        try!(File::open(path)
+                 .and_then(|mut file| file.read_to_string(&mut config))
+                 .map_err(|e| format!("Failed to read mount config: {}", e)));
+        Self::parse(&config)
+    }
+
+    /// Add the options of `dataset`, e.g. from the command line
+    pub fn add(&mut self, dataset: &str, options: &str) -> Result<(), String> {
+        // Catch typos now rather than when the dataset is mounted
+        try!(MountOptions::new().apply(options));
+        self.entries.push((dataset.to_owned(), options.to_owned()));
+        Ok(())
+    }
+
+    /// The effective mount options of `dataset`
+    pub fn options(&self, dataset: &str) -> MountOptions {
+        let mut matching: Vec<&(String, String)> =
+            self.entries
+                .iter()
+                .filter(|&&(ref name, _)| {
+                    name == "*" || traverse::Filter::new().include(name).wants_dataset(dataset)
+                })
+                .collect();
+        // Stable, so entries for the same dataset keep their order
+        matching.sort_by_key(|&&(ref name, _)| if name == "*" { 0 } else { name.len() + 1 });
+
+        let mut options = MountOptions::new();
+        for &&(_, ref entry) in &matching {
+            // Validated in `add`
+            let _ = options.apply(entry);
+        }
+        options
+    }
+}
+
+#[test]
+fn test_mount_config() {
+    let config = MountConfig::parse("# forensic copy of the server\n\
+                                     *          ro,uid=0:1000\n\
+                                     tank/home  noatime,umask=027,uid=501:1000\n\
+                                     \n\
+                                     tank/home/alice  rw,uid=0:0,gid=20:100\n")
+                     .unwrap();
+
+    let root = config.options("tank");
+    assert!(root.readonly && !root.noatime);
+    assert_eq!(root.map_uid(0), 1000);
+    assert_eq!(root.map_uid(501), 501);
+
+    let home = config.options("tank/home");
+    assert!(home.readonly && home.noatime);
+    assert_eq!((home.map_uid(0), home.map_uid(501)), (1000, 1000));
+    assert_eq!(home.map_mode(0o100775), 0o100550);
+
+    let alice = config.options("tank/home/alice");
+    assert!(!alice.readonly && alice.noatime);
+    assert_eq!((alice.map_uid(0), alice.map_gid(20), alice.map_gid(0)), (0, 100, 0));
+    assert_eq!(config.options("tank/homework"), root);
+
+    assert!(MountConfig::parse("tank nosuchoption").is_err());
+    assert!(MountConfig::parse("tank uid=1000").is_err());
+}
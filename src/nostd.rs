@@ -0,0 +1,477 @@
+//! What the modules use of std, from core and alloc, for builds without the `std` feature. It
+//! goes by the name `std` in those builds, so a module reads the same either way.
+//!
+//! What alloc doesn't have is made up here, as simply as it can be: the hash maps are B-trees,
+//! the locks spin, and with no threads to hand work to, `thread::spawn` does it there and then.
+//! The time is that of the clock given to `time::set_clock`. Until there is one, it only moves
+//! on as `thread::sleep` is called.
+//!
+//! Not all of it is used in every build, as with std.
+
+#![allow(dead_code)]
+
+pub use core::{cell, char, cmp, f64, fmt, hash, mem, ops, ptr, result};
+pub use alloc::{rc, slice, str, vec};
+
+pub mod prelude {
+    pub mod v1 {
+        pub use alloc::borrow::ToOwned;
+        pub use alloc::boxed::Box;
+        pub use alloc::string::{String, ToString};
+        pub use alloc::vec::Vec;
+    }
+}
+
+pub mod collections {
+    use core::hash::{BuildHasher, Hasher};
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    pub use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+
+    use super::super::djb2::Djb2;
+    use super::time::{SystemTime, UNIX_EPOCH};
+
+    /// A `BTreeMap`, which takes the place of std's hash map. The hasher is only kept, for what
+    /// else there is that hashes keys.
+    #[derive(Clone, Debug, Default)]
+    pub struct HashMap<K: Ord, V, S = hash_map::RandomState> {
+        map: BTreeMap<K, V>,
+        hasher: S,
+    }
+
+    impl<K: Ord, V> HashMap<K, V> {
+        pub fn new() -> Self {
+            Self::with_hasher(hash_map::RandomState::new())
+        }
+    }
+
+    impl<K: Ord, V, S> HashMap<K, V, S> {
+        pub fn with_hasher(hasher: S) -> Self {
+            HashMap {
+                map: BTreeMap::new(),
+                hasher: hasher,
+            }
+        }
+
+        pub fn hasher(&self) -> &S {
+            &self.hasher
+        }
+
+        /// A B-tree grows a node at a time, there's no room to make ahead
+        pub fn reserve(&mut self, _: usize) {}
+    }
+
+    impl<K: Ord, V, S> Deref for HashMap<K, V, S> {
+        type Target = BTreeMap<K, V>;
+
+        fn deref(&self) -> &BTreeMap<K, V> {
+            &self.map
+        }
+    }
+
+    impl<K: Ord, V, S> DerefMut for HashMap<K, V, S> {
+        fn deref_mut(&mut self) -> &mut BTreeMap<K, V> {
+            &mut self.map
+        }
+    }
+
+    /// A `BTreeSet`, which takes the place of std's hash set
+    pub type HashSet<T> = BTreeSet<T>;
+
+    pub mod hash_map {
+        use super::*;
+
+        /// Djb2, seeded with the time and a count of the states made. That's as random as it
+        /// gets without std.
+        #[derive(Clone, Debug)]
+        pub struct RandomState(u64);
+
+        impl RandomState {
+            pub fn new() -> Self {
+                static STATES: AtomicUsize = AtomicUsize::new(0);
+                let mut hasher = Djb2::default();
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                hasher.write_u64(now.as_secs());
+                hasher.write_u32(now.subsec_nanos());
+                hasher.write_usize(STATES.fetch_add(1, Ordering::Relaxed));
+                RandomState(hasher.finish())
+            }
+        }
+
+        impl Default for RandomState {
+            fn default() -> Self {
+                RandomState::new()
+            }
+        }
+
+        impl BuildHasher for RandomState {
+            type Hasher = Djb2;
+
+            fn build_hasher(&self) -> Djb2 {
+                let mut hasher = Djb2::default();
+                hasher.write_u64(self.0);
+                hasher
+            }
+        }
+    }
+}
+
+pub mod io {
+    use alloc::string::String;
+    use core::{fmt, result};
+
+    /// Kinds of std's `io::ErrorKind` that a vdev backend can fail with
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub enum ErrorKind {
+        NotFound,
+        PermissionDenied,
+        NotConnected,
+        AlreadyExists,
+        InvalidInput,
+        InvalidData,
+        WriteZero,
+        Interrupted,
+        Unsupported,
+        UnexpectedEof,
+        Other,
+    }
+
+    /// An error of a vdev backend: its kind, and what went wrong
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        message: String,
+    }
+
+    impl Error {
+        pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+            Error {
+                kind: kind,
+                message: message.into(),
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Error::new(kind, String::new())
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.message.is_empty() {
+                write!(f, "{:?}", self.kind)
+            } else {
+                f.write_str(&self.message)
+            }
+        }
+    }
+
+    pub type Result<T> = result::Result<T, Error>;
+}
+
+pub mod sync {
+    use core::cell::UnsafeCell;
+    use core::convert::Infallible;
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    pub use core::sync::atomic;
+    pub use alloc::sync::Arc;
+
+    /// A lock that spins until it's free. It's never poisoned: a panic without std doesn't
+    /// unwind.
+    pub struct Mutex<T> {
+        locked: AtomicBool,
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T) -> Self {
+            Mutex {
+                locked: AtomicBool::new(false),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn lock(&self) -> Result<MutexGuard<T>, Infallible> {
+            while self.locked
+                      .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                      .is_err() {
+                core::hint::spin_loop();
+            }
+            Ok(MutexGuard { mutex: self })
+        }
+
+        pub fn get_mut(&mut self) -> Result<&mut T, Infallible> {
+            Ok(unsafe { &mut *self.value.get() })
+        }
+
+        pub fn into_inner(self) -> Result<T, Infallible> {
+            Ok(self.value.into_inner())
+        }
+    }
+
+    impl<T: Default> Default for Mutex<T> {
+        fn default() -> Self {
+            Mutex::new(T::default())
+        }
+    }
+
+    pub struct MutexGuard<'a, T: 'a> {
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<'a, T> Deref for MutexGuard<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.value.get() }
+        }
+    }
+
+    impl<'a, T> DerefMut for MutexGuard<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.value.get() }
+        }
+    }
+
+    impl<'a, T> Drop for MutexGuard<'a, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+
+    pub mod mpsc {
+        use alloc::collections::VecDeque;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        use super::{Arc, Mutex};
+
+        struct Channel<T> {
+            queue: Mutex<VecDeque<T>>,
+            senders: AtomicUsize,
+        }
+
+        pub struct Sender<T>(Arc<Channel<T>>);
+
+        pub struct Receiver<T>(Arc<Channel<T>>);
+
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        pub struct SendError<T>(pub T);
+
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        pub struct RecvError;
+
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        pub enum TryRecvError {
+            Empty,
+            Disconnected,
+        }
+
+        pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+            let channel = Arc::new(Channel {
+                queue: Mutex::new(VecDeque::new()),
+                senders: AtomicUsize::new(1),
+            });
+            (Sender(channel.clone()), Receiver(channel))
+        }
+
+        impl<T> Sender<T> {
+            pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+                if Arc::strong_count(&self.0) == self.0.senders.load(Ordering::Acquire) {
+                    return Err(SendError(value));
+                }
+                self.0.queue.lock().unwrap().push_back(value);
+                Ok(())
+            }
+        }
+
+        impl<T> Clone for Sender<T> {
+            fn clone(&self) -> Self {
+                self.0.senders.fetch_add(1, Ordering::AcqRel);
+                Sender(self.0.clone())
+            }
+        }
+
+        impl<T> Drop for Sender<T> {
+            fn drop(&mut self) {
+                self.0.senders.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        impl<T> Receiver<T> {
+            pub fn try_recv(&self) -> Result<T, TryRecvError> {
+                match self.0.queue.lock().unwrap().pop_front() {
+                    Some(value) => Ok(value),
+                    None if self.0.senders.load(Ordering::Acquire) == 0 => {
+                        Err(TryRecvError::Disconnected)
+                    }
+                    None => Err(TryRecvError::Empty),
+                }
+            }
+
+            /// With no other threads, nothing sent is still on its way: what isn't queued never
+            /// comes
+            pub fn recv(&self) -> Result<T, RecvError> {
+                self.0.queue.lock().unwrap().pop_front().ok_or(RecvError)
+            }
+
+            pub fn iter(&self) -> Iter<T> {
+                Iter(self)
+            }
+        }
+
+        pub struct Iter<'a, T: 'a>(&'a Receiver<T>);
+
+        impl<'a, T> Iterator for Iter<'a, T> {
+            type Item = T;
+
+            fn next(&mut self) -> Option<T> {
+                self.0.recv().ok()
+            }
+        }
+    }
+}
+
+pub mod thread {
+    use core::any::Any;
+
+    use alloc::boxed::Box;
+
+    use super::time::Duration;
+
+    /// The result of the work, done before `spawn` returned
+    pub struct JoinHandle<T>(T);
+
+    impl<T> JoinHandle<T> {
+        pub fn join(self) -> Result<T, Box<Any + Send>> {
+            Ok(self.0)
+        }
+    }
+
+    /// Do `f` right away, there being no thread to do it on
+    pub fn spawn<F: FnOnce() -> T, T>(f: F) -> JoinHandle<T> {
+        JoinHandle(f())
+    }
+
+    /// Spin until `duration` has passed by the clock. With no clock, that's right away: the time
+    /// is moved on by `duration` instead, so whatever is waited for comes due.
+    pub fn sleep(duration: Duration) {
+        super::time::wait(duration);
+    }
+
+    pub fn yield_now() {}
+}
+
+pub mod time {
+    use core::cmp;
+    use core::ops::{Add, Sub};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    pub use core::time::Duration;
+
+    static CLOCK: AtomicUsize = AtomicUsize::new(0);
+    /// Milliseconds slept with no clock to wait on
+    static SLEPT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Tell the time with `clock`, which returns the time since the Unix epoch. It's what
+    /// timestamps the txgs synced and the files changed, and times IO for the statistics.
+    pub fn set_clock(clock: fn() -> Duration) {
+        CLOCK.store(clock as usize, Ordering::Release);
+    }
+
+    fn clock() -> Option<fn() -> Duration> {
+        match CLOCK.load(Ordering::Acquire) {
+            0 => None,
+            clock => Some(unsafe { ::core::mem::transmute(clock) }),
+        }
+    }
+
+    fn now() -> Duration {
+        let slept = Duration::from_millis(SLEPT.load(Ordering::Relaxed) as u64);
+        clock().map_or(slept, |clock| clock() + slept)
+    }
+
+    /// Let `duration` pass, see `thread::sleep`
+    pub fn wait(duration: Duration) {
+        match clock() {
+            Some(clock) => {
+                let until = clock() + duration;
+                while clock() < until {
+                    ::core::hint::spin_loop();
+                }
+            }
+            None => {
+                let ms = duration.as_secs() as usize * 1000 + duration.subsec_millis() as usize;
+                SLEPT.fetch_add(cmp::max(ms, 1), Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct Instant(Duration);
+
+    impl Instant {
+        pub fn now() -> Self {
+            Instant(now())
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            self.0.checked_sub(earlier.0).unwrap_or(Duration::from_secs(0))
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Instant::now().duration_since(*self)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, duration: Duration) -> Instant {
+            Instant(self.0 + duration)
+        }
+    }
+
+    impl Sub<Instant> for Instant {
+        type Output = Duration;
+
+        fn sub(self, earlier: Instant) -> Duration {
+            self.duration_since(earlier)
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct SystemTime(Duration);
+
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    #[derive(Clone, Debug)]
+    pub struct SystemTimeError(Duration);
+
+    impl SystemTimeError {
+        pub fn duration(&self) -> Duration {
+            self.0
+        }
+    }
+
+    impl SystemTime {
+        pub fn now() -> Self {
+            SystemTime(now())
+        }
+
+        pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+            self.0.checked_sub(earlier.0).ok_or(SystemTimeError(earlier.0 - self.0))
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 use std::fmt;
 
 // nvp implementation version
@@ -132,7 +134,7 @@ impl NvValue {
     pub fn num_elements(&self) -> usize {
         match *self {
             NvValue::Unknown => 1,
-            NvValue::Boolean => 1,
+            NvValue::Boolean => 0, // A boolean is true by merely being present
             NvValue::Byte(_) => 1,
             NvValue::Int16(_) => 1,
             NvValue::Uint16(_) => 1,
@@ -176,7 +178,28 @@ impl fmt::Debug for NvValue {
                 write!(f, "])")
             }
             NvValue::String(ref v) => write!(f, "String({})", v),
-            _ => write!(f, "{:?}", self),
+            NvValue::Unknown => write!(f, "Unknown"),
+            NvValue::Boolean => write!(f, "Boolean"),
+            NvValue::Byte(v) => write!(f, "Byte({})", v),
+            NvValue::Int16(v) => write!(f, "Int16({})", v),
+            NvValue::Uint16(v) => write!(f, "Uint16({})", v),
+            NvValue::Int32(v) => write!(f, "Int32({})", v),
+            NvValue::Uint32(v) => write!(f, "Uint32({})", v),
+            NvValue::ByteArray(ref v) => write!(f, "ByteArray({:?})", v),
+            NvValue::Int16Array(ref v) => write!(f, "Int16Array({:?})", v),
+            NvValue::Uint16Array(ref v) => write!(f, "Uint16Array({:?})", v),
+            NvValue::Int32Array(ref v) => write!(f, "Int32Array({:?})", v),
+            NvValue::Uint32Array(ref v) => write!(f, "Uint32Array({:?})", v),
+            NvValue::Int64Array(ref v) => write!(f, "Int64Array({:?})", v),
+            NvValue::Uint64Array(ref v) => write!(f, "Uint64Array({:?})", v),
+            NvValue::StringArray(ref v) => write!(f, "StringArray({:?})", v),
+            NvValue::HrTime(v) => write!(f, "HrTime({})", v),
+            NvValue::BooleanValue(v) => write!(f, "BooleanValue({})", v),
+            NvValue::Int8(v) => write!(f, "Int8({})", v),
+            NvValue::Uint8(v) => write!(f, "Uint8({})", v),
+            NvValue::BooleanArray(ref v) => write!(f, "BooleanArray({:?})", v),
+            NvValue::Int8Array(ref v) => write!(f, "Int8Array({:?})", v),
+            NvValue::Uint8Array(ref v) => write!(f, "Uint8Array({:?})", v),
         }
     }
 }
@@ -383,3 +406,21 @@ impl<'a> GetNvValue<'a> for &'a Vec<NvList> {
         }
     }
 }
+
+impl<'a> GetNvValue<'a> for &'a Vec<u64> {
+    fn get(value: &'a NvValue) -> Option<Self> {
+        match *value {
+            NvValue::Uint64Array(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> GetNvValue<'a> for &'a Vec<String> {
+    fn get(value: &'a NvValue) -> Option<Self> {
+        match *value {
+            NvValue::StringArray(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 use std::mem;
 
 use super::nvpair::{DataType, NV_VERSION, NvList, NvValue};
@@ -50,19 +52,23 @@ pub struct NvsHeader {
 pub fn encode_nv_list(xdr: &mut xdr::Xdr, nv_list: &NvList) -> xdr::XdrResult<()> {
     try!(encode_nv_list_header(xdr));
 
+    encode_nv_list_embedded(xdr, nv_list)
+}
+
+pub fn encode_nv_list_embedded(xdr: &mut xdr::Xdr, nv_list: &NvList) -> xdr::XdrResult<()> {
     // Encode version and nvflag
     try!(xdr.encode_i32(nv_list.version));
     try!(xdr.encode_u32(nv_list.nvflag));
 
     // Encode the pairs
     for &(ref name, ref value) in &nv_list.pairs {
+        // Encode encoded/decoded size
+        try!(xdr.encode_u32(encoded_pair_size(name, value) as u32));
+        try!(xdr.encode_u32(decoded_pair_size(name, value) as u32));
+
         // Encode name
-        // let encoded_size = 0;
-        // let decoded_size = 0;
         try!(xdr.encode_string(name));
 
-        // TODO
-
         // Encode data type
         try!(xdr.encode_u8(value.data_type().to_u8()));
 
@@ -70,6 +76,7 @@ pub fn encode_nv_list(xdr: &mut xdr::Xdr, nv_list: &NvList) -> xdr::XdrResult<()
         try!(xdr.encode_i32(value.num_elements() as i32));
 
         // Encode the value
+        try!(encode_nv_value(xdr, value));
     }
 
     // Encode 2 terminating zeros
@@ -78,6 +85,189 @@ pub fn encode_nv_list(xdr: &mut xdr::Xdr, nv_list: &NvList) -> xdr::XdrResult<()
     Ok(())
 }
 
+/// Size of `nv_list` once encoded, including the header
+pub fn encoded_size(nv_list: &NvList) -> usize {
+    4 + encoded_list_size(nv_list)
+}
+
+fn xdr_string_size(s: &str) -> usize {
+    4 + xdr_padded(s.len())
+}
+
+fn xdr_padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Encoded size of an embedded nvlist: version, flags, pairs and the terminating zeros
+fn encoded_list_size(nv_list: &NvList) -> usize {
+    let pairs: usize = nv_list.pairs
+                              .iter()
+                              .map(|&(ref name, ref value)| encoded_pair_size(name, value))
+                              .sum();
+    8 + pairs + 8
+}
+
+fn encoded_pair_size(name: &str, value: &NvValue) -> usize {
+    // sizes + name + data type + number of elements + value
+    8 + xdr_string_size(name) + 4 + 4 + encoded_value_size(value)
+}
+
+fn encoded_value_size(value: &NvValue) -> usize {
+    match *value {
+        NvValue::Unknown | NvValue::Boolean => 0,
+        NvValue::Byte(_) | NvValue::Int8(_) | NvValue::Uint8(_) | NvValue::Int16(_) |
+        NvValue::Uint16(_) | NvValue::Int32(_) | NvValue::Uint32(_) |
+        NvValue::BooleanValue(_) => 4,
+        NvValue::Int64(_) | NvValue::Uint64(_) | NvValue::HrTime(_) => 8,
+        NvValue::String(ref v) => xdr_string_size(v),
+        NvValue::ByteArray(ref v) => xdr_padded(v.len()),
+        NvValue::Int8Array(ref v) => 4 + v.len() * 4,
+        NvValue::Uint8Array(ref v) => 4 + v.len() * 4,
+        NvValue::Int16Array(ref v) => 4 + v.len() * 4,
+        NvValue::Uint16Array(ref v) => 4 + v.len() * 4,
+        NvValue::Int32Array(ref v) => 4 + v.len() * 4,
+        NvValue::Uint32Array(ref v) => 4 + v.len() * 4,
+        NvValue::BooleanArray(ref v) => 4 + v.len() * 4,
+        NvValue::Int64Array(ref v) => 4 + v.len() * 8,
+        NvValue::Uint64Array(ref v) => 4 + v.len() * 8,
+        NvValue::StringArray(ref v) => v.iter().map(|s| xdr_string_size(s)).sum(),
+        NvValue::NvList(ref v) => encoded_list_size(v),
+        NvValue::NvListArray(ref v) => v.iter().map(encoded_list_size).sum(),
+    }
+}
+
+/// Size of the pair in the native (in-memory) representation. Decoders allocate this much for
+/// the pair, so it must not be too small.
+fn decoded_pair_size(name: &str, value: &NvValue) -> usize {
+    fn align(x: usize) -> usize {
+        (x + 7) & !7
+    }
+    // The native nvpair header is 16 bytes, the name is NUL terminated
+    const NVPAIR_HEADER_SIZE: usize = 16;
+    const NVLIST_SIZE: usize = 24;
+
+    let value_size = match *value {
+        NvValue::Unknown | NvValue::Boolean => 0,
+        NvValue::Byte(_) | NvValue::Int8(_) | NvValue::Uint8(_) => 1,
+        NvValue::Int16(_) | NvValue::Uint16(_) => 2,
+        NvValue::Int32(_) | NvValue::Uint32(_) | NvValue::BooleanValue(_) => 4,
+        NvValue::Int64(_) | NvValue::Uint64(_) | NvValue::HrTime(_) => 8,
+        NvValue::String(ref v) => v.len() + 1,
+        NvValue::ByteArray(ref v) => v.len(),
+        NvValue::Int8Array(ref v) => v.len(),
+        NvValue::Uint8Array(ref v) => v.len(),
+        NvValue::Int16Array(ref v) => v.len() * 2,
+        NvValue::Uint16Array(ref v) => v.len() * 2,
+        NvValue::Int32Array(ref v) => v.len() * 4,
+        NvValue::Uint32Array(ref v) => v.len() * 4,
+        NvValue::BooleanArray(ref v) => v.len() * 4,
+        NvValue::Int64Array(ref v) => v.len() * 8,
+        NvValue::Uint64Array(ref v) => v.len() * 8,
+        // An array of pointers followed by the strings
+        NvValue::StringArray(ref v) => v.iter().map(|s| 8 + s.len() + 1).sum(),
+        NvValue::NvList(_) => NVLIST_SIZE,
+        NvValue::NvListArray(ref v) => v.len() * (8 + NVLIST_SIZE),
+    };
+
+    align(NVPAIR_HEADER_SIZE + name.len() + 1) + align(value_size)
+}
+
+fn encode_nv_value(xdr: &mut xdr::Xdr, value: &NvValue) -> xdr::XdrResult<()> {
+    match *value {
+        NvValue::Unknown => Err(xdr::XdrError),
+        NvValue::Boolean => Ok(()),
+        NvValue::Byte(v) => xdr.encode_u8(v),
+        NvValue::Int16(v) => xdr.encode_i16(v),
+        NvValue::Uint16(v) => xdr.encode_u16(v),
+        NvValue::Int32(v) => xdr.encode_i32(v),
+        NvValue::Uint32(v) => xdr.encode_u32(v),
+        NvValue::Int64(v) => xdr.encode_i64(v),
+        NvValue::Uint64(v) => xdr.encode_u64(v),
+        NvValue::String(ref v) => xdr.encode_string(v),
+        NvValue::ByteArray(ref v) => xdr.encode_opaque(v),
+        NvValue::Int16Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_i16(*v));
+            }
+            Ok(())
+        }
+        NvValue::Uint16Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_u16(*v));
+            }
+            Ok(())
+        }
+        NvValue::Int32Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_i32(*v));
+            }
+            Ok(())
+        }
+        NvValue::Uint32Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_u32(*v));
+            }
+            Ok(())
+        }
+        NvValue::Int64Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_i64(*v));
+            }
+            Ok(())
+        }
+        NvValue::Uint64Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_u64(*v));
+            }
+            Ok(())
+        }
+        NvValue::StringArray(ref v) => {
+            for v in v {
+                try!(xdr.encode_string(v));
+            }
+            Ok(())
+        }
+        NvValue::HrTime(v) => xdr.encode_i64(v),
+        NvValue::NvList(ref v) => encode_nv_list_embedded(xdr, v),
+        NvValue::NvListArray(ref v) => {
+            for v in v {
+                try!(encode_nv_list_embedded(xdr, v));
+            }
+            Ok(())
+        }
+        NvValue::BooleanValue(v) => xdr.encode_bool(v),
+        NvValue::Int8(v) => xdr.encode_i8(v),
+        NvValue::Uint8(v) => xdr.encode_u8(v),
+        NvValue::BooleanArray(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_bool(*v));
+            }
+            Ok(())
+        }
+        NvValue::Int8Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_i8(*v));
+            }
+            Ok(())
+        }
+        NvValue::Uint8Array(ref v) => {
+            try!(xdr.encode_u32(v.len() as u32));
+            for v in v {
+                try!(xdr.encode_u8(*v));
+            }
+            Ok(())
+        }
+    }
+}
+
 fn encode_nv_list_header(xdr: &mut xdr::Xdr) -> xdr::XdrResult<()> {
     let header = NvsHeader {
         encoding: NV_ENCODE_XDR,
@@ -155,6 +345,16 @@ fn decode_nv_list_header(xdr: &mut xdr::Xdr) -> xdr::XdrResult<()> {
     Ok(())
 }
 
+/// Integer arrays are encoded with `xdr_array`, which repeats the element count in front of the
+/// elements
+fn decode_array_len(xdr: &mut xdr::Xdr, num_elements: usize) -> xdr::XdrResult<()> {
+    let len = try!(xdr.decode_u32()) as usize;
+    if len != num_elements {
+        return Err(xdr::XdrError);
+    }
+    Ok(())
+}
+
 fn decode_nv_value(xdr: &mut xdr::Xdr,
                    data_type: DataType,
                    num_elements: usize)
@@ -171,13 +371,13 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
         DataType::Uint64 => Ok(NvValue::Uint64(try!(xdr.decode_u64()))),
         DataType::String => Ok(NvValue::String(try!(xdr.decode_string()))),
         DataType::ByteArray => {
+            // Byte arrays are packed opaque data rather than one XDR unit per byte
             let mut v = vec![0; num_elements];
-            for v in &mut v {
-                *v = try!(xdr.decode_u8());
-            }
+            try!(xdr.decode_opaque(&mut v));
             Ok(NvValue::ByteArray(v))
         }
         DataType::Int16Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_i16());
@@ -185,6 +385,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Int16Array(v))
         }
         DataType::Uint16Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_u16());
@@ -192,6 +393,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Uint16Array(v))
         }
         DataType::Int32Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_i32());
@@ -199,6 +401,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Int32Array(v))
         }
         DataType::Uint32Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_u32());
@@ -206,6 +409,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Uint32Array(v))
         }
         DataType::Int64Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_i64());
@@ -213,6 +417,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Int64Array(v))
         }
         DataType::Uint64Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_u64());
@@ -220,11 +425,11 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Uint64Array(v))
         }
         DataType::StringArray => {
-            let mut v = vec![0; num_elements];
-            for v in &mut v {
-                *v = try!(xdr.decode_u64());
+            let mut v = Vec::with_capacity(num_elements);
+            for _ in 0..num_elements {
+                v.push(try!(xdr.decode_string()));
             }
-            Ok(NvValue::Uint64Array(v))
+            Ok(NvValue::StringArray(v))
         }
         DataType::HrTime => Ok(NvValue::HrTime(try!(xdr.decode_i64()))),
         DataType::NvList => {
@@ -242,6 +447,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
         DataType::Int8 => Ok(NvValue::Int8(try!(xdr.decode_i8()))),
         DataType::Uint8 => Ok(NvValue::Uint8(try!(xdr.decode_u8()))),
         DataType::BooleanArray => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![false; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_bool());
@@ -249,6 +455,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::BooleanArray(v))
         }
         DataType::Int8Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_i8());
@@ -256,6 +463,7 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
             Ok(NvValue::Int8Array(v))
         }
         DataType::Uint8Array => {
+            try!(decode_array_len(xdr, num_elements));
             let mut v = vec![0; num_elements];
             for v in &mut v {
                 *v = try!(xdr.decode_u8());
@@ -264,3 +472,30 @@ fn decode_nv_value(xdr: &mut xdr::Xdr,
         }
     }
 }
+
+#[test]
+fn test_nv_list_round_trip() {
+    let mut child = NvList::new(1);
+    child.add("type".to_owned(), NvValue::String("disk".to_owned()));
+    child.add("whole_disk".to_owned(), NvValue::Uint64(1));
+
+    let mut nv_list = NvList::new(1);
+    nv_list.add("name".to_owned(), NvValue::String("tank".to_owned()));
+    nv_list.add("feature".to_owned(), NvValue::Boolean);
+    nv_list.add("stats".to_owned(), NvValue::Uint64Array(vec![1, 2, 3]));
+    nv_list.add("names".to_owned(),
+                NvValue::StringArray(vec!["a".to_owned(), "bcdef".to_owned()]));
+    nv_list.add("bytes".to_owned(), NvValue::ByteArray(vec![1, 2, 3, 4, 5]));
+    nv_list.add("children".to_owned(), NvValue::NvListArray(vec![child]));
+
+    let mut bytes = vec![0; encoded_size(&nv_list)];
+    encode_nv_list(&mut xdr::MemOps::new(&mut bytes), &nv_list).unwrap();
+    let decoded = decode_nv_list(&mut xdr::MemOps::new(&mut bytes)).unwrap();
+
+    assert_eq!(decoded.get::<&String>("name"), Some(&"tank".to_owned()));
+    assert!(decoded.find("feature").is_some());
+    assert_eq!(decoded.get::<&Vec<u64>>("stats"), Some(&vec![1, 2, 3]));
+    assert_eq!(decoded.get::<&Vec<String>>("names").map(|x| x.len()), Some(2));
+    let children = decoded.get::<&Vec<NvList>>("children").unwrap();
+    assert_eq!(children[0].get::<u64>("whole_disk"), Some(1));
+}
@@ -0,0 +1,226 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::rc::Rc;
+
+use super::avl;
+use super::space_map::Segment;
+
+/// Buckets of the segment size histogram: one for each power of two a u64 can hold
+pub const RANGE_TREE_HISTOGRAM_SIZE: usize = 64;
+
+/// A set of disjoint ranges of space, e.g. the free space of a metaslab or what got freed in a
+/// txg. Ranges that touch are coalesced into one segment, so the segments in the tree are never
+/// adjacent. The total size of the segments and a histogram of their sizes are kept up to date.
+pub struct RangeTree {
+    tree: avl::Tree<Segment, u64>, // Sorted by start
+    space: u64,
+    histogram: [u64; RANGE_TREE_HISTOGRAM_SIZE],
+}
+
+impl RangeTree {
+    pub fn new() -> Self {
+        RangeTree {
+            tree: avl::Tree::new(Rc::new(|seg: &Segment| seg.start)),
+            space: 0,
+            histogram: [0; RANGE_TREE_HISTOGRAM_SIZE],
+        }
+    }
+
+    /// Total size of the segments
+    pub fn space(&self) -> u64 {
+        self.space
+    }
+
+    /// Number of segments
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Number of segments for each size: bucket `i` counts the ones with `2^i <= size < 2^(i+1)`
+    pub fn histogram(&self) -> &[u64; RANGE_TREE_HISTOGRAM_SIZE] {
+        &self.histogram
+    }
+
+    /// Add `[start, start + size)`, which mustn't overlap the ranges in the tree yet. It's merged
+    /// with the segments it touches.
+    pub fn add(&mut self, start: u64, size: u64) {
+        assert!(size != 0, "adding an empty range");
+        let end = start + size;
+        let before = self.tree.nearest_before(start).map(|seg| (seg.start, seg.start + seg.size));
+        let after = self.tree.nearest_after(start).map(|seg| (seg.start, seg.start + seg.size));
+        if let Some((_, before_end)) = before {
+            assert!(before_end <= start, "adding an existing range at {:#x}", start);
+        }
+        if let Some((after_start, _)) = after {
+            assert!(after_start >= end, "adding an existing range at {:#x}", start);
+        }
+
+        let mut seg_start = start;
+        let mut seg_end = end;
+        if let Some((before_start, before_end)) = before {
+            if before_end == start {
+                self.remove_segment(before_start);
+                seg_start = before_start;
+            }
+        }
+        if let Some((after_start, after_end)) = after {
+            if after_start == end {
+                self.remove_segment(after_start);
+                seg_end = after_end;
+            }
+        }
+        self.insert_segment(seg_start, seg_end - seg_start);
+        self.space += size;
+    }
+
+    /// Take `[start, start + size)` out of the tree. It has to be in a single segment, which is
+    /// split if the range is from the middle of it.
+    pub fn remove(&mut self, start: u64, size: u64) {
+        assert!(size != 0, "removing an empty range");
+        let end = start + size;
+        let (seg_start, seg_end) = match self.tree.nearest_before(start) {
+            Some(seg) if seg.start + seg.size >= end => (seg.start, seg.start + seg.size),
+            _ => panic!("removing a range at {:#x} that isn't in the tree", start),
+        };
+        self.remove_segment(seg_start);
+        if seg_start < start {
+            self.insert_segment(seg_start, start - seg_start);
+        }
+        if end < seg_end {
+            self.insert_segment(end, seg_end - end);
+        }
+        self.space -= size;
+    }
+
+    /// Take whatever parts of `[start, start + size)` are in the tree out of it
+    pub fn clear(&mut self, start: u64, size: u64) {
+        let end = start + size;
+        let mut overlapping: Vec<(u64, u64)> = Vec::new();
+        if let Some(seg) = self.tree.nearest_before(start) {
+            if seg.start + seg.size > start {
+                overlapping.push((seg.start, seg.start + seg.size));
+            }
+        }
+        overlapping.extend(self.tree
+                               .range(start, end)
+                               .filter(|seg| seg.start != start)
+                               .map(|seg| (seg.start, seg.start + seg.size)));
+        for (seg_start, seg_end) in overlapping {
+            let clear_start = if seg_start < start { start } else { seg_start };
+            let clear_end = if seg_end > end { end } else { seg_end };
+            self.remove(clear_start, clear_end - clear_start);
+        }
+    }
+
+    /// Whether all of `[start, start + size)` is in the tree
+    pub fn contains(&self, start: u64, size: u64) -> bool {
+        match self.tree.nearest_before(start) {
+            Some(seg) => seg.start + seg.size >= start + size,
+            None => false,
+        }
+    }
+
+    /// The first segment overlapping `[start, start + size)`, if any
+    pub fn find(&self, start: u64, size: u64) -> Option<&Segment> {
+        if let Some(seg) = self.tree.nearest_before(start) {
+            if seg.start + seg.size > start {
+                return Some(seg);
+            }
+        }
+        self.tree.range(start, start + size).next()
+    }
+
+    /// The segments in order of their starts
+    pub fn iter<'a>(&'a self) -> avl::Iter<'a, Segment, u64> {
+        self.tree.iter()
+    }
+
+    /// Call `f` with the start and size of each segment, in order
+    pub fn walk(&self, f: &mut FnMut(u64, u64)) {
+        for seg in self.tree.iter() {
+            f(seg.start, seg.size);
+        }
+    }
+
+    /// Empty the tree, calling `f` with the start and size of each segment first if it's given
+    pub fn vacate(&mut self, f: Option<&mut FnMut(u64, u64)>) {
+        if let Some(f) = f {
+            self.walk(f);
+        }
+        *self = RangeTree::new();
+    }
+
+    fn insert_segment(&mut self, start: u64, size: u64) {
+        self.histogram[63 - size.leading_zeros() as usize] += 1;
+        self.tree.insert(Segment {
+            start: start,
+            size: size,
+        });
+    }
+
+    fn remove_segment(&mut self, start: u64) {
+        let seg = self.tree.remove(start).unwrap();
+        self.histogram[63 - seg.size.leading_zeros() as usize] -= 1;
+    }
+}
+
+#[test]
+fn test_range_tree() {
+    let segments = |rt: &RangeTree| {
+        rt.iter().map(|seg| (seg.start, seg.size)).collect::<Vec<(u64, u64)>>()
+    };
+
+    let mut rt = RangeTree::new();
+    rt.add(100, 50);
+    rt.add(300, 100);
+    rt.add(200, 10);
+    assert_eq!(segments(&rt), vec![(100, 50), (200, 10), (300, 100)]);
+    assert_eq!(rt.space(), 160);
+    assert_eq!(rt.histogram()[5], 1); // 50
+    assert_eq!(rt.histogram()[3], 1); // 10
+    assert_eq!(rt.histogram()[6], 1); // 100
+
+    // Touching ranges coalesce, on either side or both
+    rt.add(150, 50);
+    assert_eq!(segments(&rt), vec![(100, 110), (300, 100)]);
+    rt.add(290, 10);
+    rt.add(210, 80);
+    assert_eq!(segments(&rt), vec![(100, 300)]);
+    assert_eq!(rt.space(), 300);
+    assert_eq!(rt.histogram().iter().sum::<u64>(), 1);
+    assert_eq!(rt.histogram()[8], 1);
+
+    // Removing splits a segment
+    rt.remove(100, 20);
+    rt.remove(200, 50);
+    rt.remove(380, 20);
+    assert_eq!(segments(&rt), vec![(120, 80), (250, 130)]);
+    assert_eq!(rt.space(), 210);
+    assert!(rt.contains(250, 130));
+    assert!(rt.contains(130, 10));
+    assert!(!rt.contains(190, 20));
+    assert_eq!(rt.find(190, 100).map(|seg| seg.start), Some(120));
+    assert_eq!(rt.find(200, 100).map(|seg| seg.start), Some(250));
+    assert!(rt.find(200, 50).is_none());
+
+    // Clearing takes out only what's there
+    rt.clear(150, 150);
+    assert_eq!(segments(&rt), vec![(120, 30), (300, 80)]);
+    assert_eq!(rt.space(), 110);
+    rt.clear(0, 1000);
+    assert!(rt.is_empty());
+    assert_eq!(rt.space(), 0);
+    assert_eq!(rt.histogram().iter().sum::<u64>(), 0);
+
+    rt.add(0, 512);
+    rt.add(4096, 512);
+    let mut walked = Vec::new();
+    rt.vacate(Some(&mut |start, size| walked.push((start, size))));
+    assert_eq!(walked, vec![(0, 512), (4096, 512)]);
+    assert!(rt.is_empty());
+}
+
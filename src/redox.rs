@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, SeekFrom, Write};
+use std::sync::Arc;
+use std::{mem, ptr, slice, str};
+
+use super::ZfsReader;
+use super::dsl_dataset::Dataset;
+use super::zap;
+use super::zpl::{FileType, Stat};
+use super::zpl_handle::{HandleTable, OpenFlags};
+
+/// The scheme the files are served under, as in `zfs:/home/notes`
+pub const SCHEME_NAME: &'static str = "zfs";
+
+// Syscall numbers the kernel passes on in packets (`syscall::number`)
+const SYS_CLASS_PATH: usize = 0x1000_0000;
+const SYS_CLASS_FILE: usize = 0x2000_0000;
+const SYS_ARG_SLICE: usize = 0x0100_0000;
+const SYS_ARG_MSLICE: usize = 0x0200_0000;
+const SYS_RET_FILE: usize = 0x0010_0000;
+
+const SYS_OPEN: usize = SYS_CLASS_PATH | SYS_RET_FILE | 5;
+const SYS_CHMOD: usize = SYS_CLASS_PATH | 15;
+const SYS_RMDIR: usize = SYS_CLASS_PATH | 84;
+const SYS_UNLINK: usize = SYS_CLASS_PATH | 10;
+const SYS_CLOSE: usize = SYS_CLASS_FILE | 6;
+const SYS_READ: usize = SYS_CLASS_FILE | SYS_ARG_MSLICE | 3;
+const SYS_WRITE: usize = SYS_CLASS_FILE | SYS_ARG_SLICE | 4;
+const SYS_LSEEK: usize = SYS_CLASS_FILE | 19;
+const SYS_FSTAT: usize = SYS_CLASS_FILE | SYS_ARG_MSLICE | 28;
+const SYS_FSYNC: usize = SYS_CLASS_FILE | 118;
+const SYS_FTRUNCATE: usize = SYS_CLASS_FILE | 93;
+const SYS_FCHMOD: usize = SYS_CLASS_FILE | 94;
+const SYS_FUTIMENS: usize = SYS_CLASS_FILE | SYS_ARG_SLICE | 320;
+const SYS_FPATH: usize = SYS_CLASS_FILE | SYS_ARG_MSLICE | 928;
+
+// Redox's `open` flags
+const O_ACCMODE: usize = 0x0003_0000;
+const O_RDONLY: usize = 0x0001_0000;
+const O_APPEND: usize = 0x0008_0000;
+const O_CREAT: usize = 0x0200_0000;
+const O_TRUNC: usize = 0x0400_0000;
+const O_DIRECTORY: usize = 0x1000_0000;
+const O_SYMLINK: usize = 0x4000_0000;
+const O_NOFOLLOW: usize = 0x8000_0000;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+// errno values, numbered as on Linux
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const EROFS: i32 = 30;
+const ENOSYS: i32 = 38;
+
+/// A request from the kernel, read from the scheme, and the reply written back to it: `a` is
+/// the syscall number, and then its result
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Packet {
+    pub id: u64,
+    pub pid: usize,
+    pub uid: u32,
+    pub gid: u32,
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+    pub d: usize,
+}
+
+/// Redox's `struct stat`, which `fstat` fills in
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RedoxStat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u16,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_size: u64,
+    pub st_blksize: u32,
+    pub st_blocks: u64,
+    pub st_mtime: u64,
+    pub st_mtime_nsec: u32,
+    pub st_atime: u64,
+    pub st_atime_nsec: u32,
+    pub st_ctime: u64,
+    pub st_ctime_nsec: u32,
+}
+
+impl RedoxStat {
+    /// `stat` with its size replaced by `size`, which for directories is the length of their
+    /// listing
+    fn from_stat(stat: &Stat, size: u64) -> Self {
+        let times = &stat.times;
+        RedoxStat {
+            st_dev: 0,
+            st_ino: stat.object,
+            st_mode: stat.mode as u16,
+            st_nlink: stat.links as u32,
+            st_uid: stat.uid as u32,
+            st_gid: stat.gid as u32,
+            st_size: size,
+            st_blksize: 128 << 10, // The default recordsize
+            st_blocks: (size + 511) / 512,
+            st_mtime: times.mtime.secs,
+            st_mtime_nsec: times.mtime.nsecs as u32,
+            st_atime: times.atime.secs,
+            st_atime_nsec: times.atime.nsecs as u32,
+            st_ctime: times.ctime.secs,
+            st_ctime_nsec: times.ctime.nsecs as u32,
+        }
+    }
+}
+
+/// The flags of an `open`: it's refused if it's for writing, creating or truncating
+fn open_flags(flags: usize) -> Result<OpenFlags, i32> {
+    let accmode = flags & O_ACCMODE;
+    if (accmode != 0 && accmode != O_RDONLY) || flags & (O_CREAT | O_TRUNC | O_APPEND) != 0 {
+        return Err(EROFS);
+    }
+    Ok(OpenFlags {
+        directory: flags & O_DIRECTORY != 0,
+        nofollow: flags & (O_NOFOLLOW | O_SYMLINK) != 0,
+    })
+}
+
+/// A syscall result as the kernel takes it back: errors are their negated errno
+fn mux(result: Result<usize, i32>) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => -(errno as isize) as usize,
+    }
+}
+
+/// What a handle was opened as, besides what the handle table has
+struct Opened {
+    path: String,
+    /// The names of the entries of a directory, a line each, which is what reading one gives
+    listing: Option<Vec<u8>>,
+}
+
+/// A read-only `zfs:` scheme, serving the files of a ZPL dataset. Handles are those of a
+/// `HandleTable`; directories read as a list of their entries.
+pub struct ZfsScheme {
+    dataset: Arc<Dataset>,
+    handles: HandleTable,
+    opened: HashMap<u64, Opened>,
+}
+
+impl ZfsScheme {
+    /// Serve `dataset`, which should have been opened pinned
+    pub fn new(dataset: Arc<Dataset>) -> Self {
+        ZfsScheme {
+            dataset: dataset,
+            handles: HandleTable::new(),
+            opened: HashMap::new(),
+        }
+    }
+
+    /// Open `path`, relative to the root of the dataset, with Redox's `open` flags
+    pub fn open(&mut self, reader: &mut ZfsReader, path: &str, flags: usize) -> Result<usize, i32> {
+        let flags = try!(open_flags(flags));
+        let path = format!("/{}", path.trim_matches('/'));
+        let handle = try!(self.handles
+                              .open(reader, &self.dataset, &path, flags)
+                              .map_err(|_| ENOENT));
+        let file = self.handles.get(handle).unwrap();
+        let listing = if file.stat.file_type() == FileType::Directory {
+            match zap::iter(reader, &self.dataset.objset, file.object) {
+                Ok(entries) => {
+                    let mut listing = Vec::new();
+                    for (name, _) in entries {
+                        listing.extend_from_slice(name.as_bytes());
+                        listing.push(b'\n');
+                    }
+                    Some(listing)
+                }
+                Err(_) => {
+                    self.handles.close(handle);
+                    return Err(EIO);
+                }
+            }
+        } else {
+            None
+        };
+        self.opened.insert(handle,
+                           Opened {
+                               path: path,
+                               listing: listing,
+                           });
+        Ok(handle as usize)
+    }
+
+    /// Read from the current offset into `buf`, moving the offset past what was read
+    pub fn read(&mut self,
+                reader: &mut ZfsReader,
+                id: usize,
+                buf: &mut [u8])
+                -> Result<usize, i32> {
+        let file = try!(self.handles.get(id as u64).ok_or(EBADF));
+        if let Some(ref listing) = try!(self.opened.get(&(id as u64)).ok_or(EBADF)).listing {
+            let offset = try!(file.seek(SeekFrom::Current(0)).map_err(|_| EIO)) as usize;
+            let start = if offset < listing.len() { offset } else { listing.len() };
+            let left = listing.len() - start;
+            let len = if left < buf.len() { left } else { buf.len() };
+            buf[..len].copy_from_slice(&listing[start..start + len]);
+            try!(file.seek(SeekFrom::Start((start + len) as u64)).map_err(|_| EIO));
+            return Ok(len);
+        }
+        let data = try!(file.read(reader, buf.len()).map_err(|_| EIO));
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Move the offset of `id` to `pos` from where `whence` says. Returns the new offset.
+    pub fn seek(&mut self, id: usize, pos: isize, whence: usize) -> Result<usize, i32> {
+        let file = try!(self.handles.get(id as u64).ok_or(EBADF));
+        let opened = try!(self.opened.get(&(id as u64)).ok_or(EBADF));
+        let pos = match (whence, &opened.listing) {
+            (SEEK_SET, _) if pos >= 0 => SeekFrom::Start(pos as u64),
+            (SEEK_CUR, _) => SeekFrom::Current(pos as i64),
+            (SEEK_END, &Some(ref listing)) if pos >= -(listing.len() as isize) => {
+                SeekFrom::Start((listing.len() as isize + pos) as u64)
+            }
+            (SEEK_END, &None) => SeekFrom::End(pos as i64),
+            _ => return Err(EINVAL),
+        };
+        file.seek(pos).map(|offset| offset as usize).map_err(|_| EINVAL)
+    }
+
+    pub fn fstat(&self, id: usize) -> Result<RedoxStat, i32> {
+        let file = try!(self.handles.get(id as u64).ok_or(EBADF));
+        let opened = try!(self.opened.get(&(id as u64)).ok_or(EBADF));
+        let size = opened.listing.as_ref().map_or(file.stat.size, |listing| listing.len() as u64);
+        Ok(RedoxStat::from_stat(&file.stat, size))
+    }
+
+    /// The full path of `id`, scheme included, into `buf`, as much as fits
+    pub fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        let opened = try!(self.opened.get(&(id as u64)).ok_or(EBADF));
+        let path = format!("{}:{}", SCHEME_NAME, opened.path);
+        let len = if path.len() < buf.len() { path.len() } else { buf.len() };
+        buf[..len].copy_from_slice(&path.as_bytes()[..len]);
+        Ok(len)
+    }
+
+    pub fn close(&mut self, id: usize) -> Result<usize, i32> {
+        self.opened.remove(&(id as u64));
+        if self.handles.close(id as u64) { Ok(0) } else { Err(EBADF) }
+    }
+
+    /// Answer `packet`, as read from the scheme, putting the result in its `a`. The buffers it
+    /// points to are the ones the kernel mapped into our address space for the request.
+    pub fn handle(&mut self, reader: &mut ZfsReader, packet: &mut Packet) {
+        let result = match packet.a {
+            SYS_OPEN => {
+                let path = unsafe { slice::from_raw_parts(packet.b as *const u8, packet.c) };
+                match str::from_utf8(path) {
+                    Ok(path) => self.open(reader, path, packet.d),
+                    Err(_) => Err(ENOENT),
+                }
+            }
+            SYS_READ => {
+                let buf = unsafe { slice::from_raw_parts_mut(packet.c as *mut u8, packet.d) };
+                self.read(reader, packet.b, buf)
+            }
+            SYS_LSEEK => self.seek(packet.b, packet.c as isize, packet.d),
+            SYS_FSTAT => {
+                if packet.d < mem::size_of::<RedoxStat>() {
+                    Err(EFAULT)
+                } else {
+                    self.fstat(packet.b).map(|stat| {
+                        unsafe { ptr::write_unaligned(packet.c as *mut RedoxStat, stat) };
+                        0
+                    })
+                }
+            }
+            SYS_FPATH => {
+                let buf = unsafe { slice::from_raw_parts_mut(packet.c as *mut u8, packet.d) };
+                self.fpath(packet.b, buf)
+            }
+            SYS_FSYNC => {
+                if self.opened.contains_key(&(packet.b as u64)) { Ok(0) } else { Err(EBADF) }
+            }
+            SYS_CLOSE => self.close(packet.b),
+            SYS_WRITE | SYS_FTRUNCATE | SYS_FCHMOD | SYS_FUTIMENS | SYS_CHMOD | SYS_RMDIR |
+            SYS_UNLINK => Err(EROFS),
+            _ => Err(ENOSYS),
+        };
+        packet.a = mux(result);
+    }
+}
+
+/// Answer the packets read from `dev`, the scheme's file, until it's closed
+pub fn serve<D: Read + Write>(dev: &mut D,
+                              reader: &mut ZfsReader,
+                              scheme: &mut ZfsScheme)
+                              -> io::Result<()> {
+    let mut packet = Packet::default();
+    loop {
+        let len = {
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(&mut packet as *mut Packet as *mut u8,
+                                          mem::size_of::<Packet>())
+            };
+            match dev.read(bytes) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        if len != mem::size_of::<Packet>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Short scheme packet"));
+        }
+        scheme.handle(reader, &mut packet);
+        let bytes = unsafe {
+            slice::from_raw_parts(&packet as *const Packet as *const u8, mem::size_of::<Packet>())
+        };
+        try!(dev.write_all(bytes));
+    }
+}
+
+/// Register the `zfs:` scheme and serve `dataset` through it, until the scheme is closed. Only
+/// works on Redox.
+pub fn mount(reader: &mut ZfsReader, dataset: Arc<Dataset>) -> Result<(), String> {
+    let mut scheme = ZfsScheme::new(dataset);
+    let mut dev = try!(OpenOptions::new()
+                           .read(true)
+                           .write(true)
+                           .create(true)
+                           .open(format!(":{}", SCHEME_NAME))
+                           .map_err(|e| format!(":{}: {}", SCHEME_NAME, e)));
+    serve(&mut dev, reader, &mut scheme).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_redox_scheme() {
+    use super::znode::{Timestamp, Times};
+
+    assert_eq!(mem::size_of::<Packet>(), 56);
+    assert_eq!(mux(Ok(3)), 3);
+    assert_eq!(mux(Err(EBADF)), !0 - 8);
+
+    assert_eq!(open_flags(O_RDONLY | O_DIRECTORY),
+               Ok(OpenFlags {
+                   directory: true,
+                   nofollow: false,
+               }));
+    assert_eq!(open_flags(O_SYMLINK).map(|flags| flags.nofollow), Ok(true));
+    assert_eq!(open_flags(0x0003_0000), Err(EROFS));
+    assert_eq!(open_flags(O_RDONLY | O_CREAT), Err(EROFS));
+
+    let time = |secs| Timestamp { secs: secs, nsecs: 5 };
+    let stat = Stat {
+        object: 8,
+        mode: 0o40755,
+        uid: 1000,
+        gid: 1000,
+        size: 4,
+        links: 3,
+        parent: 8,
+        gen: 4,
+        rdev: 0,
+        flags: 0,
+        times: Times {
+            atime: time(1),
+            mtime: time(2),
+            ctime: time(3),
+            crtime: time(0),
+        },
+        blocks: 1,
+    };
+    let redox = RedoxStat::from_stat(&stat, 1000);
+    assert_eq!((redox.st_ino, redox.st_mode, redox.st_nlink), (8, 0o40755, 3));
+    assert_eq!((redox.st_size, redox.st_blocks), (1000, 2));
+    assert_eq!((redox.st_mtime, redox.st_atime, redox.st_ctime_nsec), (2, 1, 5));
+}
@@ -0,0 +1,450 @@
+use std::{cmp, fmt, io};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::checksum::{self, ChecksumType};
+
+/// Sent first by the sending side, followed by the protocol version
+const REPL_MAGIC: u64 = 0x7A66_7265_706C_0001;
+const REPL_VERSION: u32 = 1;
+
+/// Largest payload of one frame. Streams are cut into frames of at most this many bytes.
+pub const REPL_MAX_FRAME: usize = 128 << 10;
+
+/// How many bytes of stream the receiver lets the sender have in flight by default
+pub const REPL_DEFAULT_WINDOW: u32 = 4 << 20;
+
+// Frame kinds. Every frame is its kind (1 byte), its payload length (4 bytes, big endian) and
+// the payload.
+const FRAME_HELLO: u8 = 1;
+const FRAME_CHALLENGE: u8 = 2;
+const FRAME_RESPONSE: u8 = 3;
+const FRAME_DATA: u8 = 4;
+const FRAME_END: u8 = 5;
+const FRAME_CREDIT: u8 = 6;
+const FRAME_DONE: u8 = 7;
+
+/// Why replicating a stream over a connection failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplError {
+    /// Reading or writing the connection or the stream failed
+    Io(io::ErrorKind),
+    /// The other side sent something the protocol doesn't allow there
+    Protocol(&'static str),
+    /// The sender didn't answer the receiver's challenge right
+    AuthFailed,
+    /// The receiver failed to receive the stream, for the given reason
+    Remote(String),
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplError::Io(kind) => write!(f, "Connection failed: {:?}", kind),
+            ReplError::Protocol(what) => write!(f, "Protocol error: {}", what),
+            ReplError::AuthFailed => f.write_str("Authentication failed"),
+            ReplError::Remote(ref e) => write!(f, "Receiver failed: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ReplError {
+    fn from(e: io::Error) -> Self {
+        ReplError::Io(e.kind())
+    }
+}
+
+/// How the two sides of a connection make sure of each other. The receiver sends a challenge,
+/// the sender answers it and the receiver checks the answer before taking any of the stream.
+pub trait Authenticator {
+    /// A fresh challenge for a connecting sender
+    fn challenge(&self) -> Vec<u8>;
+
+    /// The sender's answer to `challenge`
+    fn respond(&self, challenge: &[u8]) -> Vec<u8>;
+
+    /// Whether `response` is the right answer to `challenge`
+    fn verify(&self, challenge: &[u8], response: &[u8]) -> bool {
+        self.respond(challenge) == response
+    }
+}
+
+/// Lets anyone in, for networks where the connection is trusted (or secured) already
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn challenge(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn respond(&self, _: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// Both sides know the same secret: the answer is the SHA-256 of the secret and the challenge.
+/// This keeps the secret off the wire, but the stream itself isn't encrypted.
+pub struct SharedSecret {
+    secret: Vec<u8>,
+}
+
+impl SharedSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        SharedSecret { secret: secret.to_vec() }
+    }
+}
+
+impl Authenticator for SharedSecret {
+    fn challenge(&self) -> Vec<u8> {
+        // Challenges only have to differ between connections, the clock is good enough for that
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut challenge = Vec::with_capacity(12);
+        put_u64(&mut challenge, now.as_secs());
+        put_u32(&mut challenge, now.subsec_nanos());
+        challenge
+    }
+
+    fn respond(&self, challenge: &[u8]) -> Vec<u8> {
+        let mut data = self.secret.clone();
+        data.extend_from_slice(challenge);
+        let hash = checksum::compute(ChecksumType::Sha256 as u64, &data, false).unwrap();
+        let mut response = Vec::with_capacity(32);
+        for word in &hash {
+            put_u64(&mut response, *word);
+        }
+        response
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    for i in (0..4).rev() {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    for i in (0..8).rev() {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn get_u32(bytes: &[u8]) -> u32 {
+    bytes[..4].iter().fold(0, |value, &b| value << 8 | b as u32)
+}
+
+fn get_u64(bytes: &[u8]) -> u64 {
+    bytes[..8].iter().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_frame<W: Write + ?Sized>(conn: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    put_u32(&mut frame, payload.len() as u32);
+    frame.extend_from_slice(payload);
+    try!(conn.write_all(&frame));
+    conn.flush()
+}
+
+fn read_frame<R: Read + ?Sized>(conn: &mut R) -> Result<(u8, Vec<u8>), ReplError> {
+    let mut header = [0; 5];
+    try!(conn.read_exact(&mut header));
+    let len = get_u32(&header[1..]) as usize;
+    if len > REPL_MAX_FRAME {
+        return Err(ReplError::Protocol("frame too large"));
+    }
+    let mut payload = vec![0; len];
+    try!(conn.read_exact(&mut payload));
+    Ok((header[0], payload))
+}
+
+/// The receiver's last word: how many bytes of stream it took, or why it failed
+fn write_done<W: Write + ?Sized>(conn: &mut W, result: &Result<u64, String>) -> io::Result<()> {
+    let mut payload = Vec::new();
+    match *result {
+        Ok(bytes) => {
+            payload.push(0);
+            put_u64(&mut payload, bytes);
+        }
+        Err(ref e) => {
+            payload.push(1);
+            put_u64(&mut payload, 0);
+            payload.extend_from_slice(e.as_bytes());
+        }
+    }
+    write_frame(conn, FRAME_DONE, &payload)
+}
+
+fn parse_done(payload: &[u8]) -> Result<u64, ReplError> {
+    if payload.len() < 9 {
+        return Err(ReplError::Protocol("short DONE frame"));
+    }
+    match payload[0] {
+        0 => Ok(get_u64(&payload[1..])),
+        _ => Err(ReplError::Remote(String::from_utf8_lossy(&payload[9..]).into_owned())),
+    }
+}
+
+/// Send the stream read from `stream` over `conn` to a receiver running `accept`, and return
+/// how many bytes of it the receiver took. Sending waits whenever the receiver hasn't granted
+/// it room for more, so a slow receiver slows the sender down instead of piling the stream up
+/// in memory.
+pub fn send<C: Read + Write, R: Read>(conn: &mut C,
+                                      auth: &Authenticator,
+                                      stream: &mut R)
+                                      -> Result<u64, ReplError> {
+    let mut hello = Vec::new();
+    put_u64(&mut hello, REPL_MAGIC);
+    put_u32(&mut hello, REPL_VERSION);
+    try!(write_frame(conn, FRAME_HELLO, &hello));
+
+    let challenge = match try!(read_frame(conn)) {
+        (FRAME_CHALLENGE, challenge) => challenge,
+        (FRAME_DONE, payload) => return parse_done(&payload),
+        _ => return Err(ReplError::Protocol("expected CHALLENGE")),
+    };
+    try!(write_frame(conn, FRAME_RESPONSE, &auth.respond(&challenge)));
+
+    let mut credit = 0;
+    let mut buf = vec![0; REPL_MAX_FRAME];
+    loop {
+        while credit == 0 {
+            match try!(read_frame(conn)) {
+                (FRAME_CREDIT, ref payload) if payload.len() == 4 => credit += get_u32(payload),
+                // The receiver gave up (or turned us away) before the end of the stream
+                (FRAME_DONE, payload) => return parse_done(&payload),
+                _ => return Err(ReplError::Protocol("expected CREDIT")),
+            }
+        }
+        let len = cmp::min(credit as usize, buf.len());
+        let read = try!(stream.read(&mut buf[..len]));
+        if read == 0 {
+            break;
+        }
+        try!(write_frame(conn, FRAME_DATA, &buf[..read]));
+        credit -= read as u32;
+    }
+    try!(write_frame(conn, FRAME_END, &[]));
+
+    loop {
+        match try!(read_frame(conn)) {
+            (FRAME_CREDIT, _) => {}
+            (FRAME_DONE, payload) => return parse_done(&payload),
+            _ => return Err(ReplError::Protocol("expected DONE")),
+        }
+    }
+}
+
+/// The stream a sender sends, read from its connection. It grants the sender more room as the
+/// stream is read, so the sender never gets more than a window ahead of whoever reads it.
+pub struct FrameReader<'a, C: 'a + Read + Write> {
+    conn: &'a mut C,
+    window: u32,
+    /// Bytes read since the sender was last granted room for more
+    consumed: u32,
+    frame: Vec<u8>,
+    pos: usize,
+    bytes: u64,
+    ended: bool,
+}
+
+impl<'a, C: Read + Write> FrameReader<'a, C> {
+    fn new(conn: &'a mut C, window: u32) -> Self {
+        FrameReader {
+            conn: conn,
+            window: window,
+            consumed: 0,
+            frame: Vec::new(),
+            pos: 0,
+            bytes: 0,
+            ended: false,
+        }
+    }
+
+    /// How many bytes of the stream have been read
+    pub fn received(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Read the frames up to the end of the stream, which has to have been read whole already
+    fn finish(&mut self) -> Result<(), ReplError> {
+        if self.pos < self.frame.len() {
+            return Err(ReplError::Protocol("data after the end of the stream"));
+        }
+        while !self.ended {
+            match try!(read_frame(self.conn)) {
+                (FRAME_END, _) => self.ended = true,
+                (FRAME_DATA, ref data) if data.is_empty() => {}
+                (FRAME_DATA, _) => {
+                    return Err(ReplError::Protocol("data after the end of the stream"));
+                }
+                _ => return Err(ReplError::Protocol("expected END")),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, C: Read + Write> Read for FrameReader<'a, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.frame.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            // Grant the sender more room once half the window has been read, not for every
+            // frame, which would double the number of frames
+            if self.consumed >= self.window / 2 {
+                try!(write_frame(self.conn, FRAME_CREDIT, &be_u32(self.consumed)));
+                self.consumed = 0;
+            }
+            match read_frame(self.conn) {
+                Ok((FRAME_DATA, data)) => {
+                    self.frame = data;
+                    self.pos = 0;
+                }
+                Ok((FRAME_END, _)) => self.ended = true,
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected DATA")),
+                Err(ReplError::Io(kind)) => return Err(io::Error::new(kind, "connection failed")),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+        let len = cmp::min(buf.len(), self.frame.len() - self.pos);
+        buf[..len].copy_from_slice(&self.frame[self.pos..self.pos + len]);
+        self.pos += len;
+        self.consumed += len as u32;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+}
+
+fn be_u32(value: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4);
+    put_u32(&mut bytes, value);
+    bytes
+}
+
+/// Take a stream from the sender on the other end of `conn`, letting it have `window` bytes in
+/// flight. `receive` is given the stream to read: it reads it to the end (e.g. with
+/// `dmu_recv::receive`), or fails. The sender is told how that went, and so is the caller:
+/// this returns how many bytes were received.
+pub fn accept<C: Read + Write>(conn: &mut C,
+                               auth: &Authenticator,
+                               window: u32,
+                               receive: &mut FnMut(&mut Read) -> Result<(), String>)
+                               -> Result<u64, ReplError> {
+    match try!(read_frame(conn)) {
+        (FRAME_HELLO, ref hello) if hello.len() == 12 && get_u64(hello) == REPL_MAGIC => {
+            if get_u32(&hello[8..]) != REPL_VERSION {
+                try!(write_done(conn, &Err("unsupported protocol version".to_owned())));
+                return Err(ReplError::Protocol("unsupported protocol version"));
+            }
+        }
+        _ => return Err(ReplError::Protocol("expected HELLO")),
+    }
+
+    let challenge = auth.challenge();
+    try!(write_frame(conn, FRAME_CHALLENGE, &challenge));
+    let response = match try!(read_frame(conn)) {
+        (FRAME_RESPONSE, response) => response,
+        _ => return Err(ReplError::Protocol("expected RESPONSE")),
+    };
+    if !auth.verify(&challenge, &response) {
+        try!(write_done(conn, &Err(ReplError::AuthFailed.to_string())));
+        return Err(ReplError::AuthFailed);
+    }
+
+    let window = cmp::max(window, REPL_MAX_FRAME as u32);
+    try!(write_frame(conn, FRAME_CREDIT, &be_u32(window)));
+    let (result, bytes) = {
+        let mut stream = FrameReader::new(conn, window);
+        let result = receive(&mut stream)
+                         .map_err(ReplError::Remote)
+                         .and_then(|_| stream.finish());
+        (result, stream.received())
+    };
+    match result {
+        Ok(()) => {
+            try!(write_done(conn, &Ok(bytes)));
+            Ok(bytes)
+        }
+        Err(e) => {
+            // The connection may be broken already, the error that matters is the first one
+            let message = match e {
+                ReplError::Remote(ref message) => message.clone(),
+                ref e => e.to_string(),
+            };
+            let _ = write_done(conn, &Err(message));
+            Err(e)
+        }
+    }
+}
+
+/// The receiver loop: take streams from the senders connecting to `listener`, one at a time,
+/// passing each to `receive` as `accept` does. `report` hears how each connection went and
+/// returns whether to keep serving.
+pub fn serve(listener: &TcpListener,
+             auth: &Authenticator,
+             window: u32,
+             receive: &mut FnMut(&mut Read) -> Result<(), String>,
+             report: &mut FnMut(SocketAddr, Result<u64, ReplError>) -> bool)
+             -> io::Result<()> {
+    loop {
+        let (mut conn, peer) = try!(listener.accept());
+        let result = accept(&mut conn, auth, window, receive);
+        if !report(peer, result) {
+            return Ok(());
+        }
+    }
+}
+
+#[test]
+fn test_replicate_stream() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let stream: Vec<u8> = (0..1 << 20).map(|i| (i * 7 + i / 251) as u8).collect();
+    let expected = stream.clone();
+
+    let receiver = thread::spawn(move || {
+        let auth = SharedSecret::new(b"hunter2");
+        let mut received = Vec::new();
+        let mut results = Vec::new();
+        serve(&listener,
+              &auth,
+              REPL_MAX_FRAME as u32,
+              &mut |stream| {
+                  let mut data = Vec::new();
+                  try!(stream.read_to_end(&mut data).map_err(|e| e.to_string()));
+                  if data.is_empty() {
+                      return Err("empty stream".to_owned());
+                  }
+                  received.push(data);
+                  Ok(())
+              },
+              &mut |_, result| {
+                  results.push(result);
+                  results.len() < 3
+              })
+            .unwrap();
+        (received, results)
+    });
+
+    let send_to = |secret: &[u8], stream: &[u8]| {
+        let mut conn = TcpStream::connect(address).unwrap();
+        send(&mut conn, &SharedSecret::new(secret), &mut &stream[..])
+    };
+    assert_eq!(send_to(b"hunter2", &stream), Ok(stream.len() as u64));
+    assert_eq!(send_to(b"letmein", &stream),
+               Err(ReplError::Remote("Authentication failed".to_owned())));
+    assert_eq!(send_to(b"hunter2", &[]),
+               Err(ReplError::Remote("empty stream".to_owned())));
+
+    let (received, results) = receiver.join().unwrap();
+    assert_eq!(received, vec![expected]);
+    assert_eq!(results,
+               vec![Ok(stream.len() as u64),
+                    Err(ReplError::AuthFailed),
+                    Err(ReplError::Remote("empty stream".to_owned()))]);
+}
@@ -0,0 +1,362 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use super::ZfsReader;
+use super::block_ptr::{BlockPtr, GANG_HEADER_SIZE, Gang};
+use super::checksum;
+use super::dvaddr::DVAddr;
+use super::from_bytes::FromBytes;
+use super::dmu_objset::{ObjectSet, ObjectSetPhys};
+use super::dsl_pool::DslPool;
+use super::scrub;
+use super::traverse::{self, ObjectFilter};
+use super::zfs;
+use super::zio;
+
+/// A resilver of one child of a mirror or raidz top-level vdev, one that was just attached or
+/// that replaces a failed one: every block on the top-level vdev born before the child was
+/// attached gets its part on the child written from the other children. Blocks are resilvered
+/// in the order of their birth txg, so how far it has got is a txg, and an interrupted resilver
+/// picks up after the last txg it finished.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Resilver {
+    /// The top-level vdev, by ID
+    pub top: u64,
+    /// The child, by its place among the top-level vdev's children
+    pub child: usize,
+    /// The txg the child was attached in. The blocks born after it were written to the child
+    /// along with its siblings.
+    pub max_txg: u64,
+    /// The blocks born up to this txg are on the child already
+    pub done_txg: u64,
+}
+
+impl Resilver {
+    pub fn new(top: u64, child: usize, attach_txg: u64) -> Self {
+        Resilver {
+            top: top,
+            child: child,
+            max_txg: attach_txg,
+            done_txg: 0,
+        }
+    }
+
+    /// The txgs whose blocks the child is still missing (its dirty time log)
+    pub fn missing(&self) -> Range<u64> {
+        self.done_txg + 1..self.max_txg + 1
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done_txg >= self.max_txg
+    }
+}
+
+/// Totals of a resilver run
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ResilverStats {
+    /// Blocks written to the child
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Blocks none of the other children had good data for. The child is left without them.
+    pub unrepairable_blocks: u64,
+}
+
+/// Resilver the blocks of `datasets` (the MOS goes by `traverse::MOS_NAME`), from where
+/// `resilver` left off. Once the blocks of a txg are all written, `resilver.done_txg` moves up
+/// to it and `progress` is called, to save it where it will be found if the resilver is
+/// interrupted; it returns whether to go on. Blocks shared by several datasets, e.g. by a
+/// dataset and its snapshots, are written once. A gang block's header is written along with the
+/// fragments it points at. Device errors stop the resilver, bad data on the other children only
+/// makes for an unrepairable block.
+pub fn resilver(reader: &mut zio::Reader,
+                datasets: &[(&str, &ObjectSetPhys)],
+                resilver: &mut Resilver,
+                progress: &mut FnMut(&Resilver) -> bool)
+                -> zfs::Result<ResilverStats> {
+    let mut stats = ResilverStats::default();
+    if resilver.is_done() {
+        return Ok(stats);
+    }
+
+    // The blocks to write, by birth txg and where they are on the top-level vdev
+    let mut blocks = BTreeMap::new();
+    let missing = resilver.missing();
+    let top = resilver.top;
+    for &(_, objset) in datasets {
+        // Subtrees born before the device went missing have nothing for it
+        try!(traverse::traverse_objset_since(reader,
+                                             objset,
+                                             missing.start.saturating_sub(1),
+                                             ObjectFilter::All,
+                                             &mut |_, _, block_ptr| {
+            if block_ptr.is_embedded() {
+                return;
+            }
+            let birth = block_ptr.physical_birth();
+            if birth < missing.start || birth >= missing.end {
+                return;
+            }
+            let dvas = block_ptr.dvas;
+            let copies = &dvas[..block_ptr.max_copies()];
+            for dva in copies.iter().filter(|dva| !dva.is_empty()) {
+                if dva.vdev_id() == top {
+                    blocks.insert((birth, dva.sector()), (*dva, *block_ptr));
+                }
+            }
+        }));
+    }
+
+    let mut txg = None;
+    let mut written = BTreeSet::new();
+    for ((birth, _), (dva, block_ptr)) in blocks {
+        if let Some(txg) = txg.filter(|&txg| txg < birth) {
+            resilver.done_txg = txg;
+            if !progress(resilver) {
+                return Ok(stats);
+            }
+        }
+        txg = Some(birth);
+        try!(resilver_dva(reader, resilver, &dva, &block_ptr, &mut written, &mut stats));
+    }
+    resilver.done_txg = resilver.max_txg;
+    progress(resilver);
+    Ok(stats)
+}
+
+/// Resilver the copy of `block_ptr` at `dva`. For a gang block that's its header, then the
+/// copies on the top-level vdev of the fragments it points at, which may be gang blocks in turn.
+/// The copies of a gang header point at the same fragments, `written` has the sectors already
+/// done so they're written once.
+fn resilver_dva(reader: &zio::Reader,
+                resilver: &Resilver,
+                dva: &DVAddr,
+                block_ptr: &BlockPtr,
+                written: &mut BTreeSet<u64>,
+                stats: &mut ResilverStats)
+                -> zfs::Result<()> {
+    if !written.insert(dva.sector()) {
+        return Ok(());
+    }
+    let size = if dva.gang() {
+        GANG_HEADER_SIZE as u64
+    } else {
+        block_ptr.psize() * 512
+    };
+    // The header that passed its checksum, to find the fragments with
+    let header = RefCell::new(None);
+    let verify = |data: &[u8]| if dva.gang() {
+        let good = checksum::verify_embedded(data, block_ptr.gang_verifier()).is_ok();
+        if good {
+            *header.borrow_mut() = Some(data.to_vec());
+        }
+        good
+    } else {
+        checksum::verify(block_ptr, data).is_ok()
+    };
+    match reader.resilver_dva(dva, size, resilver.child, &verify) {
+        Ok(()) => {
+            stats.blocks += 1;
+            stats.bytes += size;
+        }
+        // Without the header the fragments can't be found either
+        Err(zfs::Error::Checksum) => stats.unrepairable_blocks += 1,
+        Err(e) => return Err(e),
+    }
+
+    if let Some(header) = header.into_inner() {
+        let gang = try!(Gang::from_bytes_order(&header, block_ptr.data_order()));
+        let fragments = gang.bps;
+        for fragment in fragments.iter().filter(|bp| !bp.is_hole() && !bp.is_embedded()) {
+            let dvas = fragment.dvas;
+            for dva in dvas[..fragment.max_copies()]
+                           .iter()
+                           .filter(|dva| !dva.is_empty() && dva.vdev_id() == resilver.top) {
+                try!(resilver_dva(reader, resilver, dva, fragment, written, stats));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resilver the whole pool: the MOS, the datasets and their snapshots. Returns the datasets and
+/// snapshots that couldn't be opened along with the totals: their blocks are missing from the
+/// child, unless another dataset shares them.
+pub fn resilver_pool(reader: &mut ZfsReader,
+                     mos: &ObjectSet,
+                     dsl_pool: &DslPool,
+                     pool_name: &str,
+                     resilver: &mut Resilver,
+                     progress: &mut FnMut(&Resilver) -> bool)
+                     -> zfs::Result<(ResilverStats, Vec<String>)> {
+    let mut skipped = Vec::new();
+    let objsets = try!(scrub::pool_objsets(reader, mos, dsl_pool, pool_name, &mut skipped));
+    let mut datasets = vec![(traverse::MOS_NAME, &mos.phys)];
+    datasets.extend(objsets.iter().map(|&(ref name, ref objset)| (&name[..], &objset.phys)));
+
+    let stats = try!(self::resilver(&mut reader.zio, &datasets, resilver, progress));
+    Ok((stats, skipped))
+}
+
+#[test]
+fn test_resilver_mirror() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_objset::{DirtyObjset, ObjectSetType};
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::{DNodePhys, ObjectType};
+    use super::from_bytes::FromBytes;
+    use super::metaslab::VdevAllocator;
+    use super::traverse::Filter;
+    use super::vdev_io::MemVdev;
+    use super::vdev_mirror::MirrorVdev;
+    use super::zio_compress::ZIO_COMPRESS_OFF;
+
+    // A file written in txg 5, another in txg 6, on a single device
+    let disk = MemVdev::new(vec![0; 8 << 20]);
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(disk.clone()),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let mut objset = ObjectSet::create(ObjectSetType::Zfs);
+    for (txg, object) in vec![(5, 1), (6, 2)] {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = ObjectType::PlainFileContents as u8;
+        dnode.nblkptr = 1;
+        dnode.nlevels = 1;
+        dnode.indblkshift = 14;
+        dnode.data_blk_sz_sec = 1;
+        let mut dirty = DirtyObjset::default();
+        dirty.dnodes.insert(object, dnode);
+        let blocks: BTreeMap<u64, Vec<u8>> = vec![(0, vec![object as u8; 512])]
+                                                 .into_iter()
+                                                 .collect();
+        dirty.blocks.insert(object, blocks);
+        objset.sync(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, txg, dirty, &mut Vec::new())
+              .unwrap();
+    }
+
+    // Then mirrored onto a blank device, attached in txg 6
+    let attached = MemVdev::new(vec![0; 8 << 20]);
+    let mut mirror = zio::Reader::new(MirrorVdev::new(vec![Box::new(disk.clone()),
+                                                           Box::new(attached.clone())]));
+    let datasets = [("tank", &objset.phys)];
+    let scrub_attached = || {
+        let mut reader = zio::Reader::new(attached.clone());
+        scrub::scrub(&mut reader, &datasets, &Filter::new(), false).map(|report| report.errors)
+    };
+    let total = scrub::scrub(&mut mirror, &datasets, &Filter::new(), false).unwrap().stats.blocks;
+
+    // Interrupted after txg 5, then picked up again
+    let mut state = Resilver::new(0, 1, 6);
+    let mut saved = Vec::new();
+    let stats = resilver(&mut mirror, &datasets, &mut state, &mut |state| {
+                    saved.push(state.done_txg);
+                    false
+                })
+                    .unwrap();
+    assert_eq!(stats.blocks, 1);
+    assert_eq!((saved, state.missing()), (vec![5], 6..7));
+    // The objset's metadata was written in txg 6, there's nothing to go by on the child yet
+    assert!(scrub_attached().is_err());
+
+    let stats = resilver(&mut mirror, &datasets, &mut state, &mut |_| true).unwrap();
+    assert!(state.is_done());
+    assert_eq!(stats.blocks + 1, total);
+    assert_eq!(stats.unrepairable_blocks, 0);
+    assert_eq!(scrub_attached().unwrap(), Vec::new());
+}
+
+#[test]
+fn test_resilver_gang() {
+    use std::mem;
+
+    use super::dmu_objset::{DirtyObjset, ObjectSetType};
+    use super::dnode::{DNodePhys, ObjectType};
+    use super::metaslab::VdevAllocator;
+    use super::traverse::Filter;
+    use super::vdev_io::MemVdev;
+    use super::vdev_mirror::MirrorVdev;
+    use super::zio_compress::ZIO_COMPRESS_OFF;
+
+    // An objset with a few empty files, written in txg 5 on a single device
+    let disk = MemVdev::new(vec![0; 8 << 20]);
+    let reader = zio::Reader::new(disk.clone());
+    let mut zfs_reader = ZfsReader {
+        zio: zio::Reader::new(disk.clone()),
+        arc: super::arcache::ArCache::new(),
+        dcache: super::dcache::DentryCache::new(),
+        zfetch: super::dmu_zfetch::Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 2 << 20, None);
+    let mut objset = ObjectSet::create(ObjectSetType::Zfs);
+    let mut dirty = DirtyObjset::default();
+    for object in 1..4 {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = ObjectType::PlainFileContents as u8;
+        dnode.nblkptr = 1;
+        dnode.nlevels = 1;
+        dnode.indblkshift = 14;
+        dnode.data_blk_sz_sec = 1;
+        dirty.dnodes.insert(object, dnode);
+    }
+    objset.sync(&mut zfs_reader, &mut alloc, None, ZIO_COMPRESS_OFF, 5, dirty, &mut Vec::new())
+          .unwrap();
+
+    // Then its dnodes moved to a gang block, past what was allocated: a header at 0x1800, a one
+    // sector fragment, and a gang block of its own for the rest, with a one sector fragment and
+    // another for the rest
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    let fragment = |offset: u64, data: &[u8]| {
+        let sectors = data.len() as u64 / 512;
+        let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+        block_ptr.dvas = [DVAddr { vdev: sectors, offset: offset }, empty, empty];
+        block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32 |
+                               (sectors - 1) << 16 | (sectors - 1);
+        block_ptr.checksum = checksum::compute(7, data, false).unwrap();
+        block_ptr.birth_txg = 5;
+        reader.write_sectors(0x2000 + offset as usize, data).unwrap();
+        block_ptr
+    };
+    // Points `block_ptr` at a header at `offset`
+    let gang = |block_ptr: &mut BlockPtr, offset: u64, fragments: [BlockPtr; 2]| {
+        let mut header = vec![0; GANG_HEADER_SIZE];
+        for (i, fragment) in fragments.iter().enumerate() {
+            let bytes: [u8; 128] = unsafe { mem::transmute(*fragment) };
+            header[i * 128..(i + 1) * 128].copy_from_slice(&bytes);
+        }
+        block_ptr.dvas = [DVAddr { vdev: 1, offset: 1 << 63 | offset }, empty, empty];
+        checksum::embed(&mut header, block_ptr.gang_verifier());
+        reader.write_sectors(0x2000 + offset as usize, &header).unwrap();
+    };
+    let mut meta = objset.phys.meta_dnode.get_blockptr(0);
+    let data = reader.read_dva(&meta.dvas[0], meta.psize() * 512).unwrap();
+    assert!(data.len() >= 3 * 512);
+    let mut rest = fragment(0x1802, &data[512..]);
+    let rest_fragments = [fragment(0x1803, &data[512..1024]), fragment(0x1804, &data[1024..])];
+    gang(&mut rest, 0x1802, rest_fragments);
+    let fragments = [fragment(0x1801, &data[..512]), rest];
+    gang(&mut meta, 0x1800, fragments);
+    objset.phys.meta_dnode.set_blockptr(0, &meta);
+
+    // Mirrored onto a blank device, attached in txg 5
+    let attached = MemVdev::new(vec![0; 8 << 20]);
+    let mut mirror = zio::Reader::new(MirrorVdev::new(vec![Box::new(disk.clone()),
+                                                           Box::new(attached.clone())]));
+    let datasets = [("tank", &objset.phys)];
+    let total = scrub::scrub(&mut mirror, &datasets, &Filter::new(), false).unwrap().stats.blocks;
+
+    let mut state = Resilver::new(0, 1, 5);
+    let stats = resilver(&mut mirror, &datasets, &mut state, &mut |_| true).unwrap();
+    assert!(state.is_done());
+    // The dnodes are two headers and three fragments rather than one block
+    assert_eq!((stats.blocks, stats.unrepairable_blocks), (total + 4, 0));
+    // The attached device has the headers and the fragments to put the dnodes together from
+    let reader = zio::Reader::new(attached.clone());
+    assert_eq!(reader.read_block(&meta).unwrap(), data);
+}
@@ -0,0 +1,292 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::fmt;
+
+use super::dsl_dataset::Snapshot;
+use super::zfs;
+
+/// The periods a rule keeps one snapshot each of. Periods are in UTC, weeks start on Monday.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Period {
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Period {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hourly" => Some(Period::Hourly),
+            "daily" => Some(Period::Daily),
+            "weekly" => Some(Period::Weekly),
+            "monthly" => Some(Period::Monthly),
+            "yearly" => Some(Period::Yearly),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Period::Hourly => "hourly",
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+            Period::Monthly => "monthly",
+            Period::Yearly => "yearly",
+        }
+    }
+
+    /// The period `time` (seconds since 1970) is in, as a number that grows with time
+    pub fn of(&self, time: u64) -> u64 {
+        let days = time / 86400;
+        match *self {
+            Period::Hourly => time / 3600,
+            Period::Daily => days,
+            // 1970-01-01 was a Thursday
+            Period::Weekly => (days + 3) / 7,
+            Period::Monthly => {
+                let (year, month) = year_month(days);
+                year * 12 + month
+            }
+            Period::Yearly => year_month(days).0,
+        }
+    }
+}
+
+/// The year and month (from 0) of the day `days` days after 1970-01-01
+fn year_month(days: u64) -> (u64, u64) {
+    // Howard Hinnant's civil_from_days, in years starting on March 1st so leap days come last
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let month = if march_month < 10 {
+        march_month + 2
+    } else {
+        march_month - 10
+    };
+    let year = era * 400 + year_of_era + if month < 2 { 1 } else { 0 };
+    (year, month)
+}
+
+/// Keep the latest snapshot of each of the `keep` latest periods that have snapshots
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub period: Period,
+    pub keep: usize,
+}
+
+impl Rule {
+    /// A rule written as `period=keep`, e.g. `daily=7`
+    pub fn parse(rule: &str) -> Option<Self> {
+        let mut parts = rule.splitn(2, '=');
+        let period = parts.next().and_then(Period::from_name);
+        let keep = parts.next().and_then(|keep| keep.parse().ok());
+        match (period, keep) {
+            (Some(period), Some(keep)) => {
+                Some(Rule {
+                    period: period,
+                    keep: keep,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Which snapshots of a dataset to keep. Every snapshot none of it keeps is destroyed, so an
+/// empty policy destroys them all.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+    /// How many of the latest snapshots to keep, whatever the rules say
+    pub keep_last: usize,
+    /// Only snapshots whose names start with this are up for destruction, e.g. those taken by
+    /// the backup tool applying the policy
+    pub prefix: String,
+}
+
+/// Why a snapshot is kept
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Reason {
+    /// It's one of the `keep_last` latest snapshots
+    Latest,
+    /// It's the latest snapshot of its period, for a rule for those periods
+    Rule(Period),
+    /// It has user holds or clones, so it can't be destroyed
+    Held,
+    /// Its name doesn't start with the policy's prefix
+    NotManaged,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::Latest => f.write_str("latest"),
+            Reason::Rule(period) => f.write_str(period.name()),
+            Reason::Held => f.write_str("held"),
+            Reason::NotManaged => f.write_str("not managed"),
+        }
+    }
+}
+
+/// What a policy makes of the snapshots of a dataset, both lists oldest first
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub keep: Vec<(Snapshot, Reason)>,
+    pub destroy: Vec<Snapshot>,
+}
+
+impl Policy {
+    /// Work out which of `snapshots` to keep and which to destroy
+    pub fn plan(&self, snapshots: &[Snapshot]) -> Plan {
+        let mut snapshots = snapshots.to_vec();
+        snapshots.sort_by_key(|snapshot| (snapshot.creation_time, snapshot.creation_txg));
+
+        let mut reasons = Vec::with_capacity(snapshots.len());
+        for snapshot in &snapshots {
+            let managed = snapshot.name.starts_with(&self.prefix);
+            reasons.push(if managed { None } else { Some(Reason::NotManaged) });
+        }
+        // Latest first, among the snapshots the policy is about
+        let managed: Vec<usize> = (0..snapshots.len())
+                                      .rev()
+                                      .filter(|&i| reasons[i].is_none())
+                                      .collect();
+        for &i in managed.iter().take(self.keep_last) {
+            reasons[i] = Some(Reason::Latest);
+        }
+        for rule in &self.rules {
+            let mut last_period = None;
+            let mut kept = 0;
+            for &i in &managed {
+                if kept == rule.keep {
+                    break;
+                }
+                let period = rule.period.of(snapshots[i].creation_time);
+                if last_period != Some(period) {
+                    last_period = Some(period);
+                    kept += 1;
+                    reasons[i] = reasons[i].or(Some(Reason::Rule(rule.period)));
+                }
+            }
+        }
+
+        let mut plan = Plan {
+            keep: Vec::new(),
+            destroy: Vec::new(),
+        };
+        for (snapshot, reason) in snapshots.into_iter().zip(reasons) {
+            match reason.or(if snapshot.held { Some(Reason::Held) } else { None }) {
+                Some(reason) => plan.keep.push((snapshot, reason)),
+                None => plan.destroy.push(snapshot),
+            }
+        }
+        plan
+    }
+}
+
+/// Destroys the snapshots a plan doesn't keep
+pub trait SnapshotDestroyer {
+    fn destroy(&mut self, snapshot: &Snapshot) -> zfs::Result<()>;
+}
+
+/// Carry out `plan`: destroy its snapshots, oldest first. A snapshot that fails to be destroyed
+/// doesn't stop the others; the failures are returned, by snapshot name.
+pub fn prune<D: SnapshotDestroyer + ?Sized>(plan: &Plan,
+                                            destroyer: &mut D)
+                                            -> Vec<(String, zfs::Error)> {
+    let mut failures = Vec::new();
+    for snapshot in &plan.destroy {
+        if let Err(e) = destroyer.destroy(snapshot) {
+            failures.push((snapshot.name.clone(), e));
+        }
+    }
+    failures
+}
+
+#[test]
+fn test_retention_plan() {
+    let hour = 3600;
+    // 2021-03-01 00:00 UTC, a Monday
+    let start = 1614556800;
+    assert_eq!(year_month(start / 86400), (2021, 2));
+    assert_eq!(year_month(start / 86400 - 1), (2021, 1));
+    assert_eq!(year_month(0), (1970, 0));
+    assert_eq!(Period::Weekly.of(start), Period::Weekly.of(start + 6 * 24 * hour));
+    assert!(Period::Weekly.of(start - 1) < Period::Weekly.of(start));
+
+    // Every 6 hours for 10 days, with an hourly one on top for the last 3 hours
+    let mut snapshots = Vec::new();
+    let mut snapshot = |name: String, time: u64| {
+        snapshots.push(Snapshot {
+            name: name,
+            object: 0,
+            guid: time,
+            creation_time: time,
+            creation_txg: time / 60,
+            objset_txg: time / 60,
+            held: false,
+        })
+    };
+    for i in 0..40 {
+        snapshot(format!("auto-{}", i), start + i * 6 * hour);
+    }
+    let end = start + 39 * 6 * hour;
+    for i in 1..4 {
+        snapshot(format!("auto-h{}", i), end + i * hour);
+    }
+    snapshot("manual".to_owned(), start + hour);
+    snapshots[3].held = true;
+
+    let policy = Policy {
+        rules: vec![Rule::parse("hourly=3").unwrap(),
+                    Rule::parse("daily=3").unwrap(),
+                    Rule::parse("weekly=2").unwrap()],
+        keep_last: 1,
+        prefix: "auto-".to_owned(),
+    };
+    let plan = policy.plan(&snapshots);
+    let kept: Vec<(&str, Reason)> = plan.keep
+                                        .iter()
+                                        .map(|&(ref snapshot, reason)| (&snapshot.name[..], reason))
+                                        .collect();
+    let daily = Reason::Rule(Period::Daily);
+    let weekly = Reason::Rule(Period::Weekly);
+    assert_eq!(kept,
+               vec![("manual", Reason::NotManaged),
+                    ("auto-3", Reason::Held),
+                    // The latest of the first week is the one on Sunday night
+                    ("auto-27", weekly),
+                    ("auto-31", daily),
+                    ("auto-35", daily),
+                    ("auto-h1", Reason::Rule(Period::Hourly)),
+                    ("auto-h2", Reason::Rule(Period::Hourly)),
+                    ("auto-h3", Reason::Latest)]);
+    assert_eq!(plan.keep.len() + plan.destroy.len(), snapshots.len());
+    assert_eq!(plan.destroy[0].name, "auto-0");
+
+    struct Destroyer(Vec<String>);
+    impl SnapshotDestroyer for Destroyer {
+        fn destroy(&mut self, snapshot: &Snapshot) -> zfs::Result<()> {
+            if snapshot.name == "auto-1" {
+                return Err(zfs::Error::Invalid);
+            }
+            self.0.push(snapshot.name.clone());
+            Ok(())
+        }
+    }
+    let mut destroyer = Destroyer(Vec::new());
+    assert_eq!(prune(&plan, &mut destroyer), vec![("auto-1".to_owned(), zfs::Error::Invalid)]);
+    assert_eq!(destroyer.0.len(), plan.destroy.len() - 1);
+    assert_eq!(&destroyer.0[..2], &["auto-0".to_owned(), "auto-2".to_owned()]);
+
+    // An empty policy keeps only what can't be destroyed
+    assert_eq!(Policy::default().plan(&snapshots).keep.len(), 1);
+    assert_eq!(Rule::parse("daily"), None);
+    assert_eq!(Rule::parse("fortnightly=2"), None);
+}
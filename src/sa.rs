@@ -0,0 +1,282 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+use std::collections::HashMap;
+
+use super::ZfsReader;
+use super::dmu_objset::ObjectSet;
+use super::dnode::{DNODE_FLAG_SPILL_BLKPTR, DNodePhys};
+use super::from_bytes::{ByteOrder, FromBytes};
+use super::util;
+use super::zap;
+
+/// Bonus type of dnodes whose bonus buffer holds system attributes
+pub const DMU_OT_SA: u8 = 44;
+
+const SA_MAGIC: u32 = 0x2F505A;
+
+/// A registered attribute
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attr {
+    pub name: String,
+    pub num: u16,
+    /// 0 for variable length attributes, whose length is kept in the SA header
+    pub length: u16,
+}
+
+impl Attr {
+    /// Decode an entry of the registry ZAP: the attribute number is in bits 0-15, the byteswap
+    /// function in bits 16-23 and the length in bits 24-39
+    pub fn from_registry(name: String, value: u64) -> Self {
+        Attr {
+            name: name,
+            num: value as u16,
+            length: (value >> 24) as u16,
+        }
+    }
+}
+
+/// The attribute registry and layouts of an object set. Every SA buffer names a layout, which
+/// lists the attributes the buffer holds, in order.
+pub struct Registry {
+    attrs: Vec<Attr>,
+    layouts: HashMap<u64, Vec<u16>>,
+}
+
+impl Registry {
+    pub fn new(attrs: Vec<Attr>, layouts: HashMap<u64, Vec<u16>>) -> Self {
+        Registry {
+            attrs: attrs,
+            layouts: layouts,
+        }
+    }
+
+    /// Load the registry of `objset` from the SA master object `sa_obj` (the ZPL keeps its number
+    /// in the master node under `SA_ATTRS`)
+    pub fn load(reader: &mut ZfsReader, objset: &ObjectSet, sa_obj: u64) -> Result<Self, String> {
+        let registry_obj = try!(zap::lookup(reader, objset, sa_obj, "REGISTRY"));
+        let layouts_obj = try!(zap::lookup(reader, objset, sa_obj, "LAYOUTS"));
+
+        let attrs = try!(zap::iter(reader, objset, registry_obj))
+                        .map(|(name, value)| Attr::from_registry(name, value))
+                        .collect();
+
+        // Layouts are keyed by their number in decimal and hold arrays of 16 bit attribute numbers
+        let mut layouts = HashMap::new();
+        for (name, value) in try!(zap::iter_values(reader, objset, layouts_obj)) {
+            let layout = try!(name.parse().map_err(|_| format!("Bad SA layout `{}`", name)));
+            let attrs = match value.as_ints() {
+                Some(attrs) => attrs.iter().map(|&attr| attr as u16).collect(),
+                None => return Err(format!("Bad SA layout `{}`", name)),
+            };
+            layouts.insert(layout, attrs);
+        }
+
+        Ok(Registry::new(attrs, layouts))
+    }
+
+    fn attr(&self, num: u16) -> Option<&Attr> {
+        self.attrs.iter().find(|attr| attr.num == num)
+    }
+
+    /// Split the SA buffer `buf` into its attributes. The buffer is in the byte order of the host
+    /// that wrote it, which the magic number gives away.
+    pub fn parse(&self, buf: &[u8], attrs: &mut Attrs) -> Result<(), String> {
+        let (order, layout) = try!(self.layout(buf));
+        for (attr, offset, length) in layout {
+            attrs.values.push((attr.name.clone(), buf[offset..offset + length].to_vec(), order));
+        }
+        Ok(())
+    }
+
+    /// Change attribute `name` of the SA buffer `buf` in place, to `value` in the buffer's byte
+    /// order. Only attributes of a fixed length can be changed, the layout stays the same. Returns
+    /// false if the buffer doesn't hold the attribute.
+    pub fn set_u64s(&self, buf: &mut [u8], name: &str, value: &[u64]) -> Result<bool, String> {
+        let (order, layout) = try!(self.layout(buf));
+        let (offset, length) = match layout.iter().find(|&&(attr, _, _)| attr.name == name) {
+            Some(&(attr, offset, length)) if attr.length != 0 && length == value.len() * 8 => {
+                (offset, length)
+            }
+            Some(_) => return Err(format!("SA attribute {} can't take {:?}", name, value)),
+            None => return Ok(false),
+        };
+        for (word, &value) in buf[offset..offset + length].chunks_mut(8).zip(value) {
+            let bytes = match order {
+                ByteOrder::Little => value.to_le_bytes(),
+                ByteOrder::Big => value.to_be_bytes(),
+            };
+            word.copy_from_slice(&bytes);
+        }
+        Ok(true)
+    }
+
+    /// The byte order of the SA buffer `buf`, and where each attribute of its layout is in it, as
+    /// (attribute, offset, length)
+    fn layout(&self, buf: &[u8]) -> Result<(ByteOrder, Vec<(&Attr, usize, usize)>), String> {
+        let magic = try!(u32::from_bytes(buf));
+        let order = if magic == SA_MAGIC {
+            ByteOrder::Little
+        } else if magic == SA_MAGIC.swap_bytes() {
+            ByteOrder::Big
+        } else {
+            return Err(format!("Bad SA magic {:X}", magic));
+        };
+        let layout_info = try!(u16::from_bytes_order(&buf[4..], order));
+        let layout = (layout_info & 0x3FF) as u64;
+        let hdr_size = ((layout_info >> 10) as usize) * 8;
+        if hdr_size < 8 || hdr_size > buf.len() {
+            return Err(format!("Bad SA header size {}", hdr_size));
+        }
+        let layout = try!(self.layouts.get(&layout).ok_or(format!("No SA layout {}", layout)));
+
+        // Variable length attributes get their lengths from the header, in layout order
+        let mut var_lengths = buf[6..hdr_size].chunks(2).map(|length| {
+            u16::from_bytes_order(length, order).unwrap_or(0) as usize
+        });
+        let mut attrs = Vec::new();
+        let mut offset = hdr_size;
+        for &num in layout {
+            let attr = try!(self.attr(num).ok_or(format!("SA attribute {} isn't registered", num)));
+            let length = if attr.length != 0 {
+                attr.length as usize
+            } else {
+                try!(var_lengths.next().ok_or("SA header is missing a length".to_owned()))
+            };
+            if offset + length > buf.len() {
+                return Err(format!("SA attribute {} runs past the buffer", attr.name));
+            }
+            attrs.push((attr, offset, length));
+            // Every attribute starts 8 byte aligned
+            offset = util::p2_round_up((offset + length) as u64, 8) as usize;
+        }
+        Ok((order, attrs))
+    }
+
+    /// All attributes of the object described by `dnode`, including those in its spill block.
+    /// The bonus buffer of a large dnode goes on into `extra`, the slots after its first one
+    /// (see `dmu::read_dnode_extra`).
+    pub fn dnode_attrs(&self,
+                       reader: &mut ZfsReader,
+                       dnode: &DNodePhys,
+                       extra: &[u8])
+                       -> Result<Attrs, String> {
+        if dnode.bonus_type != DMU_OT_SA {
+            return Err("Bonus buffer doesn't hold system attributes".to_owned());
+        }
+        let mut attrs = Attrs { values: Vec::new() };
+        let bonus = dnode.large_bonus(extra);
+        let bonus_len = cmp::min(dnode.bonus_len as usize, bonus.len());
+        try!(self.parse(&bonus[..bonus_len], &mut attrs));
+
+        if dnode.flags & DNODE_FLAG_SPILL_BLKPTR != 0 {
+            let spill = dnode.large_spill_blkptr(extra);
+            let data = try!(reader.read_block(&spill));
+            try!(self.parse(&data, &mut attrs));
+        }
+        Ok(attrs)
+    }
+}
+
+/// The attributes of one object, by name, along with the byte order they were written in
+pub struct Attrs {
+    values: Vec<(String, Vec<u8>, ByteOrder)>,
+}
+
+impl Attrs {
+    fn get_order(&self, name: &str) -> Option<(&[u8], ByteOrder)> {
+        self.values
+            .iter()
+            .find(|&&(ref n, _, _)| n == name)
+            .map(|&(_, ref value, order)| (&value[..], order))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.get_order(name).map(|(value, _)| value)
+    }
+
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        self.get_order(name).and_then(|(value, order)| u64::from_bytes_order(value, order).ok())
+    }
+
+    /// A (seconds, nanoseconds) pair
+    pub fn get_u64_pair(&self, name: &str) -> Option<[u64; 2]> {
+        self.get_order(name).and_then(|(value, order)| {
+            if value.len() < 16 {
+                return None;
+            }
+            Some([u64::from_bytes_order(value, order).unwrap(),
+                  u64::from_bytes_order(&value[8..], order).unwrap()])
+        })
+    }
+}
+
+#[test]
+fn test_sa_layout() {
+    let attrs = vec![Attr::from_registry("ZPL_MODE".to_owned(), 8 << 24 | 5),
+                     Attr::from_registry("ZPL_SIZE".to_owned(), 8 << 24 | 6),
+                     Attr::from_registry("ZPL_ATIME".to_owned(), 16 << 24 | 0),
+                     Attr::from_registry("ZPL_SYMLINK".to_owned(), 0 << 24 | 17),
+                     Attr::from_registry("ZPL_UID".to_owned(), 8 << 24 | 12)];
+    let mut layouts = HashMap::new();
+    layouts.insert(3, vec![5, 6, 17, 12, 0]);
+    let registry = Registry::new(attrs, layouts);
+
+    let mut buf = vec![0u8; 8 + 8 + 8 + 8 + 8 + 16];
+    buf[..4].copy_from_slice(&[0x5A, 0x50, 0x2F, 0]);
+    let layout_info: u16 = 3 | 1 << 10;
+    buf[4..6].copy_from_slice(&[layout_info as u8, (layout_info >> 8) as u8]);
+    buf[6] = 6; // length of the symlink target
+    buf[8] = 0xED;
+    buf[9] = 0x81; // mode 0o100755
+    buf[16] = 42; // size
+    buf[24..30].copy_from_slice(b"target");
+    buf[32..34].copy_from_slice(&[0xE8, 0x03]); // uid 1000
+    buf[40] = 7; // atime seconds
+    buf[48] = 9; // atime nanoseconds
+
+    let mut parsed = Attrs { values: Vec::new() };
+    registry.parse(&buf, &mut parsed).unwrap();
+    assert_eq!(parsed.get_u64("ZPL_MODE"), Some(0o100755));
+    assert_eq!(parsed.get_u64("ZPL_SIZE"), Some(42));
+    assert_eq!(parsed.get("ZPL_SYMLINK"), Some(&b"target"[..]));
+    assert_eq!(parsed.get_u64("ZPL_UID"), Some(1000));
+    assert_eq!(parsed.get_u64_pair("ZPL_ATIME"), Some([7, 9]));
+    assert_eq!(parsed.get("ZPL_GID"), None);
+
+    // Fixed length attributes change in place
+    assert_eq!(registry.set_u64s(&mut buf, "ZPL_SIZE", &[4096]), Ok(true));
+    assert_eq!(registry.set_u64s(&mut buf, "ZPL_ATIME", &[8, 10]), Ok(true));
+    assert_eq!(registry.set_u64s(&mut buf, "ZPL_GID", &[1]), Ok(false));
+    assert!(registry.set_u64s(&mut buf, "ZPL_SYMLINK", &[1]).is_err());
+    let mut parsed = Attrs { values: Vec::new() };
+    registry.parse(&buf, &mut parsed).unwrap();
+    assert_eq!(parsed.get_u64("ZPL_SIZE"), Some(4096));
+    assert_eq!(parsed.get_u64_pair("ZPL_ATIME"), Some([8, 10]));
+    assert_eq!(parsed.get("ZPL_SYMLINK"), Some(&b"target"[..]));
+
+    buf[4] = 4; // unknown layout
+    assert!(registry.parse(&buf, &mut parsed).is_err());
+
+    // The same attributes written by a big endian host
+    let mut buf = vec![0u8; 8 + 8 + 8 + 8 + 8 + 16];
+    buf[..4].copy_from_slice(&[0, 0x2F, 0x50, 0x5A]);
+    buf[4..6].copy_from_slice(&[(layout_info >> 8) as u8, layout_info as u8]);
+    buf[7] = 6;
+    buf[14..16].copy_from_slice(&[0x81, 0xED]);
+    buf[23] = 42;
+    buf[24..30].copy_from_slice(b"target");
+    buf[38..40].copy_from_slice(&[0x03, 0xE8]);
+    buf[47] = 7;
+    buf[55] = 9;
+
+    let mut parsed = Attrs { values: Vec::new() };
+    registry.parse(&buf, &mut parsed).unwrap();
+    assert_eq!(parsed.get_u64("ZPL_MODE"), Some(0o100755));
+    assert_eq!(parsed.get_u64("ZPL_SIZE"), Some(42));
+    assert_eq!(parsed.get("ZPL_SYMLINK"), Some(&b"target"[..]));
+    assert_eq!(parsed.get_u64("ZPL_UID"), Some(1000));
+    assert_eq!(parsed.get_u64_pair("ZPL_ATIME"), Some([7, 9]));
+    registry.set_u64s(&mut buf, "ZPL_SIZE", &[4096]).unwrap();
+    assert_eq!(&buf[16..24], &[0, 0, 0, 0, 0, 0, 0x10, 0]);
+}
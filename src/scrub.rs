@@ -0,0 +1,585 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::sync::mpsc;
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum;
+use super::dmu_objset::{ObjectSet, ObjectSetPhys};
+use super::dsl_dataset::DslDatasetPhys;
+use super::dsl_pool::DslPool;
+use super::dvaddr::DVAddr;
+use super::from_bytes::FromBytes;
+use super::taskq::ZioTaskqs;
+use super::traverse::{self, Bookmark, Filter};
+use super::zap;
+use super::zfs;
+use super::zio;
+
+/// What scrubbing found out about one copy (DVA) of a block
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CopyStatus {
+    /// The copy passed checksum verification
+    Ok,
+    /// The copy was bad and has been rewritten from a good copy
+    Repaired,
+    /// The copy is bad and could not be repaired
+    Damaged(&'static str),
+    /// The copy could be read, but the block was written without a checksum (see
+    /// `checksum::is_verifiable`): there's no telling whether it's good
+    Unverified,
+}
+
+/// Per-copy verification results for a single block pointer
+#[derive(Debug)]
+pub struct DittoReport {
+    pub copies: Vec<(DVAddr, CopyStatus)>,
+}
+
+impl DittoReport {
+    /// Whether at least one copy of the block is readable. Embedded blocks have no copies of
+    /// their own to go bad.
+    pub fn readable(&self) -> bool {
+        self.copies.is_empty() ||
+        self.copies.iter().any(|&(_, status)| match status {
+            CopyStatus::Ok | CopyStatus::Repaired | CopyStatus::Unverified => true,
+            CopyStatus::Damaged(_) => false,
+        })
+    }
+
+    /// Whether all copies agreed with the block pointer's checksum, or could be read if it has
+    /// none
+    pub fn clean(&self) -> bool {
+        self.copies
+            .iter()
+            .all(|&(_, status)| status == CopyStatus::Ok || status == CopyStatus::Unverified)
+    }
+
+    /// Whether the block was written without a checksum, so its copies couldn't be verified
+    pub fn unverified(&self) -> bool {
+        self.copies.iter().any(|&(_, status)| status == CopyStatus::Unverified)
+    }
+}
+
+/// Verify every DVA copy of the block, rather than stopping at the first one that passes. When
+/// `repair` is set, bad copies get rewritten with the contents of a good one, so silent divergence
+/// between ditto copies is caught and healed. The copies of a block without a checksum are only
+/// read: none can be told to be good, so none is repaired.
+pub fn verify_dittos(reader: &zio::Reader,
+                     block_ptr: &BlockPtr,
+                     repair: bool)
+                     -> DittoReport {
+    let mut copies = Vec::new();
+    let mut good_data = None;
+    let psize = (block_ptr.psize() * 512) as usize;
+
+    // Embedded blocks live in their parent's block pointer, which the parent's checksum covers
+    let dvas = block_ptr.dvas;
+    let copy_count = if block_ptr.is_embedded() { 0 } else { block_ptr.max_copies() };
+    for dva in dvas[..copy_count].iter() {
+        if dva.is_empty() {
+            continue;
+        }
+
+        // Read straight from disk, the cache may hide a bad copy
+        let data = match reader.read_dva(dva, psize as u64) {
+            Ok(data) => data,
+            Err(_) => {
+                copies.push((*dva, CopyStatus::Damaged("Error: failed to read copy")));
+                continue;
+            }
+        };
+        if !checksum::is_verifiable(block_ptr.checksum()) {
+            copies.push((*dva, CopyStatus::Unverified));
+            continue;
+        }
+        match checksum::verify(block_ptr, &data) {
+            Ok(()) => {
+                if good_data.is_none() {
+                    good_data = Some(data);
+                }
+                copies.push((*dva, CopyStatus::Ok));
+            }
+            Err(e) => copies.push((*dva, CopyStatus::Damaged(e))),
+        }
+    }
+
+    if repair {
+        if let Some(ref good_data) = good_data {
+            for &mut (ref dva, ref mut status) in &mut copies {
+                if let CopyStatus::Damaged(_) = *status {
+                    // Make sure the rewrite actually stuck
+                    let rewritten = reader.write_dva(dva, &good_data[..psize])
+                                          .and_then(|()| reader.read_dva(dva, psize as u64));
+                    *status = match rewritten.map(|data| checksum::verify(block_ptr, &data)) {
+                        Ok(Ok(())) => CopyStatus::Repaired,
+                        _ => CopyStatus::Damaged("Error: failed to rewrite copy"),
+                    };
+                }
+            }
+        }
+    }
+
+    DittoReport { copies: copies }
+}
+
+/// Totals of a scrub run
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ScrubStats {
+    pub blocks: u64,
+    /// Copies that failed verification, repaired or not
+    pub bad_copies: u64,
+    pub repaired_copies: u64,
+    /// Blocks without a single good copy
+    pub unreadable_blocks: u64,
+    /// Blocks written without a checksum, whose copies could only be read
+    pub unverified_blocks: u64,
+}
+
+/// A block that had at least one bad copy
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockError {
+    pub dataset: String,
+    pub bookmark: Bookmark,
+    /// Where the data of a level 0 block starts in its object, in bytes
+    pub offset: Option<u64>,
+    pub bad_copies: u64,
+    pub repaired_copies: u64,
+    /// Whether a good copy was left
+    pub readable: bool,
+}
+
+/// What a scrub run found: the totals, every block with bad copies, and every block that
+/// couldn't be verified
+#[derive(Clone, Debug, Default)]
+pub struct ScrubReport {
+    pub stats: ScrubStats,
+    pub errors: Vec<BlockError>,
+    /// The blocks written without a checksum, by dataset
+    pub unverified: Vec<(String, Bookmark)>,
+    /// The datasets and snapshots that couldn't be opened to be scrubbed, none of their blocks
+    /// were visited
+    pub skipped: Vec<String>,
+}
+
+/// The damage to one object (a file, a directory, a ZAP, ...), summed over its blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectDamage {
+    pub dataset: String,
+    pub object: u64,
+    /// Blocks whose bad copies were all rewritten
+    pub repaired_blocks: u64,
+    /// Blocks that still have bad copies next to a good one, e.g. because repair was off
+    pub degraded_blocks: u64,
+    /// Blocks without a single good copy. The object has to be restored from a backup.
+    pub unrepairable_blocks: u64,
+}
+
+impl ScrubReport {
+    /// The damaged objects, ordered by dataset and object number
+    pub fn damaged_objects(&self) -> Vec<ObjectDamage> {
+        let mut objects: Vec<ObjectDamage> = Vec::new();
+        for error in &self.errors {
+            let index = match objects.iter().position(|damage| {
+                damage.dataset == error.dataset && damage.object == error.bookmark.object
+            }) {
+                Some(index) => index,
+                None => {
+                    objects.push(ObjectDamage {
+                        dataset: error.dataset.clone(),
+                        object: error.bookmark.object,
+                        repaired_blocks: 0,
+                        degraded_blocks: 0,
+                        unrepairable_blocks: 0,
+                    });
+                    objects.len() - 1
+                }
+            };
+            let damage = &mut objects[index];
+            if !error.readable {
+                damage.unrepairable_blocks += 1;
+            } else if error.repaired_copies == error.bad_copies {
+                damage.repaired_blocks += 1;
+            } else {
+                damage.degraded_blocks += 1;
+            }
+        }
+        objects.sort_by(|a, b| (&a.dataset, a.object).cmp(&(&b.dataset, b.object)));
+        objects
+    }
+
+    /// A listing of the damaged objects, unrepairable ones first, like `zpool status -v`.
+    /// `resolve` maps a dataset and object to a file path where it can (see `zpl::object_path`),
+    /// everything else is listed as `<dataset>:<0xobject>`.
+    pub fn render(&self, resolve: &mut FnMut(&str, u64) -> Option<String>) -> String {
+        let mut objects = self.damaged_objects();
+        // Stable, so the order within each group stays
+        objects.sort_by_key(|damage| damage.unrepairable_blocks == 0);
+
+        let mut out = String::new();
+        for damage in &objects {
+            let name = resolve(&damage.dataset, damage.object).unwrap_or_else(|| {
+                format!("{}:<0x{:X}>", damage.dataset, damage.object)
+            });
+            let state = if damage.unrepairable_blocks > 0 {
+                "unrepairable, restore from backup"
+            } else if damage.degraded_blocks > 0 {
+                "degraded"
+            } else {
+                "repaired"
+            };
+            out.push_str(&format!("{}: {} ({} unrepairable, {} degraded, {} repaired blocks)\n",
+                                  name,
+                                  state,
+                                  damage.unrepairable_blocks,
+                                  damage.degraded_blocks,
+                                  damage.repaired_blocks));
+        }
+
+        // Then the objects with blocks that couldn't be verified, in the order they were found
+        let mut unverified: Vec<(&str, u64, u64)> = Vec::new();
+        for &(ref dataset, ref bookmark) in &self.unverified {
+            match unverified.iter_mut().find(|&&mut (name, object, _)| {
+                name == dataset && object == bookmark.object
+            }) {
+                Some(entry) => entry.2 += 1,
+                None => unverified.push((dataset, bookmark.object, 1)),
+            }
+        }
+        for (dataset, object, blocks) in unverified {
+            let name = resolve(dataset, object).unwrap_or_else(|| {
+                format!("{}:<0x{:X}>", dataset, object)
+            });
+            out.push_str(&format!("{}: unverifiable, written without checksums ({} blocks)\n",
+                                  name,
+                                  blocks));
+        }
+        out
+    }
+}
+
+/// Scrub the blocks of the given datasets (the MOS goes by `traverse::MOS_NAME`) that pass
+/// `filter`
+pub fn scrub(reader: &mut zio::Reader,
+             datasets: &[(&str, &ObjectSetPhys)],
+             filter: &Filter,
+             repair: bool)
+             -> zfs::Result<ScrubReport> {
+    scrub_with(reader, datasets, filter, repair, None)
+}
+
+/// Like `scrub`, with the blocks verified on `taskqs` if there are any, at scrub priority: the
+/// pool's other reads go first. The traversal itself stays on the calling thread.
+pub fn scrub_with(reader: &mut zio::Reader,
+                  datasets: &[(&str, &ObjectSetPhys)],
+                  filter: &Filter,
+                  repair: bool,
+                  taskqs: Option<&ZioTaskqs>)
+                  -> zfs::Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+    let (sender, receiver) = mpsc::channel();
+    let mut visited = 0;
+
+    for (dataset, &(name, objset)) in datasets.iter().enumerate() {
+        if !filter.wants_dataset(name) {
+            continue;
+        }
+
+        try!(traverse::traverse_objset(reader,
+                                       objset,
+                                       filter.objects,
+                                       &mut |reader, bookmark, block_ptr| {
+            let (sender, block_ptr, bookmark) = (sender.clone(), *block_ptr, *bookmark);
+            let verify = move |reader: &zio::Reader| {
+                let ditto_report = verify_dittos(reader, &block_ptr, repair);
+                let _ = sender.send((visited, dataset, bookmark, block_ptr, ditto_report));
+            };
+            match taskqs {
+                Some(taskqs) => {
+                    taskqs.dispatch(zio::TaskqType::Issue, zio::Priority::Scrub, verify)
+                }
+                None => verify(reader),
+            }
+            visited += 1;
+        }));
+    }
+    if let Some(taskqs) = taskqs {
+        taskqs.wait();
+    }
+    drop(sender);
+
+    // In the order the blocks were visited, however the verifications were scheduled
+    let mut verified: Vec<_> = receiver.iter().collect();
+    verified.sort_by_key(|&(visited, _, _, _, _)| visited);
+    for (_, dataset, bookmark, block_ptr, ditto_report) in verified {
+        report.add_block(datasets[dataset].0, &bookmark, &block_ptr, &ditto_report);
+    }
+    Ok(report)
+}
+
+impl ScrubReport {
+    fn add_block(&mut self,
+                 name: &str,
+                 bookmark: &Bookmark,
+                 block_ptr: &BlockPtr,
+                 ditto_report: &DittoReport) {
+        self.stats.blocks += 1;
+        let mut error = BlockError {
+            dataset: name.to_owned(),
+            bookmark: *bookmark,
+            offset: if bookmark.level == 0 {
+                Some(bookmark.blkid * block_ptr.lsize() * 512)
+            } else {
+                None
+            },
+            bad_copies: 0,
+            repaired_copies: 0,
+            readable: ditto_report.readable(),
+        };
+        for &(_, status) in &ditto_report.copies {
+            match status {
+                CopyStatus::Ok => {}
+                CopyStatus::Repaired => {
+                    error.bad_copies += 1;
+                    error.repaired_copies += 1;
+                }
+                CopyStatus::Damaged(_) => error.bad_copies += 1,
+                CopyStatus::Unverified => {}
+            }
+        }
+        if ditto_report.unverified() {
+            self.stats.unverified_blocks += 1;
+            self.unverified.push((name.to_owned(), *bookmark));
+        }
+        self.stats.bad_copies += error.bad_copies;
+        self.stats.repaired_copies += error.repaired_copies;
+        if !error.readable {
+            self.stats.unreadable_blocks += 1;
+        }
+        if error.bad_copies > 0 || !error.readable {
+            self.errors.push(error);
+        }
+    }
+}
+
+/// The dataset or snapshot with MOS object number `object`, and its objset
+fn open_objset(reader: &mut ZfsReader,
+               mos: &ObjectSet,
+               object: u64)
+               -> Result<(DslDatasetPhys, ObjectSet), String> {
+    let dnode = try!(mos.dnode(reader, object));
+    let phys = try!(DslDatasetPhys::from_bytes(dnode.get_bonus()));
+    let objset = try!(ObjectSet::open(reader, &phys.bp));
+    Ok((phys, objset))
+}
+
+/// Every dataset of the pool followed by its snapshots, by name, with their objsets opened.
+/// Those that can't be opened are added to `skipped`.
+pub fn pool_objsets(reader: &mut ZfsReader,
+                mos: &ObjectSet,
+                dsl_pool: &DslPool,
+                pool_name: &str,
+                skipped: &mut Vec<String>)
+                -> zfs::Result<Vec<(String, ObjectSet)>> {
+    let mut objsets = Vec::new();
+    for (name, dir) in try!(dsl_pool.datasets(reader, mos, pool_name)) {
+        if dir.head_dataset_obj() == 0 {
+            continue;
+        }
+        let (phys, head) = match open_objset(reader, mos, dir.head_dataset_obj()) {
+            Ok(head) => head,
+            Err(_) => {
+                skipped.push(name);
+                continue;
+            }
+        };
+        objsets.push((name.clone(), head));
+
+        let snapnames_zapobj = phys.snapnames_zapobj;
+        if snapnames_zapobj == 0 {
+            continue;
+        }
+        let snapshots = match zap::iter(reader, mos, snapnames_zapobj) {
+            Ok(snapshots) => snapshots,
+            Err(_) => {
+                skipped.push(format!("{}@*", name));
+                continue;
+            }
+        };
+        for (snapshot, object) in snapshots {
+            let snapshot = format!("{}@{}", name, snapshot);
+            match open_objset(reader, mos, object) {
+                Ok((_, objset)) => objsets.push((snapshot, objset)),
+                Err(_) => skipped.push(snapshot),
+            }
+        }
+    }
+    Ok(objsets)
+}
+
+/// Scrub the whole pool, like `zpool scrub`: every block reachable from the MOS, the metadata,
+/// the datasets and their snapshots, that passes `filter`. Blocks shared by a dataset and its
+/// snapshots are verified for each of them. The damaged blocks are reported with the dataset,
+/// object and offset they belong to.
+pub fn scrub_pool(reader: &mut ZfsReader,
+                  mos: &ObjectSet,
+                  dsl_pool: &DslPool,
+                  pool_name: &str,
+                  filter: &Filter,
+                  repair: bool,
+                  taskqs: Option<&ZioTaskqs>)
+                  -> zfs::Result<ScrubReport> {
+    let mut skipped = Vec::new();
+    let objsets = try!(pool_objsets(reader, mos, dsl_pool, pool_name, &mut skipped));
+    let mut datasets = vec![(traverse::MOS_NAME, &mos.phys)];
+    datasets.extend(objsets.iter().map(|&(ref name, ref objset)| (&name[..], &objset.phys)));
+
+    let mut report = try!(scrub_with(&mut reader.zio, &datasets, filter, repair, taskqs));
+    report.skipped = skipped;
+    Ok(report)
+}
+
+#[test]
+fn test_damaged_objects() {
+    let error = |dataset: &str, object, blkid, bad_copies, repaired_copies, readable| {
+        BlockError {
+            dataset: dataset.to_owned(),
+            bookmark: Bookmark {
+                object: object,
+                level: 0,
+                blkid: blkid,
+            },
+            offset: Some(blkid * 512),
+            bad_copies: bad_copies,
+            repaired_copies: repaired_copies,
+            readable: readable,
+        }
+    };
+    let report = ScrubReport {
+        stats: ScrubStats::default(),
+        errors: vec![error("tank", 8, 0, 1, 1, true),
+                     error(traverse::MOS_NAME, 3, 0, 2, 0, true),
+                     error("tank", 8, 1, 2, 0, false),
+                     error("tank", 5, 4, 1, 0, true)],
+        unverified: Vec::new(),
+        skipped: Vec::new(),
+    };
+
+    let objects = report.damaged_objects();
+    assert_eq!(objects.len(), 3);
+    assert_eq!((&objects[0].dataset[..], objects[0].object), (traverse::MOS_NAME, 3));
+    assert_eq!((objects[1].object, objects[1].degraded_blocks), (5, 1));
+    assert_eq!((objects[2].object, objects[2].repaired_blocks, objects[2].unrepairable_blocks),
+               (8, 1, 1));
+
+    let rendered = report.render(&mut |dataset, object| {
+        if dataset == "tank" && object == 8 {
+            Some("/home/alice/thesis.tex".to_owned())
+        } else {
+            None
+        }
+    });
+    let lines: Vec<&str> = rendered.lines().collect();
+    assert!(lines[0].starts_with("/home/alice/thesis.tex: unrepairable"));
+    assert!(lines[1].starts_with("$MOS:<0x3>: degraded"));
+    assert!(lines[2].starts_with("tank:<0x5>: degraded"));
+}
+
+#[test]
+fn test_unverified_copies() {
+    use super::from_bytes::FromBytes;
+    use super::vdev_io::MemVdev;
+
+    let mut reader = zio::Reader::new(MemVdev::new(vec![0; (0x2000 + 4) * 512]));
+    reader.write_sectors(0x2000, &[1; 512]).unwrap();
+    reader.write_sectors(0x2001, &[2; 512]).unwrap();
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    block_ptr.dvas[0] = DVAddr { vdev: 1, offset: 0 };
+    block_ptr.dvas[1] = DVAddr { vdev: 1, offset: 1 };
+    // checksum=off, so the copies don't have to agree with anything, and aren't repaired
+    block_ptr.flags_size = 2 << 40;
+    assert_eq!(block_ptr.checksum(), 2);
+
+    let report = verify_dittos(&mut reader, &block_ptr, true);
+    assert_eq!(report.copies.iter().map(|&(_, status)| status).collect::<Vec<_>>(),
+               vec![CopyStatus::Unverified, CopyStatus::Unverified]);
+    assert!(report.readable() && report.clean() && report.unverified());
+    assert_eq!(reader.read_dva(&block_ptr.dvas[1], 512).unwrap(), vec![2; 512]);
+
+    let mut report = ScrubReport::default();
+    for blkid in 0..3 {
+        report.unverified.push(("tank".to_owned(),
+                                Bookmark {
+                                    object: 9,
+                                    level: 0,
+                                    blkid: blkid,
+                                }));
+    }
+    assert_eq!(report.render(&mut |_, _| None),
+               "tank:<0x9>: unverifiable, written without checksums (3 blocks)\n");
+}
+
+#[test]
+fn test_scrub_with_taskqs() {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu;
+    use super::dmu_objset::{DirtyObjset, ObjectSetType};
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::{DNodePhys, ObjectType};
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio_compress::ZIO_COMPRESS_OFF;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+
+    // A file of two blocks, the second of which goes bad
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.object_type = ObjectType::PlainFileContents as u8;
+    dnode.nblkptr = 1;
+    dnode.indblkshift = 14;
+    dnode.data_blk_sz_sec = 1;
+    let mut dirty = DirtyObjset::default();
+    dirty.dnodes.insert(1, dnode);
+    let blocks: BTreeMap<u64, Vec<u8>> = vec![(0, vec![1; 512]), (1, vec![2; 512])]
+                                             .into_iter()
+                                             .collect();
+    dirty.blocks.insert(1, blocks);
+    let mut objset = ObjectSet::create(ObjectSetType::Zfs);
+    objset.sync(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, 5, dirty, &mut Vec::new())
+          .unwrap();
+    let dnode = objset.dnode(&mut reader, 1).unwrap();
+    let block_ptr = dmu::block_ptr(&mut reader, &dnode, 1).unwrap().unwrap();
+    reader.zio.write_dva(&block_ptr.dvas[0], &[9; 512]).unwrap();
+
+    let datasets = [("tank", &objset.phys)];
+    let report = scrub(&mut reader.zio, &datasets, &Filter::new(), false).unwrap();
+    assert_eq!(report.errors,
+               vec![BlockError {
+                        dataset: "tank".to_owned(),
+                        bookmark: Bookmark {
+                            object: 1,
+                            level: 0,
+                            blkid: 1,
+                        },
+                        offset: Some(512),
+                        bad_copies: 1,
+                        repaired_copies: 0,
+                        readable: false,
+                    }]);
+
+    // The same, with the blocks verified on other threads
+    let taskqs = ZioTaskqs::new(Arc::new(reader.zio.try_clone().unwrap()), [2, 1, 1, 1]);
+    let queued = scrub_with(&mut reader.zio, &datasets, &Filter::new(), false, Some(&taskqs))
+                     .unwrap();
+    assert_eq!(queued.errors, report.errors);
+    assert_eq!(queued.stats.blocks, report.stats.blocks);
+}
@@ -8,25 +8,63 @@ const VDEV_ASYNC_WRITE_MIN_ACTIVE: u32 = 1;
 const VDEV_ASYNC_WRITE_MAX_ACTIVE: u32 = 10;
 const VDEV_SCRUB_MIN_ACTIVE: u32 = 1;
 const VDEV_SCRUB_MAX_ACTIVE: u32 = 2;
-const VDEV_ASYNC_WRITE_ACTIVE_MIN_DIRTY_PERCENT: u32 = 30;
-const VDEV_ASYNC_WRITE_ACTIVE_MAX_DIRTY_PERCENT: u32 = 60;
-const DIRTY_DATA_MAX: u32 = 10;
+const VDEV_ASYNC_WRITE_ACTIVE_MIN_DIRTY_PERCENT: u64 = 30;
+const VDEV_ASYNC_WRITE_ACTIVE_MAX_DIRTY_PERCENT: u64 = 60;
 
-use std::cmp;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, fmt, mem};
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::fs::OpenOptions;
+use std::hash::{BuildHasher, Hash, Hasher};
+#[cfg(feature = "std")]
+use std::io::Read;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
 
+use super::ZfsReader;
+use super::arcache::ArCache;
+use super::dcache::DentryCache;
 use super::avl;
-use super::dmu_objset::ObjectSet;
+use super::dmu;
+use super::dmu_objset::{DMU_POOL_DIRECTORY_OBJECT, ObjectSet, ObjectSetType};
+#[cfg(feature = "std")]
+use super::dmu_recv::{self, Begin, DatasetSink, RecvError, ResumeState, StreamReader};
+use super::dmu_tx::Tx;
+use super::dmu_zfetch::Zfetch;
+use super::dnode::{DNodePhys, ObjectType};
 use super::dsl_pool;
-use super::metaslab::{self, MetaslabClass};
+use super::metaslab::{self, Allocator, MetaslabClass, VdevAllocator};
+use super::mmp;
 use super::nvpair::{NvList, NvValue};
+use super::nvstream;
+use super::spa_config::{self, PoolConfig, VdevConfig};
+use super::space_map;
 use super::taskq::Taskq;
-use super::txg;
+use super::txg::{self, TxState};
 use super::uberblock::Uberblock;
+use super::util;
+use super::version::{PoolVersion, SPA_VERSION_FEATURES};
 use super::vdev;
+use super::vdev_indirect::IndirectMapping;
+#[cfg(feature = "std")]
+use super::vdev_io::MmapVdev;
+use super::vdev_io::{MissingVdev, Vdev};
+use super::vdev_mirror::MirrorVdev;
+use super::vdev_raidz::{self, RaidzVdev};
+use super::vdev_trim::{self, TrimStats};
+use super::xdr;
+use super::zap;
+use super::zfeature::{self, Feature, FeatureReport};
 use super::zfs;
+use super::zil;
 use super::zio;
 use super::dsl_pool::DslPool;
+use super::dsl_prop::PropChange;
 
 pub enum ImportType {
     Existing,
@@ -44,11 +82,14 @@ pub struct Spa {
     normal_class: Rc<MetaslabClass>, // normal data class
     log_class: Rc<MetaslabClass>, // intent log data class
     first_txg: u64,
-    mos: ObjectSet,
+    mos: Option<ObjectSet>, // Not available until the pool has been loaded
     vdev_tree: vdev::Tree,
     root_vdev: vdev::TreeIndex,
     // ubsync: Uberblock, // Last synced uberblock
-    // uberblock: Uberblock, // Current active uberblock
+    uberblock: Option<Uberblock>, // Current active uberblock
+    reader: Option<ZfsReader>, // The device the pool was imported from
+    read_only: bool,
+    feature_report: FeatureReport, // Active features we don't support
     did: u64, // if procp != p0, did of t1
 }
 
@@ -58,8 +99,9 @@ impl Spa {
         let mut writes;
         let dirty = self.dsl_pool.dp_dirty_total;
 
-        let min_bytes = DIRTY_DATA_MAX * VDEV_ASYNC_WRITE_ACTIVE_MIN_DIRTY_PERCENT / 100;
-        let max_bytes = DIRTY_DATA_MAX * VDEV_ASYNC_WRITE_ACTIVE_MAX_DIRTY_PERCENT / 100;
+        let dirty_data_max = self.dsl_pool.dirty_data_max;
+        let min_bytes = dirty_data_max * VDEV_ASYNC_WRITE_ACTIVE_MIN_DIRTY_PERCENT / 100;
+        let max_bytes = dirty_data_max * VDEV_ASYNC_WRITE_ACTIVE_MAX_DIRTY_PERCENT / 100;
 
         // TODO
         if false {//self.pending_synctask() {
@@ -71,7 +113,9 @@ impl Spa {
         } else {
 
             // linear interpolation
-            writes = (dirty - min_bytes) * (VDEV_ASYNC_WRITE_MAX_ACTIVE - VDEV_ASYNC_WRITE_MIN_ACTIVE) / (max_bytes - min_bytes) + VDEV_ASYNC_WRITE_MIN_ACTIVE;
+            writes = ((dirty - min_bytes) *
+                      (VDEV_ASYNC_WRITE_MAX_ACTIVE - VDEV_ASYNC_WRITE_MIN_ACTIVE) as u64 /
+                      (max_bytes - min_bytes)) as u32 + VDEV_ASYNC_WRITE_MIN_ACTIVE;
             assert!(writes >= VDEV_ASYNC_WRITE_MIN_ACTIVE);
             assert!(writes <= VDEV_ASYNC_WRITE_MAX_ACTIVE);
 
@@ -131,7 +175,7 @@ impl Spa {
         let mut vdev_tree = vdev::Tree::new();
         let root_vdev = {
             let nvroot: &NvList = try!(config.get("vdev_tree").ok_or(zfs::Error::Invalid));
-            try!(vdev_tree.parse(&normal_class, nvroot, None, vdev_alloc_type))
+            try!(vdev_tree.parse(&normal_class, nvroot, 0, None, vdev_alloc_type))
         };
 
         Ok(Spa {
@@ -144,9 +188,13 @@ impl Spa {
             normal_class: normal_class,
             log_class: log_class,
             first_txg: 0,
-            mos: ObjectSet,
+            mos: None,
             vdev_tree: vdev_tree,
             root_vdev: root_vdev,
+            uberblock: None,
+            reader: None,
+            read_only: false,
+            feature_report: FeatureReport::default(),
             did: 0,
         })
     }
@@ -227,7 +275,11 @@ impl Spa {
         // spa.meta_objset = spa.dsl_pool.meta_objset;
 
         // Load stuff for the top-level and leaf vdevs
-        spa.vdev_tree.load(&mut spa.mos, spa.root_vdev);
+        if let (false, Some(reader), Some(mos)) = (spa.read_only,
+                                                   spa.reader.as_mut(),
+                                                   spa.mos.as_ref()) {
+            spa.vdev_tree.load(reader, mos, spa.root_vdev);
+        }
 
         Ok(spa)
     }
@@ -325,10 +377,380 @@ impl Spa {
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &NvList {
+        &self.config
+    }
+
+    pub fn uberblock(&self) -> Option<&Uberblock> {
+        self.uberblock.as_ref()
+    }
+
+    pub fn reader_mut(&mut self) -> Option<&mut ZfsReader> {
+        self.reader.as_mut()
+    }
+
+    /// The meta object set, once the pool has been loaded
+    pub fn meta_objset(&self) -> Option<&ObjectSet> {
+        self.mos.as_ref()
+    }
+
+    /// The reader and the MOS together, for walking the MOS
+    pub fn reader_and_mos(&mut self) -> Option<(&mut ZfsReader, &ObjectSet)> {
+        match (self.reader.as_mut(), self.mos.as_ref()) {
+            (Some(reader), Some(mos)) => Some((reader, mos)),
+            _ => None,
+        }
+    }
+
+    /// The pool's on-disk format version, going by its active uberblock
+    pub fn version(&self) -> Option<PoolVersion> {
+        self.uberblock.map(|uberblock| uberblock.pool_version())
+    }
+
+    /// Whether the pool was opened read-only, because of unsupported features (see
+    /// `feature_report`) or because the device can't be written
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn feature_report(&self) -> &FeatureReport {
+        &self.feature_report
+    }
+
+    /// Every feature enabled on the pool, active or not, like `zpool get all | grep feature@`
+    pub fn features(&mut self) -> zfs::Result<Vec<Feature>> {
+        let (reader, mos) = try!(self.reader_and_mos().ok_or(zfs::Error::Invalid));
+        zfeature::features(reader, mos).map_err(|_| zfs::Error::Invalid)
+    }
+
+    pub fn dsl_pool(&self) -> &DslPool {
+        &self.dsl_pool
+    }
+
+    pub fn dsl_pool_mut(&mut self) -> &mut DslPool {
+        &mut self.dsl_pool
+    }
+
+    /// The health of the pool and its vdevs, like `zpool status`. None before the pool has been
+    /// imported.
+    pub fn status(&self) -> Option<PoolStatus> {
+        self.reader.as_ref().map(|reader| PoolStatus::new(&self.name, &reader.zio))
+    }
+
+    /// Discard all the free space of the top-level vdev `alloc` allocates from (see
+    /// `vdev_trim::trim_free`). The space maps aren't written yet, so they only say what was free
+    /// at import: `alloc` knows what's been allocated since.
+    pub fn trim(&mut self, alloc: &mut VdevAllocator) -> zfs::Result<TrimStats> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let reader = try!(self.reader.as_ref().ok_or(zfs::Error::Invalid));
+        Ok(vdev_trim::trim_free(&reader.zio, alloc))
+    }
+
+    /// Discard what frees have given back to `alloc` since the last trim (see
+    /// `VdevAllocator::autotrim`). Call it after `alloc`'s `sync_done`.
+    pub fn trim_freed(&mut self, alloc: &mut VdevAllocator) -> zfs::Result<TrimStats> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let reader = try!(self.reader.as_ref().ok_or(zfs::Error::Invalid));
+        Ok(vdev_trim::trim_freed(&reader.zio, alloc))
+    }
+
+    /// What happens when a block can't be read from any of its copies
+    pub fn failmode(&self) -> zio::FailMode {
+        self.reader.as_ref().map_or(zio::FailMode::Wait, |reader| reader.zio.failmode)
+    }
+
+    pub fn set_failmode(&mut self, failmode: zio::FailMode) {
+        if let Some(reader) = self.reader.as_mut() {
+            reader.zio.failmode = failmode;
+            if failmode != zio::FailMode::Wait {
+                reader.zio.suspended.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether the pool has separate intent log devices (slogs)
+    pub fn has_slog(&self) -> bool {
+        self.config
+            .get::<&NvList>("vdev_tree")
+            .and_then(|nv| VdevConfig::from_nv_list(nv).ok())
+            .map_or(false, |vdev| vdev.is_log || vdev.children.iter().any(|child| child.is_log))
+    }
+
+    /// The metaslab classes to allocate the log blocks of a dataset with `logbias` from, in the
+    /// order to try them
+    pub fn zil_alloc_classes(&self, logbias: zil::LogBias) -> Vec<Rc<MetaslabClass>> {
+        zil::alloc_classes(logbias, self.has_slog())
+            .iter()
+            .map(|class| {
+                match *class {
+                    zil::AllocClass::Log => self.log_class.clone(),
+                    zil::AllocClass::Normal => self.normal_class.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Upgrade the pool to `version` and enable `features` (see `zfeature::enable`). The MOS
+    /// changes go into the open txg, which is synced (see `sync`) along with the labels.
+    pub fn upgrade(&mut self,
+                   alloc: &mut Allocator,
+                   version: u64,
+                   features: &[&str])
+                   -> zfs::Result<()> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let mut uberblock = try!(self.uberblock.ok_or(zfs::Error::Invalid));
+        let old_version = try!(self.config.get("version").ok_or(zfs::Error::Invalid));
+        let new_version = try!(spa_config::upgraded_version(old_version, version, features));
+        uberblock.version = new_version;
+        // The labels only list what it takes to read the pool
+        let for_read: Vec<&str> = features.iter()
+                                          .cloned()
+                                          .filter(|guid| zfeature::needed_for_read(guid))
+                                          .collect();
+
+        let synced = {
+            let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+            let mos = try!(self.mos.as_mut().ok_or(zfs::Error::Invalid));
+            try!(zfeature::enable(reader, &mut self.dsl_pool, mos, features));
+            if new_version > old_version {
+                try!(rewrite_mos_config(reader, &mut self.dsl_pool, mos, &|nv| {
+                    if let Some(value) = nv.find_mut("version") {
+                        *value = NvValue::Uint64(new_version);
+                    }
+                }));
+            }
+            let synced = try!(try!(self.dsl_pool.sync(reader, mos, &uberblock, alloc))
+                                  .ok_or(zfs::Error::Invalid));
+            let rewritten = try!(sync_labels(&mut reader.zio, &synced, &|nv| {
+                // Checked against the pool's config already
+                let _ = spa_config::upgrade_config(nv, version, &for_read);
+            }));
+            self.dsl_pool.sync_done();
+            if rewritten == 0 {
+                return Err(zfs::Error::Invalid);
+            }
+            synced
+        };
+
+        self.uberblock = Some(synced);
+        spa_config::upgrade_config(&mut self.config, version, &for_read)
+    }
+
+    /// Give the pool and all of its vdevs new guids, so copies of the pool's devices (e.g. a cloned
+    /// disk image) can be imported next to the original. The MOS copy of the config changes in
+    /// the open txg, which is synced (see `sync`) along with the labels. Returns the new pool
+    /// guid.
+    pub fn reguid(&mut self, alloc: &mut Allocator) -> zfs::Result<u64> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let mut uberblock = try!(self.uberblock.ok_or(zfs::Error::Invalid));
+        // The root vdev's guid is the pool guid, so it gets replaced along with it
+        let guids = try!(spa_config::new_guids(&self.config, &mut generate_guid));
+        for &(old, new) in &guids {
+            uberblock.guid_sum = uberblock.guid_sum.wrapping_sub(old).wrapping_add(new);
+        }
+
+        let synced = {
+            let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+            let mos = try!(self.mos.as_mut().ok_or(zfs::Error::Invalid));
+            try!(rewrite_mos_config(reader,
+                                    &mut self.dsl_pool,
+                                    mos,
+                                    &|nv| spa_config::reguid_config(nv, &guids)));
+            let synced = try!(try!(self.dsl_pool.sync(reader, mos, &uberblock, alloc))
+                                  .ok_or(zfs::Error::Invalid));
+            let rewritten = try!(sync_labels(&mut reader.zio,
+                                             &synced,
+                                             &|nv| spa_config::reguid_config(nv, &guids)));
+            self.dsl_pool.sync_done();
+            if rewritten == 0 {
+                return Err(zfs::Error::Invalid);
+            }
+            synced
+        };
+
+        spa_config::reguid_config(&mut self.config, &guids);
+        for &(old, new) in &guids {
+            self.vdev_tree.set_guid(old, new);
+        }
+        self.uberblock = Some(synced);
+
+        Ok(guids[0].1)
+    }
+
+    /// An allocator for the pool's top-level vdev, with the free space read from its space maps
+    /// (see `VdevAllocator::open`)
+    pub fn allocator(&mut self) -> zfs::Result<VdevAllocator> {
+        let vdev = {
+            let vdev_tree = try!(self.config.get("vdev_tree").ok_or(zfs::Error::Invalid));
+            try!(VdevConfig::from_nv_list(vdev_tree))
+        };
+        let (reader, mos) = try!(self.reader_and_mos().ok_or(zfs::Error::Invalid));
+        VdevAllocator::open(reader, mos, &vdev)
+    }
+
+    /// Grow the pool into space added to the end of its device, like `zpool online -e`. The new
+    /// metaslabs get space maps, in the metaslab array, in the open txg, which is synced (see
+    /// `sync`) along with the labels. `alloc` isn't given the new space, an allocator opened
+    /// after the expansion (see `allocator`) is. Returns the new asize of the top-level vdev, or
+    /// None if the device didn't grow by a whole metaslab.
+    pub fn expand(&mut self, alloc: &mut Allocator) -> zfs::Result<Option<u64>> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let uberblock = try!(self.uberblock.ok_or(zfs::Error::Invalid));
+
+        let (top_guid, asize, synced) = {
+            let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+            let mos = try!(self.mos.as_mut().ok_or(zfs::Error::Invalid));
+            // Labels 2 and 3 moved along with the end of the device, only the front ones are
+            // where they were written
+            let mut nv = try!(spa_config::read_label_nv_list(&mut reader.zio, 0)
+                                  .or_else(|_| spa_config::read_label_nv_list(&mut reader.zio, 1)));
+            let top_guid = try!(nv.get("top_guid").ok_or(zfs::Error::Invalid));
+            let (old_asize, ms_shift, ms_array): (u64, u64, u64) = {
+                let vdev_tree: &NvList = try!(nv.get("vdev_tree").ok_or(zfs::Error::Invalid));
+                if vdev_tree.find("children").is_some() {
+                    // Mirrors and raidz only grow once all of their children have, and the other
+                    // children live on devices we don't have
+                    return Err(zfs::Error::UnsupportedFeature);
+                }
+                (try!(vdev_tree.get("asize").ok_or(zfs::Error::Invalid)),
+                 try!(vdev_tree.get("metaslab_shift").ok_or(zfs::Error::Invalid)),
+                 try!(vdev_tree.get("metaslab_array").ok_or(zfs::Error::Invalid)))
+            };
+
+            let asize = spa_config::device_asize(reader.zio.size());
+            if ms_shift == 0 || asize >> ms_shift <= old_asize >> ms_shift {
+                return Ok(None);
+            }
+            try!(spa_config::set_asize_config(&mut nv, top_guid, asize).ok_or(zfs::Error::Invalid));
+
+            // The new end labels go into space nothing references yet, so they're written first,
+            // with the uberblock as it is. If a crash interrupts the txg, the pool is still
+            // intact at its old size and the expansion can simply be retried.
+            for label in 2..zio::VDEV_LABELS {
+                try!(spa_config::write_label_nv_list(&mut reader.zio, label, &nv));
+                try!(reader.zio.write_uberblock(label, &uberblock));
+            }
+            try!(reader.zio.flush());
+
+            try!(grow_metaslab_array(reader,
+                                     &mut self.dsl_pool,
+                                     mos,
+                                     ms_array,
+                                     old_asize >> ms_shift,
+                                     asize >> ms_shift));
+            try!(rewrite_mos_config(reader, &mut self.dsl_pool, mos, &|nv| {
+                spa_config::set_asize_config(nv, top_guid, asize);
+            }));
+            let synced = try!(try!(self.dsl_pool.sync(reader, mos, &uberblock, alloc))
+                                  .ok_or(zfs::Error::Invalid));
+            let rewritten = try!(sync_labels(&mut reader.zio, &synced, &|nv| {
+                spa_config::set_asize_config(nv, top_guid, asize);
+            }));
+            self.dsl_pool.sync_done();
+            if rewritten == 0 {
+                return Err(zfs::Error::Invalid);
+            }
+            (top_guid, asize, synced)
+        };
+
+        spa_config::set_asize_config(&mut self.config, top_guid, asize);
+        self.vdev_tree.set_asize(top_guid, asize);
+        self.uberblock = Some(synced);
+
+        Ok(Some(asize))
+    }
+
+    /// Sync the next txg of the DSL pool (see `DslPool::sync`), with its blocks allocated from
+    /// `alloc`, and make it durable: the new uberblock goes to the uberblock ring of every label
+    /// once the blocks it points at are on the disk. Returns the txg, or None if there was
+    /// nothing to sync. The txg counts as synced for `alloc` too, its `sync_done` is the
+    /// caller's to call.
+    pub fn sync(&mut self, alloc: &mut Allocator) -> zfs::Result<Option<u64>> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let uberblock = try!(self.uberblock.as_mut().ok_or(zfs::Error::Invalid));
+        let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+        let mos = try!(self.mos.as_mut().ok_or(zfs::Error::Invalid));
+        sync_txg(reader, &mut self.dsl_pool, mos, uberblock, alloc)
+    }
+
+    /// Receive the rest of the stream starting with `begin` into the objset of the dataset with
+    /// MOS object number `dataset` (see `dmu_recv::DatasetSink`), and sync it all out. A full
+    /// stream replaces whatever the dataset held.
+    #[cfg(feature = "std")]
+    pub fn receive<R: Read>(&mut self,
+                            alloc: &mut Allocator,
+                            dataset: u64,
+                            begin: &Begin,
+                            stream: &mut StreamReader<R>,
+                            resume: &mut ResumeState)
+                            -> Result<(), RecvError> {
+        if self.read_only {
+            return Err(RecvError::Apply(zfs::Error::ReadOnly));
+        }
+        let invalid = RecvError::Apply(zfs::Error::Invalid);
+        let uberblock = try!(self.uberblock.as_mut().ok_or(invalid.clone()));
+        let reader = try!(self.reader.as_mut().ok_or(invalid.clone()));
+        let mos = try!(self.mos.as_mut().ok_or(invalid));
+        let mut sink = try!(DatasetSink::new(reader,
+                                             &mut self.dsl_pool,
+                                             mos,
+                                             uberblock,
+                                             alloc,
+                                             dataset,
+                                             begin)
+                                .map_err(RecvError::Apply));
+        try!(dmu_recv::receive(stream, &mut sink, resume));
+        sink.finish().map_err(RecvError::Apply)
+    }
+
+    /// Create the filesystem `name` in the open txg (see `DslPool::create_dataset`)
+    pub fn create_dataset(&mut self, name: &str) -> zfs::Result<u64> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+        let mos = try!(self.mos.as_ref().ok_or(zfs::Error::Invalid));
+        self.dsl_pool.create_dataset(reader, mos, name)
+    }
+
+    /// Destroy the filesystem `name` in the open txg (see `DslPool::destroy_dataset`)
+    pub fn destroy_dataset(&mut self, name: &str) -> zfs::Result<()> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+        let mos = try!(self.mos.as_ref().ok_or(zfs::Error::Invalid));
+        self.dsl_pool.destroy_dataset(reader, mos, name)
+    }
+
+    /// Change property `prop` of dataset `name` in the open txg (see `DslPool::set_property`)
+    pub fn set_property(&mut self, name: &str, prop: &str, change: &PropChange) -> zfs::Result<()> {
+        if self.read_only {
+            return Err(zfs::Error::ReadOnly);
+        }
+        let reader = try!(self.reader.as_mut().ok_or(zfs::Error::Invalid));
+        let mos = try!(self.mos.as_ref().ok_or(zfs::Error::Invalid));
+        self.dsl_pool.set_property(reader, mos, name, prop, change)
+    }
+
     fn last_synced_txg(&self) -> u64 {
-        // TODO
-        // self.ubsync.ub_txg
-        0
+        self.uberblock.map(|uberblock| uberblock.txg).unwrap_or(0)
     }
 
     fn first_txg(&self) -> u64 {
@@ -336,6 +758,979 @@ impl Spa {
     }
 }
 
+/// `Spa::sync` on the parts of a pool: sync the next txg of `dsl_pool`, write `uberblock`'s
+/// successor out and make it the pool's
+pub fn sync_txg(reader: &mut ZfsReader,
+                dsl_pool: &mut DslPool,
+                mos: &mut ObjectSet,
+                uberblock: &mut Uberblock,
+                alloc: &mut Allocator)
+                -> zfs::Result<Option<u64>> {
+    let synced = match try!(dsl_pool.sync(reader, mos, uberblock, alloc)) {
+        Some(synced) => synced,
+        None => return Ok(None),
+    };
+    try!(sync_uberblock(&mut reader.zio, &synced));
+    dsl_pool.sync_done();
+    *uberblock = synced;
+    Ok(Some(synced.txg))
+}
+
+/// Write a config change (`update`) and `uberblock` to the labels of `zio` in the order of a txg
+/// sync: the even labels, the uberblocks, then the odd labels, with a flush after each step.
+/// Whichever writes a crash loses, one pair of labels still agrees with the newest uberblock that
+/// made it to disk. Once the uberblocks have, a new generation of `zio` starts. Returns the
+/// number of labels rewritten.
+pub fn sync_labels(zio: &mut zio::Reader,
+                   uberblock: &Uberblock,
+                   update: &Fn(&mut NvList))
+                   -> zfs::Result<usize> {
+    let mut rewritten = try!(spa_config::rewrite_labels(zio, &[0, 2], update));
+    try!(sync_uberblock(zio, uberblock));
+    rewritten += try!(spa_config::rewrite_labels(zio, &[1, 3], update));
+    try!(zio.flush());
+    Ok(rewritten)
+}
+
+/// Make the txg of `uberblock` durable: flush what was written for it so far, then write the
+/// uberblock to every label of every top-level vdev, and flush that. Once the uberblocks are
+/// on the disk, a new generation of `zio` starts.
+pub fn sync_uberblock(zio: &mut zio::Reader, uberblock: &Uberblock) -> zfs::Result<()> {
+    try!(zio.flush());
+    for label in 0..zio::VDEV_LABELS {
+        try!(zio.write_uberblock(label, uberblock));
+    }
+    try!(zio.flush());
+    // The txg is on the disk now, what was read before may not be the pool as it is any more
+    zio.observe_txg(uberblock.txg);
+    Ok(())
+}
+
+/// What an import checks of the labels of a pool of a single top-level vdev: the newest
+/// uberblock has to belong to the config in one of the labels, which is what its `guid_sum`
+/// says. Returns that uberblock.
+pub fn check_labels(zio: &zio::Reader) -> zfs::Result<Uberblock> {
+    let uberblock = try!(zio.uber());
+    let matches = (0..zio::VDEV_LABELS).any(|label| {
+        spa_config::read_label_nv_list(zio, label)
+            .and_then(|nv| spa_config::config_guid_sum(&nv))
+            .map(|guid_sum| guid_sum == uberblock.guid_sum)
+            .unwrap_or(false)
+    });
+    if matches { Ok(uberblock) } else { Err(zfs::Error::InvalidLabel) }
+}
+
+/// Open the pool made of `devices` for reading: sort them into the top-level vdevs their labels
+/// say they belong to, and put those together (as a mirror, raidz or single device). Returns the
+/// reader along with the pool config from the labels, whose `vdev_tree` is the whole tree under
+/// a root vdev. Devices without labels at all are taken to be a single device, or a mirror.
+pub fn open_devices(devices: Vec<Box<Vdev>>) -> zfs::Result<(zio::Reader, Option<NvList>)> {
+    let (reader, config, missing) = try!(open_available(devices));
+    if !missing.is_empty() {
+        // The other top-level vdevs live on devices we weren't given
+        return Err(zfs::Error::NoEntity);
+    }
+    Ok((reader, config))
+}
+
+/// Like `open_devices`, but top-level vdevs none of the devices belong to are left out, and
+/// their IDs returned. Only the MOS config can tell whether they were removed from the pool, or
+/// their devices weren't given.
+fn open_available(mut devices: Vec<Box<Vdev>>)
+                  -> zfs::Result<(zio::Reader, Option<NvList>, Vec<u64>)> {
+    // Any intact label will do, they all carry the same config
+    let mut labels = Vec::new();
+    for device in &devices {
+        let mut reader = zio::Reader::boxed(try!(device.try_clone()));
+        labels.push((0..zio::VDEV_LABELS)
+                        .filter_map(|label| spa_config::read_label_nv_list(&mut reader, label).ok())
+                        .next());
+    }
+    if labels.iter().all(|label| label.is_none()) {
+        let disk: Box<Vdev> = match devices.len() {
+            1 => devices.pop().unwrap(),
+            _ => Box::new(MirrorVdev::new(devices)),
+        };
+        return Ok((zio::Reader::boxed(disk), None, Vec::new()));
+    }
+
+    // The devices by top-level vdev ID, along with the config of that vdev
+    let mut config: Option<(PoolConfig, NvList)> = None;
+    let mut tops = BTreeMap::new();
+    for (device, label) in devices.into_iter().zip(labels) {
+        let mut label = try!(label.ok_or(zfs::Error::InvalidLabel));
+        let device_config = try!(PoolConfig::from_nv_list(&label));
+        let index = try!(label.pairs
+                              .iter()
+                              .position(|&(ref name, _)| name == "vdev_tree")
+                              .ok_or(zfs::Error::Invalid));
+        let (_, top) = label.pairs.remove(index);
+        let top = match top {
+            NvValue::NvList(top) => top,
+            _ => return Err(zfs::Error::Invalid),
+        };
+
+        let pool_guid = match config {
+            Some((ref pool_config, _)) => pool_config.pool_guid,
+            None => device_config.pool_guid,
+        };
+        if device_config.pool_guid != pool_guid {
+            return Err(zfs::Error::Invalid);
+        }
+        tops.entry(device_config.vdev_tree.id)
+            .or_insert((top, Vec::new()))
+            .1
+            .push((device, device_config.guid));
+        if config.is_none() {
+            config = Some((device_config, label));
+        }
+    }
+    let (pool_config, mut config) = config.unwrap();
+    let missing: Vec<u64> = (0..pool_config.vdev_children)
+                                .filter(|id| !tops.contains_key(id))
+                                .collect();
+
+    let mut vdevs = BTreeMap::new();
+    let mut top_configs = Vec::new();
+    for (id, (top, devices)) in tops {
+        vdevs.insert(id, try!(open_top_vdev(&try!(VdevConfig::from_nv_list(&top)), devices)));
+        top_configs.push(top);
+    }
+    config.add("vdev_tree".to_owned(),
+               NvValue::NvList(root_vdev_config(pool_config.pool_guid, top_configs)));
+
+    // The labels and uberblocks of any top-level vdev will do too. The blocks of the vdevs that
+    // are missing mustn't go to the one that's left.
+    let reader = if vdevs.len() == 1 && missing.is_empty() {
+        zio::Reader::boxed(vdevs.into_iter().next().unwrap().1)
+    } else {
+        let mut reader = zio::Reader::boxed(try!(vdevs.values().next().unwrap().try_clone()));
+        reader.tops = vdevs;
+        reader
+    };
+    Ok((reader, Some(config), missing))
+}
+
+/// The top-level vdev `top`, out of the `devices` given for it along with their guids. Its
+/// children that weren't given are missing, which a mirror or raidz can make up for.
+fn open_top_vdev(top: &VdevConfig, devices: Vec<(Box<Vdev>, u64)>) -> zfs::Result<Box<Vdev>> {
+    if top.children.is_empty() {
+        // A leaf, all the devices given for it are the same one
+        return Ok(devices.into_iter().next().unwrap().0);
+    }
+
+    let mut children: Vec<Box<Vdev>> = top.children
+                                          .iter()
+                                          .map(|_| Box::new(MissingVdev) as _)
+                                          .collect();
+    for (device, guid) in devices {
+        match top.children.iter().position(|child| child.guid == guid) {
+            Some(child) => children[child] = device,
+            None => return Err(zfs::Error::Invalid),
+        }
+    }
+    match &top.vdev_type[..] {
+        "mirror" => Ok(Box::new(MirrorVdev::new(children))),
+        "raidz" => {
+            let nparity = top.nparity.unwrap_or(1) as usize;
+            if nparity == 0 || nparity > vdev_raidz::VDEV_RAIDZ_MAXPARITY ||
+               nparity >= children.len() {
+                return Err(zfs::Error::Invalid);
+            }
+            let ashift = top.ashift.unwrap_or(zio::SPA_MINBLOCKSHIFT);
+            let raidz = RaidzVdev::new(children, nparity, ashift)
+                            .with_expansions(top.raidz_expand_txgs.len(), top.raidz_expanding);
+            Ok(Box::new(raidz))
+        }
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// Import the pool living on the device (or image file) at `path`
+#[cfg(feature = "std")]
+pub fn import(path: &str) -> zfs::Result<Spa> {
+    import_devices(&[path])
+}
+
+/// Smallest device a pool can be created on (`SPA_MINDEVSIZE`)
+pub const SPA_MINDEVSIZE: u64 = 64 << 20;
+
+/// Largest sector size a pool can be created for, 64K
+const ASHIFT_MAX: u64 = 16;
+
+// Metaslabs are 512M, unless that makes fewer than 16 of them or more than the limit. They're
+// never smaller than 16M (`vdev_metaslab_set_size`).
+const VDEV_DEFAULT_MS_SHIFT: u64 = 29;
+const VDEV_MIN_MS_COUNT: u64 = 16;
+const VDEV_MS_COUNT_LIMIT: u64 = 128 << 10;
+const VDEV_MIN_MS_SHIFT: u64 = 24;
+
+/// The MOS copy of the config is packed into blocks of this size (`SPA_CONFIG_BLOCKSIZE`)
+const SPA_CONFIG_BLOCKSIZE: usize = 16 << 10;
+
+/// Type of the MOS ZAPs newer than the numbered object types, like the feature ZAPs
+const DMU_OTN_ZAP_METADATA: u8 = 0x80 | 0x40 | 4;
+
+/// The MOS directory entry with the uberblock of the pool's checkpoint, as integers
+pub const DMU_POOL_ZPOOL_CHECKPOINT: &'static str = "com.delphix:zpool_checkpoint";
+
+/// How to create a pool
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    /// The name of the pool, and of its root dataset
+    pub name: String,
+    /// log2 of the sector size to allocate in, 9 to 16 (`zpool create -o ashift=`)
+    pub ashift: u64,
+}
+
+impl CreateOptions {
+    /// Create the pool `name`, for 512 byte sectors
+    pub fn new(name: &str) -> Self {
+        CreateOptions {
+            name: name.to_owned(),
+            ashift: zio::SPA_MINBLOCKSHIFT,
+        }
+    }
+}
+
+/// Create a pool on the device (or image file) at `path`, like `zpool create`, and import it.
+/// Whatever was on the device is lost. See `format`.
+#[cfg(feature = "std")]
+pub fn create(path: &str, options: &CreateOptions) -> zfs::Result<Spa> {
+    let disk = try!(OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(path)
+                        .map_err(|_| zfs::Error::NoEntity));
+    try!(format(zio::Reader::new(disk), path, options));
+    import(path)
+}
+
+/// Format the device `zio`, found at `path`, as a new pool of a single top-level vdev. All four
+/// labels get the pool's config, then the first txg writes the MOS: the object directory, the
+/// pool config, the feature ZAPs with no feature enabled, and the root DSL directory with the
+/// root dataset, an empty filesystem. Returns the uberblock of that txg.
+pub fn format(zio: zio::Reader, path: &str, options: &CreateOptions) -> zfs::Result<Uberblock> {
+    if !is_valid_pool_name(&options.name) || options.ashift < zio::SPA_MINBLOCKSHIFT ||
+       options.ashift > ASHIFT_MAX {
+        return Err(zfs::Error::Invalid);
+    }
+    let size = zio.size();
+    if size < SPA_MINDEVSIZE {
+        return Err(zfs::Error::NoSpace);
+    }
+    let asize = spa_config::device_asize(size);
+    let ms_shift = metaslab_shift(asize);
+    let (pool_guid, guid) = (generate_guid(), generate_guid());
+    let txg = txg::TXG_INITIAL as u64;
+
+    let top = || {
+        let disk = if path.starts_with("/dev/") { "disk" } else { "file" };
+        let mut top = NvList::new(0);
+        top.add("type".to_owned(), NvValue::String(disk.to_owned()));
+        top.add("id".to_owned(), NvValue::Uint64(0));
+        top.add("guid".to_owned(), NvValue::Uint64(guid));
+        top.add("path".to_owned(), NvValue::String(path.to_owned()));
+        top.add("metaslab_array".to_owned(), NvValue::Uint64(MOS_METASLAB_ARRAY_OBJ));
+        top.add("metaslab_shift".to_owned(), NvValue::Uint64(ms_shift));
+        top.add("ashift".to_owned(), NvValue::Uint64(options.ashift));
+        top.add("asize".to_owned(), NvValue::Uint64(asize));
+        top.add("is_log".to_owned(), NvValue::Uint64(0));
+        top.add("create_txg".to_owned(), NvValue::Uint64(txg));
+        top
+    };
+    // The labels describe their own top-level vdev, the MOS copy the whole tree
+    let config = |vdev_tree: NvList, label: bool| {
+        let mut config = NvList::new(0);
+        config.add("version".to_owned(), NvValue::Uint64(SPA_VERSION_FEATURES));
+        config.add("name".to_owned(), NvValue::String(options.name.clone()));
+        config.add("state".to_owned(), NvValue::Uint64(zfs::PoolState::Active as u64));
+        config.add("txg".to_owned(), NvValue::Uint64(txg));
+        config.add("pool_guid".to_owned(), NvValue::Uint64(pool_guid));
+        if label {
+            config.add("top_guid".to_owned(), NvValue::Uint64(guid));
+            config.add("guid".to_owned(), NvValue::Uint64(guid));
+        }
+        config.add("vdev_children".to_owned(), NvValue::Uint64(1));
+        config.add("vdev_tree".to_owned(), NvValue::NvList(vdev_tree));
+        // No feature is enabled, so none is needed to read the MOS
+        config.add("features_for_read".to_owned(), NvValue::NvList(NvList::new(0)));
+        config
+    };
+
+    let mut zio = zio;
+    let label = config(top(), true);
+    for l in 0..zio::VDEV_LABELS {
+        try!(spa_config::clear_label(&mut zio, l));
+        try!(spa_config::write_label_nv_list(&mut zio, l, &label));
+    }
+    zio.detect_sector_shift();
+    let mut reader = ZfsReader {
+        zio: zio,
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+
+    let mut dsl_pool = DslPool::new();
+    dsl_pool.tx = TxState::new(txg - 1);
+    let txg = dsl_pool.tx.hold_open();
+    let mos_config = config(root_vdev_config(pool_guid, vec![top()]), false);
+    try!(create_mos(&mut dsl_pool, txg, &mos_config, asize >> ms_shift));
+    dsl_pool.tx.rele(txg);
+
+    // TODO: The blocks of the first txg aren't in any space map yet, the metaslabs all read as
+    // free until space maps are written
+    let mut mos = ObjectSet::create(ObjectSetType::Meta);
+    let mut alloc = VdevAllocator::new(0, options.ashift, asize, None);
+    let mut uberblock = Uberblock::new(PoolVersion::Features, pool_guid.wrapping_add(guid));
+    try!(try!(sync_txg(&mut reader, &mut dsl_pool, &mut mos, &mut uberblock, &mut alloc))
+             .ok_or(zfs::Error::Invalid));
+    Ok(uberblock)
+}
+
+// The MOS objects of a new pool
+const MOS_CONFIG_OBJ: u64 = 2;
+const MOS_FEATURES_FOR_READ_OBJ: u64 = 3;
+const MOS_FEATURES_FOR_WRITE_OBJ: u64 = 4;
+const MOS_FEATURE_DESCRIPTIONS_OBJ: u64 = 5;
+const MOS_METASLAB_ARRAY_OBJ: u64 = 6;
+const MOS_ROOT_DIR_OBJ: u64 = 7;
+const MOS_ROOT_CHILD_DIR_OBJ: u64 = 8;
+const MOS_ROOT_PROPS_OBJ: u64 = 9;
+const MOS_ROOT_DATASET_OBJ: u64 = 10;
+const MOS_ROOT_SNAPNAMES_OBJ: u64 = 11;
+
+/// Dirty the MOS objects of a new pool in txg `txg`, along with the objset of its root dataset.
+/// `config` is the pool's, and its top-level vdev has `metaslabs` metaslabs.
+fn create_mos(dsl_pool: &mut DslPool,
+              txg: u64,
+              config: &NvList,
+              metaslabs: u64)
+              -> zfs::Result<()> {
+    let mos_zap = |dsl_pool: &mut DslPool, object, object_type, entries: &[(&str, u64)]| {
+        zap::create(dsl_pool, txg, dsl_pool::MOS_OBJSET, object, object_type, entries)
+    };
+    try!(mos_zap(dsl_pool,
+                 DMU_POOL_DIRECTORY_OBJECT,
+                 ObjectType::ObjectDirectory as u8,
+                 &[("root_dataset", MOS_ROOT_DIR_OBJ),
+                   ("config", MOS_CONFIG_OBJ),
+                   ("features_for_read", MOS_FEATURES_FOR_READ_OBJ),
+                   ("features_for_write", MOS_FEATURES_FOR_WRITE_OBJ),
+                   ("feature_descriptions", MOS_FEATURE_DESCRIPTIONS_OBJ)]));
+    for &object in &[MOS_FEATURES_FOR_READ_OBJ,
+                     MOS_FEATURES_FOR_WRITE_OBJ,
+                     MOS_FEATURE_DESCRIPTIONS_OBJ] {
+        try!(mos_zap(dsl_pool, object, DMU_OTN_ZAP_METADATA, &[]));
+    }
+
+    // The packed config, its size in the bonus buffer
+    let mut packed = vec![0; nvstream::encoded_size(config)];
+    {
+        let mut xdr = xdr::MemOps::new(&mut packed);
+        try!(nvstream::encode_nv_list(&mut xdr, config).map_err(|_| zfs::Error::Invalid));
+    }
+    let size = (packed.len() as u64).to_le_bytes();
+    let dnode = DNodePhys::new(ObjectType::PackedNvList,
+                               SPA_CONFIG_BLOCKSIZE as u64,
+                               ObjectType::NvListSize,
+                               &size);
+    dsl_pool.dirty_dnode(txg, dsl_pool::MOS_OBJSET, MOS_CONFIG_OBJ, dnode);
+    for (blkid, chunk) in packed.chunks(SPA_CONFIG_BLOCKSIZE).enumerate() {
+        let mut block = chunk.to_vec();
+        block.resize(SPA_CONFIG_BLOCKSIZE, 0);
+        dsl_pool.dirty_block(txg, dsl_pool::MOS_OBJSET, MOS_CONFIG_OBJ, blkid as u64, block);
+    }
+
+    // No metaslab has a space map yet
+    let array_size = metaslabs as usize * mem::size_of::<u64>();
+    let dnode = DNodePhys::new(ObjectType::ObjectArray,
+                               SPA_CONFIG_BLOCKSIZE as u64,
+                               ObjectType::None,
+                               &[]);
+    dsl_pool.dirty_dnode(txg, dsl_pool::MOS_OBJSET, MOS_METASLAB_ARRAY_OBJ, dnode);
+    for blkid in 0..(array_size + SPA_CONFIG_BLOCKSIZE - 1) / SPA_CONFIG_BLOCKSIZE {
+        dsl_pool.dirty_block(txg,
+                             dsl_pool::MOS_OBJSET,
+                             MOS_METASLAB_ARRAY_OBJ,
+                             blkid as u64,
+                             vec![0; SPA_CONFIG_BLOCKSIZE]);
+    }
+
+    dsl_pool.create_dir(txg,
+                        0,
+                        [MOS_ROOT_DIR_OBJ,
+                         MOS_ROOT_CHILD_DIR_OBJ,
+                         MOS_ROOT_PROPS_OBJ,
+                         MOS_ROOT_DATASET_OBJ,
+                         MOS_ROOT_SNAPNAMES_OBJ])
+}
+
+/// log2 of the size of the metaslabs of a top-level vdev of `asize` bytes
+fn metaslab_shift(asize: u64) -> u64 {
+    let mut ms_shift = VDEV_DEFAULT_MS_SHIFT;
+    if asize >> ms_shift < VDEV_MIN_MS_COUNT {
+        ms_shift = util::highbit64(asize / VDEV_MIN_MS_COUNT) as u64;
+    } else if asize >> ms_shift > VDEV_MS_COUNT_LIMIT {
+        ms_shift = util::highbit64(asize / VDEV_MS_COUNT_LIMIT) as u64;
+    }
+    cmp::max(ms_shift, VDEV_MIN_MS_SHIFT)
+}
+
+/// Whether `name` can name a pool (`pool_namecheck`): letters, digits and `-_.: `, starting with
+/// a letter, and not taken for a vdev type
+fn is_valid_pool_name(name: &str) -> bool {
+    let reserved = ["mirror", "raidz", "draid"];
+    name.len() < 256 && name.chars().next().map_or(false, |c| c.is_ascii_alphabetic()) &&
+    name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.: ".contains(c)) &&
+    !reserved.iter().any(|reserved| name.starts_with(reserved)) && name != "spare" &&
+    name != "log"
+}
+
+/// The phases of an import, in order
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpenPhase {
+    /// Opening the devices, for writing if possible
+    Devices,
+    /// Reading the labels and putting the top-level vdevs together
+    Labels,
+    /// Finding the newest uberblock
+    Uberblock,
+    /// Watching the uberblocks for writes of another host that has the pool imported, only for
+    /// multihost pools another host may have
+    Activity,
+    /// Checking the vdev tree of the config against the uberblock
+    Config,
+    /// Reading the MOS
+    Mos,
+    /// Checking the active features against those we support
+    Features,
+    /// Setting up the metaslabs, for pools opened for writing
+    Metaslabs,
+    /// Opening the DSL pool
+    DslPool,
+}
+
+/// What an import went through: what it chose, what it got past, and how long each phase took.
+/// It comes back whether the import worked or not, so a pool that doesn't import says where and
+/// why.
+#[derive(Clone, Debug, Default)]
+pub struct OpenReport {
+    /// The devices, and whether each could be opened for writing
+    pub devices: Vec<(String, bool)>,
+    pub pool_name: Option<String>,
+    pub pool_guid: Option<u64>,
+    /// The vdev tree, its root put together from the labels
+    pub vdev_tree: Option<VdevConfig>,
+    /// The label the active uberblock was found in
+    pub uberblock_label: Option<usize>,
+    pub uberblock_txg: Option<u64>,
+    /// When the active uberblock was written, in seconds since 1970
+    pub uberblock_timestamp: Option<u64>,
+    /// The pool's version, the active uberblock's
+    pub version: Option<PoolVersion>,
+    pub features: Option<FeatureReport>,
+    pub read_only: bool,
+    /// Problems the import got past, like damaged labels or devices it couldn't write to
+    pub tolerated: Vec<String>,
+    /// How long each phase that was started took, in order
+    pub phases: Vec<(OpenPhase, Duration)>,
+    /// The phase the import failed in, and the error
+    pub failure: Option<(OpenPhase, zfs::Error)>,
+    running: Option<(OpenPhase, Instant)>,
+}
+
+impl OpenReport {
+    /// Start `phase`, which ends the one before it
+    fn phase(&mut self, phase: OpenPhase) {
+        self.end_phase();
+        self.running = Some((phase, Instant::now()));
+    }
+
+    fn end_phase(&mut self) {
+        if let Some((phase, started)) = self.running.take() {
+            self.phases.push((phase, started.elapsed()));
+        }
+    }
+}
+
+impl fmt::Display for OpenReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.pool_name, self.pool_guid) {
+            (&Some(ref name), Some(guid)) => try!(writeln!(f, "pool: {} (guid {:x})", name, guid)),
+            _ => try!(writeln!(f, "pool: unknown")),
+        }
+        for &(ref path, writable) in &self.devices {
+            try!(writeln!(f, "device: {}{}", path, if writable { "" } else { " (read-only)" }));
+        }
+        if let (Some(label), Some(txg)) = (self.uberblock_label, self.uberblock_txg) {
+            try!(writeln!(f,
+                          "uberblock: txg {} from label {}, written at {}",
+                          txg,
+                          label,
+                          self.uberblock_timestamp.unwrap_or(0)));
+        }
+        if let Some(version) = self.version {
+            try!(writeln!(f, "version: {}", version));
+        }
+        if let Some(ref features) = self.features {
+            for feature in &features.unsupported_for_read {
+                try!(writeln!(f, "unsupported feature: {}", feature));
+            }
+            for feature in &features.unsupported_for_write {
+                try!(writeln!(f, "unsupported feature for writing: {}", feature));
+            }
+        }
+        for problem in &self.tolerated {
+            try!(writeln!(f, "tolerated: {}", problem));
+        }
+        for &(phase, duration) in &self.phases {
+            try!(writeln!(f,
+                          "{:?}: {}.{:03}s",
+                          phase,
+                          duration.as_secs(),
+                          duration.subsec_nanos() / 1_000_000));
+        }
+        match self.failure {
+            Some((phase, error)) => writeln!(f, "failed in {:?}: {}", phase, error),
+            None => writeln!(f, "imported{}", if self.read_only { " read-only" } else { "" }),
+        }
+    }
+}
+
+/// The health of a pool and its vdevs, like `zpool status` shows it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolStatus {
+    pub name: String,
+    /// Faulted if any top-level vdev is unusable, as the pool can't do without any of them, and
+    /// degraded if any isn't healthy
+    pub state: vdev::State,
+    pub vdevs: Vec<zio::VdevStatus>,
+}
+
+impl PoolStatus {
+    /// The status of the pool named `name`, whose devices `zio` reads
+    pub fn new(name: &str, zio: &zio::Reader) -> Self {
+        let vdevs = zio.vdevs_status();
+        let state = if vdevs.iter().any(|vdev| {
+            vdev.state != vdev::State::Healthy && vdev.state != vdev::State::Degraded
+        }) {
+            vdev::State::Faulted
+        } else if vdevs.iter().any(|vdev| vdev.state == vdev::State::Degraded) {
+            vdev::State::Degraded
+        } else {
+            vdev::State::Healthy
+        };
+        PoolStatus {
+            name: name.to_owned(),
+            state: state,
+            vdevs: vdevs,
+        }
+    }
+}
+
+/// How to import a pool
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Import the pool even if another host might have it imported (`zpool import -f`): the
+    /// multihost activity check is skipped. Two hosts writing to a pool wreck it.
+    pub force: bool,
+    /// The id of this host, to tell whether the pool was last imported here. `mmp::hostid()`
+    /// if not given.
+    pub hostid: Option<u64>,
+    /// Open the devices read-only (`zpool import -o readonly=on`): nothing is written to them,
+    /// not even the probes of failing devices
+    pub read_only: bool,
+    /// Map the devices into memory (see `MmapVdev`) rather than reading them with syscalls,
+    /// which pays off for image files and reads of lots of small metadata blocks
+    pub mmap: bool,
+    /// Import the pool as it was at an earlier txg, to get at what's been damaged or destroyed
+    /// since. The pool is read-only then, so the newer uberblocks stay where they are and the
+    /// next import without it is back to the newest.
+    pub rewind: Option<Rewind>,
+}
+
+/// The uberblock to import a pool from, rather than the newest one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rewind {
+    /// The newest uberblock of txg `txg` or before (`zpool import -T`). Blocks freed after it
+    /// may have been written over, the older the txg the more likely.
+    Txg(u64),
+    /// The uberblock saved by `zpool checkpoint` (`zpool import --rewind-to-checkpoint`). What
+    /// it points at is all still there, the checkpoint keeps it from being freed.
+    Checkpoint,
+}
+
+/// Import the pool living on the devices (or image files) at `paths`, every device of every
+/// top-level vdev (see `open_devices`).
+///
+/// The label nvlists only describe the top-level vdev their device belongs to, so the root of
+/// the vdev tree is reconstructed from the pool guid. The uberblock's guid sum has to match the
+/// sum of every guid in the resulting tree, otherwise devices are missing or belong to another
+/// pool.
+#[cfg(feature = "std")]
+pub fn import_devices(paths: &[&str]) -> zfs::Result<Spa> {
+    open(paths).0
+}
+
+/// Like `import_devices`, along with the report of what the import went through, which comes
+/// back even if it failed
+#[cfg(feature = "std")]
+pub fn open(paths: &[&str]) -> (zfs::Result<Spa>, OpenReport) {
+    open_with(paths, &ImportOptions::default())
+}
+
+/// Like `open`, with `options`
+#[cfg(feature = "std")]
+pub fn open_with(paths: &[&str], options: &ImportOptions) -> (zfs::Result<Spa>, OpenReport) {
+    report_open(|report| open_paths(paths, options, report))
+}
+
+/// Like `open_with`, for the pool on `devices` rather than on the devices at paths. The devices
+/// are opened already, so `ImportOptions::mmap` has no say, and their writes are only found to
+/// fail once they're written to.
+pub fn open_vdevs(devices: Vec<Box<Vdev>>,
+                  options: &ImportOptions)
+                  -> (zfs::Result<Spa>, OpenReport) {
+    report_open(|report| open_impl(devices, options.read_only, options, report))
+}
+
+fn report_open<F>(open: F) -> (zfs::Result<Spa>, OpenReport)
+    where F: FnOnce(&mut OpenReport) -> zfs::Result<Spa>
+{
+    let mut report = OpenReport::default();
+    let result = open(&mut report);
+    if let Err(error) = result {
+        if let Some((phase, _)) = report.running {
+            report.failure = Some((phase, error));
+        }
+    }
+    report.end_phase();
+    (result, report)
+}
+
+#[cfg(feature = "std")]
+fn open_paths(paths: &[&str],
+              options: &ImportOptions,
+              report: &mut OpenReport)
+              -> zfs::Result<Spa> {
+    report.phase(OpenPhase::Devices);
+    // Fall back to read-only, the pool just can't be changed then
+    let mut device_read_only = options.read_only;
+    let mut devices: Vec<Box<Vdev>> = Vec::new();
+    for path in paths {
+        let mut writable = !options.read_only;
+        let disk = try!(OpenOptions::new()
+                            .read(true)
+                            .write(writable)
+                            .open(path)
+                            .or_else(|_| {
+                                writable = false;
+                                OpenOptions::new().read(true).open(path)
+                            })
+                            .map_err(|_| zfs::Error::NoEntity));
+        if !writable && !options.read_only {
+            device_read_only = true;
+            report.tolerated.push(format!("{}: can't be written, opened read-only", path));
+        }
+        report.devices.push((path.to_string(), writable));
+        if options.mmap {
+            devices.push(Box::new(try!(MmapVdev::new(disk, writable))));
+        } else {
+            devices.push(Box::new(disk));
+        }
+    }
+    open_impl(devices, device_read_only, options, report)
+}
+
+/// Import the pool on `devices`, read-only if `device_read_only`
+fn open_impl(devices: Vec<Box<Vdev>>,
+             device_read_only: bool,
+             options: &ImportOptions,
+             report: &mut OpenReport)
+             -> zfs::Result<Spa> {
+    report.phase(OpenPhase::Labels);
+    let (mut reader, config, missing) = try!(open_available(devices));
+    let config = try!(config.ok_or(zfs::Error::InvalidLabel));
+    reader.detect_sector_shift();
+    let pool_config = try!(PoolConfig::from_nv_list(&config));
+    report.pool_name = Some(pool_config.name.clone());
+    report.pool_guid = Some(pool_config.pool_guid);
+    // A version we don't know of is a format we can't read
+    try!(PoolVersion::from_u64(pool_config.version).ok_or(zfs::Error::UnsupportedFeature));
+    // The features the MOS needs are listed in the label, so a pool we can't make sense of is
+    // refused before reading it
+    let label_features = zfeature::check_label(&pool_config.features_for_read);
+    if !label_features.can_read() {
+        report.features = Some(label_features);
+        return Err(zfs::Error::UnsupportedFeature);
+    }
+    if let Some(vdev_tree) = config.get::<&NvList>("vdev_tree") {
+        report.vdev_tree = VdevConfig::from_nv_list(vdev_tree).ok();
+    }
+
+    report.phase(OpenPhase::Uberblock);
+    let max_txg = match options.rewind {
+        Some(Rewind::Txg(txg)) => txg,
+        _ => u64::MAX,
+    };
+    let search = try!(reader.uber_search_max(max_txg));
+    let mut uberblock = search.uberblock;
+    report.uberblock_label = Some(search.label);
+    report.uberblock_txg = Some(uberblock.txg);
+    report.uberblock_timestamp = Some(uberblock.timestamp);
+    report.version = Some(uberblock.pool_version());
+    for label in search.damaged_labels {
+        report.tolerated.push(format!("label {} has no valid uberblock", label));
+    }
+    reader.raidz_reflow(uberblock.raidz_reflow_offset());
+
+    // A pool another host is writing to would be wrecked by our writes, and read wrong too.
+    // Only the newest uberblock tells, whichever is imported.
+    let newest = if max_txg == u64::MAX { uberblock } else { try!(reader.uber()) };
+    let hostid = options.hostid.unwrap_or_else(mmp::hostid);
+    if mmp::activity_check_required(&newest, &pool_config, hostid) {
+        report.phase(OpenPhase::Activity);
+        if options.force {
+            report.tolerated.push("multihost activity check skipped".to_owned());
+        } else {
+            let duration = mmp::activity_check_duration(&newest);
+            let poll = Duration::from_millis(newest.mmp_interval()
+                                                   .unwrap_or(mmp::MMP_DEFAULT_INTERVAL));
+            try!(mmp::activity_check(&newest, duration, poll, &mut || {
+                reader.uber_search().map(|search| search.uberblock)
+            }));
+        }
+    }
+
+    report.phase(OpenPhase::Config);
+    let mut spa = try!(Spa::import(pool_config.name, config));
+
+    // The labels don't have the vdevs that are missing, that's checked against the MOS config
+    let guid_sum = uberblock.guid_sum;
+    if missing.is_empty() && spa.root_vdev.get(&spa.vdev_tree).guid_sum() != guid_sum {
+        return Err(zfs::Error::Invalid);
+    }
+
+    report.phase(OpenPhase::Mos);
+    let mut reader = ZfsReader {
+        zio: reader,
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut mos = try!(ObjectSet::open(&mut reader, &uberblock.rootbp).map_err(|_| {
+        // It may well be on a device we weren't given
+        if missing.is_empty() { zfs::Error::Invalid } else { zfs::Error::NoEntity }
+    }));
+    // The checkpoint is kept in the MOS of the newest uberblock
+    if options.rewind == Some(Rewind::Checkpoint) {
+        if uberblock.checkpoint_txg == 0 {
+            return Err(zfs::Error::NoEntity);
+        }
+        uberblock = try!(checkpoint_uberblock(&mut reader, &mos));
+        report.uberblock_txg = Some(uberblock.txg);
+        report.uberblock_timestamp = Some(uberblock.timestamp);
+        mos = try!(ObjectSet::open(&mut reader, &uberblock.rootbp)
+                       .map_err(|_| zfs::Error::Invalid));
+    }
+    if !missing.is_empty() {
+        // Only the vdev tree: the MOS config has no vdev guids of its own, unlike the labels'
+        let mos_config = try!(mos_config(&mut reader, &mos));
+        let vdev_tree = try!(mos_config.get("vdev_tree").ok_or(zfs::Error::Invalid));
+        let vdev_tree = try!(VdevConfig::from_nv_list(vdev_tree));
+        if vdev_tree.guid_sum() != guid_sum {
+            return Err(zfs::Error::Invalid);
+        }
+        for id in missing {
+            try!(open_indirect(&mut reader, &mos, &vdev_tree, id));
+            report.tolerated.push(format!("top-level vdev {} was removed", id));
+        }
+    }
+
+    // Features we can't read refuse the import, features we can't write make it read-only
+    report.phase(OpenPhase::Features);
+    let feature_report = try!(zfeature::check_features(&mut reader, &mos)
+                                  .map_err(|_| zfs::Error::Invalid));
+    report.features = Some(feature_report.clone());
+    if !feature_report.can_read() {
+        return Err(zfs::Error::UnsupportedFeature);
+    }
+    if !feature_report.can_write() {
+        report.tolerated.push("features we can't write are active, opened read-only".to_owned());
+    }
+    if options.rewind.is_some() {
+        report.tolerated.push(format!("rewound to txg {}, opened read-only", { uberblock.txg }));
+    }
+    spa.read_only = device_read_only || !feature_report.can_write() || options.rewind.is_some();
+    report.read_only = spa.read_only;
+    spa.feature_report = feature_report;
+    reader.zio.probe_writes = !spa.read_only;
+
+    // Metaslabs are only there to allocate from, a read-only pool doesn't set them up at all.
+    // Otherwise their space maps are read one by one, on the first allocation from each.
+    report.phase(OpenPhase::Metaslabs);
+    if !spa.read_only {
+        spa.vdev_tree.load(&mut reader, &mos, spa.root_vdev);
+    }
+
+    // Pools that never had the property set wait
+    reader.zio.failmode = pool_prop(&mut reader, &mos, "failmode")
+                              .and_then(zio::FailMode::from_u64)
+                              .unwrap_or(zio::FailMode::Wait);
+
+    report.phase(OpenPhase::DslPool);
+    spa.mos = Some(mos);
+    spa.uberblock = Some(uberblock);
+    spa.reader = Some(reader);
+    spa.dsl_pool = try!(DslPool::init(&mut spa, uberblock.txg));
+
+    Ok(spa)
+}
+
+/// The uberblock `zpool checkpoint` saved in the MOS, of a pool whose uberblock has a
+/// `checkpoint_txg`
+pub fn checkpoint_uberblock(reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<Uberblock> {
+    let value = try!(zap::lookup_value(reader,
+                                       mos,
+                                       DMU_POOL_DIRECTORY_OBJECT,
+                                       DMU_POOL_ZPOOL_CHECKPOINT)
+                         .map_err(|_| zfs::Error::Invalid));
+    Uberblock::from_words(try!(value.as_ints().ok_or(zfs::Error::Invalid)))
+}
+
+/// The pool config kept in the MOS. Unlike the labels' it has every top-level vdev, those that
+/// were removed from the pool too.
+pub fn mos_config(reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<NvList> {
+    let (object, size) = try!(mos_config_object(reader, mos));
+    let dnode = try!(mos.dnode(reader, object).map_err(|_| zfs::Error::Invalid));
+    let mut packed = try!(dmu::read(reader, &dnode, 0, size).map_err(|_| zfs::Error::Invalid));
+    spa_config::decode(&mut packed)
+}
+
+/// The object of the MOS config, and the size of the packed config in it
+fn mos_config_object(reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<(u64, usize)> {
+    let object = try!(mos.directory_lookup(reader, "config").map_err(|_| zfs::Error::Invalid));
+    let dnode = try!(mos.dnode(reader, object).map_err(|_| zfs::Error::Invalid));
+    // The size of the packed config is in the bonus buffer
+    let bonus = dnode.get_bonus();
+    if bonus.len() < 8 {
+        return Err(zfs::Error::Invalid);
+    }
+    let mut size = [0; 8];
+    size.copy_from_slice(&bonus[..8]);
+    Ok((object, u64::from_le_bytes(size) as usize))
+}
+
+/// Give metaslabs `from..to` of a top-level vdev space maps, and add them to its metaslab array
+/// `ms_array`, in a transaction of the open txg (`vdev_metaslab_init` with a txg)
+fn grow_metaslab_array(reader: &mut ZfsReader,
+                       dsl_pool: &mut DslPool,
+                       mos: &ObjectSet,
+                       ms_array: u64,
+                       from: u64,
+                       to: u64)
+                       -> zfs::Result<()> {
+    let offset = from * mem::size_of::<u64>() as u64;
+    let mut tx = Tx::create(dsl_pool::MOS_OBJSET);
+    tx.hold_write(ms_array, offset, (to - from) * mem::size_of::<u64>() as u64);
+    try!(tx.assign(dsl_pool));
+    let mut result = Ok(());
+    let mut objects = Vec::new();
+    for _ in from..to {
+        match space_map::alloc(reader, dsl_pool, mos, &tx) {
+            Ok(object) => objects.extend_from_slice(&object.to_le_bytes()),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    if result.is_ok() {
+        result = dmu::write(reader, dsl_pool, mos, ms_array, offset, &objects, &tx);
+    }
+    tx.commit(dsl_pool);
+    result
+}
+
+/// Change the MOS config with `update`, in a transaction of the open txg. The packed config is
+/// written over in place, so `update` can only change values of a fixed size, like guids.
+fn rewrite_mos_config(reader: &mut ZfsReader,
+                      dsl_pool: &mut DslPool,
+                      mos: &ObjectSet,
+                      update: &Fn(&mut NvList))
+                      -> zfs::Result<()> {
+    let (object, size) = try!(mos_config_object(reader, mos));
+    let mut config = try!(mos_config(reader, mos));
+    update(&mut config);
+    let mut packed = vec![0; nvstream::encoded_size(&config)];
+    if packed.len() != size {
+        return Err(zfs::Error::Invalid);
+    }
+    {
+        let mut xdr = xdr::MemOps::new(&mut packed);
+        try!(nvstream::encode_nv_list(&mut xdr, &config).map_err(|_| zfs::Error::Invalid));
+    }
+
+    let mut tx = Tx::create(dsl_pool::MOS_OBJSET);
+    tx.hold_write(object, 0, size as u64);
+    try!(tx.assign(dsl_pool));
+    let result = dmu::write(reader, dsl_pool, mos, object, 0, &packed, &tx);
+    tx.commit(dsl_pool);
+    result
+}
+
+/// Set up reads of top-level vdev `id`, which no device was given for. That's fine if it was
+/// removed from the pool: its blocks are read from where they were copied to, by the mapping
+/// `vdev_tree` (from the MOS config) names. A hole, the place of a log device that was removed,
+/// has nothing to read at all. Otherwise the vdev is missing, `NoEntity`.
+fn open_indirect(reader: &mut ZfsReader,
+                 mos: &ObjectSet,
+                 vdev_tree: &VdevConfig,
+                 id: u64)
+                 -> zfs::Result<()> {
+    let top = try!(vdev_tree.children.iter().find(|top| top.id == id).ok_or(zfs::Error::NoEntity));
+    match (&top.vdev_type[..], top.indirect_object) {
+        ("indirect", Some(object)) => {
+            let mapping = try!(IndirectMapping::open(reader, mos, object));
+            reader.zio.indirect.insert(id, Arc::new(mapping));
+            Ok(())
+        }
+        ("hole", _) => Ok(()),
+        _ => Err(zfs::Error::NoEntity),
+    }
+}
+
+/// The value of pool property `name`, if it has been set. Pool properties live in a ZAP object
+/// named by the MOS object directory.
+fn pool_prop(reader: &mut ZfsReader, mos: &ObjectSet, name: &str) -> Option<u64> {
+    zap::lookup(reader, mos, DMU_POOL_DIRECTORY_OBJECT, "pool_props")
+        .and_then(|props| zap::lookup(reader, mos, props, name))
+        .ok()
+}
+
+/// A new random, nonzero guid
+pub fn generate_guid() -> u64 {
+    // Every RandomState is seeded differently, from the OS's random source
+    let mut hasher = RandomState::new().build_hasher();
+    SystemTime::now().hash(&mut hasher);
+    match hasher.finish() {
+        0 => generate_guid(),
+        guid => guid,
+    }
+}
+
+/// Build the nvlist of a root vdev, whose guid is the pool guid
+fn root_vdev_config(pool_guid: u64, tops: Vec<NvList>) -> NvList {
+    let mut root = NvList::new(0);
+    root.add("type".to_owned(), NvValue::String("root".to_owned()));
+    root.add("id".to_owned(), NvValue::Uint64(0));
+    root.add("guid".to_owned(), NvValue::Uint64(pool_guid));
+    root.add("children".to_owned(), NvValue::NvListArray(tops));
+    root
+}
+
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 
 struct ZioTaskqInfo {
@@ -373,3 +1768,459 @@ impl SpaNamespace {
         self.avl.find_mut(name)
     }
 }
+
+#[test]
+fn test_open_striped_devices() {
+    use super::dvaddr::DVAddr;
+    use super::from_bytes::FromBytes;
+    use super::vdev_io::MemVdev;
+
+    // Two single-device top-level vdevs
+    let devices = vec![MemVdev::new(vec![0; 5 << 20]), MemVdev::new(vec![0; 5 << 20])];
+    for (id, device) in devices.iter().enumerate() {
+        let id = id as u64;
+        let mut top = NvList::new(0);
+        top.add("type".to_owned(), NvValue::String("file".to_owned()));
+        top.add("id".to_owned(), NvValue::Uint64(id));
+        top.add("guid".to_owned(), NvValue::Uint64(0x100 + id));
+        let mut label = NvList::new(0);
+        label.add("version".to_owned(), NvValue::Uint64(5000));
+        label.add("name".to_owned(), NvValue::String("tank".to_owned()));
+        label.add("state".to_owned(), NvValue::Uint64(0));
+        label.add("txg".to_owned(), NvValue::Uint64(4));
+        label.add("pool_guid".to_owned(), NvValue::Uint64(0x42));
+        label.add("top_guid".to_owned(), NvValue::Uint64(0x100 + id));
+        label.add("guid".to_owned(), NvValue::Uint64(0x100 + id));
+        label.add("vdev_children".to_owned(), NvValue::Uint64(2));
+        label.add("vdev_tree".to_owned(), NvValue::NvList(top));
+        let mut reader = zio::Reader::new(device.clone());
+        spa_config::write_label_nv_list(&mut reader, 0, &label).unwrap();
+    }
+    let boxed = |devices: &[MemVdev]| {
+        devices.iter().map(|device| Box::new(device.clone()) as Box<Vdev>).collect()
+    };
+
+    // Given in any order, the devices end up under their IDs
+    let (mut reader, config) = open_devices(boxed(&[devices[1].clone(), devices[0].clone()]))
+                                   .unwrap();
+    let config = PoolConfig::from_nv_list(&config.unwrap()).unwrap();
+    let ids: Vec<_> = config.vdev_tree.children.iter().map(|top| top.id).collect();
+    assert_eq!((&config.vdev_tree.vdev_type[..], ids), ("root", vec![0, 1]));
+
+    // DVAs go to the top-level vdev they name
+    reader.write_dva(&DVAddr { vdev: 1 << 32 | 1, offset: 3 }, &[7; 512]).unwrap();
+    reader.write_dva(&DVAddr { vdev: 1, offset: 3 }, &[9; 512]).unwrap();
+    let offset = (0x2000 + 3) * 512;
+    assert_eq!(devices[1].to_vec()[offset], 7);
+    assert_eq!(devices[0].to_vec()[offset], 9);
+    assert_eq!(reader.read_dva(&DVAddr { vdev: 1 << 32 | 1, offset: 3 }, 512).unwrap(),
+               vec![7; 512]);
+    assert!(reader.read_dva(&DVAddr { vdev: 2 << 32 | 1, offset: 3 }, 512).is_err());
+
+    // Uberblocks go to the labels of every top-level vdev
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0c, 0xb1, 0xba, 0, 0, 0, 0, 0]);
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // version 5000
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    uberblock.txg = 9;
+    sync_uberblock(&mut reader, &uberblock).unwrap();
+    for device in &devices {
+        let search = zio::Reader::new(device.clone()).uber_search().unwrap();
+        let txg = search.uberblock.txg;
+        assert_eq!((txg, search.damaged_labels.len()), (9, 0));
+    }
+
+    // Without all of its top-level vdevs, the pool can't be opened
+    assert_eq!(open_devices(boxed(&devices[..1])).err(), Some(zfs::Error::NoEntity));
+    // Unless they were removed, which only the MOS can tell
+    let (reader, _, missing) = open_available(boxed(&devices[..1])).unwrap();
+    assert_eq!((missing, reader.tops.keys().cloned().collect::<Vec<_>>()), (vec![1], vec![0]));
+}
+
+#[test]
+fn test_open_report() {
+    use std::fs;
+    use super::from_bytes::FromBytes;
+    use super::vdev_io::MemVdev;
+
+    // A label and an uberblock, but no MOS: the uberblock's guid sum doesn't add up
+    let device = MemVdev::new(vec![0; 5 << 20]);
+    let mut top = NvList::new(0);
+    top.add("type".to_owned(), NvValue::String("file".to_owned()));
+    top.add("id".to_owned(), NvValue::Uint64(0));
+    top.add("guid".to_owned(), NvValue::Uint64(0x100));
+    let mut label = NvList::new(0);
+    label.add("version".to_owned(), NvValue::Uint64(5000));
+    label.add("name".to_owned(), NvValue::String("tank".to_owned()));
+    label.add("state".to_owned(), NvValue::Uint64(0));
+    label.add("txg".to_owned(), NvValue::Uint64(4));
+    label.add("pool_guid".to_owned(), NvValue::Uint64(0x42));
+    label.add("top_guid".to_owned(), NvValue::Uint64(0x100));
+    label.add("guid".to_owned(), NvValue::Uint64(0x100));
+    label.add("vdev_children".to_owned(), NvValue::Uint64(1));
+    label.add("vdev_tree".to_owned(), NvValue::NvList(top));
+    let mut reader = zio::Reader::new(device.clone());
+    spa_config::write_label_nv_list(&mut reader, 0, &label).unwrap();
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0c, 0xb1, 0xba, 0, 0, 0, 0, 0]);
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // version 5000
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    uberblock.txg = 9;
+    sync_uberblock(&mut reader, &uberblock).unwrap();
+
+    let path = ::std::env::temp_dir().join("zfs_test_open_report");
+    fs::write(&path, device.to_vec()).unwrap();
+    let (result, report) = open(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(result.is_err());
+    assert_eq!((report.pool_name.as_ref().map(|name| &name[..]), report.pool_guid),
+               (Some("tank"), Some(0x42)));
+    assert_eq!((report.uberblock_txg, report.devices.len()), (Some(9), 1));
+    let vdev_tree = report.vdev_tree.unwrap();
+    assert_eq!((vdev_tree.guid, vdev_tree.children[0].guid), (0x42, 0x100));
+    assert_eq!(report.failure, Some((OpenPhase::Config, zfs::Error::Invalid)));
+    let phases: Vec<_> = report.phases.iter().map(|&(phase, _)| phase).collect();
+    assert_eq!(phases,
+               vec![OpenPhase::Devices,
+                    OpenPhase::Labels,
+                    OpenPhase::Uberblock,
+                    OpenPhase::Config]);
+}
+
+#[test]
+fn test_create() {
+    use std::fs;
+    use super::vdev_io::MemVdev;
+    use super::zpl;
+
+    let path = ::std::env::temp_dir().join("zfs_test_create");
+    let path = path.to_str().unwrap();
+    fs::File::create(path).unwrap().set_len(SPA_MINDEVSIZE).unwrap();
+    let mut options = CreateOptions::new("tank");
+    options.ashift = 12;
+    let created = create(path, &options);
+    let reimported = import(path);
+    fs::remove_file(path).unwrap();
+
+    // The new pool imports as it was created, with an empty filesystem as its root dataset
+    let mut spa = created.unwrap();
+    assert_eq!((spa.name(), spa.is_read_only()), ("tank", false));
+    assert!(spa.features().unwrap().is_empty());
+    let vdev_tree = PoolConfig::from_nv_list(spa.config()).unwrap().vdev_tree;
+    let top = &vdev_tree.children[0];
+    assert_eq!((top.ashift, top.metaslab_shift), (Some(12), Some(24)));
+    let guid = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let datasets = dsl_pool.datasets(reader, mos, "tank").unwrap();
+        assert_eq!(datasets.len(), 1);
+        let dataset = dsl_pool.open_dataset(reader, mos, "tank", false).unwrap();
+        assert!(zpl::readdir(reader, &dataset, "/").unwrap().is_empty());
+        let stat = zpl::stat(reader, &dataset, "/").unwrap();
+        assert_eq!((stat.mode, stat.links), (0o40755, 2));
+        dataset.phys.guid
+    };
+    let mut spa = reimported.unwrap();
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    let dsl_pool = DslPool::open(reader, mos).unwrap();
+    let dataset = dsl_pool.open_dataset(reader, mos, "tank", false).unwrap();
+    let reimported_guid = dataset.phys.guid;
+    assert_eq!(reimported_guid, guid);
+
+    // Too small a device, or a name no pool can have
+    let small = zio::Reader::new(MemVdev::new(vec![0; 32 << 20]));
+    assert_eq!(format(small, "small", &CreateOptions::new("tank")).err(),
+               Some(zfs::Error::NoSpace));
+    let device = zio::Reader::new(MemVdev::new(vec![0; 64 << 20]));
+    assert_eq!(format(device, "device", &CreateOptions::new("mirror1")).err(),
+               Some(zfs::Error::Invalid));
+}
+
+#[test]
+fn test_rewind() {
+    use std::mem;
+    use super::test_util;
+    use super::zpl;
+
+    let (mut spa, mut alloc, device) = test_util::pool("zfs_test_rewind");
+    let path = device.path();
+    let open_dataset = |spa: &mut Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+    let read = |spa: &mut Spa| {
+        let dataset = open_dataset(spa);
+        let reader = spa.reader_mut().unwrap();
+        zpl::open(reader, &dataset, "/notes").unwrap().read_all(reader).unwrap()
+    };
+
+    // The file is written in one txg, and written over in the next
+    let mut synced = Vec::new();
+    for data in &[b"before", b"after!"] {
+        let dataset = open_dataset(&mut spa);
+        let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+        {
+            let (reader, mos) = spa.reader_and_mos().unwrap();
+            let file = match zpl::lookup(reader, &dataset, "/notes", true) {
+                Ok(file) => file,
+                Err(_) => {
+                    zpl::create(reader, &mut dsl_pool, mos, &dataset, "/notes", 0o644).unwrap()
+                }
+            };
+            zpl::write(reader, &mut dsl_pool, &dataset, file, 0, &data[..]).unwrap();
+        }
+        mem::replace(spa.dsl_pool_mut(), dsl_pool);
+        synced.push(spa.sync(&mut alloc).unwrap().unwrap());
+    }
+
+    let rewound = |rewind| {
+        let options = ImportOptions { rewind: Some(rewind), ..ImportOptions::default() };
+        open_with(&[path], &options)
+    };
+    let (result, report) = rewound(Rewind::Txg(synced[0]));
+    let (newest, _) = open(&[path]);
+    let (checkpoint, _) = rewound(Rewind::Checkpoint);
+    let (too_old, _) = rewound(Rewind::Txg(0));
+
+    // The rewound pool is read-only, as it was as of the txg
+    let mut spa = result.unwrap();
+    assert_eq!((report.uberblock_txg, spa.is_read_only()), (Some(synced[0]), true));
+    assert!(report.tolerated.iter().any(|problem| problem.starts_with("rewound to txg")));
+    assert_eq!(read(&mut spa), b"before");
+    assert_eq!(spa.sync(&mut alloc).err(), Some(zfs::Error::ReadOnly));
+    // While the newer uberblocks are still there
+    let mut spa = newest.unwrap();
+    assert_eq!(read(&mut spa), b"after!");
+
+    // There's no checkpoint, and no uberblock from before the pool was created
+    assert_eq!(checkpoint.err(), Some(zfs::Error::NoEntity));
+    assert_eq!(too_old.err(), Some(zfs::Error::NoUberblock));
+}
+
+#[test]
+fn test_reguid() {
+    use super::test_util;
+
+    let (mut spa, mut alloc, device) = test_util::pool("zfs_test_reguid");
+    let path = device.path();
+    let old_guid = spa.config().get::<u64>("pool_guid").unwrap();
+    let txg = spa.uberblock().unwrap().txg;
+
+    let guid = spa.reguid(&mut alloc).unwrap();
+    let (result, _) = open(&[path]);
+
+    // The labels, the uberblock and the MOS config all changed in the same txg
+    assert!(guid != old_guid);
+    let mut spa = result.unwrap();
+    let synced = spa.uberblock().unwrap().txg;
+    assert_eq!(synced, txg + 1);
+    assert_eq!(spa.config().get::<u64>("pool_guid"), Some(guid));
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    assert_eq!(mos_config(reader, mos).unwrap().get::<u64>("pool_guid"), Some(guid));
+}
+
+#[test]
+fn test_upgrade() {
+    use super::test_util;
+
+    let (mut spa, mut alloc, device) = test_util::pool("zfs_test_upgrade");
+    let path = device.path();
+
+    assert_eq!(spa.upgrade(&mut alloc, SPA_VERSION_FEATURES, &["org.example:unknown"]).err(),
+               Some(zfs::Error::UnsupportedFeature));
+    let features = ["com.delphix:hole_birth", "com.delphix:enabled_txg"];
+    spa.upgrade(&mut alloc, SPA_VERSION_FEATURES, &features).unwrap();
+    let txg = spa.uberblock().unwrap().txg;
+    let (result, _) = open(&[path]);
+
+    // Both are enabled, in the ZAP they belong in, as of the txg of the upgrade
+    let mut spa = result.unwrap();
+    let enabled: Vec<_> = spa.features()
+                             .unwrap()
+                             .into_iter()
+                             .map(|feature| {
+                                 (feature.guid,
+                                  feature.refcount,
+                                  feature.read_only_compatible,
+                                  feature.enabled_txg)
+                             })
+                             .collect();
+    assert_eq!(enabled,
+               vec![("com.delphix:enabled_txg".to_owned(), 0, true, Some(txg)),
+                    ("com.delphix:hole_birth".to_owned(), 0, false, Some(txg))]);
+}
+
+#[test]
+fn test_open_removed_vdev() {
+    use super::dvaddr::DVAddr;
+    use super::test_util;
+
+    let (mut spa, mut alloc, device) = test_util::pool("zfs_test_open_removed_vdev");
+    let path = device.path();
+    let feature = "com.delphix:device_removal";
+    spa.upgrade(&mut alloc, SPA_VERSION_FEATURES, &[feature]).unwrap();
+
+    // Top-level vdev 1 was removed, the one sector allocated on it was copied to vdev 0
+    let removed_guid = 0x1234;
+    let copy = DVAddr { vdev: 1, offset: 0x10000 };
+    {
+        let reader = spa.reader.as_mut().unwrap();
+        let mos = spa.mos.as_mut().unwrap();
+        let dsl_pool = &mut spa.dsl_pool;
+        reader.zio.write_dva(&copy, &[7; 512]).unwrap();
+
+        let features = mos.directory_lookup(reader, "features_for_read").unwrap();
+        let (config_object, _) = mos_config_object(reader, mos).unwrap();
+        let mut tx = Tx::create(dsl_pool::MOS_OBJSET);
+        tx.hold_zap(features, true, Some(feature));
+        tx.hold_write(config_object, 0, SPA_CONFIG_BLOCKSIZE as u64);
+        let txg = tx.assign(dsl_pool).unwrap();
+        zap::update(reader, dsl_pool, mos, features, feature, 8, &[1], &tx).unwrap();
+
+        // The mapping: max offset, bytes mapped, one entry, no counts object in the bonus
+        // buffer, and the entry, sector 0 to the copy
+        let mut bonus = Vec::new();
+        for &word in &[512u64, 512, 1, 0] {
+            bonus.extend_from_slice(&word.to_le_bytes());
+        }
+        let mut dnode = DNodePhys::new(ObjectType::None, 512, ObjectType::None, &bonus);
+        dnode.object_type = 0x80 | 0x40 | 3; // DMU_OTN_UINT64_METADATA
+        let mapping = dmu::create_object(reader, dsl_pool, mos, dnode, &tx).unwrap();
+        let mut entry = vec![0; 512];
+        entry[8..16].copy_from_slice(&copy.vdev.to_le_bytes());
+        entry[16..24].copy_from_slice(&copy.offset.to_le_bytes());
+        dsl_pool.dirty_block(txg, dsl_pool::MOS_OBJSET, mapping, 0, entry);
+
+        // The MOS config has the removed vdev, as an indirect one
+        let mut config = mos_config(reader, mos).unwrap();
+        let mut indirect = NvList::new(0);
+        indirect.add("type".to_owned(), NvValue::String("indirect".to_owned()));
+        indirect.add("id".to_owned(), NvValue::Uint64(1));
+        indirect.add("guid".to_owned(), NvValue::Uint64(removed_guid));
+        indirect.add("com.delphix:indirect_object".to_owned(), NvValue::Uint64(mapping));
+        if let Some(&mut NvValue::NvList(ref mut vdev_tree)) = config.find_mut("vdev_tree") {
+            if let Some(&mut NvValue::NvListArray(ref mut children)) =
+                   vdev_tree.find_mut("children") {
+                children.push(indirect);
+            }
+        }
+        *config.find_mut("vdev_children").unwrap() = NvValue::Uint64(2);
+        let mut packed = vec![0; nvstream::encoded_size(&config)];
+        nvstream::encode_nv_list(&mut xdr::MemOps::new(&mut packed), &config).unwrap();
+        let mut dnode = mos.dnode(reader, config_object).unwrap();
+        dnode.bonus_mut()[..8].copy_from_slice(&(packed.len() as u64).to_le_bytes());
+        dsl_pool.dirty_dnode(txg, dsl_pool::MOS_OBJSET, config_object, dnode);
+        packed.resize(SPA_CONFIG_BLOCKSIZE, 0);
+        dsl_pool.dirty_block(txg, dsl_pool::MOS_OBJSET, config_object, 0, packed);
+        tx.commit(dsl_pool);
+
+        // Its guid counts towards the uberblock's guid sum, the labels know of two vdevs
+        let mut uberblock = spa.uberblock.unwrap();
+        uberblock.guid_sum = uberblock.guid_sum.wrapping_add(removed_guid);
+        let synced = dsl_pool.sync(reader, mos, &uberblock, &mut alloc).unwrap().unwrap();
+        sync_labels(&mut reader.zio, &synced, &|nv| {
+            *nv.find_mut("vdev_children").unwrap() = NvValue::Uint64(2);
+        })
+            .unwrap();
+        dsl_pool.sync_done();
+    }
+    let (result, report) = open(&[path]);
+
+    // Imported read-only, with the removed vdev's blocks read from where they were copied to
+    let mut spa = result.unwrap();
+    assert!(spa.is_read_only());
+    assert!(report.tolerated.contains(&"top-level vdev 1 was removed".to_owned()));
+    assert_eq!(report.features.unwrap().unsupported_for_write, vec![feature.to_owned()]);
+    let reader = spa.reader_mut().unwrap();
+    assert_eq!(reader.zio.read_dva(&DVAddr { vdev: 1 << 32 | 1, offset: 0 }, 512).unwrap(),
+               vec![7; 512]);
+}
+
+#[test]
+fn test_expand() {
+    use std::fs;
+
+    let path = ::std::env::temp_dir().join("zfs_test_expand");
+    let path = path.to_str().unwrap();
+    fs::File::create(path).unwrap().set_len(SPA_MINDEVSIZE).unwrap();
+    create(path, &CreateOptions::new("tank")).unwrap();
+    let old_asize = spa_config::device_asize(SPA_MINDEVSIZE);
+    fs::OpenOptions::new().write(true).open(path).unwrap().set_len(4 * SPA_MINDEVSIZE).unwrap();
+    let mut spa = import(path).unwrap();
+    let mut alloc = VdevAllocator::new(0, 9, SPA_MINDEVSIZE - (4 << 20), None);
+    alloc.alloc(16 << 20).unwrap();
+    let asize = spa.expand(&mut alloc).unwrap().unwrap();
+    assert_eq!(spa.expand(&mut alloc).unwrap(), None);
+    let (result, _) = open(&[path]);
+    fs::remove_file(path).unwrap();
+
+    // Every metaslab of the new space has a space map, with nothing allocated from it yet
+    let mut spa = result.unwrap();
+    let (reader, mos) = spa.reader_and_mos().unwrap();
+    let config = mos_config(reader, mos).unwrap();
+    let vdev_tree = VdevConfig::from_nv_list(config.get("vdev_tree").unwrap()).unwrap();
+    let space = metaslab::pool_space(reader, mos, &vdev_tree).unwrap();
+    let ms_shift = metaslab_shift(old_asize);
+    let old_count = (old_asize >> ms_shift) as usize;
+    assert_eq!(space[0].metaslabs.len() as u64, asize >> ms_shift);
+    assert!(space[0].metaslabs[..old_count].iter().all(|metaslab| metaslab.object == 0));
+    assert!(space[0].metaslabs[old_count..]
+                .iter()
+                .all(|metaslab| metaslab.object != 0 && metaslab.alloc == 0));
+}
+
+#[test]
+fn test_pool_status() {
+    use super::dvaddr::DVAddr;
+    use super::vdev_inject::{Fault, InjectVdev};
+    use super::vdev_io::MemVdev;
+    use super::vdev_mirror::VDEV_ERROR_LIMIT;
+
+    // The mirror's second child is slower, so reads go to the first one first
+    let flaky = InjectVdev::new(MemVdev::new(vec![0; 1 << 20]));
+    let slow = InjectVdev::new(MemVdev::new(vec![0; 1 << 20]));
+    slow.inject(Fault::Latency(::std::time::Duration::from_millis(1)));
+    let mirror = MirrorVdev::new(vec![Box::new(flaky.clone()), Box::new(slow)]);
+    let raidz = RaidzVdev::new(vec![Box::new(MemVdev::new(vec![0; 8 << 20])),
+                                    Box::new(MissingVdev),
+                                    Box::new(MemVdev::new(vec![0; 8 << 20]))],
+                               1,
+                               9);
+    let mut zio = zio::Reader::new(MemVdev::new(vec![0; 1 << 20]));
+    zio.tops.insert(0, Box::new(mirror));
+    zio.tops.insert(1, Box::new(raidz));
+
+    // The raidz is missing a child, which its parity makes up for
+    let status = PoolStatus::new("tank", &zio);
+    assert_eq!(status.state, vdev::State::Degraded);
+    assert_eq!(status.vdevs[0].state, vdev::State::Healthy);
+    assert_eq!(status.vdevs[1].state, vdev::State::Degraded);
+    assert_eq!(status.vdevs[1].children[1].0, vdev::State::CannotOpen);
+
+    // Enough failed reads fault a child of the mirror, the other one still has the data
+    flaky.inject(Fault::ReadError { percent: 100 });
+    let mut buf = [0; 512];
+    for _ in 0..VDEV_ERROR_LIMIT {
+        zio.tops[&0].read_at(0, &mut buf).unwrap();
+    }
+    let status = zio.vdev_status(0);
+    assert_eq!(status.state, vdev::State::Degraded);
+    assert_eq!(status.children[0].0, vdev::State::Faulted);
+    assert_eq!(status.children[1], (vdev::State::Healthy, Default::default()));
+
+    // Writes the raidz can't take count against it, until it's faulted and the pool with it
+    let dva = DVAddr {
+        vdev: 1 << 32 | 1,
+        offset: 0,
+    };
+    for _ in 0..VDEV_ERROR_LIMIT {
+        assert!(zio.write_dva(&dva, &[1; 512]).is_err());
+    }
+    let status = PoolStatus::new("tank", &zio);
+    assert_eq!(status.vdevs[1].errors.write, VDEV_ERROR_LIMIT);
+    assert_eq!(status.vdevs[1].state, vdev::State::Faulted);
+    assert_eq!(status.vdevs[0].state, vdev::State::Degraded);
+    assert_eq!(status.state, vdev::State::Faulted);
+}
@@ -0,0 +1,651 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+
+use super::checksum;
+use super::nvpair::{NvList, NvValue};
+use super::nvstream;
+use super::util;
+use super::vdev::VdevLabel;
+use super::version::PoolVersion;
+use super::xdr;
+use super::zfs;
+use super::zio;
+
+
+/// Byte offset of the packed nvlist inside a vdev label
+pub const LABEL_NVLIST_OFFSET: u64 = 16 * 1024;
+/// Size of the nvlist area, see `VdevLabel::nv_pairs`
+pub const LABEL_NVLIST_SIZE: u64 = 112 * 1024;
+
+/// The boot block, between labels 0 and 1 and the first allocatable sector. ZFS doesn't use it,
+/// it's left to bootloaders.
+pub const VDEV_BOOT_OFFSET: u64 = 512 << 10;
+pub const VDEV_BOOT_SIZE: u64 = 7 << 19;
+/// Space reserved at the start of a device for labels 0 and 1 and the boot block
+pub const VDEV_LABEL_START_SIZE: u64 = 4 << 20;
+/// Space reserved at the end of a device for labels 2 and 3
+pub const VDEV_LABEL_END_SIZE: u64 = 512 << 10;
+
+/// The pool configuration stored in the nvlist of every vdev label
+#[derive(Debug)]
+pub struct PoolConfig {
+    pub version: u64,
+    pub name: String,
+    pub state: u64,
+    pub txg: u64,
+    pub pool_guid: u64,
+    pub hostid: Option<u64>,
+    pub hostname: Option<String>,
+    pub top_guid: u64, // guid of the top-level vdev this label belongs to
+    pub guid: u64, // guid of the vdev this label belongs to
+    pub vdev_children: u64,
+    pub vdev_tree: VdevConfig,
+    pub features_for_read: Vec<String>,
+}
+
+impl PoolConfig {
+    pub fn from_nv_list(nv: &NvList) -> zfs::Result<Self> {
+        let vdev_tree: &NvList = try!(nv.get("vdev_tree").ok_or(zfs::Error::Invalid));
+
+        // Only pools using feature flags have this
+        let features_for_read = match nv.get::<&NvList>("features_for_read") {
+            Some(features) => features.pairs.iter().map(|&(ref name, _)| name.clone()).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(PoolConfig {
+            version: try!(nv.get("version").ok_or(zfs::Error::Invalid)),
+            name: try!(nv.get::<&String>("name").ok_or(zfs::Error::Invalid)).clone(),
+            state: try!(nv.get("state").ok_or(zfs::Error::Invalid)),
+            txg: try!(nv.get("txg").ok_or(zfs::Error::Invalid)),
+            pool_guid: try!(nv.get("pool_guid").ok_or(zfs::Error::Invalid)),
+            hostid: nv.get("hostid"),
+            hostname: nv.get::<&String>("hostname").cloned(),
+            top_guid: try!(nv.get("top_guid").ok_or(zfs::Error::Invalid)),
+            guid: try!(nv.get("guid").ok_or(zfs::Error::Invalid)),
+            vdev_children: nv.get("vdev_children").unwrap_or(1),
+            vdev_tree: try!(VdevConfig::from_nv_list(vdev_tree)),
+            features_for_read: features_for_read,
+        })
+    }
+}
+
+/// One node of the `vdev_tree` nvlist
+#[derive(Clone, Debug)]
+pub struct VdevConfig {
+    pub vdev_type: String, // "root", "mirror", "raidz", "disk", "file", ...
+    pub id: u64,
+    pub guid: u64,
+    pub path: Option<String>,
+    pub ashift: Option<u64>,
+    pub asize: Option<u64>,
+    pub metaslab_array: Option<u64>,
+    pub metaslab_shift: Option<u64>,
+    pub nparity: Option<u64>,
+    /// For a raidz, the txgs each expansion of it (`zpool attach`) completed in, oldest first
+    pub raidz_expand_txgs: Vec<u64>,
+    /// Whether a raidz is being expanded right now
+    pub raidz_expanding: bool,
+    pub is_log: bool,
+    pub create_txg: Option<u64>,
+    /// For an indirect vdev, one that was removed, the MOS object mapping its space to where it
+    /// was copied to
+    pub indirect_object: Option<u64>,
+    pub children: Vec<VdevConfig>,
+}
+
+impl VdevConfig {
+    pub fn from_nv_list(nv: &NvList) -> zfs::Result<Self> {
+        let mut children = Vec::new();
+        if let Some(nv_children) = nv.get::<&Vec<NvList>>("children") {
+            for child in nv_children {
+                children.push(try!(VdevConfig::from_nv_list(child)));
+            }
+        }
+
+        Ok(VdevConfig {
+            vdev_type: try!(nv.get::<&String>("type").ok_or(zfs::Error::Invalid)).clone(),
+            id: try!(nv.get("id").ok_or(zfs::Error::Invalid)),
+            guid: try!(nv.get("guid").ok_or(zfs::Error::Invalid)),
+            path: nv.get::<&String>("path").cloned(),
+            ashift: nv.get("ashift"),
+            asize: nv.get("asize"),
+            metaslab_array: nv.get("metaslab_array"),
+            metaslab_shift: nv.get("metaslab_shift"),
+            nparity: nv.get("nparity"),
+            raidz_expand_txgs: nv.get::<&Vec<u64>>("raidz_expand_txgs")
+                                 .cloned()
+                                 .unwrap_or_default(),
+            // A boolean flag, there or not
+            raidz_expanding: nv.find("raidz_expanding").is_some(),
+            is_log: nv.get::<u64>("is_log").map(|x| x != 0).unwrap_or(false),
+            create_txg: nv.get("create_txg"),
+            indirect_object: nv.get("com.delphix:indirect_object"),
+            children: children,
+        })
+    }
+
+    /// Depth-first search for the vdev with the given guid
+    pub fn find(&self, guid: u64) -> Option<&VdevConfig> {
+        if self.guid == guid {
+            return Some(self);
+        }
+        self.children.iter().filter_map(|child| child.find(guid)).next()
+    }
+
+    /// Whether this vdev has no children, i.e. is backed by a device
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Sum of the guids of this vdev and all of its descendants, as uberblocks have it
+    pub fn guid_sum(&self) -> u64 {
+        self.children.iter().fold(self.guid, |sum, child| sum.wrapping_add(child.guid_sum()))
+    }
+}
+
+/// Decode a packed (XDR) nvlist
+pub fn decode(bytes: &mut [u8]) -> zfs::Result<NvList> {
+    let mut xdr = xdr::MemOps::new(bytes);
+    nvstream::decode_nv_list(&mut xdr).map_err(|_| zfs::Error::Invalid)
+}
+
+/// Read the raw nvlist from one of the four labels of a device
+pub fn read_label_nv_list(reader: &zio::Reader, label: usize) -> zfs::Result<NvList> {
+    let size = reader.size();
+    let offset = try!(zio::label_offset(size, label).ok_or(zfs::Error::Invalid));
+    let mut bytes = try!(reader.read(((offset + LABEL_NVLIST_OFFSET) / 512) as usize,
+                                     (LABEL_NVLIST_SIZE / 512) as usize));
+    decode(&mut bytes).map_err(|_| zfs::Error::InvalidLabel)
+}
+
+/// Encode `nv` and write it to the nvlist area of one of the four labels of a device, sealed
+/// with the embedded label checksum
+pub fn write_label_nv_list(reader: &mut zio::Reader, label: usize, nv: &NvList) -> zfs::Result<()> {
+    let size = reader.size();
+    let offset = try!(zio::label_offset(size, label).ok_or(zfs::Error::Invalid)) +
+                 LABEL_NVLIST_OFFSET;
+
+    let mut bytes = vec![0; LABEL_NVLIST_SIZE as usize];
+    {
+        let mut xdr = xdr::MemOps::new(&mut bytes);
+        try!(nvstream::encode_nv_list(&mut xdr, nv).map_err(|_| zfs::Error::Invalid));
+    }
+    // The label checksum is verified against the offset the nvlist was written to
+    checksum::embed(&mut bytes, [offset, 0, 0, 0]);
+
+    Ok(try!(reader.write_sectors((offset / 512) as usize, &bytes)))
+}
+
+/// Zero all of one of the four labels of a device, uberblock ring included, so nothing a pool
+/// that lived on the device before left there is found again
+pub fn clear_label(reader: &mut zio::Reader, label: usize) -> zfs::Result<()> {
+    let offset = try!(zio::label_offset(reader.size(), label).ok_or(zfs::Error::Invalid));
+    Ok(try!(reader.write_at(offset, &vec![0; mem::size_of::<VdevLabel>()])))
+}
+
+/// The regions of a vdev label ZFS keeps no pool data in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LabelRegion {
+    /// The 8K blank at the start of the label, left for disk labels (VTOC). Probes read it and
+    /// write back what they read.
+    Pad,
+    /// The 8K boot block header that follows it
+    BootHeader,
+}
+
+impl LabelRegion {
+    /// Byte offset of the region inside a label
+    pub fn offset(&self) -> u64 {
+        match *self {
+            LabelRegion::Pad => 0,
+            LabelRegion::BootHeader => 8 * 1024,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        8 * 1024
+    }
+}
+
+/// Read `region` of one of the four labels of a device
+pub fn read_label_region(reader: &zio::Reader,
+                         label: usize,
+                         region: LabelRegion)
+                         -> zfs::Result<Vec<u8>> {
+    let offset = try!(zio::label_offset(reader.size(), label).ok_or(zfs::Error::Invalid));
+    Ok(try!(reader.read_at(offset + region.offset(), region.size() as usize)))
+}
+
+/// Write `data` to `region` of one of the four labels of a device, padded with zeros to the size
+/// of the region. The rest of the label is left alone.
+pub fn write_label_region(reader: &mut zio::Reader,
+                          label: usize,
+                          region: LabelRegion,
+                          data: &[u8])
+                          -> zfs::Result<()> {
+    if data.len() as u64 > region.size() {
+        return Err(zfs::Error::Invalid);
+    }
+    let offset = try!(zio::label_offset(reader.size(), label).ok_or(zfs::Error::Invalid));
+    let mut bytes = data.to_vec();
+    bytes.resize(region.size() as usize, 0);
+    Ok(try!(reader.write_at(offset + region.offset(), &bytes)))
+}
+
+/// Offset on the device of `len` bytes at `offset` of the boot block, if they're inside it and
+/// the device is large enough to have one
+fn boot_block_offset(reader: &zio::Reader, offset: u64, len: usize) -> zfs::Result<u64> {
+    if offset + len as u64 > VDEV_BOOT_SIZE ||
+       reader.size() < VDEV_LABEL_START_SIZE + VDEV_LABEL_END_SIZE {
+        return Err(zfs::Error::Invalid);
+    }
+    Ok(VDEV_BOOT_OFFSET + offset)
+}
+
+/// Read `len` bytes at `offset` of the 3.5M boot block
+pub fn read_boot_block(reader: &zio::Reader, offset: u64, len: usize) -> zfs::Result<Vec<u8>> {
+    let start = try!(boot_block_offset(reader, offset, len));
+    Ok(try!(reader.read_at(start, len)))
+}
+
+/// Write `data` at `offset` of the 3.5M boot block
+pub fn write_boot_block(reader: &mut zio::Reader, offset: u64, data: &[u8]) -> zfs::Result<()> {
+    let start = try!(boot_block_offset(reader, offset, data.len()));
+    Ok(try!(reader.write_at(start, data)))
+}
+
+/// Bump the pool version of a label config and add newly enabled features to its
+/// `features_for_read`. The version never goes down. Fails with `Invalid` for a version there's
+/// no such thing as, or features without feature flags.
+pub fn upgrade_config(nv: &mut NvList, version: u64, features: &[&str]) -> zfs::Result<()> {
+    let old_version: u64 = try!(nv.get("version").ok_or(zfs::Error::Invalid));
+    try!(upgraded_version(old_version, version, features));
+    if version > old_version {
+        if let Some(value) = nv.find_mut("version") {
+            *value = NvValue::Uint64(version);
+        }
+    }
+
+    if features.is_empty() {
+        return Ok(());
+    }
+    if nv.find("features_for_read").is_none() {
+        nv.add("features_for_read".to_owned(), NvValue::NvList(NvList::new(1)));
+    }
+    if let Some(&mut NvValue::NvList(ref mut features_for_read)) =
+           nv.find_mut("features_for_read") {
+        for feature in features {
+            if features_for_read.find(feature).is_none() {
+                features_for_read.add((*feature).to_owned(), NvValue::Boolean);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The version of a pool at `old_version` after an upgrade to `version` with `features` enabled,
+/// like `upgrade_config`
+pub fn upgraded_version(old_version: u64, version: u64, features: &[&str]) -> zfs::Result<u64> {
+    let new_version = try!(PoolVersion::from_u64(cmp::max(version, old_version))
+                               .ok_or(zfs::Error::Invalid));
+    if !features.is_empty() && !new_version.has_features() {
+        return Err(zfs::Error::Invalid);
+    }
+    Ok(new_version.as_u64())
+}
+
+/// Pick new guids for the pool and every vdev of a label config. Returns (old, new) guid pairs,
+/// to be applied to every label with `reguid_config`.
+pub fn new_guids(nv: &NvList, generate: &mut FnMut() -> u64) -> zfs::Result<Vec<(u64, u64)>> {
+    let mut guids = Vec::new();
+    let pool_guid = try!(nv.get("pool_guid").ok_or(zfs::Error::Invalid));
+    guids.push((pool_guid, generate()));
+    let vdev_tree = try!(nv.get("vdev_tree").ok_or(zfs::Error::Invalid));
+    try!(new_vdev_guids(vdev_tree, generate, &mut guids));
+    Ok(guids)
+}
+
+fn new_vdev_guids(nv: &NvList,
+                  generate: &mut FnMut() -> u64,
+                  guids: &mut Vec<(u64, u64)>)
+                  -> zfs::Result<()> {
+    let guid = try!(nv.get("guid").ok_or(zfs::Error::Invalid));
+    if !guids.iter().any(|&(old, _)| old == guid) {
+        guids.push((guid, generate()));
+    }
+    if let Some(children) = nv.get::<&Vec<NvList>>("children") {
+        for child in children {
+            try!(new_vdev_guids(child, generate, guids));
+        }
+    }
+    Ok(())
+}
+
+/// Replace every guid of a config (the pool's, and those of the vdevs in its tree) that has a new
+/// guid in `guids`
+pub fn reguid_config(nv: &mut NvList, guids: &[(u64, u64)]) {
+    for &mut (ref name, ref mut value) in &mut nv.pairs {
+        match *value {
+            NvValue::Uint64(ref mut guid) if name == "pool_guid" || name == "top_guid" ||
+                                             name == "guid" => {
+                if let Some(&(_, new)) = guids.iter().find(|&&(old, _)| old == *guid) {
+                    *guid = new;
+                }
+            }
+            NvValue::NvList(ref mut vdev_tree) if name == "vdev_tree" => {
+                reguid_config(vdev_tree, guids);
+            }
+            NvValue::NvListArray(ref mut children) if name == "children" => {
+                for child in children {
+                    reguid_config(child, guids);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Rewrite the config in the given labels of the device with the new guids. Returns the number of
+/// labels rewritten.
+pub fn reguid_labels(reader: &mut zio::Reader,
+                     labels: &[usize],
+                     guids: &[(u64, u64)])
+                     -> zfs::Result<usize> {
+    rewrite_labels(reader, labels, &|nv| reguid_config(nv, guids))
+}
+
+/// Rewrite the config in the given labels of the device with `update` applied. Returns the number
+/// of labels rewritten.
+pub fn rewrite_labels(reader: &mut zio::Reader,
+                      labels: &[usize],
+                      update: &Fn(&mut NvList))
+                      -> zfs::Result<usize> {
+    let mut rewritten = 0;
+    for &label in labels {
+        // Damaged labels are left alone, rewriting them is the job of a label repair
+        let mut nv = match read_label_nv_list(reader, label) {
+            Ok(nv) => nv,
+            Err(_) => continue,
+        };
+        update(&mut nv);
+        try!(write_label_nv_list(reader, label, &nv));
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+/// The sum of the guids of a label config: the pool's, and those of the vdevs in its tree. For a
+/// pool of a single top-level vdev, that's the whole pool, and the uberblock's `guid_sum` has to
+/// match it.
+pub fn config_guid_sum(nv: &NvList) -> zfs::Result<u64> {
+    fn vdev_guid_sum(nv: &NvList) -> zfs::Result<u64> {
+        let mut sum: u64 = try!(nv.get("guid").ok_or(zfs::Error::Invalid));
+        if let Some(children) = nv.get::<&Vec<NvList>>("children") {
+            for child in children {
+                sum = sum.wrapping_add(try!(vdev_guid_sum(child)));
+            }
+        }
+        Ok(sum)
+    }
+    let pool_guid: u64 = try!(nv.get("pool_guid").ok_or(zfs::Error::Invalid));
+    let vdev_tree = try!(nv.get("vdev_tree").ok_or(zfs::Error::Invalid));
+    Ok(pool_guid.wrapping_add(try!(vdev_guid_sum(vdev_tree))))
+}
+
+/// The allocatable size of a leaf vdev on a device of `device_size` bytes: whole labels only, minus
+/// the space the labels and the boot block take up
+pub fn device_asize(device_size: u64) -> u64 {
+    util::p2_align(device_size, 256 * 1024)
+        .saturating_sub(VDEV_LABEL_START_SIZE + VDEV_LABEL_END_SIZE)
+}
+
+/// Set the asize of the top-level vdev `top_guid` in a config. Returns the old asize, or None if
+/// the config has no such vdev.
+pub fn set_asize_config(nv: &mut NvList, top_guid: u64, asize: u64) -> Option<u64> {
+    if nv.get::<u64>("guid") == Some(top_guid) {
+        if let Some(&mut NvValue::Uint64(ref mut old)) = nv.find_mut("asize") {
+            return Some(mem::replace(old, asize));
+        }
+    }
+    for &mut (ref name, ref mut value) in &mut nv.pairs {
+        let old = match *value {
+            NvValue::NvList(ref mut vdev_tree) if name == "vdev_tree" => {
+                set_asize_config(vdev_tree, top_guid, asize)
+            }
+            NvValue::NvListArray(ref mut children) if name == "children" => {
+                children.iter_mut()
+                        .filter_map(|child| set_asize_config(child, top_guid, asize))
+                        .next()
+            }
+            _ => None,
+        };
+        if old.is_some() {
+            return old;
+        }
+    }
+    None
+}
+
+pub fn read_label_config(reader: &zio::Reader, label: usize) -> zfs::Result<PoolConfig> {
+    let nv = try!(read_label_nv_list(reader, label));
+    PoolConfig::from_nv_list(&nv)
+}
+
+#[cfg(test)]
+fn put_pair(xdr: &mut xdr::Xdr, name: &str, data_type: u8, num_elements: i32) {
+    // The sizes are only used by the native decoder, they just have to be nonzero
+    xdr.encode_u32(1).unwrap();
+    xdr.encode_u32(1).unwrap();
+    xdr.encode_string(&name.to_owned()).unwrap();
+    xdr.encode_u8(data_type).unwrap();
+    xdr.encode_i32(num_elements).unwrap();
+}
+
+#[cfg(test)]
+fn put_u64(xdr: &mut xdr::Xdr, name: &str, value: u64) {
+    put_pair(xdr, name, 8, 1);
+    xdr.encode_u64(value).unwrap();
+}
+
+#[cfg(test)]
+fn put_string(xdr: &mut xdr::Xdr, name: &str, value: &str) {
+    put_pair(xdr, name, 9, 1);
+    xdr.encode_string(&value.to_owned()).unwrap();
+}
+
+#[cfg(test)]
+fn put_list_start(xdr: &mut xdr::Xdr) {
+    xdr.encode_i32(0).unwrap(); // version
+    xdr.encode_u32(1).unwrap(); // NV_UNIQUE_NAME
+}
+
+#[cfg(test)]
+fn put_list_end(xdr: &mut xdr::Xdr) {
+    xdr.encode_i32(0).unwrap();
+    xdr.encode_i32(0).unwrap();
+}
+
+#[test]
+fn test_label_config() {
+    // Same layout as the label of a single disk pool created by `zpool create tank /dev/sdb`
+    let mut bytes = vec![0; 4096];
+    {
+        let mut mem_ops = xdr::MemOps::new(&mut bytes);
+        let xdr: &mut xdr::Xdr = &mut mem_ops;
+        xdr.encode_opaque(&[1, 1, 0, 0]).unwrap();
+        put_list_start(xdr);
+        put_u64(xdr, "version", 5000);
+        put_string(xdr, "name", "tank");
+        put_u64(xdr, "state", 0);
+        put_u64(xdr, "txg", 42);
+        put_u64(xdr, "pool_guid", 0x1234);
+        put_string(xdr, "hostname", "box");
+        put_u64(xdr, "top_guid", 0x5678);
+        put_u64(xdr, "guid", 0x5678);
+        put_u64(xdr, "vdev_children", 1);
+        put_pair(xdr, "vdev_tree", 19, 1);
+        put_list_start(xdr);
+        put_string(xdr, "type", "disk");
+        put_u64(xdr, "id", 0);
+        put_u64(xdr, "guid", 0x5678);
+        put_string(xdr, "path", "/dev/sdb1");
+        put_u64(xdr, "metaslab_array", 35);
+        put_u64(xdr, "metaslab_shift", 24);
+        put_u64(xdr, "ashift", 9);
+        put_u64(xdr, "asize", 1 << 30);
+        put_u64(xdr, "is_log", 0);
+        put_u64(xdr, "create_txg", 4);
+        put_list_end(xdr);
+        put_pair(xdr, "features_for_read", 19, 1);
+        put_list_start(xdr);
+        put_pair(xdr, "com.delphix:hole_birth", 1, 0);
+        put_pair(xdr, "com.delphix:embedded_data", 1, 0);
+        put_list_end(xdr);
+        put_pair(xdr, "guids", 16, 2);
+        xdr.encode_u32(2).unwrap();
+        xdr.encode_u64(1).unwrap();
+        xdr.encode_u64(2).unwrap();
+        put_pair(xdr, "names", 17, 2);
+        xdr.encode_string(&"a".to_owned()).unwrap();
+        xdr.encode_string(&"bc".to_owned()).unwrap();
+        put_list_end(xdr);
+    }
+
+    let nv = decode(&mut bytes).unwrap();
+    assert_eq!(nv.get::<&Vec<u64>>("guids"), Some(&vec![1, 2]));
+    assert_eq!(nv.get::<&Vec<String>>("names"),
+               Some(&vec!["a".to_owned(), "bc".to_owned()]));
+
+    let config = PoolConfig::from_nv_list(&nv).unwrap();
+    assert_eq!(config.name, "tank");
+    assert_eq!(config.txg, 42);
+    assert_eq!(config.hostid, None);
+    assert_eq!(config.hostname, Some("box".to_owned()));
+    assert_eq!(config.features_for_read.len(), 2);
+    assert_eq!(config.vdev_tree.vdev_type, "disk");
+    assert_eq!(config.vdev_tree.path, Some("/dev/sdb1".to_owned()));
+    assert_eq!(config.vdev_tree.metaslab_array, Some(35));
+    assert!(config.vdev_tree.is_leaf());
+    assert_eq!(config.vdev_tree.find(0x5678).map(|v| v.id), Some(0));
+}
+
+#[test]
+fn test_upgrade_config() {
+    use super::version::SPA_VERSION_FEATURES;
+
+    let mut nv = NvList::new(1);
+    nv.add("version".to_owned(), NvValue::Uint64(28));
+
+    assert!(upgrade_config(&mut nv, 28, &["com.delphix:hole_birth"]).is_err());
+    assert!(upgrade_config(&mut nv, 29, &[]).is_err());
+    upgrade_config(&mut nv, SPA_VERSION_FEATURES, &["com.delphix:hole_birth"]).unwrap();
+    upgrade_config(&mut nv, 5, &["com.delphix:hole_birth", "org.open-zfs:large_blocks"]).unwrap();
+
+    assert_eq!(nv.get::<u64>("version"), Some(SPA_VERSION_FEATURES));
+    let features: &NvList = nv.get("features_for_read").unwrap();
+    assert_eq!(features.pairs.len(), 2);
+
+    // The sealed nvlist only verifies at the offset it was sealed for
+    let mut bytes = vec![0; 4096];
+    nvstream::encode_nv_list(&mut xdr::MemOps::new(&mut bytes), &nv).unwrap();
+    checksum::embed(&mut bytes, [16 * 1024, 0, 0, 0]);
+    assert!(checksum::verify_embedded(&bytes, [16 * 1024, 0, 0, 0]).is_ok());
+    assert!(checksum::verify_embedded(&bytes, [272 * 1024, 0, 0, 0]).is_err());
+}
+
+#[test]
+fn test_reguid_config() {
+    let mut top = NvList::new(1);
+    top.add("type".to_owned(), NvValue::String("mirror".to_owned()));
+    top.add("guid".to_owned(), NvValue::Uint64(10));
+    let children = [11, 12].iter()
+                           .map(|&guid| {
+                               let mut child = NvList::new(1);
+                               child.add("type".to_owned(), NvValue::String("disk".to_owned()));
+                               child.add("guid".to_owned(), NvValue::Uint64(guid));
+                               child
+                           })
+                           .collect();
+    top.add("children".to_owned(), NvValue::NvListArray(children));
+
+    let mut nv = NvList::new(1);
+    nv.add("pool_guid".to_owned(), NvValue::Uint64(1));
+    nv.add("txg".to_owned(), NvValue::Uint64(1));
+    nv.add("top_guid".to_owned(), NvValue::Uint64(10));
+    nv.add("guid".to_owned(), NvValue::Uint64(12));
+    nv.add("vdev_tree".to_owned(), NvValue::NvList(top));
+
+    let mut next = 100;
+    let guids = new_guids(&nv, &mut || {
+                    next += 1;
+                    next
+                })
+                    .unwrap();
+    assert_eq!(guids, vec![(1, 101), (10, 102), (11, 103), (12, 104)]);
+
+    reguid_config(&mut nv, &guids);
+    assert_eq!(nv.get::<u64>("pool_guid"), Some(101));
+    // Not a guid, even though 1 is being replaced
+    assert_eq!(nv.get::<u64>("txg"), Some(1));
+    assert_eq!(nv.get::<u64>("top_guid"), Some(102));
+    assert_eq!(nv.get::<u64>("guid"), Some(104));
+    let vdev_tree: &NvList = nv.get("vdev_tree").unwrap();
+    let children: &Vec<NvList> = vdev_tree.get("children").unwrap();
+    assert_eq!(vdev_tree.get::<u64>("guid"), Some(102));
+    assert_eq!(children.iter().map(|child| child.get::<u64>("guid")).collect::<Vec<_>>(),
+               vec![Some(103), Some(104)]);
+}
+
+#[test]
+fn test_expand_config() {
+    // 64M and a bit, the bit isn't a whole label
+    assert_eq!(device_asize((64 << 20) + 100 * 1024), (64 << 20) - (4 << 20) - (512 << 10));
+    assert_eq!(device_asize(1 << 20), 0);
+
+    let mut top = NvList::new(1);
+    top.add("type".to_owned(), NvValue::String("file".to_owned()));
+    top.add("guid".to_owned(), NvValue::Uint64(10));
+    top.add("metaslab_shift".to_owned(), NvValue::Uint64(24));
+    top.add("asize".to_owned(), NvValue::Uint64(60 << 20));
+    let mut nv = NvList::new(1);
+    // The label config's own guid is the leaf's, which here is the top-level vdev too
+    nv.add("top_guid".to_owned(), NvValue::Uint64(10));
+    nv.add("guid".to_owned(), NvValue::Uint64(10));
+    nv.add("vdev_tree".to_owned(), NvValue::NvList(top));
+
+    assert_eq!(set_asize_config(&mut nv, 11, 1 << 30), None);
+    assert_eq!(set_asize_config(&mut nv, 10, 1 << 30), Some(60 << 20));
+    let vdev_tree: &NvList = nv.get("vdev_tree").unwrap();
+    assert_eq!(vdev_tree.get::<u64>("asize"), Some(1 << 30));
+}
+
+#[test]
+fn test_label_regions() {
+    use super::vdev_io::MemVdev;
+
+    let mut reader = zio::Reader::new(MemVdev::new(vec![0; 8 << 20]));
+    let mut nv = NvList::new(1);
+    nv.add("version".to_owned(), NvValue::Uint64(5000));
+    write_label_nv_list(&mut reader, 2, &nv).unwrap();
+
+    write_label_region(&mut reader, 2, LabelRegion::Pad, b"pad").unwrap();
+    write_label_region(&mut reader, 2, LabelRegion::BootHeader, &[0x42; 8192]).unwrap();
+    assert_eq!(&read_label_region(&reader, 2, LabelRegion::Pad).unwrap()[..4], b"pad\0");
+    assert_eq!(read_label_region(&reader, 2, LabelRegion::BootHeader).unwrap(), vec![0x42; 8192]);
+    assert_eq!(read_label_region(&reader, 3, LabelRegion::Pad).unwrap(), vec![0; 8192]);
+    assert_eq!(write_label_region(&mut reader, 2, LabelRegion::Pad, &[0; 8193]).err(),
+               Some(zfs::Error::Invalid));
+    // The config next to them is untouched
+    assert_eq!(read_label_nv_list(&reader, 2).unwrap().get::<u64>("version"), Some(5000));
+
+    write_boot_block(&mut reader, 1000, b"boot").unwrap();
+    assert_eq!(read_boot_block(&reader, 998, 8).unwrap(), b"\0\0boot\0\0");
+    assert_eq!(reader.read_at(VDEV_BOOT_OFFSET + 1000, 4).unwrap(), b"boot");
+    assert!(write_boot_block(&mut reader, VDEV_BOOT_SIZE - 2, b"boot").is_err());
+    // Too small for a boot block
+    let reader = zio::Reader::new(MemVdev::new(vec![0; 1 << 20]));
+    assert!(read_boot_block(&reader, 0, 512).is_err());
+}
@@ -1,11 +1,56 @@
-use std::{fmt, mem};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, fmt, mem};
+use std::collections::BTreeMap;
 
+use super::ZfsReader;
 use super::avl;
+use super::dmu;
 use super::dmu_objset::ObjectSet;
-use super::from_bytes::FromBytes;
+use super::dmu_tx::Tx;
+use super::dnode::{DNodePhys, ObjectType};
+use super::dsl_pool::DslPool;
+use super::from_bytes::{Decoder, FromBytes};
 use super::zfs;
 
 const SPACE_MAP_HISTOGRAM_SIZE: usize = 32;
+/// Size of the header of a space map without a histogram
+const SPACE_MAP_SIZE_V0: usize = 3 * 8;
+/// Block size of a new space map object
+const SPACE_MAP_BLOCKSIZE: u64 = 1 << 12;
+
+/// The top two bits of an entry say what kind it is: a one-word entry if the top one is clear,
+/// otherwise one of these
+const SM_DEBUG_PREFIX: u64 = 2;
+/// The first word of a two-word entry, as written with the spacemap_v2 feature
+const SM2_PREFIX: u64 = 3;
+
+/// The vdev id of two-word entries that don't name a vdev
+const SM_NO_VDEVID: u64 = (1 << 24) - 1;
+
+/// Create an empty space map object in the MOS, in the txg `tx` is assigned to
+/// (`space_map_alloc`). Returns its object number.
+pub fn alloc(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             mos: &ObjectSet,
+             tx: &Tx)
+             -> zfs::Result<u64> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    let mut header = [0; SPACE_MAP_SIZE_V0];
+    let dnode = DNodePhys::new(ObjectType::SpaceMap,
+                               SPACE_MAP_BLOCKSIZE,
+                               ObjectType::SpaceMapHeader,
+                               &header);
+    let object = try!(dmu::create_object(reader, dsl_pool, mos, dnode, tx));
+    // The header names its object, though nothing reads that any more
+    header[..8].copy_from_slice(&object.to_le_bytes());
+    let dnode = DNodePhys::new(ObjectType::SpaceMap,
+                               SPACE_MAP_BLOCKSIZE,
+                               ObjectType::SpaceMapHeader,
+                               &header);
+    dsl_pool.dirty_dnode(txg, tx.objset(), object, dnode);
+    Ok(object)
+}
 
 /// The `SpaceMapPhys` is the on-disk representation of the space map.
 /// Consumers of space maps should never reference any of the members of this
@@ -27,7 +72,30 @@ pub struct SpaceMapPhys {
                  * histogram: [u64; SPACE_MAP_HISTOGRAM_SIZE], */
 }
 
-impl FromBytes for SpaceMapPhys {}
+impl SpaceMapPhys {
+    /// Number of bytes of entries in the space map object
+    pub fn length(&self) -> u64 {
+        self.objsize
+    }
+
+    /// Bytes allocated in the space the map covers, as of the last sync
+    pub fn alloc(&self) -> u64 {
+        self.alloc
+    }
+}
+
+impl FromBytes for SpaceMapPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let object = decoder.u64();
+        let objsize = decoder.u64();
+        let alloc = decoder.u64();
+        SpaceMapPhys {
+            object: object,
+            objsize: objsize,
+            alloc: alloc,
+        }
+    }
+}
 
 pub struct SpaceMap {
     start: u64, // start of map
@@ -55,12 +123,8 @@ impl SpaceMap {
     // }
 
 
-    pub fn open(os: &mut ObjectSet,
-                object: u64,
-                start: u64,
-                size: u64,
-                shift: u8)
-                -> zfs::Result<Self> {
+    /// Nothing is read yet, the entries are only read by `load`
+    pub fn open(object: u64, start: u64, size: u64, shift: u8) -> zfs::Result<Self> {
         assert!(object != 0);
 
         // TODO
@@ -88,6 +152,44 @@ impl SpaceMap {
         Ok(space_map)
     }
 
+    /// Read the header of the space map object, in its bonus buffer
+    pub fn phys(&self, reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<SpaceMapPhys> {
+        let dnode = try!(mos.dnode(reader, self.object).map_err(|_| zfs::Error::Invalid));
+        SpaceMapPhys::from_bytes(dnode.get_bonus())
+    }
+
+    /// Read the entries of the space map object from the MOS. The bonus buffer says how many
+    /// bytes of them there are.
+    pub fn load(&self, reader: &mut ZfsReader, mos: &ObjectSet) -> zfs::Result<Vec<Entry>> {
+        let dnode = try!(mos.dnode(reader, self.object).map_err(|_| zfs::Error::Invalid));
+        let phys = try!(SpaceMapPhys::from_bytes(dnode.get_bonus()));
+        if phys.objsize == 0 {
+            return Ok(Vec::new());
+        }
+        let bytes = try!(dmu::read(reader, &dnode, 0, phys.objsize as usize)
+                             .map_err(|_| zfs::Error::Invalid));
+        bytes.chunks(mem::size_of::<Entry>())
+             .map(|entry| Entry::from_bytes(entry))
+             .collect()
+    }
+
+    /// The free segments of the map after replaying `entries`, as (start, size) pairs in bytes
+    /// sorted by start. The map starts out all free; allocations are taken out of it and frees
+    /// put back, in log order.
+    pub fn free_segments(&self, entries: &[Entry]) -> Vec<(u64, u64)> {
+        let mut free = BTreeMap::new();
+        free.insert(self.start, self.start + self.size);
+        for run in runs(entries) {
+            let start = self.start + (run.offset << self.shift);
+            let end = start + (run.run << self.shift);
+            match run.map_type {
+                MapType::Alloc => remove_range(&mut free, start, end),
+                MapType::Free => add_range(&mut free, start, end),
+            }
+        }
+        free.into_iter().map(|(start, end)| (start, end - start)).collect()
+    }
+
     pub fn load_avl(&self,
                     tree: &mut avl::Tree<Segment, u64>,
                     bytes: &[u8],
@@ -106,6 +208,7 @@ impl SpaceMap {
                 tree.insert(Segment::from_entry(&entry));
             }
         }
+        #[cfg(feature = "std")]
         tree.in_order(|node| {
             println!("{:?}", node.value());
         });
@@ -114,6 +217,41 @@ impl SpaceMap {
     }
 }
 
+/// Take [start, end) out of a set of ranges, kept as start -> end
+fn remove_range(ranges: &mut BTreeMap<u64, u64>, start: u64, end: u64) {
+    let overlapping: Vec<(u64, u64)> = ranges.range(..end)
+                                             .filter(|&(_, &range_end)| range_end > start)
+                                             .map(|(&range_start, &range_end)| {
+                                                 (range_start, range_end)
+                                             })
+                                             .collect();
+    for (range_start, range_end) in overlapping {
+        ranges.remove(&range_start);
+        if range_start < start {
+            ranges.insert(range_start, start);
+        }
+        if range_end > end {
+            ranges.insert(end, range_end);
+        }
+    }
+}
+
+/// Add [start, end) to a set of ranges, merging it with the ranges it touches
+fn add_range(ranges: &mut BTreeMap<u64, u64>, mut start: u64, mut end: u64) {
+    let touching: Vec<(u64, u64)> = ranges.range(..end + 1)
+                                          .filter(|&(_, &range_end)| range_end >= start)
+                                          .map(|(&range_start, &range_end)| {
+                                              (range_start, range_end)
+                                          })
+                                          .collect();
+    for (range_start, range_end) in touching {
+        ranges.remove(&range_start);
+        start = cmp::min(start, range_start);
+        end = cmp::max(end, range_end);
+    }
+    ranges.insert(start, end);
+}
+
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MapType {
@@ -131,14 +269,72 @@ impl MapType {
     }
 }
 
+/// An allocation or free of `run` units (of `1 << shift` bytes) at `offset` units into the
+/// space map, from either kind of entry
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Run {
+    pub map_type: MapType,
+    pub offset: u64,
+    pub run: u64,
+    /// The vdev the space is on, if a two-word entry says
+    pub vdev: Option<u64>,
+}
+
+/// The allocations and frees of `entries` in log order. Debug entries are skipped, and so is a
+/// two-word entry cut off by the end of the map.
+pub fn runs(entries: &[Entry]) -> Vec<Run> {
+    let mut runs = Vec::new();
+    let mut words = entries.iter();
+    while let Some(entry) = words.next() {
+        if entry.debug() == 1 {
+            continue;
+        }
+        if !entry.is_two_word() {
+            // The map type is a single bit, either value is one
+            runs.push(Run {
+                map_type: entry.map_type().unwrap(),
+                offset: entry.offset(),
+                run: entry.size() + 1,
+                vdev: None,
+            });
+            continue;
+        }
+        let second = match words.next() {
+            Some(second) => second.0,
+            None => break,
+        };
+        runs.push(Run {
+            map_type: MapType::from_u64(second >> 63).unwrap(),
+            offset: second & !(1 << 63),
+            run: entry.run2() + 1,
+            vdev: if entry.vdev() == SM_NO_VDEVID { None } else { Some(entry.vdev()) },
+        });
+    }
+    runs
+}
+
 #[derive(Copy, Clone)]
 pub struct Entry(u64);
 
-impl FromBytes for Entry {}
+impl FromBytes for Entry {
+    fn decode(decoder: &mut Decoder) -> Self {
+        Entry(decoder.u64())
+    }
+}
 
 impl Entry {
+    fn prefix(&self) -> u64 {
+        self.0 >> 62 // 2 bits long
+    }
+
     pub fn debug(&self) -> u64 {
-        (self.0 >> 63) & 0x1 // 1 bit long
+        (self.prefix() == SM_DEBUG_PREFIX) as u64
+    }
+
+    /// Whether this is the first word of a two-word entry, the second word holds the map type
+    /// and offset (see `runs`)
+    pub fn is_two_word(&self) -> bool {
+        self.prefix() == SM2_PREFIX
     }
 
     // Non-debug entries
@@ -168,11 +364,26 @@ impl Entry {
     pub fn txg(&self) -> u64 {
         self.0 & 0x3FFFFFFFFFFFF // 50 bytes long
     }
+
+    // First words of two-word entries
+
+    pub fn run2(&self) -> u64 {
+        (self.0 >> 24) & 0xFFFFFFFFF // 36 bits long
+    }
+
+    pub fn vdev(&self) -> u64 {
+        self.0 & 0xFFFFFF // 24 bits long
+    }
 }
 
 impl fmt::Debug for Entry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.debug() == 1 {
+        if self.is_two_word() {
+            try!(write!(f,
+                        "ENTRY2: run:0x{:X}  vdev:0x{:X}",
+                        self.run2(),
+                        self.vdev()));
+        } else if self.debug() == 1 {
             try!(write!(f,
                         "DEBUG: action:0x{:X}  sync_pass:{:X}  txg:0x{:X}",
                         self.action(),
@@ -205,3 +416,68 @@ impl Segment {
         }
     }
 }
+
+#[test]
+fn test_free_segments() {
+    let space_map = SpaceMap::open(1, 1 << 20, 1 << 20, 9).unwrap();
+    // (offset in sectors, sectors, free)
+    let entry = |offset: u64, run: u64, free: bool| {
+        Entry(offset << 16 | (free as u64) << 15 | (run - 1))
+    };
+    let entries = [Entry(1 << 63 | 42), // debug entries are skipped
+                   entry(0, 8, false),
+                   entry(16, 16, false),
+                   entry(20, 4, true),
+                   entry(4, 2, true)];
+    assert_eq!(space_map.free_segments(&entries),
+               vec![((1 << 20) + 4 * 512, 2 * 512),
+                    ((1 << 20) + 8 * 512, 8 * 512),
+                    ((1 << 20) + 20 * 512, 4 * 512),
+                    ((1 << 20) + 32 * 512, (1 << 20) - 32 * 512)]);
+
+    // Freeing next to free space merges with it
+    let entries = [entry(0, 8, false), entry(4, 4, true)];
+    assert_eq!(space_map.free_segments(&entries),
+               vec![((1 << 20) + 4 * 512, (1 << 20) - 4 * 512)]);
+}
+
+#[test]
+fn test_two_word_entries() {
+    // (offset in sectors, sectors, free, vdev)
+    let entry = |offset: u64, run: u64, free: bool, vdev: u64| {
+        [Entry(3 << 62 | (run - 1) << 24 | vdev), Entry((free as u64) << 63 | offset)]
+    };
+    let mut entries = vec![Entry(2 << 62 | 7)]; // debug entries are skipped
+    // Runs and offsets too big for a one-word entry
+    entries.extend_from_slice(&entry(1 << 48, 1 << 20, false, 5));
+    entries.push(Entry(16 << 16 | 7)); // one-word entries mix in
+    entries.extend_from_slice(&entry((1 << 48) + 8, 8, true, SM_NO_VDEVID));
+    // Cut off
+    entries.push(Entry(3 << 62 | 3 << 24 | 1));
+    assert_eq!(runs(&entries),
+               vec![Run {
+                        map_type: MapType::Alloc,
+                        offset: 1 << 48,
+                        run: 1 << 20,
+                        vdev: Some(5),
+                    },
+                    Run {
+                        map_type: MapType::Alloc,
+                        offset: 16,
+                        run: 8,
+                        vdev: None,
+                    },
+                    Run {
+                        map_type: MapType::Free,
+                        offset: (1 << 48) + 8,
+                        run: 8,
+                        vdev: None,
+                    }]);
+
+    let space_map = SpaceMap::open(1, 0, (1 << 48) * 512 * 2, 9).unwrap();
+    assert_eq!(space_map.free_segments(&entries),
+               vec![(0, 16 * 512),
+                    (24 * 512, ((1 << 48) - 24) * 512),
+                    (((1 << 48) + 8) * 512, 8 * 512),
+                    (((1 << 48) + (1 << 20)) * 512, ((1 << 48) - (1 << 20)) * 512)]);
+}
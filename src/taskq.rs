@@ -1,9 +1,20 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+#[cfg(feature = "std")]
 use std::cmp;
-// use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
 // use std::sync::mpsc::{channel, Sender, Receiver};
-use std::thread;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::sync::{Condvar, Mutex};
+#[cfg(feature = "std")]
+use std::thread::{self, JoinHandle};
 
 use super::zfs;
+use super::zio::{self, Priority, TaskqType};
 
 const TQENT_FLAG_PREALLOC: u64 = 0x1; // taskq_dispatch_ent used
 
@@ -369,3 +380,285 @@ struct Task {
 // mutex_exit(&tq.lock);
 // thread_exit();
 // }
+
+//-------------------------------------------------------------------------------------------------//
+
+/// Number of `zio::Priority` levels
+const NUM_PRIORITIES: usize = Priority::Scrub as usize + 1;
+
+/// Most tasks taken ahead of one that was queued before them. Past that, the oldest task goes
+/// next whatever its priority, so a busy pool still gets some scrubbing and prefetching done.
+pub const TASKQ_MAX_BYPASS: usize = 16;
+
+/// Work for a ZIO taskq, done on one of its threads with the pool's reader
+pub type ZioTask = Box<FnOnce(&zio::Reader) + Send>;
+
+/// Tasks queued by priority, in the order `zio::Priority` declares them: sync reads before
+/// async reads before scrubs. FIFO within a priority.
+#[cfg(feature = "std")]
+struct PriorityQueue<T> {
+    /// By priority, the tasks with the number they were queued as
+    queues: Vec<VecDeque<(u64, T)>>,
+    next_seq: u64,
+    /// Tasks taken since the oldest one queued was last taken
+    bypassed: usize,
+}
+
+#[cfg(feature = "std")]
+impl<T> PriorityQueue<T> {
+    fn new() -> Self {
+        PriorityQueue {
+            queues: (0..NUM_PRIORITIES).map(|_| VecDeque::new()).collect(),
+            next_seq: 0,
+            bypassed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn push(&mut self, priority: Priority, task: T) {
+        self.queues[priority as usize].push_back((self.next_seq, task));
+        self.next_seq += 1;
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let urgent = match self.queues.iter().position(|queue| !queue.is_empty()) {
+            Some(urgent) => urgent,
+            None => return None,
+        };
+        let oldest = (0..NUM_PRIORITIES)
+                         .filter_map(|p| self.queues[p].front().map(|&(seq, _)| (seq, p)))
+                         .min()
+                         .map(|(_, p)| p)
+                         .unwrap();
+        let priority = if oldest == urgent || self.bypassed >= TASKQ_MAX_BYPASS {
+            self.bypassed = 0;
+            oldest
+        } else {
+            self.bypassed += 1;
+            urgent
+        };
+        self.queues[priority].pop_front().map(|(_, task)| task)
+    }
+}
+
+#[cfg(feature = "std")]
+struct Queue {
+    tasks: PriorityQueue<ZioTask>,
+    /// Tasks queued or running
+    pending: usize,
+    /// No more tasks are coming, the threads exit once the queue is empty
+    closed: bool,
+}
+
+#[cfg(feature = "std")]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when a task is queued
+    ready: Condvar,
+    /// Signalled when the last pending task is done
+    idle: Condvar,
+}
+
+/// The ZIO taskqs of a pool, one for each `TaskqType`, each with threads of its own. IO issued
+/// by the pool goes to the issue taskqs, what's done once it completes to the interrupt ones.
+#[cfg(feature = "std")]
+pub struct ZioTaskqs {
+    reader: Arc<zio::Reader>,
+    /// By `TaskqType`
+    taskqs: Vec<(Arc<Shared>, Vec<JoinHandle<()>>)>,
+}
+
+#[cfg(feature = "std")]
+impl ZioTaskqs {
+    /// Start the taskqs, with `threads[q]` threads for taskq type `q`
+    pub fn new(reader: Arc<zio::Reader>, threads: [usize; zio::NUM_TASKQ_TYPES]) -> Self {
+        let taskqs = threads.iter()
+                            .map(|&threads| {
+                                let shared = Arc::new(Shared {
+                                    queue: Mutex::new(Queue {
+                                        tasks: PriorityQueue::new(),
+                                        pending: 0,
+                                        closed: false,
+                                    }),
+                                    ready: Condvar::new(),
+                                    idle: Condvar::new(),
+                                });
+                                let workers = (0..cmp::max(threads, 1))
+                                                  .map(|_| {
+                                                      let reader = reader.clone();
+                                                      let shared = shared.clone();
+                                                      thread::spawn(move || {
+                                                          taskq_work(&reader, &shared)
+                                                      })
+                                                  })
+                                                  .collect();
+                                (shared, workers)
+                            })
+                            .collect();
+        ZioTaskqs {
+            reader: reader,
+            taskqs: taskqs,
+        }
+    }
+
+    /// Queue `task` on taskq `taskq`, behind the tasks of more urgent priorities. `Now` tasks
+    /// aren't queued at all, they're done right away on the calling thread.
+    pub fn dispatch<F>(&self, taskq: TaskqType, priority: Priority, task: F)
+        where F: FnOnce(&zio::Reader) + Send + 'static
+    {
+        if priority == Priority::Now {
+            return task(&self.reader);
+        }
+        let shared = &self.taskqs[taskq as usize].0;
+        {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.tasks.push(priority, Box::new(task));
+            queue.pending += 1;
+        }
+        shared.ready.notify_one();
+    }
+
+    /// Number of tasks waiting on taskq `taskq`, not counting those running
+    pub fn queued(&self, taskq: TaskqType) -> usize {
+        self.taskqs[taskq as usize].0.queue.lock().unwrap().tasks.len()
+    }
+
+    /// Wait until every task dispatched so far is done
+    pub fn wait(&self) {
+        for &(ref shared, _) in &self.taskqs {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.pending > 0 {
+                queue = shared.idle.wait(queue).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for ZioTaskqs {
+    /// Tasks already queued are still done
+    fn drop(&mut self) {
+        for &(ref shared, _) in &self.taskqs {
+            shared.queue.lock().unwrap().closed = true;
+            shared.ready.notify_all();
+        }
+        for (_, workers) in self.taskqs.drain(..) {
+            for worker in workers {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn taskq_work(reader: &zio::Reader, shared: &Shared) {
+    loop {
+        let task = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(task) = queue.tasks.pop() {
+                    break task;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+        // A task that panics is lost, not the thread doing it
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| task(reader)));
+
+        let mut queue = shared.queue.lock().unwrap();
+        queue.pending -= 1;
+        if queue.pending == 0 {
+            shared.idle.notify_all();
+        }
+    }
+}
+
+/// Without std there are no threads to hand tasks to: every task is done right away on the
+/// calling thread, like the `Now` ones
+#[cfg(not(feature = "std"))]
+pub struct ZioTaskqs {
+    reader: Arc<zio::Reader>,
+}
+
+#[cfg(not(feature = "std"))]
+impl ZioTaskqs {
+    pub fn new(reader: Arc<zio::Reader>, _: [usize; zio::NUM_TASKQ_TYPES]) -> Self {
+        ZioTaskqs { reader: reader }
+    }
+
+    pub fn dispatch<F>(&self, _: TaskqType, _: Priority, task: F)
+        where F: FnOnce(&zio::Reader) + Send + 'static
+    {
+        task(&self.reader)
+    }
+
+    pub fn queued(&self, _: TaskqType) -> usize {
+        0
+    }
+
+    pub fn wait(&self) {}
+}
+
+#[test]
+fn test_priority_queue() {
+    let mut queue = PriorityQueue::new();
+    queue.push(Priority::Scrub, "scrub");
+    queue.push(Priority::AsyncRead, "prefetch");
+    queue.push(Priority::SyncRead, "read 0");
+    queue.push(Priority::SyncRead, "read 1");
+    assert_eq!(queue.pop(), Some("read 0"));
+    assert_eq!(queue.pop(), Some("read 1"));
+    assert_eq!(queue.pop(), Some("prefetch"));
+    assert_eq!(queue.pop(), Some("scrub"));
+    assert_eq!(queue.pop(), None);
+
+    // A steady stream of sync reads doesn't hold the scrub back forever
+    queue.push(Priority::Scrub, "scrub");
+    for _ in 0..TASKQ_MAX_BYPASS * 2 {
+        queue.push(Priority::SyncRead, "read");
+    }
+    let scrubbed = (0..).position(|_| queue.pop() == Some("scrub")).unwrap();
+    assert_eq!(scrubbed, TASKQ_MAX_BYPASS);
+    assert_eq!(queue.len(), TASKQ_MAX_BYPASS);
+}
+
+#[test]
+fn test_zio_taskqs() {
+    use std::sync::mpsc;
+    use super::vdev_io::MemVdev;
+
+    let reader = Arc::new(zio::Reader::new(MemVdev::new(vec![0; 4096])));
+    let taskqs = ZioTaskqs::new(reader, [1, 1, 1, 1]);
+
+    // Hold the only issue thread until everything else is queued
+    let (release, held) = mpsc::channel::<()>();
+    taskqs.dispatch(TaskqType::Issue, Priority::SyncRead, move |_| {
+        held.recv().unwrap();
+    });
+    while taskqs.queued(TaskqType::Issue) > 0 {
+        thread::yield_now();
+    }
+    let done = Arc::new(Mutex::new(Vec::new()));
+    for &(priority, name) in &[(Priority::Scrub, "scrub"),
+                               (Priority::AsyncRead, "prefetch"),
+                               (Priority::SyncRead, "read")] {
+        let done = done.clone();
+        taskqs.dispatch(TaskqType::Issue, priority, move |reader| {
+            reader.read_at(0, 512).unwrap();
+            done.lock().unwrap().push(name);
+        });
+    }
+    let now = done.clone();
+    taskqs.dispatch(TaskqType::Issue, Priority::Now, move |_| now.lock().unwrap().push("now"));
+    assert_eq!(taskqs.queued(TaskqType::Issue), 3);
+
+    release.send(()).unwrap();
+    taskqs.wait();
+    assert_eq!(*done.lock().unwrap(), vec!["now", "read", "prefetch", "scrub"]);
+}
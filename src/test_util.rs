@@ -0,0 +1,55 @@
+use std::{env, fs};
+use std::path::PathBuf;
+
+use super::dvaddr::DVAddr;
+use super::metaslab::{Allocator, VdevAllocator};
+use super::spa::{self, CreateOptions, Spa, SPA_MINDEVSIZE};
+use super::zfs;
+
+/// Hands out the sectors after the labels in order, and never takes them back
+pub struct Bump(pub u64);
+
+impl Allocator for Bump {
+    fn alloc(&mut self, psize: u64) -> zfs::Result<DVAddr> {
+        let dva = DVAddr {
+            vdev: psize / 512,
+            offset: self.0,
+        };
+        self.0 += psize / 512;
+        Ok(dva)
+    }
+
+    fn free(&mut self, _: &DVAddr, _: u64) {}
+}
+
+/// A file called `name` in the temp directory, removed when this goes out of scope, however the
+/// test ends
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        TempFile(env::temp_dir().join(name))
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// A new pool `tank` on file `name` of `SPA_MINDEVSIZE` bytes, and an allocator for the blocks
+/// written to it
+pub fn pool(name: &str) -> (Spa, VdevAllocator, TempFile) {
+    let file = TempFile::new(name);
+    fs::File::create(file.path()).unwrap().set_len(SPA_MINDEVSIZE).unwrap();
+    let spa = spa::create(file.path(), &CreateOptions::new("tank")).unwrap();
+    // There are no space maps yet: keep clear of the blocks the pool was created with
+    let mut alloc = VdevAllocator::new(0, 9, SPA_MINDEVSIZE - (4 << 20), None);
+    alloc.alloc(16 << 20).unwrap();
+    (spa, alloc, file)
+}
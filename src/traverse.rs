@@ -0,0 +1,338 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+use std::collections::BTreeSet;
+
+use super::block_ptr::BlockPtr;
+use super::dmu_objset::ObjectSetPhys;
+use super::dnode::DNodePhys;
+use super::from_bytes::FromBytes;
+use super::zfs;
+use super::zio;
+
+/// Name used to refer to the meta object set in dataset filters
+pub const MOS_NAME: &'static str = "$MOS";
+
+// DMU object types whose level 0 blocks hold user data rather than metadata
+const OT_PLAIN_FILE_CONTENTS: u64 = 19;
+const OT_ZVOL: u64 = 23;
+
+/// Which blocks a traversal hands to its visitor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ObjectFilter {
+    All,
+    /// Indirect blocks, dnodes, ZAPs, space maps, ...
+    Metadata,
+    /// Only the level 0 blocks of files and volumes
+    Data,
+}
+
+impl ObjectFilter {
+    pub fn matches(self, block_ptr: &BlockPtr) -> bool {
+        match self {
+            ObjectFilter::All => true,
+            ObjectFilter::Metadata => is_metadata(block_ptr),
+            ObjectFilter::Data => !is_metadata(block_ptr),
+        }
+    }
+}
+
+/// Everything but the level 0 blocks of files and volumes is metadata
+pub fn is_metadata(block_ptr: &BlockPtr) -> bool {
+    let object_type = block_ptr.object_type();
+    block_ptr.level() > 0 ||
+    (object_type != OT_PLAIN_FILE_CONTENTS && object_type != OT_ZVOL)
+}
+
+/// Selects the datasets and blocks a traversal (scrub, send, ...) visits
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Datasets to visit, along with their children. Everything is visited if empty.
+    pub include: Vec<String>,
+    /// Datasets to skip, along with their children. Takes precedence over `include`.
+    pub exclude: Vec<String>,
+    pub objects: ObjectFilter,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            objects: ObjectFilter::All,
+        }
+    }
+
+    pub fn include(mut self, name: &str) -> Self {
+        self.include.push(name.to_owned());
+        self
+    }
+
+    pub fn exclude(mut self, name: &str) -> Self {
+        self.exclude.push(name.to_owned());
+        self
+    }
+
+    pub fn objects(mut self, objects: ObjectFilter) -> Self {
+        self.objects = objects;
+        self
+    }
+
+    pub fn wants_dataset(&self, name: &str) -> bool {
+        if self.exclude.iter().any(|exclude| is_same_or_child(name, exclude)) {
+            return false;
+        }
+        self.include.is_empty() ||
+        self.include.iter().any(|include| is_same_or_child(name, include))
+    }
+}
+
+fn is_same_or_child(name: &str, parent: &str) -> bool {
+    name == parent ||
+    (name.starts_with(parent) && name[parent.len()..].starts_with(|c| c == '/' || c == '@'))
+}
+
+/// Object number of the meta dnode, for the blocks of the dnode arrays themselves
+pub const META_DNODE_OBJECT: u64 = 0;
+
+/// Where a block sits in its object set: the object it belongs to, its level, and its block id
+/// among the blocks of that level (a `zbookmark_phys_t` without the object set)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bookmark {
+    pub object: u64,
+    pub level: u64,
+    pub blkid: u64,
+}
+
+/// Visit the block pointers of every object in `objset` that pass `filter`. Indirect blocks are
+/// always descended into, even when they are filtered out themselves, so a data-only traversal
+/// still reaches the data.
+pub fn traverse_objset<F>(reader: &mut zio::Reader,
+                          objset: &ObjectSetPhys,
+                          filter: ObjectFilter,
+                          visit: &mut F)
+                          -> zfs::Result<()>
+    where F: FnMut(&mut zio::Reader, &Bookmark, &BlockPtr)
+{
+    traverse_objset_since(reader, objset, 0, filter, visit)
+}
+
+/// Like `traverse_objset`, but only the blocks born after txg `min_txg` are visited. A block
+/// is never born before the blocks below it, so the subtrees of older blocks aren't read at all:
+/// the cost is in what changed since `min_txg`, not in the size of the object set.
+pub fn traverse_objset_since<F>(reader: &mut zio::Reader,
+                                objset: &ObjectSetPhys,
+                                min_txg: u64,
+                                filter: ObjectFilter,
+                                visit: &mut F)
+                                -> zfs::Result<()>
+    where F: FnMut(&mut zio::Reader, &Bookmark, &BlockPtr)
+{
+    traverse_dnode(reader,
+                   &objset.meta_dnode,
+                   META_DNODE_OBJECT,
+                   min_txg,
+                   filter,
+                   visit,
+                   true)
+}
+
+/// Visit the block pointers of object `object`, whose dnode is `dnode`. The object's own dnode
+/// isn't read, so it can be one that's not in an object set, like the meta dnode.
+pub fn traverse_object<F>(reader: &mut zio::Reader,
+                          dnode: &DNodePhys,
+                          object: u64,
+                          filter: ObjectFilter,
+                          visit: &mut F)
+                          -> zfs::Result<()>
+    where F: FnMut(&mut zio::Reader, &Bookmark, &BlockPtr)
+{
+    traverse_dnode(reader, dnode, object, 0, filter, visit, false)
+}
+
+/// The objects of `objset` with blocks born after txg `min_txg`: the ones written to since.
+// TODO: objects whose dnode alone changed, e.g. their bonus buffer, aren't found
+pub fn changed_objects(reader: &mut zio::Reader,
+                       objset: &ObjectSetPhys,
+                       min_txg: u64)
+                       -> zfs::Result<BTreeSet<u64>> {
+    let mut objects = BTreeSet::new();
+    try!(traverse_objset_since(reader,
+                               objset,
+                               min_txg,
+                               ObjectFilter::All,
+                               &mut |_, bookmark, _| {
+        if bookmark.object != META_DNODE_OBJECT {
+            objects.insert(bookmark.object);
+        }
+    }));
+    Ok(objects)
+}
+
+fn traverse_dnode<F>(reader: &mut zio::Reader,
+                     dnode: &DNodePhys,
+                     object: u64,
+                     min_txg: u64,
+                     filter: ObjectFilter,
+                     visit: &mut F,
+                     is_meta_dnode: bool)
+                     -> zfs::Result<()>
+    where F: FnMut(&mut zio::Reader, &Bookmark, &BlockPtr)
+{
+    // There's only room for 3 block pointers in a dnode
+    for i in 0..cmp::min(dnode.nblkptr as usize, 3) {
+        let bookmark = Bookmark {
+            object: object,
+            level: (dnode.nlevels as u64).saturating_sub(1),
+            blkid: i as u64,
+        };
+        try!(traverse_block(reader,
+                            &dnode.get_blockptr(i),
+                            &bookmark,
+                            dnode.epbs(),
+                            min_txg,
+                            filter,
+                            visit,
+                            is_meta_dnode));
+    }
+    Ok(())
+}
+
+fn traverse_block<F>(reader: &mut zio::Reader,
+                     block_ptr: &BlockPtr,
+                     bookmark: &Bookmark,
+                     epbs: u64,
+                     min_txg: u64,
+                     filter: ObjectFilter,
+                     visit: &mut F,
+                     is_meta_dnode: bool)
+                     -> zfs::Result<()>
+    where F: FnMut(&mut zio::Reader, &Bookmark, &BlockPtr)
+{
+    // Holes have never been written, or have been freed since
+    if block_ptr.is_hole() || block_ptr.birth_txg == 0 {
+        return Ok(());
+    }
+    // Neither it nor anything below it changed since `min_txg`
+    if block_ptr.birth_txg <= min_txg {
+        return Ok(());
+    }
+
+    if filter.matches(block_ptr) {
+        visit(reader, bookmark, block_ptr);
+    }
+
+    if block_ptr.level() > 0 {
+        let data = try!(reader.read_block(block_ptr));
+        for (i, chunk) in data.chunks(mem::size_of::<BlockPtr>()).enumerate() {
+            let child = try!(BlockPtr::from_bytes_order(chunk, block_ptr.data_order()));
+            let child_bookmark = Bookmark {
+                object: bookmark.object,
+                level: bookmark.level.saturating_sub(1),
+                blkid: (bookmark.blkid << epbs) + i as u64,
+            };
+            try!(traverse_block(reader,
+                                &child,
+                                &child_bookmark,
+                                epbs,
+                                min_txg,
+                                filter,
+                                visit,
+                                is_meta_dnode));
+        }
+    } else if is_meta_dnode {
+        // Level 0 blocks of the meta dnode are arrays of dnodes
+        let data = try!(reader.read_block(block_ptr));
+        let dnodes_per_block = (data.len() / mem::size_of::<DNodePhys>()) as u64;
+        let mut offset = 0;
+        while offset < data.len() {
+            let dnode = try!(DNodePhys::from_bytes_order(&data[offset..], block_ptr.data_order()));
+            if dnode.nblkptr > 0 {
+                let object = bookmark.blkid * dnodes_per_block +
+                             (offset / mem::size_of::<DNodePhys>()) as u64;
+                try!(traverse_dnode(reader, &dnode, object, min_txg, filter, visit, false));
+            }
+            // Large dnodes spill into the following slots
+            offset += (1 + dnode.extra_slots as usize) * mem::size_of::<DNodePhys>();
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dataset_filter() {
+    let filter = Filter::new().include("tank/home").exclude("tank/home/scratch");
+    assert!(filter.wants_dataset("tank/home"));
+    assert!(filter.wants_dataset("tank/home/alice"));
+    assert!(filter.wants_dataset("tank/home@yesterday"));
+    assert!(!filter.wants_dataset("tank/home/scratch"));
+    assert!(!filter.wants_dataset("tank/homework"));
+    assert!(!filter.wants_dataset(MOS_NAME));
+    assert!(Filter::new().wants_dataset(MOS_NAME));
+}
+
+#[test]
+fn test_traverse_since() {
+    use std::collections::BTreeMap;
+    use super::ZfsReader;
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_objset::{DirtyObjset, ObjectSet, ObjectSetType};
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode::ObjectType;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio_compress::ZIO_COMPRESS_OFF;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let mut objset = ObjectSet::create(ObjectSetType::Zfs);
+    // A file written in each of txgs 5, 6 and 7, and the first one again in 7
+    for (txg, object) in vec![(5, 1), (6, 2), (7, 3), (7, 1)] {
+        let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+        dnode.object_type = ObjectType::PlainFileContents as u8;
+        dnode.nblkptr = 1;
+        dnode.nlevels = 1;
+        dnode.indblkshift = 14;
+        dnode.data_blk_sz_sec = 1;
+        let mut dirty = DirtyObjset::default();
+        dirty.dnodes.insert(object, dnode);
+        let blocks: BTreeMap<u64, Vec<u8>> = vec![(0, vec![txg as u8; 512])]
+                                                 .into_iter()
+                                                 .collect();
+        dirty.blocks.insert(object, blocks);
+        objset.sync(&mut reader, &mut alloc, None, ZIO_COMPRESS_OFF, txg, dirty, &mut Vec::new())
+              .unwrap();
+    }
+
+    let changed = |reader: &mut ZfsReader, min_txg| {
+        changed_objects(&mut reader.zio, &objset.phys, min_txg)
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<u64>>()
+    };
+    assert_eq!(changed(&mut reader, 0), vec![1, 2, 3]);
+    assert_eq!(changed(&mut reader, 5), vec![1, 2, 3]);
+    assert_eq!(changed(&mut reader, 6), vec![1, 3]);
+    assert_eq!(changed(&mut reader, 7), vec![]);
+
+    // Nothing older than the threshold is visited, the dnode blocks included
+    let mut births = Vec::new();
+    traverse_objset_since(&mut reader.zio,
+                          &objset.phys,
+                          6,
+                          ObjectFilter::All,
+                          &mut |_, bookmark, block_ptr| {
+                              births.push((bookmark.object, block_ptr.birth_txg));
+                          })
+        .unwrap();
+    assert!(!births.is_empty());
+    assert!(births.iter().all(|&(_, birth)| birth == 7));
+    assert!(births.iter().any(|&(object, _)| object == META_DNODE_OBJECT));
+}
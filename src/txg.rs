@@ -2,4 +2,149 @@ pub const DEFER_SIZE: usize = 2;
 
 pub const TXG_SIZE: usize = 4;
 
+/// A txg's slot in the arrays kept per txg in flight: `txg & TXG_MASK`
+pub const TXG_MASK: u64 = TXG_SIZE as u64 - 1;
+
 pub const TXG_INITIAL: usize = TXG_SIZE;
+
+/// Where a txg is in its life. Changes go into the open txg; once it's closed, it quiesces until
+/// the changes still being made to it are done, then it's written out to the pool.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TxgState {
+    /// Not open yet
+    Future,
+    Open,
+    /// Closed to new changes, waiting for the last holds on it to be released
+    Quiescing,
+    Syncing,
+    /// On the disk
+    Synced,
+}
+
+/// The txgs of a pool in flight: at most one of each of open, quiescing and syncing. They move
+/// through the states in order, so a txg can be filling up while the one before it syncs.
+pub struct TxState {
+    open: u64,
+    quiescing: Option<u64>,
+    syncing: Option<u64>,
+    synced: u64,
+    /// The holds on each txg in flight, by `TXG_MASK` slot
+    holds: [u64; TXG_SIZE],
+}
+
+impl TxState {
+    /// Start after `synced`, the txg of the pool's active uberblock
+    pub fn new(synced: u64) -> Self {
+        TxState {
+            open: synced + 1,
+            quiescing: None,
+            syncing: None,
+            synced: synced,
+            holds: [0; TXG_SIZE],
+        }
+    }
+
+    pub fn open_txg(&self) -> u64 {
+        self.open
+    }
+
+    pub fn quiescing_txg(&self) -> Option<u64> {
+        self.quiescing
+    }
+
+    pub fn syncing_txg(&self) -> Option<u64> {
+        self.syncing
+    }
+
+    /// The newest txg on the disk
+    pub fn synced_txg(&self) -> u64 {
+        self.synced
+    }
+
+    pub fn state(&self, txg: u64) -> TxgState {
+        if txg <= self.synced {
+            TxgState::Synced
+        } else if Some(txg) == self.syncing {
+            TxgState::Syncing
+        } else if Some(txg) == self.quiescing {
+            TxgState::Quiescing
+        } else if txg == self.open {
+            TxgState::Open
+        } else {
+            TxgState::Future
+        }
+    }
+
+    /// Hold the open txg open for a change to go into it. Returns the txg, which can't quiesce
+    /// until the hold is released again (`rele`).
+    pub fn hold_open(&mut self) -> u64 {
+        self.holds[(self.open & TXG_MASK) as usize] += 1;
+        self.open
+    }
+
+    pub fn rele(&mut self, txg: u64) {
+        let holds = &mut self.holds[(txg & TXG_MASK) as usize];
+        assert!(*holds > 0, "txg {} isn't held", txg);
+        *holds -= 1;
+    }
+
+    pub fn holds(&self, txg: u64) -> u64 {
+        self.holds[(txg & TXG_MASK) as usize]
+    }
+
+    /// Close the open txg and open the next one. Returns the closed txg, or None if the one
+    /// closed before it is still quiescing.
+    pub fn quiesce(&mut self) -> Option<u64> {
+        if self.quiescing.is_some() {
+            return None;
+        }
+        self.quiescing = Some(self.open);
+        self.open += 1;
+        self.quiescing
+    }
+
+    /// Start syncing the quiescing txg, once nothing holds it any more and the txg before it is
+    /// done syncing. Returns the txg.
+    pub fn sync_start(&mut self) -> Option<u64> {
+        match self.quiescing {
+            Some(txg) if self.syncing.is_none() && self.holds(txg) == 0 => {
+                self.quiescing = None;
+                self.syncing = Some(txg);
+                Some(txg)
+            }
+            _ => None,
+        }
+    }
+
+    /// The syncing txg made it to the disk
+    pub fn sync_done(&mut self) {
+        if let Some(txg) = self.syncing.take() {
+            self.synced = txg;
+        }
+    }
+}
+
+#[test]
+fn test_tx_state() {
+    let mut tx = TxState::new(10);
+    assert_eq!(tx.state(11), TxgState::Open);
+    let txg = tx.hold_open();
+    assert_eq!(txg, 11);
+
+    // Held, so it can be closed but not synced
+    assert_eq!(tx.quiesce(), Some(11));
+    assert_eq!(tx.open_txg(), 12);
+    assert_eq!(tx.quiesce(), None);
+    assert_eq!(tx.sync_start(), None);
+    tx.rele(txg);
+    assert_eq!(tx.sync_start(), Some(11));
+    assert_eq!(tx.state(11), TxgState::Syncing);
+
+    // The next one quiesces while it syncs
+    assert_eq!(tx.quiesce(), Some(12));
+    assert_eq!(tx.sync_start(), None);
+    tx.sync_done();
+    assert_eq!((tx.synced_txg(), tx.state(11)), (11, TxgState::Synced));
+    assert_eq!(tx.sync_start(), Some(12));
+    assert_eq!(tx.state(14), TxgState::Future);
+}
@@ -0,0 +1,195 @@
+//! Normalization of UTF-8 names (`u8_textprep_str`), as ZAPs with normalization flags hash and
+//! match them: the directories of datasets created with `normalization` set or not fully case
+//! sensitive. Names are case folded first, then normalized, by Unicode 3.2.0 like ZFS.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+mod tables;
+
+/// Fold case to upper case, as case insensitive datasets do
+pub const U8_TEXTPREP_TOUPPER: u64 = 0x1;
+pub const U8_TEXTPREP_TOLOWER: u64 = 0x2;
+
+pub const U8_CANON_DECOMP: u64 = 0x10;
+pub const U8_COMPAT_DECOMP: u64 = 0x20;
+pub const U8_CANON_COMP: u64 = 0x40;
+
+/// The `normalization` property's forms
+pub const U8_TEXTPREP_NFD: u64 = U8_CANON_DECOMP;
+pub const U8_TEXTPREP_NFC: u64 = U8_CANON_DECOMP | U8_CANON_COMP;
+pub const U8_TEXTPREP_NFKD: u64 = U8_COMPAT_DECOMP;
+pub const U8_TEXTPREP_NFKC: u64 = U8_COMPAT_DECOMP | U8_CANON_COMP;
+
+// Hangul syllables are composed of a leading consonant, a vowel and maybe a trailing consonant
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = HANGUL_L_COUNT * HANGUL_N_COUNT;
+
+/// `name` case folded and normalized by `flags`, a ZAP's normalization flags. Folding to lower
+/// case and the compatibility forms (`formKC` and `formKD`) aren't supported.
+// TODO: compatibility decompositions
+pub fn normalize(name: &str, flags: u64) -> Result<String, String> {
+    let form = flags & !U8_TEXTPREP_TOUPPER;
+    if form != 0 && form != U8_TEXTPREP_NFD && form != U8_TEXTPREP_NFC {
+        return Err(format!("Unsupported normalization flags {:X}", flags));
+    }
+
+    let upper = flags & U8_TEXTPREP_TOUPPER != 0;
+    let folded = name.chars().map(|c| if upper { to_upper(c) } else { c });
+    if form == 0 {
+        return Ok(folded.collect());
+    }
+    let mut chars = Vec::with_capacity(name.len());
+    for c in folded {
+        decompose(c, &mut chars);
+    }
+    reorder(&mut chars);
+    if form & U8_CANON_COMP != 0 {
+        compose(&mut chars);
+    }
+    Ok(chars.into_iter().collect())
+}
+
+/// The simple upper case mapping of `c`, `c` itself if it has none
+pub fn to_upper(c: char) -> char {
+    match tables::UPPERCASE.binary_search_by_key(&c, |&(lower, _)| lower) {
+        Ok(i) => tables::UPPERCASE[i].1,
+        Err(_) => c,
+    }
+}
+
+/// The canonical combining class of `c`, 0 for starters
+pub fn combining_class(c: char) -> u8 {
+    let i = match tables::COMBINING_CLASS.binary_search_by_key(&c, |&(start, _, _)| start) {
+        Ok(i) => i,
+        Err(0) => return 0,
+        Err(i) => i - 1,
+    };
+    let (_, end, class) = tables::COMBINING_CLASS[i];
+    if c <= end { class } else { 0 }
+}
+
+/// Push the full canonical decomposition of `c`
+fn decompose(c: char, chars: &mut Vec<char>) {
+    let s = c as u32;
+    if s >= HANGUL_S_BASE && s < HANGUL_S_BASE + HANGUL_S_COUNT {
+        let index = s - HANGUL_S_BASE;
+        let jamo = |c| ::std::char::from_u32(c).unwrap();
+        chars.push(jamo(HANGUL_L_BASE + index / HANGUL_N_COUNT));
+        chars.push(jamo(HANGUL_V_BASE + index % HANGUL_N_COUNT / HANGUL_T_COUNT));
+        if index % HANGUL_T_COUNT != 0 {
+            chars.push(jamo(HANGUL_T_BASE + index % HANGUL_T_COUNT));
+        }
+        return;
+    }
+    match tables::CANONICAL_DECOMPOSITION.binary_search_by_key(&c, |&(c, _)| c) {
+        Ok(i) => chars.extend(tables::CANONICAL_DECOMPOSITION[i].1.chars()),
+        Err(_) => chars.push(c),
+    }
+}
+
+/// Put the runs of combining characters in canonical order: by combining class, keeping the
+/// order of those of the same class
+fn reorder(chars: &mut [char]) {
+    let mut start = 0;
+    while start < chars.len() {
+        if combining_class(chars[start]) == 0 {
+            start += 1;
+            continue;
+        }
+        let end = chars[start..]
+                      .iter()
+                      .position(|&c| combining_class(c) == 0)
+                      .map_or(chars.len(), |len| start + len);
+        chars[start..end].sort_by_key(|&c| combining_class(c));
+        start = end;
+    }
+}
+
+/// The primary composite of `a` followed by `b`, if there is one
+fn compose_pair(a: char, b: char) -> Option<char> {
+    let (l, v) = (a as u32, b as u32);
+    if l >= HANGUL_L_BASE && l < HANGUL_L_BASE + HANGUL_L_COUNT && v >= HANGUL_V_BASE &&
+       v < HANGUL_V_BASE + HANGUL_V_COUNT {
+        let s = HANGUL_S_BASE + ((l - HANGUL_L_BASE) * HANGUL_V_COUNT + v - HANGUL_V_BASE) *
+                                HANGUL_T_COUNT;
+        return ::std::char::from_u32(s);
+    }
+    let (s, t) = (a as u32, b as u32);
+    if s >= HANGUL_S_BASE && s < HANGUL_S_BASE + HANGUL_S_COUNT &&
+       (s - HANGUL_S_BASE) % HANGUL_T_COUNT == 0 && t > HANGUL_T_BASE &&
+       t < HANGUL_T_BASE + HANGUL_T_COUNT {
+        return ::std::char::from_u32(s + t - HANGUL_T_BASE);
+    }
+    tables::CANONICAL_COMPOSITION.binary_search_by_key(&(a, b), |&(pair, _)| pair)
+                                 .ok()
+                                 .map(|i| tables::CANONICAL_COMPOSITION[i].1)
+}
+
+/// Compose canonically decomposed `chars` in place: each character is combined with the last
+/// starter before it, unless a character of the same or a higher class is in between
+fn compose(chars: &mut Vec<char>) {
+    let mut starter: Option<usize> = None;
+    let mut last_class = 0;
+    let mut out = 0;
+    for i in 0..chars.len() {
+        let c = chars[i];
+        let class = combining_class(c);
+        if let Some(s) = starter {
+            // Blocked by a character in between of the same class, or a starter
+            let blocked = out > s + 1 && (last_class == 0 || last_class >= class);
+            if !blocked {
+                if let Some(composite) = compose_pair(chars[s], c) {
+                    chars[s] = composite;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(out);
+        }
+        last_class = class;
+        chars[out] = c;
+        out += 1;
+    }
+    chars.truncate(out);
+}
+
+#[test]
+fn test_normalize() {
+    // "é" composed, and decomposed
+    let (nfc, nfd) = ("\u{e9}t\u{e9}", "e\u{301}te\u{301}");
+    assert_eq!(normalize(nfc, U8_TEXTPREP_NFD).unwrap(), nfd);
+    assert_eq!(normalize(nfd, U8_TEXTPREP_NFC).unwrap(), nfc);
+    assert_eq!(normalize(nfd, 0).unwrap(), nfd);
+    assert_eq!(normalize(nfc, U8_TEXTPREP_TOUPPER | U8_TEXTPREP_NFD).unwrap(),
+               "E\u{301}TE\u{301}");
+    assert_eq!(normalize("Stra\u{df}e", U8_TEXTPREP_TOUPPER).unwrap(), "STRA\u{df}E");
+
+    // Combining marks are put in order, and composed past those of another class
+    assert_eq!(normalize("a\u{301}\u{323}", U8_TEXTPREP_NFD).unwrap(), "a\u{323}\u{301}");
+    assert_eq!(normalize("a\u{323}\u{302}", U8_TEXTPREP_NFC).unwrap(), "\u{1ead}");
+    assert_eq!(normalize("a\u{301}\u{301}", U8_TEXTPREP_NFC).unwrap(), "\u{e1}\u{301}");
+    // Composition exclusions and singletons stay decomposed
+    assert_eq!(normalize("\u{212b}", U8_TEXTPREP_NFC).unwrap(), "\u{c5}");
+    assert_eq!(normalize("\u{958}", U8_TEXTPREP_NFC).unwrap(), "\u{915}\u{93c}");
+
+    // Hangul, with and without a trailing consonant
+    assert_eq!(normalize("\u{d55c}\u{ac00}", U8_TEXTPREP_NFD).unwrap(),
+               "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1161}");
+    assert_eq!(normalize("\u{1112}\u{1161}\u{11ab}\u{1100}\u{1161}", U8_TEXTPREP_NFC).unwrap(),
+               "\u{d55c}\u{ac00}");
+
+    // Case is folded by the simple mappings of Unicode 3.2, which has no Georgian capitals
+    assert_eq!(to_upper('\u{3c3}'), '\u{3a3}');
+    assert_eq!(to_upper('\u{10d0}'), '\u{10d0}');
+    assert_eq!(combining_class('\u{301}'), 230);
+    assert!(normalize("x", U8_TEXTPREP_NFKC).is_err());
+}
@@ -1,11 +1,22 @@
-use std::{mem, ptr};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::mem;
 
-use super::from_bytes::FromBytes;
+use super::from_bytes::{ByteOrder, DecodeError, Decoder, FromBytes};
 use super::block_ptr::BlockPtr;
+use super::version::PoolVersion;
+use super::zfs;
 
 const UBERBLOCK_MAGIC: u64 = 0x00bab10c; // oo-ba-bloc!
 pub const UBERBLOCK_SHIFT: u64 = 10;         // up to 1K
 
+/// Marks uberblocks written by a host with multihost protection (MMP) on
+pub const MMP_MAGIC: u64 = 0xa11cea11;
+// Which of the fields packed in `mmp_config` are there
+const MMP_INTERVAL_VALID_BIT: u64 = 0x01;
+const MMP_SEQ_VALID_BIT: u64 = 0x02;
+const MMP_FAIL_INT_VALID_BIT: u64 = 0x04;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
 pub struct Uberblock {
@@ -15,31 +26,230 @@ pub struct Uberblock {
     pub guid_sum: u64,
     pub timestamp: u64,
     pub rootbp: BlockPtr,
+    pub software_version: u64,
+    pub mmp_magic: u64,
+    pub mmp_delay: u64,
+    pub mmp_config: u64,
+    pub checkpoint_txg: u64,
+    /// How far the raidz expansion in progress got, and the state of its scratch area
+    pub raidz_reflow_info: u64,
 }
 
 impl Uberblock {
-    pub fn magic_little() -> u64 {
-        UBERBLOCK_MAGIC.swap_bytes()
+    /// The uberblock of a new pool of version `version`, whose vdevs' guids add up to
+    /// `guid_sum`. It points at no MOS until the pool's first txg is synced.
+    pub fn new(version: PoolVersion, guid_sum: u64) -> Self {
+        Uberblock {
+            magic: UBERBLOCK_MAGIC,
+            version: version.as_u64(),
+            txg: 0,
+            guid_sum: guid_sum,
+            timestamp: 0,
+            rootbp: BlockPtr::from_bytes(&[0; 128]).unwrap(),
+            software_version: 0,
+            mmp_magic: 0,
+            mmp_delay: 0,
+            mmp_config: 0,
+            checkpoint_txg: 0,
+            raidz_reflow_info: 0,
+        }
+    }
+
+    /// Decode an uberblock kept as integers, its fields in order, like the one of the pool's
+    /// checkpoint in the MOS
+    pub fn from_words(words: &[u64]) -> zfs::Result<Self> {
+        let mut bytes = Vec::with_capacity(words.len() * 8);
+        for word in words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        Uberblock::from_bytes(&bytes)
+    }
+
+    /// The byte order of the pool, going by how the magic number reads
+    pub fn byte_order(data: &[u8]) -> Option<ByteOrder> {
+        match u64::from_bytes(data) {
+            Ok(magic) if magic == UBERBLOCK_MAGIC => Some(ByteOrder::Little),
+            Ok(magic) if magic == UBERBLOCK_MAGIC.swap_bytes() => Some(ByteOrder::Big),
+            _ => None,
+        }
+    }
+
+    /// The pool's version. Only uberblocks of a version there is get decoded.
+    pub fn pool_version(&self) -> PoolVersion {
+        PoolVersion::from_u64(self.version).expect("uberblock of an unknown version")
+    }
+
+    /// Whether the MMP fields are there: uberblocks from before MMP, or written with it off
+    /// by older hosts, don't have them
+    pub fn mmp_valid(&self) -> bool {
+        self.mmp_magic == MMP_MAGIC
+    }
+
+    /// Whether the host that wrote the uberblock had multihost protection on. It keeps
+    /// writing uberblocks, MMP writes, for as long as it has the pool imported.
+    pub fn multihost(&self) -> bool {
+        self.mmp_valid() && self.mmp_delay != 0
+    }
+
+    /// The interval between the MMP writes of the host, in milliseconds
+    pub fn mmp_interval(&self) -> Option<u64> {
+        self.mmp_field(MMP_INTERVAL_VALID_BIT).map(|config| (config >> 8) & 0xFF_FFFF)
+    }
+
+    /// How many MMP writes there have been with the same txg and timestamp. Uberblocks written
+    /// within the same second are told apart by it.
+    pub fn mmp_seq(&self) -> Option<u64> {
+        self.mmp_field(MMP_SEQ_VALID_BIT).map(|config| (config >> 32) & 0xFFFF)
+    }
+
+    /// How many MMP write intervals can go by without one before the host suspends the pool
+    pub fn mmp_fail_intervals(&self) -> Option<u64> {
+        self.mmp_field(MMP_FAIL_INT_VALID_BIT).map(|config| config >> 48)
+    }
+
+    fn mmp_field(&self, valid_bit: u64) -> Option<u64> {
+        let config = self.mmp_config;
+        if self.mmp_valid() && config & valid_bit != 0 {
+            Some(config)
+        } else {
+            None
+        }
     }
 
-    pub fn magic_big() -> u64 {
-        UBERBLOCK_MAGIC
+    /// The offset on the expanding raidz vdev below which its blocks have been moved to where
+    /// they go with the new child (`RRSS_GET_OFFSET`). Only means anything while an expansion
+    /// is in progress.
+    pub fn raidz_reflow_offset(&self) -> u64 {
+        (self.raidz_reflow_info & ((1 << 55) - 1)) << 9
     }
 }
 
 impl FromBytes for Uberblock {
-    fn from_bytes(data: &[u8]) -> Result<Self, &str> {
-        if data.len() >= mem::size_of::<Uberblock>() {
-            let uberblock = unsafe { ptr::read(data.as_ptr() as *const Uberblock) };
-            if uberblock.magic == Uberblock::magic_little() {
-                Ok(uberblock)
-            } else if uberblock.magic == Uberblock::magic_big() {
-                Ok(uberblock)
-            } else {
-                Err("Error: Invalid uberblock magic number")
+    fn decode(decoder: &mut Decoder) -> Self {
+        let magic = decoder.u64();
+        let version = decoder.u64();
+        let txg = decoder.u64();
+        let guid_sum = decoder.u64();
+        let timestamp = decoder.u64();
+        let rootbp = BlockPtr::decode(decoder);
+        let mut tail = [0; 6];
+        decoder.fill_u64(&mut tail);
+        Uberblock {
+            magic: magic,
+            version: version,
+            txg: txg,
+            guid_sum: guid_sum,
+            timestamp: timestamp,
+            rootbp: rootbp,
+            software_version: tail[0],
+            mmp_magic: tail[1],
+            mmp_delay: tail[2],
+            mmp_config: tail[3],
+            checkpoint_txg: tail[4],
+            raidz_reflow_info: tail[5],
+        }
+    }
+
+    fn validate(&self) -> Result<(), DecodeError> {
+        if self.magic != UBERBLOCK_MAGIC {
+            return Err(DecodeError::BadMagic(self.magic));
+        }
+        let version = self.version;
+        if PoolVersion::from_u64(version).is_none() {
+            return Err(DecodeError::BadVersion(version));
+        }
+        self.rootbp.validate()
+    }
+
+    /// Uberblocks are written in the byte order of the host, which the magic number gives away
+    fn try_from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        match Uberblock::byte_order(data) {
+            Some(order) => Uberblock::try_from_bytes_order(data, order),
+            None if data.len() < mem::size_of::<Uberblock>() => {
+                Err(DecodeError::TooShort {
+                    needed: mem::size_of::<Uberblock>(),
+                    got: data.len(),
+                })
             }
-        } else {
-            Err("Not enough bytes to read Uberblock.")
+            None => Err(DecodeError::BadMagic(u64::from_bytes(data).unwrap())),
         }
     }
 }
+
+#[test]
+fn test_big_endian_uberblock() {
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0, 0, 0, 0, 0, 0xBA, 0xB1, 0x0C]);
+    bytes[14..16].copy_from_slice(&[0x13, 0x88]); // version 5000
+    bytes[23] = 42; // txg
+    assert_eq!(Uberblock::byte_order(&bytes), Some(ByteOrder::Big));
+    let uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    assert_eq!((uberblock.magic, uberblock.version, uberblock.txg),
+               (UBERBLOCK_MAGIC, 5000, 42));
+
+    bytes[..8].copy_from_slice(&[0x0C, 0xB1, 0xBA, 0, 0, 0, 0, 0]);
+    assert_eq!(Uberblock::byte_order(&bytes), Some(ByteOrder::Little));
+    bytes[0] = 0;
+    assert!(Uberblock::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn test_invalid_uberblocks() {
+    use super::zfs;
+
+    let mut bytes = [0u8; 1024];
+    bytes[..8].copy_from_slice(&[0x0C, 0xB1, 0xBA, 0, 0, 0, 0, 0]);
+    bytes[8] = 28;
+    assert!(Uberblock::try_from_bytes(&bytes).is_ok());
+
+    bytes[8] = 29;
+    assert_eq!(Uberblock::try_from_bytes(&bytes).unwrap_err(), DecodeError::BadVersion(29));
+    bytes[8..10].copy_from_slice(&[0x88, 0x13]); // 5000
+    assert!(Uberblock::try_from_bytes(&bytes).is_ok());
+
+    // The root block pointer is checked too: checksum 99
+    bytes[40 + 53] = 99;
+    assert_eq!(Uberblock::try_from_bytes(&bytes).unwrap_err(), DecodeError::BadChecksum(99));
+
+    bytes[0] = 0x0D;
+    assert_eq!(Uberblock::try_from_bytes(&bytes).unwrap_err(),
+               DecodeError::BadMagic(0xbab10d));
+    assert_eq!(Uberblock::from_bytes(&bytes[..100]).err(),
+               Some(zfs::Error::Decode(DecodeError::TooShort {
+                   needed: 216,
+                   got: 100,
+               })));
+}
+
+#[test]
+fn test_uberblock_from_words() {
+    // The magic, version, txg, guid sum and timestamp, a hole for the root block pointer, then
+    // the software version, the MMP fields and the checkpoint txg
+    let mut words = vec![UBERBLOCK_MAGIC, 5000, 42, 7, 1000];
+    words.extend_from_slice(&[0; 16]);
+    words.extend_from_slice(&[0, 0, 0, 0, 30, 0]);
+    let uberblock = Uberblock::from_words(&words).unwrap();
+    assert_eq!((uberblock.txg, uberblock.guid_sum, uberblock.checkpoint_txg), (42, 7, 30));
+    assert!(Uberblock::from_words(&words[..20]).is_err());
+    words[1] = 29;
+    assert!(Uberblock::from_words(&words).is_err());
+}
+
+#[test]
+fn test_mmp_fields() {
+    let mut bytes = [0u8; 1024];
+    bytes[..9].copy_from_slice(&[0x0C, 0xB1, 0xBA, 0, 0, 0, 0, 0, 28]);
+    let mut uberblock = Uberblock::from_bytes(&bytes).unwrap();
+    assert!(!uberblock.mmp_valid());
+    uberblock.mmp_config = 0x000A_0003_0003_E807;
+    assert_eq!(uberblock.mmp_interval(), None);
+
+    uberblock.mmp_magic = MMP_MAGIC;
+    assert!(!uberblock.multihost());
+    uberblock.mmp_delay = 1_000_000;
+    assert!(uberblock.multihost());
+    assert_eq!((uberblock.mmp_interval(), uberblock.mmp_seq(), uberblock.mmp_fail_intervals()),
+               (Some(1000), Some(3), Some(10)));
+    uberblock.mmp_config &= !MMP_SEQ_VALID_BIT;
+    assert_eq!(uberblock.mmp_seq(), None);
+}
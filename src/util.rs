@@ -8,10 +8,15 @@ fn p2_cross(x: u64, y: u64, align: u64) -> bool {
     x ^ y > align - 1
 }
 
-fn p2_round_up(x: u64, align: u64) -> u64 {
+pub fn p2_round_up(x: u64, align: u64) -> u64 {
     ((x - 1) | (align - 1)) + 1
 }
 
+/// Round `x` up to a multiple of `align`, which doesn't have to be a power of two
+pub fn round_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) / align * align
+}
+
 fn p2_boundary(off: u64, len: u64, align: u64) -> bool {
     (off ^ (off + len - 1)) > (align - 1)
 }
@@ -32,7 +37,7 @@ fn is_p2(x: u64) -> bool {
     x & (x - 1) == 0
 }
 
-fn is_p2_aligned(v: u64, a: u64) -> bool {
+pub fn is_p2_aligned(v: u64, a: u64) -> bool {
     v & (a - 1) == 0
 }
 
@@ -1,13 +1,20 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 use std::{cmp, mem};
 use std::rc::Rc;
 
+use super::ZfsReader;
+use super::dmu;
 use super::dmu_objset::ObjectSet;
-use super::from_bytes::FromBytes;
+use super::from_bytes::{Decoder, FromBytes};
 use super::metaslab::{Metaslab, MetaslabClass, MetaslabGroup};
 use super::nvpair::{NvList, NvValue};
+use super::spa;
 use super::uberblock;
 use super::util;
 use super::vdev_file::VdevFile;
+use super::vdev_mirror::VdevMirror;
+use super::vdev_root::VdevRoot;
 use super::zfs;
 
 #[repr(packed)]
@@ -18,7 +25,21 @@ pub struct VdevLabel {
     pub uberblocks: [u8; 128 * 1024],
 }
 
-impl FromBytes for VdevLabel {}
+impl FromBytes for VdevLabel {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut label = VdevLabel {
+            blank: [0; 8 * 1024],
+            boot_header: [0; 8 * 1024],
+            nv_pairs: [0; 112 * 1024],
+            uberblocks: [0; 128 * 1024],
+        };
+        decoder.fill(&mut label.blank);
+        decoder.fill(&mut label.boot_header);
+        decoder.fill(&mut label.nv_pairs);
+        decoder.fill(&mut label.uberblocks);
+        label
+    }
+}
 
 /// /////////////////////////////////////////////////////////////////////////////////////////////////
 
@@ -90,6 +111,27 @@ fn load_ops(vdev_type: &str, nv: &NvList) -> zfs::Result<VdevOps> {
                 is_leaf: true,
             })
         }
+        "file" => {
+            Ok(VdevOps {
+                ops: Box::new(try!(VdevFile::load(nv))),
+                vdev_type: VdevType::File,
+                is_leaf: true,
+            })
+        }
+        "mirror" => {
+            Ok(VdevOps {
+                ops: Box::new(try!(VdevMirror::load(nv))),
+                vdev_type: VdevType::Mirror,
+                is_leaf: false,
+            })
+        }
+        "root" => {
+            Ok(VdevOps {
+                ops: Box::new(VdevRoot),
+                vdev_type: VdevType::Root,
+                is_leaf: false,
+            })
+        }
         _ => Err(zfs::Error::Invalid),
     }
 }
@@ -191,10 +233,7 @@ impl Vdev {
                create_txg: u64,
                vdev_top: Option<Top>)
                -> Self {
-        let guid = guid.unwrap_or_else(|| {
-            // TODO: generate a guid
-            0
-        });
+        let guid = guid.unwrap_or_else(spa::generate_guid);
 
         // TODO vdev_queue_init
 
@@ -248,10 +287,12 @@ impl Vdev {
             _ => None,
         };
 
-        let create_txg = try!(nv.get("create_txg").ok_or(zfs::Error::Invalid));
-        let ashift = try!(nv.get("ashift").ok_or(zfs::Error::Invalid));
+        // Only top-level vdevs record these, the root and interior vdevs don't
+        let create_txg = nv.get("create_txg").unwrap_or(0);
+        let ashift = nv.get("ashift").unwrap_or(0);
 
         let mut vdev_top = None;
+        let mut asize = 0;
 
         // If we're a top-level vdev, try to load the allocation parameters,
         // create the metaslab group, and create the vdev::Top
@@ -262,7 +303,8 @@ impl Vdev {
                 if alloc_type == AllocType::Load || alloc_type == AllocType::Split {
                     ms_array = try!(nv.get("metaslab_array").ok_or(zfs::Error::Invalid));
                     ms_shift = try!(nv.get("metaslab_shift").ok_or(zfs::Error::Invalid));
-                    // let asize = try!(nv.get("asize").ok_or(zfs::Error::Invalid));
+                    // Until the device is opened, the size it had when the config was written
+                    asize = nv.get("asize").unwrap_or(0);
                     // let removing = try!(nv.get("removing").ok_or(zfs::Error::Invalid));
                 }
 
@@ -279,6 +321,7 @@ impl Vdev {
 
         let mut vdev = Self::new(id, guid, ashift, ops, create_txg, vdev_top);
         vdev.parent = parent;
+        vdev.asize = asize;
 
         Ok(vdev)
     }
@@ -287,7 +330,14 @@ impl Vdev {
         Ok(())
     }
 
-    fn metaslab_init(&mut self, mos: &mut ObjectSet, txg: u64) -> zfs::Result<()> {
+    /// Set up the metaslabs of a top-level vdev. Their space maps aren't read yet, only the
+    /// numbers of their objects.
+    fn metaslab_init(&mut self,
+                     reader: &mut ZfsReader,
+                     mos: &ObjectSet,
+                     txg: u64)
+                     -> zfs::Result<()> {
+        let ashift = self.ashift;
         // We assume this is a top-level vdev
         let ref mut top = try!(self.top.as_mut().ok_or(zfs::Error::Invalid));
 
@@ -310,16 +360,23 @@ impl Vdev {
 
         assert!(old_count <= new_count);
 
-        for m in old_count..new_count {
-            let object: u64 = 0;
-
-            if txg == 0 {
-                // try!(dmu_read(mos, top.ms_array, m * mem::size_of::<u64>(),
-                // mem::size_of::<u64>(), &object, DMU_READ_PREFETCH));
-            }
+        // The metaslab array holds the space map object of every metaslab
+        let objects = if txg == 0 {
+            let len = (new_count - old_count) * mem::size_of::<u64>();
+            try!(dmu::read_object(reader,
+                                  mos,
+                                  top.ms_array,
+                                  (old_count * mem::size_of::<u64>()) as u64,
+                                  len)
+                     .map_err(|_| zfs::Error::Invalid))
+        } else {
+            vec![0; (new_count - old_count) * mem::size_of::<u64>()]
+        };
 
-            // let metaslab = try!(Metaslab::init(mos, self, m as u64, object, txg));
-            // top.metaslabs.push(metaslab);
+        for (m, object) in (old_count..new_count).zip(objects.chunks(mem::size_of::<u64>())) {
+            let object = try!(u64::from_bytes(object));
+            let metaslab = try!(Metaslab::init(top, ashift, m as u64, object, txg));
+            top.metaslabs.push(metaslab);
         }
 
         // if (txg == 0)
@@ -387,9 +444,17 @@ impl Vdev {
     // txg_list_add(&self.spa.vdev_txg_list, self, txg);
     // }
 
+    pub fn guid(&self) -> u64 {
+        self.guid
+    }
+
+    /// Sum of the guids of this vdev and all of its descendants
+    pub fn guid_sum(&self) -> u64 {
+        self.guid_sum
+    }
+
     pub fn uberblock_shift(&self) -> u64 {
-        cmp::min(cmp::max(self.ashift, uberblock::UBERBLOCK_SHIFT),
-                 MAX_UBERBLOCK_SHIFT)
+        uberblock_shift(self.ashift)
     }
 
     pub fn uberblock_count(&self) -> u64 {
@@ -455,20 +520,65 @@ impl Tree {
         });
 
         if let Some(parent) = parent {
-            parent.get_mut(self).guid_sum += guid;
             parent.get_mut(self).children.push(index);
         }
 
+        // Every ancestor's guid_sum covers its whole subtree
+        let mut ancestor = parent;
+        while let Some(a) = ancestor {
+            let vdev = a.get_mut(self);
+            vdev.guid_sum = vdev.guid_sum.wrapping_add(guid);
+            ancestor = vdev.parent;
+        }
+
         index
     }
 
+    fn find(&self, guid: u64) -> Option<TreeIndex> {
+        self.nodes
+            .iter()
+            .position(|node| node.as_ref().map(|vdev| vdev.guid) == Some(guid))
+            .map(TreeIndex)
+    }
+
+    /// Set the allocatable size of the vdev with the given guid, e.g. after its device grew.
+    /// Returns false if there's no such vdev.
+    pub fn set_asize(&mut self, guid: u64, asize: u64) -> bool {
+        match self.find(guid) {
+            Some(index) => {
+                index.get_mut(self).asize = asize;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change the guid of the vdev whose guid is `old`, keeping the guid sums of it and its
+    /// ancestors right. Returns false if there's no such vdev.
+    pub fn set_guid(&mut self, old: u64, new: u64) -> bool {
+        let index = match self.find(old) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        index.get_mut(self).guid = new;
+        let mut ancestor = Some(index);
+        while let Some(a) = ancestor {
+            let vdev = a.get_mut(self);
+            vdev.guid_sum = vdev.guid_sum.wrapping_sub(old).wrapping_add(new);
+            ancestor = vdev.parent;
+        }
+        true
+    }
+
     pub fn parse(&mut self,
                  normal_class: &Rc<MetaslabClass>,
                  nv: &NvList,
+                 id: u64,
                  parent: Option<TreeIndex>,
                  alloc_type: AllocType)
                  -> zfs::Result<TreeIndex> {
-        let vdev = try!(Vdev::load(normal_class, nv, 0, parent, self, alloc_type));
+        let vdev = try!(Vdev::load(normal_class, nv, id, parent, self, alloc_type));
         let index = self.add(vdev);
 
         // Done parsing if this is a leaf
@@ -479,14 +589,14 @@ impl Tree {
         // Get the vdev's children
         let children: &Vec<NvList> = try!(nv.get("children").ok_or(zfs::Error::Invalid));
 
-        for child in children {
-            self.parse(normal_class, child, Some(index), alloc_type);
+        for (id, child) in children.iter().enumerate() {
+            try!(self.parse(normal_class, child, id as u64, Some(index), alloc_type));
         }
 
         Ok(index)
     }
 
-    pub fn load(&mut self, mos: &mut ObjectSet, root: TreeIndex) {
+    pub fn load(&mut self, reader: &mut ZfsReader, mos: &ObjectSet, root: TreeIndex) {
         // We use an iterative solution because of borrowing issues
         let mut queue = vec![root];
 
@@ -502,7 +612,8 @@ impl Tree {
             // if let Some(ref top) = vdev.top {
             if vdev.top.is_some() {
                 // if !top.is_hole {
-                if vdev.ashift == 0 || vdev.asize == 0 || vdev.metaslab_init(mos, 0).is_err() {
+                if vdev.ashift == 0 || vdev.asize == 0 ||
+                   vdev.metaslab_init(reader, mos, 0).is_err() {
                     // TODO: Set vdev state to error
                 }
                 // }
@@ -528,3 +639,36 @@ const UBERBLOCK_RING: u64 = 128 << 10;
 
 // The largest uberblock we support is 8k.
 const MAX_UBERBLOCK_SHIFT: u64 = 13;
+
+/// Size of the uberblock ring slots on a vdev of allocation shift `ashift`, as a shift
+pub fn uberblock_shift(ashift: u64) -> u64 {
+    cmp::min(cmp::max(ashift, uberblock::UBERBLOCK_SHIFT), MAX_UBERBLOCK_SHIFT)
+}
+
+// Logical sector sizes we know about: 512 byte and 4K native
+const MIN_SECTOR_SHIFT: u64 = 9;
+const MAX_SECTOR_SHIFT: u64 = 12;
+
+/// Guess the logical sector size (as a shift) of a device of `device_size` bytes. ZFS never picks
+/// an ashift below the logical sector size, so that's an upper bound, and a device is always a
+/// whole number of sectors. Without an ashift (e.g. a blank device) 512 byte sectors are assumed.
+/// Guessing 4K on a 512e device only costs some extra IO, as 4K IO is valid there too.
+pub fn logical_sector_shift(device_size: u64, ashift: Option<u64>) -> u64 {
+    let mut shift = cmp::max(cmp::min(ashift.unwrap_or(MIN_SECTOR_SHIFT), MAX_SECTOR_SHIFT),
+                             MIN_SECTOR_SHIFT);
+    while shift > MIN_SECTOR_SHIFT && device_size % (1 << shift) != 0 {
+        shift -= 1;
+    }
+    shift
+}
+
+#[test]
+fn test_logical_sector_shift() {
+    assert_eq!(logical_sector_shift(64 << 20, None), 9);
+    assert_eq!(logical_sector_shift(64 << 20, Some(9)), 9);
+    assert_eq!(logical_sector_shift(64 << 20, Some(12)), 12);
+    // ashift 13 SSDs still have 4K logical sectors at most
+    assert_eq!(logical_sector_shift(64 << 20, Some(13)), 12);
+    // Can't be 4Kn if the image isn't a whole number of 4K sectors
+    assert_eq!(logical_sector_shift((64 << 20) + 512, Some(12)), 9);
+}
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::vdev_io::{MemVdev, Vdev};
+
+/// Most writes between two flushes for which every subset of them is tried. Past that, only
+/// the writes in the order they were made are: there would be too many.
+pub const CRASH_MAX_REORDERED_WRITES: usize = 10;
+
+enum Event {
+    Write(u64, Vec<u8>),
+    Flush,
+}
+
+/// A device in memory that records the writes and flushes made to it, to find out what a power
+/// loss at any point could have left on the disk. Clones share the device and the record.
+#[derive(Clone)]
+pub struct CrashVdev {
+    /// The device as it was before the first write
+    image: Arc<Vec<u8>>,
+    disk: MemVdev,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl CrashVdev {
+    pub fn new(image: Vec<u8>) -> Self {
+        CrashVdev {
+            disk: MemVdev::new(image.clone()),
+            image: Arc::new(image),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// How many writes were made to the device
+    pub fn writes(&self) -> usize {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| match **event {
+                Event::Write(..) => true,
+                Event::Flush => false,
+            })
+            .count()
+    }
+
+    /// Every state a crash could have left the device in, from before the first write to after
+    /// the last one. The writes before a flush are all on disk, any of those since the last one
+    /// may be missing; each write is taken to make it to the disk whole or not at all.
+    pub fn crash_states(&self) -> CrashStates {
+        let mut epochs = VecDeque::new();
+        let mut epoch = Vec::new();
+        for event in self.events.lock().unwrap().iter() {
+            match *event {
+                Event::Write(offset, ref data) => epoch.push((offset, data.clone())),
+                Event::Flush => epochs.push_back(::std::mem::replace(&mut epoch, Vec::new())),
+            }
+        }
+        epochs.push_back(epoch);
+        CrashStates {
+            disk: (*self.image).clone(),
+            epochs: epochs,
+            next: None,
+        }
+    }
+}
+
+impl Vdev for CrashVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.disk.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.events.lock().unwrap().push(Event::Write(offset, data.to_vec()));
+        self.disk.write_at(offset, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.events.lock().unwrap().push(Event::Flush);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.disk.size()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The states of a `CrashVdev` a crash could leave, as devices of their own
+pub struct CrashStates {
+    /// The device with the writes of every epoch before the current one
+    disk: Vec<u8>,
+    /// The writes between flushes, oldest first
+    epochs: VecDeque<Vec<(u64, Vec<u8>)>>,
+    /// The state of the current epoch to return next, None before the very first state
+    next: Option<u64>,
+}
+
+fn apply(disk: &mut Vec<u8>, offset: u64, data: &[u8]) {
+    let end = offset as usize + data.len();
+    if disk.len() < end {
+        disk.resize(end, 0);
+    }
+    disk[offset as usize..end].copy_from_slice(data);
+}
+
+impl Iterator for CrashStates {
+    type Item = MemVdev;
+
+    fn next(&mut self) -> Option<MemVdev> {
+        let mut next = match self.next {
+            Some(next) => next,
+            None => {
+                self.next = Some(1);
+                return Some(MemVdev::new(self.disk.clone()));
+            }
+        };
+        loop {
+            let count = match self.epochs.front() {
+                Some(epoch) => epoch.len(),
+                None => return None,
+            };
+            // States are numbered from 1: none of the epoch's writes is the state the previous
+            // epoch ended with. With few writes, the state is the set of them that made it.
+            let reordered = count <= CRASH_MAX_REORDERED_WRITES;
+            let states = if reordered { (1 << count) - 1 } else { count as u64 };
+            if next <= states {
+                self.next = Some(next + 1);
+                let mut disk = self.disk.clone();
+                for (i, &(offset, ref data)) in self.epochs[0].iter().enumerate() {
+                    let written = if reordered { next >> i & 1 == 1 } else { (i as u64) < next };
+                    if written {
+                        apply(&mut disk, offset, data);
+                    }
+                }
+                return Some(MemVdev::new(disk));
+            }
+
+            // On to the next epoch, with all of this one on disk
+            for (offset, data) in self.epochs.pop_front().unwrap() {
+                apply(&mut self.disk, offset, &data);
+            }
+            next = 1;
+        }
+    }
+}
+
+#[test]
+fn test_crash_states() {
+    use super::block_ptr::BlockPtr;
+    use super::from_bytes::FromBytes;
+    use super::nvpair::{NvList, NvValue};
+    use super::spa;
+    use super::spa_config;
+    use super::uberblock::Uberblock;
+    use super::zio;
+
+    // A pool of a single device at txg 4
+    let mut top = NvList::new(0);
+    top.add("type".to_owned(), NvValue::String("file".to_owned()));
+    top.add("id".to_owned(), NvValue::Uint64(0));
+    top.add("guid".to_owned(), NvValue::Uint64(0x100));
+    let mut label = NvList::new(0);
+    label.add("version".to_owned(), NvValue::Uint64(5000));
+    label.add("name".to_owned(), NvValue::String("tank".to_owned()));
+    label.add("state".to_owned(), NvValue::Uint64(0));
+    label.add("txg".to_owned(), NvValue::Uint64(4));
+    label.add("pool_guid".to_owned(), NvValue::Uint64(0x42));
+    label.add("top_guid".to_owned(), NvValue::Uint64(0x100));
+    label.add("guid".to_owned(), NvValue::Uint64(0x100));
+    label.add("vdev_children".to_owned(), NvValue::Uint64(1));
+    label.add("vdev_tree".to_owned(), NvValue::NvList(top));
+    let mut uberblock = Uberblock {
+        magic: 0x00bab10c,
+        version: 5000,
+        txg: 4,
+        guid_sum: 0x142,
+        timestamp: 0,
+        rootbp: BlockPtr::from_bytes(&[0; 128]).unwrap(),
+        software_version: 0,
+        mmp_magic: 0,
+        mmp_delay: 0,
+        mmp_config: 0,
+        checkpoint_txg: 0,
+        raidz_reflow_info: 0,
+    };
+    let device = MemVdev::new(vec![0; 2 << 20]);
+    let mut reader = zio::Reader::new(device.clone());
+    for label_index in 0..zio::VDEV_LABELS {
+        spa_config::write_label_nv_list(&mut reader, label_index, &label).unwrap();
+        reader.write_uberblock(label_index, &uberblock).unwrap();
+    }
+
+    // Reguid it the way a pool does, at txg 5
+    let guids = [(0x42, 0x43), (0x100, 0x200)];
+    let reguid = |nv: &mut NvList| spa_config::reguid_config(nv, &guids);
+    uberblock.txg = 5;
+    uberblock.guid_sum = 0x243;
+    let crash = CrashVdev::new(device.to_vec());
+    let mut reader = zio::Reader::new(crash.clone());
+    assert_eq!(spa::sync_labels(&mut reader, &uberblock, &reguid).unwrap(), 4);
+    assert_eq!(crash.writes(), 8);
+    assert_eq!(reader.generation(), 1);
+
+    // Whatever a crash leaves, the pool imports, at the old txg or the new one
+    let mut states = 0;
+    for state in crash.crash_states() {
+        let (reader, _) = spa::open_devices(vec![Box::new(state)]).unwrap();
+        let txg = spa::check_labels(&reader).unwrap().txg;
+        assert!(txg == 4 || txg == 5);
+        states += 1;
+    }
+    // Before the first write, then any of the even labels, the uberblocks and the odd labels
+    assert_eq!(states, 1 + 3 + 15 + 3);
+
+    // Without the flushes, the new uberblocks can make it to the disk without any new label
+    let crash = CrashVdev::new(device.to_vec());
+    let mut reader = zio::Reader::new(crash.clone());
+    for label_index in 0..zio::VDEV_LABELS {
+        reader.write_uberblock(label_index, &uberblock).unwrap();
+    }
+    spa_config::rewrite_labels(&mut reader, &[0, 1, 2, 3], &reguid).unwrap();
+    assert!(crash.crash_states().any(|state| spa::check_labels(&zio::Reader::new(state)).is_err()));
+}
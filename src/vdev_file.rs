@@ -1,5 +1,10 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+#[cfg(feature = "std")]
+use std::fs;
+
 use super::nvpair::NvList;
-use super::{vdev, zfs};
+use super::{util, vdev, zfs};
 
 pub struct VdevFile {
     path: String,
@@ -18,14 +23,24 @@ impl VdevFile {
 }
 
 impl vdev::IVdevOps for VdevFile {
+    #[cfg(feature = "std")]
     fn open(&mut self, vdev: &mut vdev::Vdev) -> zfs::Result<(u64, u64, u64)> {
-        Ok((0, 0, 0))
+        let size = try!(fs::metadata(&self.path).map_err(|_| zfs::Error::NoEntity)).len();
+        // A file can't be grown behind our back, so it's also the max size
+        Ok((size, size, vdev::logical_sector_shift(size, Some(vdev.ashift))))
+    }
+
+    /// Without std there's no file at the path to look at: the device is only reached through
+    /// the `Vdev` it was opened as
+    #[cfg(not(feature = "std"))]
+    fn open(&mut self, _: &mut vdev::Vdev) -> zfs::Result<(u64, u64, u64)> {
+        Err(zfs::Error::NoEntity)
     }
 
     fn close(&mut self, vdev: &mut vdev::Vdev) {}
 
     fn asize(&mut self, vdev: &mut vdev::Vdev, psize: u64) -> u64 {
-        0
+        util::p2_round_up(psize, 1 << vdev.ashift)
     }
 
     fn hold(&mut self, vdev: &mut vdev::Vdev) {}
@@ -0,0 +1,205 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem};
+
+use super::ZfsReader;
+use super::dmu;
+use super::dmu_objset::ObjectSet;
+use super::dvaddr::DVAddr;
+use super::from_bytes::{Decoder, FromBytes};
+use super::zfs;
+use super::zio::SPA_MINBLOCKSHIFT;
+
+/// The bonus buffer of an indirect mapping object. Pools from before obsolete counts were kept
+/// have no `counts_object`, which reads as 0.
+#[repr(packed)]
+pub struct IndirectMappingPhys {
+    /// The end of the last range mapped, in bytes
+    pub max_offset: u64,
+    pub bytes_mapped: u64,
+    pub num_entries: u64,
+    /// An array of how many bytes of each entry are no longer referenced
+    pub counts_object: u64,
+}
+
+impl FromBytes for IndirectMappingPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut fields = [0; 4];
+        decoder.fill_u64(&mut fields);
+        IndirectMappingPhys {
+            max_offset: fields[0],
+            bytes_mapped: fields[1],
+            num_entries: fields[2],
+            counts_object: fields[3],
+        }
+    }
+}
+
+impl IndirectMappingPhys {
+    /// Decode a bonus buffer of either version
+    pub fn from_bonus(bonus: &[u8]) -> Result<Self, String> {
+        let mut data = bonus.to_vec();
+        data.resize(mem::size_of::<Self>(), 0);
+        Self::from_bytes(&data).map_err(String::from)
+    }
+}
+
+/// A range of a removed vdev and the DVA it was copied to, whose asize is the range's size. The
+/// source offset is in sectors, the top bit is left for marking entries in memory.
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct IndirectMappingEntry {
+    pub src: u64,
+    pub dst: DVAddr,
+}
+
+impl FromBytes for IndirectMappingEntry {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let src = decoder.u64();
+        let vdev = decoder.u64();
+        let offset = decoder.u64();
+        IndirectMappingEntry {
+            src: src,
+            dst: DVAddr {
+                vdev: vdev,
+                offset: offset,
+            },
+        }
+    }
+}
+
+impl IndirectMappingEntry {
+    /// Where the range starts on the removed vdev, in bytes
+    pub fn src_offset(&self) -> u64 {
+        (self.src & !(1 << 63)) << SPA_MINBLOCKSHIFT
+    }
+
+    pub fn size(&self) -> u64 {
+        self.dst.asize() << SPA_MINBLOCKSHIFT
+    }
+}
+
+/// Where the space of a top-level vdev removed from the pool (`zpool remove`) went: the
+/// removal copied each allocated range of it to another vdev. Blocks still point at the removed
+/// vdev, which is only left in the config as an "indirect" vdev, so their DVAs are translated
+/// through the mapping on every read.
+pub struct IndirectMapping {
+    /// The ranges, by source offset
+    entries: Vec<IndirectMappingEntry>,
+}
+
+impl IndirectMapping {
+    pub fn new(mut entries: Vec<IndirectMappingEntry>) -> Self {
+        entries.sort_by_key(IndirectMappingEntry::src_offset);
+        IndirectMapping { entries: entries }
+    }
+
+    /// Read the mapping from MOS object `object`, named by the indirect vdev's config
+    pub fn open(reader: &mut ZfsReader, mos: &ObjectSet, object: u64) -> zfs::Result<Self> {
+        let dnode = try!(mos.dnode(reader, object).map_err(|_| zfs::Error::Invalid));
+        let phys = try!(IndirectMappingPhys::from_bonus(dnode.get_bonus())
+                            .map_err(|_| zfs::Error::Invalid));
+        let entry_size = mem::size_of::<IndirectMappingEntry>();
+        let len = try!((phys.num_entries as usize).checked_mul(entry_size)
+                                                  .ok_or(zfs::Error::Invalid));
+        let data = try!(dmu::read(reader, &dnode, 0, len).map_err(|_| zfs::Error::Invalid));
+        let mut entries = Vec::with_capacity(phys.num_entries as usize);
+        for chunk in data.chunks(entry_size) {
+            entries.push(try!(IndirectMappingEntry::from_bytes(chunk)));
+        }
+        Ok(IndirectMapping::new(entries))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Where the `size` bytes at byte `offset` of the removed vdev are now, as the pieces they
+    /// were split into: each piece's offset into the range, and the DVA of its copy. None if
+    /// any of the range wasn't copied, it wasn't allocated when the vdev was removed.
+    pub fn remap(&self, offset: u64, size: u64) -> Option<Vec<(u64, DVAddr)>> {
+        let first = match self.entries.binary_search_by_key(&offset, |entry| entry.src_offset()) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let mut pieces = Vec::new();
+        let mut pos = offset;
+        for entry in &self.entries[first..] {
+            if pos == offset + size {
+                break;
+            }
+            let start = entry.src_offset();
+            if start > pos || start + entry.size() <= pos {
+                return None;
+            }
+            let len = cmp::min(start + entry.size(), offset + size) - pos;
+            let dst = entry.dst;
+            pieces.push((pos - offset,
+                         DVAddr {
+                             vdev: dst.vdev_id() << 32 | len >> SPA_MINBLOCKSHIFT,
+                             offset: dst.offset() + ((pos - start) >> SPA_MINBLOCKSHIFT),
+                         }));
+            pos += len;
+        }
+        if pos == offset + size { Some(pieces) } else { None }
+    }
+}
+
+#[test]
+fn test_indirect_mapping() {
+    use std::sync::Arc;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let entry = |src: u64, vdev: u64, offset: u64, asize: u64| {
+        IndirectMappingEntry {
+            src: src,
+            dst: DVAddr {
+                vdev: vdev << 32 | asize,
+                offset: offset,
+            },
+        }
+    };
+    // Sectors 0..12 of the removed vdev went to two places, 20..24 to a third
+    let mapping = IndirectMapping::new(vec![entry(20, 0, 200, 4),
+                                            entry(0, 0, 100, 8),
+                                            entry(8, 2, 50, 4)]);
+    let pieces = |offset, size| {
+        mapping.remap(offset, size).map(|pieces| {
+            pieces.iter()
+                  .map(|&(at, dva)| (at, dva.vdev_id(), dva.offset(), dva.asize()))
+                  .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(pieces(0, 1024), Some(vec![(0, 0, 100, 2)]));
+    assert_eq!(pieces(2048, 4096),
+               Some(vec![(0, 0, 104, 4), (2048, 2, 50, 4)]));
+    assert_eq!(pieces(20 * 512 + 512, 512), Some(vec![(0, 0, 201, 1)]));
+    // Nothing was allocated in between
+    assert_eq!(pieces(4096, 8192), None);
+    assert_eq!(pieces(12 * 512, 512), None);
+    assert_eq!(pieces(24 * 512, 512), None);
+
+    // Blocks of the removed vdev are read from the pieces, whole
+    let disks = [MemVdev::new(vec![0; 8 << 20]), MemVdev::new(vec![0; 8 << 20])];
+    let mut reader = zio::Reader::new(disks[0].clone());
+    reader.tops.insert(0, Box::new(disks[0].clone()));
+    reader.tops.insert(2, Box::new(disks[1].clone()));
+    reader.indirect.insert(1, Arc::new(mapping));
+    reader.write_dva(&DVAddr { vdev: 4, offset: 104 }, &[1; 2048]).unwrap();
+    reader.write_dva(&DVAddr { vdev: 2 << 32 | 4, offset: 50 }, &[2; 2048]).unwrap();
+    let dva = DVAddr { vdev: 1 << 32 | 8, offset: 4 };
+    let data = reader.read_dva(&dva, 3000).unwrap();
+    assert_eq!(data.len(), 3000);
+    assert!(data[..2048].iter().all(|&b| b == 1) && data[2048..].iter().all(|&b| b == 2));
+    assert!(reader.read_dva_verified(&dva, 4096, &|data| data[4095] == 2).is_ok());
+    assert_eq!(reader.read_many(&[dva]).unwrap(), vec![reader.read_dva(&dva, 4096).unwrap()]);
+    assert!(reader.read_dva(&DVAddr { vdev: 1 << 32 | 1, offset: 12 }, 512).is_err());
+
+    // Removals can be chained, a vdev's blocks copied to one removed later
+    let chained = IndirectMapping::new(vec![entry(0, 1, 4, 8)]);
+    reader.indirect.insert(3, Arc::new(chained));
+    assert_eq!(reader.read_dva(&DVAddr { vdev: 3 << 32 | 8, offset: 0 }, 4096).unwrap(),
+               reader.read_dva(&dva, 4096).unwrap());
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::dvaddr::DVAddr;
+use super::vdev_io::Vdev;
+use super::zio::SPA_MINBLOCKSHIFT;
+
+/// A fault `InjectVdev` injects into the IO to its device
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Flip bit `bit` of the byte at `offset` in everything read of it. The device keeps the
+    /// right data, so a rewrite doesn't repair it.
+    BitFlip { offset: u64, bit: u8 },
+    /// Fail `percent` of the reads with EIO
+    ReadError { percent: u32 },
+    /// Write only the first `keep` bytes of the next write and fail it, as if the power went out
+    /// in the middle of it
+    TornWrite { keep: usize },
+    /// Take `delay` longer over every read and write
+    Latency(Duration),
+}
+
+impl Fault {
+    /// Flip bit `bit` of byte `byte` of the block at `dva`, on the device it's on or on a child
+    /// of a mirror
+    pub fn flip_dva(dva: &DVAddr, byte: u64, bit: u8) -> Fault {
+        Fault::BitFlip {
+            offset: (dva.sector() << SPA_MINBLOCKSHIFT) + byte,
+            bit: bit,
+        }
+    }
+}
+
+/// A device injecting faults into the IO to another one, like `zinject`, to exercise the paths
+/// that deal with them: checksum failover, raidz reconstruction, scrub's reports. Wrap leaf
+/// devices, the children of a mirror or raidz, to have it find its way around them. Clones
+/// share the device and the faults.
+#[derive(Clone)]
+pub struct InjectVdev {
+    disk: Arc<Vdev>,
+    faults: Arc<Mutex<Vec<Fault>>>,
+    /// State of the xorshift generator picking the reads to fail, so runs can be repeated
+    rng: Arc<Mutex<u64>>,
+    injected: Arc<AtomicU64>,
+}
+
+impl InjectVdev {
+    pub fn new<V: Vdev + 'static>(disk: V) -> Self {
+        InjectVdev {
+            disk: Arc::new(disk),
+            faults: Arc::new(Mutex::new(Vec::new())),
+            rng: Arc::new(Mutex::new(0x2545f4914f6cdd1d)),
+            injected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.faults.lock().unwrap().push(fault);
+    }
+
+    /// Stop injecting faults
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    /// How many IOs faults were injected into
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// A number in `0..100`
+    fn roll(&self) -> u32 {
+        let mut rng = self.rng.lock().unwrap();
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        (*rng % 100) as u32
+    }
+
+    fn delay(&self, faults: &[Fault]) {
+        for fault in faults {
+            if let Fault::Latency(delay) = *fault {
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+impl Vdev for InjectVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let faults = self.faults.lock().unwrap().clone();
+        self.delay(&faults);
+        for fault in &faults {
+            if let Fault::ReadError { percent } = *fault {
+                if self.roll() < percent {
+                    self.injected.fetch_add(1, Ordering::Relaxed);
+                    return Err(io::Error::from_raw_os_error(5));
+                }
+            }
+        }
+
+        let read = try!(self.disk.read_at(offset, buf));
+        for fault in &faults {
+            if let Fault::BitFlip { offset: at, bit } = *fault {
+                if at >= offset && at < offset + read as u64 {
+                    buf[(at - offset) as usize] ^= 1 << bit;
+                    self.injected.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let torn = {
+            let mut faults = self.faults.lock().unwrap();
+            self.delay(&faults);
+            let torn = faults.iter().position(|fault| match *fault {
+                Fault::TornWrite { .. } => true,
+                _ => false,
+            });
+            torn.map(|i| faults.remove(i))
+        };
+        if let Some(Fault::TornWrite { keep }) = torn {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+            let keep = ::std::cmp::min(keep, data.len());
+            try!(self.disk.write_at(offset, &data[..keep]));
+            return Err(io::Error::new(io::ErrorKind::Other, "torn write"));
+        }
+        self.disk.write_at(offset, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.disk.flush()
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.disk.discard(offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.disk.size()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+#[test]
+fn test_inject() {
+    use std::time::Instant;
+    use super::vdev_io::{self, MemVdev};
+    use super::vdev_mirror::MirrorVdev;
+
+    let disk = MemVdev::new(vec![0; 4096]);
+    let vdev = InjectVdev::new(disk.clone());
+    let mut buf = [0; 16];
+
+    vdev.inject(Fault::BitFlip {
+        offset: 100,
+        bit: 3,
+    });
+    vdev.read_at(96, &mut buf).unwrap();
+    assert_eq!(buf[4], 8);
+    assert_eq!(vdev.injected(), 1);
+    assert_eq!(Fault::flip_dva(&DVAddr { vdev: 0, offset: 1 }, 4, 0),
+               Fault::BitFlip {
+                   offset: 0x2001 * 512 + 4,
+                   bit: 0,
+               });
+
+    // Reads fail about as often as they're meant to
+    vdev.clear();
+    vdev.inject(Fault::ReadError { percent: 50 });
+    let failed = (0..1000).filter(|_| vdev.read_at(0, &mut buf).is_err()).count();
+    assert!(failed > 400 && failed < 600, "{} reads failed", failed);
+
+    // A torn write leaves part of it behind, once
+    vdev.clear();
+    vdev.inject(Fault::TornWrite { keep: 4 });
+    assert!(vdev.write_at(0, &[7; 8]).is_err());
+    assert_eq!(&disk.to_vec()[..9], &[7, 7, 7, 7, 0, 0, 0, 0, 0]);
+    vdev.write_at(0, &[7; 8]).unwrap();
+    assert_eq!(&disk.to_vec()[..9], &[7, 7, 7, 7, 7, 7, 7, 7, 0]);
+
+    vdev.inject(Fault::Latency(Duration::from_millis(20)));
+    let begin = Instant::now();
+    vdev.read_at(0, &mut buf).unwrap();
+    assert!(begin.elapsed() >= Duration::from_millis(20));
+
+    // A mirror gets the data from the child that has it right, and counts the other one's
+    // errors. Its first child is the first it reads from.
+    let mirror_of = |bad: &InjectVdev| {
+        MirrorVdev::new(vec![Box::new(bad.clone()), Box::new(MemVdev::new(vec![1; 4096]))])
+    };
+    let good = |data: &[u8]| data.iter().all(|&x| x == 1);
+    let bad = InjectVdev::new(MemVdev::new(vec![1; 4096]));
+    bad.inject(Fault::BitFlip {
+        offset: 10,
+        bit: 0,
+    });
+    let mirror = mirror_of(&bad);
+    assert!(mirror.read_verified(0, &mut buf, &good).unwrap());
+    assert_eq!(mirror.child_errors(0).unwrap().checksum, 1);
+
+    bad.clear();
+    bad.inject(Fault::ReadError { percent: 100 });
+    let mirror = mirror_of(&bad);
+    assert!(mirror.read_verified(0, &mut buf, &good).unwrap());
+    assert_eq!(mirror.child_errors(0).unwrap().read, 1);
+    assert!(vdev_io::read_exact_at(&bad, 0, &mut buf).is_err());
+}
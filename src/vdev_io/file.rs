@@ -0,0 +1,618 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Deref;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use super::super::util;
+use super::{MappedBlock, Vdev, read_full};
+
+/// Size of a file, or of the block device it is: those have no length in their metadata
+fn file_size(file: &File) -> io::Result<u64> {
+    let len = try!(file.metadata()).len();
+    if len != 0 {
+        return Ok(len);
+    }
+    let mut file = file;
+    file.seek(SeekFrom::End(0))
+}
+
+/// `pread`: read into `buf` from byte `offset` of `file`, without moving its file position
+#[cfg(unix)]
+fn pread(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn pread(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+/// Elsewhere the file position is all there is, IO through clones of the file has to take
+/// turns
+#[cfg(not(any(unix, windows)))]
+fn pread(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    use std::io::Read;
+    let mut file = file;
+    try!(file.seek(SeekFrom::Start(offset)));
+    file.read(buf)
+}
+
+/// `pwrite`: write all of `data` at byte `offset` of `file`, without moving its file position
+#[cfg(unix)]
+fn pwrite_all(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn pwrite_all(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut written = 0;
+    while written < data.len() {
+        match file.seek_write(&data[written..], offset + written as u64) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write")),
+            Ok(count) => written += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn pwrite_all(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    let mut file = file;
+    try!(file.seek(SeekFrom::Start(offset)));
+    file.write_all(data)
+}
+
+// fallocate(2) and the BLKDISCARD ioctl
+#[cfg(target_os = "linux")]
+const FALLOC_FL_KEEP_SIZE: c_int = 1;
+#[cfg(target_os = "linux")]
+const FALLOC_FL_PUNCH_HOLE: c_int = 2;
+#[cfg(target_os = "linux")]
+const BLKDISCARD: c_ulong = 0x1277;
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn fallocate(fd: c_int, mode: c_int, offset: i64, len: i64) -> c_int;
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// Discard the `len` bytes at `offset` of `file`: `BLKDISCARD` them if it's a block device, punch
+/// a hole there if it's a regular file
+#[cfg(target_os = "linux")]
+fn discard_file(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    let ret = if try!(file.metadata()).file_type().is_block_device() {
+        let range: [u64; 2] = [offset, len];
+        unsafe { ioctl(file.as_raw_fd(), BLKDISCARD, range.as_ptr()) }
+    } else {
+        unsafe {
+            fallocate(file.as_raw_fd(),
+                      FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                      offset as i64,
+                      len as i64)
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// TODO: F_PUNCHHOLE on macOS, FSCTL_SET_ZERO_DATA on Windows
+#[cfg(not(target_os = "linux"))]
+fn discard_file(_: &File, _: u64, _: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn preadv(fd: c_int, iov: *const IoVec, iovcnt: c_int, offset: i64) -> isize;
+}
+
+/// Most buffers one `preadv` takes
+#[cfg(target_os = "linux")]
+const IOV_MAX: usize = 1024;
+
+/// Read `extents` from `file`, each run of them that follow each other with one `preadv`
+#[cfg(target_os = "linux")]
+fn preadv_extents(file: &File, extents: &mut [(u64, &mut [u8])]) -> io::Result<Vec<usize>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut read = Vec::with_capacity(extents.len());
+    let mut i = 0;
+    while i < extents.len() {
+        let start = extents[i].0;
+        let mut end = start + extents[i].1.len() as u64;
+        let mut run = i + 1;
+        while run < extents.len() && run - i < IOV_MAX && extents[run].0 == end {
+            end += extents[run].1.len() as u64;
+            run += 1;
+        }
+
+        let iovecs: Vec<IoVec> = extents[i..run]
+                                     .iter_mut()
+                                     .map(|&mut (_, ref mut buf)| {
+                                         IoVec {
+                                             base: buf.as_mut_ptr() as *mut c_void,
+                                             len: buf.len(),
+                                         }
+                                     })
+                                     .collect();
+        let count = unsafe {
+            preadv(file.as_raw_fd(),
+                   iovecs.as_ptr(),
+                   iovecs.len() as c_int,
+                   start as i64)
+        };
+        if count < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        // What a short read left out is read an extent at a time, it only happens at the end
+        // of the file or on a signal
+        let mut left = count as usize;
+        for &mut (offset, ref mut buf) in &mut extents[i..run] {
+            let got = cmp::min(left, buf.len());
+            left -= got;
+            if got < buf.len() {
+                read.push(got + try!(read_full(file, offset + got as u64, &mut buf[got..])));
+            } else {
+                read.push(got);
+            }
+        }
+        i = run;
+    }
+    Ok(read)
+}
+
+impl Vdev for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        pread(self, offset, buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_extents(&self, extents: &mut [(u64, &mut [u8])]) -> io::Result<Vec<usize>> {
+        preadv_extents(self, extents)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        pwrite_all(self, offset, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        discard_file(self, offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        file_size(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(try!(File::try_clone(self))))
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
+const O_DIRECT: i32 = 0o200000;
+#[cfg(all(target_os = "linux", not(any(target_arch = "arm", target_arch = "aarch64"))))]
+const O_DIRECT: i32 = 0o40000;
+
+/// Alignment of the buffers, offsets and lengths of direct IO. 4K covers 4K native devices too.
+const DIRECT_IO_ALIGN: u64 = 4096;
+
+/// A raw block device, opened for direct IO where the OS has it, so reads bypass the page cache
+/// (the ARC caches them already). Direct IO has to be aligned in memory and on the device, so
+/// every IO goes through an aligned bounce buffer.
+pub struct RawVdev {
+    path: String,
+    file: File,
+    write: bool,
+}
+
+impl RawVdev {
+    pub fn open(path: &str, write: bool) -> io::Result<Self> {
+        let file = try!(Self::open_options(write).open(Path::new(path)));
+        Ok(RawVdev {
+            path: path.to_owned(),
+            file: file,
+            write: write,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_options(write: bool) -> OpenOptions {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(write).custom_flags(O_DIRECT);
+        options
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_options(write: bool) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.read(true).write(write);
+        options
+    }
+
+    /// Read the aligned range `start..start + len` into an aligned buffer. Returns the buffer
+    /// along with where the range starts in it and how many bytes of it the device had.
+    fn read_aligned(&self, start: u64, len: usize) -> io::Result<(Vec<u8>, usize, usize)> {
+        let mut buf = vec![0; len + DIRECT_IO_ALIGN as usize];
+        let skip = (util::p2_round_up(buf.as_ptr() as u64, DIRECT_IO_ALIGN) -
+                    buf.as_ptr() as u64) as usize;
+        let mut read = 0;
+        while read < len {
+            match pread(&self.file, start + read as u64, &mut buf[skip + read..skip + len]) {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((buf, skip, read))
+    }
+}
+
+impl Vdev for RawVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = util::p2_align(offset, DIRECT_IO_ALIGN);
+        let end = util::p2_round_up(offset + buf.len() as u64, DIRECT_IO_ALIGN);
+        let (aligned, skip, read) = try!(self.read_aligned(start, (end - start) as usize));
+
+        let head = (offset - start) as usize;
+        let len = cmp::min(buf.len(), read.saturating_sub(head));
+        buf[..len].copy_from_slice(&aligned[skip + head..skip + head + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        // Partially covered blocks are read first so their other bytes are kept
+        let start = util::p2_align(offset, DIRECT_IO_ALIGN);
+        let end = util::p2_round_up(offset + data.len() as u64, DIRECT_IO_ALIGN);
+        let (mut aligned, skip, _) = try!(self.read_aligned(start, (end - start) as usize));
+
+        let head = skip + (offset - start) as usize;
+        aligned[head..head + data.len()].copy_from_slice(data);
+        pwrite_all(&self.file, start, &aligned[skip..skip + (end - start) as usize])
+    }
+
+    /// Direct IO bypasses the page cache, not the device's write cache
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        discard_file(&self.file, offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        file_size(&self.file)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(try!(RawVdev::open(&self.path, self.write))))
+    }
+}
+
+// mmap(2)
+#[cfg(unix)]
+const PROT_READ: c_int = 1;
+#[cfg(unix)]
+const MAP_SHARED: c_int = 1;
+
+#[cfg(unix)]
+extern "C" {
+    fn mmap(addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64)
+            -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// A read-only mapping of a whole file
+struct Mapping {
+    ptr: *const u8,
+    len: usize,
+}
+
+// Nothing writes through the pointer, the bytes change only through the file
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    #[cfg(unix)]
+    fn new(file: &File, len: u64) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        // An empty mapping is refused, there's nothing to map anyway
+        if len == 0 {
+            return Ok(Mapping {
+                ptr: ::std::ptr::null(),
+                len: 0,
+            });
+        }
+        if len > usize::max_value() as u64 {
+            return Err(io::Error::new(io::ErrorKind::Other, "file too large to map"));
+        }
+        let ptr = unsafe {
+            mmap(::std::ptr::null_mut(),
+                 len as usize,
+                 PROT_READ,
+                 MAP_SHARED,
+                 file.as_raw_fd(),
+                 0)
+        };
+        if ptr as usize == !0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *const u8,
+            len: len as usize,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn new(_: &File, _: u64) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "memory mapping isn't supported here"))
+    }
+}
+
+/// The bytes of the mapping. They're only to be looked at while nothing writes the file: under
+/// `MmapVdev::lock`, or for good if the file can't be written.
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { ::std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                munmap(self.ptr as *mut c_void, self.len);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+/// An image file mapped into memory, so reads are copies out of the page cache without a
+/// syscall each, and `slice` hands out the bytes without copying at all. Clones share the
+/// mapping. Writes go to the file, which the mapping sees as it's shared with the page cache.
+/// The mapping covers the file as it was opened: what a write appends past it is read with
+/// `pread`.
+///
+/// Bytes borrowed from the mapping mustn't change under whoever holds them, so writes wait for
+/// them to be given back (see `slice`). A file opened read-only never changes, and its bytes can
+/// be held on to for good instead (see `Vdev::mapped`), which is how the ARC caches its blocks
+/// without copying them. That holds as long as nothing else writes the file while it's mapped,
+/// and nothing truncates it: reading the pages that were cut off kills the process with SIGBUS.
+#[derive(Clone)]
+pub struct MmapVdev {
+    file: Arc<File>,
+    map: Arc<Mapping>,
+    /// Held for reading while the mapping is read, and for writing while the file is written
+    lock: Arc<RwLock<()>>,
+    write: bool,
+}
+
+/// Bytes borrowed from an `MmapVdev`'s mapping. Writes to the device wait until they're dropped.
+pub struct MmapSlice<'a> {
+    bytes: &'a [u8],
+    _guard: RwLockReadGuard<'a, ()>,
+}
+
+impl<'a> Deref for MmapSlice<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl MmapVdev {
+    pub fn open(path: &str, write: bool) -> io::Result<Self> {
+        let file = try!(OpenOptions::new().read(true).write(write).open(Path::new(path)));
+        MmapVdev::new(file, write)
+    }
+
+    /// Map all of `file`, which is opened for writing if `write`
+    pub fn new(file: File, write: bool) -> io::Result<Self> {
+        let len = try!(file_size(&file));
+        let map = try!(Mapping::new(&file, len));
+        Ok(MmapVdev {
+            file: Arc::new(file),
+            map: Arc::new(map),
+            lock: Arc::new(RwLock::new(())),
+            write: write,
+        })
+    }
+
+    /// The `len` bytes at `offset`, straight out of the mapping. None if they aren't all in it.
+    /// Writes to the device wait until they're dropped, so a thread mustn't write while it holds
+    /// some.
+    pub fn slice<'a>(&'a self, offset: u64, len: usize) -> Option<MmapSlice<'a>> {
+        let bytes = (*self.map).as_ref();
+        if offset > bytes.len() as u64 || len > bytes.len() - offset as usize {
+            return None;
+        }
+        Some(MmapSlice {
+            bytes: &bytes[offset as usize..offset as usize + len],
+            _guard: self.lock.read().unwrap(),
+        })
+    }
+}
+
+impl Vdev for MmapVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let _guard = self.lock.read().unwrap();
+        let bytes = (*self.map).as_ref();
+        if offset >= bytes.len() as u64 {
+            return pread(&self.file, offset, buf);
+        }
+        let start = offset as usize;
+        let len = cmp::min(buf.len(), bytes.len() - start);
+        buf[..len].copy_from_slice(&bytes[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let _guard = self.lock.write().unwrap();
+        pwrite_all(&self.file, offset, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// The hole shows through the mapping as zeros
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let _guard = self.lock.write().unwrap();
+        discard_file(&self.file, offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        file_size(&self.file)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// Only the mapping of a file opened read-only, which nothing here writes to
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedBlock> {
+        if self.write {
+            return None;
+        }
+        MappedBlock::new(self.map.clone(), offset, len)
+    }
+}
+
+#[test]
+fn test_mmap_vdev() {
+    use std::io::Write;
+
+    let path = ::std::env::temp_dir().join("zfs_test_mmap_vdev");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[3; 4096]).unwrap();
+    }
+    let vdev = MmapVdev::open(path.to_str().unwrap(), true).unwrap();
+    assert_eq!(vdev.size().unwrap(), 4096);
+    let mut buf = [0; 64];
+    assert_eq!(vdev.read_at(4064, &mut buf).unwrap(), 32);
+    assert_eq!(&buf[..32], &[3; 32][..]);
+    assert_eq!(&*vdev.slice(4000, 96).unwrap(), &[3; 96][..]);
+    assert!(vdev.slice(4000, 97).is_none());
+
+    // Writes show up in the mapping, and past it they're read from the file
+    let clone = vdev.try_clone().unwrap();
+    clone.write_at(100, &[9; 4]).unwrap();
+    clone.write_at(4096, &[8; 4]).unwrap();
+    assert_eq!(&*vdev.slice(99, 6).unwrap(), &[3, 9, 9, 9, 9, 3][..]);
+    assert_eq!(vdev.size().unwrap(), 4100);
+    assert_eq!(vdev.read_at(4096, &mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], &[8; 4]);
+    assert_eq!(vdev.read_at(5000, &mut buf).unwrap(), 0);
+
+    // A discard punches a hole, which reads as zeros through the mapping. Not every file system
+    // can punch one.
+    if cfg!(target_os = "linux") && vdev.discard(96, 8).is_ok() {
+        assert_eq!(&*vdev.slice(95, 10).unwrap(), &[3, 0, 0, 0, 0, 0, 0, 0, 0, 3][..]);
+        assert_eq!(vdev.size().unwrap(), 4100);
+    }
+
+    // A write waits for the bytes borrowed from the mapping to be given back
+    let slice = vdev.slice(200, 4).unwrap();
+    let writer = {
+        let clone = vdev.clone();
+        ::std::thread::spawn(move || clone.write_at(200, &[7; 4]).unwrap())
+    };
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    assert_eq!(&*slice, &[3; 4]);
+    drop(slice);
+    writer.join().unwrap();
+    assert_eq!(&*vdev.slice(200, 4).unwrap(), &[7; 4]);
+
+    // Only a file that can't be written lends its bytes out for good, and they outlive it
+    assert!(vdev.mapped(200, 4).is_none());
+    let block = MmapVdev::open(path.to_str().unwrap(), false).unwrap().mapped(200, 4).unwrap();
+    assert_eq!(&*block, &[7; 4]);
+
+    drop(clone);
+    drop(vdev);
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_read_extents() {
+    use super::{MemVdev, read_extents_exact};
+
+    let path = ::std::env::temp_dir().join("zfs_test_read_extents");
+    let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    file.set_len(0).unwrap();
+    let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+    file.write_at(0, &data).unwrap();
+
+    let (mut a, mut b, mut c, mut d) = ([0; 16], [0; 32], [0; 8], [0; 16]);
+    let read = {
+        // A run of two extents, one on its own, one running past the end
+        let mut extents: Vec<(u64, &mut [u8])> = vec![(100, &mut a[..]),
+                                                      (116, &mut b[..]),
+                                                      (1000, &mut c[..]),
+                                                      (4088, &mut d[..])];
+        file.read_extents(&mut extents).unwrap()
+    };
+    assert_eq!(read, vec![16, 32, 8, 8]);
+    assert_eq!(&a[..], &data[100..116]);
+    assert_eq!(&b[..], &data[116..148]);
+    assert_eq!(&c[..], &data[1000..1008]);
+    assert_eq!(&d[..8], &data[4088..]);
+
+    // The same through the default, one extent at a time
+    let mem = MemVdev::new(data.clone());
+    let mut e = [0; 8];
+    let mut extents: Vec<(u64, &mut [u8])> = vec![(1000, &mut e[..]), (4092, &mut d[..])];
+    assert_eq!(mem.read_extents(&mut extents).unwrap(), vec![8, 4]);
+    assert!(read_extents_exact(&mem, &mut extents).is_err());
+    assert!(read_extents_exact(&mem, &mut extents[..1]).is_ok());
+
+    let _ = ::std::fs::remove_file(&path);
+}
@@ -0,0 +1,361 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use super::kstat;
+use super::vdev;
+use super::vdev_mirror::ChildErrors;
+
+#[cfg(feature = "std")]
+pub use self::file::{MmapSlice, MmapVdev, RawVdev};
+
+/// Files and block devices, opened through std
+#[cfg(feature = "std")]
+mod file;
+
+/// Where the bytes of a leaf vdev come from. `zio::Reader` does all of its IO through this, so a
+/// pool can live in an image file, in memory or on a raw block device. IO is positioned, like
+/// `pread` and `pwrite`, so it goes through a shared reference: threads sharing a device don't
+/// have to take turns with it.
+pub trait Vdev: Send + Sync {
+    /// Read into `buf` from byte `offset`, like `pread`: returns how many bytes were read, which
+    /// is less than asked for only at the end of the device
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Read each of `extents`, a byte offset and the buffer to fill from there, like `read_full`
+    /// would one at a time. Returns how many bytes each of them got. Devices that can read a run
+    /// of extents that follow each other in one call (`preadv`) do.
+    fn read_extents(&self, extents: &mut [(u64, &mut [u8])]) -> io::Result<Vec<usize>> {
+        let mut read = Vec::with_capacity(extents.len());
+        for &mut (offset, ref mut buf) in extents {
+            read.push(try!(read_full(self, offset, buf)));
+        }
+        Ok(read)
+    }
+
+    /// Write all of `data` at byte `offset`
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make the writes so far durable, before any write that comes after. Writes between two
+    /// flushes can reach the disk in any order, or not at all if the power goes out.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Tell the device the `len` bytes at `offset` are no longer in use (TRIM), so an SSD can
+    /// erase them ahead of time and an image file can give them back. It's only a hint: what's
+    /// read of them afterwards is undefined, and devices that can't discard do nothing.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Size of the device in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Another handle on the same device, e.g. to read from another thread
+    fn try_clone(&self) -> io::Result<Box<Vdev>>;
+
+    /// Fill `buf` with the block at byte `offset`, checking it passes `verify`, and return
+    /// whether it does. This is how blocks are read, `buf` always holds a whole one: devices
+    /// that spread blocks over their children (raidz) can only find them knowing their size.
+    /// Devices holding several copies of the data (mirrors) try each of them until one passes.
+    fn read_verified(&self,
+                     offset: u64,
+                     buf: &mut [u8],
+                     verify: &Fn(&[u8]) -> bool)
+                     -> io::Result<bool> {
+        try!(read_exact_at(self, offset, buf));
+        Ok(verify(buf))
+    }
+
+    /// Write the part of the block at byte `offset` that belongs on child `child` (one just
+    /// attached, or replacing a failed one) from what the other children hold, checking with
+    /// `verify` that it's put together right first. `buf` gets the block, whole. Returns whether
+    /// good data was found to write. Only devices with children can be resilvered.
+    fn resilver(&self,
+                offset: u64,
+                buf: &mut [u8],
+                child: usize,
+                verify: &Fn(&[u8]) -> bool)
+                -> io::Result<bool> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "device has no children to resilver"))
+    }
+
+    /// The `len` bytes at `offset`, borrowed from a mapping of the device that nothing writes
+    /// to, so they can be held on to for as long as it takes. None if the device isn't mapped
+    /// like that, or they aren't all in the mapping: they're read with `read_at` instead.
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedBlock> {
+        None
+    }
+
+    /// Whether blocks are laid out on the device the way DVAs address them, so a byte range of
+    /// it holds the blocks in it and they can be read together with `read_at`. Not so on raidz,
+    /// which spreads each block over its children.
+    fn is_linear(&self) -> bool {
+        true
+    }
+
+    /// How far the expansion of a raidz in progress has got, from the uberblock. Other devices
+    /// have no use for it.
+    fn raidz_reflow(&self, offset: u64) {}
+
+    /// Add the device's statistics to `kstats`
+    fn kstats(&self, kstats: &mut kstat::Registry) {}
+
+    /// The device's health, as far as the IO to its children found out. Devices without
+    /// children can't tell, the reads of them can.
+    fn state(&self) -> vdev::State {
+        vdev::State::Healthy
+    }
+
+    /// The state of each of the device's children, with the errors it ran into. Devices without
+    /// children have none.
+    fn child_status(&self) -> Vec<(vdev::State, ChildErrors)> {
+        Vec::new()
+    }
+}
+
+/// Fill `buf` from byte `offset` of `vdev`, reading again after short reads. Returns how many
+/// bytes there were before the end of the device, the rest of `buf` is left alone.
+pub fn read_full<V: Vdev + ?Sized>(vdev: &V,
+                                   offset: u64,
+                                   buf: &mut [u8])
+                                   -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match vdev.read_at(offset + read as u64, &mut buf[read..]) {
+            Ok(0) => break,
+            Ok(count) => read += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Fill `buf` from byte `offset` of `vdev`. Running into the end of the device is an error.
+pub fn read_exact_at<V: Vdev + ?Sized>(vdev: &V,
+                                       offset: u64,
+                                       buf: &mut [u8])
+                                       -> io::Result<()> {
+    let read = try!(read_full(vdev, offset, buf));
+    if read < buf.len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("short read of {} bytes at {:X}", read, offset)));
+    }
+    Ok(())
+}
+
+/// Fill each of `extents` from `vdev` (see `Vdev::read_extents`). Running into the end of the
+/// device is an error.
+pub fn read_extents_exact<V: Vdev + ?Sized>(vdev: &V,
+                                            extents: &mut [(u64, &mut [u8])])
+                                            -> io::Result<()> {
+    let read = try!(vdev.read_extents(extents));
+    for (&(offset, ref buf), &read) in extents.iter().zip(&read) {
+        if read < buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("short read of {} bytes at {:X}", read, offset)));
+        }
+    }
+    Ok(())
+}
+
+impl Vdev for Box<Vdev> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn read_extents(&self, extents: &mut [(u64, &mut [u8])]) -> io::Result<Vec<usize>> {
+        (**self).read_extents(extents)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        (**self).write_at(offset, data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        (**self).discard(offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        (**self).try_clone()
+    }
+
+    fn read_verified(&self,
+                     offset: u64,
+                     buf: &mut [u8],
+                     verify: &Fn(&[u8]) -> bool)
+                     -> io::Result<bool> {
+        (**self).read_verified(offset, buf, verify)
+    }
+
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedBlock> {
+        (**self).mapped(offset, len)
+    }
+
+    fn is_linear(&self) -> bool {
+        (**self).is_linear()
+    }
+
+    fn raidz_reflow(&self, offset: u64) {
+        (**self).raidz_reflow(offset)
+    }
+
+    fn kstats(&self, kstats: &mut kstat::Registry) {
+        (**self).kstats(kstats)
+    }
+
+    fn state(&self) -> vdev::State {
+        (**self).state()
+    }
+
+    fn child_status(&self) -> Vec<(vdev::State, ChildErrors)> {
+        (**self).child_status()
+    }
+}
+
+/// Bytes of a device borrowed from its mapping in memory (see `Vdev::mapped`), which stays
+/// mapped as long as any of them are around. Clones share them.
+#[derive(Clone)]
+pub struct MappedBlock {
+    map: Arc<AsRef<[u8]> + Send + Sync>,
+    start: usize,
+    len: usize,
+}
+
+impl MappedBlock {
+    /// The `len` bytes of `map` at `offset`, None if they aren't all in it
+    pub fn new(map: Arc<AsRef<[u8]> + Send + Sync>, offset: u64, len: usize) -> Option<Self> {
+        let size = (*map).as_ref().len();
+        if offset > size as u64 || len > size - offset as usize {
+            return None;
+        }
+        Some(MappedBlock {
+            map: map,
+            start: offset as usize,
+            len: len,
+        })
+    }
+}
+
+impl Deref for MappedBlock {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.map).as_ref()[self.start..self.start + self.len]
+    }
+}
+
+/// A device held in memory. Clones share the same bytes. Writes past the end grow it, like they
+/// would an image file.
+#[derive(Clone)]
+pub struct MemVdev {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemVdev {
+    pub fn new(data: Vec<u8>) -> Self {
+        MemVdev { data: Arc::new(Mutex::new(data)) }
+    }
+
+    /// A copy of the device's contents
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Vdev for MemVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = cmp::min(offset, data.len() as u64) as usize;
+        let len = cmp::min(buf.len(), data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut bytes = self.data.lock().unwrap();
+        let end = offset as usize + data.len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Discarded bytes read as zeros, like a hole punched in an image file
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut bytes = self.data.lock().unwrap();
+        let start = cmp::min(offset, bytes.len() as u64) as usize;
+        let end = cmp::min(offset.saturating_add(len), bytes.len() as u64) as usize;
+        for b in &mut bytes[start..end] {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// A device that's gone missing: every IO to it fails. Stands in for the missing children of a
+/// degraded vdev, whose data has to be found some other way.
+pub struct MissingVdev;
+
+impl MissingVdev {
+    fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "device is missing")
+    }
+}
+
+impl Vdev for MissingVdev {
+    fn read_at(&self, _: u64, _: &mut [u8]) -> io::Result<usize> {
+        Err(MissingVdev::error())
+    }
+
+    fn write_at(&self, _: u64, _: &[u8]) -> io::Result<()> {
+        Err(MissingVdev::error())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Err(MissingVdev::error())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        Ok(Box::new(MissingVdev))
+    }
+}
+
+#[test]
+fn test_mem_vdev() {
+    let vdev = MemVdev::new(vec![0; 1024]);
+    vdev.write_at(1000, &[7; 48]).unwrap();
+    assert_eq!(vdev.size().unwrap(), 1048);
+
+    let clone = vdev.try_clone().unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(clone.read_at(1000, &mut buf).unwrap(), 48);
+    assert_eq!(&buf[..48], &[7; 48][..]);
+    assert_eq!(clone.read_at(2000, &mut buf).unwrap(), 0);
+
+    // Clones see each other's writes
+    clone.write_at(0, &[1; 4]).unwrap();
+    assert_eq!(&vdev.to_vec()[..5], &[1, 1, 1, 1, 0]);
+}
@@ -1,3 +1,5 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 use std::mem;
 
 use super::vdev::VdevLabel;
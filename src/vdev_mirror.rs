@@ -0,0 +1,504 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::io;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::kstat;
+use super::nvpair::NvList;
+use super::vdev_io::{self, Vdev};
+use super::{vdev, zfs};
+
+/// Weight of the newest sample in a child's average read latency, as a shift: each sample counts
+/// for 1/8
+const LATENCY_SHIFT: u32 = 3;
+
+/// What a mirror knows about the recent reads of one of its children
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChildLoad {
+    /// Reads issued to the child that haven't completed yet
+    pub queued: u64,
+    /// Moving average of the child's read latency in nanoseconds, 0 until the first read is done
+    pub latency_ns: u64,
+}
+
+impl ChildLoad {
+    /// How long a new read would take: it has to wait for the queued reads, then takes as long
+    /// as a read usually does
+    fn expected_ns(&self) -> u64 {
+        (self.queued + 1).saturating_mul(self.latency_ns)
+    }
+}
+
+/// A mirror: every child holds a full copy of the data, so a read can go to any of them
+pub struct VdevMirror {
+    children: Vec<ChildLoad>,
+    /// Where to start looking among equally loaded children, so they take turns
+    rotor: usize,
+}
+
+impl VdevMirror {
+    pub fn new(children: usize) -> Self {
+        VdevMirror {
+            children: vec![ChildLoad::default(); children],
+            rotor: 0,
+        }
+    }
+
+    pub fn load(nv: &NvList) -> zfs::Result<Self> {
+        let children = try!(nv.get::<&Vec<NvList>>("children").ok_or(zfs::Error::Invalid));
+        Ok(VdevMirror::new(children.len()))
+    }
+
+    pub fn child_load(&self, child: usize) -> Option<&ChildLoad> {
+        self.children.get(child)
+    }
+
+    /// Pick the child to read from among `candidates` (the children that are healthy and have
+    /// the block): the one expected to answer first, going by its queue and its recent latency.
+    /// On mirrors of mismatched devices this keeps the slow ones from holding reads up, while
+    /// still sending them reads when the fast ones are busy. Children nothing is known about yet
+    /// are tried first, so every child gets measured.
+    pub fn select(&mut self, candidates: &[usize]) -> Option<usize> {
+        let count = self.children.len();
+        let mut best: Option<(u64, usize)> = None;
+        for i in 0..count {
+            let child = (self.rotor + i) % count;
+            if !candidates.contains(&child) {
+                continue;
+            }
+            let expected = self.children[child].expected_ns();
+            if best.map_or(true, |(best_expected, _)| expected < best_expected) {
+                best = Some((expected, child));
+            }
+        }
+
+        best.map(|(_, child)| {
+            self.rotor = (child + 1) % count;
+            child
+        })
+    }
+
+    /// A read was issued to `child`
+    pub fn io_start(&mut self, child: usize) {
+        self.children[child].queued += 1;
+    }
+
+    /// A read of `child` completed after `latency_ns` nanoseconds
+    pub fn io_done(&mut self, child: usize, latency_ns: u64) {
+        let load = &mut self.children[child];
+        load.queued = load.queued.saturating_sub(1);
+        load.latency_ns = if load.latency_ns == 0 {
+            latency_ns
+        } else {
+            load.latency_ns - (load.latency_ns >> LATENCY_SHIFT) + (latency_ns >> LATENCY_SHIFT)
+        };
+    }
+}
+
+impl vdev::IVdevOps for VdevMirror {
+    fn open(&mut self, vdev: &mut vdev::Vdev) -> zfs::Result<(u64, u64, u64)> {
+        // The size is the smallest of the children's, which are opened on their own
+        Ok((0, 0, 0))
+    }
+
+    fn close(&mut self, vdev: &mut vdev::Vdev) {}
+
+    fn asize(&mut self, vdev: &mut vdev::Vdev, psize: u64) -> u64 {
+        psize
+    }
+
+    fn hold(&mut self, vdev: &mut vdev::Vdev) {}
+
+    fn release(&mut self, vdev: &mut vdev::Vdev) {}
+}
+
+/// Errors after which a device is taken for faulted if they're IO errors, or degraded if its
+/// data failed checksum verification, like the thresholds zed goes by
+pub const VDEV_ERROR_LIMIT: u64 = 10;
+
+/// The errors one child of a mirror ran into
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChildErrors {
+    pub read: u64,
+    pub write: u64,
+    /// Reads that came back, but with data that failed checksum verification
+    pub checksum: u64,
+}
+
+impl ChildErrors {
+    /// The state these errors leave the device in: faulted or degraded past `VDEV_ERROR_LIMIT`
+    pub fn state(&self) -> vdev::State {
+        if self.read + self.write >= VDEV_ERROR_LIMIT {
+            vdev::State::Faulted
+        } else if self.checksum >= VDEV_ERROR_LIMIT {
+            vdev::State::Degraded
+        } else {
+            vdev::State::Healthy
+        }
+    }
+}
+
+/// The state of each of `children`, which ran into `errors`: one that can't even tell its size
+/// can't be opened
+pub fn child_status(children: &[Box<Vdev>],
+                    errors: &[ChildErrors])
+                    -> Vec<(vdev::State, ChildErrors)> {
+    children.iter()
+            .zip(errors)
+            .map(|(child, errors)| if child.size().is_err() {
+                (vdev::State::CannotOpen, *errors)
+            } else {
+                (errors.state(), *errors)
+            })
+            .collect()
+}
+
+/// The state of a vdev that can do without `redundancy` of its children, whose states are
+/// `children`: faulted if more of them are unusable, degraded if any isn't healthy
+pub fn redundant_state(children: &[(vdev::State, ChildErrors)], redundancy: usize) -> vdev::State {
+    let unusable = children.iter()
+                           .filter(|&&(state, _)| {
+                               state != vdev::State::Healthy && state != vdev::State::Degraded
+                           })
+                           .count();
+    if unusable > redundancy {
+        vdev::State::Faulted
+    } else if children.iter().any(|&(state, _)| state != vdev::State::Healthy) {
+        vdev::State::Degraded
+    } else {
+        vdev::State::Healthy
+    }
+}
+
+/// The devices of a mirror, read and written as one. Reads go to the child `VdevMirror::select`
+/// picks, and fail over to its siblings when it returns an error or data with a bad checksum.
+/// Writes go to every child and succeed if any child took them.
+pub struct MirrorVdev {
+    children: Vec<Box<Vdev>>,
+    /// Only held while picking a child and accounting for its IO, never during the IO itself
+    mirror: Mutex<VdevMirror>,
+    errors: Mutex<Vec<ChildErrors>>,
+}
+
+impl MirrorVdev {
+    pub fn new(children: Vec<Box<Vdev>>) -> Self {
+        let count = children.len();
+        MirrorVdev {
+            children: children,
+            mirror: Mutex::new(VdevMirror::new(count)),
+            errors: Mutex::new(vec![ChildErrors::default(); count]),
+        }
+    }
+
+    pub fn child_errors(&self, child: usize) -> Option<ChildErrors> {
+        self.errors.lock().unwrap().get(child).cloned()
+    }
+
+    /// Read from `candidates`, best first, until `done` is happy with what one of them returned.
+    /// Returns whether one did; if none returned anything at all, the last error.
+    fn read_children<F>(&self,
+                        mut candidates: Vec<usize>,
+                        offset: u64,
+                        buf: &mut [u8],
+                        mut done: F)
+                        -> io::Result<bool>
+        where F: FnMut(usize, &[u8]) -> bool
+    {
+        let mut error = None;
+        let mut any_read = false;
+        loop {
+            let child = {
+                let mut mirror = self.mirror.lock().unwrap();
+                match mirror.select(&candidates) {
+                    Some(child) => {
+                        mirror.io_start(child);
+                        child
+                    }
+                    None => break,
+                }
+            };
+            candidates.retain(|&candidate| candidate != child);
+
+            let begin = Instant::now();
+            let read = vdev_io::read_full(&*self.children[child], offset, buf);
+            let elapsed = begin.elapsed();
+            self.mirror
+                .lock()
+                .unwrap()
+                .io_done(child,
+                         elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64);
+
+            match read {
+                Ok(read) if read == buf.len() => {
+                    any_read = true;
+                    if done(child, buf) {
+                        return Ok(true);
+                    }
+                    self.errors.lock().unwrap()[child].checksum += 1;
+                }
+                Ok(read) => {
+                    self.errors.lock().unwrap()[child].read += 1;
+                    let message = format!("short read of {} bytes at {:X}", read, offset);
+                    error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+                }
+                Err(e) => {
+                    self.errors.lock().unwrap()[child].read += 1;
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(error) if !any_read => Err(error),
+            // No children
+            None if !any_read => Err(io::Error::new(io::ErrorKind::NotFound, "mirror is empty")),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Vdev for MirrorVdev {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Every child has the same data, any that returns the whole of it will do
+        try!(self.read_children((0..self.children.len()).collect(), offset, buf, |_, _| true));
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut error = None;
+        let mut written = false;
+        for (i, child) in self.children.iter().enumerate() {
+            match child.write_at(offset, data) {
+                Ok(()) => written = true,
+                Err(e) => {
+                    self.errors.lock().unwrap()[i].write += 1;
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(error) if !written => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every child discards the range. Like writes, a discard succeeds if any child's does.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut error = None;
+        let mut discarded = false;
+        for child in &self.children {
+            match child.discard(offset, len) {
+                Ok(()) => discarded = true,
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(error) if !discarded => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every child is flushed. Like writes, a flush succeeds if any child's does.
+    fn flush(&self) -> io::Result<()> {
+        let mut error = None;
+        let mut flushed = false;
+        for child in &self.children {
+            match child.flush() {
+                Ok(()) => flushed = true,
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(error) if !flushed => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// The smallest of the children's sizes, the rest of the bigger ones isn't mirrored
+    fn size(&self) -> io::Result<u64> {
+        let mut size = None;
+        for child in &self.children {
+            let child_size = try!(child.size());
+            size = Some(size.map_or(child_size, |size: u64| size.min(child_size)));
+        }
+        size.ok_or(io::Error::new(io::ErrorKind::NotFound, "mirror is empty"))
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        let mut children = Vec::new();
+        for child in &self.children {
+            children.push(try!(child.try_clone()));
+        }
+        Ok(Box::new(MirrorVdev::new(children)))
+    }
+
+    fn read_verified(&self,
+                     offset: u64,
+                     buf: &mut [u8],
+                     verify: &Fn(&[u8]) -> bool)
+                     -> io::Result<bool> {
+        self.read_children((0..self.children.len()).collect(),
+                           offset,
+                           buf,
+                           |_, data| verify(data))
+    }
+
+    /// The block is read from the other children, and written to `child` as a whole
+    fn resilver(&self,
+                offset: u64,
+                buf: &mut [u8],
+                child: usize,
+                verify: &Fn(&[u8]) -> bool)
+                -> io::Result<bool> {
+        if child >= self.children.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("mirror has no child {}", child)));
+        }
+        let others = (0..self.children.len()).filter(|&other| other != child).collect();
+        if !try!(self.read_children(others, offset, buf, |_, data| verify(data))) {
+            return Ok(false);
+        }
+        if let Err(e) = self.children[child].write_at(offset, buf) {
+            self.errors.lock().unwrap()[child].write += 1;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// A mirror needs only one of its children
+    fn state(&self) -> vdev::State {
+        redundant_state(&self.child_status(), self.children.len().saturating_sub(1))
+    }
+
+    fn child_status(&self) -> Vec<(vdev::State, ChildErrors)> {
+        child_status(&self.children, &self.errors.lock().unwrap())
+    }
+
+    fn kstats(&self, kstats: &mut kstat::Registry) {
+        for (child, errors) in self.errors.lock().unwrap().iter().enumerate() {
+            let labels = vec![("child".to_owned(), child.to_string())];
+            kstats.add("zfs_vdev_mirror_read_errors_total",
+                       "Reads a mirror child failed",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       errors.read as f64);
+            kstats.add("zfs_vdev_mirror_write_errors_total",
+                       "Writes a mirror child failed",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       errors.write as f64);
+            kstats.add("zfs_vdev_mirror_checksum_errors_total",
+                       "Reads a mirror child returned data with a bad checksum for",
+                       kstat::KstatKind::Counter,
+                       labels,
+                       errors.checksum as f64);
+        }
+    }
+}
+
+#[test]
+fn test_mirror_child_select() {
+    let mut mirror = VdevMirror::new(3);
+    // Nothing measured yet, the children take turns
+    assert_eq!(mirror.select(&[0, 1, 2]), Some(0));
+    assert_eq!(mirror.select(&[0, 1, 2]), Some(1));
+    assert_eq!(mirror.select(&[0, 2]), Some(2));
+
+    // A fast SSD next to two slow disks
+    mirror.io_done(0, 100_000);
+    mirror.io_done(1, 8_000_000);
+    mirror.io_done(2, 10_000_000);
+    assert_eq!(mirror.select(&[0, 1, 2]), Some(0));
+    assert_eq!(mirror.select(&[1, 2]), Some(1));
+
+    // The SSD stays the better choice with a few reads queued...
+    for _ in 0..10 {
+        mirror.io_start(0);
+    }
+    assert_eq!(mirror.select(&[0, 1, 2]), Some(0));
+    // ...but not with a hundred
+    for _ in 0..90 {
+        mirror.io_start(0);
+    }
+    assert_eq!(mirror.select(&[0, 1, 2]), Some(1));
+    assert_eq!(mirror.select(&[0]), Some(0));
+    assert_eq!(mirror.select(&[]), None);
+
+    // Latency follows the recent reads
+    mirror.io_done(1, 800_000);
+    assert_eq!(mirror.child_load(1).unwrap().latency_ns, 7_100_000);
+}
+
+#[test]
+fn test_mirror_failover() {
+    use super::vdev_io::MemVdev;
+
+    let good = MemVdev::new(vec![0x42; 4096]);
+    let bad = MemVdev::new(vec![0x13; 4096]);
+    let short = MemVdev::new(vec![0x42; 1024]);
+    let mut mirror = MirrorVdev::new(vec![Box::new(bad.clone()),
+                                          Box::new(short.clone()),
+                                          Box::new(good.clone())]);
+    assert_eq!(mirror.size().unwrap(), 1024);
+
+    // The first child has bad data and the second is too short, the third has it right
+    let mut buf = [0; 2048];
+    assert!(mirror.read_verified(0, &mut buf, &|data| data.iter().all(|&b| b == 0x42)).unwrap());
+    assert_eq!(&buf[..], &[0x42; 2048][..]);
+    assert_eq!(mirror.child_errors(0), Some(ChildErrors { read: 0, write: 0, checksum: 1 }));
+    assert_eq!(mirror.child_errors(1), Some(ChildErrors { read: 1, write: 0, checksum: 0 }));
+    assert_eq!(mirror.child_errors(2), Some(ChildErrors::default()));
+
+    // No child has data that passes
+    assert!(!mirror.read_verified(0, &mut buf, &|_| false).unwrap());
+    // Past the end of every child
+    assert!(mirror.read_verified(8192, &mut buf, &|_| true).is_err());
+
+    // Writes go everywhere, fixing the bad child
+    mirror.write_at(0, &[0x42; 512]).unwrap();
+    assert_eq!(&bad.to_vec()[..513], &[&[0x42; 512][..], &[0x13]].concat()[..]);
+    assert_eq!(&short.to_vec()[..512], &[0x42; 512][..]);
+
+    let mut kstats = kstat::Registry::new();
+    vdev_io::Vdev::kstats(&mirror, &mut kstats);
+    assert_eq!(kstats.stats().len(), 9);
+}
+
+#[test]
+fn test_mirror_read_block() {
+    use std::sync::atomic::Ordering;
+
+    use super::block_ptr::BlockPtr;
+    use super::checksum;
+    use super::dvaddr::DVAddr;
+    use super::from_bytes::FromBytes;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let data = [0x42; 512];
+    let mut good = vec![0; (0x2000 + 2) * 512];
+    good[0x2001 * 512..].copy_from_slice(&data);
+    let mut bad = good.clone();
+    bad[0x2001 * 512] = 0x13;
+    // Only one copy of the block, it's up to the mirror to find a good one
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 1 }, empty, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    block_ptr.checksum = checksum::compute(7, &data, false).unwrap();
+
+    let mut reader = zio::Reader::new(MirrorVdev::new(vec![Box::new(MemVdev::new(bad)),
+                                                           Box::new(MemVdev::new(good))]));
+    assert_eq!(reader.read_block(&block_ptr).unwrap(), data.to_vec());
+    assert_eq!(reader.ditto_failovers.load(Ordering::Relaxed), 0);
+
+    let mut kstats = kstat::Registry::new();
+    reader.kstats(&mut kstats);
+    let errors = kstats.stats()
+                       .iter()
+                       .filter(|stat| stat.name == "zfs_vdev_mirror_checksum_errors_total")
+                       .map(|stat| stat.value)
+                       .collect::<Vec<_>>();
+    assert_eq!(errors, vec![1.0, 0.0]);
+}
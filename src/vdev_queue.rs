@@ -1,14 +1,13 @@
-use super::zio;
-
 // ZFS IO Scheduler
 // ---------------
 //
 // ZFS issues IO operations to leaf vdevs to satisfy and complete zios.  The
 // IO scheduler determines when and in what order those operations are
-// issued.  The IO scheduler divides operations into five IO classes
-// prioritized in the following order: sync read, sync write, async read,
-// async write, and scrub/resilver.  Each queue defines the minimum and
-// maximum number of concurrent operations that may be issued to the device.
+// issued.  The IO scheduler divides operations into seven IO classes
+// prioritized in the following order: sync read, sync write, log write (ZIL
+// blocks), async read, async write, scrub/resilver, and free (TRIM).  Each
+// queue defines the minimum and maximum number of concurrent operations that
+// may be issued to the device.
 // In addition, the device has an aggregate maximum. Note that the sum of the
 // per-queue minimums must not exceed the aggregate maximum. If the
 // sum of the per-queue maximums exceeds the aggregate maximum, then the
@@ -77,606 +76,526 @@ use super::zio;
 // maximum percentage, this indicates that the rate of incoming data is
 // greater than the rate that the backend storage can handle. In this case, we
 // must further throttle incoming writes (see dmu_tx_delay() for details).
-
-// the sum of each queue's max_active.  It must be at least the sum of each
-// queue's min_active.
-uint32_t zfs_vdev_max_active = 1000;
-
-// Per-queue limits on the number of IOs active to each device.  If the
-// number of active IOs is < zfs_vdev_max_active, then the min_active comes
-// into play. We will send min_active from each queue, and then select from
-// queues in the order defined by zio_priority_t.
 //
-// In general, smaller max_active's will lead to lower latency of synchronous
-// operations.  Larger max_active's may lead to higher overall throughput,
-// depending on underlying storage.
+// Starvation
 //
-// The ratio of the queues' max_actives determines the balance of performance
-// between reads, writes, and scrubs.  E.g., increasing
-// zfs_vdev_scrub_max_active will cause the scrub or resilver to complete
-// more quickly, but reads and writes to have higher latency and lower
-// throughput.
-uint32_t zfs_vdev_sync_read_min_active = 10;
-uint32_t zfs_vdev_sync_read_max_active = 10;
-uint32_t zfs_vdev_sync_write_min_active = 10;
-uint32_t zfs_vdev_sync_write_max_active = 10;
-uint32_t zfs_vdev_async_read_min_active = 1;
-uint32_t zfs_vdev_async_read_max_active = 3;
-uint32_t zfs_vdev_async_write_min_active = 1;
-uint32_t zfs_vdev_async_write_max_active = 10;
-uint32_t zfs_vdev_scrub_min_active = 1;
-uint32_t zfs_vdev_scrub_max_active = 2;
-
-// When the pool has less than zfs_vdev_async_write_active_min_dirty_percent
-// dirty data, use zfs_vdev_async_write_min_active.  When it has more than
-// zfs_vdev_async_write_active_max_dirty_percent, use
-// zfs_vdev_async_write_max_active. The value is linearly interpolated
-// between min and max.
-int zfs_vdev_async_write_active_min_dirty_percent = 30;
-int zfs_vdev_async_write_active_max_dirty_percent = 60;
-
-// To reduce IOPs, we aggregate small adjacent IOs into one large IO.
-// For read IOs, we also aggregate across small adjacency gaps; for writes
-// we include spans of optional IOs to aid aggregation at the disk even when
-// they aren't able to help us aggregate at this level.
-int zfs_vdev_aggregation_limit = SPA_OLD_MAXBLOCKSIZE;
-int zfs_vdev_read_gap_limit = 32 << 10;
-int zfs_vdev_write_gap_limit = 4 << 10;
-
-fn vdev_queue_offset_compare(const void *x1, const void *x2) -> i32 {
-    const zio_t *z1 = x1;
-    const zio_t *z2 = x2;
-
-    if z1.offset < z2.offset {
-        return -1;
-    }
-    if z1.offset > z2.offset {
-        return 1;
-    }
+// Strict priority order means a steady stream of synchronous IO can keep the
+// lower classes from ever reaching the device once the aggregate maximum is
+// hit, since every completion hands its slot straight back to the sync
+// queues. To bound the queuing delay, an IO that has been queued for longer
+// than the queue's deadline is promoted: its class is served before any other
+// class, oldest expired IO first, as long as the class is below its own
+// maximum. Promotion only reorders the classes; the per-class and aggregate
+// limits still hold.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::collections::BTreeMap;
 
-    if z1 < z2 {
-        return -1;
-    }
-    if z1 > z2 {
-        return 1;
-    }
-
-    return 0;
-}
+use super::zio;
 
-static inline avl_tree_t *
-vdev_queue_class_tree(vdev_queue_t *vq, zio_priority_t p)
-{
-    return (&vq->vq_class[p].vqc_queued_tree);
+/// Maximum number of IOs active to a device at once. This should be at least
+/// the sum of the queues' min_active.
+pub const VDEV_MAX_ACTIVE: u32 = 1000;
+
+pub const VDEV_SYNC_READ_MIN_ACTIVE: u32 = 10;
+pub const VDEV_SYNC_READ_MAX_ACTIVE: u32 = 10;
+pub const VDEV_SYNC_WRITE_MIN_ACTIVE: u32 = 10;
+pub const VDEV_SYNC_WRITE_MAX_ACTIVE: u32 = 10;
+pub const VDEV_LOG_WRITE_MIN_ACTIVE: u32 = 10;
+pub const VDEV_LOG_WRITE_MAX_ACTIVE: u32 = 10;
+pub const VDEV_ASYNC_READ_MIN_ACTIVE: u32 = 1;
+pub const VDEV_ASYNC_READ_MAX_ACTIVE: u32 = 3;
+pub const VDEV_ASYNC_WRITE_MIN_ACTIVE: u32 = 1;
+pub const VDEV_ASYNC_WRITE_MAX_ACTIVE: u32 = 10;
+pub const VDEV_SCRUB_MIN_ACTIVE: u32 = 1;
+pub const VDEV_SCRUB_MAX_ACTIVE: u32 = 2;
+pub const VDEV_TRIM_MIN_ACTIVE: u32 = 1;
+pub const VDEV_TRIM_MAX_ACTIVE: u32 = 2;
+
+/// How long (in nanoseconds) an IO may sit in the queue before it's promoted
+/// ahead of the higher priority classes
+pub const VDEV_QUEUE_DEADLINE: u64 = 500 * 1000 * 1000;
+
+/// Largest IO adjacent IOs are aggregated into, in bytes
+pub const VDEV_AGGREGATION_LIMIT: u64 = 128 << 10;
+
+// The queueable classes, in the order they are served
+const NUM_QUEUEABLE: usize = 7;
+const CLASSES: [zio::Priority; NUM_QUEUEABLE] = [zio::Priority::SyncRead,
+                                                 zio::Priority::SyncWrite,
+                                                 zio::Priority::LogWrite,
+                                                 zio::Priority::AsyncRead,
+                                                 zio::Priority::AsyncWrite,
+                                                 zio::Priority::Scrub,
+                                                 zio::Priority::Free];
+
+fn class_index(p: zio::Priority) -> Option<usize> {
+    CLASSES.iter().position(|&class| class == p)
 }
 
-static inline avl_tree_t *
-vdev_queue_type_tree(vdev_queue_t *vq, zio_type_t t)
-{
-    assert!(t == ZIO_TYPE_READ || t == ZIO_TYPE_WRITE);
-    if t == ZIO_TYPE_READ {
-        return &vq->vq_read_offset_tree;
-    } else {
-        return &vq->vq_write_offset_tree;
+fn class_min_active(p: zio::Priority) -> u32 {
+    match p {
+        zio::Priority::SyncRead => VDEV_SYNC_READ_MIN_ACTIVE,
+        zio::Priority::SyncWrite => VDEV_SYNC_WRITE_MIN_ACTIVE,
+        zio::Priority::LogWrite => VDEV_LOG_WRITE_MIN_ACTIVE,
+        zio::Priority::AsyncRead => VDEV_ASYNC_READ_MIN_ACTIVE,
+        zio::Priority::AsyncWrite => VDEV_ASYNC_WRITE_MIN_ACTIVE,
+        zio::Priority::Scrub => VDEV_SCRUB_MIN_ACTIVE,
+        zio::Priority::Free => VDEV_TRIM_MIN_ACTIVE,
+        _ => panic!("invalid priority {:?}", p),
     }
 }
 
-int
-vdev_queue_timestamp_compare(const void *x1, const void *x2)
-{
-    const zio_t *z1 = x1;
-    const zio_t *z2 = x2;
-
-    if (z1->io_timestamp < z2->io_timestamp)
-        return (-1);
-    if (z1->io_timestamp > z2->io_timestamp)
-        return (1);
-
-    if (z1 < z2)
-        return (-1);
-    if (z1 > z2)
-        return (1);
-
-    return (0);
+// The synchronous queues are dispatched in FIFO rather than LBA order. This
+// provides more consistent latency for these IOs.
+fn is_fifo(p: zio::Priority) -> bool {
+    p == zio::Priority::SyncRead || p == zio::Priority::SyncWrite || p == zio::Priority::LogWrite
 }
 
-static int
-vdev_queue_class_min_active(zio_priority_t p)
-{
-    switch (p) {
-    case ZIO_PRIORITY_SYNC_READ:
-        return (zfs_vdev_sync_read_min_active);
-    case ZIO_PRIORITY_SYNC_WRITE:
-        return (zfs_vdev_sync_write_min_active);
-    case ZIO_PRIORITY_ASYNC_READ:
-        return (zfs_vdev_async_read_min_active);
-    case ZIO_PRIORITY_ASYNC_WRITE:
-        return (zfs_vdev_async_write_min_active);
-    case ZIO_PRIORITY_SCRUB:
-        return (zfs_vdev_scrub_min_active);
-    default:
-        panic("invalid priority %u", p);
-        return (0);
-    }
+/// An IO waiting in (or issued from) a vdev queue
+#[derive(Clone, Debug)]
+pub struct QueuedIo<T> {
+    pub offset: u64,
+    pub size: u64,
+    pub priority: zio::Priority,
+    /// When the IO was queued, in nanoseconds
+    pub timestamp: u64,
+    /// `zio::Flag`s, as a mask. `DontAggregate` keeps the IO out of aggregates.
+    pub flags: u32,
+    pub io: T,
 }
 
-static int
-vdev_queue_max_async_writes(spa_t *spa)
-{
-    int writes;
-    uint64_t dirty = spa->spa_dsl_pool->dp_dirty_total;
-    uint64_t min_bytes = zfs_dirty_data_max *
-        zfs_vdev_async_write_active_min_dirty_percent / 100;
-    uint64_t max_bytes = zfs_dirty_data_max *
-        zfs_vdev_async_write_active_max_dirty_percent / 100;
-
-    // Sync tasks correspond to interactive user actions. To reduce the
-    // execution time of those actions we push data out as fast as possible.
-    if (spa_has_pending_synctask(spa)) {
-        return zfs_vdev_async_write_max_active;
-    }
-
-    if dirty < min_bytes {
-        return zfs_vdev_async_write_min_active;
+impl<T> QueuedIo<T> {
+    fn can_aggregate(&self) -> bool {
+        self.flags & zio::Flag::DontAggregate as u32 == 0
     }
-    if dirty > max_bytes {
-        return zfs_vdev_async_write_max_active;
-    }
-
-    // linear interpolation:
-    // slope = (max_writes - min_writes) / (max_bytes - min_bytes)
-    // move right by min_bytes
-    // move up by min_writes
-    writes = (dirty - min_bytes) *
-             (zfs_vdev_async_write_max_active - zfs_vdev_async_write_min_active) /
-             (max_bytes - min_bytes) + zfs_vdev_async_write_min_active;
-    assert!(writes >= zfs_vdev_async_write_min_active);
-    assert!(writes <= zfs_vdev_async_write_max_active);
-    return (writes);
 }
 
-fn vdev_queue_class_max_active(spa_t *spa, p: zio::Priority) -> int {
-    match p {
-        zio::Priority::SyncRead =>  zfs_vdev_sync_read_max_active,
-        zio::Priority::SyncWrite => zfs_vdev_sync_write_max_active,
-        zio::Priority::AsyncRead => zfs_vdev_async_read_max_active,
-        zio::Priority::AsyncWrite => vdev_queue_max_async_writes(spa),
-        zio::Priority::Scrub => zfs_vdev_scrub_max_active,
-        _ => {
-            panic!("invalid priority {}", p);
-            0
-        }
-    }
+/// Queued IOs to adjacent sectors, issued to the device as a single IO
+#[derive(Clone, Debug)]
+pub struct AggregateIo<T> {
+    pub offset: u64,
+    pub size: u64,
+    /// In offset order, all of the same class
+    pub ios: Vec<QueuedIo<T>>,
 }
 
-// Return the IO class to issue from, or ZIO_PRIORITY_MAX_QUEUEABLE if
-// there is no eligible class.
-static zio_priority_t
-vdev_queue_class_to_issue(vdev_queue_t *vq)
-{
-    spa_t *spa = vq->vq_vdev->vdev_spa;
-    zio_priority_t p;
-
-    if (avl_numnodes(&vq->vq_active_tree) >= zfs_vdev_max_active)
-        return (ZIO_PRIORITY_NUM_QUEUEABLE);
-
-    for (p = 0; p < ZIO_PRIORITY_NUM_QUEUEABLE; p++) {
-        if (avl_numnodes(vdev_queue_class_tree(vq, p)) > 0 &&
-            vq->vq_class[p].vqc_active <
-            vdev_queue_class_min_active(p))
-            return (p);
-    }
-
-    // If we haven't found a queue, look for one that hasn't reached its
-    // maximum # outstanding IOs.
-    for (p = 0; p < ZIO_PRIORITY_NUM_QUEUEABLE; p++) {
-        if (avl_numnodes(vdev_queue_class_tree(vq, p)) > 0 &&
-            vq->vq_class[p].vqc_active <
-            vdev_queue_class_max_active(spa, p))
-            return (p);
+impl<T> AggregateIo<T> {
+    /// Split the data of the aggregate into that of each of its IOs, in the same order
+    pub fn split<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        self.ios
+            .iter()
+            .map(|io| {
+                let start = (io.offset - self.offset) as usize;
+                &data[start..start + io.size as usize]
+            })
+            .collect()
     }
-
-    return (ZIO_PRIORITY_NUM_QUEUEABLE);
 }
 
-void
-vdev_queue_init(vdev_t *vd)
-{
-    vdev_queue_t *vq = &vd->vdev_queue;
-    zio_priority_t p;
-
-    mutex_init(&vq->vq_lock, NULL, MUTEX_DEFAULT, NULL);
-    vq->vq_vdev = vd;
-    taskq_init_ent(&vd->vdev_queue.vq_io_search.io_tqent);
-
-    avl_create(&vq->vq_active_tree, vdev_queue_offset_compare,
-        sizeof (zio_t), offsetof(struct zio, io_queue_node));
-    avl_create(vdev_queue_type_tree(vq, ZIO_TYPE_READ),
-        vdev_queue_offset_compare, sizeof (zio_t),
-        offsetof(struct zio, io_offset_node));
-    avl_create(vdev_queue_type_tree(vq, ZIO_TYPE_WRITE),
-        vdev_queue_offset_compare, sizeof (zio_t),
-        offsetof(struct zio, io_offset_node));
-
-    for (p = 0; p < ZIO_PRIORITY_NUM_QUEUEABLE; p++) {
-        int (*compfn) (const void *, const void *);
-
-        // The synchronous IO queues are dispatched in FIFO rather
-        // than LBA order. This provides more consistent latency for
-        // these IOs.
-        if (p == ZIO_PRIORITY_SYNC_READ || p == ZIO_PRIORITY_SYNC_WRITE)
-            compfn = vdev_queue_timestamp_compare;
-        else
-            compfn = vdev_queue_offset_compare;
-        avl_create(vdev_queue_class_tree(vq, p), compfn,
-            sizeof (zio_t), offsetof(struct zio, io_queue_node));
-    }
+struct QueueClass<T> {
+    // Keyed by (timestamp, seq) for FIFO classes and (offset, seq) otherwise, the sequence
+    // number keeping equal keys apart
+    queued: BTreeMap<(u64, u64), QueuedIo<T>>,
+    active: u32,
 }
 
-void
-vdev_queue_fini(vdev_t *vd)
-{
-    vdev_queue_t *vq = &vd->vdev_queue;
-    zio_priority_t p;
-
-    for (p = 0; p < ZIO_PRIORITY_NUM_QUEUEABLE; p++)
-        avl_destroy(vdev_queue_class_tree(vq, p));
-    avl_destroy(&vq->vq_active_tree);
-    avl_destroy(vdev_queue_type_tree(vq, ZIO_TYPE_READ));
-    avl_destroy(vdev_queue_type_tree(vq, ZIO_TYPE_WRITE));
-
-    mutex_destroy(&vq->vq_lock);
-}
-
-static void
-vdev_queue_io_add(vdev_queue_t *vq, zio_t *zio)
-{
-    spa_t *spa = zio->io_spa;
-    spa_stats_history_t *ssh = &spa->spa_stats.io_history;
-
-    assert!(zio->io_priority, <, ZIO_PRIORITY_NUM_QUEUEABLE);
-    avl_add(vdev_queue_class_tree(vq, zio->io_priority), zio);
-    avl_add(vdev_queue_type_tree(vq, zio->io_type), zio);
-
-    if (ssh->kstat != NULL) {
-        mutex_enter(&ssh->lock);
-        kstat_waitq_enter(ssh->kstat->ks_data);
-        mutex_exit(&ssh->lock);
+impl<T> QueueClass<T> {
+    fn new() -> Self {
+        QueueClass {
+            queued: BTreeMap::new(),
+            active: 0,
+        }
     }
-}
 
-static void
-vdev_queue_io_remove(vdev_queue_t *vq, zio_t *zio)
-{
-    spa_t *spa = zio->io_spa;
-    spa_stats_history_t *ssh = &spa->spa_stats.io_history;
-
-    assert!(zio->io_priority, <, ZIO_PRIORITY_NUM_QUEUEABLE);
-    avl_remove(vdev_queue_class_tree(vq, zio->io_priority), zio);
-    avl_remove(vdev_queue_type_tree(vq, zio->io_type), zio);
-
-    if (ssh->kstat != NULL) {
-        mutex_enter(&ssh->lock);
-        kstat_waitq_exit(ssh->kstat->ks_data);
-        mutex_exit(&ssh->lock);
+    fn oldest(&self) -> Option<(u64, (u64, u64))> {
+        self.queued.iter().map(|(key, io)| (io.timestamp, *key)).min()
     }
 }
 
-static void
-vdev_queue_pending_add(vdev_queue_t *vq, zio_t *zio)
-{
-    spa_t *spa = zio->io_spa;
-    spa_stats_history_t *ssh = &spa->spa_stats.io_history;
-
-    ASSERT(MUTEX_HELD(&vq->vq_lock));
-    assert!(zio->io_priority, <, ZIO_PRIORITY_NUM_QUEUEABLE);
-    vq->vq_class[zio->io_priority].vqc_active++;
-    avl_add(&vq->vq_active_tree, zio);
-
-    if (ssh->kstat != NULL) {
-        mutex_enter(&ssh->lock);
-        kstat_runq_enter(ssh->kstat->ks_data);
-        mutex_exit(&ssh->lock);
-    }
+/// Per leaf vdev IO scheduler
+pub struct VdevQueue<T> {
+    classes: Vec<QueueClass<T>>,
+    /// Aggregate limit on the number of active IOs
+    pub max_active: u32,
+    /// Limit for the async write class, which depends on the pool's dirty data (see
+    /// `Spa::vdev_queue_max_async_writes`)
+    pub async_write_max_active: u32,
+    /// Queuing delay after which an IO is promoted. `None` disables promotion.
+    pub deadline: Option<u64>,
+    last_offset: u64,
+    seq: u64,
 }
 
-static void
-vdev_queue_pending_remove(vdev_queue_t *vq, zio_t *zio)
-{
-    spa_t *spa = zio->io_spa;
-    spa_stats_history_t *ssh = &spa->spa_stats.io_history;
-
-    ASSERT(MUTEX_HELD(&vq->vq_lock));
-    assert!(zio->io_priority, <, ZIO_PRIORITY_NUM_QUEUEABLE);
-    vq->vq_class[zio->io_priority].vqc_active--;
-    avl_remove(&vq->vq_active_tree, zio);
-
-    if (ssh->kstat != NULL) {
-        kstat_io_t *ksio = ssh->kstat->ks_data;
-
-        mutex_enter(&ssh->lock);
-        kstat_runq_exit(ksio);
-        if (zio->io_type == ZIO_TYPE_READ) {
-            ksio->reads++;
-            ksio->nread += zio->io_size;
-        } else if (zio->io_type == ZIO_TYPE_WRITE) {
-            ksio->writes++;
-            ksio->nwritten += zio->io_size;
+impl<T> VdevQueue<T> {
+    pub fn new() -> Self {
+        VdevQueue {
+            classes: (0..NUM_QUEUEABLE).map(|_| QueueClass::new()).collect(),
+            max_active: VDEV_MAX_ACTIVE,
+            async_write_max_active: VDEV_ASYNC_WRITE_MIN_ACTIVE,
+            deadline: Some(VDEV_QUEUE_DEADLINE),
+            last_offset: 0,
+            seq: 0,
         }
-        mutex_exit(&ssh->lock);
     }
-}
 
-fn vdev_queue_agg_io_done(aio: &mut Zio) {
-    if (aio.zio_type == ZIO_TYPE_READ) {
-        zio_t *pio;
-        while (pio = zio_walk_parents(aio)) != NULL {
-            bcopy(aio.data + (pio.offset - aio.offset), pio.data, pio.size);
+    fn class_max_active(&self, p: zio::Priority) -> u32 {
+        match p {
+            zio::Priority::SyncRead => VDEV_SYNC_READ_MAX_ACTIVE,
+            zio::Priority::SyncWrite => VDEV_SYNC_WRITE_MAX_ACTIVE,
+            zio::Priority::LogWrite => VDEV_LOG_WRITE_MAX_ACTIVE,
+            zio::Priority::AsyncRead => VDEV_ASYNC_READ_MAX_ACTIVE,
+            zio::Priority::AsyncWrite => self.async_write_max_active,
+            zio::Priority::Scrub => VDEV_SCRUB_MAX_ACTIVE,
+            zio::Priority::Free => VDEV_TRIM_MAX_ACTIVE,
+            _ => panic!("invalid priority {:?}", p),
         }
     }
 
-    zio_buf_free(aio.data, aio.size);
-}
-
-// Compute the range spanned by two IOs, which is the endpoint of the last
-// (lio->io_offset + lio->io_size) minus start of the first (fio->io_offset).
-// Conveniently, the gap between fio and lio is given by -IO_SPAN(lio, fio);
-// thus fio and lio are adjacent if and only if IO_SPAN(lio, fio) == 0.
-#define    IO_SPAN(fio, lio) ((lio)->io_offset + (lio)->io_size - (fio)->io_offset)
-#define    IO_GAP(fio, lio) (-IO_SPAN(lio, fio))
-
-static zio_t *
-vdev_queue_aggregate(vdev_queue_t *vq, zio_t *zio)
-{
-    zio_t *first, *last, *aio, *dio, *mandatory, *nio;
-    uint64_t maxgap = 0;
-    uint64_t size;
-    boolean_t stretch = B_FALSE;
-    avl_tree_t *t = vdev_queue_type_tree(vq, zio->io_type);
-    enum zio_flag flags = zio->io_flags & ZIO_FLAG_AGG_INHERIT;
-
-    if (zio->io_flags & ZIO_FLAG_DONT_AGGREGATE)
-        return (NULL);
-
-    // Prevent users from setting the zfs_vdev_aggregation_limit
-    // tuning larger than SPA_MAXBLOCKSIZE.
-    zfs_vdev_aggregation_limit =
-        MIN(zfs_vdev_aggregation_limit, SPA_MAXBLOCKSIZE);
-
-    first = last = zio;
-
-    if (zio->io_type == ZIO_TYPE_READ)
-        maxgap = zfs_vdev_read_gap_limit;
-
-    // We can aggregate IOs that are sufficiently adjacent and of
-    // the same flavor, as expressed by the AGG_INHERIT flags.
-    // The latter requirement is necessary so that certain
-    // attributes of the IO, such as whether it's a normal IO
-    // or a scrub/resilver, can be preserved in the aggregate.
-    // We can include optional IOs, but don't allow them
-    // to begin a range as they add no benefit in that situation.
-
-    // We keep track of the last non-optional IO.
-    mandatory = (first->io_flags & ZIO_FLAG_OPTIONAL) ? NULL : first;
-
-    // Walk backwards through sufficiently contiguous IOs
-    // recording the last non-option IO.
-    while ((dio = AVL_PREV(t, first)) != NULL &&
-        (dio->io_flags & ZIO_FLAG_AGG_INHERIT) == flags &&
-        IO_SPAN(dio, last) <= zfs_vdev_aggregation_limit &&
-        IO_GAP(dio, first) <= maxgap) {
-        first = dio;
-        if (mandatory == NULL && !(first->io_flags & ZIO_FLAG_OPTIONAL))
-            mandatory = first;
+    pub fn num_queued(&self) -> usize {
+        self.classes.iter().map(|class| class.queued.len()).sum()
     }
 
-    // Skip any initial optional IOs.
-    while ((first->io_flags & ZIO_FLAG_OPTIONAL) && first != last) {
-        first = AVL_NEXT(t, first);
-        ASSERT(first != NULL);
+    pub fn num_active(&self) -> u32 {
+        self.classes.iter().map(|class| class.active).sum()
     }
 
-
-    // Walk forward through sufficiently contiguous IOs.
-    while ((dio = AVL_NEXT(t, last)) != NULL &&
-        (dio->io_flags & ZIO_FLAG_AGG_INHERIT) == flags &&
-        IO_SPAN(first, dio) <= zfs_vdev_aggregation_limit &&
-        IO_GAP(last, dio) <= maxgap) {
-        last = dio;
-        if (!(last->io_flags & ZIO_FLAG_OPTIONAL))
-            mandatory = last;
+    /// Queue an IO. Only the sync/async read/write, log write, scrub and free classes are
+    /// queueable.
+    pub fn add(&mut self, io: QueuedIo<T>) {
+        let index = class_index(io.priority).expect("IO priority is not queueable");
+        let key = if is_fifo(io.priority) {
+            (io.timestamp, self.seq)
+        } else {
+            (io.offset, self.seq)
+        };
+        self.seq += 1;
+        self.classes[index].queued.insert(key, io);
     }
 
-    // Now that we've established the range of the IO aggregation
-    // we must decide what to do with trailing optional IOs.
-    // For reads, there's nothing to do. While we are unable to
-    // aggregate further, it's possible that a trailing optional
-    // IO would allow the underlying device to aggregate with
-    // subsequent IOs. We must therefore determine if the next
-    // non-optional IO is close enough to make aggregation
-    // worthwhile.
-    if (zio->io_type == ZIO_TYPE_WRITE && mandatory != NULL) {
-        zio_t *nio = last;
-        while ((dio = AVL_NEXT(t, nio)) != NULL &&
-            IO_GAP(nio, dio) == 0 &&
-            IO_GAP(mandatory, dio) <= zfs_vdev_write_gap_limit) {
-            nio = dio;
-            if (!(nio->io_flags & ZIO_FLAG_OPTIONAL)) {
-                stretch = B_TRUE;
-                break;
-            }
+    // Pick the class to issue from and the key of the IO to issue, if any class is eligible
+    fn class_to_issue(&self, now: u64) -> Option<(usize, Option<(u64, u64)>)> {
+        if self.num_active() >= self.max_active {
+            return None;
         }
-    }
 
-    if (stretch) {
-        // This may be a no-op.
-        dio = AVL_NEXT(t, last);
-        dio->io_flags &= ~ZIO_FLAG_OPTIONAL;
-    } else {
-        while (last != mandatory && last != first) {
-            ASSERT(last->io_flags & ZIO_FLAG_OPTIONAL);
-            last = AVL_PREV(t, last);
-            ASSERT(last != NULL);
+        // Expired IOs go first, oldest first
+        if let Some(deadline) = self.deadline {
+            let expired = self.classes
+                              .iter()
+                              .enumerate()
+                              .filter(|&(p, class)| {
+                                  class.active < self.class_max_active(CLASSES[p])
+                              })
+                              .filter_map(|(p, class)| class.oldest().map(|oldest| (oldest, p)))
+                              .filter(|&((timestamp, _), _)| {
+                                  now.saturating_sub(timestamp) >= deadline
+                              })
+                              .min();
+            if let Some(((_, key), p)) = expired {
+                return Some((p, Some(key)));
+            }
         }
-    }
 
-    if (first == last)
-        return (NULL);
-
-    size = IO_SPAN(first, last);
-    assert!(size, <=, zfs_vdev_aggregation_limit);
-
-    aio = zio_vdev_delegated_io(first->io_vd, first->io_offset,
-        zio_buf_alloc(size), size, first->io_type, zio->io_priority,
-        flags | ZIO_FLAG_DONT_CACHE | ZIO_FLAG_DONT_QUEUE,
-        vdev_queue_agg_io_done, NULL);
-    aio->io_timestamp = first->io_timestamp;
-
-    nio = first;
-    do {
-        dio = nio;
-        nio = AVL_NEXT(t, dio);
-        assert!(dio->io_type, ==, aio->io_type);
-
-        if (dio->io_flags & ZIO_FLAG_NODATA) {
-            assert!(dio->io_type, ==, ZIO_TYPE_WRITE);
-            bzero((char *)aio->io_data + (dio->io_offset -
-                aio->io_offset), dio->io_size);
-        } else if (dio->io_type == ZIO_TYPE_WRITE) {
-            bcopy(dio->io_data, (char *)aio->io_data +
-                (dio->io_offset - aio->io_offset),
-                dio->io_size);
+        for (p, class) in self.classes.iter().enumerate() {
+            if !class.queued.is_empty() && class.active < class_min_active(CLASSES[p]) {
+                return Some((p, None));
+            }
         }
 
-        zio_add_child(dio, aio);
-        vdev_queue_io_remove(vq, dio);
-        zio_vdev_io_bypass(dio);
-        zio_execute(dio);
-    } while (dio != last);
-
-    return (aio);
-}
-
-fn vdev_queue_io_to_issue(vdev_queue_t *vq) -> Option<Zio> {
-    zio_t *zio, *aio;
-    zio_priority_t p;
-    avl_index_t idx;
-    avl_tree_t *tree;
+        // If we haven't found a queue, look for one that hasn't reached its maximum # outstanding
+        // IOs.
+        for (p, class) in self.classes.iter().enumerate() {
+            if !class.queued.is_empty() && class.active < self.class_max_active(CLASSES[p]) {
+                return Some((p, None));
+            }
+        }
 
-again:
-    ASSERT(MUTEX_HELD(&vq->vq_lock));
+        None
+    }
 
-    p = vdev_queue_class_to_issue(vq);
+    /// Take the next IO to send to the device, marking it active. `now` is the current time in
+    /// nanoseconds, used to find IOs past their deadline.
+    pub fn issue(&mut self, now: u64) -> Option<QueuedIo<T>> {
+        let (p, key) = match self.class_to_issue(now) {
+            Some(choice) => choice,
+            None => return None,
+        };
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let class = &self.classes[p];
+                if is_fifo(CLASSES[p]) {
+                    // Issue the IO with the lowest timestamp
+                    *class.queued.keys().next().unwrap()
+                } else {
+                    // Issue the IO which follows the most recently issued IO in LBA order
+                    *class.queued
+                          .range((self.last_offset + 1, 0)..)
+                          .next()
+                          .or_else(|| class.queued.iter().next())
+                          .unwrap()
+                          .0
+                }
+            }
+        };
 
-    if (p == ZIO_PRIORITY_NUM_QUEUEABLE) {
-        // No eligible queued IOs
-        return (NULL);
+        let class = &mut self.classes[p];
+        let io = class.queued.remove(&key).unwrap();
+        class.active += 1;
+        self.last_offset = io.offset;
+        Some(io)
     }
 
-    // For LBA-ordered queues (async / scrub), issue the IO which follows
-    // the most recently issued IO in LBA (offset) order.
-    //
-    // For FIFO queues (sync), issue the IO with the lowest timestamp.
-    tree = vdev_queue_class_tree(vq, p);
-    vq->vq_io_search.io_timestamp = 0;
-    vq->vq_io_search.io_offset = vq->vq_last_offset + 1;
-    //VERIFY(avl_find(tree, &vq->vq_io_search, &idx) == NULL);
-    zio = avl_nearest(tree, idx, AVL_AFTER);
-    if (zio == NULL)
-        zio = avl_first(tree);
-    assert!(zio->io_priority == p);
-
-    aio = vdev_queue_aggregate(vq, zio);
-    if (aio != NULL)
-        zio = aio;
-    else
-        vdev_queue_io_remove(vq, zio);
-
-    // If the IO is or was optional and therefore has no data, we need to
-    // simply discard it. We need to drop the vdev queue's lock to avoid a
-    // deadlock that we could encounter since this IO will complete
-    // immediately.
-    if (zio->io_flags & ZIO_FLAG_NODATA) {
-        mutex_exit(&vq->vq_lock);
-        zio_vdev_io_bypass(zio);
-        zio_execute(zio);
-        mutex_enter(&vq->vq_lock);
-        goto again;
+    /// Called once an issued IO completes, freeing its slot
+    pub fn done(&mut self, io: &QueuedIo<T>) {
+        let index = class_index(io.priority).expect("IO priority is not queueable");
+        assert!(self.classes[index].active > 0);
+        self.classes[index].active -= 1;
     }
 
-    vdev_queue_pending_add(vq, zio);
-    vq->vq_last_offset = zio->io_offset;
-
-    return (zio);
-}
+    /// The key of a queued IO of class `p` that can be aggregated with one at `offset`: one
+    /// ending there if `before`, or starting there
+    fn adjacent(&self, p: usize, offset: u64, before: bool) -> Option<(u64, u64)> {
+        let adjacent = |io: &QueuedIo<T>| {
+            io.can_aggregate() &&
+            if before { io.offset + io.size == offset } else { io.offset == offset }
+        };
+        let queued = &self.classes[p].queued;
+        let found = if is_fifo(CLASSES[p]) {
+            queued.iter().find(|&(_, io)| adjacent(io))
+        } else if before {
+            queued.range(..(offset, 0)).next_back().filter(|&(_, io)| adjacent(io))
+        } else {
+            queued.range((offset, 0)..).next().filter(|&(_, io)| adjacent(io))
+        };
+        found.map(|(&key, _)| key)
+    }
 
-pub fn vdev_queue_io(zio_t *zio) -> Option<Zio> {
-    vdev_queue_t *vq = &zio.vd.vdev_queue;
+    /// Like `issue`, but along with the IO, take the queued IOs of the same class to the sectors
+    /// right before and after it, up to `VDEV_AGGREGATION_LIMIT` bytes in all. They're issued as
+    /// one IO, taking one slot (see `done_aggregate`).
+    pub fn issue_aggregate(&mut self, now: u64) -> Option<AggregateIo<T>> {
+        let first = match self.issue(now) {
+            Some(io) => io,
+            None => return None,
+        };
+        let p = class_index(first.priority).unwrap();
+        let mut aggregate = AggregateIo {
+            offset: first.offset,
+            size: first.size,
+            ios: Vec::new(),
+        };
+        let aggregate_first = first.can_aggregate();
+        aggregate.ios.push(first);
+        if !aggregate_first {
+            return Some(aggregate);
+        }
 
-    if zio->io_flags & ZIO_FLAG_DONT_QUEUE != 0 {
-        return zio;
+        for &before in &[true, false] {
+            loop {
+                let edge = if before {
+                    aggregate.offset
+                } else {
+                    aggregate.offset + aggregate.size
+                };
+                let key = match self.adjacent(p, edge, before) {
+                    Some(key) => key,
+                    None => break,
+                };
+                if aggregate.size + self.classes[p].queued[&key].size > VDEV_AGGREGATION_LIMIT {
+                    break;
+                }
+                let io = self.classes[p].queued.remove(&key).unwrap();
+                aggregate.size += io.size;
+                if before {
+                    aggregate.offset = io.offset;
+                    aggregate.ios.insert(0, io);
+                } else {
+                    aggregate.ios.push(io);
+                }
+            }
+        }
+        self.last_offset = aggregate.ios.last().unwrap().offset;
+        Some(aggregate)
     }
 
-    // Children IOs inherent their parent's priority, which might
-    // not match the child's IO type. Fix it up here.
-    if zio.zio_type == ZIO_TYPE_READ {
-        if zio->io_priority != ZIO_PRIORITY_SYNC_READ &&
-            zio->io_priority != ZIO_PRIORITY_ASYNC_READ &&
-            zio->io_priority != ZIO_PRIORITY_SCRUB
-        {
-            zio->io_priority = ZIO_PRIORITY_ASYNC_READ;
-        }
-    } else {
-        assert!(zio.zio_type == ZIO_TYPE_WRITE);
-        if (zio.priority != ZIO_PRIORITY_SYNC_WRITE &&
-            zio.priority != ZIO_PRIORITY_ASYNC_WRITE)
-            zio.priority = ZIO_PRIORITY_ASYNC_WRITE;
+    /// Called once an issued aggregate completes, freeing its slot
+    pub fn done_aggregate(&mut self, io: &AggregateIo<T>) {
+        self.done(&io.ios[0]);
     }
+}
 
-    zio->io_flags |= ZIO_FLAG_DONT_CACHE | ZIO_FLAG_DONT_QUEUE;
+// Simulate a device with `max_active` slots which completes one IO every `service_time`
+// nanoseconds, fed with a never ending stream of sync reads on top of the given background
+// IOs (queued at time 0). Returns the queuing delay of every background IO that got issued.
+#[cfg(test)]
+fn simulate(deadline: Option<u64>,
+            max_active: u32,
+            background: &[zio::Priority],
+            service_time: u64,
+            duration: u64)
+            -> Vec<(zio::Priority, u64)> {
+    use std::collections::VecDeque;
+
+    let mut queue = VdevQueue::new();
+    queue.deadline = deadline;
+    queue.max_active = max_active;
+
+    for (i, &priority) in background.iter().enumerate() {
+        queue.add(QueuedIo {
+            offset: i as u64 * 4096,
+            size: 4096,
+            priority: priority,
+            timestamp: 0,
+            flags: 0,
+            io: true,
+        });
+    }
 
-    mutex_enter(&vq->vq_lock);
-    zio.timestamp = gethrtime();
-    vdev_queue_io_add(vq, zio);
-    let nio = vdev_queue_io_to_issue(vq);
-    mutex_exit(&vq->vq_lock);
+    let mut delays = Vec::new();
+    let mut device = VecDeque::new();
+    let mut sync_offset = 0;
+    let mut now = 0;
+    while now < duration {
+        // Keep more sync reads queued than the device can take
+        while queue.num_queued() < background.len() + 2 * max_active as usize {
+            queue.add(QueuedIo {
+                offset: sync_offset,
+                size: 4096,
+                priority: zio::Priority::SyncRead,
+                timestamp: now,
+                flags: 0,
+                io: false,
+            });
+            sync_offset += 4096;
+        }
 
-    if let Some(nio) = nio {
-        if nio.done == vdev_queue_agg_io_done {
-            nio.no_wait();
-            return None;
+        while let Some(io) = queue.issue(now) {
+            if io.io {
+                delays.push((io.priority, now - io.timestamp));
+            }
+            device.push_back(io);
         }
+
+        now += service_time;
+        let io = device.pop_front().unwrap();
+        queue.done(&io);
     }
 
-    nio
+    delays
 }
 
-fn vdev_queue_io_done(zio_t *zio) {
-    vdev_queue_t *vq = &zio->io_vd->vdev_queue;
-    zio_t *nio;
+#[test]
+fn test_sync_read_starvation() {
+    let background = [zio::Priority::Scrub, zio::Priority::AsyncRead, zio::Priority::AsyncWrite];
+    let delays = simulate(None, 4, &background, 1000 * 1000, 10 * VDEV_QUEUE_DEADLINE);
+    assert!(delays.is_empty());
+}
 
-    if zio_injection_enabled {
-        delay(SEC_TO_TICK(zio_handle_io_delay(zio)));
+#[test]
+fn test_bounded_queuing_delay() {
+    let background = [zio::Priority::Scrub,
+                      zio::Priority::Scrub,
+                      zio::Priority::Scrub,
+                      zio::Priority::AsyncRead,
+                      zio::Priority::AsyncRead,
+                      zio::Priority::AsyncWrite,
+                      zio::Priority::SyncWrite];
+    let service_time = 1000 * 1000;
+    let deadline = 50 * service_time;
+    let delays = simulate(Some(deadline), 4, &background, service_time, 100 * deadline);
+
+    assert_eq!(delays.len(), background.len());
+    // Each expired IO waits at most for the ones that expired at the same time, and scrub only
+    // gets VDEV_SCRUB_MAX_ACTIVE slots
+    let bound = deadline + background.len() as u64 * 4 * service_time;
+    for &(priority, delay) in &delays {
+        assert!(delay <= bound, "{:?} waited {}ns", priority, delay);
     }
+    // Even the sync write only gets through once promoted
+    assert!(delays.iter().all(|&(_, delay)| delay >= deadline));
+}
 
-    mutex_enter(&vq->vq_lock);
-
-    vdev_queue_pending_remove(vq, zio);
+#[test]
+fn test_priority_order_before_deadline() {
+    let mut queue = VdevQueue::new();
+    queue.max_active = 1;
+    for &(priority, timestamp) in &[(zio::Priority::Scrub, 0),
+                                    (zio::Priority::SyncRead, 10),
+                                    (zio::Priority::AsyncRead, 20)] {
+        queue.add(QueuedIo {
+            offset: timestamp,
+            size: 512,
+            priority: priority,
+            timestamp: timestamp,
+            flags: 0,
+            io: (),
+        });
+    }
 
-    zio.delta = gethrtime() - zio.timestamp;
-    vq.io_complete_ts = gethrtime();
-    vq.io_delta_ts = vq.io_complete_ts - zio.timestamp;
+    let io = queue.issue(100).unwrap();
+    assert_eq!(io.priority, zio::Priority::SyncRead);
+    assert!(queue.issue(100).is_none());
+    queue.done(&io);
+
+    // Once past its deadline the scrub IO overtakes the async read
+    let io = queue.issue(VDEV_QUEUE_DEADLINE).unwrap();
+    assert_eq!(io.priority, zio::Priority::Scrub);
+    queue.done(&io);
+    assert_eq!(queue.issue(VDEV_QUEUE_DEADLINE).unwrap().priority, zio::Priority::AsyncRead);
+    assert_eq!(queue.num_queued(), 0);
+    assert_eq!(queue.num_active(), 1);
+}
 
-    while (nio = vdev_queue_io_to_issue(vq)) != NULL {
-        mutex_exit(&vq->vq_lock);
-        if (nio.done == vdev_queue_agg_io_done) {
-            nio.no_wait();
-        } else {
-            zio_vdev_io_reissue(nio);
-            nio.execute();
-        }
-        mutex_enter(&vq.lock);
+#[test]
+fn test_aggregate() {
+    let mut queue = VdevQueue::new();
+    let mut add = |offset: u64, size: u64, flags: u32| {
+        queue.add(QueuedIo {
+            offset: offset,
+            size: size,
+            priority: zio::Priority::Scrub,
+            timestamp: 0,
+            flags: flags,
+            io: offset,
+        })
+    };
+    add(0, 4096, 0);
+    add(4096, 4096, 0);
+    add(8192, 4096, 0);
+    add(12288, 4096, zio::Flag::DontAggregate as u32);
+    add(16384, 4096, 0);
+    add(24576, 4096, 0);
+    // Past the aggregation limit
+    for i in 0..64 {
+        add((1 << 20) + i * 4096, 4096, 0);
     }
 
-    mutex_exit(&vq->vq_lock);
+    // The first IO issued is the one after the last offset, the IOs before it come along
+    let aggregate = queue.issue_aggregate(0).unwrap();
+    assert_eq!((aggregate.offset, aggregate.size), (0, 12288));
+    assert_eq!(aggregate.ios.iter().map(|io| io.io).collect::<Vec<_>>(), vec![0, 4096, 8192]);
+    let data: Vec<u8> = (0..12288).map(|i| (i / 4096) as u8).collect();
+    assert_eq!(aggregate.split(&data),
+               vec![&[0; 4096][..], &[1; 4096][..], &[2; 4096][..]]);
+    assert_eq!(queue.num_active(), 1);
+    queue.done_aggregate(&aggregate);
+
+    let aggregate = queue.issue_aggregate(0).unwrap();
+    assert_eq!((aggregate.offset, aggregate.ios.len()), (12288, 1));
+    queue.done_aggregate(&aggregate);
+    // There's a gap after the next one
+    let aggregate = queue.issue_aggregate(0).unwrap();
+    assert_eq!((aggregate.offset, aggregate.ios.len()), (16384, 1));
+    queue.done_aggregate(&aggregate);
+    let aggregate = queue.issue_aggregate(0).unwrap();
+    assert_eq!((aggregate.offset, aggregate.ios.len()), (24576, 1));
+    queue.done_aggregate(&aggregate);
+
+    let aggregate = queue.issue_aggregate(0).unwrap();
+    assert_eq!((aggregate.offset, aggregate.size), (1 << 20, VDEV_AGGREGATION_LIMIT));
+    assert_eq!(queue.num_queued(), 64 - 32);
 }
@@ -0,0 +1,1050 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, io};
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::kstat;
+use super::spa_config::{VDEV_LABEL_END_SIZE, VDEV_LABEL_START_SIZE};
+use super::util;
+use super::vdev;
+use super::vdev_io::{self, Vdev};
+use super::vdev_mirror::{self, ChildErrors};
+
+/// raidz3 is as far as it goes
+pub const VDEV_RAIDZ_MAXPARITY: usize = 3;
+
+/// Multiplication in GF(2^8), the field the Q and R syndromes are computed in, using the
+/// polynomial x^8 + x^4 + x^3 + x^2 + 1 like ZFS does
+struct Gf {
+    exp: [u8; 255],
+    log: [u8; 256],
+}
+
+impl Gf {
+    fn new() -> Self {
+        let mut gf = Gf {
+            exp: [0; 255],
+            log: [0; 256],
+        };
+        let mut x = 1u8;
+        for i in 0..255 {
+            gf.exp[i] = x;
+            gf.log[x as usize] = i as u8;
+            x = mul2(x);
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[(self.log[a as usize] as usize + self.log[b as usize] as usize) % 255]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[(255 - self.log[a as usize] as usize) % 255]
+    }
+
+    /// 2 to the `power`
+    fn pow2(&self, power: usize) -> u8 {
+        self.exp[power % 255]
+    }
+}
+
+fn mul2(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1D } else { 0 }
+}
+
+/// One column of a raidz map: the part of a block that's on one child
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RaidzCol {
+    pub child: usize,
+    /// Byte offset on the child, not counting the labels at its front
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// How a block is laid out on the children of a raidz vdev (`vdev_raidz_map_alloc`). The block
+/// is cut in sectors that are dealt out to the children in turn, starting from the child its
+/// offset falls on, after `nparity` parity columns. Columns are the same size, or one sector
+/// apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaidzMap {
+    /// Parity columns first, then the data columns in the order the data is in
+    pub cols: Vec<RaidzCol>,
+    pub nparity: usize,
+    /// What the block takes up on the children, including the skip sectors
+    pub asize: u64,
+    /// Where each data column's bytes go in the block
+    data_offsets: Vec<u64>,
+}
+
+impl RaidzMap {
+    /// The map of the block of `size` bytes (a multiple of `1 << ashift`) at byte `offset` of a
+    /// raidz vdev with `children` children, `nparity` of which hold parity
+    pub fn new(offset: u64, size: u64, ashift: u64, children: usize, nparity: usize) -> Self {
+        let dcols = children as u64;
+        let nparity64 = nparity as u64;
+        // Everything in sectors of the vdev
+        let b = offset >> ashift;
+        let s = size >> ashift;
+        let f = b % dcols;
+        let o = (b / dcols) << ashift;
+        let q = s / (dcols - nparity64);
+        let r = s - q * (dcols - nparity64);
+        // The first `bc` columns have a sector more than the others
+        let bc = if r == 0 { 0 } else { r + nparity64 };
+        // Small blocks don't reach every child
+        let acols = if q == 0 { bc } else { dcols };
+
+        let mut cols = Vec::with_capacity(acols as usize);
+        let mut asize = 0;
+        for c in 0..acols {
+            let mut child = f + c;
+            let mut coff = o;
+            if child >= dcols {
+                child -= dcols;
+                coff += 1 << ashift;
+            }
+            let size = if c < bc { (q + 1) << ashift } else { q << ashift };
+            asize += size;
+            cols.push(RaidzCol {
+                child: child as usize,
+                offset: coff,
+                size: size,
+            });
+        }
+
+        // On raidz1, which child gets the parity alternates every 1M, so it isn't all on one
+        // child. Only where the columns are is swapped, their sizes stay.
+        if nparity == 1 && offset & (1 << 20) != 0 && cols.len() > 1 {
+            let (child, offset) = (cols[0].child, cols[0].offset);
+            cols[0].child = cols[1].child;
+            cols[0].offset = cols[1].offset;
+            cols[1].child = child;
+            cols[1].offset = offset;
+        }
+
+        // The data columns hold the block in order
+        let data_offsets = cols[nparity..]
+                               .iter()
+                               .scan(0, |offset, col| {
+                                   *offset += col.size;
+                                   Some(*offset - col.size)
+                               })
+                               .collect();
+        RaidzMap {
+            cols: cols,
+            nparity: nparity,
+            asize: util::round_up(asize, (nparity64 + 1) << ashift),
+            data_offsets: data_offsets,
+        }
+    }
+
+    /// The maps of a block laid out `logical_cols` wide on a raidz vdev that's been expanded to
+    /// `physical_cols` children since (`vdev_raidz_map_alloc_expanded`). The data is still dealt
+    /// out to the columns the way it was at that width, but the expansion moved every sector of
+    /// the vdev to where it goes on the wider vdev, so each row of the block now starts at its
+    /// own child and offset. A map is returned per row, of a sector per column; the last row's
+    /// columns past the end of the data are empty, they only count as zeros in the parity. Rows
+    /// reaching `reflow_offset` or past it haven't been moved yet.
+    pub fn expanded(offset: u64,
+                    size: u64,
+                    ashift: u64,
+                    physical_cols: usize,
+                    logical_cols: usize,
+                    nparity: usize,
+                    reflow_offset: u64)
+                    -> Vec<Self> {
+        let logical = logical_cols as u64;
+        let nparity64 = nparity as u64;
+        let s = size >> ashift;
+        let q = s / (logical - nparity64);
+        let r = s - q * (logical - nparity64);
+        let bc = if r == 0 { 0 } else { r + nparity64 };
+        let total = s + nparity64 * (q + (r != 0) as u64);
+        let rows = (total + logical - 1) / logical;
+        let cols = cmp::min(total, logical);
+
+        let mut maps = Vec::with_capacity(rows as usize);
+        for row in 0..rows {
+            // The row's first sector on the vdev
+            let b = (offset >> ashift) + row * logical;
+            let mut physical = physical_cols as u64;
+            if b + cols > reflow_offset >> ashift {
+                physical -= 1;
+            }
+            let mut map = RaidzMap {
+                cols: Vec::with_capacity(cols as usize),
+                nparity: nparity,
+                asize: 0,
+                data_offsets: Vec::new(),
+            };
+            for c in 0..cols {
+                let sector = b + c;
+                let empty = row == rows - 1 && bc != 0 && c >= bc;
+                map.cols.push(RaidzCol {
+                    child: (sector % physical) as usize,
+                    offset: (sector / physical) << ashift,
+                    size: if empty { 0 } else { 1 << ashift },
+                });
+                if c >= nparity64 {
+                    // Data column `dc` holds the sectors `dc * rows` on, the first `r` columns
+                    // having a sector more than the others
+                    let dc = c - nparity64;
+                    let sector = if c < bc || r == 0 {
+                        dc * rows + row
+                    } else {
+                        r * rows + (dc - r) * (rows - 1) + row
+                    };
+                    map.data_offsets.push(sector << ashift);
+                }
+            }
+            map.asize = map.cols.iter().map(|col| col.size).sum();
+            maps.push(map);
+        }
+        maps
+    }
+
+    pub fn data_cols(&self) -> Range<usize> {
+        self.nparity..self.cols.len()
+    }
+
+    /// Split `data` (the whole block) into the map's columns, along with their parity
+    pub fn columns(&self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut cols: Vec<Vec<u8>> = self.cols
+                                         .iter()
+                                         .map(|col| vec![0; col.size as usize])
+                                         .collect();
+        for c in self.data_cols() {
+            let start = self.data_offsets[c - self.nparity] as usize;
+            let len = cols[c].len();
+            cols[c].copy_from_slice(&data[start..start + len]);
+        }
+        self.generate_parity(&mut cols);
+        cols
+    }
+
+    /// Put the data columns back together into the block
+    pub fn data(&self, cols: &[Vec<u8>]) -> Vec<u8> {
+        let size = self.cols[self.data_cols()].iter().map(|col| col.size).sum::<u64>();
+        let mut data = vec![0; size as usize];
+        self.place(cols, &mut data);
+        data
+    }
+
+    /// Copy the data columns to where they go in `block`
+    fn place(&self, cols: &[Vec<u8>], block: &mut [u8]) {
+        for c in self.data_cols() {
+            let start = self.data_offsets[c - self.nparity] as usize;
+            block[start..start + cols[c].len()].copy_from_slice(&cols[c]);
+        }
+    }
+
+    /// Parity column `row` (P, Q or R) is the sum of the data columns, each multiplied by a
+    /// power of 1, 2 or 4 respectively: the first data column by the highest power, the last by
+    /// 1. The shorter columns are padded with zeros.
+    fn coefficient(&self, gf: &Gf, row: usize, c: usize) -> u8 {
+        gf.pow2(row * (self.cols.len() - 1 - c))
+    }
+
+    fn generate_parity(&self, cols: &mut [Vec<u8>]) {
+        let len = cols[0].len();
+        for row in 0..self.nparity {
+            // Horner's rule: multiply what's there so far by 2^row, add the next column
+            let mut parity = vec![0; len];
+            for c in self.data_cols() {
+                for (i, byte) in parity.iter_mut().enumerate() {
+                    for _ in 0..row {
+                        *byte = mul2(*byte);
+                    }
+                    *byte ^= cols[c].get(i).cloned().unwrap_or(0);
+                }
+            }
+            cols[row] = parity;
+        }
+    }
+
+    /// Rebuild the columns in `missing` (those that couldn't be read, or are assumed to be
+    /// bad) from the parity. Returns whether there was enough parity left to do so.
+    ///
+    /// Each parity column that's there gives an equation in the missing data columns: its
+    /// syndrome, the parity minus what the data that's there adds to it. With as many equations
+    /// as missing columns, inverting their coefficients gives the missing bytes.
+    pub fn reconstruct(&self, cols: &mut [Vec<u8>], missing: &[usize]) -> bool {
+        let data_missing: Vec<usize> = missing.iter()
+                                              .cloned()
+                                              .filter(|&c| c >= self.nparity)
+                                              .collect();
+        if data_missing.is_empty() {
+            // Only parity is gone, the data is all there
+            return true;
+        }
+        let rows: Vec<usize> = (0..self.nparity)
+                                   .filter(|row| !missing.contains(row))
+                                   .take(data_missing.len())
+                                   .collect();
+        if rows.len() < data_missing.len() {
+            return false;
+        }
+
+        let gf = Gf::new();
+        let len = cols[0].len();
+        let count = rows.len();
+        let mut syndromes = Vec::with_capacity(count);
+        for &row in &rows {
+            let mut syndrome = cols[row].clone();
+            for c in self.data_cols().filter(|c| !data_missing.contains(c)) {
+                let coefficient = self.coefficient(&gf, row, c);
+                for (s, &d) in syndrome.iter_mut().zip(&cols[c]) {
+                    *s ^= gf.mul(coefficient, d);
+                }
+            }
+            syndromes.push(syndrome);
+        }
+
+        // Gauss-Jordan elimination of the coefficients of the missing columns, with the
+        // inverse built up next to them
+        let mut matrix: Vec<Vec<u8>> = rows.iter()
+                                           .map(|&row| {
+                                               data_missing.iter()
+                                                           .map(|&c| self.coefficient(&gf, row, c))
+                                                           .collect()
+                                           })
+                                           .collect();
+        let mut inverse: Vec<Vec<u8>> = (0..count)
+                                            .map(|i| (0..count).map(|j| (i == j) as u8).collect())
+                                            .collect();
+        for i in 0..count {
+            let pivot = match (i..count).find(|&k| matrix[k][i] != 0) {
+                Some(pivot) => pivot,
+                None => return false,
+            };
+            matrix.swap(i, pivot);
+            inverse.swap(i, pivot);
+            let scale = gf.inv(matrix[i][i]);
+            for j in 0..count {
+                matrix[i][j] = gf.mul(matrix[i][j], scale);
+                inverse[i][j] = gf.mul(inverse[i][j], scale);
+            }
+            for k in (0..count).filter(|&k| k != i) {
+                let factor = matrix[k][i];
+                for j in 0..count {
+                    matrix[k][j] ^= gf.mul(factor, matrix[i][j]);
+                    inverse[k][j] ^= gf.mul(factor, inverse[i][j]);
+                }
+            }
+        }
+
+        for (m, &c) in data_missing.iter().enumerate() {
+            let mut rebuilt = vec![0; len];
+            for (r, syndrome) in syndromes.iter().enumerate() {
+                let coefficient = inverse[m][r];
+                for (byte, &s) in rebuilt.iter_mut().zip(syndrome) {
+                    *byte ^= gf.mul(coefficient, s);
+                }
+            }
+            rebuilt.truncate(self.cols[c].size as usize);
+            cols[c] = rebuilt;
+        }
+        true
+    }
+}
+
+/// Every way of picking `count` of `items`
+fn combinations(items: &[usize], count: usize) -> Vec<Vec<usize>> {
+    if count == 0 {
+        return vec![Vec::new()];
+    }
+    let mut combinations = Vec::new();
+    for (i, &item) in items.iter().enumerate() {
+        for mut rest in self::combinations(&items[i + 1..], count - 1) {
+            rest.insert(0, item);
+            combinations.push(rest);
+        }
+    }
+    combinations
+}
+
+/// What a raidz vdev with `children` children, `nparity` of them parity, allocates for a block
+/// of `psize` bytes (`vdev_raidz_asize`)
+pub fn asize(psize: u64, ashift: u64, children: usize, nparity: usize) -> u64 {
+    let cols = children as u64;
+    let nparity = nparity as u64;
+    let sectors = ((psize - 1) >> ashift) + 1;
+    let sectors = sectors + nparity * ((sectors + cols - nparity - 1) / (cols - nparity));
+    util::round_up(sectors, nparity + 1) << ashift
+}
+
+/// A raidz vdev: blocks are striped over the children along with parity, so they can be read
+/// when some of the children fail. Blocks are read through `read_verified`, by their offset in
+/// the vdev and their size. Plain reads and writes go to the children's own labels, at the
+/// front and back of each of them.
+///
+/// A raidz that's been expanded (`zpool attach` of a child) keeps its older blocks at the width
+/// they were written at. The width a block was written at goes by its birth txg, which a read
+/// doesn't come with, so the widths are tried from the newest on, until one passes the checksum.
+pub struct RaidzVdev {
+    children: Vec<Box<Vdev>>,
+    nparity: usize,
+    ashift: u64,
+    /// Completed expansions: the raidz was this many children narrower at first
+    expansions: usize,
+    /// Whether the last child is being added right now
+    expanding: bool,
+    /// While expanding, how far the blocks have been moved to their new place, from the
+    /// uberblock's reflow info
+    reflow_offset: AtomicU64,
+    errors: Mutex<Vec<ChildErrors>>,
+}
+
+impl RaidzVdev {
+    pub fn new(children: Vec<Box<Vdev>>, nparity: usize, ashift: u64) -> Self {
+        let count = children.len();
+        RaidzVdev {
+            children: children,
+            nparity: nparity,
+            ashift: ashift,
+            expansions: 0,
+            expanding: false,
+            reflow_offset: AtomicU64::new(0),
+            errors: Mutex::new(vec![ChildErrors::default(); count]),
+        }
+    }
+
+    /// The raidz after `expansions` completed expansions, and one more in progress if
+    /// `expanding` is set, as the config lists them
+    pub fn with_expansions(mut self, expansions: usize, expanding: bool) -> Self {
+        self.expansions = expansions;
+        self.expanding = expanding;
+        self
+    }
+
+    /// The maps of the block of `size` bytes at `offset`, for each width it could have been
+    /// written at, newest first. Blocks written at the width the raidz has now have a single
+    /// map; older ones have one per row.
+    fn layouts(&self, offset: u64, size: u64) -> Vec<Vec<RaidzMap>> {
+        let physical = self.children.len();
+        let reflow_offset = if self.expanding {
+            self.reflow_offset.load(Ordering::Relaxed)
+        } else {
+            u64::max_value()
+        };
+        let newest = physical - self.expanding as usize;
+        let oldest = cmp::max(newest.saturating_sub(self.expansions), self.nparity + 1);
+        (oldest..newest + 1)
+            .rev()
+            .map(|width| if width == physical {
+                vec![RaidzMap::new(offset, size, self.ashift, physical, self.nparity)]
+            } else {
+                RaidzMap::expanded(offset,
+                                   size,
+                                   self.ashift,
+                                   physical,
+                                   width,
+                                   self.nparity,
+                                   reflow_offset)
+            })
+            .collect()
+    }
+
+    pub fn child_errors(&self, child: usize) -> Option<ChildErrors> {
+        self.errors.lock().unwrap().get(child).cloned()
+    }
+
+    /// Whether `offset..offset + len` is in the label areas of the children
+    fn is_label(&self, offset: u64, len: usize) -> bool {
+        let end = offset + len as u64;
+        end <= VDEV_LABEL_START_SIZE ||
+        self.size().map_or(false, |size| offset >= size.saturating_sub(VDEV_LABEL_END_SIZE))
+    }
+
+    /// Read the columns of `map`. Returns them, along with the columns that couldn't be read
+    /// and the last error.
+    fn read_columns(&self, map: &RaidzMap) -> (Vec<Vec<u8>>, Vec<usize>, Option<io::Error>) {
+        let mut cols: Vec<Vec<u8>> = map.cols
+                                        .iter()
+                                        .map(|col| vec![0; col.size as usize])
+                                        .collect();
+        let mut missing = Vec::new();
+        let mut error = None;
+        // The columns on each child are read with one vectored read
+        for (child, device) in self.children.iter().enumerate() {
+            let on_child: Vec<usize> = (0..map.cols.len())
+                                           .filter(|&c| {
+                                               map.cols[c].child == child && map.cols[c].size != 0
+                                           })
+                                           .collect();
+            if on_child.is_empty() {
+                continue;
+            }
+            let read = {
+                let mut extents: Vec<(u64, &mut [u8])> =
+                    cols.iter_mut()
+                        .enumerate()
+                        .filter(|&(c, _)| on_child.contains(&c))
+                        .map(|(c, buf)| (VDEV_LABEL_START_SIZE + map.cols[c].offset, &mut buf[..]))
+                        .collect();
+                vdev_io::read_extents_exact(&**device, &mut extents)
+            };
+            if let Err(e) = read {
+                self.errors.lock().unwrap()[child].read += 1;
+                missing.extend(on_child);
+                error = Some(e);
+            }
+        }
+        missing.sort();
+        (cols, missing, error)
+    }
+}
+
+impl Vdev for RaidzVdev {
+    /// Every child has its own labels, any of them that can be read will do
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "raidz has no children");
+        for (i, child) in self.children.iter().enumerate() {
+            match child.read_at(offset, buf) {
+                Ok(read) => return Ok(read),
+                Err(e) => {
+                    self.errors.lock().unwrap()[i].read += 1;
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if !self.is_label(offset, data.len()) {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "raidz blocks can't be written yet"));
+        }
+        let mut error = None;
+        let mut written = false;
+        for (i, child) in self.children.iter().enumerate() {
+            match child.write_at(offset, data) {
+                Ok(()) => written = true,
+                Err(e) => {
+                    self.errors.lock().unwrap()[i].write += 1;
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(error) if !written => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every child is flushed, a flush succeeds if any child's does, like the label writes
+    fn flush(&self) -> io::Result<()> {
+        let mut error = None;
+        let mut flushed = false;
+        for child in &self.children {
+            match child.flush() {
+                Ok(()) => flushed = true,
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(error) if !flushed => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// The smallest of the sizes of the children that are there
+    fn size(&self) -> io::Result<u64> {
+        self.children
+            .iter()
+            .filter_map(|child| child.size().ok())
+            .min()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "raidz has no children"))
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Vdev>> {
+        let mut children = Vec::new();
+        for child in &self.children {
+            children.push(try!(child.try_clone()));
+        }
+        let raidz = RaidzVdev::new(children, self.nparity, self.ashift)
+                        .with_expansions(self.expansions, self.expanding);
+        raidz.raidz_reflow(self.reflow_offset.load(Ordering::Relaxed));
+        Ok(Box::new(raidz))
+    }
+
+    fn is_linear(&self) -> bool {
+        false
+    }
+
+    fn raidz_reflow(&self, offset: u64) {
+        self.reflow_offset.store(offset, Ordering::Relaxed);
+    }
+
+    fn read_verified(&self,
+                     offset: u64,
+                     buf: &mut [u8],
+                     verify: &Fn(&[u8]) -> bool)
+                     -> io::Result<bool> {
+        if offset < VDEV_LABEL_START_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("no raidz block at {:X}", offset)));
+        }
+        let size = util::p2_round_up(buf.len() as u64, 1 << self.ashift);
+        let len = buf.len();
+
+        // The columns of each layout that could be read, with those that couldn't be, rebuilt
+        // from parity where they were missing
+        let mut read = Vec::new();
+        let mut error = None;
+        for maps in self.layouts(offset - VDEV_LABEL_START_SIZE, size) {
+            let mut rows = Vec::with_capacity(maps.len());
+            for map in &maps {
+                let (mut cols, missing, row_error) = self.read_columns(map);
+                if !map.reconstruct(&mut cols, &missing) {
+                    error = row_error;
+                    break;
+                }
+                rows.push((cols, missing));
+            }
+            if rows.len() < maps.len() {
+                continue;
+            }
+            let mut block = vec![0; size as usize];
+            for (map, &(ref cols, _)) in maps.iter().zip(&rows) {
+                map.place(cols, &mut block);
+            }
+            buf.copy_from_slice(&block[..len]);
+            if verify(buf) {
+                return Ok(true);
+            }
+            read.push((maps, rows, block));
+        }
+        if read.is_empty() {
+            return Err(error.unwrap());
+        }
+
+        // Some of the columns that came back hold bad data, but there's no telling which. With
+        // the checksum as the judge, try the data columns as the bad ones, one at a time, then
+        // by pairs and so on, as far as the parity left over from the failed reads goes. Rows
+        // of a block written before an expansion are tried one at a time.
+        for (maps, rows, block) in read {
+            for (map, &(ref cols, ref missing)) in maps.iter().zip(&rows) {
+                let spare = map.nparity.saturating_sub(missing.len());
+                let candidates: Vec<usize> = map.data_cols()
+                                                .filter(|&c| {
+                                                    !missing.contains(&c) && map.cols[c].size != 0
+                                                })
+                                                .collect();
+                for count in 1..spare + 1 {
+                    for bad in combinations(&candidates, count) {
+                        let mut rebuilt = cols.clone();
+                        let assumed: Vec<usize> = missing.iter().chain(&bad).cloned().collect();
+                        if !map.reconstruct(&mut rebuilt, &assumed) {
+                            continue;
+                        }
+                        let mut block = block.clone();
+                        map.place(&rebuilt, &mut block);
+                        buf.copy_from_slice(&block[..len]);
+                        if verify(buf) {
+                            for &c in bad.iter().filter(|&&c| rebuilt[c] != cols[c]) {
+                                self.errors.lock().unwrap()[map.cols[c].child].checksum += 1;
+                            }
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// The block is put together from the other children, as if `child` were missing, then its
+    /// columns on `child`, data and parity alike, are written from it. Unlike a read, the
+    /// other children's columns have to be right: bad ones aren't looked for.
+    fn resilver(&self,
+                offset: u64,
+                buf: &mut [u8],
+                child: usize,
+                verify: &Fn(&[u8]) -> bool)
+                -> io::Result<bool> {
+        if offset < VDEV_LABEL_START_SIZE || child >= self.children.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("no raidz block at {:X} on child {}",
+                                              offset,
+                                              child)));
+        }
+        let size = util::p2_round_up(buf.len() as u64, 1 << self.ashift);
+        let len = buf.len();
+
+        let mut error = None;
+        for maps in self.layouts(offset - VDEV_LABEL_START_SIZE, size) {
+            let mut block = vec![0; size as usize];
+            let mut rebuilt = true;
+            for map in &maps {
+                let (mut cols, mut missing, row_error) = self.read_columns(map);
+                for c in 0..map.cols.len() {
+                    if map.cols[c].child == child && !missing.contains(&c) {
+                        missing.push(c);
+                    }
+                }
+                if !map.reconstruct(&mut cols, &missing) {
+                    error = row_error;
+                    rebuilt = false;
+                    break;
+                }
+                map.place(&cols, &mut block);
+            }
+            if !rebuilt {
+                continue;
+            }
+            buf.copy_from_slice(&block[..len]);
+            if !verify(buf) {
+                continue;
+            }
+
+            for map in &maps {
+                for (col, data) in map.cols.iter().zip(map.columns(&block)) {
+                    if col.child != child || col.size == 0 {
+                        continue;
+                    }
+                    let offset = VDEV_LABEL_START_SIZE + col.offset;
+                    if let Err(e) = self.children[child].write_at(offset, &data) {
+                        self.errors.lock().unwrap()[child].write += 1;
+                        return Err(e);
+                    }
+                }
+            }
+            return Ok(true);
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(false),
+        }
+    }
+
+    /// A raidz can do without as many children as it has parity
+    fn state(&self) -> vdev::State {
+        vdev_mirror::redundant_state(&self.child_status(), self.nparity)
+    }
+
+    fn child_status(&self) -> Vec<(vdev::State, ChildErrors)> {
+        vdev_mirror::child_status(&self.children, &self.errors.lock().unwrap())
+    }
+
+    fn kstats(&self, kstats: &mut kstat::Registry) {
+        for (child, errors) in self.errors.lock().unwrap().iter().enumerate() {
+            let labels = vec![("child".to_owned(), child.to_string())];
+            kstats.add("zfs_vdev_raidz_read_errors_total",
+                       "Reads a raidz child failed",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       errors.read as f64);
+            kstats.add("zfs_vdev_raidz_write_errors_total",
+                       "Writes a raidz child failed",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       errors.write as f64);
+            kstats.add("zfs_vdev_raidz_checksum_errors_total",
+                       "Columns a raidz child returned bad data for",
+                       kstat::KstatKind::Counter,
+                       labels,
+                       errors.checksum as f64);
+        }
+    }
+}
+
+#[test]
+fn test_raidz_map() {
+    // 5 sectors on 3 children: 3 data columns of 2, 2 and 1 sectors, plus parity
+    let map = RaidzMap::new(4 << 9, 5 << 9, 9, 3, 1);
+    let layout: Vec<(usize, u64, u64)> =
+        map.cols.iter().map(|col| (col.child, col.offset >> 9, col.size >> 9)).collect();
+    assert_eq!(layout, vec![(1, 1, 3), (2, 1, 3), (0, 2, 2)]);
+    assert_eq!(map.asize, 8 << 9);
+    assert_eq!(asize(5 << 9, 9, 3, 1), map.asize);
+
+    // A single sector only takes two children
+    let map = RaidzMap::new(0, 1 << 9, 9, 3, 1);
+    assert_eq!(map.cols.len(), 2);
+    assert_eq!(asize(1 << 9, 9, 3, 1), 2 << 9);
+
+    // The parity column moves over every other 1M
+    let map = RaidzMap::new((1 << 20) + (3 << 12), 8 << 12, 12, 3, 1);
+    let children: Vec<usize> = map.cols.iter().map(|col| col.child).collect();
+    assert_eq!(children, vec![2, 1, 0]);
+
+    let data: Vec<u8> = (0..5 * 512).map(|i| (i * 7) as u8).collect();
+    let map = RaidzMap::new(4 << 9, 5 << 9, 9, 3, 1);
+    let mut cols = map.columns(&data);
+    assert_eq!(map.data(&cols), data);
+    cols[2] = vec![0xFF; 1024];
+    assert!(map.reconstruct(&mut cols, &[2]));
+    assert_eq!(map.data(&cols), data);
+    assert!(!map.reconstruct(&mut cols, &[0, 2]));
+}
+
+#[test]
+fn test_raidz_reconstruct() {
+    use super::vdev_io::MemVdev;
+
+    let children: Vec<MemVdev> = (0..3).map(|_| MemVdev::new(vec![0; 8 << 20])).collect();
+    let data: Vec<u8> = (0..12 * 512).map(|i| (i * 13 + i / 512) as u8).collect();
+    let offset = 9 << 9;
+    let map = RaidzMap::new(offset, data.len() as u64, 9, 3, 1);
+    for (col, buf) in map.cols.iter().zip(map.columns(&data)) {
+        let mut child = children[col.child].clone();
+        child.write_at(VDEV_LABEL_START_SIZE + col.offset, &buf).unwrap();
+    }
+    let boxed = children.iter().map(|child| Box::new(child.clone()) as Box<Vdev>).collect();
+    let mut raidz = RaidzVdev::new(boxed, 1, 9);
+    let check = data.clone();
+    let verify = move |block: &[u8]| block == &check[..];
+
+    let mut buf = vec![0; data.len()];
+    assert!(raidz.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+    assert_eq!(buf, data);
+
+    // One child gone: its column comes from parity
+    let mut degraded = RaidzVdev::new(vec![children[0].try_clone().unwrap(),
+                                           Box::new(vdev_io::MissingVdev),
+                                           children[2].try_clone().unwrap()],
+                                      1,
+                                      9);
+    let mut buf = vec![0; data.len()];
+    assert!(degraded.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+    assert_eq!(buf, data);
+    assert_eq!(degraded.child_errors(1).unwrap().read, 1);
+
+    // Silently damaged data is found by trying each column against the checksum
+    let damaged = map.cols[2];
+    let mut child = children[damaged.child].clone();
+    child.write_at(VDEV_LABEL_START_SIZE + damaged.offset + 100, &[0xAA; 8]).unwrap();
+    let mut buf = vec![0; data.len()];
+    assert!(raidz.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+    assert_eq!(buf, data);
+    assert_eq!(raidz.child_errors(damaged.child).unwrap().checksum, 1);
+
+    // Damage on top of a missing child is more than raidz1 can take
+    let mut buf = vec![0; data.len()];
+    assert!(!degraded.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+}
+
+#[test]
+fn test_raidz_syndromes() {
+    // Two data columns of one sector: Q = 2 * D0 + D1, R = 4 * D0 + D1
+    let map = RaidzMap::new(0, 2 << 9, 9, 5, 3);
+    let mut data = vec![0x01; 512];
+    data.extend_from_slice(&[0x80; 512]);
+    let cols = map.columns(&data);
+    assert_eq!((cols[0][0], cols[1][0], cols[2][0]), (0x81, 0x82, 0x84));
+    // Multiplying 0x80 by 2 wraps around the polynomial
+    let cols = map.columns(&[vec![0x80; 512], vec![0x00; 512]].concat());
+    assert_eq!((cols[0][0], cols[1][0], cols[2][0]), (0x80, 0x1D, 0x3A));
+
+    // Any three columns can be rebuilt from the other columns of a raidz3 map
+    let data: Vec<u8> = (0..13 * 512).map(|i| (i * 31 + i / 512 * 7) as u8).collect();
+    let map = RaidzMap::new(5 << 9, 13 << 9, 9, 7, 3);
+    let cols = map.columns(&data);
+    let all: Vec<usize> = (0..map.cols.len()).collect();
+    for count in 1..4 {
+        for missing in combinations(&all, count) {
+            let mut damaged = cols.clone();
+            for &c in &missing {
+                damaged[c] = vec![0xEE; damaged[c].len()];
+            }
+            assert!(map.reconstruct(&mut damaged, &missing));
+            assert_eq!(map.data(&damaged), data);
+        }
+    }
+    assert!(!map.reconstruct(&mut cols.clone(), &[3, 4, 5, 6]));
+}
+
+#[test]
+fn test_raidz2_combinatorial() {
+    use super::vdev_io::MemVdev;
+
+    let children: Vec<MemVdev> = (0..6).map(|_| MemVdev::new(vec![0; 8 << 20])).collect();
+    let data: Vec<u8> = (0..16 * 512).map(|i| (i * 17 + i / 512) as u8).collect();
+    let offset = 10 << 9;
+    let map = RaidzMap::new(offset, data.len() as u64, 9, 6, 2);
+    for (col, buf) in map.cols.iter().zip(map.columns(&data)) {
+        let mut child = children[col.child].clone();
+        child.write_at(VDEV_LABEL_START_SIZE + col.offset, &buf).unwrap();
+    }
+    let check = data.clone();
+    let verify = move |block: &[u8]| block == &check[..];
+
+    // Two data columns silently damaged, nothing tells which but the checksum
+    for &c in &[3, 5] {
+        let mut child = children[map.cols[c].child].clone();
+        child.write_at(VDEV_LABEL_START_SIZE + map.cols[c].offset, &[0x5A; 16]).unwrap();
+    }
+    let boxed = children.iter().map(|child| Box::new(child.clone()) as Box<Vdev>).collect();
+    let mut raidz = RaidzVdev::new(boxed, 2, 9);
+    let mut buf = vec![0; data.len()];
+    assert!(raidz.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+    assert_eq!(buf, data);
+    let checksum_errors: Vec<u64> =
+        map.cols.iter().map(|col| raidz.child_errors(col.child).unwrap().checksum).collect();
+    assert_eq!(checksum_errors, vec![0, 0, 0, 1, 0, 1]);
+
+    // A missing child and a damaged column
+    let mut boxed: Vec<Box<Vdev>> =
+        children.iter().map(|child| Box::new(child.clone()) as Box<Vdev>).collect();
+    boxed[map.cols[3].child] = Box::new(vdev_io::MissingVdev);
+    let mut raidz = RaidzVdev::new(boxed, 2, 9);
+    let mut buf = vec![0; data.len()];
+    assert!(raidz.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+    assert_eq!(buf, data);
+
+    // Two missing children and a damaged column is one too many
+    let mut boxed: Vec<Box<Vdev>> =
+        children.iter().map(|child| Box::new(child.clone()) as Box<Vdev>).collect();
+    boxed[map.cols[3].child] = Box::new(vdev_io::MissingVdev);
+    boxed[map.cols[2].child] = Box::new(vdev_io::MissingVdev);
+    let mut raidz = RaidzVdev::new(boxed, 2, 9);
+    let mut buf = vec![0; data.len()];
+    assert!(!raidz.read_verified(VDEV_LABEL_START_SIZE + offset, &mut buf, &verify).unwrap());
+}
+
+#[test]
+fn test_raidz_expansion() {
+    use super::vdev_io::MemVdev;
+
+    // Before any expansion, the rows are just another way of cutting up the usual map
+    let layout = |maps: &[RaidzMap]| {
+        let mut sectors = Vec::new();
+        for map in maps {
+            for c in map.data_cols() {
+                for sector in 0..map.cols[c].size >> 9 {
+                    sectors.push((map.data_offsets[c - map.nparity] + (sector << 9),
+                                  map.cols[c].child,
+                                  map.cols[c].offset + (sector << 9)));
+                }
+            }
+        }
+        sectors.sort();
+        sectors
+    };
+    for &(offset, sectors, width, nparity) in &[(7, 11, 5, 1), (3, 2, 5, 2), (12, 18, 6, 2)] {
+        let map = RaidzMap::new(offset << 9, sectors << 9, 9, width, nparity);
+        let rows = RaidzMap::expanded(offset << 9,
+                                      sectors << 9,
+                                      9,
+                                      width,
+                                      width,
+                                      nparity,
+                                      u64::max_value());
+        assert_eq!(layout(&rows), layout(&[map]));
+    }
+
+    // A raidz1 of 3 children expanded to 4, with a block from before and one from after
+    let children: Vec<MemVdev> = (0..4).map(|_| MemVdev::new(vec![0; 8 << 20])).collect();
+    let write = |maps: &[RaidzMap], data: &[u8]| {
+        for map in maps {
+            for (col, buf) in map.cols.iter().zip(map.columns(data)) {
+                let mut child = children[col.child].clone();
+                child.write_at(VDEV_LABEL_START_SIZE + col.offset, &buf).unwrap();
+            }
+        }
+    };
+    let old: Vec<u8> = (0..7 * 512).map(|i| (i * 11 + i / 512) as u8).collect();
+    let old_offset = 5 << 9;
+    let old_maps = RaidzMap::expanded(old_offset, 7 << 9, 9, 4, 3, 1, u64::max_value());
+    assert_eq!(old_maps.len(), 4);
+    write(&old_maps, &old);
+    let new: Vec<u8> = (0..6 * 512).map(|i| (i * 5 + i / 512) as u8).collect();
+    let new_offset = 40 << 9;
+    write(&[RaidzMap::new(new_offset, 6 << 9, 9, 4, 1)], &new);
+
+    let boxed: Vec<Box<Vdev>> =
+        children.iter().map(|child| Box::new(child.clone()) as Box<Vdev>).collect();
+    let raidz = RaidzVdev::new(boxed, 1, 9).with_expansions(1, false);
+    let read = |raidz: &RaidzVdev, offset: u64, data: &[u8]| {
+        let mut buf = vec![0; data.len()];
+        let verified = raidz.read_verified(VDEV_LABEL_START_SIZE + offset,
+                                           &mut buf,
+                                           &|block| block == data)
+                            .unwrap();
+        verified && buf == data
+    };
+    assert!(read(&raidz, old_offset, &old));
+    assert!(read(&raidz, new_offset, &new));
+
+    // A damaged sector of the old block is rebuilt from the parity of its row
+    let damaged = old_maps[2].cols[1];
+    let mut child = children[damaged.child].clone();
+    child.write_at(VDEV_LABEL_START_SIZE + damaged.offset, &[0xAA; 512]).unwrap();
+    assert!(read(&raidz, old_offset, &old));
+    assert_eq!(raidz.child_errors(damaged.child).unwrap().checksum, 1);
+
+    // Halfway through the expansion, the rows from the reflow offset on are where they were
+    let reflow_offset = old_offset + (6 << 9);
+    let maps = RaidzMap::expanded(old_offset, 7 << 9, 9, 4, 3, 1, reflow_offset);
+    assert_eq!(maps[1].cols, old_maps[1].cols);
+    let children: Vec<usize> = maps[2].cols.iter().map(|col| col.child).collect();
+    assert_eq!(children, vec![2, 0, 1]);
+    let boxed = (0..4).map(|_| Box::new(MemVdev::new(vec![0; 8 << 20])) as Box<Vdev>).collect();
+    let raidz = RaidzVdev::new(boxed, 1, 9).with_expansions(0, true);
+    raidz.raidz_reflow(reflow_offset);
+    assert_eq!(raidz.layouts(old_offset, 7 << 9), vec![maps]);
+}
+
+#[test]
+fn test_raidz_resilver() {
+    use super::vdev_io::MemVdev;
+
+    let children: Vec<MemVdev> = (0..3).map(|_| MemVdev::new(vec![0; 8 << 20])).collect();
+    let data: Vec<u8> = (0..12 * 512).map(|i| (i * 11 + i / 512) as u8).collect();
+    let offset = 9 << 9;
+    let map = RaidzMap::new(offset, data.len() as u64, 9, 3, 1);
+    let cols = map.columns(&data);
+    for (col, buf) in map.cols.iter().zip(&cols) {
+        let mut child = children[col.child].clone();
+        child.write_at(VDEV_LABEL_START_SIZE + col.offset, buf).unwrap();
+    }
+    let check = data.clone();
+    let verify = move |block: &[u8]| block == &check[..];
+
+    // Each child in turn replaced by a blank one, parity and data alike get written to it
+    for replaced in 0..3 {
+        let blank = MemVdev::new(vec![0; 8 << 20]);
+        let boxed = (0..3)
+                        .map(|c| if c == replaced {
+                            Box::new(blank.clone()) as Box<Vdev>
+                        } else {
+                            children[c].try_clone().unwrap()
+                        })
+                        .collect();
+        let raidz = RaidzVdev::new(boxed, 1, 9);
+        let mut buf = vec![0; data.len()];
+        assert!(raidz.resilver(VDEV_LABEL_START_SIZE + offset, &mut buf, replaced, &verify)
+                     .unwrap());
+        assert_eq!(buf, data);
+        for (col, expected) in map.cols.iter().zip(&cols) {
+            if col.child != replaced {
+                continue;
+            }
+            let start = (VDEV_LABEL_START_SIZE + col.offset) as usize;
+            assert_eq!(&blank.to_vec()[start..start + expected.len()], &expected[..]);
+        }
+    }
+
+    // A bad column on another child can't be told apart from the replaced one's
+    let mut child = children[map.cols[2].child].clone();
+    child.write_at(VDEV_LABEL_START_SIZE + map.cols[2].offset, &[0xAA; 8]).unwrap();
+    let replaced = map.cols[1].child;
+    let boxed = (0..3)
+                    .map(|c| if c == replaced {
+                        Box::new(MemVdev::new(vec![0; 8 << 20])) as Box<Vdev>
+                    } else {
+                        children[c].try_clone().unwrap()
+                    })
+                    .collect();
+    let raidz = RaidzVdev::new(boxed, 1, 9);
+    let mut buf = vec![0; data.len()];
+    assert!(!raidz.resilver(VDEV_LABEL_START_SIZE + offset, &mut buf, replaced, &verify).unwrap());
+}
@@ -0,0 +1,21 @@
+use super::{vdev, zfs};
+
+/// The root of the vdev tree. It has no backing storage of its own, every top-level vdev of the
+/// pool is a child of it.
+pub struct VdevRoot;
+
+impl vdev::IVdevOps for VdevRoot {
+    fn open(&mut self, vdev: &mut vdev::Vdev) -> zfs::Result<(u64, u64, u64)> {
+        Ok((0, 0, 0))
+    }
+
+    fn close(&mut self, vdev: &mut vdev::Vdev) {}
+
+    fn asize(&mut self, vdev: &mut vdev::Vdev, psize: u64) -> u64 {
+        psize
+    }
+
+    fn hold(&mut self, vdev: &mut vdev::Vdev) {}
+
+    fn release(&mut self, vdev: &mut vdev::Vdev) {}
+}
@@ -0,0 +1,118 @@
+use super::metaslab::VdevAllocator;
+use super::vdev_queue::{QueuedIo, VdevQueue};
+use super::zio;
+
+/// Largest discard issued at once, longer extents are split
+pub const TRIM_EXTENT_BYTES_MAX: u64 = 128 << 20;
+
+/// What a trim discarded
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TrimStats {
+    /// Discards issued, and the bytes they covered
+    pub extents: u64,
+    pub bytes: u64,
+    /// Discards the device failed, e.g. for not supporting them
+    pub failed: u64,
+}
+
+/// Discard `extents` of top-level vdev `top`, as starts and sizes in bytes past the labels like a
+/// DVA's. They're issued in order of offset at `Priority::Free`, the lowest there is. A discard
+/// the device fails isn't an error, discards are only hints: it's counted in the stats.
+pub fn discard_extents(zio: &zio::Reader, top: u64, extents: &[(u64, u64)]) -> TrimStats {
+    let mut queue = VdevQueue::new();
+    for &(start, size) in extents {
+        let mut offset = start;
+        while offset < start + size {
+            let len = ::std::cmp::min(start + size - offset, TRIM_EXTENT_BYTES_MAX);
+            queue.add(QueuedIo {
+                offset: offset,
+                size: len,
+                priority: zio::Priority::Free,
+                timestamp: 0,
+                flags: 0,
+                io: (),
+            });
+            offset += len;
+        }
+    }
+
+    let mut stats = TrimStats::default();
+    while let Some(io) = queue.issue(0) {
+        match zio.discard(top, io.offset, io.size) {
+            Ok(()) => {
+                stats.extents += 1;
+                stats.bytes += io.size;
+            }
+            Err(_) => stats.failed += 1,
+        }
+        queue.done(&io);
+    }
+    stats
+}
+
+/// Discard what frees have given back to `alloc` since the last trim, once no rollback needs it
+/// anymore (`VdevAllocator::autotrim`). Meant to be called after each `sync_done`.
+pub fn trim_freed(zio: &zio::Reader, alloc: &mut VdevAllocator) -> TrimStats {
+    let trims = alloc.take_trims();
+    discard_extents(zio, alloc.id(), &trims)
+}
+
+/// Discard all the free space of the vdev of `alloc`: what its space maps had free when it was
+/// opened, less what it's allocated since. The deferred frees are left alone until they're
+/// given back.
+pub fn trim_free(zio: &zio::Reader, alloc: &mut VdevAllocator) -> TrimStats {
+    // Those are free segments too
+    alloc.take_trims();
+    discard_extents(zio, alloc.id(), &alloc.free_segments())
+}
+
+#[test]
+fn test_trim() {
+    use super::metaslab::Allocator;
+    use super::vdev_io::MemVdev;
+
+    let disk = MemVdev::new(vec![0xaa; 8 << 20]);
+    let reader = zio::Reader::new(disk.clone());
+    let mut alloc = VdevAllocator::new(0, 9, 4 * 4096, None);
+    alloc.autotrim = true;
+    let byte_at = |dva: &super::dvaddr::DVAddr| disk.to_vec()[(dva.sector() << 9) as usize];
+
+    let blocks: Vec<_> = (0..4).map(|_| alloc.alloc(4096).unwrap()).collect();
+    for dva in &blocks {
+        reader.write_dva(dva, &[1; 4096]).unwrap();
+    }
+
+    // Frees are batched once they're given back, adjacent ones as one extent
+    alloc.free(&blocks[1], 5);
+    alloc.free(&blocks[2], 5);
+    assert_eq!(trim_freed(&reader, &mut alloc), TrimStats::default());
+    alloc.sync_done(5 + super::txg::DEFER_SIZE as u64);
+    assert_eq!(alloc.take_trims(), vec![(4096, 8192)]);
+    alloc.free(&blocks[3], 6);
+    alloc.sync_done(6 + super::txg::DEFER_SIZE as u64);
+    let stats = trim_freed(&reader, &mut alloc);
+    assert_eq!((stats.extents, stats.bytes, stats.failed), (1, 4096, 0));
+    assert_eq!((byte_at(&blocks[2]), byte_at(&blocks[3])), (1, 0));
+
+    // Space allocated again before the trim isn't discarded
+    alloc.free(&blocks[0], 7);
+    alloc.sync_done(7 + super::txg::DEFER_SIZE as u64);
+    let again = alloc.alloc(4096).unwrap();
+    reader.write_dva(&again, &[2; 4096]).unwrap();
+    assert_eq!(trim_freed(&reader, &mut alloc).extents, 0);
+    assert_eq!(byte_at(&again), 2);
+
+    // A manual trim discards all of the free space
+    let stats = trim_free(&reader, &mut alloc);
+    let free = alloc.free_space();
+    assert_eq!((stats.bytes, stats.failed), (free, 0));
+    let data = disk.to_vec();
+    let start = (again.sector() << 9) as usize;
+    assert!(data[start..start + 4096].iter().all(|&b| b == 2));
+    assert!(data[start + 4096..start + 4 * 4096].iter().all(|&b| b == 0));
+    assert!(data[..start].iter().chain(&data[start + 4 * 4096..]).all(|&b| b == 0xaa));
+
+    // Long extents are split
+    let big = discard_extents(&reader, 0, &[(0, TRIM_EXTENT_BYTES_MAX + 512)]);
+    assert_eq!((big.extents, big.bytes), (2, TRIM_EXTENT_BYTES_MAX + 512));
+}
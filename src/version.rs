@@ -0,0 +1,108 @@
+use std::fmt;
+
+// The legacy pool versions, by the first version with each capability. Pools past version 28
+// are all version 5000, with feature flags telling what they can do instead.
+pub const SPA_VERSION_INITIAL: u64 = 1;
+pub const SPA_VERSION_DITTO_BLOCKS: u64 = 2;
+pub const SPA_VERSION_SPARES: u64 = 3;
+pub const SPA_VERSION_RAIDZ2: u64 = 3;
+pub const SPA_VERSION_BPLIST_ACCOUNT: u64 = 3;
+pub const SPA_VERSION_RAIDZ_DEFLATE: u64 = 3;
+pub const SPA_VERSION_DNODE_BYTES: u64 = 3;
+pub const SPA_VERSION_ZPOOL_HISTORY: u64 = 4;
+pub const SPA_VERSION_GZIP_COMPRESSION: u64 = 5;
+pub const SPA_VERSION_BOOTFS: u64 = 6;
+pub const SPA_VERSION_SLOGS: u64 = 7;
+pub const SPA_VERSION_DELEGATED_PERMS: u64 = 8;
+pub const SPA_VERSION_FUID: u64 = 9;
+pub const SPA_VERSION_REFRESERVATION: u64 = 9;
+pub const SPA_VERSION_REFQUOTA: u64 = 9;
+pub const SPA_VERSION_UNIQUE_ACCURATE: u64 = 9;
+pub const SPA_VERSION_L2CACHE: u64 = 10;
+pub const SPA_VERSION_NEXT_CLONES: u64 = 11;
+pub const SPA_VERSION_ORIGIN: u64 = 11;
+pub const SPA_VERSION_DSL_SCRUB: u64 = 11;
+pub const SPA_VERSION_SNAP_PROPS: u64 = 12;
+pub const SPA_VERSION_USED_BREAKDOWN: u64 = 13;
+pub const SPA_VERSION_PASSTHROUGH_X: u64 = 14;
+pub const SPA_VERSION_USERSPACE: u64 = 15;
+pub const SPA_VERSION_STMF_PROP: u64 = 16;
+pub const SPA_VERSION_RAIDZ3: u64 = 17;
+pub const SPA_VERSION_USERREFS: u64 = 18;
+pub const SPA_VERSION_HOLES: u64 = 19;
+pub const SPA_VERSION_ZLE_COMPRESSION: u64 = 20;
+pub const SPA_VERSION_DEDUP: u64 = 21;
+pub const SPA_VERSION_RECVD_PROPS: u64 = 22;
+pub const SPA_VERSION_SLIM_ZIL: u64 = 23;
+pub const SPA_VERSION_SA: u64 = 24;
+pub const SPA_VERSION_SCAN: u64 = 25;
+pub const SPA_VERSION_DIR_CLONES: u64 = 26;
+pub const SPA_VERSION_DEADLISTS: u64 = 26;
+pub const SPA_VERSION_FAST_SNAP: u64 = 27;
+pub const SPA_VERSION_MULTI_REPLACE: u64 = 28;
+pub const SPA_VERSION_BEFORE_FEATURES: u64 = 28;
+/// First pool version using feature flags instead of version numbers
+pub const SPA_VERSION_FEATURES: u64 = 5000;
+
+/// The version of a pool's on-disk format, from its uberblock or label
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PoolVersion {
+    /// One of the numbered versions, 1 to 28, each adding to what the one before it can do
+    Legacy(u64),
+    /// Version 5000: everything version 28 can do, and whatever its enabled features add
+    Features,
+}
+
+impl PoolVersion {
+    /// The version numbered `version`, None if there's no such version
+    pub fn from_u64(version: u64) -> Option<Self> {
+        if version >= SPA_VERSION_INITIAL && version <= SPA_VERSION_BEFORE_FEATURES {
+            Some(PoolVersion::Legacy(version))
+        } else if version == SPA_VERSION_FEATURES {
+            Some(PoolVersion::Features)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_u64(self) -> u64 {
+        match self {
+            PoolVersion::Legacy(version) => version,
+            PoolVersion::Features => SPA_VERSION_FEATURES,
+        }
+    }
+
+    /// Whether the pool can do what came with the legacy version `since`, e.g.
+    /// `SPA_VERSION_DEDUP`. Feature flags pools can do all of it.
+    pub fn supports(self, since: u64) -> bool {
+        self.as_u64() >= since
+    }
+
+    pub fn has_features(self) -> bool {
+        self == PoolVersion::Features
+    }
+}
+
+impl fmt::Display for PoolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolVersion::Legacy(version) => write!(f, "{}", version),
+            PoolVersion::Features => write!(f, "{} (feature flags)", SPA_VERSION_FEATURES),
+        }
+    }
+}
+
+#[test]
+fn test_pool_version() {
+    assert_eq!(PoolVersion::from_u64(0), None);
+    assert_eq!(PoolVersion::from_u64(29), None);
+    assert_eq!(PoolVersion::from_u64(5000), Some(PoolVersion::Features));
+
+    let version = PoolVersion::from_u64(21).unwrap();
+    assert!(version.supports(SPA_VERSION_DEDUP));
+    assert!(!version.supports(SPA_VERSION_SA));
+    assert!(PoolVersion::Features.supports(SPA_VERSION_MULTI_REPLACE));
+    assert!(version < PoolVersion::Features);
+    assert_eq!(version.to_string(), "21");
+    assert_eq!(PoolVersion::Features.to_string(), "5000 (feature flags)");
+}
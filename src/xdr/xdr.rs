@@ -1,4 +1,6 @@
 // use std::*;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 
 #[derive(Debug)]
 pub struct XdrError;
@@ -1,10 +1,26 @@
-use std::{fmt, mem, ptr, str};
-use std::io::Seek;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, fmt, mem, slice, str, vec};
+use std::collections::BTreeMap;
 
-use super::from_bytes::FromBytes;
+use super::ZfsReader;
+use super::dmu;
+use super::dmu_objset::ObjectSet;
+use super::dmu_tx::Tx;
+use super::dnode::{DNodePhys, ObjectType};
+use super::dsl_pool::DslPool;
+use super::from_bytes::{ByteOrder, Decoder, FromBytes};
+use super::u8_textprep::{self, U8_TEXTPREP_TOUPPER};
+use super::zfs;
 
 const MZAP_ENT_LEN: usize = 64;
 const MZAP_NAME_LEN: usize = MZAP_ENT_LEN - 8 - 4 - 2;
+/// Micro ZAPs grow up to this, then they're turned into fat ZAPs
+const MZAP_MAX_BLKSZ: usize = 128 << 10;
+
+/// Longest name a ZAP entry can have, its NUL included (`ZAP_MAXNAMELEN_NEW`): 1024 bytes since
+/// the longname feature, 256 before
+pub const ZAP_MAXNAMELEN: usize = 1024;
 
 #[repr(u64)]
 #[derive(Copy, Clone, Debug)]
@@ -17,47 +33,66 @@ pub enum ZapObjectType {
 /// Microzap
 #[repr(packed)]
 pub struct MZapPhys {
-    pub block_type: ZapObjectType, // ZapObjectType::Micro
+    pub block_type: u64, // ZapObjectType::Micro
     pub salt: u64,
     pub norm_flags: u64,
     pad: [u64; 5],
 }
 
+impl FromBytes for MZapPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let block_type = decoder.u64();
+        let salt = decoder.u64();
+        let norm_flags = decoder.u64();
+        let mut pad = [0; 5];
+        decoder.fill_u64(&mut pad);
+        MZapPhys {
+            block_type: block_type,
+            salt: salt,
+            norm_flags: norm_flags,
+            pad: pad,
+        }
+    }
+}
+
 pub struct MZapWrapper {
     pub phys: MZapPhys,
     pub chunks: Vec<MZapEntPhys>, // variable size depending on block size
 }
 
-impl FromBytes for MZapWrapper {
-    fn from_bytes(data: &[u8]) -> Result<Self, &str> {
-        if data.len() >= mem::size_of::<MZapPhys>() {
-            // Read the first part of the mzap -- its base phys struct
-            let mzap_phys = unsafe { ptr::read(data.as_ptr() as *const MZapPhys) };
-            // Read the mzap entries, aka chunks
-            let mut mzap_entries = Vec::new();
-            let num_entries = (data.len() - mem::size_of::<MZapPhys>()) /
-                              mem::size_of::<MZapEntPhys>();
-            for i in 0..num_entries {
-                let entry_pos = mem::size_of::<MZapPhys>() + i * mem::size_of::<MZapEntPhys>();
-                let mzap_ent = unsafe {
-                    ptr::read(data[entry_pos..].as_ptr() as *const MZapEntPhys)
-                };
-                mzap_entries.push(mzap_ent);
-            }
-            Ok(MZapWrapper {
-                phys: mzap_phys,
-                chunks: mzap_entries,
-            })
-        } else {
-            Err("Error: needs a proper error message")
+impl MZapWrapper {
+    pub fn from_bytes(data: &[u8], order: ByteOrder) -> Result<Self, &'static str> {
+        // Read the first part of the mzap -- its base phys struct
+        let mzap_phys = try!(MZapPhys::from_bytes_order(data, order)
+                                 .map_err(|_| "Error: micro ZAP block is too short"));
+        // Read the mzap entries, aka chunks
+        let mut mzap_entries = Vec::new();
+        let num_entries = (data.len() - mem::size_of::<MZapPhys>()) /
+                          mem::size_of::<MZapEntPhys>();
+        for i in 0..num_entries {
+            let entry_pos = mem::size_of::<MZapPhys>() + i * mem::size_of::<MZapEntPhys>();
+            mzap_entries.push(MZapEntPhys::from_bytes_order(&data[entry_pos..], order).unwrap());
         }
+        Ok(MZapWrapper {
+            phys: mzap_phys,
+            chunks: mzap_entries,
+        })
+    }
+
+    /// The entries in use. Free chunks have an empty name.
+    pub fn entries(&self) -> Vec<(String, u64)> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| chunk.name().map(|name| (name.to_owned(), chunk.value)))
+            .filter(|&(ref name, _)| !name.is_empty())
+            .collect()
     }
 }
 
 impl fmt::Debug for MZapWrapper {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f,
-                    "MZapPhys {{\nblock_type: {:?},\nsalt: {:X},\nnorm_flags: {:X},\nchunk: [\n",
+                    "MZapPhys {{\nblock_type: {:X},\nsalt: {:X},\nnorm_flags: {:X},\nchunk: [\n",
                     self.phys.block_type,
                     self.phys.salt,
                     self.phys.norm_flags));
@@ -69,6 +104,733 @@ impl fmt::Debug for MZapWrapper {
     }
 }
 
+// Every ZAP block starts with its type. Check it before trusting the rest of the block. The type
+// only reads right in the byte order the block was written in, which is returned along with it.
+fn block_type(data: &[u8]) -> Option<(ZapObjectType, ByteOrder)> {
+    let block_type = match u64::from_bytes(data) {
+        Ok(block_type) => block_type,
+        Err(_) => return None,
+    };
+    let types = [ZapObjectType::Micro, ZapObjectType::Header, ZapObjectType::Leaf];
+    for &(order, block_type) in &[(ByteOrder::Little, block_type),
+                                  (ByteOrder::Big, block_type.swap_bytes())] {
+        if let Some(&zap_type) = types.iter().find(|&&t| t as u64 == block_type) {
+            return Some((zap_type, order));
+        }
+    }
+    None
+}
+
+/// How a lookup matches names (`matchtype_t`). ZAPs without normalization flags only ever match
+/// them exactly.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MatchType {
+    /// Byte for byte
+    Exact,
+    /// Names that are the same once normalized by the ZAP's flags, e.g. in any case
+    Normalize,
+    /// Like `Normalize`, but case has to match even if the flags fold it, as lookups on
+    /// datasets of mixed case sensitivity do
+    NormalizeMatchCase,
+}
+
+/// A name to look up, as it's hashed and matched (`zap_name_t`). ZAPs with normalization flags
+/// hash names normalized by them, however they're matched.
+pub struct ZapName<'a> {
+    name: &'a str,
+    hashed: String,
+    /// The flags names are normalized by before they're compared, None to compare them as they
+    /// are, and the name normalized by them
+    match_flags: Option<u64>,
+    normalized: String,
+}
+
+impl<'a> ZapName<'a> {
+    pub fn new(name: &'a str, norm_flags: u64, mt: MatchType) -> Result<Self, String> {
+        let hashed = match u8_textprep::normalize(name, norm_flags) {
+            Ok(hashed) => hashed,
+            // Names normalization doesn't change still hash right
+            Err(_) if mt == MatchType::Exact => name.to_owned(),
+            Err(e) => return Err(e),
+        };
+        let match_flags = match mt {
+            _ if norm_flags == 0 => None,
+            MatchType::Exact => None,
+            MatchType::Normalize => Some(norm_flags),
+            MatchType::NormalizeMatchCase => Some(norm_flags & !U8_TEXTPREP_TOUPPER),
+        };
+        let normalized = match match_flags {
+            Some(flags) if flags != norm_flags => try!(u8_textprep::normalize(name, flags)),
+            _ => hashed.clone(),
+        };
+        Ok(ZapName {
+            name: name,
+            hashed: hashed,
+            match_flags: match_flags,
+            normalized: normalized,
+        })
+    }
+
+    /// Whether the entry named `name` is the one looked up (`zap_match`)
+    pub fn matches(&self, name: &str) -> bool {
+        match self.match_flags {
+            Some(flags) => {
+                u8_textprep::normalize(name, flags).map_or(false, |name| name == self.normalized)
+            }
+            None => name == self.name,
+        }
+    }
+}
+
+/// Look up `name` in ZAP object `obj` of `objset`
+pub fn lookup(reader: &mut ZfsReader,
+              objset: &ObjectSet,
+              obj: u64,
+              name: &str)
+              -> Result<u64, String> {
+    lookup_match(reader, objset, obj, name, MatchType::Exact).map(|(_, value)| value)
+}
+
+/// Look up `name` in ZAP object `obj` of `objset`, matching names as `mt` says. Returns the name
+/// of the entry found, which can be another than `name`, along with its value.
+pub fn lookup_match(reader: &mut ZfsReader,
+                    objset: &ObjectSet,
+                    obj: u64,
+                    name: &str,
+                    mt: MatchType)
+                    -> Result<(String, u64), String> {
+    if name.len() >= ZAP_MAXNAMELEN {
+        return Err(format!("ZAP name of {} bytes is too long", name.len()));
+    }
+    let dnode = try!(objset.dnode(reader, obj));
+    let data = try!(dmu::read_block(reader, &dnode, 0));
+    let entry = match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            let zap = try!(MZapWrapper::from_bytes(&data, order).map_err(|x| x.to_owned()));
+            let zap_name = try!(ZapName::new(name, zap.phys.norm_flags, mt));
+            zap.chunks
+               .iter()
+               .filter_map(|chunk| chunk.name().map(|entry| (entry, chunk.value)))
+               .find(|&(entry, _)| !entry.is_empty() && zap_name.matches(entry))
+               .map(|(entry, value)| (entry.to_owned(), value))
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let zap = try!(FatZap::from_bytes(&data, order));
+            let zap_name = try!(ZapName::new(name, zap.phys.norm_flags, mt));
+            let mut read = |block| dmu::read_block_order(reader, &dnode, block);
+            try!(zap.lookup(&mut read, &data, &zap_name))
+        }
+        _ => return Err(format!("Object {} is not a ZAP", obj)),
+    };
+    entry.ok_or(format!("No `{}` in ZAP object {}", name, obj))
+}
+
+/// Look up `name` in ZAP object `obj` of `objset`, with its whole value (see `ZapValue`)
+pub fn lookup_value(reader: &mut ZfsReader,
+                    objset: &ObjectSet,
+                    obj: u64,
+                    name: &str)
+                    -> Result<ZapValue, String> {
+    if name.len() >= ZAP_MAXNAMELEN {
+        return Err(format!("ZAP name of {} bytes is too long", name.len()));
+    }
+    let dnode = try!(objset.dnode(reader, obj));
+    let data = try!(dmu::read_block(reader, &dnode, 0));
+    let value = match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            let zap = try!(MZapWrapper::from_bytes(&data, order).map_err(|x| x.to_owned()));
+            zap.chunks
+               .iter()
+               .find(|chunk| !name.is_empty() && chunk.name() == Some(name))
+               .map(|chunk| ZapValue::Int(chunk.value))
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let zap = try!(FatZap::from_bytes(&data, order));
+            let zap_name = try!(ZapName::new(name, zap.phys.norm_flags, MatchType::Exact));
+            let mut read = |block| dmu::read_block_order(reader, &dnode, block);
+            try!(zap.lookup_value(&mut read, &data, &zap_name))
+        }
+        _ => return Err(format!("Object {} is not a ZAP", obj)),
+    };
+    value.ok_or(format!("No `{}` in ZAP object {}", name, obj))
+}
+
+/// All (name, value) pairs of ZAP object `obj` of `objset`
+pub fn iter(reader: &mut ZfsReader,
+            objset: &ObjectSet,
+            obj: u64)
+            -> Result<vec::IntoIter<(String, u64)>, String> {
+    let dnode = try!(objset.dnode(reader, obj));
+    iter_dnode(reader, &dnode).map_err(|e| format!("Object {}: {}", obj, e))
+}
+
+/// All (name, value) pairs of the ZAP object `dnode` describes, for the ones that aren't in the
+/// meta dnode, like the user and group used ZAPs
+pub fn iter_dnode(reader: &mut ZfsReader,
+                  dnode: &DNodePhys)
+                  -> Result<vec::IntoIter<(String, u64)>, String> {
+    let data = try!(dmu::read_block(reader, dnode, 0));
+    let entries = match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            try!(MZapWrapper::from_bytes(&data, order).map_err(|x| x.to_owned())).entries()
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let zap = try!(FatZap::from_bytes(&data, order));
+            let mut read = |block| dmu::read_block_order(reader, dnode, block);
+            try!(zap.entries(&mut read, &data))
+        }
+        _ => return Err("not a ZAP".to_owned()),
+    };
+    Ok(entries.into_iter())
+}
+
+/// How many entries ZAP object `obj` of `objset` has. Fat ZAPs keep count in their header, so
+/// their leaves aren't read, and an object without blocks has no entries at all.
+pub fn count(reader: &mut ZfsReader, objset: &ObjectSet, obj: u64) -> Result<u64, String> {
+    let dnode = try!(objset.dnode(reader, obj));
+    if dnode.fill_count() == 0 {
+        return Ok(0);
+    }
+    let data = try!(dmu::read_block(reader, &dnode, 0));
+    match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            let zap = try!(MZapWrapper::from_bytes(&data, order).map_err(|x| x.to_owned()));
+            Ok(zap.entries().len() as u64)
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let header = try!(ZapPhys::from_bytes_order(&data, order));
+            Ok(header.num_entries)
+        }
+        _ => Err(format!("Object {} is not a ZAP", obj)),
+    }
+}
+
+/// The block of a micro ZAP holding `entries`, little endian: the smallest power of two from
+/// 512 bytes on with a chunk for each of them. `salt` is the object's, for hashing names. None
+/// if they don't make a micro ZAP, with a name too long for a chunk or too many of them.
+pub fn mzap_block(entries: &[(&str, u64)], salt: u64) -> Option<Vec<u8>> {
+    if entries.iter().any(|&(name, _)| name.is_empty() || name.len() >= MZAP_NAME_LEN) {
+        return None;
+    }
+    let header = mem::size_of::<MZapPhys>();
+    let mut size = 512;
+    while size < header + entries.len() * MZAP_ENT_LEN {
+        size *= 2;
+    }
+    if size > MZAP_MAX_BLKSZ {
+        return None;
+    }
+
+    let mut block = vec![0; size];
+    put_u64(&mut block, ZapObjectType::Micro as u64);
+    put_u64(&mut block[8..], salt);
+    for (i, &(name, value)) in entries.iter().enumerate() {
+        let entry = header + i * MZAP_ENT_LEN;
+        put_u64(&mut block[entry..], value);
+        block[entry + 14..entry + 14 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    Some(block)
+}
+
+/// Create micro ZAP `obj` of objset `objset` (see `dsl_pool::MOS_OBJSET`) in txg `txg`, holding
+/// `entries`. `object_type` is the object's, a ZAP type or one of the `DMU_OTN_ZAP_*` types.
+pub fn create(dsl_pool: &mut DslPool,
+              txg: u64,
+              objset: u64,
+              obj: u64,
+              object_type: u8,
+              entries: &[(&str, u64)])
+              -> zfs::Result<()> {
+    let block = try!(mzap_block(entries, salt(txg, objset, obj)).ok_or(zfs::Error::Invalid));
+    let mut dnode = DNodePhys::new(ObjectType::None, block.len() as u64, ObjectType::None, &[]);
+    dnode.object_type = object_type;
+    dsl_pool.dirty_dnode(txg, objset, obj, dnode);
+    dsl_pool.dirty_block(txg, objset, obj, 0, block);
+    Ok(())
+}
+
+/// Create ZAP `obj` like `create`, as an empty fat ZAP of `1 << block_shift` byte blocks with
+/// ZAP flags `flags`, e.g. `ZAP_FLAG_UINT64_KEY` (`zap_create_flags`)
+pub fn create_flags(dsl_pool: &mut DslPool,
+                    txg: u64,
+                    objset: u64,
+                    obj: u64,
+                    object_type: u8,
+                    flags: u64,
+                    block_shift: u32)
+                    -> zfs::Result<()> {
+    if block_shift < 9 || block_shift > 17 {
+        return Err(zfs::Error::Invalid);
+    }
+    let zap = FatZapTx::create(block_shift, salt(txg, objset, obj), 0, flags);
+    let mut dnode = DNodePhys::new(ObjectType::None, 1 << block_shift, ObjectType::None, &[]);
+    dnode.object_type = object_type;
+    dsl_pool.dirty_dnode(txg, objset, obj, dnode);
+    dsl_pool.dirty_block(txg, objset, obj, 0, zap.header);
+    for (blkid, block) in zap.blocks {
+        dsl_pool.dirty_block(txg, objset, obj, blkid, block);
+    }
+    Ok(())
+}
+
+// Any salt will do, it only has to stay the same for the life of the ZAP
+fn salt(txg: u64, objset: u64, obj: u64) -> u64 {
+    (txg << 32 ^ objset << 16 ^ obj) | 1
+}
+
+/// All (name, value) pairs of ZAP object `obj` of objset `objset_id`, as the txgs in flight in
+/// `dsl_pool` leave it: what they change of it, or else what's on the disk in `objset`
+pub fn pending_entries(reader: &mut ZfsReader,
+                       dsl_pool: &DslPool,
+                       objset: &ObjectSet,
+                       objset_id: u64,
+                       obj: u64)
+                       -> zfs::Result<Vec<(String, u64)>> {
+    let data = match dsl_pool.pending_block(objset_id, obj, 0) {
+        Some(data) => data.to_vec(),
+        None => {
+            let entries = try!(iter(reader, objset, obj).map_err(|_| zfs::Error::Invalid));
+            return Ok(entries.collect());
+        }
+    };
+    match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            let zap = try!(MZapWrapper::from_bytes(&data, order).map_err(|_| zfs::Error::Invalid));
+            Ok(zap.entries())
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let dnode = try!(pending_dnode(reader, dsl_pool, objset, objset_id, obj));
+            let zap = try!(FatZap::from_bytes(&data, order).map_err(|_| zfs::Error::Invalid));
+            let mut read = |blkid| pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid);
+            zap.entries(&mut read, &data).map_err(|_| zfs::Error::Invalid)
+        }
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// The normalization flags of ZAP object `obj` of objset `objset_id`, that its names are hashed
+/// and matched by, as the txgs in flight in `dsl_pool` leave it, like `pending_entries`
+pub fn pending_norm_flags(reader: &mut ZfsReader,
+                          dsl_pool: &DslPool,
+                          objset: &ObjectSet,
+                          objset_id: u64,
+                          obj: u64)
+                          -> zfs::Result<u64> {
+    let dnode = try!(pending_dnode(reader, dsl_pool, objset, objset_id, obj));
+    let (data, _) = try!(pending_block(reader, dsl_pool, objset_id, obj, &dnode, 0)
+                             .map_err(|_| zfs::Error::Invalid));
+    match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            MZapWrapper::from_bytes(&data, order)
+                .map(|zap| zap.phys.norm_flags)
+                .map_err(|_| zfs::Error::Invalid)
+        }
+        Some((ZapObjectType::Header, order)) => {
+            FatZap::from_bytes(&data, order)
+                .map(|zap| zap.phys.norm_flags)
+                .map_err(|_| zfs::Error::Invalid)
+        }
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// Add `name` with `value` to ZAP object `obj` of `objset`, in the txg `tx` is assigned to. The
+/// change has to be covered by a hold of `tx`. Fails with `Exists` if the ZAP has the name
+/// already. A micro ZAP's block grows as it fills up, and it's turned into a fat ZAP once it
+/// has no room for the entry, or the name is too long for it.
+pub fn add(reader: &mut ZfsReader,
+           dsl_pool: &mut DslPool,
+           objset: &ObjectSet,
+           obj: u64,
+           name: &str,
+           value: u64,
+           tx: &Tx)
+           -> zfs::Result<()> {
+    if name.is_empty() || name.len() >= ZAP_MAXNAMELEN {
+        return Err(zfs::Error::Invalid);
+    }
+    let (dnode, block) = try!(tx_zap(reader, dsl_pool, objset, obj, tx));
+    let zap = match block_type(&block) {
+        Some((ZapObjectType::Micro, _)) => {
+            let mut mzap = try!(MZapWrapper::from_bytes(&block, ByteOrder::Little)
+                                    .map_err(|_| zfs::Error::Invalid));
+            if mzap.chunks.iter().any(|chunk| chunk.name() == Some(name)) {
+                return Err(zfs::Error::Exists);
+            }
+            if name.len() < MZAP_NAME_LEN {
+                if let Some(i) = mzap.chunks.iter().position(|chunk| chunk.name[0] == 0) {
+                    mzap.chunks[i].value = value;
+                    mzap.chunks[i].name[..name.len()].copy_from_slice(name.as_bytes());
+                    let block = mzap_bytes(&mzap, dnode.data_block_size() as usize);
+                    return write_mzap(dsl_pool, obj, dnode, block, tx);
+                }
+                let mut entries = mzap.entries();
+                entries.push((name.to_owned(), value));
+                let entries: Vec<_> = entries.iter()
+                                             .map(|&(ref name, value)| (&name[..], value))
+                                             .collect();
+                if let Some(mut block) = mzap_block(&entries, mzap.phys.salt) {
+                    put_u64(&mut block[16..], mzap.phys.norm_flags);
+                    return write_mzap(dsl_pool, obj, dnode, block, tx);
+                }
+            }
+            let mut zap = try!(FatZapTx::upgrade(&mzap));
+            try!(zap.add(&mut no_block, name, value));
+            zap
+        }
+        _ => {
+            let mut zap = try!(FatZapTx::new(block));
+            {
+                let objset_id = tx.objset();
+                let dsl_pool = &*dsl_pool;
+                let mut read = |blkid| {
+                    pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid)
+                };
+                try!(zap.add(&mut read, name, value));
+            }
+            zap
+        }
+    };
+    write_fzap(dsl_pool, obj, dnode, zap, tx)
+}
+
+/// Remove `name` from ZAP object `obj` of `objset`, in the txg `tx` is assigned to, like `add`.
+/// Returns the value it had.
+pub fn remove(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              objset: &ObjectSet,
+              obj: u64,
+              name: &str,
+              tx: &Tx)
+              -> zfs::Result<u64> {
+    if name.is_empty() {
+        return Err(zfs::Error::NoEntity);
+    }
+    let (dnode, block) = try!(tx_zap(reader, dsl_pool, objset, obj, tx));
+    if let Some((ZapObjectType::Micro, _)) = block_type(&block) {
+        let mut mzap = try!(MZapWrapper::from_bytes(&block, ByteOrder::Little)
+                                .map_err(|_| zfs::Error::Invalid));
+        let value = match mzap.chunks.iter_mut().find(|chunk| chunk.name() == Some(name)) {
+            Some(chunk) => {
+                let value = chunk.value;
+                chunk.value = 0;
+                chunk.name = [0; MZAP_NAME_LEN];
+                value
+            }
+            None => return Err(zfs::Error::NoEntity),
+        };
+        let block = mzap_bytes(&mzap, dnode.data_block_size() as usize);
+        try!(write_mzap(dsl_pool, obj, dnode, block, tx));
+        return Ok(value);
+    }
+    let mut zap = try!(FatZapTx::new(block));
+    let value = {
+        let objset_id = tx.objset();
+        let dsl_pool = &*dsl_pool;
+        let mut read = |blkid| pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid);
+        let key = try!(zap.name_key(name));
+        try!(zap.remove(&mut read, &key))
+    };
+    try!(write_fzap(dsl_pool, obj, dnode, zap, tx));
+    Ok(value)
+}
+
+/// Set `name` to `value`, integers of `int_size` bytes, in ZAP object `obj` of `objset`, adding
+/// it if the ZAP doesn't have it yet (`zap_update`), like `add`. Micro ZAPs only hold single
+/// 8 byte integers: one is turned into a fat ZAP for any other value.
+pub fn update(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              objset: &ObjectSet,
+              obj: u64,
+              name: &str,
+              int_size: u8,
+              value: &[u64],
+              tx: &Tx)
+              -> zfs::Result<()> {
+    if name.is_empty() || name.len() >= ZAP_MAXNAMELEN || value.is_empty() ||
+       ![1, 2, 4, 8].contains(&int_size) {
+        return Err(zfs::Error::Invalid);
+    }
+    let (dnode, block) = try!(tx_zap(reader, dsl_pool, objset, obj, tx));
+    let mut zap = match block_type(&block) {
+        Some((ZapObjectType::Micro, _)) => {
+            let mut mzap = try!(MZapWrapper::from_bytes(&block, ByteOrder::Little)
+                                    .map_err(|_| zfs::Error::Invalid));
+            if int_size == 8 && value.len() == 1 {
+                match mzap.chunks.iter().position(|chunk| chunk.name() == Some(name)) {
+                    Some(i) => {
+                        mzap.chunks[i].value = value[0];
+                        let block = mzap_bytes(&mzap, dnode.data_block_size() as usize);
+                        return write_mzap(dsl_pool, obj, dnode, block, tx);
+                    }
+                    None => return add(reader, dsl_pool, objset, obj, name, value[0], tx),
+                }
+            }
+            try!(FatZapTx::upgrade(&mzap))
+        }
+        _ => try!(FatZapTx::new(block)),
+    };
+    {
+        let key = try!(zap.name_key(name));
+        let objset_id = tx.objset();
+        let dsl_pool = &*dsl_pool;
+        let mut read = |blkid| pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid);
+        try!(zap.put(&mut read, &key, int_size, &int_bytes(int_size, value), true));
+    }
+    write_fzap(dsl_pool, obj, dnode, zap, tx)
+}
+
+/// Set integer key `key` to `value` in fat ZAP object `obj` of `objset`, whose keys are arrays
+/// of integers (`ZAP_FLAG_UINT64_KEY`), like `update` (`zap_update_uint64`)
+pub fn update_uint64(reader: &mut ZfsReader,
+                     dsl_pool: &mut DslPool,
+                     objset: &ObjectSet,
+                     obj: u64,
+                     key: &[u64],
+                     int_size: u8,
+                     value: &[u64],
+                     tx: &Tx)
+                     -> zfs::Result<()> {
+    if value.is_empty() || ![1, 2, 4, 8].contains(&int_size) {
+        return Err(zfs::Error::Invalid);
+    }
+    let (dnode, mut zap) = try!(tx_fat_zap(reader, dsl_pool, objset, obj, tx));
+    {
+        let objset_id = tx.objset();
+        let dsl_pool = &*dsl_pool;
+        let mut read = |blkid| pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid);
+        try!(zap.put(&mut read, &FatKey::Ints(key), int_size, &int_bytes(int_size, value), true));
+    }
+    write_fzap(dsl_pool, obj, dnode, zap, tx)
+}
+
+/// Remove integer key `key` from fat ZAP object `obj` of `objset`, like `update_uint64`
+/// (`zap_remove_uint64`)
+pub fn remove_uint64(reader: &mut ZfsReader,
+                     dsl_pool: &mut DslPool,
+                     objset: &ObjectSet,
+                     obj: u64,
+                     key: &[u64],
+                     tx: &Tx)
+                     -> zfs::Result<()> {
+    let (dnode, mut zap) = try!(tx_fat_zap(reader, dsl_pool, objset, obj, tx));
+    {
+        let objset_id = tx.objset();
+        let dsl_pool = &*dsl_pool;
+        let mut read = |blkid| pending_block(reader, dsl_pool, objset_id, obj, &dnode, blkid);
+        try!(zap.remove(&mut read, &FatKey::Ints(key)));
+    }
+    write_fzap(dsl_pool, obj, dnode, zap, tx)
+}
+
+/// The dnode of ZAP object `obj` of objset `objset_id`, as the txgs in flight leave it
+fn pending_dnode(reader: &mut ZfsReader,
+                 dsl_pool: &DslPool,
+                 objset: &ObjectSet,
+                 objset_id: u64,
+                 obj: u64)
+                 -> zfs::Result<DNodePhys> {
+    match dsl_pool.pending_dnode(objset_id, obj) {
+        Some(dnode) => Ok(dnode),
+        None => objset.dnode(reader, obj).map_err(|_| zfs::Error::NoEntity),
+    }
+}
+
+/// Block `blkid` of ZAP object `obj` of objset `objset_id`, whose dnode is `dnode`, as the txgs
+/// in flight leave it
+fn pending_block(reader: &mut ZfsReader,
+                 dsl_pool: &DslPool,
+                 objset_id: u64,
+                 obj: u64,
+                 dnode: &DNodePhys,
+                 blkid: u64)
+                 -> Result<(Vec<u8>, ByteOrder), String> {
+    match dsl_pool.pending_block(objset_id, obj, blkid) {
+        Some(data) => Ok((data.to_vec(), ByteOrder::Little)),
+        None => dmu::read_block_order(reader, dnode, blkid),
+    }
+}
+
+/// The dnode and the first block of ZAP object `obj`, for `tx` to change. The block is a
+/// little endian micro ZAP or fat ZAP header.
+fn tx_zap(reader: &mut ZfsReader,
+          dsl_pool: &DslPool,
+          objset: &ObjectSet,
+          obj: u64,
+          tx: &Tx)
+          -> zfs::Result<(DNodePhys, Vec<u8>)> {
+    if tx.txg().is_none() || !tx.holds_zap(obj) {
+        return Err(zfs::Error::Invalid);
+    }
+    let dnode = try!(pending_dnode(reader, dsl_pool, objset, tx.objset(), obj));
+    let data = match dsl_pool.pending_block(tx.objset(), obj, 0) {
+        Some(data) => data.to_vec(),
+        None => try!(dmu::read_block(reader, &dnode, 0).map_err(|_| zfs::Error::Invalid)),
+    };
+    match block_type(&data) {
+        // TODO: rewrite the ZAPs of big endian hosts
+        Some((_, ByteOrder::Big)) => Err(zfs::Error::UnsupportedFeature),
+        Some((ZapObjectType::Micro, _)) |
+        Some((ZapObjectType::Header, _)) => Ok((dnode, data)),
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// The dnode and the header of fat ZAP object `obj`, for `tx` to change, like `tx_zap`
+fn tx_fat_zap(reader: &mut ZfsReader,
+              dsl_pool: &DslPool,
+              objset: &ObjectSet,
+              obj: u64,
+              tx: &Tx)
+              -> zfs::Result<(DNodePhys, FatZapTx)> {
+    let (dnode, block) = try!(tx_zap(reader, dsl_pool, objset, obj, tx));
+    match block_type(&block) {
+        Some((ZapObjectType::Header, _)) => Ok((dnode, try!(FatZapTx::new(block)))),
+        _ => Err(zfs::Error::Invalid),
+    }
+}
+
+/// `zap` as a little endian block of `size` bytes
+fn mzap_bytes(zap: &MZapWrapper, size: usize) -> Vec<u8> {
+    let header = mem::size_of::<MZapPhys>();
+    let mut block = vec![0; size];
+    put_u64(&mut block, ZapObjectType::Micro as u64);
+    put_u64(&mut block[8..], zap.phys.salt);
+    put_u64(&mut block[16..], zap.phys.norm_flags);
+    for (i, chunk) in zap.chunks.iter().enumerate() {
+        let entry = header + i * MZAP_ENT_LEN;
+        put_u64(&mut block[entry..], chunk.value);
+        block[entry + 14..entry + MZAP_ENT_LEN].copy_from_slice(&chunk.name);
+    }
+    block
+}
+
+/// Dirty `block` as the micro ZAP `obj`, growing its dnode's block size to the block's
+fn write_mzap(dsl_pool: &mut DslPool,
+              obj: u64,
+              mut dnode: DNodePhys,
+              block: Vec<u8>,
+              tx: &Tx)
+              -> zfs::Result<()> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    if block.len() as u64 != dnode.data_block_size() {
+        dnode.data_blk_sz_sec = (block.len() / 512) as u16;
+        dsl_pool.dirty_dnode(txg, tx.objset(), obj, dnode);
+    }
+    dsl_pool.dirty_block(txg, tx.objset(), obj, 0, block);
+    Ok(())
+}
+
+/// Dirty the blocks `zap` changes as the fat ZAP `obj`, setting its dnode's block size to the
+/// ZAP's when it's just been turned into one
+fn write_fzap(dsl_pool: &mut DslPool,
+              obj: u64,
+              mut dnode: DNodePhys,
+              zap: FatZapTx,
+              tx: &Tx)
+              -> zfs::Result<()> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    if zap.header.len() as u64 != dnode.data_block_size() {
+        dnode.data_blk_sz_sec = (zap.header.len() / 512) as u16;
+        dsl_pool.dirty_dnode(txg, tx.objset(), obj, dnode);
+    }
+    dsl_pool.dirty_block(txg, tx.objset(), obj, 0, zap.header);
+    for (blkid, block) in zap.blocks {
+        dsl_pool.dirty_block(txg, tx.objset(), obj, blkid, block);
+    }
+    Ok(())
+}
+
+/// A whole ZAP value. Micro ZAPs only hold single integers; fat ZAPs hold arrays of 1, 2, 4 or 8
+/// byte integers. Arrays of 2, 4 or 8 byte integers are read as integers of that size, byte
+/// arrays as strings if they're NUL terminated UTF-8, and as bytes otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZapValue {
+    Int(u64),
+    Array(Vec<u64>),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl ZapValue {
+    pub fn as_int(&self) -> Option<u64> {
+        match *self {
+            ZapValue::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The integers of an integer value, a single one included
+    pub fn as_ints(&self) -> Option<&[u64]> {
+        match *self {
+            ZapValue::Int(ref value) => Some(slice::from_ref(value)),
+            ZapValue::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            ZapValue::String(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The bytes of a byte array value, strings included (without their NUL)
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            ZapValue::String(ref value) => Some(value.as_bytes()),
+            ZapValue::Bytes(ref bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// All (name, value) pairs of ZAP object `obj` of `objset`, with the full values
+pub fn iter_values(reader: &mut ZfsReader,
+                   objset: &ObjectSet,
+                   obj: u64)
+                   -> Result<vec::IntoIter<(String, ZapValue)>, String> {
+    let dnode = try!(objset.dnode(reader, obj));
+    let data = try!(dmu::read_block(reader, &dnode, 0));
+    let entries = match block_type(&data) {
+        Some((ZapObjectType::Micro, order)) => {
+            try!(MZapWrapper::from_bytes(&data, order).map_err(|x| x.to_owned()))
+                .entries()
+                .into_iter()
+                .map(|(name, value)| (name, ZapValue::Int(value)))
+                .collect()
+        }
+        Some((ZapObjectType::Header, order)) => {
+            let zap = try!(FatZap::from_bytes(&data, order));
+            let mut read = |block| dmu::read_block_order(reader, &dnode, block);
+            try!(zap.entry_values(&mut read, &data))
+        }
+        _ => return Err(format!("Object {} is not a ZAP", obj)),
+    };
+    Ok(entries.into_iter())
+}
+
+/// All (key, value) pairs of fat ZAP object `obj` of `objset`, whose keys are arrays of integers
+/// rather than names (`ZAP_FLAG_UINT64_KEY`), like those of the dedup tables. The values are the
+/// bytes they're stored as: integers wider than a byte are big endian.
+pub fn iter_uint64_keys(reader: &mut ZfsReader,
+                        objset: &ObjectSet,
+                        obj: u64)
+                        -> Result<vec::IntoIter<(Vec<u64>, Vec<u8>)>, String> {
+    let dnode = try!(objset.dnode(reader, obj));
+    let data = try!(dmu::read_block(reader, &dnode, 0));
+    let zap = match block_type(&data) {
+        Some((ZapObjectType::Header, order)) => try!(FatZap::open(&data, order)),
+        _ => return Err(format!("Object {} is not a fat ZAP", obj)),
+    };
+    if zap.phys.flags & ZAP_FLAG_UINT64_KEY == 0 {
+        return Err(format!("ZAP object {} has names for keys", obj));
+    }
+    let mut read = |block| dmu::read_block_order(reader, &dnode, block);
+    Ok(try!(zap.keyed_entries(&mut read, &data)).into_iter())
+}
+
 #[repr(packed)]
 pub struct MZapEntPhys {
     pub value: u64,
@@ -91,6 +853,22 @@ impl MZapEntPhys {
     }
 }
 
+impl FromBytes for MZapEntPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let value = decoder.u64();
+        let cd = decoder.u32();
+        let pad = decoder.u16();
+        let mut name = [0; MZAP_NAME_LEN];
+        decoder.fill(&mut name);
+        MZapEntPhys {
+            value: value,
+            cd: cd,
+            pad: pad,
+            name: name,
+        }
+    }
+}
+
 impl fmt::Debug for MZapEntPhys {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f,
@@ -108,20 +886,69 @@ impl fmt::Debug for MZapEntPhys {
     }
 }
 
-/// Fatzap
+const ZAP_MAGIC: u64 = 0x2F52AB2AB;
+
+// Flags of a fat ZAP
+pub const ZAP_FLAG_HASH64: u64 = 1 << 0;
+pub const ZAP_FLAG_UINT64_KEY: u64 = 1 << 1;
+/// The first integer of a key is its hash already
+pub const ZAP_FLAG_PRE_HASHED_KEY: u64 = 1 << 2;
+
+const ZFS_CRC64_POLY: u64 = 0xC96C5795D7870F42;
+
+/// Block size of the fat ZAPs micro ZAPs are turned into (`fzap_default_block_shift`)
+const FZAP_BLOCK_SHIFT: u32 = 14;
+
+// Offsets of the header fields changed in place
+const ZAP_PTR_TABLE: usize = 16;
+const ZAP_FREE_BLOCK: usize = 56;
+const ZAP_NUM_LEAFS: usize = 64;
+const ZAP_NUM_ENTRIES: usize = 72;
+const ZAP_SALT: usize = 80;
+const ZAP_NORM_FLAGS: usize = 88;
+const ZAP_FLAGS: usize = 96;
+
+/// Longest value of an entry, in bytes
+const ZAP_MAXVALUELEN: usize = 1024 * 8;
+
+/// Header of the first block of a fat ZAP. The second half of the block is the embedded pointer
+/// table, which is used as long as the table fits in there.
 #[repr(packed)]
 pub struct ZapPhys {
-    pub block_type: ZapObjectType, // ZapObjectType::Header
+    pub block_type: u64, // ZapObjectType::Header
     pub magic: u64,
     pub ptr_table: ZapTablePhys,
     pub free_block: u64,
     pub num_leafs: u64,
     pub num_entries: u64,
     pub salt: u64,
-    pub pad: [u64; 8181],
-    pub leafs: [u64; 8192],
+    pub norm_flags: u64,
+    pub flags: u64,
+}
+
+impl FromBytes for ZapPhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let block_type = decoder.u64();
+        let magic = decoder.u64();
+        let ptr_table = ZapTablePhys::decode(decoder);
+        let mut words = [0; 6];
+        decoder.fill_u64(&mut words);
+        ZapPhys {
+            block_type: block_type,
+            magic: magic,
+            ptr_table: ptr_table,
+            free_block: words[0],
+            num_leafs: words[1],
+            num_entries: words[2],
+            salt: words[3],
+            norm_flags: words[4],
+            flags: words[5],
+        }
+    }
 }
 
+/// Where a fat ZAP's pointer table lives. `num_blocks` is 0 while the table is embedded in the
+/// header block.
 #[repr(packed)]
 pub struct ZapTablePhys {
     pub block: u64,
@@ -131,6 +958,20 @@ pub struct ZapTablePhys {
     pub block_copied: u64,
 }
 
+impl FromBytes for ZapTablePhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut words = [0; 5];
+        decoder.fill_u64(&mut words);
+        ZapTablePhys {
+            block: words[0],
+            num_blocks: words[1],
+            shift: words[2],
+            next_block: words[3],
+            block_copied: words[4],
+        }
+    }
+}
+
 const ZAP_LEAF_MAGIC: u32 = 0x2AB1EAF;
 const ZAP_LEAF_CHUNKSIZE: usize = 24;
 
@@ -138,6 +979,23 @@ const ZAP_LEAF_CHUNKSIZE: usize = 24;
 // chunk size - space for type (1) - space for next pointer (2)
 const ZAP_LEAF_ARRAY_BYTES: usize = ZAP_LEAF_CHUNKSIZE - 3;
 
+const ZAP_CHUNK_FREE: u8 = 253;
+const ZAP_CHUNK_ENTRY: u8 = 252;
+const ZAP_CHUNK_ARRAY: u8 = 251;
+const CHAIN_END: u16 = 0xFFFF;
+
+// Offsets of the leaf header fields changed in place
+const LEAF_PREFIX: usize = 16;
+const LEAF_N_FREE: usize = 28;
+const LEAF_N_ENTRIES: usize = 30;
+const LEAF_PREFIX_LEN: usize = 32;
+const LEAF_FREE_LIST: usize = 34;
+// The first byte of `pad2`
+const LEAF_FLAGS: usize = 36;
+
+/// The entries of each hash chain of a leaf are sorted by collision differentiator
+const ZLF_ENTRIES_CDSORTED: u8 = 1 << 0;
+
 // pub struct ZapLeafPhys {
 // pub header: ZapLeafHeader,
 // hash: [u16; ZAP_LEAF_HASH_NUMENTRIES],
@@ -150,8 +1008,8 @@ const ZAP_LEAF_ARRAY_BYTES: usize = ZAP_LEAF_CHUNKSIZE - 3;
 
 #[repr(packed)]
 pub struct ZapLeafHeader {
-    pub block_type: ZapObjectType, // ZapObjectType::Leaf
-    pub next: u64,
+    pub block_type: u64, // ZapObjectType::Leaf
+    pub next: u64, // Unused, leaves used to be chained through this
     pub prefix: u64,
     pub magic: u32,
     pub n_free: u16,
@@ -161,20 +1019,70 @@ pub struct ZapLeafHeader {
     pad2: [u8; 12],
 }
 
+impl FromBytes for ZapLeafHeader {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let block_type = decoder.u64();
+        let next = decoder.u64();
+        let prefix = decoder.u64();
+        let magic = decoder.u32();
+        let n_free = decoder.u16();
+        let n_entries = decoder.u16();
+        let prefix_len = decoder.u16();
+        let free_list = decoder.u16();
+        let mut pad2 = [0; 12];
+        decoder.fill(&mut pad2);
+        ZapLeafHeader {
+            block_type: block_type,
+            next: next,
+            prefix: prefix,
+            magic: magic,
+            n_free: n_free,
+            n_entries: n_entries,
+            prefix_len: prefix_len,
+            free_list: free_list,
+            pad2: pad2,
+        }
+    }
+}
+
 #[repr(packed)]
 struct ZapLeafEntry {
     leaf_type: u8,
     int_size: u8,
-    next: u16,
+    next: u16, // Next entry with the same leaf hash
     name_chunk: u16,
     name_length: u16,
     value_chunk: u16,
     value_length: u16,
-    cd: u16,
-    pad: [u8; 2],
+    cd: u32,
     hash: u64,
 }
 
+impl FromBytes for ZapLeafEntry {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let leaf_type = decoder.u8();
+        let int_size = decoder.u8();
+        let next = decoder.u16();
+        let name_chunk = decoder.u16();
+        let name_length = decoder.u16();
+        let value_chunk = decoder.u16();
+        let value_length = decoder.u16();
+        let cd = decoder.u32();
+        let hash = decoder.u64();
+        ZapLeafEntry {
+            leaf_type: leaf_type,
+            int_size: int_size,
+            next: next,
+            name_chunk: name_chunk,
+            name_length: name_length,
+            value_chunk: value_chunk,
+            value_length: value_length,
+            cd: cd,
+            hash: hash,
+        }
+    }
+}
+
 #[repr(packed)]
 struct ZapLeafArray {
     leaf_type: u8,
@@ -182,9 +1090,1378 @@ struct ZapLeafArray {
     next: u16,
 }
 
+impl FromBytes for ZapLeafArray {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let leaf_type = decoder.u8();
+        let mut array = [0; ZAP_LEAF_ARRAY_BYTES];
+        decoder.fill(&mut array);
+        let next = decoder.u16();
+        ZapLeafArray {
+            leaf_type: leaf_type,
+            array: array,
+            next: next,
+        }
+    }
+}
+
 #[repr(packed)]
 struct ZapLeafFree {
     free_type: u8,
     pad: [u8; ZAP_LEAF_ARRAY_BYTES],
     next: u16,
 }
+
+/// /////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Reads block `blkid` of a fat ZAP, returning it with the byte order it was written in
+type ReadBlock<'a> = FnMut(u64) -> Result<(Vec<u8>, ByteOrder), String> + 'a;
+
+/// The index of hash `hash` in a table of `1 << shift` entries, the top `shift` bits of the hash
+/// (`ZAP_HASH_IDX`)
+fn hash_index(hash: u64, shift: u64) -> u64 {
+    if shift == 0 {
+        0
+    } else {
+        hash >> (64 - shift)
+    }
+}
+
+/// A fat ZAP: a header block with the pointer table, which maps the top bits of a name's hash to
+/// the leaf block holding it
+struct FatZap {
+    phys: ZapPhys,
+    block_shift: u32,
+    order: ByteOrder,
+}
+
+impl FatZap {
+    /// A fat ZAP looked up by name
+    fn from_bytes(data: &[u8], order: ByteOrder) -> Result<Self, String> {
+        let zap = try!(Self::open(data, order));
+        if zap.phys.flags & ZAP_FLAG_UINT64_KEY != 0 {
+            return Err("ZAPs with integer keys are not supported".to_owned());
+        }
+        Ok(zap)
+    }
+
+    fn open(data: &[u8], order: ByteOrder) -> Result<Self, String> {
+        let phys = try!(ZapPhys::from_bytes_order(data, order));
+        let magic = phys.magic;
+        if magic != ZAP_MAGIC {
+            return Err(format!("Bad fat ZAP magic {:X}", magic));
+        }
+        if !data.len().is_power_of_two() {
+            return Err(format!("Bad fat ZAP block size {}", data.len()));
+        }
+        Ok(FatZap {
+            phys: phys,
+            block_shift: data.len().trailing_zeros(),
+            order: order,
+        })
+    }
+
+    fn hash_bits(&self) -> u32 {
+        if self.phys.flags & ZAP_FLAG_HASH64 != 0 {
+            48
+        } else {
+            28
+        }
+    }
+
+    /// The salted CRC64 of `name`, truncated to the ZAP's hash bits
+    fn hash(&self, name: &str) -> u64 {
+        self.hash_bytes(name.as_bytes())
+    }
+
+    /// The hash of integer key `key`: its first integer if keys are hashed already, or else
+    /// the salted CRC64 of its integers, little endian
+    fn hash_key(&self, key: &[u64]) -> u64 {
+        if self.phys.flags & ZAP_FLAG_PRE_HASHED_KEY != 0 {
+            return self.truncate_hash(key.first().cloned().unwrap_or(0));
+        }
+        let bytes: Vec<u8> = key.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        self.hash_bytes(&bytes)
+    }
+
+    fn hash_bytes(&self, bytes: &[u8]) -> u64 {
+        let table = crc64_table();
+        let mut hash = self.phys.salt;
+        for &c in bytes {
+            hash = (hash >> 8) ^ table[((hash ^ c as u64) & 0xFF) as usize];
+        }
+        self.truncate_hash(hash)
+    }
+
+    fn truncate_hash(&self, hash: u64) -> u64 {
+        hash & !((1 << (64 - self.hash_bits())) - 1)
+    }
+
+    /// Block number of the leaf that holds names with hash `hash`
+    fn leaf_block(&self, read: &mut ReadBlock, header: &[u8], hash: u64) -> Result<u64, String> {
+        let index = hash_index(hash, self.phys.ptr_table.shift);
+        self.ptr_table_entry(read, header, index)
+    }
+
+    fn ptr_table_entry(&self,
+                       read: &mut ReadBlock,
+                       header: &[u8],
+                       index: u64)
+                       -> Result<u64, String> {
+        let entries_per_block = 1 << (self.block_shift - 3);
+        let num_blocks = self.phys.ptr_table.num_blocks;
+        let entry = if num_blocks == 0 {
+            // The embedded table takes up the second half of the header block
+            let offset = ((entries_per_block / 2 + index) * 8) as usize;
+            if offset + 8 > header.len() {
+                return Err(format!("Pointer table index {} out of range", index));
+            }
+            u64::from_bytes_order(&header[offset..], self.order).unwrap()
+        } else {
+            let block = self.phys.ptr_table.block + index / entries_per_block;
+            let (data, order) = try!(read(block));
+            let offset = ((index % entries_per_block) * 8) as usize;
+            try!(u64::from_bytes_order(&data[offset..], order))
+        };
+        Ok(entry)
+    }
+
+    fn read_leaf(&self, read: &mut ReadBlock, block: u64) -> Result<Leaf, String> {
+        let (data, _) = try!(read(block));
+        Leaf::new(data)
+    }
+
+    fn lookup(&self,
+              read: &mut ReadBlock,
+              header: &[u8],
+              name: &ZapName)
+              -> Result<Option<(String, u64)>, String> {
+        let hash = self.hash(&name.hashed);
+        let block = try!(self.leaf_block(read, header, hash));
+        let leaf = try!(self.read_leaf(read, block));
+        leaf.lookup(hash, name)
+    }
+
+    fn lookup_value(&self,
+                    read: &mut ReadBlock,
+                    header: &[u8],
+                    name: &ZapName)
+                    -> Result<Option<ZapValue>, String> {
+        let hash = self.hash(&name.hashed);
+        let block = try!(self.leaf_block(read, header, hash));
+        let leaf = try!(self.read_leaf(read, block));
+        leaf.lookup_value(hash, name)
+    }
+
+    fn entries(&self, read: &mut ReadBlock, header: &[u8]) -> Result<Vec<(String, u64)>, String> {
+        let mut entries = Vec::new();
+        for block in try!(self.leaf_blocks(read, header)) {
+            let leaf = try!(self.read_leaf(read, block));
+            entries.extend(try!(leaf.entries()));
+        }
+        Ok(entries)
+    }
+
+    fn entry_values(&self,
+                    read: &mut ReadBlock,
+                    header: &[u8])
+                    -> Result<Vec<(String, ZapValue)>, String> {
+        let mut entries = Vec::new();
+        for block in try!(self.leaf_blocks(read, header)) {
+            let leaf = try!(self.read_leaf(read, block));
+            entries.extend(try!(leaf.entry_values()));
+        }
+        Ok(entries)
+    }
+
+    fn keyed_entries(&self,
+                     read: &mut ReadBlock,
+                     header: &[u8])
+                     -> Result<Vec<(Vec<u64>, Vec<u8>)>, String> {
+        let mut entries = Vec::new();
+        for block in try!(self.leaf_blocks(read, header)) {
+            let leaf = try!(self.read_leaf(read, block));
+            entries.extend(try!(leaf.keyed_entries()));
+        }
+        Ok(entries)
+    }
+
+    fn leaf_blocks(&self, read: &mut ReadBlock, header: &[u8]) -> Result<Vec<u64>, String> {
+        // Several pointer table entries point at the same leaf when the leaf's prefix is shorter
+        // than the table's shift, so only visit each leaf once
+        let mut blocks = Vec::new();
+        for index in 0..1 << self.phys.ptr_table.shift {
+            let block = try!(self.ptr_table_entry(read, header, index));
+            if !blocks.contains(&block) {
+                blocks.push(block);
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+/// What an entry of a fat ZAP is found by: its name, or in a ZAP with `ZAP_FLAG_UINT64_KEY`,
+/// an array of integers
+enum FatKey<'a> {
+    Name(ZapName<'a>),
+    Ints(&'a [u64]),
+}
+
+/// A fat ZAP being changed (`fzap_add`, `fzap_remove`): its header, and the other blocks
+/// changed so far. The rest are read as they're needed. Only little endian blocks are changed.
+struct FatZapTx {
+    zap: FatZap,
+    header: Vec<u8>,
+    blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl FatZapTx {
+    fn new(header: Vec<u8>) -> zfs::Result<Self> {
+        let zap = try!(FatZap::open(&header, ByteOrder::Little).map_err(|_| zfs::Error::Invalid));
+        Ok(FatZapTx {
+            zap: zap,
+            header: header,
+            blocks: BTreeMap::new(),
+        })
+    }
+
+    /// An empty fat ZAP of `1 << block_shift` byte blocks, with ZAP flags `flags`: a header with
+    /// the pointer table embedded, all of whose entries point at a single leaf to begin with
+    /// (`fzap_upgrade`)
+    fn create(block_shift: u32, salt: u64, norm_flags: u64, flags: u64) -> Self {
+        let mut header = vec![0; 1 << block_shift];
+        let shift = block_shift as u64 - 3 - 1;
+        put_u64(&mut header, ZapObjectType::Header as u64);
+        put_u64(&mut header[8..], ZAP_MAGIC);
+        put_u64(&mut header[ZAP_PTR_TABLE + 16..], shift);
+        put_u64(&mut header[ZAP_FREE_BLOCK..], 2);
+        put_u64(&mut header[ZAP_NUM_LEAFS..], 1);
+        put_u64(&mut header[ZAP_SALT..], salt);
+        put_u64(&mut header[ZAP_NORM_FLAGS..], norm_flags);
+        put_u64(&mut header[ZAP_FLAGS..], flags);
+        let table = header.len() / 2;
+        for i in 0..1 << shift {
+            put_u64(&mut header[table + i * 8..], 1);
+        }
+        let mut zap = FatZapTx::new(header).unwrap();
+        zap.blocks.insert(1, Leaf::empty(block_shift).data);
+        zap
+    }
+
+    /// The fat ZAP micro ZAP `mzap` turns into once it outgrows its block (`mzap_upgrade`)
+    fn upgrade(mzap: &MZapWrapper) -> zfs::Result<Self> {
+        let (salt, norm_flags) = (mzap.phys.salt, mzap.phys.norm_flags);
+        let mut zap = FatZapTx::create(FZAP_BLOCK_SHIFT, salt, norm_flags, 0);
+        for (name, value) in mzap.entries() {
+            try!(zap.add(&mut no_block, &name, value));
+        }
+        Ok(zap)
+    }
+
+    fn set_header(&mut self, offset: usize, value: u64) {
+        put_u64(&mut self.header[offset..], value);
+        self.zap.phys = ZapPhys::from_bytes_order(&self.header, ByteOrder::Little).unwrap();
+    }
+
+    /// Block `blkid` to change, `read` if it's not changed already
+    fn block_mut(&mut self, read: &mut ReadBlock, blkid: u64) -> zfs::Result<&mut Vec<u8>> {
+        if blkid == 0 {
+            return Ok(&mut self.header);
+        }
+        if !self.blocks.contains_key(&blkid) {
+            let (data, order) = try!(read(blkid).map_err(|_| zfs::Error::Invalid));
+            if order != ByteOrder::Little {
+                return Err(zfs::Error::UnsupportedFeature);
+            }
+            self.blocks.insert(blkid, data);
+        }
+        Ok(self.blocks.get_mut(&blkid).unwrap())
+    }
+
+    fn ptr_table_entry(&self, read: &mut ReadBlock, index: u64) -> zfs::Result<u64> {
+        let blocks = &self.blocks;
+        let mut read = |blkid| match blocks.get(&blkid) {
+            Some(data) => Ok((data.clone(), ByteOrder::Little)),
+            None => read(blkid),
+        };
+        self.zap.ptr_table_entry(&mut read, &self.header, index).map_err(|_| zfs::Error::Invalid)
+    }
+
+    fn set_ptr_table_entry(&mut self,
+                           read: &mut ReadBlock,
+                           index: u64,
+                           leaf: u64)
+                           -> zfs::Result<()> {
+        let entries_per_block = 1 << (self.zap.block_shift - 3);
+        let (blkid, offset) = if self.zap.phys.ptr_table.num_blocks == 0 {
+            (0, entries_per_block / 2 + index)
+        } else {
+            (self.zap.phys.ptr_table.block + index / entries_per_block,
+             index % entries_per_block)
+        };
+        put_u64(&mut try!(self.block_mut(read, blkid))[offset as usize * 8..], leaf);
+        Ok(())
+    }
+
+    /// The block number and the leaf holding the names with hash `hash`
+    fn leaf(&self, read: &mut ReadBlock, hash: u64) -> zfs::Result<(u64, Leaf)> {
+        let index = hash_index(hash, self.zap.phys.ptr_table.shift);
+        let blkid = try!(self.ptr_table_entry(read, index));
+        let data = match self.blocks.get(&blkid) {
+            Some(data) => data.clone(),
+            None => try!(read(blkid).map_err(|_| zfs::Error::Invalid)).0,
+        };
+        let leaf = try!(Leaf::new(data).map_err(|_| zfs::Error::Invalid));
+        if leaf.order != ByteOrder::Little {
+            return Err(zfs::Error::UnsupportedFeature);
+        }
+        Ok((blkid, leaf))
+    }
+
+    /// The key of the entry named `name`
+    fn name_key<'a>(&self, name: &'a str) -> zfs::Result<FatKey<'a>> {
+        ZapName::new(name, self.zap.phys.norm_flags, MatchType::Exact)
+            .map(FatKey::Name)
+            .map_err(|_| zfs::Error::Invalid)
+    }
+
+    /// The hash of `key`, and the key as it's stored, with its length: a name with its NUL in
+    /// bytes, or big endian integers in integers. Fails if it's not the kind of key the ZAP has.
+    fn stored_key(&self, key: &FatKey) -> zfs::Result<(u64, Vec<u8>, u16)> {
+        let uint64_key = self.zap.phys.flags & ZAP_FLAG_UINT64_KEY != 0;
+        match *key {
+            FatKey::Name(ref name) if !uint64_key => {
+                let mut bytes = name.name.as_bytes().to_vec();
+                bytes.push(0);
+                let len = bytes.len() as u16;
+                Ok((self.zap.hash(&name.hashed), bytes, len))
+            }
+            FatKey::Ints(ints) if uint64_key && !ints.is_empty() => {
+                Ok((self.zap.hash_key(ints), int_bytes(8, ints), ints.len() as u16))
+            }
+            _ => Err(zfs::Error::Invalid),
+        }
+    }
+
+    /// Add `name` with `value`, like `put`
+    fn add(&mut self, read: &mut ReadBlock, name: &str, value: u64) -> zfs::Result<()> {
+        let key = try!(self.name_key(name));
+        self.put(read, &key, 8, &value.to_be_bytes(), false)
+    }
+
+    /// Add `key` with `value`, big endian integers of `int_size` bytes, splitting the leaf it
+    /// goes in for as long as it's full. Fails with `Exists` if the ZAP has the key already,
+    /// unless `replace`, in which case the entry is replaced (`fzap_update`).
+    fn put(&mut self,
+           read: &mut ReadBlock,
+           key: &FatKey,
+           int_size: u8,
+           value: &[u8],
+           replace: bool)
+           -> zfs::Result<()> {
+        let (hash, stored_key, key_length) = try!(self.stored_key(key));
+        let value_length = value.len() / int_size as usize;
+        let chunks = 1 + array_chunks(stored_key.len()) + array_chunks(value.len());
+        if value.len() > ZAP_MAXVALUELEN || chunks > leaf_chunks(self.zap.block_shift) {
+            return Err(zfs::Error::Invalid);
+        }
+        if replace {
+            match self.remove(read, key) {
+                Ok(_) | Err(zfs::Error::NoEntity) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        loop {
+            let (blkid, mut leaf) = try!(self.leaf(read, hash));
+            if try!(leaf.find_key(hash, key).map_err(|_| zfs::Error::Invalid)).is_some() {
+                return Err(zfs::Error::Exists);
+            }
+            let cd = try!(leaf.next_cd(hash).map_err(|_| zfs::Error::Invalid));
+            if try!(leaf.add_entry(hash,
+                                   cd,
+                                   &stored_key,
+                                   key_length,
+                                   int_size,
+                                   value,
+                                   value_length as u16)
+                        .map_err(|_| zfs::Error::Invalid)) {
+                self.blocks.insert(blkid, leaf.data);
+                let num_entries = self.zap.phys.num_entries;
+                self.set_header(ZAP_NUM_ENTRIES, num_entries + 1);
+                return Ok(());
+            }
+            try!(self.split(read, blkid, leaf, hash));
+        }
+    }
+
+    /// Remove the entry with key `key`, returning the first integer of its value
+    fn remove(&mut self, read: &mut ReadBlock, key: &FatKey) -> zfs::Result<u64> {
+        let (hash, _, _) = try!(self.stored_key(key));
+        let (blkid, mut leaf) = try!(self.leaf(read, hash));
+        let (index, entry) = match leaf.find_key(hash, key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Err(zfs::Error::NoEntity),
+            Err(_) => return Err(zfs::Error::Invalid),
+        };
+        let value = try!(leaf.value(&entry).map_err(|_| zfs::Error::Invalid));
+        try!(leaf.remove_entry(index).map_err(|_| zfs::Error::Invalid));
+        self.blocks.insert(blkid, leaf.data);
+        let num_entries = self.zap.phys.num_entries;
+        self.set_header(ZAP_NUM_ENTRIES, num_entries.saturating_sub(1));
+        Ok(value)
+    }
+
+    /// Split full leaf `blkid` in two, the names with hash `hash` going in one of them. A leaf
+    /// with a pointer table entry to itself can't be split before the table grows
+    /// (`zap_expand_leaf`).
+    fn split(&mut self, read: &mut ReadBlock, blkid: u64, mut leaf: Leaf, hash: u64)
+             -> zfs::Result<()> {
+        let prefix_len = leaf.prefix_len as u64;
+        if prefix_len >= self.zap.hash_bits() as u64 {
+            return Err(zfs::Error::NoSpace);
+        }
+        let shift = self.zap.phys.ptr_table.shift;
+        if prefix_len >= shift {
+            return self.grow_ptr_table(read);
+        }
+
+        let sibling_blkid = self.zap.phys.free_block;
+        let num_leafs = self.zap.phys.num_leafs;
+        self.set_header(ZAP_FREE_BLOCK, sibling_blkid + 1);
+        self.set_header(ZAP_NUM_LEAFS, num_leafs + 1);
+        let mut sibling = Leaf::empty(self.zap.block_shift);
+        try!(leaf.split(&mut sibling).map_err(|_| zfs::Error::Invalid));
+        // The sibling takes the second half of the pointer table entries of the leaf
+        let prefix_diff = shift - (prefix_len + 1);
+        let first = (hash_index(hash, prefix_len + 1) | 1) << prefix_diff;
+        for index in first..first + (1 << prefix_diff) {
+            try!(self.set_ptr_table_entry(read, index, sibling_blkid));
+        }
+        self.blocks.insert(blkid, leaf.data);
+        self.blocks.insert(sibling_blkid, sibling.data);
+        Ok(())
+    }
+
+    /// Double the pointer table, moving it out of the header block once it no longer fits in
+    /// there (`zap_grow_ptrtbl`). The whole table is copied at once, and the blocks of the old
+    /// one stay with the object, unused.
+    fn grow_ptr_table(&mut self, read: &mut ReadBlock) -> zfs::Result<()> {
+        let shift = self.zap.phys.ptr_table.shift;
+        let mut entries = Vec::new();
+        for index in 0..1 << shift {
+            entries.push(try!(self.ptr_table_entry(read, index)));
+        }
+        let entries_per_block = 1 << (self.zap.block_shift - 3);
+        if self.zap.phys.ptr_table.num_blocks != 0 || 2 << shift > entries_per_block / 2 {
+            let num_blocks = cmp::max(1, (2 << shift) / entries_per_block);
+            let first = self.zap.phys.free_block;
+            for blkid in first..first + num_blocks {
+                self.blocks.insert(blkid, vec![0; 1 << self.zap.block_shift]);
+            }
+            self.set_header(ZAP_FREE_BLOCK, first + num_blocks);
+            self.set_header(ZAP_PTR_TABLE, first);
+            self.set_header(ZAP_PTR_TABLE + 8, num_blocks);
+            self.set_header(ZAP_PTR_TABLE + 24, 0);
+            self.set_header(ZAP_PTR_TABLE + 32, 0);
+        }
+        self.set_header(ZAP_PTR_TABLE + 16, shift + 1);
+        for (index, &leaf) in entries.iter().enumerate() {
+            try!(self.set_ptr_table_entry(read, 2 * index as u64, leaf));
+            try!(self.set_ptr_table_entry(read, 2 * index as u64 + 1, leaf));
+        }
+        Ok(())
+    }
+}
+
+// The blocks of a fat ZAP made in memory are all there
+fn no_block(blkid: u64) -> Result<(Vec<u8>, ByteOrder), String> {
+    Err(format!("Block {} of a new fat ZAP is not in memory", blkid))
+}
+
+fn crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    for i in 0..256 {
+        let mut crc = i as u64;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0u64.wrapping_sub(crc & 1) & ZFS_CRC64_POLY);
+        }
+        table[i] = crc;
+    }
+    table
+}
+
+/// A fat ZAP leaf block: a header, a hash table of chunk indices and the chunks themselves.
+/// Each entry chunk points at the array chunks holding its name and value.
+struct Leaf {
+    data: Vec<u8>,
+    block_shift: u32,
+    prefix_len: u16,
+    order: ByteOrder,
+}
+
+impl Leaf {
+    fn new(data: Vec<u8>) -> Result<Self, String> {
+        let order = match block_type(&data) {
+            Some((ZapObjectType::Leaf, order)) => order,
+            _ => return Err("Not a ZAP leaf".to_owned()),
+        };
+        let header = try!(ZapLeafHeader::from_bytes_order(&data, order));
+        let magic = header.magic;
+        if magic != ZAP_LEAF_MAGIC {
+            return Err(format!("Bad ZAP leaf magic {:X}", magic));
+        }
+        if !data.len().is_power_of_two() {
+            return Err(format!("Bad ZAP leaf size {}", data.len()));
+        }
+        Ok(Leaf {
+            block_shift: data.len().trailing_zeros(),
+            prefix_len: header.prefix_len,
+            data: data,
+            order: order,
+        })
+    }
+
+    fn hash_shift(&self) -> u32 {
+        self.block_shift - 5
+    }
+
+    fn hash_entries(&self) -> usize {
+        1 << self.hash_shift()
+    }
+
+    // The hash table follows the header, which is 2 chunks long
+    fn chunks_offset(&self) -> usize {
+        2 * ZAP_LEAF_CHUNKSIZE + 2 * self.hash_entries()
+    }
+
+    fn num_chunks(&self) -> usize {
+        leaf_chunks(self.block_shift)
+    }
+
+    fn chunk_offset(&self, index: u16) -> usize {
+        self.chunks_offset() + index as usize * ZAP_LEAF_CHUNKSIZE
+    }
+
+    fn chunk(&self, index: u16) -> Result<&[u8], String> {
+        if index as usize >= self.num_chunks() {
+            return Err(format!("ZAP leaf chunk {} out of range", index));
+        }
+        let offset = self.chunk_offset(index);
+        Ok(&self.data[offset..offset + ZAP_LEAF_CHUNKSIZE])
+    }
+
+    fn entry(&self, index: u16) -> Result<ZapLeafEntry, String> {
+        let entry = try!(ZapLeafEntry::from_bytes_order(try!(self.chunk(index)), self.order));
+        if entry.leaf_type != ZAP_CHUNK_ENTRY {
+            return Err(format!("ZAP leaf chunk {} is not an entry", index));
+        }
+        Ok(entry)
+    }
+
+    /// Read `len` bytes from the chain of array chunks starting at `index`
+    fn read_array(&self, mut index: u16, len: usize) -> Result<Vec<u8>, String> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            if index == CHAIN_END {
+                return Err("ZAP leaf array ends early".to_owned());
+            }
+            let array = try!(ZapLeafArray::from_bytes_order(try!(self.chunk(index)), self.order));
+            if array.leaf_type != ZAP_CHUNK_ARRAY {
+                return Err(format!("ZAP leaf chunk {} is not an array", index));
+            }
+            let count = cmp::min(len - data.len(), ZAP_LEAF_ARRAY_BYTES);
+            data.extend_from_slice(&array.array[..count]);
+            index = array.next;
+        }
+        Ok(data)
+    }
+
+    fn name(&self, entry: &ZapLeafEntry) -> Result<String, String> {
+        // String names are stored with their NUL terminator
+        let mut name = try!(self.read_array(entry.name_chunk, entry.name_length as usize));
+        if name.last() == Some(&0) {
+            name.pop();
+        }
+        // Only utf8only file systems are sure to have UTF-8 names. One that isn't shouldn't make
+        // the whole directory unreadable.
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+
+    /// The key of an entry of a ZAP with integer keys, which are stored big endian
+    fn key(&self, entry: &ZapLeafEntry) -> Result<Vec<u64>, String> {
+        let bytes = try!(self.read_array(entry.name_chunk, entry.name_length as usize * 8));
+        Ok(bytes.chunks(8)
+                .map(|int| int.iter().fold(0, |key, &byte| (key << 8) | byte as u64))
+                .collect())
+    }
+
+    /// The first integer of the entry's value. Integers are stored big endian.
+    fn value(&self, entry: &ZapLeafEntry) -> Result<u64, String> {
+        let int_size = entry.int_size as usize;
+        let value_length = entry.value_length;
+        if int_size == 0 || int_size > 8 || value_length == 0 {
+            return Err(format!("Unsupported ZAP value of {}x{} bytes", value_length, int_size));
+        }
+        let bytes = try!(self.read_array(entry.value_chunk, int_size));
+        Ok(bytes.iter().fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    /// The entry's whole value. Byte arrays are taken to be strings if they are NUL terminated.
+    fn full_value(&self, entry: &ZapLeafEntry) -> Result<ZapValue, String> {
+        let int_size = entry.int_size as usize;
+        let value_length = entry.value_length as usize;
+        if int_size == 0 || int_size > 8 || !int_size.is_power_of_two() {
+            return Err(format!("Unsupported ZAP value of {}x{} bytes", value_length, int_size));
+        }
+        let bytes = try!(self.read_array(entry.value_chunk, int_size * value_length));
+        if int_size == 1 {
+            if bytes.last() == Some(&0) {
+                if let Ok(string) = str::from_utf8(&bytes[..bytes.len() - 1]) {
+                    return Ok(ZapValue::String(string.to_owned()));
+                }
+            }
+            return Ok(ZapValue::Bytes(bytes));
+        }
+        let mut ints: Vec<u64> = bytes.chunks(int_size)
+                                      .map(|int| {
+                                          int.iter().fold(0, |value, &byte| {
+                                              (value << 8) | byte as u64
+                                          })
+                                      })
+                                      .collect();
+        if ints.len() == 1 {
+            Ok(ZapValue::Int(ints.pop().unwrap()))
+        } else {
+            Ok(ZapValue::Array(ints))
+        }
+    }
+
+    /// Offset of the hash table entry heading the chain of entries with hash `hash`
+    fn hash_slot(&self, hash: u64) -> usize {
+        let shift = 64 - self.hash_shift() as u64 - self.prefix_len as u64;
+        let index = (hash >> shift) as usize & (self.hash_entries() - 1);
+        2 * ZAP_LEAF_CHUNKSIZE + 2 * index
+    }
+
+    fn hash_head(&self, hash: u64) -> u16 {
+        u16::from_bytes_order(&self.data[self.hash_slot(hash)..], self.order).unwrap()
+    }
+
+    fn lookup_entry(&self, hash: u64, name: &ZapName) -> Result<Option<ZapLeafEntry>, String> {
+        let entry = try!(self.find_entry(hash, &|entry| name.matches(entry)));
+        Ok(entry.map(|(_, entry)| entry))
+    }
+
+    /// The first entry with hash `hash` whose name passes `matches`, and the index of its chunk
+    fn find_entry(&self,
+                  hash: u64,
+                  matches: &Fn(&str) -> bool)
+                  -> Result<Option<(u16, ZapLeafEntry)>, String> {
+        let mut index = self.hash_head(hash);
+        while index != CHAIN_END {
+            let entry = try!(self.entry(index));
+            if entry.hash == hash && matches(&try!(self.name(&entry))) {
+                return Ok(Some((index, entry)));
+            }
+            index = entry.next;
+        }
+        Ok(None)
+    }
+
+    /// The entry with hash `hash` and key `key`, and the index of its chunk
+    fn find_key(&self, hash: u64, key: &FatKey) -> Result<Option<(u16, ZapLeafEntry)>, String> {
+        let ints = match *key {
+            FatKey::Name(ref name) => return self.find_entry(hash, &|entry| name.matches(entry)),
+            FatKey::Ints(ints) => ints,
+        };
+        let mut index = self.hash_head(hash);
+        while index != CHAIN_END {
+            let entry = try!(self.entry(index));
+            if entry.hash == hash && entry.name_length as usize == ints.len() &&
+               try!(self.key(&entry)) == ints {
+                return Ok(Some((index, entry)));
+            }
+            index = entry.next;
+        }
+        Ok(None)
+    }
+
+    /// The entry with hash `hash` that matches `name`, its name and the first integer of its
+    /// value
+    fn lookup(&self, hash: u64, name: &ZapName) -> Result<Option<(String, u64)>, String> {
+        match try!(self.lookup_entry(hash, name)) {
+            Some(entry) => Ok(Some((try!(self.name(&entry)), try!(self.value(&entry))))),
+            None => Ok(None),
+        }
+    }
+
+    fn lookup_value(&self, hash: u64, name: &ZapName) -> Result<Option<ZapValue>, String> {
+        match try!(self.lookup_entry(hash, name)) {
+            Some(entry) => self.full_value(&entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<(String, u64)>, String> {
+        let mut entries = Vec::new();
+        for index in 0..self.num_chunks() as u16 {
+            if try!(self.chunk(index))[0] == ZAP_CHUNK_ENTRY {
+                let entry = try!(self.entry(index));
+                entries.push((try!(self.name(&entry)), try!(self.value(&entry))));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn keyed_entries(&self) -> Result<Vec<(Vec<u64>, Vec<u8>)>, String> {
+        let mut entries = Vec::new();
+        for index in 0..self.num_chunks() as u16 {
+            if try!(self.chunk(index))[0] == ZAP_CHUNK_ENTRY {
+                let entry = try!(self.entry(index));
+                let len = entry.int_size as usize * entry.value_length as usize;
+                let value = try!(self.read_array(entry.value_chunk, len));
+                entries.push((try!(self.key(&entry)), value));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn entry_values(&self) -> Result<Vec<(String, ZapValue)>, String> {
+        let mut entries = Vec::new();
+        for index in 0..self.num_chunks() as u16 {
+            if try!(self.chunk(index))[0] == ZAP_CHUNK_ENTRY {
+                let entry = try!(self.entry(index));
+                entries.push((try!(self.name(&entry)), try!(self.full_value(&entry))));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Changing leaves. Only little endian ones are changed, so their fields are read as such.
+impl Leaf {
+    /// An empty leaf of `1 << block_shift` bytes, all of whose chunks are free
+    /// (`zap_leaf_init`). It gets its prefix when it's split off another leaf.
+    fn empty(block_shift: u32) -> Self {
+        let mut leaf = Leaf {
+            data: vec![0; 1 << block_shift],
+            block_shift: block_shift,
+            prefix_len: 0,
+            order: ByteOrder::Little,
+        };
+        put_u64(&mut leaf.data, ZapObjectType::Leaf as u64);
+        put_u32(&mut leaf.data[24..], ZAP_LEAF_MAGIC);
+        let num_chunks = leaf.num_chunks() as u16;
+        leaf.set_u16(LEAF_N_FREE, num_chunks);
+        leaf.data[LEAF_FLAGS] = ZLF_ENTRIES_CDSORTED;
+        for i in 0..leaf.hash_entries() {
+            leaf.set_u16(2 * ZAP_LEAF_CHUNKSIZE + 2 * i, CHAIN_END);
+        }
+        for index in 0..num_chunks {
+            let chunk = leaf.chunk_offset(index);
+            let next = if index + 1 == num_chunks { CHAIN_END } else { index + 1 };
+            leaf.data[chunk] = ZAP_CHUNK_FREE;
+            leaf.set_u16(chunk + 22, next);
+        }
+        leaf
+    }
+
+    fn get_u16(&self, offset: usize) -> u16 {
+        self.data[offset] as u16 | (self.data[offset + 1] as u16) << 8
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        put_u16(&mut self.data[offset..], value);
+    }
+
+    /// Take the first chunk off the free list (`zap_leaf_chunk_alloc`)
+    fn alloc_chunk(&mut self) -> Result<u16, String> {
+        let index = self.get_u16(LEAF_FREE_LIST);
+        if try!(self.chunk(index))[0] != ZAP_CHUNK_FREE {
+            return Err(format!("ZAP leaf chunk {} on the free list is in use", index));
+        }
+        let chunk = self.chunk_offset(index);
+        let next = self.get_u16(chunk + 22);
+        for byte in &mut self.data[chunk..chunk + ZAP_LEAF_CHUNKSIZE] {
+            *byte = 0;
+        }
+        self.set_u16(LEAF_FREE_LIST, next);
+        let n_free = self.get_u16(LEAF_N_FREE);
+        self.set_u16(LEAF_N_FREE, n_free.wrapping_sub(1));
+        Ok(index)
+    }
+
+    /// Put chunk `index` back on the free list (`zap_leaf_chunk_free`)
+    fn free_chunk(&mut self, index: u16) {
+        let chunk = self.chunk_offset(index);
+        for byte in &mut self.data[chunk..chunk + ZAP_LEAF_CHUNKSIZE] {
+            *byte = 0;
+        }
+        self.data[chunk] = ZAP_CHUNK_FREE;
+        let head = self.get_u16(LEAF_FREE_LIST);
+        self.set_u16(chunk + 22, head);
+        self.set_u16(LEAF_FREE_LIST, index);
+        let n_free = self.get_u16(LEAF_N_FREE);
+        self.set_u16(LEAF_N_FREE, n_free + 1);
+    }
+
+    /// Store `bytes` in a chain of array chunks, returning the index of the first
+    /// (`zap_leaf_array_create`)
+    fn write_array(&mut self, bytes: &[u8]) -> Result<u16, String> {
+        let mut first = CHAIN_END;
+        let mut last = None;
+        for piece in bytes.chunks(ZAP_LEAF_ARRAY_BYTES) {
+            let index = try!(self.alloc_chunk());
+            let chunk = self.chunk_offset(index);
+            self.data[chunk] = ZAP_CHUNK_ARRAY;
+            self.data[chunk + 1..chunk + 1 + piece.len()].copy_from_slice(piece);
+            self.set_u16(chunk + 22, CHAIN_END);
+            match last {
+                Some(last) => self.set_u16(last + 22, index),
+                None => first = index,
+            }
+            last = Some(chunk);
+        }
+        Ok(first)
+    }
+
+    /// All the bytes of the chain of array chunks starting at `index`
+    fn read_chain(&self, mut index: u16) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        while index != CHAIN_END {
+            let array = try!(ZapLeafArray::from_bytes_order(try!(self.chunk(index)), self.order));
+            if array.leaf_type != ZAP_CHUNK_ARRAY {
+                return Err(format!("ZAP leaf chunk {} is not an array", index));
+            }
+            data.extend_from_slice(&array.array);
+            index = array.next;
+        }
+        Ok(data)
+    }
+
+    /// Free the chain of array chunks starting at `index` (`zap_leaf_array_free`)
+    fn free_array(&mut self, mut index: u16) -> Result<(), String> {
+        while index != CHAIN_END {
+            let array = try!(ZapLeafArray::from_bytes_order(try!(self.chunk(index)), self.order));
+            if array.leaf_type != ZAP_CHUNK_ARRAY {
+                return Err(format!("ZAP leaf chunk {} is not an array", index));
+            }
+            self.free_chunk(index);
+            index = array.next;
+        }
+        Ok(())
+    }
+
+    /// The lowest collision differentiator no entry with hash `hash` has yet
+    fn next_cd(&self, hash: u64) -> Result<u32, String> {
+        let mut cds = Vec::new();
+        let mut index = self.hash_head(hash);
+        while index != CHAIN_END {
+            let entry = try!(self.entry(index));
+            if entry.hash == hash {
+                cds.push(entry.cd);
+            }
+            index = entry.next;
+        }
+        Ok((0..).find(|cd| !cds.contains(cd)).unwrap())
+    }
+
+    /// Add an entry with hash `hash` and collision differentiator `cd`, whose key is `name`, a
+    /// name (its NUL included) or big endian integers, `name_length` bytes or integers long.
+    /// Its value is `value`, `value_length` big endian integers of `int_size` bytes. False if
+    /// the leaf has too few free chunks left for it (`zap_entry_create`).
+    fn add_entry(&mut self,
+                 hash: u64,
+                 cd: u32,
+                 name: &[u8],
+                 name_length: u16,
+                 int_size: u8,
+                 value: &[u8],
+                 value_length: u16)
+                 -> Result<bool, String> {
+        if 1 + array_chunks(name.len()) + array_chunks(value.len()) >
+           self.get_u16(LEAF_N_FREE) as usize {
+            return Ok(false);
+        }
+        let index = try!(self.alloc_chunk());
+        let name_chunk = try!(self.write_array(name));
+        let value_chunk = try!(self.write_array(value));
+        let chunk = self.chunk_offset(index);
+        self.data[chunk] = ZAP_CHUNK_ENTRY;
+        self.data[chunk + 1] = int_size;
+        self.set_u16(chunk + 4, name_chunk);
+        self.set_u16(chunk + 6, name_length);
+        self.set_u16(chunk + 8, value_chunk);
+        self.set_u16(chunk + 10, value_length);
+        put_u32(&mut self.data[chunk + 12..], cd);
+        put_u64(&mut self.data[chunk + 16..], hash);
+        try!(self.rehash(index));
+        let n_entries = self.get_u16(LEAF_N_ENTRIES);
+        self.set_u16(LEAF_N_ENTRIES, n_entries + 1);
+        Ok(true)
+    }
+
+    /// Link entry `index` into the chain of its hash, which is kept sorted by collision
+    /// differentiator (`zap_leaf_rehash_entry`)
+    fn rehash(&mut self, index: u16) -> Result<(), String> {
+        let entry = try!(self.entry(index));
+        let mut slot = self.hash_slot(entry.hash);
+        loop {
+            let next = self.get_u16(slot);
+            if next == CHAIN_END || try!(self.entry(next)).cd > entry.cd {
+                break;
+            }
+            slot = self.chunk_offset(next) + 2;
+        }
+        let next = self.get_u16(slot);
+        let chunk = self.chunk_offset(index);
+        self.set_u16(chunk + 2, next);
+        self.set_u16(slot, index);
+        Ok(())
+    }
+
+    /// Free entry `index` and its arrays, which are no longer in a hash chain
+    fn free_entry(&mut self, index: u16) -> Result<(), String> {
+        let entry = try!(self.entry(index));
+        try!(self.free_array(entry.name_chunk));
+        try!(self.free_array(entry.value_chunk));
+        self.free_chunk(index);
+        let n_entries = self.get_u16(LEAF_N_ENTRIES);
+        self.set_u16(LEAF_N_ENTRIES, n_entries.wrapping_sub(1));
+        Ok(())
+    }
+
+    /// Remove entry `index` (`zap_entry_remove`)
+    fn remove_entry(&mut self, index: u16) -> Result<(), String> {
+        let entry = try!(self.entry(index));
+        let mut slot = self.hash_slot(entry.hash);
+        loop {
+            let next = self.get_u16(slot);
+            if next == index {
+                break;
+            }
+            if next == CHAIN_END {
+                return Err(format!("ZAP leaf entry {} is not in its hash chain", index));
+            }
+            try!(self.entry(next));
+            slot = self.chunk_offset(next) + 2;
+        }
+        self.set_u16(slot, entry.next);
+        self.free_entry(index)
+    }
+
+    /// Split the leaf in two, moving the entries whose hash has the bit after the prefix set to
+    /// `sibling`, an empty leaf. Both end up with a prefix a bit longer (`zap_leaf_split`).
+    fn split(&mut self, sibling: &mut Leaf) -> Result<(), String> {
+        let bit = 63 - self.prefix_len as u64;
+        let prefix = try!(u64::from_bytes_order(&self.data[LEAF_PREFIX..], self.order));
+        self.prefix_len += 1;
+        put_u64(&mut self.data[LEAF_PREFIX..], prefix << 1);
+        let prefix_len = self.prefix_len;
+        self.set_u16(LEAF_PREFIX_LEN, prefix_len);
+        sibling.prefix_len = prefix_len;
+        put_u64(&mut sibling.data[LEAF_PREFIX..], prefix << 1 | 1);
+        sibling.set_u16(LEAF_PREFIX_LEN, prefix_len);
+
+        // The chains are rebuilt for the longer prefix
+        for i in 0..self.hash_entries() {
+            self.set_u16(2 * ZAP_LEAF_CHUNKSIZE + 2 * i, CHAIN_END);
+        }
+        self.data[LEAF_FLAGS] |= ZLF_ENTRIES_CDSORTED;
+        for index in 0..self.num_chunks() as u16 {
+            if try!(self.chunk(index))[0] != ZAP_CHUNK_ENTRY {
+                continue;
+            }
+            let entry = try!(self.entry(index));
+            if entry.hash & 1 << bit == 0 {
+                try!(self.rehash(index));
+                continue;
+            }
+            // The arrays are copied whole, whatever the key is made of
+            let name = try!(self.read_chain(entry.name_chunk));
+            let value = try!(self.read_chain(entry.value_chunk));
+            if !try!(sibling.add_entry(entry.hash,
+                                       entry.cd,
+                                       &name,
+                                       entry.name_length,
+                                       entry.int_size,
+                                       &value,
+                                       entry.value_length)) {
+                return Err("ZAP leaf split runs out of chunks".to_owned());
+            }
+            try!(self.free_entry(index));
+        }
+        Ok(())
+    }
+}
+
+/// Chunks of a leaf of `1 << block_shift` bytes, after its header and hash table
+fn leaf_chunks(block_shift: u32) -> usize {
+    ((1 << block_shift) - 2 * (1 << (block_shift - 5))) / ZAP_LEAF_CHUNKSIZE - 2
+}
+
+/// Array chunks it takes to store `len` bytes
+fn array_chunks(len: usize) -> usize {
+    (len + ZAP_LEAF_ARRAY_BYTES - 1) / ZAP_LEAF_ARRAY_BYTES
+}
+
+/// `ints` as a fat ZAP stores them: big endian, `int_size` bytes each
+fn int_bytes(int_size: u8, ints: &[u64]) -> Vec<u8> {
+    ints.iter().flat_map(|int| int.to_be_bytes()[8 - int_size as usize..].to_vec()).collect()
+}
+
+fn put_u16(data: &mut [u8], value: u16) {
+    data[0] = value as u8;
+    data[1] = (value >> 8) as u8;
+}
+
+fn put_u32(data: &mut [u8], value: u32) {
+    for i in 0..4 {
+        data[i] = (value >> (i * 8)) as u8;
+    }
+}
+
+fn put_u64(data: &mut [u8], value: u64) {
+    for i in 0..8 {
+        data[i] = (value >> (i * 8)) as u8;
+    }
+}
+
+#[test]
+fn test_micro_zap_entries() {
+    let mut block = vec![0u8; 512];
+    put_u64(&mut block, ZapObjectType::Micro as u64);
+    for &(slot, name, value) in &[(0, "ROOT", 34), (2, "DELETE_QUEUE", 33)] {
+        let entry = mem::size_of::<MZapPhys>() + slot * MZAP_ENT_LEN;
+        put_u64(&mut block[entry..], value);
+        block[entry + 14..entry + 14 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    assert_eq!(block_type(&block).map(|(t, order)| (t as u64, order)),
+               Some((ZapObjectType::Micro as u64, ByteOrder::Little)));
+    let zap = MZapWrapper::from_bytes(&block, ByteOrder::Little).unwrap();
+    // Slot 1 is free and skipped
+    assert_eq!(zap.entries(),
+               vec![("ROOT".to_owned(), 34), ("DELETE_QUEUE".to_owned(), 33)]);
+
+    // The same block written by a big endian host
+    for &word in &[0, mem::size_of::<MZapPhys>(), mem::size_of::<MZapPhys>() + 2 * MZAP_ENT_LEN] {
+        block[word..word + 8].reverse();
+    }
+    let (_, order) = block_type(&block).unwrap();
+    assert_eq!(order, ByteOrder::Big);
+    assert_eq!(MZapWrapper::from_bytes(&block, order).unwrap().entries(),
+               vec![("ROOT".to_owned(), 34), ("DELETE_QUEUE".to_owned(), 33)]);
+}
+
+#[test]
+fn test_mzap_block() {
+    let block = mzap_block(&[("ROOT", 34), ("DELETE_QUEUE", 33)], 0x1235).unwrap();
+    assert_eq!(block.len(), 512);
+    let zap = MZapWrapper::from_bytes(&block, ByteOrder::Little).unwrap();
+    let salt = zap.phys.salt;
+    assert_eq!(salt, 0x1235);
+    assert_eq!(zap.entries(),
+               vec![("ROOT".to_owned(), 34), ("DELETE_QUEUE".to_owned(), 33)]);
+
+    // Blocks double until every entry has a chunk
+    let names: Vec<String> = (0..20).map(|i| format!("entry{}", i)).collect();
+    let entries: Vec<_> = names.iter().map(|name| (&name[..], 1)).collect();
+    assert_eq!(mzap_block(&entries, 1).unwrap().len(), 2048);
+    assert!(mzap_block(&[(&"x".repeat(MZAP_NAME_LEN)[..], 1)], 1).is_none());
+}
+
+#[test]
+fn test_zap_add_remove() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_objset::ObjectSetType;
+    use super::dmu_zfetch::Zfetch;
+    use super::dsl_pool::MOS_OBJSET;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 1 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mos = ObjectSet::create(ObjectSetType::Meta);
+    let mut dsl_pool = DslPool::new();
+    let mut tx = Tx::create(MOS_OBJSET);
+    tx.hold_zap(7, true, None);
+    let txg = tx.assign(&mut dsl_pool).unwrap();
+    create(&mut dsl_pool, txg, MOS_OBJSET, 7, ObjectType::DslProps as u8, &[("a", 1)]).unwrap();
+
+    // 512 bytes have room for 7 entries, the 8th doubles the block
+    let names: Vec<String> = (0..8).map(|i| format!("entry{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        add(&mut reader, &mut dsl_pool, &mos, 7, name, i as u64, &tx).unwrap();
+    }
+    assert_eq!(dsl_pool.pending_block(MOS_OBJSET, 7, 0).map(|block| block.len()), Some(1024));
+    assert_eq!(dsl_pool.pending_dnode(MOS_OBJSET, 7).unwrap().data_block_size(), 1024);
+    assert_eq!(add(&mut reader, &mut dsl_pool, &mos, 7, "a", 2, &tx),
+               Err(zfs::Error::Exists));
+
+    assert_eq!(remove(&mut reader, &mut dsl_pool, &mos, 7, "a", &tx), Ok(1));
+    assert_eq!(remove(&mut reader, &mut dsl_pool, &mos, 7, "a", &tx),
+               Err(zfs::Error::NoEntity));
+    let mut entries = pending_entries(&mut reader, &dsl_pool, &mos, MOS_OBJSET, 7).unwrap();
+    entries.sort();
+    let expected: Vec<_> = names.iter().cloned().zip(0..).collect();
+    assert_eq!(entries, expected);
+
+    // A name too long for a micro ZAP turns it into a fat ZAP
+    let long = "x".repeat(MZAP_NAME_LEN);
+    add(&mut reader, &mut dsl_pool, &mos, 7, &long, 8, &tx).unwrap();
+    assert_eq!(dsl_pool.pending_dnode(MOS_OBJSET, 7).unwrap().data_block_size(),
+               1 << FZAP_BLOCK_SHIFT);
+    assert_eq!(add(&mut reader, &mut dsl_pool, &mos, 7, "entry1", 2, &tx),
+               Err(zfs::Error::Exists));
+    assert_eq!(remove(&mut reader, &mut dsl_pool, &mos, 7, "entry0", &tx), Ok(0));
+    let mut entries = pending_entries(&mut reader, &dsl_pool, &mos, MOS_OBJSET, 7).unwrap();
+    entries.sort();
+    let mut expected: Vec<_> = expected[1..].iter().cloned().chain(Some((long, 8))).collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+
+    // Changes have to be held
+    let mut other = Tx::create(MOS_OBJSET);
+    other.hold_zap(8, true, None);
+    other.assign(&mut dsl_pool).unwrap();
+    assert_eq!(add(&mut reader, &mut dsl_pool, &mos, 7, "b", 1, &other),
+               Err(zfs::Error::Invalid));
+}
+
+#[test]
+fn test_fat_zap_split() {
+    // Blocks of 512 bytes make leaves of 18 chunks, with room for 6 entries with short names,
+    // and an embedded pointer table of 32 entries. So 1000 entries split leaves, and move the
+    // table out of the header.
+    let mut zap = FatZapTx::create(9, 0x1234, 0, 0);
+    let names: Vec<String> = (0..1000).map(|i| format!("entry{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        zap.add(&mut no_block, name, i as u64).unwrap();
+    }
+    assert_eq!(zap.add(&mut no_block, "entry7", 0), Err(zfs::Error::Exists));
+    let num_entries = zap.zap.phys.num_entries;
+    let num_blocks = zap.zap.phys.ptr_table.num_blocks;
+    let free_block = zap.zap.phys.free_block;
+    assert_eq!(num_entries, 1000);
+    assert!(num_blocks > 1);
+    assert_eq!(*zap.blocks.keys().last().unwrap(), free_block - 1);
+
+    // Every entry is listed, and looked up in the leaf the pointer table has for it
+    let entries = |zap: &FatZapTx| {
+        let mut read = |blkid| {
+            let block = zap.blocks.get(&blkid).cloned();
+            block.map(|block| (block, ByteOrder::Little)).ok_or(String::new())
+        };
+        let mut entries = zap.zap.entries(&mut read, &zap.header).unwrap();
+        entries.sort_by_key(|&(_, value)| value);
+        for &(ref name, value) in &entries {
+            let zap_name = ZapName::new(name, 0, MatchType::Exact).unwrap();
+            let entry = zap.zap.lookup(&mut read, &zap.header, &zap_name).unwrap();
+            assert_eq!(entry, Some((name.clone(), value)));
+        }
+        entries
+    };
+    let expected: Vec<_> = names.iter().cloned().zip(0..).collect();
+    assert_eq!(entries(&zap), expected);
+
+    for name in names.iter().step_by(2) {
+        let key = zap.name_key(name).unwrap();
+        zap.remove(&mut no_block, &key).unwrap();
+    }
+    let key = zap.name_key("entry0").unwrap();
+    assert_eq!(zap.remove(&mut no_block, &key), Err(zfs::Error::NoEntity));
+    let expected: Vec<_> = expected.into_iter().filter(|&(_, value)| value % 2 == 1).collect();
+    assert_eq!(entries(&zap), expected);
+    let num_entries = zap.zap.phys.num_entries;
+    assert_eq!(num_entries, 500);
+}
+
+// Append a chain of array chunks holding `bytes`, returning the index of the first
+#[cfg(test)]
+fn put_array(data: &mut [u8], chunks_offset: usize, next_chunk: &mut u16, bytes: &[u8]) -> u16 {
+    let first = *next_chunk;
+    let pieces: Vec<&[u8]> = bytes.chunks(ZAP_LEAF_ARRAY_BYTES).collect();
+    for (i, piece) in pieces.iter().enumerate() {
+        let chunk = chunks_offset + *next_chunk as usize * ZAP_LEAF_CHUNKSIZE;
+        data[chunk] = ZAP_CHUNK_ARRAY;
+        data[chunk + 1..chunk + 1 + piece.len()].copy_from_slice(piece);
+        *next_chunk += 1;
+        let next = if i + 1 == pieces.len() { CHAIN_END } else { *next_chunk };
+        put_u16(&mut data[chunk + 22..], next);
+    }
+    first
+}
+
+#[test]
+fn test_fat_zap_leaf() {
+    let mut header = vec![0u8; 4096];
+    put_u64(&mut header, ZapObjectType::Header as u64);
+    put_u64(&mut header[8..], ZAP_MAGIC);
+    put_u64(&mut header[72..], 0x1234567); // salt
+    let zap = FatZap::from_bytes(&header, ByteOrder::Little).unwrap();
+    assert_eq!(crc64_table()[128], ZFS_CRC64_POLY);
+    // Only the top 28 bits of the hash are kept
+    assert_eq!(zap.hash("file") & 0xFFFFFFFFF, 0);
+    assert!(zap.hash("file") != zap.hash("elif"));
+
+    let mut data = vec![0u8; 4096];
+    put_u64(&mut data, ZapObjectType::Leaf as u64);
+    data[24..28].copy_from_slice(&[0xAF, 0x1E, 0xAB, 0x02]); // ZAP_LEAF_MAGIC
+    let chunks_offset = 2 * ZAP_LEAF_CHUNKSIZE + 2 * 128;
+    for i in 0..128 {
+        put_u16(&mut data[2 * ZAP_LEAF_CHUNKSIZE + 2 * i..], CHAIN_END);
+    }
+
+    // The last two names land in the same hash bucket, so they get chained
+    let long_name = "a name too long to fit in a single array chunk";
+    let names = [("file", zap.hash("file"), 7u64),
+                 (long_name, 0x1230000000000000, 8),
+                 ("collision", 0x1238000000000000, 9)];
+    let mut next_chunk = 0;
+    for &(name, hash, value) in &names {
+        let entry = next_chunk;
+        next_chunk += 1;
+        let mut name_bytes = name.as_bytes().to_vec();
+        name_bytes.push(0);
+        let name_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, &name_bytes);
+        let value_bytes = [0, 0, 0, 0, 0, 0, 0, value as u8];
+        let value_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, &value_bytes);
+
+        let bucket = 2 * ZAP_LEAF_CHUNKSIZE + 2 * (hash >> (64 - 7)) as usize;
+        let head = data[bucket] as u16 | (data[bucket + 1] as u16) << 8;
+        put_u16(&mut data[bucket..], entry);
+
+        let chunk = chunks_offset + entry as usize * ZAP_LEAF_CHUNKSIZE;
+        data[chunk] = ZAP_CHUNK_ENTRY;
+        data[chunk + 1] = 8;
+        put_u16(&mut data[chunk + 2..], head);
+        put_u16(&mut data[chunk + 4..], name_chunk);
+        put_u16(&mut data[chunk + 6..], name_bytes.len() as u16);
+        put_u16(&mut data[chunk + 8..], value_chunk);
+        put_u16(&mut data[chunk + 10..], 1);
+        put_u64(&mut data[chunk + 16..], hash);
+    }
+
+    let leaf = Leaf::new(data).unwrap();
+    let exact = |name| ZapName::new(name, 0, MatchType::Exact).unwrap();
+    for &(name, hash, value) in &names {
+        assert_eq!(leaf.lookup(hash, &exact(name)).unwrap(), Some((name.to_owned(), value)));
+    }
+    assert_eq!(leaf.lookup(names[1].1, &exact("collision")).unwrap(), None);
+    assert_eq!(leaf.lookup(zap.hash("missing"), &exact("missing")).unwrap(), None);
+    assert_eq!(leaf.entries().unwrap(),
+               names.iter().map(|&(name, _, value)| (name.to_owned(), value)).collect::<Vec<_>>());
+    assert_eq!(leaf.entry_values().unwrap()[0],
+               ("file".to_owned(), ZapValue::Int(7)));
+}
+
+#[test]
+fn test_zap_values() {
+    let mut data = vec![0u8; 4096];
+    put_u64(&mut data, ZapObjectType::Leaf as u64);
+    data[24..28].copy_from_slice(&[0xAF, 0x1E, 0xAB, 0x02]); // ZAP_LEAF_MAGIC
+    let chunks_offset = 2 * ZAP_LEAF_CHUNKSIZE + 2 * 128;
+    for i in 0..128 {
+        put_u16(&mut data[2 * ZAP_LEAF_CHUNKSIZE + 2 * i..], CHAIN_END);
+    }
+
+    // Integers are stored big endian, in the integer size of the entry
+    let values: [(&str, u8, &[u8]); 5] = [("int", 8, &[0, 0, 0, 0, 0, 0, 1, 2]),
+                                          ("layout", 2, &[0, 5, 0, 6, 1, 0]),
+                                          ("mountpoint", 1, b"/tank\0"),
+                                          ("key", 1, &[0xFF, 0, 0xFE]),
+                                          ("odd", 3, &[0, 0, 1])];
+    let mut next_chunk = 0;
+    for (i, &(name, int_size, value)) in values.iter().enumerate() {
+        let hash = (i as u64) << 57;
+        let entry = next_chunk;
+        next_chunk += 1;
+        let mut name_bytes = name.as_bytes().to_vec();
+        name_bytes.push(0);
+        let name_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, &name_bytes);
+        let value_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, value);
+        put_u16(&mut data[2 * ZAP_LEAF_CHUNKSIZE + 2 * i..], entry);
+
+        let chunk = chunks_offset + entry as usize * ZAP_LEAF_CHUNKSIZE;
+        data[chunk] = ZAP_CHUNK_ENTRY;
+        data[chunk + 1] = int_size;
+        put_u16(&mut data[chunk + 2..], CHAIN_END);
+        put_u16(&mut data[chunk + 4..], name_chunk);
+        put_u16(&mut data[chunk + 6..], name_bytes.len() as u16);
+        put_u16(&mut data[chunk + 8..], value_chunk);
+        put_u16(&mut data[chunk + 10..], (value.len() / int_size as usize) as u16);
+        put_u64(&mut data[chunk + 16..], hash);
+    }
+
+    let leaf = Leaf::new(data).unwrap();
+    let exact = |name| ZapName::new(name, 0, MatchType::Exact).unwrap();
+    let value = |i: u64| leaf.lookup_value(i << 57, &exact(values[i as usize].0));
+    assert_eq!(value(0).unwrap(), Some(ZapValue::Int(0x102)));
+    assert_eq!(value(1).unwrap(), Some(ZapValue::Array(vec![5, 6, 0x100])));
+    assert_eq!(value(1).unwrap().unwrap().as_ints(), Some(&[5, 6, 0x100][..]));
+    assert_eq!(value(2).unwrap().unwrap().as_str(), Some("/tank"));
+    assert_eq!(value(3).unwrap(), Some(ZapValue::Bytes(vec![0xFF, 0, 0xFE])));
+    assert!(value(4).is_err());
+    assert_eq!(leaf.lookup_value(0, &exact("missing")).unwrap(), None);
+    // Only the first integer of a value is looked up by plain lookups
+    assert_eq!(leaf.lookup(1 << 57, &exact("layout")).unwrap(), Some(("layout".to_owned(), 5)));
+    assert_eq!(ZapValue::Int(7).as_ints(), Some(&[7][..]));
+}
+
+#[test]
+fn test_uint64_keys() {
+    let mut data = vec![0u8; 4096];
+    put_u64(&mut data, ZapObjectType::Leaf as u64);
+    data[24..28].copy_from_slice(&[0xAF, 0x1E, 0xAB, 0x02]); // ZAP_LEAF_MAGIC
+    let chunks_offset = 2 * ZAP_LEAF_CHUNKSIZE + 2 * 128;
+    for i in 0..128 {
+        put_u16(&mut data[2 * ZAP_LEAF_CHUNKSIZE + 2 * i..], CHAIN_END);
+    }
+
+    // A key of two integers, stored big endian, with a value of 3 bytes
+    let mut next_chunk = 1;
+    let key = [0, 0, 0, 0, 0, 0, 0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 5];
+    let name_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, &key);
+    let value_chunk = put_array(&mut data, chunks_offset, &mut next_chunk, &[7, 0, 9]);
+    put_u16(&mut data[2 * ZAP_LEAF_CHUNKSIZE..], 0);
+    data[chunks_offset] = ZAP_CHUNK_ENTRY;
+    data[chunks_offset + 1] = 1;
+    put_u16(&mut data[chunks_offset + 2..], CHAIN_END);
+    put_u16(&mut data[chunks_offset + 4..], name_chunk);
+    put_u16(&mut data[chunks_offset + 6..], 2);
+    put_u16(&mut data[chunks_offset + 8..], value_chunk);
+    put_u16(&mut data[chunks_offset + 10..], 3);
+
+    let leaf = Leaf::new(data).unwrap();
+    assert_eq!(leaf.keyed_entries().unwrap(), vec![(vec![0x1234, 5], vec![7, 0, 9])]);
+}
+
+#[test]
+fn test_zap_name() {
+    use super::u8_textprep::U8_TEXTPREP_NFD;
+
+    // A directory of a case insensitive dataset normalized to form D
+    let flags = U8_TEXTPREP_TOUPPER | U8_TEXTPREP_NFD;
+    let name = ZapName::new("\u{c9}t\u{e9}", flags, MatchType::Normalize).unwrap();
+    assert_eq!(name.hashed, "E\u{301}TE\u{301}");
+    assert!(name.matches("\u{e9}t\u{e9}") && name.matches("E\u{301}TE\u{301}"));
+    assert!(!name.matches("ete"));
+
+    // Only normalization differences match when case has to
+    let name = ZapName::new("\u{c9}t\u{e9}", flags, MatchType::NormalizeMatchCase).unwrap();
+    assert_eq!(name.hashed, "E\u{301}TE\u{301}");
+    assert!(name.matches("E\u{301}te\u{301}") && !name.matches("\u{e9}t\u{e9}"));
+
+    // Exact matches still hash the normalized name, ZAPs without flags don't normalize at all
+    let name = ZapName::new("\u{c9}t\u{e9}", flags, MatchType::Exact).unwrap();
+    assert_eq!(name.hashed, "E\u{301}TE\u{301}");
+    assert!(name.matches("\u{c9}t\u{e9}") && !name.matches("E\u{301}TE\u{301}"));
+    let name = ZapName::new("\u{c9}t\u{e9}", 0, MatchType::Normalize).unwrap();
+    assert_eq!(name.hashed, "\u{c9}t\u{e9}");
+    assert!(!name.matches("\u{e9}t\u{e9}"));
+}
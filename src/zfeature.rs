@@ -0,0 +1,363 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::fmt;
+
+use super::ZfsReader;
+use super::dmu;
+use super::dmu_objset::{DMU_POOL_DIRECTORY_OBJECT, ObjectSet};
+use super::dmu_tx::Tx;
+use super::dnode::{DNodePhys, ObjectType};
+use super::dsl_pool::{DslPool, MOS_OBJSET};
+use super::zap;
+use super::zfs;
+
+/// The feature that has the MOS keep the txg each feature was enabled in
+const ENABLED_TXG: &'static str = "com.delphix:enabled_txg";
+const DMU_OTN_ZAP_METADATA: u8 = 0x80 | 0x40 | 4;
+
+/// Features whose on-disk format we understand, so pools with them active can be read
+const READ_FEATURES: [&'static str; 9] = ["com.datto:encryption",
+                                          "com.delphix:device_removal",
+                                          "com.delphix:embedded_data",
+                                          "com.delphix:extensible_dataset",
+                                          "com.delphix:hole_birth",
+                                          "org.open-zfs:large_blocks",
+                                          "org.openzfs:raidz_expansion",
+                                          "org.zfsonlinux:large_dnode",
+                                          "org.zfsonlinux:longname"];
+
+/// Features we read, but don't write, so pools with them active are opened read-only. Our
+/// writes would leave blocks of encrypted datasets unencrypted, and allocate from removed vdevs
+/// and never count their obsolete space. Obsolete counts are read-only compatible, kept in
+/// `features_for_write`, but they come with device removal.
+const READ_ONLY_FEATURES: [&'static str; 3] = ["com.datto:encryption",
+                                               "com.delphix:device_removal",
+                                               "com.delphix:obsolete_counts"];
+
+/// Read-only compatible features that don't get in the way of our writes. We only ever rewrite
+/// labels and uberblocks, so features that just change MOS bookkeeping are fine. Features like
+/// checkpoints or device removal change what a label or uberblock rewrite means, so they aren't.
+const WRITE_FEATURES: [&'static str; 7] = ["com.delphix:async_destroy",
+                                           "com.delphix:bookmarks",
+                                           "com.delphix:empty_bpobj",
+                                           "com.delphix:enabled_txg",
+                                           "com.delphix:spacemap_histogram",
+                                           "com.joyent:filesystem_limits",
+                                           "com.joyent:multi_vdev_crash_dump"];
+
+/// A feature enabled on a pool, as the MOS feature objects have it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Feature {
+    pub guid: String,
+    /// How many things on disk use the feature. It's active if any do, only enabled otherwise.
+    pub refcount: u64,
+    /// Whether the pool can still be read, but not written, without understanding the feature
+    /// (it's in `features_for_write`)
+    pub read_only_compatible: bool,
+    /// The txg the feature was enabled in, for pools keeping track (the enabled_txg feature)
+    pub enabled_txg: Option<u64>,
+}
+
+impl Feature {
+    pub fn is_active(&self) -> bool {
+        self.refcount > 0
+    }
+
+    /// Whether we understand the feature well enough to read the pool, and to write it if
+    /// `write` is set
+    pub fn is_supported(&self, write: bool) -> bool {
+        if write && READ_ONLY_FEATURES.contains(&&self.guid[..]) {
+            false
+        } else if self.read_only_compatible {
+            !write || WRITE_FEATURES.contains(&&self.guid[..])
+        } else {
+            READ_FEATURES.contains(&&self.guid[..])
+        }
+    }
+}
+
+/// Which of a pool's active features we don't support, and what that means for opening it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeatureReport {
+    /// Active features that have to be understood to read the pool at all
+    pub unsupported_for_read: Vec<String>,
+    /// Active read-only compatible features. The pool can still be read, but not written.
+    pub unsupported_for_write: Vec<String>,
+}
+
+impl FeatureReport {
+    pub fn can_read(&self) -> bool {
+        self.unsupported_for_read.is_empty()
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.can_read() && self.unsupported_for_write.is_empty()
+    }
+}
+
+impl fmt::Display for FeatureReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.can_read() {
+            try!(write!(f,
+                        "unsupported features needed for reading: {}",
+                        self.unsupported_for_read.join(", ")));
+            if !self.unsupported_for_write.is_empty() {
+                try!(write!(f, "; "));
+            }
+        }
+        if !self.unsupported_for_write.is_empty() {
+            try!(write!(f,
+                        "read-only because of unsupported features: {}",
+                        self.unsupported_for_write.join(", ")));
+        }
+        if self.can_write() {
+            try!(write!(f, "all active features are supported"));
+        }
+        Ok(())
+    }
+}
+
+/// Every feature enabled on the pool, by guid, from the MOS `features_for_read` and
+/// `features_for_write` ZAPs (with their refcounts) and `feature_enabled_txg`. Pools from
+/// before feature flags have none of them.
+pub fn features(reader: &mut ZfsReader, mos: &ObjectSet) -> Result<Vec<Feature>, String> {
+    let enabled_txgs = try!(feature_zap(reader, mos, "feature_enabled_txg"));
+    let mut features = Vec::new();
+    for &(zap_name, read_only_compatible) in &[("features_for_read", false),
+                                               ("features_for_write", true)] {
+        for (guid, refcount) in try!(feature_zap(reader, mos, zap_name)) {
+            let enabled_txg = enabled_txgs.iter()
+                                          .find(|&&(ref name, _)| *name == guid)
+                                          .map(|&(_, txg)| txg);
+            features.push(Feature {
+                guid: guid,
+                refcount: refcount,
+                read_only_compatible: read_only_compatible,
+                enabled_txg: enabled_txg,
+            });
+        }
+    }
+    features.sort_by(|a, b| a.guid.cmp(&b.guid));
+    Ok(features)
+}
+
+fn feature_zap(reader: &mut ZfsReader,
+               mos: &ObjectSet,
+               zap_name: &str)
+               -> Result<Vec<(String, u64)>, String> {
+    match mos.directory_lookup(reader, zap_name) {
+        Ok(object) => Ok(try!(zap::iter(reader, mos, object)).collect()),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Whether feature `guid` has to be understood to read the pool at all, rather than being read
+/// only compatible, as far as we know it
+pub fn needed_for_read(guid: &str) -> bool {
+    READ_FEATURES.contains(&guid)
+}
+
+/// Enable features `guids` in a transaction of the open txg (`feature_enable_sync`). Each goes
+/// into `features_for_read`, or `features_for_write` if it's read-only compatible, with a
+/// refcount of 0, and into `feature_enabled_txg` with the txg, if the pool keeps track of that
+/// (or starts to with these features). A feature we don't know is `UnsupportedFeature`, there's
+/// no telling which ZAP it belongs in. ZAPs the pool doesn't have yet, e.g. when it's upgraded
+/// from before feature flags, are created; `feature_descriptions` is left alone.
+pub fn enable(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              mos: &ObjectSet,
+              guids: &[&str])
+              -> zfs::Result<()> {
+    let enabled = try!(features(reader, mos).map_err(|_| zfs::Error::Invalid));
+    let mut new: Vec<&str> = Vec::new();
+    for &guid in guids {
+        if !enabled.iter().any(|feature| feature.guid == guid) && !new.contains(&guid) {
+            new.push(guid);
+        }
+    }
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    let mut zaps = vec![("features_for_read", Vec::new()), ("features_for_write", Vec::new())];
+    for &guid in &new {
+        if READ_FEATURES.contains(&guid) {
+            zaps[0].1.push(guid);
+        } else if WRITE_FEATURES.contains(&guid) {
+            zaps[1].1.push(guid);
+        } else {
+            return Err(zfs::Error::UnsupportedFeature);
+        }
+    }
+    if new.contains(&ENABLED_TXG) || enabled.iter().any(|feature| feature.guid == ENABLED_TXG) {
+        zaps.push(("feature_enabled_txg", new.clone()));
+    }
+    zaps.retain(|&(_, ref guids)| !guids.is_empty());
+
+    // The ZAPs the pool has, and the directory entries of those it doesn't
+    let objects: Vec<Option<u64>> = zaps.iter()
+                                        .map(|&(zap_name, _)| {
+                                            mos.directory_lookup(reader, zap_name).ok()
+                                        })
+                                        .collect();
+    let mut tx = Tx::create(MOS_OBJSET);
+    for (&(zap_name, ref guids), object) in zaps.iter().zip(&objects) {
+        match *object {
+            Some(object) => {
+                for &guid in guids {
+                    tx.hold_zap(object, true, Some(guid));
+                }
+            }
+            None => tx.hold_zap(DMU_POOL_DIRECTORY_OBJECT, true, Some(zap_name)),
+        }
+    }
+    let txg = try!(tx.assign(dsl_pool));
+
+    let mut result = Ok(());
+    for (&(zap_name, ref guids), object) in zaps.iter().zip(&objects) {
+        let value = if zap_name == "feature_enabled_txg" { txg } else { 0 };
+        result = match *object {
+            Some(object) => {
+                guids.iter()
+                     .map(|guid| zap::add(reader, dsl_pool, mos, object, guid, value, &tx))
+                     .collect()
+            }
+            None => create_zap(reader, dsl_pool, mos, zap_name, guids, value, &tx),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    tx.commit(dsl_pool);
+    result
+}
+
+/// Create MOS ZAP `zap_name` with `guids`, each with `value`, and add it to the directory
+fn create_zap(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              mos: &ObjectSet,
+              zap_name: &str,
+              guids: &[&str],
+              value: u64,
+              tx: &Tx)
+              -> zfs::Result<()> {
+    let txg = try!(tx.txg().ok_or(zfs::Error::Invalid));
+    let mut taken = DNodePhys::new(ObjectType::None, 512, ObjectType::None, &[]);
+    taken.object_type = DMU_OTN_ZAP_METADATA;
+    let object = try!(dmu::create_object(reader, dsl_pool, mos, taken, tx));
+    let entries: Vec<_> = guids.iter().map(|&guid| (guid, value)).collect();
+    try!(zap::create(dsl_pool, txg, MOS_OBJSET, object, DMU_OTN_ZAP_METADATA, &entries));
+    zap::add(reader, dsl_pool, mos, DMU_POOL_DIRECTORY_OBJECT, zap_name, object, tx)
+}
+
+/// Compare the features active in the pool with the ones we support. A feature is active when
+/// its refcount in the MOS `features_for_read`/`features_for_write` ZAPs is nonzero. Pools from
+/// before feature flags have neither ZAP and everything is supported.
+pub fn check_features(reader: &mut ZfsReader, mos: &ObjectSet) -> Result<FeatureReport, String> {
+    Ok(report(&try!(features(reader, mos))))
+}
+
+fn report(features: &[Feature]) -> FeatureReport {
+    let active = |read_only_compatible: bool| {
+        features.iter()
+                .filter(|feature| feature.read_only_compatible == read_only_compatible)
+                .map(|feature| (feature.guid.clone(), feature.refcount))
+                .collect::<Vec<_>>()
+                .into_iter()
+    };
+    // The features we read but don't write, of either kind, keep the pool read-only
+    let mut unsupported_for_write = unsupported_active(active(true), &WRITE_FEATURES);
+    for guid in unsupported_active(active(false), &[]) {
+        if READ_ONLY_FEATURES.contains(&&guid[..]) {
+            unsupported_for_write.push(guid);
+        }
+    }
+    unsupported_for_write.sort();
+    FeatureReport {
+        unsupported_for_read: unsupported_active(active(false), &READ_FEATURES),
+        unsupported_for_write: unsupported_for_write,
+    }
+}
+
+/// Check the features a label lists as needed to read the pool (its `features_for_read`, those
+/// the MOS itself depends on), before reading the MOS: one we don't understand could have us
+/// make anything of it
+pub fn check_label(features_for_read: &[String]) -> FeatureReport {
+    let listed = features_for_read.iter().map(|guid| (guid.clone(), 1));
+    FeatureReport {
+        unsupported_for_read: unsupported_active(listed, &READ_FEATURES),
+        unsupported_for_write: Vec::new(),
+    }
+}
+
+fn unsupported_active<I>(features: I, supported: &[&str]) -> Vec<String>
+    where I: Iterator<Item = (String, u64)>
+{
+    let mut unsupported: Vec<String> =
+        features.filter(|&(ref guid, refcount)| refcount > 0 && !supported.contains(&&guid[..]))
+                .map(|(guid, _)| guid)
+                .collect();
+    unsupported.sort();
+    unsupported
+}
+
+#[test]
+fn test_feature_report() {
+    let feature = |guid: &str, refcount, read_only_compatible| {
+        Feature {
+            guid: guid.to_owned(),
+            refcount: refcount,
+            read_only_compatible: read_only_compatible,
+            enabled_txg: None,
+        }
+    };
+    let mut features = vec![feature("com.delphix:hole_birth", 1, false),
+                            feature("org.illumos:lz4_compress", 0, false),
+                            feature("com.delphix:redaction_bookmarks", 2, false),
+                            feature("com.delphix:zpool_checkpoint", 1, true),
+                            feature("com.delphix:empty_bpobj", 3, true)];
+
+    // Enabled but never used features don't count
+    let mut report = self::report(&features);
+    assert_eq!(report.unsupported_for_read,
+               vec!["com.delphix:redaction_bookmarks".to_owned()]);
+    assert_eq!(report.unsupported_for_write,
+               vec!["com.delphix:zpool_checkpoint".to_owned()]);
+    assert!(!report.can_read());
+
+    report.unsupported_for_read.clear();
+    assert!(report.can_read());
+    assert!(!report.can_write());
+    assert_eq!(report.to_string(),
+               "read-only because of unsupported features: com.delphix:zpool_checkpoint");
+    assert!(FeatureReport::default().can_write());
+
+    // Encrypted pools can be read, not written
+    features.truncate(2);
+    features.push(feature("com.datto:encryption", 2, false));
+    let report = self::report(&features);
+    assert!(report.can_read() && !report.can_write());
+    assert_eq!(report.unsupported_for_write, vec!["com.datto:encryption".to_owned()]);
+    assert!(features[2].is_supported(false) && !features[2].is_supported(true));
+}
+
+#[test]
+fn test_label_features() {
+    let listed = vec!["com.delphix:embedded_data".to_owned(),
+                      "org.illumos:lz4_compress".to_owned(),
+                      "com.delphix:hole_birth".to_owned()];
+    let report = check_label(&listed);
+    assert_eq!(report.unsupported_for_read, vec!["org.illumos:lz4_compress".to_owned()]);
+    assert!(!report.can_read());
+    assert!(check_label(&[]).can_write());
+
+    let feature = Feature {
+        guid: "com.delphix:zpool_checkpoint".to_owned(),
+        refcount: 0,
+        read_only_compatible: true,
+        enabled_txg: Some(4),
+    };
+    assert!(!feature.is_active());
+    assert!(feature.is_supported(false));
+    assert!(!feature.is_supported(true));
+}
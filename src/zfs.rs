@@ -1,10 +1,158 @@
-use std::result;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{fmt, io, result};
+#[cfg(feature = "std")]
+use std::error;
 
 /// The error type used throughout ZFS
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Error {
     NoEntity,
     Invalid,
+    /// The pool (or device) can only be read
+    ReadOnly,
+    /// The pool uses on-disk features we don't understand
+    UnsupportedFeature,
+    /// Not enough room left, e.g. in the cache for a block
+    NoSpace,
+    /// The device failed a read or write
+    Io(io::ErrorKind),
+    /// A block didn't match its checksum, on any of its copies
+    Checksum,
+    /// A block's data couldn't be decompressed, or uses a compression we don't know
+    Decompress,
+    /// An on-disk structure is truncated or holds values that make no sense
+    Decode(DecodeError),
+    /// The nvlist of a vdev label couldn't be read
+    InvalidLabel,
+    /// None of the uberblocks of the device is valid
+    NoUberblock,
+    /// The pool is imported on another host, which is still writing to it
+    InUse,
+    /// The block is encrypted, and none of the keys loaded decrypts it
+    NoKey,
+    /// The key given doesn't unwrap the dataset's master key
+    WrongKey,
+    /// Something of that name is there already, or is still in the way, like the children of a
+    /// dataset being destroyed
+    Exists,
+    /// The dataset is in use, e.g. it still has snapshots
+    Busy,
+    /// The change would take a dataset over its quota or refquota
+    QuotaExceeded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoEntity => f.write_str("No such entity"),
+            Error::Invalid => f.write_str("Invalid argument"),
+            Error::ReadOnly => f.write_str("Read-only pool"),
+            Error::UnsupportedFeature => f.write_str("Pool uses unsupported features"),
+            Error::NoSpace => f.write_str("No space left"),
+            Error::Io(kind) => write!(f, "IO error: {:?}", kind),
+            Error::Checksum => f.write_str("Checksum mismatch"),
+            Error::Decompress => f.write_str("Decompression failed"),
+            Error::Decode(e) => write!(f, "{}", e),
+            Error::InvalidLabel => f.write_str("Invalid vdev label"),
+            Error::NoUberblock => f.write_str("No valid uberblock"),
+            Error::InUse => f.write_str("Pool is in use by another host"),
+            Error::NoKey => f.write_str("Key not loaded"),
+            Error::WrongKey => f.write_str("Incorrect key"),
+            Error::Exists => f.write_str("File exists"),
+            Error::Busy => f.write_str("Resource busy"),
+            Error::QuotaExceeded => f.write_str("Disk quota exceeded"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e.kind())
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+/// Most of the reading code still reports errors as strings
+impl From<Error> for String {
+    fn from(e: Error) -> Self {
+        e.to_string()
+    }
+}
+
+/// Why bytes couldn't be decoded into an on-disk structure. Used through `from_bytes`, but it's
+/// defined here along with `Error`, which it's part of.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    TooShort { needed: usize, got: usize },
+    BadMagic(u64),
+    BadVersion(u64),
+    BadObjectType(u64),
+    BadChecksum(u64),
+    BadCompression(u64),
+    BadEmbeddedType(u64),
+    /// Logical and physical size in bytes
+    BadSize { lsize: u64, psize: u64 },
+    /// A block written after the block pointer to it was
+    BadBirth { logical: u64, physical: u64 },
+    /// A DVA on a top-level vdev the pool doesn't have
+    BadVdev(u64),
+    /// A DVA past the end of its top-level vdev, as a byte offset and size
+    BadOffset { vdev: u64, offset: u64, asize: u64 },
+}
+
+impl DecodeError {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            DecodeError::TooShort { .. } => "Buffer not long enough.",
+            DecodeError::BadMagic(_) => "Error: Invalid magic number",
+            DecodeError::BadVersion(_) => "Error: Unsupported version",
+            DecodeError::BadObjectType(_) => "Error: Invalid object type",
+            DecodeError::BadChecksum(_) => "Error: Invalid checksum type",
+            DecodeError::BadCompression(_) => "Error: Invalid compression type",
+            DecodeError::BadEmbeddedType(_) => "Error: Invalid embedded block pointer type",
+            DecodeError::BadSize { .. } => "Error: Invalid block size",
+            DecodeError::BadBirth { .. } => "Error: Invalid birth txg",
+            DecodeError::BadVdev(_) => "Error: Invalid vdev",
+            DecodeError::BadOffset { .. } => "Error: Block beyond the end of the vdev",
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::TooShort { needed, got } => {
+                write!(f, "{} bytes needed, {} available", needed, got)
+            }
+            DecodeError::BadMagic(value) |
+            DecodeError::BadVersion(value) |
+            DecodeError::BadObjectType(value) |
+            DecodeError::BadChecksum(value) |
+            DecodeError::BadCompression(value) |
+            DecodeError::BadEmbeddedType(value) |
+            DecodeError::BadVdev(value) => {
+                write!(f, "{} ({:X})", self.description(), value)
+            }
+            DecodeError::BadSize { lsize, psize } => {
+                write!(f, "{} (lsize {:X}, psize {:X})", self.description(), lsize, psize)
+            }
+            DecodeError::BadBirth { logical, physical } => {
+                write!(f, "{} (logical {}, physical {})", self.description(), logical, physical)
+            }
+            DecodeError::BadOffset { vdev, offset, asize } => {
+                write!(f, "{} ({}:{:X}:{:X})", self.description(), vdev, offset, asize)
+            }
+        }
+    }
 }
 
 /// The Result type used throughout ZFS
@@ -0,0 +1,1045 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::checksum;
+use super::dmu_objset::ObjectSet;
+use super::dnode::ObjectType;
+use super::dvaddr::DVAddr;
+use super::from_bytes::{Decoder, FromBytes};
+use super::metaslab::Allocator;
+use super::spa;
+use super::vdev_queue::{QueuedIo, VdevQueue};
+use super::zfs;
+use super::zil_header::ZilHeader;
+use super::zio;
+use super::zio_compress::ZIO_COMPRESS_OFF;
+
+/// Size of the chain trailer (`zil_chain_t`) at the start of every log block: the next block
+/// pointer, the bytes used and the embedded checksum
+pub const ZIL_CHAIN_SIZE: u64 = 184;
+
+/// Log blocks written before the chain moved to their header keep it in a trailer, with an
+/// embedded fletcher 2 checksum (`ZIO_CHECKSUM_ZILOG`). Newer ones use fletcher 4
+/// (`ZIO_CHECKSUM_ZILOG2`).
+const ZIO_CHECKSUM_ZILOG: u64 = 5;
+const ZIO_CHECKSUM_ZILOG2: u64 = 9;
+
+/// Offset of the embedded checksum in the chain, after the padding, the next block pointer and
+/// the bytes used
+pub const ZIL_CHAIN_ECK: usize = 144;
+
+/// The words of a log block's checksum verifier: a random guid of the chain, the objset the log
+/// belongs to, and the block's sequence number (`ZIL_ZC_SEQ`)
+const ZIL_ZC_GUID_0: usize = 0;
+const ZIL_ZC_GUID_1: usize = 1;
+const ZIL_ZC_OBJSET: usize = 2;
+const ZIL_ZC_SEQ: usize = 3;
+
+/// Size of the header common to every log record (`lr_t`)
+pub const LR_SIZE: usize = 32;
+
+/// Size of a write record, not counting the data copied after it (`lr_write_t`)
+pub const LR_WRITE_SIZE: usize = 192;
+
+/// Size of a truncate record (`lr_truncate_t`)
+const LR_TRUNCATE_SIZE: usize = 56;
+
+/// Record types (`lrc_txtype`) we replay
+pub const TX_WRITE: u64 = 9;
+pub const TX_TRUNCATE: u64 = 10;
+/// Block cloning record, whose block pointers are only authenticated in encrypted logs
+pub const TX_CLONE_RANGE: u64 = 26;
+
+/// Set in the type of records of case insensitive operations (`TX_CI`)
+const TX_CI: u64 = 1 << 63;
+
+/// Largest log write block (`zil_maxblocksize`)
+pub const ZIL_MAX_BLOCK_SIZE: u64 = 128 * 1024;
+
+/// Number of recent log block sizes remembered (`ZIL_PREV_BLKS`)
+pub const ZIL_PREV_BLKS: usize = 16;
+
+/// Most data a write record copies into the log, so it fits the largest log block along with
+/// the chain (`zil_max_copied_data`). Longer writes are logged as several records.
+pub const ZIL_MAX_COPIED_DATA: u64 = ZIL_MAX_BLOCK_SIZE - ZIL_CHAIN_SIZE - LR_WRITE_SIZE as u64;
+
+/// Log block sizes for the usual commit sizes, as (commit size limit, block size). A log block
+/// holds the commit's records plus the chain trailer, so e.g. a 128K write needs a bit more than
+/// 128K. Instead of a mostly empty 256K block, that's split into two 68K blocks.
+const ZIL_BLOCK_BUCKETS: &'static [(u64, u64)] = &[(4096, 4096), // non TX_WRITE
+                                                     (8192 + 4096, 8192 + 4096), // database
+                                                     (32768 + 4096, 32768 + 4096), // NFS writes
+                                                     (65536 + 4096, 65536 + 4096), // 64K writes
+                                                     (131072, 131072), // < 128K writes
+                                                     (131072 + 4096, 65536 + 4096), // 128K writes
+                                                     (u64::MAX, 131072) /* > 128K writes */];
+
+/// Picks the size of each new log write block (lwb). Allocating a block per commit that fits it
+/// exactly would mean an allocation (and a device write) for every small commit of a burst, so
+/// the size is the biggest of the recent commits. A single large commit keeps the blocks large for
+/// the next `ZIL_PREV_BLKS` blocks, then they shrink back.
+pub struct LwbSizer {
+    prev_blks: [u64; ZIL_PREV_BLKS],
+    rotor: usize,
+    max_block_size: u64,
+}
+
+impl LwbSizer {
+    pub fn new(max_block_size: u64) -> Self {
+        LwbSizer {
+            prev_blks: [0; ZIL_PREV_BLKS],
+            rotor: 0,
+            max_block_size: max_block_size,
+        }
+    }
+
+    /// The size of the next log block, given that the commit in flight has `cur_used` bytes of
+    /// log records
+    pub fn next_size(&mut self, cur_used: u64) -> u64 {
+        let needed = cur_used + ZIL_CHAIN_SIZE;
+        let &(_, bucket_size) = ZIL_BLOCK_BUCKETS.iter()
+                                                 .find(|&&(limit, _)| needed <= limit)
+                                                 .unwrap();
+        let size = cmp::min(bucket_size, self.max_block_size);
+
+        self.prev_blks[self.rotor] = size;
+        self.rotor = (self.rotor + 1) % ZIL_PREV_BLKS;
+        self.prev_blks.iter().fold(size, |max, &prev| cmp::max(max, prev))
+    }
+}
+
+/// The `logbias` property of a dataset
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogBias {
+    /// Sync writes go to the separate log devices, if the pool has any
+    Latency,
+    /// Sync writes skip the log devices, so they don't become a bottleneck for streaming writes
+    Throughput,
+}
+
+impl LogBias {
+    pub fn from_prop(value: &str) -> Option<Self> {
+        match value {
+            "latency" => Some(LogBias::Latency),
+            "throughput" => Some(LogBias::Throughput),
+            _ => None,
+        }
+    }
+}
+
+/// The metaslab class a log block can be allocated from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AllocClass {
+    Log,
+    Normal,
+}
+
+/// The classes to try, in order, when allocating a log block. Log devices are only used for
+/// latency biased datasets, and when they're full (or the pool has none), the block comes from the
+/// normal class like any other.
+pub fn alloc_classes(logbias: LogBias, has_slog: bool) -> &'static [AllocClass] {
+    if has_slog && logbias == LogBias::Latency {
+        &[AllocClass::Log, AllocClass::Normal]
+    } else {
+        &[AllocClass::Normal]
+    }
+}
+
+/// What a log record does
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogOp {
+    /// `data` was written at `offset` of `object`
+    Write {
+        object: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// `len` bytes at `offset` of `object` were freed. With a `len` of 0, the file was truncated
+    /// to `offset`.
+    Truncate {
+        object: u64,
+        offset: u64,
+        len: u64,
+    },
+    /// Anything else (creates, renames, setattrs, ...), which isn't replayed
+    Other,
+}
+
+/// A record of the intent log: a change committed to the log, but maybe not to the dataset yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub txtype: u64,
+    /// The txg the change went into
+    pub txg: u64,
+    pub seq: u64,
+    pub op: LogOp,
+}
+
+/// Read the log chain of `header`, and the records in it, in the order they were committed.
+/// The chain ends at the first block that can't be read or doesn't pass its checksum: the
+/// checksum verifier of a block has its sequence number, so a stale block left behind where the
+/// next one would have gone doesn't pass. Blocks written since the log was claimed aren't part
+/// of it.
+pub fn read_log(reader: &mut ZfsReader, header: &ZilHeader) -> Vec<LogRecord> {
+    let claim_txg = header.claim_txg();
+    let mut records = Vec::new();
+    let mut block_ptr = header.log();
+    while !block_ptr.is_hole() && !block_ptr.is_embedded() {
+        let verifier = block_ptr.checksum;
+        if claim_txg != 0 &&
+           (block_ptr.birth_txg >= claim_txg || verifier[ZIL_ZC_SEQ] > header.claim_blk_seq()) {
+            break;
+        }
+        let (data, start, end, next) = match read_log_block(reader, &block_ptr) {
+            Some(block) => block,
+            None => break,
+        };
+        let order = block_ptr.data_order();
+        let mut pos = start;
+        while pos + LR_SIZE <= end {
+            let mut decoder = Decoder::new(&data[pos..end], order);
+            let (txtype, reclen) = (decoder.u64(), decoder.u64() as usize);
+            let (txg, seq) = (decoder.u64(), decoder.u64());
+            if reclen < LR_SIZE || pos + reclen > end ||
+               (claim_txg != 0 && seq > header.claim_lr_seq()) {
+                break;
+            }
+            let txtype = txtype & !TX_CI;
+            records.push(LogRecord {
+                txtype: txtype,
+                txg: txg,
+                seq: seq,
+                op: log_op(reader, txtype, &data[pos..pos + reclen], &mut decoder),
+            });
+            pos += reclen;
+        }
+
+        // The next block carries on the sequence, or the chain ends here
+        let mut expected = verifier;
+        expected[ZIL_ZC_SEQ] += 1;
+        let next_verifier = next.checksum;
+        if next_verifier != expected {
+            break;
+        }
+        block_ptr = next;
+    }
+    records
+}
+
+/// The blocks of the chain `header` starts, as far as `read_log` follows it, and the block the
+/// last one read points at: that one was allocated along with it, written or not
+/// (`zil_free_log_block`)
+fn chain_blocks(reader: &mut ZfsReader, header: &ZilHeader) -> Vec<BlockPtr> {
+    let claim_txg = header.claim_txg();
+    let mut blocks = Vec::new();
+    let mut block_ptr = header.log();
+    while !block_ptr.is_hole() && !block_ptr.is_embedded() {
+        let verifier = block_ptr.checksum;
+        if claim_txg != 0 &&
+           (block_ptr.birth_txg >= claim_txg || verifier[ZIL_ZC_SEQ] > header.claim_blk_seq()) {
+            break;
+        }
+        blocks.push(block_ptr);
+        let next = match read_log_block(reader, &block_ptr) {
+            Some((_, _, _, next)) => next,
+            None => break,
+        };
+        let mut expected = verifier;
+        expected[ZIL_ZC_SEQ] += 1;
+        let next_verifier = next.checksum;
+        if next_verifier != expected {
+            break;
+        }
+        block_ptr = next;
+    }
+    blocks
+}
+
+/// Read the log block at `block_ptr`, from the first of its copies that passes its embedded
+/// checksum. Returns the block, where its records start and end, and the next block pointer of
+/// the chain.
+fn read_log_block(reader: &mut ZfsReader,
+                  block_ptr: &BlockPtr)
+                  -> Option<(Vec<u8>, usize, usize, BlockPtr)> {
+    let id = block_ptr.checksum();
+    let size = (block_ptr.psize() * 512) as usize;
+    if (id != ZIO_CHECKSUM_ZILOG && id != ZIO_CHECKSUM_ZILOG2) || size < ZIL_CHAIN_SIZE as usize {
+        return None;
+    }
+    // The chain is at the start of the block, or in the trailer of old blocks
+    let chain = if id == ZIO_CHECKSUM_ZILOG2 { 0 } else { size - ZIL_CHAIN_SIZE as usize };
+    let verifier = block_ptr.checksum;
+    let verify = |data: &[u8]| {
+        checksum::verify_embedded_at(id, data, chain + ZIL_CHAIN_ECK, verifier).is_ok()
+    };
+    let dvas = block_ptr.dvas;
+    let data = match dvas[..block_ptr.max_copies()]
+                         .iter()
+                         .filter(|dva| !dva.is_empty())
+                         .filter_map(|dva| {
+                             reader.zio.read_dva_verified(dva, size as u64, &verify).ok()
+                         })
+                         .next() {
+        Some(data) => data,
+        None => return None,
+    };
+
+    let order = block_ptr.data_order();
+    let next = match BlockPtr::from_bytes_order(&data[chain + 8..], order) {
+        Ok(next) => next,
+        Err(_) => return None,
+    };
+    let nused = Decoder::new(&data[chain + 136..], order).u64() as usize;
+    let (start, end) = if id == ZIO_CHECKSUM_ZILOG2 {
+        (ZIL_CHAIN_SIZE as usize, nused)
+    } else {
+        (0, nused)
+    };
+    if end < start || end > size || (chain > 0 && end > chain) {
+        return None;
+    }
+    Some((data, start, end, next))
+}
+
+/// Decode what the record `record` of type `txtype` does, `decoder` being past its common
+/// header. The data of indirect writes is read from the block the record points at.
+fn log_op(reader: &mut ZfsReader,
+          txtype: u64,
+          record: &[u8],
+          decoder: &mut Decoder)
+          -> LogOp {
+    match txtype {
+        TX_WRITE if record.len() >= LR_WRITE_SIZE => {
+            let (object, offset) = (decoder.u64(), decoder.u64());
+            let (len, blkoff) = (decoder.u64() as usize, decoder.u64() as usize);
+            let block_ptr = BlockPtr::decode(decoder);
+            let data = if record.len() > LR_WRITE_SIZE {
+                // Copied into the log, right after the record
+                if record.len() < LR_WRITE_SIZE + len {
+                    return LogOp::Other;
+                }
+                record[LR_WRITE_SIZE..LR_WRITE_SIZE + len].to_vec()
+            } else if block_ptr.is_hole() {
+                vec![0; len]
+            } else {
+                match reader.read_block(&block_ptr) {
+                    Ok(ref block) if block.len() >= blkoff + len => {
+                        block[blkoff..blkoff + len].to_vec()
+                    }
+                    _ => return LogOp::Other,
+                }
+            };
+            LogOp::Write {
+                object: object,
+                offset: offset,
+                data: data,
+            }
+        }
+        TX_TRUNCATE if record.len() >= LR_TRUNCATE_SIZE => {
+            LogOp::Truncate {
+                object: decoder.u64(),
+                offset: decoder.u64(),
+                len: decoder.u64(),
+            }
+        }
+        _ => LogOp::Other,
+    }
+}
+
+/// The changes the log holds to one object, in order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectLog {
+    ops: Vec<LogOp>,
+}
+
+impl ObjectLog {
+    /// The size of the object once the log is replayed, from `size` on disk
+    pub fn size(&self, size: u64) -> u64 {
+        self.ops.iter().fold(size, |size, op| {
+            match *op {
+                LogOp::Write { offset, ref data, .. } => cmp::max(size, offset + data.len() as u64),
+                LogOp::Truncate { offset, len: 0, .. } => offset,
+                _ => size,
+            }
+        })
+    }
+
+    /// Replay the log onto `data`, the bytes of the object from `offset` on disk
+    pub fn apply(&self, offset: u64, data: &mut [u8]) {
+        let end = offset + data.len() as u64;
+        for op in &self.ops {
+            let (start, stop) = match *op {
+                LogOp::Write { offset: at, data: ref written, .. } => {
+                    (at, at + written.len() as u64)
+                }
+                LogOp::Truncate { offset: at, len: 0, .. } => (at, u64::MAX),
+                LogOp::Truncate { offset: at, len, .. } => (at, at.saturating_add(len)),
+                LogOp::Other => continue,
+            };
+            let (from, to) = (cmp::max(start, offset), cmp::min(stop, end));
+            if from >= to {
+                continue;
+            }
+            let range = (from - offset) as usize..(to - offset) as usize;
+            match *op {
+                LogOp::Write { data: ref written, .. } => {
+                    let skip = (from - start) as usize;
+                    data[range.clone()].copy_from_slice(&written[skip..skip + range.len()]);
+                }
+                _ => {
+                    for byte in &mut data[range] {
+                        *byte = 0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What replaying the intent log of a dataset changes, on top of what's on disk: the writes and
+/// truncates committed to the log but not synced yet, when the pool wasn't exported cleanly.
+/// The other records aren't replayed, so the files written have to exist on disk already.
+#[derive(Clone, Debug, Default)]
+pub struct Replay {
+    objects: BTreeMap<u64, ObjectLog>,
+    /// Records replayed
+    pub replayed: usize,
+    /// Outstanding records that couldn't be replayed
+    pub skipped: usize,
+}
+
+impl Replay {
+    /// Replay `records` of a log whose records up to `replay_seq` were replayed already, onto an
+    /// objset synced up to `synced_txg`
+    pub fn new(records: Vec<LogRecord>, replay_seq: u64, synced_txg: u64) -> Self {
+        let mut replay = Replay::default();
+        for record in records {
+            if record.seq <= replay_seq || record.txg <= synced_txg {
+                continue;
+            }
+            let object = match record.op {
+                LogOp::Write { object, .. } |
+                LogOp::Truncate { object, .. } => object,
+                LogOp::Other => {
+                    replay.skipped += 1;
+                    continue;
+                }
+            };
+            replay.objects.entry(object).or_insert_with(ObjectLog::default).ops.push(record.op);
+            replay.replayed += 1;
+        }
+        replay
+    }
+
+    /// Read and replay the log of `objset`, synced up to `synced_txg`
+    pub fn open(reader: &mut ZfsReader, objset: &ObjectSet, synced_txg: u64) -> Self {
+        let header = &objset.phys.zil_header;
+        if header.log().is_hole() {
+            return Replay::default();
+        }
+        Replay::new(read_log(reader, header), header.replay_seq(), synced_txg)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The replayed changes to `object`, if any
+    pub fn object(&self, object: u64) -> Option<&ObjectLog> {
+        self.objects.get(&object)
+    }
+}
+
+/// A change logged but not committed yet (`itx_t`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Itx {
+    pub txg: u64,
+    pub op: LogOp,
+    /// Set for writes logged `WR_NEED_COPY`: their data isn't copied when they're logged, the
+    /// `len` bytes written are read back from the file when the record is committed. Writes
+    /// logged `WR_COPIED` have their data in `op` already.
+    pub need_copy: Option<u64>,
+}
+
+/// A log block written and still needed (`lwb_t`)
+struct Lwb {
+    block_ptr: BlockPtr,
+    class: AllocClass,
+    /// The newest txg of the records in it: the block is obsolete once that's synced
+    max_txg: u64,
+}
+
+/// The writing side of the intent log of an objset (`zilog_t`). Changes are logged as they're
+/// made (`log_write`, `log_truncate`) and kept in memory until `commit` writes them out to a
+/// chain of log blocks, so they survive a crash before their txg is synced. Each log block
+/// points at the next one, allocated along with it, and the header in the objset at the oldest
+/// block still needed: the header moves past blocks once the txgs of all their records are
+/// synced (`sync`). A chain of a log that was there before is dropped on the first commit, its
+/// blocks freed in the txg that writes the header of the new one.
+pub struct Zilog {
+    objset: u64,
+    pub logbias: LogBias,
+    /// The header to write out with the next sync, if it's changed
+    header: Option<ZilHeader>,
+    /// Log blocks written and not obsolete yet, oldest first
+    lwbs: VecDeque<Lwb>,
+    /// The block the next commit writes, the end of the chain, and where it was allocated. A
+    /// hole until the chain is started.
+    next: BlockPtr,
+    next_class: AllocClass,
+    /// Blocks of the log devices synced out and not given back yet, with their txg: only a
+    /// commit has the allocator of the log devices
+    slog_freed: Vec<(DVAddr, u64)>,
+    /// Blocks of the chain the new one replaces, freed along with the new header
+    replaced: Vec<(DVAddr, AllocClass)>,
+    /// Commits can't be found from the header on disk until this txg or a later one is synced:
+    /// the chain was started since (0), or one of its blocks couldn't be written
+    unlinked_txg: Option<u64>,
+    itxs: Vec<Itx>,
+    /// Sequence number of the last record committed
+    lr_seq: u64,
+    sizer: LwbSizer,
+}
+
+impl Zilog {
+    /// The log of objset `objset`, by the MOS object of its dataset
+    pub fn new(objset: u64) -> Self {
+        Zilog {
+            objset: objset,
+            logbias: LogBias::Latency,
+            header: None,
+            lwbs: VecDeque::new(),
+            next: BlockPtr::hole(),
+            next_class: AllocClass::Normal,
+            slog_freed: Vec::new(),
+            replaced: Vec::new(),
+            unlinked_txg: None,
+            itxs: Vec::new(),
+            lr_seq: 0,
+            sizer: LwbSizer::new(ZIL_MAX_BLOCK_SIZE),
+        }
+    }
+
+    /// Log `data` written at `offset` of `object` in txg `txg` (`zfs_log_write`). The data of a
+    /// synchronous write is copied into the record right away (`WR_COPIED`), the others are
+    /// read back from the file if they're ever committed (`WR_NEED_COPY`).
+    pub fn log_write(&mut self, txg: u64, object: u64, offset: u64, data: &[u8], sync: bool) {
+        for (i, chunk) in data.chunks(ZIL_MAX_COPIED_DATA as usize).enumerate() {
+            self.itxs.push(Itx {
+                txg: txg,
+                op: LogOp::Write {
+                    object: object,
+                    offset: offset + i as u64 * ZIL_MAX_COPIED_DATA,
+                    data: if sync { chunk.to_vec() } else { Vec::new() },
+                },
+                need_copy: if sync { None } else { Some(chunk.len() as u64) },
+            });
+        }
+    }
+
+    /// Log `len` bytes at `offset` of `object` freed in txg `txg`, or with a `len` of 0, the
+    /// object truncated to `offset` (`zfs_log_truncate`)
+    pub fn log_truncate(&mut self, txg: u64, object: u64, offset: u64, len: u64) {
+        self.itxs.push(Itx {
+            txg: txg,
+            op: LogOp::Truncate {
+                object: object,
+                offset: offset,
+                len: len,
+            },
+            need_copy: None,
+        });
+    }
+
+    /// The changes logged and not committed yet, in order
+    pub fn itxs(&self) -> &[Itx] {
+        &self.itxs
+    }
+
+    /// Write what's been logged to the chain of log blocks, at `Priority::LogWrite`, and flush
+    /// the devices (`zil_commit`). `header` is the one on disk, whose chain a new chain replaces.
+    /// `txg` is the open txg, the blocks are born in it. They're
+    /// allocated from `slog`, the log devices, or from `alloc` (see `alloc_classes`). The data
+    /// of `WR_NEED_COPY` writes is read with `read`, given the object, offset and length: a write
+    /// to an object that's gone by now isn't committed. Returns whether the records committed
+    /// can be found from the header on disk: a new chain is only once the txg with its header
+    /// is synced, like `zil_create` waits for.
+    pub fn commit(&mut self,
+                  reader: &mut ZfsReader,
+                  header: &ZilHeader,
+                  txg: u64,
+                  alloc: &mut Allocator,
+                  mut slog: Option<&mut Allocator>,
+                  read: &mut FnMut(&mut ZfsReader, u64, u64, u64) -> zfs::Result<Vec<u8>>)
+                  -> zfs::Result<bool> {
+        if let Some(ref mut slog) = slog {
+            for (dva, freed_txg) in self.slog_freed.drain(..) {
+                slog.free(&dva, freed_txg);
+            }
+        }
+
+        let mut records = Vec::with_capacity(self.itxs.len());
+        for itx in &self.itxs {
+            let op = match (&itx.op, itx.need_copy) {
+                (&LogOp::Write { object, offset, .. }, Some(len)) => {
+                    match read(reader, object, offset, len) {
+                        Ok(data) => {
+                            LogOp::Write {
+                                object: object,
+                                offset: offset,
+                                data: data,
+                            }
+                        }
+                        Err(zfs::Error::NoEntity) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                (op, _) => op.clone(),
+            };
+            let seq = self.lr_seq + records.len() as u64 + 1;
+            records.push((itx.txg, encode_record(itx.txg, seq, &op)));
+        }
+        self.itxs.clear();
+        self.lr_seq += records.len() as u64;
+        if records.is_empty() {
+            return Ok(self.unlinked_txg.is_none());
+        }
+
+        if self.next.is_hole() {
+            let used: u64 = records.iter().map(|&(_, ref record)| record.len() as u64).sum();
+            let size = self.sizer.next_size(used);
+            let mut verifier = [0; 4];
+            verifier[ZIL_ZC_GUID_0] = spa::generate_guid();
+            verifier[ZIL_ZC_GUID_1] = spa::generate_guid();
+            verifier[ZIL_ZC_OBJSET] = self.objset;
+            verifier[ZIL_ZC_SEQ] = 1;
+            let (next, class) = try!(self.alloc_block(size, txg, verifier, alloc, &mut slog));
+            self.next = next;
+            self.next_class = class;
+            self.header = Some(ZilHeader::new(next));
+            self.unlinked_txg = Some(0);
+            for block_ptr in chain_blocks(reader, header) {
+                let dvas = block_ptr.dvas;
+                for dva in dvas[..block_ptr.max_copies()].iter().filter(|dva| !dva.is_empty()) {
+                    let on_slog = slog.as_ref().map_or(false, |slog| slog.owns(dva));
+                    let class = if on_slog { AllocClass::Log } else { AllocClass::Normal };
+                    self.replaced.push((*dva, class));
+                }
+            }
+        }
+
+        // Fill the blocks in chain order, each allocating the next one to point at
+        let mut queue = VdevQueue::new();
+        let mut pos = 0;
+        let mut commit_txg = 0;
+        while pos < records.len() {
+            let block_ptr = self.next;
+            let size = (block_ptr.psize() * 512) as usize;
+            let mut block = vec![0; size];
+            let mut used = ZIL_CHAIN_SIZE as usize;
+            let mut max_txg = 0;
+            while pos < records.len() && used + records[pos].1.len() <= size {
+                let (record_txg, ref record) = records[pos];
+                block[used..used + record.len()].copy_from_slice(record);
+                used += record.len();
+                max_txg = cmp::max(max_txg, record_txg);
+                pos += 1;
+            }
+            commit_txg = cmp::max(commit_txg, max_txg);
+
+            // A block allocated smaller than the record that comes next goes out empty
+            let left: u64 = records[pos..].iter().map(|&(_, ref record)| record.len() as u64).sum();
+            let first = records.get(pos).map_or(0, |&(_, ref record)| {
+                (record.len() as u64 + ZIL_CHAIN_SIZE + 511) / 512 * 512
+            });
+            let next_size = cmp::max(self.sizer.next_size(left), first);
+            let mut verifier = block_ptr.checksum;
+            verifier[ZIL_ZC_SEQ] += 1;
+            let (next, class) = try!(self.alloc_block(next_size, txg, verifier, alloc, &mut slog));
+            block[8..136].copy_from_slice(next.as_bytes());
+            block[136..144].copy_from_slice(&(used as u64).to_ne_bytes());
+            try!(checksum::embed_at(ZIO_CHECKSUM_ZILOG2,
+                                    &mut block,
+                                    ZIL_CHAIN_ECK,
+                                    block_ptr.checksum)
+                     .map_err(|_| zfs::Error::Invalid));
+
+            let dva = block_ptr.dvas[0];
+            queue.add(QueuedIo {
+                offset: dva.offset() << zio::SPA_MINBLOCKSHIFT,
+                size: size as u64,
+                priority: zio::Priority::LogWrite,
+                timestamp: self.lwbs.len() as u64,
+                flags: 0,
+                io: (dva, block),
+            });
+            self.lwbs.push_back(Lwb {
+                block_ptr: block_ptr,
+                class: self.next_class,
+                max_txg: max_txg,
+            });
+            self.next = next;
+            self.next_class = class;
+        }
+
+        // The chain ends for readers at a block that didn't make it, until it's obsolete
+        let written = issue_log_writes(&reader.zio, &mut queue).and_then(|_| reader.zio.flush());
+        if let Err(e) = written {
+            self.unlinked_txg = Some(cmp::max(self.unlinked_txg.unwrap_or(0), commit_txg));
+            return Err(zfs::Error::from(e));
+        }
+        Ok(self.unlinked_txg.is_none())
+    }
+
+    /// Allocate a log block of `size` bytes with checksum verifier `verifier`, from the first
+    /// class of `alloc_classes` that has room
+    fn alloc_block(&self,
+                   size: u64,
+                   txg: u64,
+                   verifier: [u64; 4],
+                   alloc: &mut Allocator,
+                   slog: &mut Option<&mut Allocator>)
+                   -> zfs::Result<(BlockPtr, AllocClass)> {
+        let mut result = Err(zfs::Error::NoSpace);
+        for &class in alloc_classes(self.logbias, slog.is_some()) {
+            let dva = match class {
+                AllocClass::Log => slog.as_mut().unwrap().alloc(size),
+                AllocClass::Normal => alloc.alloc(size),
+            };
+            result = dva.map(|dva| (dva, class));
+            if result.is_ok() {
+                break;
+            }
+        }
+        let (dva, class) = try!(result);
+
+        let sectors = size / 512;
+        let mut block_ptr = BlockPtr::hole();
+        block_ptr.dvas[0] = dva;
+        block_ptr.flags_size = checksum::host_byte_order() << 63 |
+                               (ObjectType::IntentLog as u64) << 48 |
+                               ZIO_CHECKSUM_ZILOG2 << 40 |
+                               ZIO_COMPRESS_OFF << 32 | (sectors - 1) << 16 |
+                               (sectors - 1);
+        block_ptr.birth_txg = txg;
+        block_ptr.checksum = verifier;
+        Ok((block_ptr, class))
+    }
+
+    /// Txg `txg` is being synced (`zil_sync`): what was logged in it is dropped, and the blocks
+    /// it makes obsolete are freed to `alloc`, the replaced chain's with the first header of the
+    /// new one. Returns the header to write to the objset in the txg, if it's changed.
+    pub fn sync(&mut self, txg: u64, alloc: &mut Allocator) -> Option<ZilHeader> {
+        self.itxs.retain(|itx| itx.txg > txg);
+        if self.unlinked_txg.map_or(false, |unlinked| unlinked <= txg) {
+            self.unlinked_txg = None;
+        }
+
+        let mut moved = false;
+        while self.lwbs.front().map_or(false, |lwb| lwb.max_txg <= txg) {
+            let lwb = self.lwbs.pop_front().unwrap();
+            let dva = lwb.block_ptr.dvas[0];
+            match lwb.class {
+                AllocClass::Log => self.slog_freed.push((dva, txg)),
+                AllocClass::Normal => alloc.free(&dva, txg),
+            }
+            moved = true;
+        }
+        if moved {
+            let head = self.lwbs.front().map_or(self.next, |lwb| lwb.block_ptr);
+            self.header = Some(ZilHeader::new(head));
+        }
+        if self.header.is_some() {
+            for (dva, class) in self.replaced.drain(..) {
+                match class {
+                    AllocClass::Log => self.slog_freed.push((dva, txg)),
+                    AllocClass::Normal => alloc.free(&dva, txg),
+                }
+            }
+        }
+        self.header.take()
+    }
+}
+
+/// Issue the log block writes of `queue`, in the order they were queued
+fn issue_log_writes(zio: &zio::Reader,
+                    queue: &mut VdevQueue<(DVAddr, Vec<u8>)>)
+                    -> ::std::io::Result<()> {
+    while let Some(io) = queue.issue(0) {
+        try!(zio.write_dva(&io.io.0, &io.io.1));
+        queue.done(&io);
+    }
+    Ok(())
+}
+
+/// The record of `op`, logged in txg `txg` with sequence number `seq`, as it's written to a log
+/// block: writes have their data copied after them
+fn encode_record(txg: u64, seq: u64, op: &LogOp) -> Vec<u8> {
+    let (txtype, body, data): (u64, Vec<u64>, &[u8]) = match *op {
+        LogOp::Write { object, offset, ref data } => {
+            // Then the block offset and a hole for a block pointer, the data isn't indirect
+            let mut body = vec![object, offset, data.len() as u64, 0];
+            body.extend_from_slice(&[0; 16]);
+            (TX_WRITE, body, data)
+        }
+        LogOp::Truncate { object, offset, len } => (TX_TRUNCATE, vec![object, offset, len], &[]),
+        LogOp::Other => panic!("only writes and truncates are logged"),
+    };
+    let reclen = (LR_SIZE + body.len() * 8 + data.len() + 7) / 8 * 8;
+    let mut record = Vec::with_capacity(reclen);
+    for word in [txtype, reclen as u64, txg, seq].iter().chain(&body) {
+        record.extend_from_slice(&word.to_ne_bytes());
+    }
+    record.extend_from_slice(data);
+    record.resize(reclen, 0);
+    record
+}
+
+#[test]
+fn test_lwb_sizes() {
+    let mut sizer = LwbSizer::new(ZIL_MAX_BLOCK_SIZE);
+    assert_eq!(sizer.next_size(100), 4096);
+    assert_eq!(sizer.next_size(8192), 8192 + 4096);
+    // A 128K write doesn't quite fit a 128K block with the trailer, two smaller blocks are used
+    assert_eq!(sizer.next_size(131072 - 100), 65536 + 4096);
+    // The bigger block sticks around for the following small commits...
+    for _ in 0..ZIL_PREV_BLKS - 1 {
+        assert_eq!(sizer.next_size(100), 65536 + 4096);
+    }
+    // ...until it drops out of the history
+    assert_eq!(sizer.next_size(100), 4096);
+
+    assert_eq!(sizer.next_size(1 << 20), ZIL_MAX_BLOCK_SIZE);
+    assert_eq!(LwbSizer::new(32768).next_size(65536), 32768);
+
+    assert_eq!(alloc_classes(LogBias::Latency, true),
+               &[AllocClass::Log, AllocClass::Normal]);
+    assert_eq!(alloc_classes(LogBias::Throughput, true), &[AllocClass::Normal]);
+    assert_eq!(alloc_classes(LogBias::Latency, false), &[AllocClass::Normal]);
+    assert_eq!(LogBias::from_prop("throughput"), Some(LogBias::Throughput));
+}
+
+#[test]
+fn test_replay() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::dvaddr::DVAddr;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    // 4K log blocks, the one with sequence number `seq` at sector `seq * 8`
+    let log_bp = |seq: u64| {
+        let mut block_ptr = BlockPtr::hole();
+        block_ptr.dvas[0] = DVAddr {
+            vdev: 8,
+            offset: seq * 8,
+        };
+        block_ptr.flags_size = checksum::host_byte_order() << 63 | ZIO_CHECKSUM_ZILOG2 << 40 |
+                               2 << 32 | 7 << 16 | 7;
+        block_ptr.birth_txg = 10;
+        block_ptr.checksum = [0x1234, 0x5678, 21, seq];
+        block_ptr
+    };
+    let record = |txtype: u64, txg: u64, seq: u64, body: &[u64], data: &[u8]| {
+        let mut record = Vec::new();
+        let reclen = (LR_SIZE + body.len() * 8 + data.len() + 7) / 8 * 8;
+        for word in [txtype, reclen as u64, txg, seq].iter().chain(body) {
+            record.extend_from_slice(&word.to_le_bytes());
+        }
+        record.extend_from_slice(data);
+        record.resize(reclen, 0);
+        record
+    };
+    let write = |txg, seq, object, offset, data: &[u8]| {
+        let mut body = vec![object, offset, data.len() as u64, 0];
+        body.extend_from_slice(&[0; 16]); // a hole, the data is copied
+        record(TX_WRITE, txg, seq, &body, data)
+    };
+    let mut write_block = |seq: u64, next: &BlockPtr, records: &[Vec<u8>]| {
+        let mut block = vec![0; 4096];
+        block[8..136].copy_from_slice(next.as_bytes());
+        let mut pos = ZIL_CHAIN_SIZE as usize;
+        for record in records {
+            block[pos..pos + record.len()].copy_from_slice(record);
+            pos += record.len();
+        }
+        block[136..144].copy_from_slice(&(pos as u64).to_le_bytes());
+        let block_ptr = log_bp(seq);
+        let verifier = block_ptr.checksum;
+        checksum::embed_at(ZIO_CHECKSUM_ZILOG2, &mut block, ZIL_CHAIN_ECK, verifier).unwrap();
+        reader.zio.write_dva(&block_ptr.dvas[0], &block).unwrap();
+    };
+
+    write_block(1,
+                &log_bp(2),
+                &[write(11, 1, 5, 10, b"hello"),
+                  record(11, 12, 2, &[4, 1, 0o644, 0, 0, 0, 0, 0, 0, 0], b""), // TX_SETATTR
+                  write(12, 3, 5, 8, b"abcd"),
+                  record(TX_TRUNCATE, 12, 4, &[5, 11, 2], b"")]);
+    write_block(2,
+                &log_bp(3),
+                &[write(12, 5, 6, 0, b"other"), record(TX_TRUNCATE, 13, 6, &[5, 9, 0], b"")]);
+    // A stale block where the third one would be: its sequence number doesn't match
+    write_block(4, &BlockPtr::hole(), &[write(13, 7, 5, 0, b"stale")]);
+    let stale = reader.zio.read_dva(&log_bp(4).dvas[0], 4096).unwrap();
+    reader.zio.write_dva(&log_bp(3).dvas[0], &stale).unwrap();
+
+    let mut header = vec![0; 192];
+    header[16..144].copy_from_slice(log_bp(1).as_bytes());
+    let header = ZilHeader::from_bytes(&header).unwrap();
+    let records = read_log(&mut reader, &header);
+    let seqs: Vec<u64> = records.iter().map(|record| record.seq).collect();
+    assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(records[1].op, LogOp::Other);
+    assert_eq!(records[3].op,
+               LogOp::Truncate {
+                   object: 5,
+                   offset: 11,
+                   len: 2,
+               });
+
+    // Txg 11 was synced already
+    let replay = Replay::new(records, 0, 11);
+    assert_eq!((replay.replayed, replay.skipped), (4, 1));
+    let log = replay.object(5).unwrap();
+    let mut data = vec![b'.'; 16];
+    log.apply(0, &mut data);
+    assert_eq!(&data[..], b"........a\0\0\0\0\0\0\0");
+    assert_eq!((log.size(100), log.size(4)), (9, 9));
+    let mut data = vec![b'.'; 4];
+    log.apply(6, &mut data);
+    assert_eq!(&data[..], b"..a\0");
+    assert_eq!(replay.object(6).unwrap().size(0), 5);
+    assert!(replay.object(7).is_none());
+}
+
+#[test]
+fn test_commit() {
+    use super::arcache::ArCache;
+    use super::dcache::DentryCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+
+    let (disk, log_disk) = (MemVdev::new(vec![0; 8 << 20]), MemVdev::new(vec![0; 4 << 20]));
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(disk.clone()),
+        arc: ArCache::new(),
+        dcache: DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    reader.zio.tops.insert(0, Box::new(disk));
+    reader.zio.tops.insert(1, Box::new(log_disk));
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let file: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+    let mut read = |_: &mut ZfsReader, object: u64, offset: u64, len: u64| {
+        if object != 5 {
+            return Err(zfs::Error::NoEntity);
+        }
+        Ok(file[offset as usize..(offset + len) as usize].to_vec())
+    };
+    let write = |object: u64, offset: u64, data: &[u8]| {
+        LogOp::Write {
+            object: object,
+            offset: offset,
+            data: data.to_vec(),
+        }
+    };
+
+    // Synchronous writes are copied as they're logged, the others read back when committed. A
+    // write to a file that's gone since isn't committed.
+    let mut zilog = Zilog::new(54);
+    zilog.log_write(10, 5, 0, b"sync", true);
+    zilog.log_write(10, 5, 100, &[0xff; 50], false);
+    zilog.log_write(11, 6, 0, b"gone", false);
+    zilog.log_truncate(11, 5, 120, 0);
+    assert_eq!((zilog.itxs()[0].need_copy, zilog.itxs()[1].need_copy), (None, Some(50)));
+    // The new chain can't be found until its header is synced
+    let none = ZilHeader::new(BlockPtr::hole());
+    assert!(!zilog.commit(&mut reader, &none, 11, &mut alloc, None, &mut read).unwrap());
+    assert!(zilog.itxs().is_empty());
+    let header = zilog.sync(9, &mut alloc).unwrap();
+    assert!(zilog.sync(9, &mut alloc).is_none());
+    let records = read_log(&mut reader, &header);
+    assert_eq!(records.iter().map(|record| (record.txg, record.seq)).collect::<Vec<_>>(),
+               vec![(10, 1), (10, 2), (11, 3)]);
+    assert_eq!(records[0].op, write(5, 0, b"sync"));
+    assert_eq!(records[1].op, write(5, 100, &file[100..150]));
+    assert_eq!(records[2].op,
+               LogOp::Truncate {
+                   object: 5,
+                   offset: 120,
+                   len: 0,
+               });
+
+    // A long write is split into records that fit the largest block. The small block allocated
+    // last time goes out empty.
+    zilog.log_write(12, 5, 0, &file, true);
+    assert_eq!(zilog.itxs().len(), 2);
+    assert!(zilog.commit(&mut reader, &none, 12, &mut alloc, None, &mut read).unwrap());
+    assert_eq!(zilog.lwbs.len(), 4);
+    let records = read_log(&mut reader, &header);
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[3].op, write(5, 0, &file[..ZIL_MAX_COPIED_DATA as usize]));
+    assert_eq!(records[4].op,
+               write(5, ZIL_MAX_COPIED_DATA, &file[ZIL_MAX_COPIED_DATA as usize..]));
+
+    // Syncing a txg moves the header past the blocks it makes obsolete
+    let header = zilog.sync(11, &mut alloc).unwrap();
+    let seqs: Vec<u64> = read_log(&mut reader, &header).iter().map(|record| record.seq).collect();
+    assert_eq!(seqs, vec![4, 5]);
+    let header = zilog.sync(12, &mut alloc).unwrap();
+    assert!(read_log(&mut reader, &header).is_empty());
+    assert!(zilog.lwbs.is_empty());
+
+    // The next blocks come from the log devices, unless the log is biased towards throughput
+    let mut slog = VdevAllocator::new(1, 9, 2 << 20, None);
+    zilog.log_write(13, 5, 0, b"slog", true);
+    assert!(zilog.commit(&mut reader, &none, 13, &mut alloc, Some(&mut slog), &mut read).unwrap());
+    assert_eq!(zilog.next.dvas[0].vdev_id(), 1);
+    zilog.logbias = LogBias::Throughput;
+    zilog.log_write(13, 5, 4, b"normal", true);
+    assert!(zilog.commit(&mut reader, &none, 13, &mut alloc, Some(&mut slog), &mut read).unwrap());
+    assert_eq!(zilog.next.dvas[0].vdev_id(), 0);
+    let ops: Vec<LogOp> = read_log(&mut reader, &header).into_iter().map(|r| r.op).collect();
+    assert_eq!(ops, vec![write(5, 0, b"slog"), write(5, 4, b"normal")]);
+    // The log device's block is given back to it on the next commit
+    assert!(slog.free_space() < 2 << 20);
+    let header = zilog.sync(13, &mut alloc).unwrap();
+    assert_eq!(zilog.slog_freed.len(), 1);
+    assert!(zilog.commit(&mut reader, &none, 14, &mut alloc, Some(&mut slog), &mut read).unwrap());
+    assert!(zilog.slog_freed.is_empty());
+    slog.sync_done(13 + super::txg::DEFER_SIZE as u64);
+    assert_eq!(slog.free_space(), 2 << 20);
+
+    // The log as it's found on the next import, with a block more than the header says
+    zilog.log_write(14, 5, 0, b"old", true);
+    assert!(zilog.commit(&mut reader, &none, 14, &mut alloc, None, &mut read).unwrap());
+    assert_eq!(read_log(&mut reader, &header).len(), 1);
+
+    // A new log starts a chain of its own, from the log device. The blocks of the one on disk
+    // are freed with the header that replaces it, the block allocated after the last one too.
+    let mut zilog = Zilog::new(54);
+    zilog.log_write(15, 5, 0, b"new", true);
+    let allocated = alloc.allocated;
+    assert!(!zilog.commit(&mut reader, &header, 15, &mut alloc, Some(&mut slog), &mut read)
+                  .unwrap());
+    assert_eq!(zilog.replaced.len(), 2);
+    assert_eq!(alloc.allocated, allocated);
+    let header = zilog.sync(14, &mut alloc).unwrap();
+    assert!(zilog.replaced.is_empty());
+    assert!(alloc.allocated < allocated);
+    let ops: Vec<LogOp> = read_log(&mut reader, &header).into_iter().map(|r| r.op).collect();
+    assert_eq!(ops, vec![write(5, 0, b"new")]);
+}
@@ -1,8 +1,74 @@
 use super::block_ptr::BlockPtr;
+use super::from_bytes::{Decoder, FromBytes};
 
+#[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct ZilHeader {
-    claim_txg: u64,
-    replay_seq: u64,
-    log: BlockPtr,
+    claim_txg: u64, // txg in which log blocks were claimed
+    replay_seq: u64, // highest replayed sequence number
+    log: BlockPtr, // log chain
+    claim_blk_seq: u64, // highest claimed block sequence number
+    flags: u64, // header flags
+    claim_lr_seq: u64, // highest claimed lr sequence number
+    pad: [u64; 3],
+}
+
+impl FromBytes for ZilHeader {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let claim_txg = decoder.u64();
+        let replay_seq = decoder.u64();
+        let log = BlockPtr::decode(decoder);
+        let claim_blk_seq = decoder.u64();
+        let flags = decoder.u64();
+        let claim_lr_seq = decoder.u64();
+        let mut pad = [0; 3];
+        decoder.fill_u64(&mut pad);
+        ZilHeader {
+            claim_txg: claim_txg,
+            replay_seq: replay_seq,
+            log: log,
+            claim_blk_seq: claim_blk_seq,
+            flags: flags,
+            claim_lr_seq: claim_lr_seq,
+            pad: pad,
+        }
+    }
+}
+
+impl ZilHeader {
+    /// The header of a new log chain starting at `log`, which hasn't been claimed or replayed
+    pub fn new(log: BlockPtr) -> Self {
+        ZilHeader {
+            claim_txg: 0,
+            replay_seq: 0,
+            log: log,
+            claim_blk_seq: 0,
+            flags: 0,
+            claim_lr_seq: 0,
+            pad: [0; 3],
+        }
+    }
+
+    /// The txg the log blocks were claimed in at import, 0 if they haven't been
+    pub fn claim_txg(&self) -> u64 {
+        self.claim_txg
+    }
+
+    /// The sequence number of the last record replayed
+    pub fn replay_seq(&self) -> u64 {
+        self.replay_seq
+    }
+
+    /// The first block of the log chain, a hole if the log is empty
+    pub fn log(&self) -> BlockPtr {
+        self.log
+    }
+
+    pub fn claim_blk_seq(&self) -> u64 {
+        self.claim_blk_seq
+    }
+
+    pub fn claim_lr_seq(&self) -> u64 {
+        self.claim_lr_seq
+    }
 }
@@ -1,102 +1,1200 @@
-use std::{mem, ptr};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, mem, slice, thread};
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use super::avl;
-use super::block_ptr::BlockPtr;
+use super::block_ptr::{BP_EMBEDDED_TYPE_DATA, BlockPtr, GANG_HEADER_SIZE, Gang};
+use super::checksum;
 use super::dvaddr::DVAddr;
-use super::from_bytes::FromBytes;
-use super::lzjb;
+use super::from_bytes::{DecodeError, FromBytes};
+use super::kstat;
+use super::spa_config;
 use super::uberblock::Uberblock;
+use super::util;
+use super::vdev::{self, VdevLabel};
+use super::vdev_indirect::IndirectMapping;
+use super::vdev_io::{self, MappedBlock, Vdev};
+use super::vdev_mirror::ChildErrors;
 use super::zfs;
+use super::zio_compress::{self, Codec};
+use super::zio_crypt::CryptoKey;
 
 pub const NUM_TYPES: usize = 6;
 pub const NUM_TASKQ_TYPES: usize = 4;
 
+/// Addresses above the device are in units of 512 byte sectors, whatever the device's logical
+/// sector size
+pub const SPA_MINBLOCKSHIFT: u64 = 9;
+
+/// How long a pool with `failmode=wait` waits before trying a failed read again
+const FAILMODE_RETRY_INTERVAL_MS: u64 = 100;
+
+/// How a read that failed with a device error is retried before its copy is given up on. The
+/// delay before each retry doubles, up to `max_delay_ms`; a random part of it keeps the reads of
+/// many threads from all coming back at once.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries of a copy, none to give up right away
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay_ms: 10,
+            max_delay_ms: 1000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry `retry` (from 0): half of the backoff, plus a random part of
+    /// the other half
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = cmp::min(self.base_delay_ms.checked_shl(retry).unwrap_or(u64::max_value()),
+                               self.max_delay_ms);
+        Duration::from_millis(backoff - backoff / 2 + random() % (backoff / 2 + 1))
+    }
+}
+
+/// Whether a read that failed with `kind` may work when tried again: EIO, EAGAIN, interrupted and
+/// timed out reads may, reads past the end of the device or of a vdev that's gone won't
+fn retryable(kind: io::ErrorKind) -> bool {
+    match kind {
+        io::ErrorKind::UnexpectedEof |
+        io::ErrorKind::NotFound |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::InvalidInput |
+        io::ErrorKind::PermissionDenied |
+        io::ErrorKind::Unsupported => false,
+        _ => true,
+    }
+}
+
+/// A random number, for jitter
+fn random() -> u64 {
+    // Every RandomState is seeded differently
+    let mut hasher = RandomState::new().build_hasher();
+    SystemTime::now().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The retries of reads from one top-level vdev
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RetryStats {
+    /// Reads tried again after a device error
+    pub retries: u64,
+    /// Reads that worked on a retry
+    pub recovered: u64,
+    /// Reads that still failed after the last retry
+    pub exhausted: u64,
+}
+
+/// The health of a top-level vdev, see `Reader::vdev_status`
+#[derive(Clone, Debug, PartialEq)]
+pub struct VdevStatus {
+    pub id: u64,
+    pub state: vdev::State,
+    /// Errors of the reads and writes of the vdev itself: those its children's failover
+    /// didn't make up for
+    pub errors: ChildErrors,
+    /// The state of each of its children and the errors they ran into
+    pub children: Vec<(vdev::State, ChildErrors)>,
+}
+
+/// Size of the pad at the start of each label that probes read and write: blank space nothing
+/// else uses
+const PROBE_SIZE: usize = 8 * 1024;
+
+/// Most bytes `Reader::read_many` reads in one go, like `zfs_vdev_aggregation_limit`
+pub const READ_COALESCE_MAX: u64 = 128 << 10;
+
+/// Most writes a reader keeps track of for the ARC (see `Reader::take_writes`). Past that, all
+/// of every device counts as written.
+pub const WRITE_LOG_MAX: usize = 1024;
+
+/// A byte range of a device that's been written to, for caches to drop what they have of it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Written {
+    /// The top-level vdev written to, None for all of them: a reader of a single device doesn't
+    /// tell them apart, and the labels' device may be any of them
+    pub top: Option<u64>,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// What to do when a block can't be read from any of its copies (the `failmode` pool property)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FailMode {
+    /// Suspend IO and keep retrying until the devices are back. The default for pools.
+    Wait,
+    /// Return the error to whoever asked for the block
+    Continue,
+    /// Abort, rather than go on with a pool that's lost data
+    Panic,
+}
+
+impl FailMode {
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(FailMode::Wait),
+            1 => Some(FailMode::Continue),
+            2 => Some(FailMode::Panic),
+            _ => None,
+        }
+    }
+
+    pub fn from_prop(value: &str) -> Option<Self> {
+        match value {
+            "wait" => Some(FailMode::Wait),
+            "continue" => Some(FailMode::Continue),
+            "panic" => Some(FailMode::Panic),
+            _ => None,
+        }
+    }
+}
+
+/// Reads and writes the pool's devices. All IO goes through `&self`, a reader can be shared
+/// between threads (in an `Arc`) and they all read at the same time.
 pub struct Reader {
-    pub disk: File,
+    /// The device the labels and uberblocks are read from
+    pub disk: Box<Vdev>,
+    /// The pool's top-level vdevs by ID, which is where DVAs point. Pools of a single top-level
+    /// vdev leave it empty, their blocks are all on `disk`.
+    pub tops: BTreeMap<u64, Box<Vdev>>,
+    /// The mappings of the top-level vdevs removed from the pool, by ID. Their blocks are read
+    /// from where the removal copied them to.
+    pub indirect: BTreeMap<u64, Arc<IndirectMapping>>,
+    /// Logical sector size of the device as a shift. IO to the device is aligned to it.
+    pub sector_shift: u64,
+    /// The pool's allocation shift (the vdev's `ashift`), as recorded in the label. Allocations
+    /// are rounded up to it, and so are the slots of the uberblock ring.
+    pub ashift: u64,
+    /// Number of reads issued to the disk
+    pub reads: AtomicU64,
+    /// Number of bytes read from the disk
+    pub read_bytes: AtomicU64,
+    /// Total time spent in disk reads, in nanoseconds
+    pub read_time_ns: AtomicU64,
+    /// Blocks that had to be read from another copy than the first
+    pub ditto_failovers: AtomicU64,
+    /// Reads that failed on every copy of the block
+    pub io_failures: AtomicU64,
+    /// Blocks read along with the block before them, in one read (see `read_many`)
+    pub coalesced: AtomicU64,
+    /// What to do about those. A bare reader gives up, pools set it from their property.
+    pub failmode: FailMode,
+    /// Whether IO is suspended, waiting for the devices to come back (see `FailMode::Wait`)
+    pub suspended: AtomicBool,
+    /// Whether probes write the label pads back after reading them, as they do for pools opened
+    /// for writing. A device that can still be read but no longer written is faulted then.
+    pub probe_writes: bool,
+    /// Probes of a vdev after a read from it failed
+    pub probes: AtomicU64,
+    /// Failed reads after which the probe found the vdev fine, so the failure was a passing one
+    pub transient_errors: AtomicU64,
+    /// The top-level vdevs whose probe failed. Reads skip them until they pass a probe again.
+    faulted: Mutex<BTreeSet<u64>>,
+    /// How reads that failed with a device error are retried
+    pub retry_policy: RetryPolicy,
+    /// The retries of each top-level vdev that had any
+    retry_stats: Mutex<BTreeMap<u64, RetryStats>>,
+    /// The errors reads and writes of each top-level vdev ran into, after whatever failover its
+    /// children do
+    errors: Mutex<BTreeMap<u64, ChildErrors>>,
+    /// The writes since the last `take_writes`
+    writes: Mutex<Vec<Written>>,
+    writes_pending: AtomicBool,
+    /// Bumped at every txg sync, see `generation`
+    generation: AtomicU64,
+    /// The newest txg known to be on the disk, see `observe_txg`
+    synced_txg: AtomicU64,
+    /// The keys of the encrypted datasets loaded so far. Encrypted blocks are decrypted with
+    /// whichever of them their MAC says they were encrypted with.
+    pub keys: Vec<CryptoKey>,
 }
 
 impl Reader {
-    // TODO: Error handling
-    pub fn read(&mut self, start: usize, length: usize) -> Vec<u8> {
-        let mut ret: Vec<u8> = vec![0; length*512];
+    pub fn new<D: Vdev + 'static>(disk: D) -> Self {
+        Reader::boxed(Box::new(disk))
+    }
+
+    pub fn boxed(disk: Box<Vdev>) -> Self {
+        Reader {
+            disk: disk,
+            tops: BTreeMap::new(),
+            indirect: BTreeMap::new(),
+            sector_shift: SPA_MINBLOCKSHIFT,
+            ashift: SPA_MINBLOCKSHIFT,
+            reads: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            read_time_ns: AtomicU64::new(0),
+            ditto_failovers: AtomicU64::new(0),
+            io_failures: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            failmode: FailMode::Continue,
+            suspended: AtomicBool::new(false),
+            probe_writes: false,
+            probes: AtomicU64::new(0),
+            transient_errors: AtomicU64::new(0),
+            faulted: Mutex::new(BTreeSet::new()),
+            retry_policy: RetryPolicy::default(),
+            retry_stats: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            writes: Mutex::new(Vec::new()),
+            writes_pending: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            synced_txg: AtomicU64::new(0),
+            keys: Vec::new(),
+        }
+    }
 
-        self.disk.seek(SeekFrom::Start(start as u64 * 512));
-        self.disk.read(&mut ret);
+    /// Another reader of the same devices, e.g. to read from another thread. It starts with
+    /// statistics of its own.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let mut reader = Reader::boxed(try!(self.disk.try_clone()));
+        for (&id, top) in &self.tops {
+            reader.tops.insert(id, try!(top.try_clone()));
+        }
+        reader.indirect = self.indirect.clone();
+        reader.sector_shift = self.sector_shift;
+        reader.ashift = self.ashift;
+        reader.failmode = self.failmode;
+        reader.probe_writes = self.probe_writes;
+        reader.retry_policy = self.retry_policy;
+        reader.generation.store(self.generation(), Ordering::Relaxed);
+        reader.synced_txg.store(self.synced_txg.load(Ordering::Acquire), Ordering::Relaxed);
+        reader.keys = self.keys.clone();
+        *reader.faulted.lock().unwrap() = self.faulted.lock().unwrap().clone();
+        Ok(reader)
+    }
 
-        ret
+    pub fn read(&self, start: usize, length: usize) -> io::Result<Vec<u8>> {
+        self.read_at((start as u64) << SPA_MINBLOCKSHIFT,
+                     length << SPA_MINBLOCKSHIFT)
     }
 
-    pub fn write(&mut self, block: usize, data: &[u8; 512]) {
-        self.disk.seek(SeekFrom::Start(block as u64 * 512));
-        self.disk.write(data);
+    /// Read `len` bytes at byte `offset`, widening the IO to whole device sectors. Reading past
+    /// the end of the device is an error, like any other failed read.
+    pub fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let sector_size = 1 << self.sector_shift;
+        let start = util::p2_align(offset, sector_size);
+        let end = util::p2_round_up(offset + cmp::max(len as u64, 1), sector_size);
+        let mut ret: Vec<u8> = vec![0; (end - start) as usize];
+
+        let begin = Instant::now();
+        let read = vdev_io::read_exact_at(&*self.disk, start, &mut ret);
+        self.account_read(begin, ret.len());
+        try!(read);
+
+        let skip = (offset - start) as usize;
+        ret.drain(..skip);
+        ret.truncate(len);
+        Ok(ret)
     }
 
-    pub fn read_dva(&mut self, dva: &DVAddr) -> Vec<u8> {
-        self.read(dva.sector() as usize, dva.asize() as usize)
+    /// Fill `buf` from the device, or from top-level vdev `top`, starting at byte `start`.
+    /// Returns how many bytes there were before the end of the device, the rest of `buf` is
+    /// left alone.
+    fn read_sectors(&self,
+                    top: Option<u64>,
+                    start: u64,
+                    buf: &mut [u8])
+                    -> io::Result<usize> {
+        let begin = Instant::now();
+        let read = try!(vdev_io::read_full(try!(self.device(top)), start, buf));
+        self.account_read(begin, read);
+        Ok(read)
     }
 
-    pub fn read_block(&mut self, block_ptr: &BlockPtr) -> Result<Vec<u8>, &'static str> {
-        let data = self.read_dva(&block_ptr.dvas[0]);
-        match block_ptr.compression() {
-            2 => {
-                // compression off
-                Ok(data)
+    /// The device DVAs on top-level vdev `top` point into, or the labels' device for `None`
+    fn device(&self, top: Option<u64>) -> io::Result<&Vdev> {
+        match top {
+            Some(id) if !self.tops.is_empty() => {
+                self.tops.get(&id).map(|top| &**top).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("no top-level vdev {}", id))
+                })
             }
-            1 | 3 => {
-                // lzjb compression
-                let mut decompressed = vec![0; (block_ptr.lsize()*512) as usize];
-                lzjb::LzjbDecoder::new(&data).read(&mut decompressed);
-                Ok(decompressed)
+            _ => Ok(&*self.disk),
+        }
+    }
+
+    /// Probe top-level vdev `top`, to tell a device that's gone from one that failed a single
+    /// read: read the pad of each of its labels, and write it back if `probe_writes` is set. The
+    /// vdev passes if that works for any label. One that doesn't is faulted, one that does is
+    /// healthy again. Returns whether it passed.
+    pub fn probe(&self, top: u64) -> bool {
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        let healthy = match self.device(Some(top)) {
+            Ok(device) => probe_device(device, self.probe_writes),
+            Err(_) => false,
+        };
+        let mut faulted = self.faulted.lock().unwrap();
+        if healthy {
+            faulted.remove(&top);
+        } else {
+            faulted.insert(top);
+        }
+        healthy
+    }
+
+    /// Probe the faulted vdevs again, bringing back those that pass. Returns how many are still
+    /// faulted.
+    pub fn reprobe(&self) -> usize {
+        let faulted = self.faulted();
+        faulted.iter().filter(|&&top| !self.probe(top)).count()
+    }
+
+    /// The top-level vdevs that are faulted
+    pub fn faulted(&self) -> Vec<u64> {
+        self.faulted.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// State of top-level vdev `top`, as far as reads found out: faulted or healthy
+    pub fn vdev_state(&self, top: u64) -> vdev::State {
+        if self.faulted.lock().unwrap().contains(&top) {
+            vdev::State::Faulted
+        } else {
+            vdev::State::Healthy
+        }
+    }
+
+    /// The retries of reads from top-level vdev `top`
+    pub fn retry_stats(&self, top: u64) -> RetryStats {
+        self.retry_stats.lock().unwrap().get(&top).cloned().unwrap_or_default()
+    }
+
+    fn count_retry<F: FnOnce(&mut RetryStats)>(&self, top: u64, count: F) {
+        count(self.retry_stats.lock().unwrap().entry(top).or_insert_with(RetryStats::default));
+    }
+
+    /// The errors reads and writes of top-level vdev `top` ran into
+    pub fn vdev_errors(&self, top: u64) -> ChildErrors {
+        self.errors.lock().unwrap().get(&top).cloned().unwrap_or_default()
+    }
+
+    fn count_error<F: FnOnce(&mut ChildErrors)>(&self, top: u64, count: F) {
+        count(self.errors.lock().unwrap().entry(top).or_insert_with(ChildErrors::default));
+    }
+
+    /// The health of top-level vdev `top` and its children, like `zpool status` shows it.
+    /// Faulted if it failed a probe, otherwise in the state its children leave it in, or its
+    /// own errors if that's worse.
+    pub fn vdev_status(&self, top: u64) -> VdevStatus {
+        let errors = self.vdev_errors(top);
+        let (state, children) = match self.device(Some(top)) {
+            Ok(device) => (device.state(), device.child_status()),
+            Err(_) => (vdev::State::CannotOpen, Vec::new()),
+        };
+        let state = if self.vdev_state(top) == vdev::State::Faulted ||
+                       errors.state() == vdev::State::Faulted {
+            vdev::State::Faulted
+        } else if state == vdev::State::Healthy {
+            errors.state()
+        } else {
+            state
+        };
+        VdevStatus {
+            id: top,
+            state: state,
+            errors: errors,
+            children: children,
+        }
+    }
+
+    /// `vdev_status` of every top-level vdev
+    pub fn vdevs_status(&self) -> Vec<VdevStatus> {
+        if self.tops.is_empty() {
+            return vec![self.vdev_status(0)];
+        }
+        self.tops.keys().map(|&top| self.vdev_status(top)).collect()
+    }
+
+    /// Let the top-level vdevs know how far the raidz expansion in progress, if any, has got
+    pub fn raidz_reflow(&self, offset: u64) {
+        self.disk.raidz_reflow(offset);
+        for top in self.tops.values() {
+            top.raidz_reflow(offset);
+        }
+    }
+
+    /// Count a read of `bytes` bytes started at `begin` in the statistics
+    fn account_read(&self, begin: Instant, bytes: usize) {
+        let elapsed = begin.elapsed();
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.read_time_ns.fetch_add(elapsed.as_secs() * 1_000_000_000 +
+                                    elapsed.subsec_nanos() as u64,
+                                    Ordering::Relaxed);
+    }
+
+    /// Add this reader's statistics to `kstats`
+    pub fn kstats(&self, kstats: &mut kstat::Registry) {
+        kstats.counter("zfs_zio_reads_total",
+                       "Reads issued to the disk",
+                       self.reads.load(Ordering::Relaxed));
+        kstats.counter("zfs_zio_read_bytes_total",
+                       "Bytes read from the disk",
+                       self.read_bytes.load(Ordering::Relaxed));
+        kstats.add("zfs_zio_read_seconds_total",
+                   "Time spent waiting for disk reads",
+                   kstat::KstatKind::Counter,
+                   Vec::new(),
+                   self.read_time_ns.load(Ordering::Relaxed) as f64 / 1e9);
+        kstats.counter("zfs_zio_ditto_failovers_total",
+                       "Blocks read from another copy after the first one failed",
+                       self.ditto_failovers.load(Ordering::Relaxed));
+        kstats.counter("zfs_zio_io_failures_total",
+                       "Reads that failed on every copy of the block",
+                       self.io_failures.load(Ordering::Relaxed));
+        kstats.counter("zfs_zio_coalesced_total",
+                       "Blocks read in one read with the block before them",
+                       self.coalesced.load(Ordering::Relaxed));
+        kstats.gauge("zfs_zio_suspended",
+                     "Whether IO is suspended until the devices come back",
+                     self.suspended.load(Ordering::Relaxed) as u64);
+        kstats.counter("zfs_zio_probes_total",
+                       "Probes of a vdev after a read from it failed",
+                       self.probes.load(Ordering::Relaxed));
+        kstats.counter("zfs_zio_transient_errors_total",
+                       "Failed reads of vdevs that passed the probe after",
+                       self.transient_errors.load(Ordering::Relaxed));
+        kstats.gauge("zfs_vdev_faulted",
+                     "Top-level vdevs faulted after failing a probe",
+                     self.faulted.lock().unwrap().len() as u64);
+        for (top, stats) in self.retry_stats.lock().unwrap().iter() {
+            let labels = vec![("vdev".to_owned(), top.to_string())];
+            kstats.add("zfs_vdev_read_retries_total",
+                       "Reads tried again after a device error",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       stats.retries as f64);
+            kstats.add("zfs_vdev_read_retries_recovered_total",
+                       "Reads that worked on a retry",
+                       kstat::KstatKind::Counter,
+                       labels.clone(),
+                       stats.recovered as f64);
+            kstats.add("zfs_vdev_read_retries_exhausted_total",
+                       "Reads that still failed after the last retry",
+                       kstat::KstatKind::Counter,
+                       labels,
+                       stats.exhausted as f64);
+        }
+        self.disk.kstats(kstats);
+        for top in self.tops.values() {
+            top.kstats(kstats);
+        }
+    }
+
+    /// Apply the failure mode to a block none of whose copies could be read: give up on the block
+    /// (`Err`), or wait a bit for the devices to come back and then try it again (`Ok`)
+    pub fn io_failure(&self, error: zfs::Error) -> zfs::Result<()> {
+        self.io_failures.fetch_add(1, Ordering::Relaxed);
+        match self.failmode {
+            FailMode::Continue => Err(error),
+            FailMode::Wait => {
+                self.suspended.store(true, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(FAILMODE_RETRY_INTERVAL_MS));
+                Ok(())
             }
-            _ => Err("Error: not enough bytes"),
+            FailMode::Panic => panic!("Pool IO failure with failmode=panic: {}", error),
+        }
+    }
+
+    pub fn write(&self, block: usize, data: &[u8; 512]) -> io::Result<()> {
+        self.write_at((block as u64) << SPA_MINBLOCKSHIFT, data)
+    }
+
+    /// Write `data` starting at sector `start`. `data` is padded with zeros to a whole sector.
+    pub fn write_sectors(&self, start: usize, data: &[u8]) -> io::Result<()> {
+        self.write_padded(None, start as u64, data)
+    }
+
+    /// Write `data` to sector `start` of the device, or of top-level vdev `top`, padded with
+    /// zeros to a whole sector
+    fn write_padded(&self, top: Option<u64>, start: u64, data: &[u8]) -> io::Result<()> {
+        let mut padded = data.to_vec();
+        let len = util::p2_round_up(cmp::max(data.len() as u64, 1), 1 << SPA_MINBLOCKSHIFT);
+        padded.resize(len as usize, 0);
+        self.write_device(top, start << SPA_MINBLOCKSHIFT, &padded)
+    }
+
+    /// Write `data` at byte `offset`. Device sectors only partially covered by `data` are read
+    /// first so their other bytes are kept.
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.write_device(None, offset, data)
+    }
+
+    fn write_device(&self, top: Option<u64>, offset: u64, data: &[u8]) -> io::Result<()> {
+        let written = self.write_sectors_of(top, offset, data);
+        if let (Some(top), true) = (top, written.is_err()) {
+            self.count_error(top, |errors| errors.write += 1);
+        }
+        written
+    }
+
+    fn write_sectors_of(&self, top: Option<u64>, offset: u64, data: &[u8]) -> io::Result<()> {
+        let sector_size = 1 << self.sector_shift;
+        let end = offset + data.len() as u64;
+        self.log_write(top, offset, end);
+        if util::is_p2_aligned(offset, sector_size) && util::is_p2_aligned(end, sector_size) {
+            return try!(self.device(top)).write_at(offset, data);
+        }
+
+        let start = util::p2_align(offset, sector_size);
+        let len = (util::p2_round_up(end, sector_size) - start) as usize;
+        // Sectors past the end of an image file are about to be written, they count as zeros
+        let mut sectors = vec![0; len];
+        try!(self.read_sectors(top, start, &mut sectors));
+        let skip = (offset - start) as usize;
+        sectors[skip..skip + data.len()].copy_from_slice(data);
+        try!(self.device(top)).write_at(start, &sectors)
+    }
+
+    /// Remember that bytes `start` to `end` of the device of `top` are being written. A failed
+    /// write counts too, it may have made it in part.
+    fn log_write(&self, top: Option<u64>, start: u64, end: u64) {
+        let written = Written {
+            top: if self.tops.is_empty() { None } else { top },
+            start: start,
+            end: end,
+        };
+        let mut writes = self.writes.lock().unwrap();
+        if writes.len() < WRITE_LOG_MAX {
+            writes.push(written);
+        } else if writes.len() == WRITE_LOG_MAX {
+            writes.push(Written {
+                top: None,
+                start: 0,
+                end: u64::max_value(),
+            });
         }
+        self.writes_pending.store(true, Ordering::Release);
+    }
+
+    /// The byte ranges of the devices written through this reader since the last call, so the
+    /// ARC can drop the blocks it has of them. Writes through other handles of the devices
+    /// (`try_clone`) aren't included.
+    pub fn take_writes(&self) -> Vec<Written> {
+        if !self.writes_pending.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        let mut writes = self.writes.lock().unwrap();
+        self.writes_pending.store(false, Ordering::Release);
+        mem::replace(&mut *writes, Vec::new())
+    }
+
+    /// The pool's generation: it goes up with every txg seen to be synced, through this reader or
+    /// by another writer (see `observe_txg`). What's been read from the pool at one generation
+    /// may be out of date at the next, so caches that hold on to more than a block (an objset, a
+    /// dataset's phys) keep the generation they read it at and look again once it moves.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Txg `txg` is on the disk. If it's newer than any seen before, a new generation starts.
+    /// Returns whether it was.
+    pub fn observe_txg(&self, txg: u64) -> bool {
+        if self.synced_txg.fetch_max(txg, Ordering::AcqRel) >= txg {
+            return false;
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        true
+    }
+
+    /// Make the writes so far to every device durable, before any write that comes after
+    pub fn flush(&self) -> io::Result<()> {
+        try!(self.disk.flush());
+        for top in self.tops.values() {
+            try!(top.flush());
+        }
+        Ok(())
+    }
+
+    /// Discard the `size` bytes at `offset` of top-level vdev `top`, an offset past the labels
+    /// like a DVA's (see `Vdev::discard`). Nothing can be cached of them: a freed DVA is only read
+    /// again once it's been written.
+    pub fn discard(&self, top: u64, offset: u64, size: u64) -> io::Result<()> {
+        try!(self.device(Some(top))).discard(offset + spa_config::VDEV_LABEL_START_SIZE, size)
+    }
+
+    /// Take the ashift recorded in the device's labels, and work out the logical sector size of
+    /// the device from it and the device's size. All further IO is aligned to the sector size.
+    pub fn detect_sector_shift(&mut self) -> u64 {
+        let device_size = self.disk.size().unwrap_or(0);
+        let ashift = (0..VDEV_LABELS)
+                         .filter_map(|label| spa_config::read_label_config(self, label).ok())
+                         .next()
+                         .and_then(|config| config.vdev_tree.ashift);
+        self.ashift = ashift.unwrap_or(SPA_MINBLOCKSHIFT);
+        self.sector_shift = vdev::logical_sector_shift(device_size, ashift);
+        self.sector_shift
+    }
+
+    /// The space a block of `psize` bytes takes up on the device, in bytes
+    pub fn asize(&self, psize: u64) -> u64 {
+        util::p2_round_up(psize, 1 << self.ashift)
+    }
+
+    /// Size of an uberblock ring slot as a shift: big enough for an uberblock and a whole
+    /// allocation unit, so every slot can be written on its own
+    fn uberblock_shift(&self) -> u64 {
+        vdev::uberblock_shift(self.ashift)
+    }
+
+    /// Write `data` to the sectors of `dva`. `data` is padded with zeros to a whole sector.
+    pub fn write_dva(&self, dva: &DVAddr, data: &[u8]) -> io::Result<()> {
+        self.write_padded(Some(dva.vdev_id()), dva.sector(), data)
+    }
+
+    /// Read the `size` bytes of the block at `dva`, from the top-level vdev it's on: its psize,
+    /// or the size of a gang header. Blocks are read whole, since on raidz where their data is
+    /// depends on their size.
+    pub fn read_dva(&self, dva: &DVAddr, size: u64) -> io::Result<Vec<u8>> {
+        self.read_dva_sectors(dva, size, &|_| true).map(|(data, _)| data)
+    }
+
+    /// Like `read_dva`, but making sure the data passes `verify`. On a mirror, a child whose
+    /// copy doesn't pass is skipped for the next one; on raidz, the data is reconstructed from
+    /// parity.
+    pub fn read_dva_verified(&self,
+                             dva: &DVAddr,
+                             size: u64,
+                             verify: &Fn(&[u8]) -> bool)
+                             -> zfs::Result<Vec<u8>> {
+        match try!(self.read_dva_sectors(dva, size, verify)) {
+            (data, true) => Ok(data),
+            (_, false) => Err(zfs::Error::Checksum),
+        }
+    }
+
+    /// The `size` bytes of the block at `dva` borrowed from the mapping of its device rather than
+    /// read, if the device lends them out (see `Vdev::mapped`). None for any other device, or a
+    /// block that isn't in one piece on it, which is read with `read_dva` instead.
+    pub fn mapped_dva(&self, dva: &DVAddr, size: u64) -> Option<MappedBlock> {
+        if self.indirect.contains_key(&dva.vdev_id()) {
+            return None;
+        }
+        match self.device(Some(dva.vdev_id())) {
+            Ok(device) if device.is_linear() => {
+                device.mapped(dva.sector() << SPA_MINBLOCKSHIFT, size as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Rewrite the block at `dva`, `size` bytes of it, onto child `child` of its top-level vdev
+    /// from the other children, checking it passes `verify` (see `Vdev::resilver`). Fails with
+    /// `Checksum` if the other children don't have it right.
+    pub fn resilver_dva(&self,
+                        dva: &DVAddr,
+                        size: u64,
+                        child: usize,
+                        verify: &Fn(&[u8]) -> bool)
+                        -> zfs::Result<()> {
+        let offset = dva.sector() << SPA_MINBLOCKSHIFT;
+        let len = size as usize;
+        let sector_size = 1 << self.sector_shift;
+        let start = util::p2_align(offset, sector_size);
+        let end = util::p2_round_up(offset + cmp::max(len as u64, 1), sector_size);
+        let skip = (offset - start) as usize;
+        let mut sectors: Vec<u8> = vec![0; (end - start) as usize];
+
+        let device = try!(self.device(Some(dva.vdev_id())));
+        let resilvered = try!(device.resilver(start, &mut sectors, child, &|sectors| {
+            verify(&sectors[skip..skip + len])
+        }));
+        if resilvered {
+            Ok(())
+        } else {
+            Err(zfs::Error::Checksum)
+        }
+    }
+
+    fn read_dva_sectors(&self,
+                        dva: &DVAddr,
+                        size: u64,
+                        verify: &Fn(&[u8]) -> bool)
+                        -> io::Result<(Vec<u8>, bool)> {
+        if let Some(mapping) = self.indirect.get(&dva.vdev_id()) {
+            return self.read_remapped(mapping, dva, size, verify);
+        }
+        let offset = dva.sector() << SPA_MINBLOCKSHIFT;
+        let len = size as usize;
+        let sector_size = 1 << self.sector_shift;
+        let start = util::p2_align(offset, sector_size);
+        let end = util::p2_round_up(offset + cmp::max(len as u64, 1), sector_size);
+        let skip = (offset - start) as usize;
+        let mut ret: Vec<u8> = vec![0; (end - start) as usize];
+
+        let begin = Instant::now();
+        let verified = try!(self.device(Some(dva.vdev_id())))
+                           .read_verified(start, &mut ret, &|sectors| {
+                               verify(&sectors[skip..skip + len])
+                           });
+        self.account_read(begin, ret.len());
+        let verified = try!(verified);
+
+        ret.drain(..skip);
+        ret.truncate(len);
+        Ok((ret, verified))
+    }
+
+    /// Read the block at `dva` of a removed vdev from where it was copied to, which may be split
+    /// over several places, or even a vdev that was removed in turn. It's verified as a whole.
+    fn read_remapped(&self,
+                     mapping: &IndirectMapping,
+                     dva: &DVAddr,
+                     size: u64,
+                     verify: &Fn(&[u8]) -> bool)
+                     -> io::Result<(Vec<u8>, bool)> {
+        let offset = dva.offset() << SPA_MINBLOCKSHIFT;
+        let pieces = try!(mapping.remap(offset, util::p2_round_up(size, 1 << SPA_MINBLOCKSHIFT))
+                                 .ok_or_else(|| {
+                                     io::Error::new(io::ErrorKind::NotFound,
+                                                    format!("no mapping of vdev {} at {}",
+                                                            dva.vdev_id(),
+                                                            offset))
+                                 }));
+        let mut data = Vec::with_capacity(size as usize);
+        for (_, piece) in pieces {
+            data.extend(try!(self.read_dva(&piece, piece.asize() << SPA_MINBLOCKSHIFT)));
+        }
+        data.truncate(size as usize);
+        let verified = verify(&data);
+        Ok((data, verified))
+    }
+
+    /// Read the blocks at `dvas`: the `asize` sectors of each, or the header of a gang block.
+    /// Blocks next to each other on the same top-level vdev are read in one device read, of up
+    /// to `READ_COALESCE_MAX` bytes, which is sliced up again. Returns the data of each block, in
+    /// the order of `dvas`. Nothing is verified, that's up to the caller.
+    pub fn read_many(&self, dvas: &[DVAddr]) -> io::Result<Vec<Vec<u8>>> {
+        let size = |dva: &DVAddr| if dva.gang() {
+            GANG_HEADER_SIZE as u64
+        } else {
+            dva.asize() << SPA_MINBLOCKSHIFT
+        };
+        let mut order: Vec<usize> = (0..dvas.len()).collect();
+        order.sort_by_key(|&i| (dvas[i].vdev_id(), dvas[i].sector()));
+
+        let mut blocks = vec![Vec::new(); dvas.len()];
+        let mut i = 0;
+        while i < order.len() {
+            let first = dvas[order[i]];
+            if self.indirect.contains_key(&first.vdev_id()) {
+                blocks[order[i]] = try!(self.read_dva(&first, size(&first)));
+                i += 1;
+                continue;
+            }
+            let device = try!(self.device(Some(first.vdev_id())));
+            if !device.is_linear() {
+                blocks[order[i]] = try!(self.read_dva(&first, size(&first)));
+                i += 1;
+                continue;
+            }
+
+            // The run of blocks that start where the one before them ends
+            let start = first.sector() << SPA_MINBLOCKSHIFT;
+            let mut end = start + size(&first);
+            let mut run = i + 1;
+            while let Some(&next) = order.get(run) {
+                let dva = dvas[next];
+                let dva_start = dva.sector() << SPA_MINBLOCKSHIFT;
+                if dva.vdev_id() != first.vdev_id() || dva_start != end ||
+                   dva_start + size(&dva) - start > READ_COALESCE_MAX {
+                    break;
+                }
+                end = dva_start + size(&dva);
+                run += 1;
+            }
+
+            // The blocks are read straight into buffers of their own, with one vectored read,
+            // the rest of the sectors at either end into scratch ones
+            let sector_size = 1 << self.sector_shift;
+            let aligned = util::p2_align(start, sector_size);
+            let aligned_end = util::p2_round_up(end, sector_size);
+            let mut head = vec![0; (start - aligned) as usize];
+            let mut tail = vec![0; (aligned_end - end) as usize];
+            let mut bufs: Vec<Vec<u8>> = order[i..run]
+                                             .iter()
+                                             .map(|&block| vec![0; size(&dvas[block]) as usize])
+                                             .collect();
+            let begin = Instant::now();
+            let read = {
+                let mut extents: Vec<(u64, &mut [u8])> = Vec::with_capacity(bufs.len() + 2);
+                extents.push((aligned, &mut head[..]));
+                let mut offset = start;
+                for buf in &mut bufs {
+                    let len = buf.len() as u64;
+                    extents.push((offset, &mut buf[..]));
+                    offset += len;
+                }
+                extents.push((end, &mut tail[..]));
+                vdev_io::read_extents_exact(device, &mut extents)
+            };
+            self.account_read(begin, (aligned_end - aligned) as usize);
+            try!(read);
+            self.coalesced.fetch_add((run - i - 1) as u64, Ordering::Relaxed);
+
+            for (&block, buf) in order[i..run].iter().zip(bufs) {
+                blocks[block] = buf;
+            }
+            i = run;
+        }
+        Ok(blocks)
+    }
+
+    /// Check the block pointer makes sense before anything is read through it: its fields (see
+    /// `BlockPtr::validate`), and that each of its DVAs is on a top-level vdev there is, within
+    /// its bounds. The bounds of vdevs that spread blocks over their children aren't checked.
+    pub fn verify_block_ptr(&self, block_ptr: &BlockPtr) -> zfs::Result<()> {
+        try!(block_ptr.validate());
+        if block_ptr.is_embedded() {
+            return Ok(());
+        }
+        let dvas = block_ptr.dvas;
+        for dva in dvas[..block_ptr.max_copies()].iter().filter(|dva| !dva.is_empty()) {
+            // Where the blocks of a removed vdev are is checked as they're remapped
+            if self.indirect.contains_key(&dva.vdev_id()) {
+                continue;
+            }
+            let device = match self.device(Some(dva.vdev_id())) {
+                Ok(device) => device,
+                Err(_) => return Err(zfs::Error::Decode(DecodeError::BadVdev(dva.vdev_id()))),
+            };
+            if !device.is_linear() {
+                continue;
+            }
+            // A vdev whose size can't be had fails the read soon enough
+            let size = match device.size() {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            let asize = if dva.gang() {
+                GANG_HEADER_SIZE as u64
+            } else {
+                dva.asize() << SPA_MINBLOCKSHIFT
+            };
+            if (dva.sector() << SPA_MINBLOCKSHIFT) + asize > size {
+                return Err(zfs::Error::Decode(DecodeError::BadOffset {
+                    vdev: dva.vdev_id(),
+                    offset: dva.offset() << SPA_MINBLOCKSHIFT,
+                    asize: asize,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&self, block_ptr: &BlockPtr) -> zfs::Result<Vec<u8>> {
+        self.read_block_copy(block_ptr).map(|(data, _)| data)
+    }
+
+    /// Read a block, from the first of its copies (DVAs) that passes checksum verification.
+    /// Returns the data along with the index of the copy it came from.
+    pub fn read_block_copy(&self, block_ptr: &BlockPtr) -> zfs::Result<(Vec<u8>, usize)> {
+        Zio::read(self, block_ptr).execute()
+    }
+
+    /// Read a block as it's on disk: still compressed, and encrypted if it is
+    pub fn read_block_raw(&self, block_ptr: &BlockPtr) -> zfs::Result<Vec<u8>> {
+        let (mut data, _) = try!(Zio::read(self, block_ptr).flag(Flag::Raw).execute());
+        data.truncate((block_ptr.psize() * 512) as usize);
+        Ok(data)
+    }
+
+    /// Decrypt the data of encrypted block `block_ptr`, as read from disk, in place. The MAC
+    /// tells which of the loaded keys it was encrypted with; `NoKey` if none of them.
+    pub fn decrypt(&self, block_ptr: &BlockPtr, data: &mut Vec<u8>) -> zfs::Result<()> {
+        let psize = (block_ptr.psize() * 512) as usize;
+        if data.len() < psize {
+            return Err(zfs::Error::Invalid);
+        }
+        data.truncate(psize);
+        for key in &self.keys {
+            match key.decrypt_block(block_ptr, data) {
+                Err(zfs::Error::NoKey) => {}
+                result => return result,
+            }
+        }
+        Err(zfs::Error::NoKey)
+    }
+
+    /// Reassemble a gang block: read its header, then the fragments it points at, in order. The
+    /// fragments are gang blocks themselves if there wasn't enough contiguous space left for them
+    /// either. The checksum of the block pointer covers the reassembled data, each fragment has a
+    /// checksum of its own.
+    pub fn read_gang(&self, block_ptr: &BlockPtr, dva: &DVAddr) -> zfs::Result<Vec<u8>> {
+        // On a mirror, a child without the header is skipped
+        let verifier = block_ptr.gang_verifier();
+        let header = try!(self.read_dva_verified(dva, GANG_HEADER_SIZE as u64, &|header| {
+            checksum::verify_embedded(header, verifier).is_ok()
+        }));
+        let header = &header[..GANG_HEADER_SIZE];
+        let gang = try!(Gang::from_bytes_order(header, block_ptr.data_order()));
+
+        let psize = (block_ptr.psize() * 512) as usize;
+        let mut data = Vec::with_capacity(psize);
+        // The first copies of the fragments are read together, they're often next to each
+        // other. A fragment that doesn't pass its checksum that way gets a read of its own, which
+        // goes on to its other copies.
+        let fragments: Vec<&BlockPtr> = gang.bps.iter().filter(|bp| !bp.is_hole()).collect();
+        let direct = |fragment: &BlockPtr| {
+            let dva = fragment.dvas[0];
+            !fragment.is_embedded() && !dva.is_empty() && !dva.gang() &&
+            self.vdev_state(dva.vdev_id()) != vdev::State::Faulted
+        };
+        let dvas: Vec<DVAddr> = fragments.iter()
+                                         .filter(|fragment| direct(fragment))
+                                         .map(|fragment| fragment.dvas[0])
+                                         .collect();
+        let mut read = self.read_many(&dvas).unwrap_or_default().into_iter();
+        for fragment in fragments {
+            let size = (fragment.psize() * 512) as usize;
+            let coalesced = if direct(fragment) { read.next() } else { None };
+            let fragment_data = match coalesced {
+                Some(ref read) if read.len() >= size &&
+                                  checksum::verify(fragment, &read[..size]).is_ok() => {
+                    read[..size].to_vec()
+                }
+                _ => try!(Zio::read(self, fragment).flag(Flag::Raw).execute()).0,
+            };
+            let fragment_size = cmp::min(size, fragment_data.len());
+            data.extend_from_slice(&fragment_data[..fragment_size]);
+        }
+        if data.len() < psize {
+            // Fragments missing
+            return Err(zfs::Error::Invalid);
+        }
+        data.truncate(psize);
+        Ok(data)
     }
 
     /*
-    pub fn read_type<T: FromBytes>(&mut self, block_ptr: &BlockPtr) -> Result<T, &'static str> {
+    pub fn read_type<T: FromBytes>(&self, block_ptr: &BlockPtr) -> Result<T, &'static str> {
         self.read_block(block_ptr).and_then(|data| T::from_bytes(&data[..]))
     }
     */
 
-    pub fn read_type_array<T: FromBytes>(&mut self,
+    pub fn read_type_array<T: FromBytes>(&self,
                                          block_ptr: &BlockPtr,
                                          offset: usize)
-        -> Result<T, String> {
-            self.read_block(block_ptr).map_err(|x| x.to_owned()).and_then(|data| T::from_bytes(&data[offset * mem::size_of::<T>()..]).map_err(|x| x.to_owned()))
-        }
-
-    pub fn uber(&mut self) -> Result<Uberblock, &'static str> {
-        let mut newest_uberblock: Option<Uberblock> = None;
-        for i in 0..128 {
-            if let Ok(uberblock) = Uberblock::from_bytes(&self.read(256 + i * 2, 2)) {
-                let newest = match newest_uberblock {
-                    Some(previous) => {
-                        if uberblock.txg > previous.txg {
-                            // Found a newer uberblock
-                            true
-                        } else {
-                            false
-                        }
+        -> zfs::Result<T> {
+            let data = try!(self.read_block(block_ptr));
+            T::from_bytes_order(&data[offset * mem::size_of::<T>()..], block_ptr.data_order())
+        }
+
+    /// Size of the underlying device in bytes, rounded down to a whole number of labels
+    pub fn size(&self) -> u64 {
+        let label_size = mem::size_of::<VdevLabel>() as u64;
+        match self.disk.size() {
+            Ok(size) => util::p2_align(size, label_size),
+            Err(_) => 0,
+        }
+    }
+
+    /// Find the newest valid uberblock
+    pub fn uber(&self) -> zfs::Result<Uberblock> {
+        self.uber_search().map(|search| search.uberblock)
+    }
+
+    /// Scan the uberblock rings of all four vdev labels and pick the newest valid uberblock,
+    /// keeping track of labels that had no valid uberblock at all.
+    pub fn uber_search(&self) -> zfs::Result<UberblockSearch> {
+        self.uber_search_max(u64::MAX)
+    }
+
+    /// Like `uber_search`, passing over the uberblocks of txgs after `max_txg`: the newest one
+    /// left is what the pool was as of `max_txg` (`spa_load_max_txg`)
+    pub fn uber_search_max(&self, max_txg: u64) -> zfs::Result<UberblockSearch> {
+        let mut newest: Option<(Uberblock, usize)> = None;
+        let mut damaged_labels = Vec::new();
+
+        for label in 0..VDEV_LABELS {
+            // Missing labels are as damaged as those without a single valid uberblock
+            let uberblocks = self.label_uberblocks(label).unwrap_or_else(Vec::new);
+            if uberblocks.is_empty() {
+                damaged_labels.push(label);
+                continue;
+            }
+
+            for (_, uberblock) in uberblocks {
+                if uberblock.txg > max_txg {
+                    continue;
+                }
+                let is_newer = match newest {
+                    Some((previous, _)) => {
+                        // Newest txg wins, the timestamp breaks ties, then the MMP sequence
+                        // number of uberblocks written in the same second
+                        uberblock.txg > previous.txg ||
+                        (uberblock.txg == previous.txg &&
+                         (uberblock.timestamp > previous.timestamp ||
+                          (uberblock.timestamp == previous.timestamp &&
+                           uberblock.mmp_seq() > previous.mmp_seq())))
                     }
                     // No uberblock yet, so first one we find is the newest
                     None => true,
                 };
 
-                if newest {
-                    newest_uberblock = Some(uberblock);
+                if is_newer {
+                    newest = Some((uberblock, label));
                 }
             }
         }
 
-        match newest_uberblock {
-            Some(uberblock) => Ok(uberblock),
-            None => Err("Failed to find valid uberblock"),
+        match newest {
+            Some((uberblock, label)) => {
+                Ok(UberblockSearch {
+                    uberblock: uberblock,
+                    label: label,
+                    damaged_labels: damaged_labels,
+                })
+            }
+            None => Err(zfs::Error::NoUberblock),
+        }
+    }
+
+    /// The valid uberblocks in the uberblock ring of label `label`, along with their slots. None
+    /// if the device is too small to have the label. A slot only counts if its embedded
+    /// checksum, verified against the slot's byte offset, is intact: a torn write of a newer
+    /// uberblock mustn't win over the last good one.
+    pub fn label_uberblocks(&self, label: usize) -> Option<Vec<(u64, Uberblock)>> {
+        let label_offset = match label_offset(self.size(), label) {
+            Some(offset) => offset,
+            None => return None,
+        };
+        // 128 KB of ubers after 128 KB of other stuff
+        let ring_offset = label_offset + UBERBLOCK_RING_OFFSET;
+        let shift = self.uberblock_shift();
+
+        let mut uberblocks = Vec::new();
+        for i in 0..UBERBLOCK_RING_SIZE >> shift {
+            // A slot that can't be read is as good as one without a valid uberblock
+            let offset = ring_offset + (i << shift);
+            let slot = match self.read_at(offset, 1 << shift) {
+                Ok(slot) => slot,
+                Err(_) => continue,
+            };
+            if checksum::verify_embedded(&slot, [offset, 0, 0, 0]).is_err() {
+                continue;
+            }
+            if let Ok(uberblock) = Uberblock::from_bytes(&slot) {
+                uberblocks.push((i, uberblock));
+            }
+        }
+        Some(uberblocks)
+    }
+
+    /// Write `uberblock` to its slot (picked by txg) in the uberblock ring of label `label` of
+    /// every top-level vdev, sealed with the embedded checksum. Any of them may be the one the
+    /// pool is imported from next.
+    pub fn write_uberblock(&self, label: usize, uberblock: &Uberblock) -> zfs::Result<()> {
+        let shift = self.uberblock_shift();
+        let slot = uberblock.txg % (UBERBLOCK_RING_SIZE >> shift);
+        let bytes = unsafe {
+            slice::from_raw_parts(uberblock as *const Uberblock as *const u8,
+                                  mem::size_of::<Uberblock>())
+        };
+
+        let tops: Vec<Option<u64>> = if self.tops.is_empty() {
+            vec![None]
+        } else {
+            self.tops.keys().map(|&id| Some(id)).collect()
+        };
+        let label_size = mem::size_of::<VdevLabel>() as u64;
+        for top in tops {
+            let size = util::p2_align(try!(try!(self.device(top)).size()), label_size);
+            let label_offset = try!(label_offset(size, label).ok_or(zfs::Error::Invalid));
+            let offset = label_offset + UBERBLOCK_RING_OFFSET + (slot << shift);
+
+            let mut data = vec![0; 1 << shift];
+            data[..bytes.len()].copy_from_slice(bytes);
+            checksum::embed(&mut data, [offset, 0, 0, 0]);
+            try!(self.write_device(top, offset, &data));
         }
+        Ok(())
     }
 }
 
+/// The result of scanning all vdev labels for the active uberblock
+pub struct UberblockSearch {
+    /// The newest valid uberblock
+    pub uberblock: Uberblock,
+    /// The label the uberblock was found in
+    pub label: usize,
+    /// Labels without a single valid uberblock
+    pub damaged_labels: Vec<usize>,
+}
+
+/// Number of vdev labels on each leaf device: two at the front, two at the end
+pub const VDEV_LABELS: usize = 4;
+
+/// Offset of the uberblock ring inside a label
+const UBERBLOCK_RING_OFFSET: u64 = 128 * 1024;
+/// Size of the uberblock ring. How many slots it has depends on the ashift.
+const UBERBLOCK_RING_SIZE: u64 = 128 * 1024;
+
+/// The data of an embedded block pointer. There's nothing to read from disk and no checksum to
+/// verify, the payload is decompressed straight out of the block pointer.
+pub fn decode_embedded(block_ptr: &BlockPtr) -> zfs::Result<Vec<u8>> {
+    if block_ptr.embedded_type() != BP_EMBEDDED_TYPE_DATA {
+        return Err(zfs::Error::Decode(DecodeError::BadEmbeddedType(block_ptr.embedded_type())));
+    }
+    let lsize = block_ptr.embedded_lsize() as usize;
+    let mut data = try!(zio_compress::decompress(block_ptr.compression(),
+                                                 block_ptr.embedded_payload(),
+                                                 lsize));
+    data.truncate(lsize);
+    Ok(data)
+}
+
+/// Read the pad of each label of `device`, and write it back if `write` is set, until that
+/// works for one of them
+fn probe_device(device: &Vdev, write: bool) -> bool {
+    let label_size = mem::size_of::<VdevLabel>() as u64;
+    let size = match device.size() {
+        Ok(size) => util::p2_align(size, label_size),
+        Err(_) => return false,
+    };
+    (0..VDEV_LABELS).filter_map(|l| label_offset(size, l)).any(|offset| {
+        let mut pad = vec![0; PROBE_SIZE];
+        vdev_io::read_exact_at(device, offset, &mut pad).is_ok() &&
+        (!write || device.write_at(offset, &pad).is_ok())
+    })
+}
+
+/// Byte offset of label `l` on a device of `size` bytes
+pub fn label_offset(size: u64, l: usize) -> Option<u64> {
+    let label_size = mem::size_of::<VdevLabel>() as u64;
+    if size < label_size * VDEV_LABELS as u64 {
+        // Too small to hold the labels at the end, but the front ones may still be readable
+        return if size >= label_size * (l as u64 + 1) && l < VDEV_LABELS / 2 {
+            Some(l as u64 * label_size)
+        } else {
+            None
+        };
+    }
+
+    Some(l as u64 * label_size +
+         if l < VDEV_LABELS / 2 {
+             0
+         } else {
+             size - VDEV_LABELS as u64 * label_size
+         })
+}
+
 /// ZIOO priority
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Priority {
@@ -140,7 +1238,11 @@ pub enum Type {
     /// IO control (VDev modifications etc.)
     IoCtl,
 }
-enum Stage {
+
+/// Stages of the ZIO pipeline, in the order they're run. The letters are the IO types that go
+/// through the stage: read, write, free, claim and ioctl.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Stage {
     /// RWFCI
     Open = 1 << 0,
     /// R....
@@ -188,6 +1290,7 @@ enum Stage {
 }
 
 /// Taskq type
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TaskqType {
     /// An "issue"
     Issue,
@@ -205,8 +1308,8 @@ enum PipelineFlow {
     Stop = 0x101,
 }
 
-#[derive(Copy, Clone, PartialEq)]
-enum Flag {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Flag {
     /// Must be equal for two zios to aggregate
     DontAggregate  = 1 << 0,
     IoRepair       = 1 << 1,
@@ -259,3 +1362,786 @@ enum WaitType {
     Ready = 0,
     Done,
 }
+
+/// The stages of a read of a block
+const READ_PIPELINE: u32 = Stage::ReadBpInit as u32 | Stage::VdevIoStart as u32 |
+                           Stage::VdevIoAssess as u32 |
+                           Stage::ChecksumVerify as u32 | Stage::Done as u32;
+
+/// An IO moving through its pipeline of stages, one at a time. Each stage hands it on to the
+/// next one in the pipeline, or sends it back to an earlier one to try again.
+pub struct Zio<'a> {
+    reader: &'a Reader,
+    block_ptr: BlockPtr,
+    /// The stages it goes through, as a mask of `Stage`s
+    pipeline: u32,
+    /// The `Flag`s it was issued with, as a mask
+    flags: u32,
+    /// The stage it's at. Whatever it is, the next one run is the next in the pipeline.
+    stage: u32,
+    /// The copy (DVA) being read
+    copy: usize,
+    /// The data read, as it is on disk until `Done` decompresses it
+    data: Option<Vec<u8>>,
+    /// Whether the vdev verified the data against the checksum already, e.g. to pick a good
+    /// child of a mirror
+    verified: bool,
+    /// Why the data couldn't be read
+    error: zfs::Error,
+    /// Number of times a copy was read, failed attempts included
+    pub attempts: usize,
+    /// Number of times the copy being read was tried again
+    retries: u32,
+}
+
+impl<'a> Zio<'a> {
+    /// A read of `block_ptr`, verified and decompressed, failing over to its other copies and
+    /// then up to the pool's failure mode
+    pub fn read(reader: &'a Reader, block_ptr: &BlockPtr) -> Self {
+        Zio {
+            reader: reader,
+            block_ptr: *block_ptr,
+            pipeline: READ_PIPELINE,
+            flags: 0,
+            stage: Stage::Open as u32,
+            copy: 0,
+            data: None,
+            verified: false,
+            error: zfs::Error::NoEntity,
+            attempts: 0,
+            retries: 0,
+        }
+    }
+
+    /// Issue the IO with `flag` as well. Reads take `Raw` (keep the data as it is on disk),
+    /// `DontRetry` (only read the first copy, and only once), `CanFail` (return the error,
+    /// whatever the failure mode) and `Speculative` (a read ahead: like `DontRetry` and
+    /// `CanFail`, without probing the vdev on errors either).
+    pub fn flag(mut self, flag: Flag) -> Self {
+        self.flags |= flag as u32;
+        self
+    }
+
+    /// Leave `stage` out of the pipeline, e.g. `ChecksumVerify` to get at the data of a block
+    /// whose checksum is known bad
+    pub fn skip(mut self, stage: Stage) -> Self {
+        self.pipeline &= !(stage as u32);
+        self
+    }
+
+    fn has_flag(&self, flag: Flag) -> bool {
+        self.flags & flag as u32 != 0
+    }
+
+    /// Go back to just before `stage`, which is run next
+    fn rewind(&mut self, stage: Stage) {
+        self.stage = stage as u32 >> 1;
+    }
+
+    /// Run the IO through its pipeline. Returns the data along with the index of the copy it came
+    /// from.
+    pub fn execute(mut self) -> zfs::Result<(Vec<u8>, usize)> {
+        loop {
+            let later = self.pipeline & !((self.stage << 1) - 1);
+            if later == 0 {
+                break;
+            }
+            self.stage = later & later.wrapping_neg();
+            let flow = match self.stage {
+                s if s == Stage::ReadBpInit as u32 => self.read_bp_init(),
+                s if s == Stage::VdevIoStart as u32 => self.vdev_io_start(),
+                s if s == Stage::VdevIoAssess as u32 => self.vdev_io_assess(),
+                s if s == Stage::ChecksumVerify as u32 => self.checksum_verify(),
+                s if s == Stage::Done as u32 => self.done(),
+                _ => PipelineFlow::Continue,
+            };
+            if flow == PipelineFlow::Stop {
+                break;
+            }
+        }
+        match self.data {
+            Some(data) => Ok((data, self.copy)),
+            None => Err(self.error),
+        }
+    }
+
+    /// The first copy from `copy` on there is
+    fn next_copy(&self, copy: usize) -> Option<usize> {
+        (copy..self.block_ptr.max_copies()).find(|&copy| !self.block_ptr.dvas[copy].is_empty())
+    }
+
+    fn read_bp_init(&mut self) -> PipelineFlow {
+        if self.block_ptr.is_embedded() {
+            // Nothing to read or verify, and the payload comes out decompressed already
+            match decode_embedded(&self.block_ptr) {
+                Ok(data) => self.data = Some(data),
+                Err(e) => {
+                    self.error = e;
+                    return PipelineFlow::Stop;
+                }
+            }
+            self.flags |= Flag::Raw as u32;
+            self.rewind(Stage::Done);
+            return PipelineFlow::Continue;
+        }
+        // Corrupted metadata mustn't send the read off to a wild offset
+        if let Err(e) = self.reader.verify_block_ptr(&self.block_ptr) {
+            self.error = e;
+            return PipelineFlow::Stop;
+        }
+        self.retries = 0;
+        match self.next_copy(0) {
+            Some(copy) => self.copy = copy,
+            // A block without copies has nothing to read
+            None => {
+                self.error = zfs::Error::NoEntity;
+                self.rewind(Stage::VdevIoAssess);
+            }
+        }
+        PipelineFlow::Continue
+    }
+
+    fn vdev_io_start(&mut self) -> PipelineFlow {
+        let block_ptr = self.block_ptr;
+        let dva = block_ptr.dvas[self.copy];
+        if self.reader.vdev_state(dva.vdev_id()) == vdev::State::Faulted {
+            // Not worth a try, on to the next copy
+            self.error = zfs::Error::Io(io::ErrorKind::NotConnected);
+            return PipelineFlow::Continue;
+        }
+        self.attempts += 1;
+        let read = if dva.gang() {
+            self.reader.read_gang(&block_ptr, &dva).map(|data| (data, false))
+        } else {
+            let size = block_ptr.psize() << SPA_MINBLOCKSHIFT;
+            let read = if self.pipeline & Stage::ChecksumVerify as u32 != 0 {
+                self.reader.read_dva_sectors(&dva, size, &|data| {
+                    checksum::verify(&block_ptr, data).is_ok()
+                })
+            } else {
+                self.reader.read_dva_sectors(&dva, size, &|_| true)
+            };
+            read.map_err(zfs::Error::from)
+        };
+        match read {
+            Ok((data, verified)) => {
+                self.data = Some(data);
+                self.verified = verified;
+            }
+            Err(e) => {
+                if let zfs::Error::Io(_) = e {
+                    self.reader.count_error(dva.vdev_id(), |errors| errors.read += 1);
+                }
+                self.error = e;
+            }
+        }
+        PipelineFlow::Continue
+    }
+
+    /// Fail over to the next copy if this one couldn't be read, and once there are none left, to
+    /// the pool's failure mode. A device error is retried first, following the reader's
+    /// `retry_policy`. If that doesn't help, the vdev is probed: if the probe fails too, the vdev
+    /// is faulted and the other reads skip it.
+    fn vdev_io_assess(&mut self) -> PipelineFlow {
+        let top = self.block_ptr.dvas.get(self.copy).map(|dva| dva.vdev_id());
+        if self.data.is_some() {
+            if let (Some(top), true) = (top, self.retries > 0) {
+                self.reader.count_retry(top, |stats| stats.recovered += 1);
+            }
+            return PipelineFlow::Continue;
+        }
+        if self.has_flag(Flag::Speculative) {
+            // Nobody's waiting for the block yet, the read that needs it deals with the error
+            return PipelineFlow::Stop;
+        }
+        if let (zfs::Error::Io(kind), Some(top)) = (self.error, top) {
+            if self.reader.vdev_state(top) != vdev::State::Faulted {
+                let policy = self.reader.retry_policy;
+                if retryable(kind) && !self.has_flag(Flag::DontRetry) {
+                    if self.retries < policy.max_retries {
+                        self.reader.count_retry(top, |stats| stats.retries += 1);
+                        thread::sleep(policy.delay(self.retries));
+                        self.retries += 1;
+                        self.rewind(Stage::VdevIoStart);
+                        return PipelineFlow::Continue;
+                    }
+                    if policy.max_retries > 0 {
+                        self.reader.count_retry(top, |stats| stats.exhausted += 1);
+                    }
+                }
+                if self.reader.probe(top) {
+                    self.reader.transient_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        let next = self.next_copy(self.copy + 1);
+        if let (Some(copy), false) = (next, self.has_flag(Flag::DontRetry)) {
+            self.copy = copy;
+            self.retries = 0;
+            self.rewind(Stage::VdevIoStart);
+            return PipelineFlow::Continue;
+        }
+        if self.has_flag(Flag::CanFail) {
+            return PipelineFlow::Stop;
+        }
+        match self.reader.io_failure(self.error) {
+            // Start over from the first copy, on whatever vdevs came back
+            Ok(()) => {
+                self.reader.reprobe();
+                self.rewind(Stage::ReadBpInit);
+                PipelineFlow::Continue
+            }
+            Err(_) => PipelineFlow::Stop,
+        }
+    }
+
+    fn checksum_verify(&mut self) -> PipelineFlow {
+        let good = self.verified ||
+                   self.data.as_ref().map_or(false, |data| {
+                       checksum::verify(&self.block_ptr, data).is_ok()
+                   });
+        if !good {
+            if let Some(dva) = self.block_ptr.dvas.get(self.copy) {
+                self.reader.count_error(dva.vdev_id(), |errors| errors.checksum += 1);
+            }
+            self.data = None;
+            self.error = zfs::Error::Checksum;
+            self.rewind(Stage::VdevIoAssess);
+        }
+        PipelineFlow::Continue
+    }
+
+    fn done(&mut self) -> PipelineFlow {
+        if self.attempts > 0 {
+            self.reader.suspended.store(false, Ordering::Relaxed);
+            if self.copy > 0 {
+                self.reader.ditto_failovers.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if !self.has_flag(Flag::Raw) {
+            let mut data = self.data.take().unwrap();
+            // Encrypted blocks are compressed before they're encrypted
+            if self.block_ptr.is_encrypted() {
+                if let Err(e) = self.reader.decrypt(&self.block_ptr, &mut data) {
+                    self.error = e;
+                    return PipelineFlow::Stop;
+                }
+            }
+            match Codec::of(&self.block_ptr).decode(data) {
+                Ok(data) => self.data = Some(data),
+                Err(e) => self.error = e,
+            }
+        }
+        PipelineFlow::Stop
+    }
+}
+
+#[test]
+fn test_unaligned_write_4kn() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_unaligned_write_4kn");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len(16 * 1024).unwrap();
+    let mut reader = Reader::new(disk);
+    reader.sector_shift = 12;
+
+    reader.write_sectors(0, &[0xAA; 8192]).unwrap();
+    // Sectors 9 and 10 sit in the middle of the second 4K device sector
+    reader.write_sectors(9, &[0x55; 1024]).unwrap();
+    let data = reader.read(8, 8).unwrap();
+    assert!(data[..512].iter().all(|&x| x == 0xAA));
+    assert!(data[512..1536].iter().all(|&x| x == 0x55));
+    assert!(data[1536..].iter().all(|&x| x == 0xAA));
+    assert_eq!(reader.read_at(4608 + 1000, 4).unwrap(), vec![0x55; 4]);
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_gang_block() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_gang_block");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len((0x2000 + 8) * 512).unwrap();
+    let mut reader = Reader::new(disk);
+
+    // Uncompressed, unchecksummed, little endian blocks of `sectors` sectors at `offset`
+    let block_ptr = |offset: u64, sectors: u64| {
+        let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+        let empty = DVAddr { vdev: 0, offset: 0 };
+        block_ptr.dvas = [DVAddr { vdev: sectors, offset: offset }, empty, empty];
+        block_ptr.flags_size = 1 << 63 | 2 << 40 | 2 << 32 | (sectors - 1) << 16 | (sectors - 1);
+        block_ptr.birth_txg = 5;
+        block_ptr
+    };
+
+    // A 3 sector block, split into a 1 and a 2 sector fragment
+    let fragments = [block_ptr(1, 1), block_ptr(2, 2), BlockPtr::from_bytes(&[0; 128]).unwrap()];
+    let mut header = vec![0; GANG_HEADER_SIZE];
+    for (i, fragment) in fragments.iter().enumerate() {
+        let bytes: [u8; 128] = unsafe { mem::transmute(*fragment) };
+        header[i * 128..(i + 1) * 128].copy_from_slice(&bytes);
+    }
+    let mut gang = block_ptr(1 << 63, 3);
+    checksum::embed(&mut header, gang.gang_verifier());
+    reader.write_sectors(0x2000, &header).unwrap();
+    reader.write_sectors(0x2001, &[1; 512]).unwrap();
+    reader.write_sectors(0x2002, &[2; 1024]).unwrap();
+
+    let data = reader.read_block(&gang).unwrap();
+    assert_eq!(data.len(), 1536);
+    assert!(data[..512].iter().all(|&x| x == 1) && data[512..].iter().all(|&x| x == 2));
+    // The fragments are next to each other, they're read in one go
+    assert_eq!(reader.coalesced.load(Ordering::Relaxed), 1);
+
+    // The header is tied to its birth txg
+    gang.birth_txg = 6;
+    assert!(reader.read_block(&gang).is_err());
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_gang_block_copies() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_gang_block_copies");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len((0x2000 + 0x30) * 512).unwrap();
+    let reader = Reader::new(disk);
+
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    let block_ptr = |dvas: [DVAddr; 3], sectors: u64| {
+        let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+        block_ptr.dvas = dvas;
+        block_ptr.flags_size = 1 << 63 | 2 << 40 | 2 << 32 | (sectors - 1) << 16 | (sectors - 1);
+        block_ptr.birth_txg = 5;
+        block_ptr
+    };
+
+    // Two copies of the header of a 2 sector block, at 0x10 and 0x20, with one set of fragments
+    let gang = block_ptr([DVAddr { vdev: 1, offset: 1 << 63 | 0x10 },
+                          DVAddr { vdev: 1, offset: 1 << 63 | 0x20 },
+                          empty],
+                         2);
+    let dvas = gang.dvas;
+    let mut header = vec![0; GANG_HEADER_SIZE];
+    for (i, &offset) in [0x11, 0x12].iter().enumerate() {
+        let fragment = block_ptr([DVAddr { vdev: 1, offset: offset }, empty, empty], 1);
+        let bytes: [u8; 128] = unsafe { mem::transmute(fragment) };
+        header[i * 128..(i + 1) * 128].copy_from_slice(&bytes);
+        reader.write_sectors(0x2000 + offset as usize, &[i as u8 + 1; 512]).unwrap();
+    }
+    // Both copies are checked against where the first one is
+    checksum::embed(&mut header, [0, 0x10 << 9, 5, 0]);
+    reader.write_sectors(0x2010, &header).unwrap();
+    reader.write_sectors(0x2020, &header).unwrap();
+    assert_eq!(gang.gang_verifier(), [0, 0x10 << 9, 5, 0]);
+
+    let expected: Vec<u8> = vec![1; 512].into_iter().chain(vec![2; 512]).collect();
+    assert_eq!(reader.read_gang(&gang, &dvas[1]).unwrap(), expected);
+    // The first copy went bad, the second one is read instead
+    reader.write_sectors(0x2010, &[0; 512]).unwrap();
+    assert_eq!(reader.read_block(&gang).unwrap(), expected);
+
+    // A copy checksummed against its own place isn't one of the block's
+    checksum::embed(&mut header, [0, 0x20 << 9, 5, 0]);
+    reader.write_sectors(0x2020, &header).unwrap();
+    assert!(reader.read_gang(&gang, &dvas[1]).is_err());
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_ditto_failover() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_ditto_failover");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len((0x2000 + 4) * 512).unwrap();
+    let mut reader = Reader::new(disk);
+
+    let data = [0x42; 512];
+    reader.write_sectors(0x2000, &[0x13; 512]).unwrap(); // the first copy went bad
+    reader.write_sectors(0x2001, &data).unwrap();
+
+    // One uncompressed sector, fletcher4, two copies
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 0 }, DVAddr { vdev: 1, offset: 1 }, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    block_ptr.checksum = checksum::compute(7, &data, false).unwrap();
+
+    assert_eq!(reader.read_block_copy(&block_ptr).unwrap(), (data.to_vec(), 1));
+    assert_eq!(reader.ditto_failovers.load(Ordering::Relaxed), 1);
+
+    reader.write_sectors(0x2001, &[0x13; 512]).unwrap();
+    assert!(reader.read_block(&block_ptr).is_err());
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_failmode_wait() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_failmode_wait");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len((0x2001 + 1) * 512).unwrap();
+    let mut reader = Reader::new(disk);
+    reader.write_sectors(0x2001, &[0x13; 512]).unwrap();
+
+    let data = [0x42; 512];
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 1 }, empty, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    block_ptr.checksum = checksum::compute(7, &data, false).unwrap();
+
+    assert_eq!(reader.read_block(&block_ptr).err(), Some(zfs::Error::Checksum));
+    assert_eq!(reader.io_failures.load(Ordering::Relaxed), 1);
+
+    // The device comes back while the read is waiting
+    reader.failmode = FailMode::Wait;
+    let mut repaired = Reader::new(OpenOptions::new().write(true).open(&path).unwrap());
+    let repair = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(FAILMODE_RETRY_INTERVAL_MS));
+        repaired.write_sectors(0x2001, &data).unwrap();
+    });
+    assert_eq!(reader.read_block(&block_ptr).unwrap(), data.to_vec());
+    assert!(reader.io_failures.load(Ordering::Relaxed) > 1 &&
+            !reader.suspended.load(Ordering::Relaxed));
+    repair.join().unwrap();
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_read_past_end() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_read_past_end");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len(0x2001 * 512).unwrap();
+    let mut reader = Reader::new(disk);
+
+    // Only the first half of the read is on the device, the rest mustn't come back as zeros
+    assert_eq!(reader.read(0x2000, 1).unwrap(), vec![0; 512]);
+    assert_eq!(reader.read(0x2000, 2).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 1 }, empty, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    // The block pointer itself says it's past the end: it's not even read
+    let reads = reader.reads.load(Ordering::Relaxed);
+    assert_eq!(reader.read_block(&block_ptr).err(),
+               Some(zfs::Error::Decode(DecodeError::BadOffset {
+                   vdev: 0,
+                   offset: 512,
+                   asize: 512,
+               })));
+    assert_eq!(reader.reads.load(Ordering::Relaxed), reads);
+
+    // Nor is one on a top-level vdev the pool doesn't have
+    reader.tops.insert(0, Box::new(reader.disk.try_clone().unwrap()));
+    block_ptr.dvas[0] = DVAddr { vdev: 3 << 32 | 1, offset: 0 };
+    assert_eq!(reader.read_block(&block_ptr).err(),
+               Some(zfs::Error::Decode(DecodeError::BadVdev(3))));
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_uberblock_ring_ashift() {
+    use std::fs::OpenOptions;
+
+    let path = ::std::env::temp_dir().join("zfs_test_uberblock_ring_ashift");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len(4 << 20).unwrap();
+    let mut reader = Reader::new(disk);
+    reader.ashift = 12;
+    assert_eq!((reader.asize(512), reader.asize(4096), reader.asize(4097)), (4096, 4096, 8192));
+
+    let mut uberblock = Uberblock {
+        magic: 0x00bab10c,
+        version: 5000,
+        txg: 5,
+        guid_sum: 0,
+        timestamp: 0,
+        rootbp: BlockPtr::from_bytes(&[0; 128]).unwrap(),
+        software_version: 0,
+        mmp_magic: 0,
+        mmp_delay: 0,
+        mmp_config: 0,
+        checkpoint_txg: 0,
+        raidz_reflow_info: 0,
+    };
+    reader.write_uberblock(0, &uberblock).unwrap();
+    assert_eq!({ reader.uber().unwrap().txg }, 5);
+    // With 4K slots the ring only has 32 of them, so txg 37 takes the slot of txg 5
+    uberblock.txg = 37;
+    reader.write_uberblock(0, &uberblock).unwrap();
+    uberblock.txg = 6;
+    reader.write_uberblock(0, &uberblock).unwrap();
+    let offset = label_offset(reader.size(), 0).unwrap() + UBERBLOCK_RING_OFFSET;
+    let slot = reader.read_at(offset + 5 * 4096, 4096).unwrap();
+    assert_eq!({ Uberblock::from_bytes(&slot).unwrap().txg }, 37);
+    let slot = reader.read_at(offset + 6 * 4096, 4096).unwrap();
+    assert_eq!({ Uberblock::from_bytes(&slot).unwrap().txg }, 6);
+    assert_eq!({ reader.uber().unwrap().txg }, 37);
+
+    // A newer uberblock whose slot doesn't check out is passed over for the last good one
+    uberblock.txg = 38;
+    reader.write_uberblock(0, &uberblock).unwrap();
+    assert_eq!({ reader.uber().unwrap().txg }, 38);
+    let mut slot = reader.read_at(offset + 6 * 4096, 4096).unwrap();
+    slot[100] ^= 1;
+    reader.write_at(offset + 6 * 4096, &slot).unwrap();
+    assert_eq!({ Uberblock::from_bytes(&slot).unwrap().txg }, 38);
+    assert_eq!({ reader.uber().unwrap().txg }, 37);
+    // So is one copied to a slot it wasn't sealed for
+    let slot = reader.read_at(offset + 5 * 4096, 4096).unwrap();
+    reader.write_at(offset + 7 * 4096, &slot).unwrap();
+    let slots = reader.label_uberblocks(0).unwrap();
+    assert_eq!(slots.iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec![5]);
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_memory_backed_reader() {
+    use super::vdev_io::MemVdev;
+
+    let vdev = MemVdev::new(vec![0; (0x2000 + 4) * 512]);
+    let mut reader = Reader::new(vdev.clone());
+    let dva = DVAddr { vdev: 2, offset: 1 };
+    reader.write_dva(&dva, &[0x42; 700]).unwrap();
+    assert_eq!(&vdev.to_vec()[0x2001 * 512..0x2001 * 512 + 700], &[0x42; 700][..]);
+
+    let data = reader.read_dva(&dva, 1024).unwrap();
+    assert_eq!((&data[..700], &data[700..]), (&[0x42; 700][..], &[0; 324][..]));
+    assert!(reader.read_dva(&DVAddr { vdev: 4, offset: 1 }, 2048).is_err());
+}
+
+#[test]
+fn test_shared_reader() {
+    use std::fs::OpenOptions;
+    use std::sync::Arc;
+
+    let path = ::std::env::temp_dir().join("zfs_test_shared_reader");
+    let disk = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    disk.set_len(64 * 512).unwrap();
+    let reader = Arc::new(Reader::new(disk));
+    for sector in 0..64 {
+        reader.write_sectors(sector, &[sector as u8; 512]).unwrap();
+    }
+
+    // Every thread reads its own sectors, none of them may see another's data
+    let threads: Vec<_> = (0..4)
+                              .map(|i| {
+                                  let reader = reader.clone();
+                                  thread::spawn(move || {
+                                      for _ in 0..50 {
+                                          for sector in (i..64).filter(|s| s % 4 == i) {
+                                              let data = reader.read(sector, 1).unwrap();
+                                              assert_eq!(data, vec![sector as u8; 512]);
+                                          }
+                                      }
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(reader.reads.load(Ordering::Relaxed), 4 * 50 * 16);
+
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_zio_pipeline() {
+    let mut disk = vec![0; (0x2000 + 2) * 512];
+    disk[0x2000 * 512..0x2001 * 512].copy_from_slice(&[0x13; 512]); // the first copy went bad
+    disk[0x2001 * 512..].copy_from_slice(&[0x42; 512]);
+    let reader = Reader::new(vdev_io::MemVdev::new(disk));
+
+    // One sector compressed with an algorithm we don't have, fletcher4, two copies
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 0 }, DVAddr { vdev: 1, offset: 1 }, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 15 << 32;
+    block_ptr.checksum = checksum::compute(7, &[0x42; 512], false).unwrap();
+
+    assert_eq!(Zio::read(&reader, &block_ptr).execute().err(),
+               Some(zfs::Error::Decompress));
+    assert_eq!(Zio::read(&reader, &block_ptr).flag(Flag::Raw).execute().unwrap(),
+               (vec![0x42; 512], 1));
+    // Without retries, the bad copy is all there is
+    assert_eq!(Zio::read(&reader, &block_ptr)
+                   .flag(Flag::Raw)
+                   .flag(Flag::DontRetry)
+                   .flag(Flag::CanFail)
+                   .execute()
+                   .err(),
+               Some(zfs::Error::Checksum));
+    assert_eq!(reader.io_failures.load(Ordering::Relaxed), 0);
+    assert_eq!(Zio::read(&reader, &block_ptr)
+                   .flag(Flag::Raw)
+                   .skip(Stage::ChecksumVerify)
+                   .execute()
+                   .unwrap(),
+               (vec![0x13; 512], 0));
+    // Both of the first two reads got the data from the second copy
+    assert_eq!(reader.ditto_failovers.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_vdev_probe() {
+    use super::vdev_io::{MemVdev, MissingVdev};
+
+    let mut reader = Reader::new(MemVdev::new(vec![0; 4 << 20]));
+    reader.tops.insert(0, Box::new(MemVdev::new(vec![0x42; 8 << 20])));
+    reader.tops.insert(1, Box::new(MissingVdev));
+    reader.probe_writes = true;
+
+    // One uncompressed sector, the first copy on a device that's gone
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1 << 32 | 1, offset: 0x10 },
+                      DVAddr { vdev: 1, offset: 0x10 },
+                      empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    block_ptr.checksum = checksum::compute(7, &[0x42; 512], false).unwrap();
+
+    assert_eq!(reader.read_block_copy(&block_ptr).unwrap(), (vec![0x42; 512], 1));
+    assert_eq!(reader.vdev_state(1), vdev::State::Faulted);
+    assert_eq!(reader.vdev_state(0), vdev::State::Healthy);
+    // A faulted vdev isn't read at all
+    let reads = reader.reads.load(Ordering::Relaxed);
+    let (_, copy) = reader.read_block_copy(&block_ptr).unwrap();
+    assert_eq!((copy, reader.reads.load(Ordering::Relaxed)), (1, reads + 1));
+    assert_eq!(reader.probes.load(Ordering::Relaxed), 1);
+
+    // A block pointer past the end of a device that's still there is no reason to fault it,
+    // it's not even read
+    block_ptr.dvas[1].offset = 0x10000;
+    match reader.read_block(&block_ptr) {
+        Err(zfs::Error::Decode(DecodeError::BadOffset { vdev: 0, .. })) => (),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    assert_eq!(reader.vdev_state(0), vdev::State::Healthy);
+    assert_eq!(reader.probes.load(Ordering::Relaxed), 1);
+
+    // The device is back
+    reader.tops.insert(1, Box::new(MemVdev::new(vec![0x42; 8 << 20])));
+    assert_eq!(reader.reprobe(), 0);
+    assert!(reader.faulted().is_empty());
+    block_ptr.dvas[1].offset = 0x10;
+    assert_eq!(reader.read_block_copy(&block_ptr).unwrap().1, 0);
+}
+
+#[test]
+fn test_read_retry() {
+    use std::sync::Arc;
+    use super::vdev_io::MemVdev;
+
+    /// A device whose next few reads fail with EIO
+    #[derive(Clone)]
+    struct FlakyVdev {
+        disk: MemVdev,
+        failures: Arc<AtomicU64>,
+    }
+
+    impl Vdev for FlakyVdev {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let failures = self.failures.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::Relaxed);
+                return Err(io::Error::from_raw_os_error(5));
+            }
+            self.disk.read_at(offset, buf)
+        }
+
+        fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.disk.write_at(offset, data)
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            self.disk.size()
+        }
+
+        fn try_clone(&self) -> io::Result<Box<Vdev>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    let flaky = FlakyVdev {
+        disk: MemVdev::new(vec![0x42; 8 << 20]),
+        failures: Arc::new(AtomicU64::new(2)),
+    };
+    let mut reader = Reader::new(flaky.clone());
+    reader.retry_policy = RetryPolicy {
+        max_retries: 3,
+        base_delay_ms: 1,
+        max_delay_ms: 4,
+    };
+
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    let empty = DVAddr { vdev: 0, offset: 0 };
+    block_ptr.dvas = [DVAddr { vdev: 1, offset: 0x10 }, empty, empty];
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 7 << 40 | 2 << 32;
+    block_ptr.checksum = checksum::compute(7, &[0x42; 512], false).unwrap();
+
+    // Two failures, then the third try works
+    assert_eq!(reader.read_block(&block_ptr).unwrap(), vec![0x42; 512]);
+    assert_eq!(reader.retry_stats(0),
+               RetryStats {
+                   retries: 2,
+                   recovered: 1,
+                   exhausted: 0,
+               });
+    assert_eq!(reader.probes.load(Ordering::Relaxed), 0);
+
+    // More failures than retries: the copy is given up on, the probe finds the device there
+    flaky.failures.store(4, Ordering::Relaxed);
+    assert!(reader.read_block(&block_ptr).is_err());
+    assert_eq!(reader.retry_stats(0).exhausted, 1);
+    assert_eq!(reader.vdev_state(0), vdev::State::Healthy);
+
+    // Not retried at all
+    flaky.failures.store(1, Ordering::Relaxed);
+    assert!(Zio::read(&reader, &block_ptr).flag(Flag::DontRetry).execute().is_err());
+    assert_eq!(reader.retry_stats(0).retries, 5);
+
+    for retry in 0..8 {
+        let delay = reader.retry_policy.delay(retry);
+        assert!(delay >= Duration::from_millis(cmp::min(1 << retry, 4) / 2) &&
+                delay <= Duration::from_millis(4));
+    }
+}
+
+#[test]
+fn test_read_many() {
+    use super::vdev_io::MemVdev;
+
+    let reader = Reader::new(MemVdev::new(vec![0; 4 << 20]));
+    for sector in 0..16 {
+        reader.write_sectors(0x2000 + sector, &[sector as u8; 512]).unwrap();
+    }
+    let dva = |offset: u64, sectors: u64| DVAddr { vdev: sectors, offset: offset };
+
+    // Blocks 0, 1 and 4 follow each other, 10 is a read of its own
+    let dvas = [dva(4, 2), dva(10, 1), dva(0, 1), dva(1, 3)];
+    let blocks = reader.read_many(&dvas).unwrap();
+    assert_eq!(reader.reads.load(Ordering::Relaxed), 2);
+    assert_eq!(reader.coalesced.load(Ordering::Relaxed), 2);
+    for (dva, block) in dvas.iter().zip(&blocks) {
+        assert_eq!(block.len() as u64, dva.asize() * 512);
+        for (i, sector) in block.chunks(512).enumerate() {
+            assert!(sector.iter().all(|&x| x as u64 == dva.offset() + i as u64));
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+
+use super::block_ptr::BlockPtr;
+use super::dvaddr::DVAddr;
+use super::zfs;
+use super::zio;
+
+struct Slot<T> {
+    /// What the IO returned, or the panic it ran into
+    value: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// The result of an IO, once a worker has done it. Works with any executor: the task awaiting
+/// it is woken when the IO is done.
+pub struct IoFuture<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> IoFuture<T> {
+    /// A future that's done already, e.g. for a block found in the cache
+    pub fn ready(value: T) -> Self {
+        IoFuture {
+            slot: Arc::new(Mutex::new(Slot {
+                value: Some(Ok(value)),
+                waker: None,
+            })),
+        }
+    }
+
+    fn pending() -> Self {
+        IoFuture {
+            slot: Arc::new(Mutex::new(Slot {
+                value: None,
+                waker: None,
+            })),
+        }
+    }
+}
+
+fn complete<T>(slot: &Mutex<Slot<T>>, value: thread::Result<T>) {
+    let waker = {
+        let mut slot = slot.lock().unwrap();
+        slot.value = Some(value);
+        slot.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = T;
+
+    /// A panic in the IO is passed on to the task awaiting it
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.value.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+type Job = Box<FnOnce(&zio::Reader) + Send>;
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// No more jobs are coming, the workers exit once the queue is empty
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// A reader whose reads return futures, for servers that have many blocks in flight at once.
+/// The reads are done by a fixed set of worker threads sharing one `zio::Reader`, however many
+/// there are: a read waiting for its turn takes up a queue entry, not a thread.
+pub struct AsyncReader {
+    reader: Arc<zio::Reader>,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl AsyncReader {
+    pub fn new(reader: Arc<zio::Reader>, workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let workers = (0..workers.max(1))
+                          .map(|_| {
+                              let reader = reader.clone();
+                              let shared = shared.clone();
+                              thread::spawn(move || work(&reader, &shared))
+                          })
+                          .collect();
+        AsyncReader {
+            reader: reader,
+            shared: shared,
+            workers: workers,
+        }
+    }
+
+    /// The reader the workers read with
+    pub fn reader(&self) -> &Arc<zio::Reader> {
+        &self.reader
+    }
+
+    /// Queue `io` for a worker to do with the reader, and return a future of what it returns
+    pub fn spawn<T, F>(&self, io: F) -> IoFuture<T>
+        where T: Send + 'static,
+              F: FnOnce(&zio::Reader) -> T + Send + 'static
+    {
+        let future = IoFuture::pending();
+        let slot = future.slot.clone();
+        let job: Job = Box::new(move |reader: &zio::Reader| {
+            complete(&slot, panic::catch_unwind(AssertUnwindSafe(|| io(reader))));
+        });
+        self.shared.queue.lock().unwrap().jobs.push_back(job);
+        self.shared.ready.notify_one();
+        future
+    }
+
+    /// See `zio::Reader::read_at`
+    pub fn read_at(&self, offset: u64, len: usize) -> IoFuture<io::Result<Vec<u8>>> {
+        self.spawn(move |reader| reader.read_at(offset, len))
+    }
+
+    /// See `zio::Reader::read_dva`
+    pub fn read_dva(&self, dva: DVAddr, size: u64) -> IoFuture<io::Result<Vec<u8>>> {
+        self.spawn(move |reader| reader.read_dva(&dva, size))
+    }
+
+    /// See `zio::Reader::read_block`
+    pub fn read_block(&self, block_ptr: BlockPtr) -> IoFuture<zfs::Result<Vec<u8>>> {
+        self.spawn(move |reader| reader.read_block(&block_ptr))
+    }
+}
+
+impl Drop for AsyncReader {
+    /// Reads already queued are still done, so their futures complete
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(reader: &zio::Reader, shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+        job(reader);
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the calling thread, for callers without an executor of their
+/// own
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn test_async_reads() {
+    use super::arcache::ArCache;
+    use super::vdev_io::MemVdev;
+
+    let mut disk = vec![0; (0x2000 + 64) * 512];
+    for sector in 0..64 {
+        let start = (0x2000 + sector) * 512;
+        for b in &mut disk[start..start + 512] {
+            *b = sector as u8;
+        }
+    }
+    let reader = AsyncReader::new(Arc::new(zio::Reader::new(MemVdev::new(disk))), 3);
+
+    // All of them are in flight before the first is awaited
+    let reads: Vec<_> = (0..64)
+                            .map(|sector| reader.read_dva(DVAddr { vdev: 1, offset: sector }, 512))
+                            .collect();
+    for (sector, read) in reads.into_iter().enumerate() {
+        assert_eq!(block_on(read).unwrap(), vec![sector as u8; 512]);
+    }
+    assert!(block_on(reader.read_at(64 << 20, 512)).is_err());
+
+    // The ARC caches what it reads asynchronously like anything else
+    let arc = ArCache::new();
+    let dva = DVAddr { vdev: 1, offset: 7 };
+    assert_eq!(block_on(arc.read_async(&reader, &dva, 512)).unwrap().to_vec(), vec![7; 512]);
+    assert_eq!(arc.read(reader.reader(), &dva, 512).unwrap().to_vec(), vec![7; 512]);
+    assert_eq!(block_on(arc.read_async(&reader, &dva, 512)).unwrap().to_vec(), vec![7; 512]);
+    let mut kstats = super::kstat::Registry::new();
+    arc.kstats(&mut kstats);
+    assert_eq!(kstats.get("zfs_arc_hits_total").unwrap().value, 2.0);
+    assert_eq!(kstats.get("zfs_arc_misses_total").unwrap().value, 1.0);
+    assert_eq!(kstats.get("zfs_arc_prefetch_blocks_total").unwrap().value, 0.0);
+
+    // A panicking read reaches whoever awaits it, the workers carry on
+    let panicked = reader.spawn(|_| -> u8 { panic!("read failed") });
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| block_on(panicked))).is_err());
+    assert_eq!(block_on(reader.spawn(|_| 42)), 42);
+}
@@ -0,0 +1,176 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::cmp;
+
+use super::block_ptr::BlockPtr;
+use super::lzjb;
+use super::zfs;
+
+/// The `compression=on` property value, which picks the default codec (`compress_select`)
+pub const ZIO_COMPRESS_ON: u64 = 1;
+
+/// Compression of blocks stored as they are
+pub const ZIO_COMPRESS_OFF: u64 = 2;
+
+pub const ZIO_COMPRESS_LZJB: u64 = 3;
+
+/// Zero-length encoding: only runs of zeros are compressed
+pub const ZIO_COMPRESS_ZLE: u64 = 14;
+
+/// Runs of up to this many bytes are stored as they are by ZLE, longer ones are zeros
+const ZLE_LITERAL_MAX: usize = 64;
+
+/// How a block's data is stored on disk: what it's compressed with, and how large it is once
+/// decompressed. It's all it takes to decompress the data, so whoever holds on to on-disk data
+/// (like the ARC) can do it without the block pointer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Codec {
+    pub compression: u64,
+    /// Logical size of the block in bytes
+    pub lsize: usize,
+}
+
+impl Codec {
+    pub fn of(block_ptr: &BlockPtr) -> Self {
+        Codec {
+            compression: block_ptr.compression(),
+            lsize: (block_ptr.lsize() * 512) as usize,
+        }
+    }
+
+    /// Whether the data has to be decompressed at all
+    pub fn is_compressed(&self) -> bool {
+        self.compression != ZIO_COMPRESS_OFF
+    }
+
+    pub fn decode(&self, data: Vec<u8>) -> zfs::Result<Vec<u8>> {
+        decompress(self.compression, data, self.lsize)
+    }
+
+    /// Like `decode`, for data that's borrowed, e.g. from the ARC. Uncompressed data is copied,
+    /// there's no need to call this for it (see `is_compressed`).
+    pub fn decode_from(&self, data: &[u8]) -> zfs::Result<Vec<u8>> {
+        match self.compression {
+            ZIO_COMPRESS_OFF => Ok(data.to_vec()),
+            _ => decompress_from(self.compression, data, self.lsize),
+        }
+    }
+}
+
+/// The codec blocks are compressed with for the `compression` property value `compression`:
+/// `on` stands for LZJB, the only general purpose codec there's an encoder for
+pub fn compress_select(compression: u64) -> u64 {
+    match compression {
+        ZIO_COMPRESS_ON => ZIO_COMPRESS_LZJB,
+        compression => compression,
+    }
+}
+
+/// Compress the `data` of a block with `compression`, if that saves at least 12.5% of it, like
+/// `zio_compress_data`. Returns None if it doesn't, or if there's no encoder for `compression`:
+/// the block is stored uncompressed then.
+pub fn compress(compression: u64, data: &[u8]) -> Option<Vec<u8>> {
+    let max = data.len() - (data.len() >> 3);
+    match compress_select(compression) {
+        ZIO_COMPRESS_LZJB => {
+            // Less than what it takes for a cycle of copy items and their flags isn't worth it
+            if max <= 1 + 2 * 8 {
+                return None;
+            }
+            let mut compressed = vec![0; max];
+            match lzjb::LzjbEncoder::new(data).encode(&mut compressed) {
+                len if len <= max && len < data.len() => {
+                    compressed.truncate(len);
+                    Some(compressed)
+                }
+                _ => None,
+            }
+        }
+        ZIO_COMPRESS_ZLE => zle_compress(data, max),
+        _ => None,
+    }
+}
+
+/// Decompress the data of a block with compression `compression` into `lsize` bytes
+pub fn decompress(compression: u64, data: Vec<u8>, lsize: usize) -> zfs::Result<Vec<u8>> {
+    match compression {
+        ZIO_COMPRESS_OFF => Ok(data),
+        _ => decompress_from(compression, &data, lsize),
+    }
+}
+
+fn decompress_from(compression: u64, data: &[u8], lsize: usize) -> zfs::Result<Vec<u8>> {
+    match compression {
+        1 | 3 => {
+            // lzjb compression
+            let mut decompressed = vec![0; lsize];
+            try!(lzjb::LzjbDecoder::new(data)
+                     .decode(&mut decompressed)
+                     .map_err(|_| zfs::Error::Decompress));
+            Ok(decompressed)
+        }
+        ZIO_COMPRESS_ZLE => zle_decompress(data, lsize),
+        _ => Err(zfs::Error::Decompress),
+    }
+}
+
+/// The inverse of `zle_decompress`, in at most `max` bytes
+fn zle_compress(data: &[u8], max: usize) -> Option<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(max);
+    let mut pos = 0;
+    while pos < data.len() {
+        if data[pos] == 0 {
+            let end = cmp::min(pos + 256 - ZLE_LITERAL_MAX, data.len());
+            let zeros = data[pos..end].iter().take_while(|&&byte| byte == 0).count();
+            compressed.push((zeros - 1 + ZLE_LITERAL_MAX) as u8);
+            pos += zeros;
+        } else {
+            // Literals up to the next pair of zeros
+            let end = cmp::min(pos + ZLE_LITERAL_MAX, data.len());
+            let mut literal = pos + 1;
+            while literal < end && (data[literal] != 0 || data.get(literal + 1) != Some(&0)) {
+                literal += 1;
+            }
+            compressed.push((literal - pos - 1) as u8);
+            compressed.extend_from_slice(&data[pos..literal]);
+            pos = literal;
+        }
+        if compressed.len() > max {
+            return None;
+        }
+    }
+    Some(compressed)
+}
+
+/// Each run starts with a byte giving its length less one: up to `ZLE_LITERAL_MAX` literal bytes
+/// follow, beyond that it's that many zeros less `ZLE_LITERAL_MAX`
+fn zle_decompress(data: &[u8], lsize: usize) -> zfs::Result<Vec<u8>> {
+    let mut decompressed = Vec::with_capacity(lsize);
+    let mut pos = 0;
+    while pos < data.len() && decompressed.len() < lsize {
+        let len = data[pos] as usize + 1;
+        pos += 1;
+        if len <= ZLE_LITERAL_MAX {
+            let literal = try!(data.get(pos..pos + len).ok_or(zfs::Error::Decompress));
+            decompressed.extend_from_slice(literal);
+            pos += len;
+        } else {
+            let zeros = decompressed.len() + len - ZLE_LITERAL_MAX;
+            decompressed.resize(zeros, 0);
+        }
+    }
+    if decompressed.len() != lsize {
+        return Err(zfs::Error::Decompress);
+    }
+    Ok(decompressed)
+}
+
+#[test]
+fn test_lzjb_corrupt() {
+    // Two literals, a copy of three bytes from two back, and a literal
+    let data = [0x04, b'a', b'b', 0x00, 0x02, b'c'];
+    assert_eq!(decompress(1, data.to_vec(), 6).unwrap(), b"ababac".to_vec());
+    // A copy from before the start, and data that ends too soon
+    assert_eq!(decompress(1, vec![0x01, 0x00, 0x05], 8), Err(zfs::Error::Decompress));
+    assert_eq!(decompress(1, vec![0x00, b'a', b'b'], 8), Err(zfs::Error::Decompress));
+}
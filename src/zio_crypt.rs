@@ -0,0 +1,502 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use super::block_ptr::BlockPtr;
+use super::crypto;
+use super::dnode::{DNODE_FLAG_SPILL_BLKPTR, DNODE_SHIFT};
+use super::from_bytes::FromBytes;
+use super::zfs;
+use super::zil::{LR_SIZE, LR_WRITE_SIZE, TX_CLONE_RANGE, TX_WRITE, ZIL_CHAIN_ECK, ZIL_CHAIN_SIZE};
+
+/// Bit of new style object types (`DMU_OT_NEWTYPE`), and the bit saying they're encrypted
+const DMU_OT_NEWTYPE: u64 = 0x80;
+const DMU_OT_ENCRYPTED: u64 = 0x20;
+
+/// The old style object types whose blocks are encrypted rather than only authenticated: the
+/// ZIL, dnodes, file and directory contents, ACLs, FUID tables, user quotas and SA objects
+const ENCRYPTED_TYPES: [u64; 18] = [9, 10, 18, 19, 20, 22, 23, 25, 26, 33, 34, 35, 40, 44, 45,
+                                    46, 47, 49];
+
+const DMU_OT_INTENT_LOG: u64 = 9;
+const DMU_OT_DNODE: u64 = 10;
+
+/// The MAC of a block, in the last two words of its checksum
+pub const ZIO_DATA_MAC_LEN: usize = 16;
+/// Room there is for a master key in the DSL crypto key object
+pub const MASTER_KEY_MAX_LEN: usize = 32;
+/// The HMAC key, which dedup and indirect block MACs are computed with
+pub const SHA512_HMAC_KEYLEN: usize = 64;
+/// The key the master keys are wrapped with is always AES-256
+pub const WRAPPING_KEY_LEN: usize = 32;
+/// IV the master keys were wrapped with
+pub const WRAPPING_IV_LEN: usize = 12;
+
+/// Version 0 keys left some non-portable bits of block pointers in the authenticated data
+pub const ZIO_CRYPT_KEY_CURRENT_VERSION: u64 = 1;
+
+/// Size of the core of a dnode, up to its block pointers
+const DNODE_CORE_SIZE: usize = 64;
+const BLKPTR_SIZE: usize = 128;
+/// Offset of `used` in a dnode, which isn't portable, so it's left out of the MAC
+const DNODE_USED_OFFSET: usize = 24;
+
+/// The ciphers encrypted datasets are encrypted with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CryptMode {
+    Ccm,
+    Gcm,
+}
+
+/// The values of the `encryption` property that are suites (`zio_encrypt`), rather than `on`,
+/// `off` or inherited
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CryptSuite {
+    Aes128Ccm = 3,
+    Aes192Ccm,
+    Aes256Ccm,
+    Aes128Gcm,
+    Aes192Gcm,
+    Aes256Gcm,
+}
+
+impl CryptSuite {
+    pub fn from_u64(suite: u64) -> Option<Self> {
+        match suite {
+            3 => Some(CryptSuite::Aes128Ccm),
+            4 => Some(CryptSuite::Aes192Ccm),
+            5 => Some(CryptSuite::Aes256Ccm),
+            6 => Some(CryptSuite::Aes128Gcm),
+            7 => Some(CryptSuite::Aes192Gcm),
+            8 => Some(CryptSuite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CryptSuite::Aes128Ccm => "aes-128-ccm",
+            CryptSuite::Aes192Ccm => "aes-192-ccm",
+            CryptSuite::Aes256Ccm => "aes-256-ccm",
+            CryptSuite::Aes128Gcm => "aes-128-gcm",
+            CryptSuite::Aes192Gcm => "aes-192-gcm",
+            CryptSuite::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+
+    /// Length in bytes of the master key, and of the keys derived from it
+    pub fn key_len(&self) -> usize {
+        match *self {
+            CryptSuite::Aes128Ccm | CryptSuite::Aes128Gcm => 16,
+            CryptSuite::Aes192Ccm | CryptSuite::Aes192Gcm => 24,
+            CryptSuite::Aes256Ccm | CryptSuite::Aes256Gcm => 32,
+        }
+    }
+
+    pub fn mode(&self) -> CryptMode {
+        match *self {
+            CryptSuite::Aes128Ccm | CryptSuite::Aes192Ccm | CryptSuite::Aes256Ccm => {
+                CryptMode::Ccm
+            }
+            _ => CryptMode::Gcm,
+        }
+    }
+}
+
+/// Encrypt `data` in place, returning its MAC. None if the key has the wrong length.
+pub fn encrypt(mode: CryptMode,
+               key: &[u8],
+               iv: &[u8],
+               aad: &[u8],
+               data: &mut [u8])
+               -> Option<[u8; ZIO_DATA_MAC_LEN]> {
+    match mode {
+        CryptMode::Gcm => crypto::gcm_encrypt(key, iv, aad, data),
+        CryptMode::Ccm => {
+            crypto::ccm_encrypt(key, iv, aad, data, ZIO_DATA_MAC_LEN).map(|tag| {
+                let mut mac = [0; ZIO_DATA_MAC_LEN];
+                mac.copy_from_slice(&tag);
+                mac
+            })
+        }
+    }
+}
+
+/// Decrypt `data` in place if it and `aad` match `mac`. Returns whether they did.
+pub fn decrypt(mode: CryptMode,
+               key: &[u8],
+               iv: &[u8],
+               aad: &[u8],
+               data: &mut [u8],
+               mac: &[u8])
+               -> bool {
+    match mode {
+        CryptMode::Gcm => crypto::gcm_decrypt(key, iv, aad, data, mac),
+        CryptMode::Ccm => crypto::ccm_decrypt(key, iv, aad, data, mac),
+    }
+}
+
+/// Whether the blocks of objects of type `object_type` are encrypted in encrypted datasets
+/// (`DMU_OT_IS_ENCRYPTED`). Those of the other types are only authenticated.
+pub fn is_encrypted_type(object_type: u64) -> bool {
+    if object_type & DMU_OT_NEWTYPE != 0 {
+        object_type & DMU_OT_ENCRYPTED != 0
+    } else {
+        ENCRYPTED_TYPES.contains(&object_type)
+    }
+}
+
+/// The master key of an encrypted dataset, unwrapped (see `dsl_crypt`)
+#[derive(Clone)]
+pub struct CryptoKey {
+    pub suite: CryptSuite,
+    pub version: u64,
+    pub guid: u64,
+    /// `suite.key_len()` bytes of it
+    pub master: Vec<u8>,
+    pub hmac: Vec<u8>,
+}
+
+impl CryptoKey {
+    /// The key blocks with `salt` are encrypted with, derived from the master key
+    pub fn data_key(&self, salt: &[u8]) -> Vec<u8> {
+        crypto::hkdf_sha512(&self.master, &[], salt, self.suite.key_len())
+    }
+
+    /// Decrypt the data of `block_ptr` (as read from disk, before decompression) in place, if
+    /// this is the key it was encrypted with. Fails with `NoKey` if it isn't, or if the block
+    /// was tampered with; the MAC can't tell those apart.
+    pub fn decrypt_block(&self, block_ptr: &BlockPtr, data: &mut [u8]) -> zfs::Result<()> {
+        let key = self.data_key(&block_ptr.salt());
+        let (iv, mac) = (block_ptr.iv(), block_ptr.mac());
+        let good = match block_ptr.object_type() {
+            // Log blocks carry their MAC themselves, their checksum is the chain's verifier
+            DMU_OT_INTENT_LOG => {
+                let (aad, regions) = try!(log_regions(block_ptr, data));
+                let mut mac = [0; ZIO_DATA_MAC_LEN];
+                mac.copy_from_slice(&data[ZIL_CHAIN_ECK + 24..ZIL_CHAIN_ECK + 40]);
+                decrypt_regions(self.suite.mode(), &key, &iv, &aad, &regions, &mac, data)
+            }
+            DMU_OT_DNODE => {
+                let (aad, regions) = try!(dnode_regions(block_ptr, self.version, data));
+                decrypt_regions(self.suite.mode(), &key, &iv, &aad, &regions, &mac, data)
+            }
+            _ => decrypt(self.suite.mode(), &key, &iv, &[], data, &mac),
+        };
+        if good {
+            Ok(())
+        } else {
+            Err(zfs::Error::NoKey)
+        }
+    }
+}
+
+/// Decrypt the `regions` of `data`, as one run of ciphertext authenticated along with `aad`,
+/// leaving the rest as it is. Returns whether the MAC matched.
+fn decrypt_regions(mode: CryptMode,
+                   key: &[u8],
+                   iv: &[u8],
+                   aad: &[u8],
+                   regions: &[(usize, usize)],
+                   mac: &[u8],
+                   data: &mut [u8])
+                   -> bool {
+    let mut encrypted = Vec::new();
+    for &(start, end) in regions {
+        encrypted.extend_from_slice(&data[start..end]);
+    }
+    if !decrypt(mode, key, iv, aad, &mut encrypted, mac) {
+        return false;
+    }
+    let mut decrypted = &encrypted[..];
+    for &(start, end) in regions {
+        data[start..end].copy_from_slice(&decrypted[..end - start]);
+        decrypted = &decrypted[end - start..];
+    }
+    true
+}
+
+/// What of a log block is authenticated, and where the encrypted parts of its records are
+/// (`zio_crypt_init_uios_zil`). The chain and the record headers stay in the clear so the log
+/// can be claimed without the key, and so do the block pointers of writes; those are only
+/// authenticated, as are the records of cloned blocks.
+fn log_regions(block_ptr: &BlockPtr, data: &[u8]) -> zfs::Result<(Vec<u8>, Vec<(usize, usize)>)> {
+    let order = block_ptr.data_order();
+    let word = |at: usize| u64::from_bytes_order(&data[at..at + 8], order);
+    let chain = ZIL_CHAIN_SIZE as usize;
+    if data.len() < chain {
+        return Err(zfs::Error::Invalid);
+    }
+    let used = try!(word(ZIL_CHAIN_ECK - 8)) as usize;
+    if used < chain || used > data.len() {
+        return Err(zfs::Error::Invalid);
+    }
+
+    // The chain but its embedded checksum, which holds the MAC
+    let mut aad = data[..ZIL_CHAIN_ECK].to_vec();
+    let mut regions = Vec::new();
+    let mut start = chain;
+    while start < used {
+        if start + LR_SIZE > used {
+            return Err(zfs::Error::Invalid);
+        }
+        let txtype = try!(word(start));
+        let len = try!(word(start + 8)) as usize;
+        if len < LR_SIZE || start + len > used {
+            return Err(zfs::Error::Invalid);
+        }
+        aad.extend_from_slice(&data[start..start + LR_SIZE]);
+        if txtype == TX_WRITE {
+            if len < LR_WRITE_SIZE {
+                return Err(zfs::Error::Invalid);
+            }
+            let block_ptr = start + LR_WRITE_SIZE - BLKPTR_SIZE;
+            regions.push((start + LR_SIZE, block_ptr));
+            aad.extend_from_slice(&data[block_ptr..start + LR_WRITE_SIZE]);
+            if len > LR_WRITE_SIZE {
+                regions.push((start + LR_WRITE_SIZE, start + len));
+            }
+        } else if txtype == TX_CLONE_RANGE {
+            aad.extend_from_slice(&data[start + LR_SIZE..start + len]);
+        } else {
+            regions.push((start + LR_SIZE, start + len));
+        }
+        start += len;
+    }
+    Ok((aad, regions))
+}
+
+/// What of a dnode block is authenticated, and where the bonus buffers that are encrypted are.
+/// The dnodes themselves stay in the clear, the DMU has to be able to find the blocks without
+/// the key; only their portable bits and the MACs of their block pointers are authenticated.
+fn dnode_regions(block_ptr: &BlockPtr,
+                 version: u64,
+                 data: &[u8])
+                 -> zfs::Result<(Vec<u8>, Vec<(usize, usize)>)> {
+    // The cores are authenticated as they are on disk, the block pointers by their values
+    let order = block_ptr.data_order();
+    let mut aad = Vec::new();
+    let mut regions = Vec::new();
+    let mut start = 0;
+    while start + (1 << DNODE_SHIFT) <= data.len() {
+        let dnode = &data[start..];
+        let (object_type, nblkptr, bonus_type, flags) = (dnode[0], dnode[3], dnode[4], dnode[7]);
+        let bonus_len = try!(u16::from_bytes_order(&dnode[10..12], order));
+        let len = (dnode[12] as usize + 1) << DNODE_SHIFT;
+        let spill = flags & DNODE_FLAG_SPILL_BLKPTR != 0;
+        let bonus_start = DNODE_CORE_SIZE + nblkptr as usize * BLKPTR_SIZE;
+        let bonus_end = if spill { len - BLKPTR_SIZE } else { len };
+        if start + len > data.len() || bonus_start > bonus_end {
+            return Err(zfs::Error::Invalid);
+        }
+
+        let core = aad.len();
+        aad.extend_from_slice(&dnode[..DNODE_CORE_SIZE]);
+        aad[core + 7] &= DNODE_FLAG_SPILL_BLKPTR;
+        for byte in &mut aad[core + DNODE_USED_OFFSET..core + DNODE_USED_OFFSET + 8] {
+            *byte = 0;
+        }
+        for i in 0..nblkptr as usize {
+            let offset = DNODE_CORE_SIZE + i * BLKPTR_SIZE;
+            let child = try!(BlockPtr::from_bytes_order(&dnode[offset..offset + BLKPTR_SIZE],
+                                                        order));
+            auth_block_ptr(&child, version, &mut aad);
+        }
+        if spill {
+            let child = try!(BlockPtr::from_bytes_order(&dnode[len - BLKPTR_SIZE..len], order));
+            auth_block_ptr(&child, version, &mut aad);
+        }
+
+        if object_type != 0 && is_encrypted_type(bonus_type as u64) && bonus_len != 0 {
+            regions.push((start + bonus_start, start + bonus_end));
+        } else {
+            aad.extend_from_slice(&dnode[bonus_start..bonus_end]);
+        }
+        start += len;
+    }
+    Ok((aad, regions))
+}
+
+/// Append what's authenticated of `block_ptr` to `aad` (`blkptr_auth_buf_t`): the bits of its
+/// properties that survive a raw send, little endian, then its MAC
+fn auth_block_ptr(block_ptr: &BlockPtr, version: u64, aad: &mut Vec<u8>) {
+    const BYTE_ORDER: u64 = 1 << 63;
+    const DEDUP: u64 = 1 << 62;
+    const CHECKSUM: u64 = 0xFF << 40;
+    const COMPRESSION: u64 = 0x7F << 32;
+    // A psize field of 0 is 512 bytes
+    const PSIZE: u64 = 0xFFFF << 16;
+
+    let mac = block_ptr.mac();
+
+    let mut prop = block_ptr.flags_size;
+    if version == 0 {
+        prop &= !(DEDUP | CHECKSUM | PSIZE);
+    } else if block_ptr.is_hole() {
+        // hole_birth may or may not have set them
+        prop = 0;
+    } else {
+        // Raw sends don't carry indirect blocks, the other side may well have them otherwise
+        if block_ptr.level() != 0 {
+            prop &= !(BYTE_ORDER | COMPRESSION | PSIZE);
+        }
+        prop &= !(DEDUP | CHECKSUM);
+    }
+
+    aad.extend_from_slice(&prop.to_le_bytes());
+    aad.extend_from_slice(&mac);
+    // Version 0 left out the padding
+    if version > 0 {
+        aad.extend_from_slice(&[0; 8]);
+    }
+}
+
+#[test]
+fn test_decrypt_block() {
+    use super::checksum;
+    use super::dvaddr::DVAddr;
+
+    let key = CryptoKey {
+        suite: CryptSuite::Aes256Gcm,
+        version: ZIO_CRYPT_KEY_CURRENT_VERSION,
+        guid: 0x1234,
+        master: vec![0x11; 32],
+        hmac: vec![0x22; 64],
+    };
+
+    // A level 0 plain file block: encrypted whole, the salt and IV in the third DVA
+    let mut block_ptr = BlockPtr::from_bytes(&[0; 128]).unwrap();
+    block_ptr.dvas[0] = DVAddr { vdev: 1, offset: 0x10 };
+    block_ptr.dvas[2] = DVAddr { vdev: 0x0807060504030201, offset: 0x1817161514131211 };
+    block_ptr.fill_count = 0xAABBCCDD << 32 | 1;
+    block_ptr.flags_size = checksum::host_byte_order() << 63 | 1 << 61 | 19 << 48 | 7 << 40 |
+                           2 << 32;
+    assert!(block_ptr.is_encrypted());
+    assert_eq!(block_ptr.max_copies(), 2);
+    assert_eq!(block_ptr.fill(), 1);
+    assert_eq!(&block_ptr.salt(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(&block_ptr.iv(),
+               &[0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0xDD, 0xCC, 0xBB, 0xAA]);
+
+    let plain: Vec<u8> = (0..512).map(|i| i as u8).collect();
+    let mut data = plain.clone();
+    let mac = encrypt(CryptMode::Gcm,
+                      &key.data_key(&block_ptr.salt()),
+                      &block_ptr.iv(),
+                      &[],
+                      &mut data)
+                  .unwrap();
+    let mut checksum = checksum::compute(7, &data, false).unwrap();
+    checksum[0] ^= checksum[2];
+    checksum[1] ^= checksum[3];
+    checksum[2] = u64::from_be_bytes([mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], mac[6],
+                                      mac[7]]);
+    checksum[3] = u64::from_be_bytes([mac[8], mac[9], mac[10], mac[11], mac[12], mac[13],
+                                      mac[14], mac[15]]);
+    block_ptr.checksum = checksum;
+    assert_eq!(block_ptr.mac(), mac);
+    // Half the checksum is the MAC, the other half the (fletcher4, so folded) checksum of the
+    // ciphertext
+    assert!(checksum::verify(&block_ptr, &data).is_ok());
+
+    let mut other = key.clone();
+    other.master = vec![0x33; 32];
+    let mut read = data.clone();
+    assert_eq!(other.decrypt_block(&block_ptr, &mut read), Err(zfs::Error::NoKey));
+    assert_eq!(read, data);
+    key.decrypt_block(&block_ptr, &mut read).unwrap();
+    assert_eq!(read, plain);
+
+    // A dnode block: a file whose bonus buffer is encrypted, and a free dnode
+    let mut dnodes = vec![0; 1024];
+    dnodes[0] = 19;
+    dnodes[3] = 1;
+    dnodes[4] = 44;
+    dnodes[10] = 168;
+    dnodes[24] = 0x99;
+    for byte in &mut dnodes[192..360] {
+        *byte = 0x5A;
+    }
+    let mut dnode_ptr = block_ptr;
+    dnode_ptr.flags_size = checksum::host_byte_order() << 63 | 1 << 61 | 10 << 48 | 8 << 40 |
+                           1 << 16 | 1;
+    let plain = dnodes.clone();
+    let (aad, regions) = dnode_regions(&dnode_ptr, key.version, &dnodes).unwrap();
+    assert_eq!(regions, vec![(192, 512)]);
+    // Both cores, the hole and the bonus buffer of the free dnode
+    assert_eq!(aad.len(), 64 + 32 + 64 + 448);
+    assert!(aad[24..32].iter().all(|&x| x == 0));
+    let mut encrypted = dnodes[192..512].to_vec();
+    let mac = encrypt(CryptMode::Gcm,
+                      &key.data_key(&dnode_ptr.salt()),
+                      &dnode_ptr.iv(),
+                      &aad,
+                      &mut encrypted)
+                  .unwrap();
+    dnodes[192..512].copy_from_slice(&encrypted);
+    dnode_ptr.checksum[2] = u64::from_be_bytes([mac[0], mac[1], mac[2], mac[3], mac[4], mac[5],
+                                                mac[6], mac[7]]);
+    dnode_ptr.checksum[3] = u64::from_be_bytes([mac[8], mac[9], mac[10], mac[11], mac[12],
+                                                mac[13], mac[14], mac[15]]);
+    // `used` isn't authenticated, the object type is
+    let mut read = dnodes.clone();
+    read[24] = 0x42;
+    key.decrypt_block(&dnode_ptr, &mut read).unwrap();
+    assert_eq!(&read[192..512], &plain[192..512]);
+    let mut read = dnodes.clone();
+    read[0] = 20;
+    assert_eq!(key.decrypt_block(&dnode_ptr, &mut read), Err(zfs::Error::NoKey));
+
+    // The same dnodes written by a host of the other endianness
+    let mut swapped = plain.clone();
+    swapped.swap(10, 11);
+    let mut swapped_ptr = dnode_ptr;
+    swapped_ptr.flags_size ^= 1 << 63;
+    assert_eq!(dnode_regions(&swapped_ptr, key.version, &swapped).unwrap().1,
+               vec![(192, 512)]);
+
+    // A log block: a write with 16 bytes of data, then a truncate
+    let mut log = vec![0; 4096];
+    let words = |log: &mut Vec<u8>, at: usize, words: &[u64]| {
+        for (i, word) in words.iter().enumerate() {
+            log[at + i * 8..at + i * 8 + 8].copy_from_slice(&word.to_ne_bytes());
+        }
+    };
+    words(&mut log, 136, &[184 + 208 + 56]);
+    words(&mut log, 184, &[TX_WRITE, 208, 5, 1, 7, 0, 16, 0]);
+    words(&mut log, 392, &[10, 56, 5, 2, 7, 0, 100]);
+    for byte in &mut log[376..392] {
+        *byte = 0xCD;
+    }
+    let mut log_ptr = block_ptr;
+    log_ptr.flags_size = checksum::host_byte_order() << 63 | 1 << 61 | 9 << 48 | 9 << 40 |
+                         7 << 16 | 7;
+    let (aad, regions) = log_regions(&log_ptr, &log).unwrap();
+    assert_eq!(regions, vec![(216, 248), (376, 392), (424, 448)]);
+    // The chain, both record headers and the write's block pointer
+    assert_eq!(aad.len(), 144 + 32 + 128 + 32);
+    let plain = log.clone();
+    let mut encrypted: Vec<u8> = regions.iter()
+                                        .flat_map(|&(start, end)| log[start..end].to_vec())
+                                        .collect();
+    let mac = encrypt(CryptMode::Gcm,
+                      &key.data_key(&log_ptr.salt()),
+                      &log_ptr.iv(),
+                      &aad,
+                      &mut encrypted)
+                  .unwrap();
+    let mut at = 0;
+    for &(start, end) in &regions {
+        log[start..end].copy_from_slice(&encrypted[at..at + end - start]);
+        at += end - start;
+    }
+    log[168..184].copy_from_slice(&mac);
+    assert!(log[376..392].iter().any(|&byte| byte != 0xCD));
+    let mut read = log.clone();
+    key.decrypt_block(&log_ptr, &mut read).unwrap();
+    assert_eq!(&read[..168], &plain[..168]);
+    assert_eq!(&read[184..], &plain[184..]);
+    // The record headers are authenticated
+    let mut read = log.clone();
+    read[392 + 24] = 3;
+    assert_eq!(key.decrypt_block(&log_ptr, &mut read), Err(zfs::Error::NoKey));
+}
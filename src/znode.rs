@@ -0,0 +1,97 @@
+use std::{mem, slice};
+
+use super::from_bytes::{Decoder, FromBytes};
+
+/// A ZPL timestamp. ZFS stores every file time as a (seconds, nanoseconds) pair.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Timestamp {
+    pub secs: u64,
+    pub nsecs: u64,
+}
+
+impl Timestamp {
+    pub fn from_phys(phys: [u64; 2]) -> Self {
+        Timestamp {
+            secs: phys[0],
+            nsecs: phys[1],
+        }
+    }
+}
+
+/// All four file times of a znode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Times {
+    pub atime: Timestamp, // last access
+    pub mtime: Timestamp, // last data modification
+    pub ctime: Timestamp, // last status change
+    pub crtime: Timestamp, // creation
+}
+
+/// The legacy (pre-SA) znode, stored in the bonus buffer of every ZPL dnode
+#[repr(packed)]
+pub struct ZNodePhys {
+    pub atime: [u64; 2],
+    pub mtime: [u64; 2],
+    pub ctime: [u64; 2],
+    pub crtime: [u64; 2],
+    pub gen: u64, // generation (txg of creation)
+    pub mode: u64, // file mode bits
+    pub size: u64, // size of file
+    pub parent: u64, // directory parent (`..`)
+    pub links: u64, // number of links to file
+    pub xattr: u64, // DMU object for xattrs
+    pub rdev: u64, // dev_t for VBLK & VCHR files
+    pub flags: u64, // persistent flags
+    pub uid: u64, // file owner
+    pub gid: u64, // owning group
+    pub zap: u64, // extra attributes
+    pub pad: [u64; 3], // future
+    // zfs_acl_phys_t follows
+}
+
+impl ZNodePhys {
+    /// The znode as it's written to the start of its dnode's bonus buffer, in host byte order.
+    /// The ACL isn't part of it.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const ZNodePhys as *const u8,
+                                  mem::size_of::<ZNodePhys>())
+        }
+    }
+
+    pub fn times(&self) -> Times {
+        Times {
+            atime: Timestamp::from_phys(self.atime),
+            mtime: Timestamp::from_phys(self.mtime),
+            ctime: Timestamp::from_phys(self.ctime),
+            crtime: Timestamp::from_phys(self.crtime),
+        }
+    }
+}
+
+impl FromBytes for ZNodePhys {
+    fn decode(decoder: &mut Decoder) -> Self {
+        let mut words = [0; 19];
+        decoder.fill_u64(&mut words);
+        let mut pad = [0; 3];
+        decoder.fill_u64(&mut pad);
+        ZNodePhys {
+            atime: [words[0], words[1]],
+            mtime: [words[2], words[3]],
+            ctime: [words[4], words[5]],
+            crtime: [words[6], words[7]],
+            gen: words[8],
+            mode: words[9],
+            size: words[10],
+            parent: words[11],
+            links: words[12],
+            xattr: words[13],
+            rdev: words[14],
+            flags: words[15],
+            uid: words[16],
+            gid: words[17],
+            zap: words[18],
+            pad: pad,
+        }
+    }
+}
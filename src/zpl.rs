@@ -0,0 +1,2879 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::{cmp, str};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::ZfsReader;
+use super::block_ptr::BlockPtr;
+use super::dcache;
+use super::dmu;
+use super::dmu_objset::{ObjectSet, ObjectSetType, UserAccounting};
+use super::dnode::{DNodePhys, ObjectType};
+use super::dsl_dataset::Dataset;
+use super::dmu_tx::Tx;
+use super::dsl_pool::DslPool;
+use super::from_bytes::FromBytes;
+use super::metaslab::Allocator;
+use super::nvpair::NvValue;
+use super::sa;
+use super::spa_config;
+use super::zap;
+use super::zfs;
+use super::zil;
+use super::znode::{Timestamp, Times, ZNodePhys};
+
+/// The master node is always object 1 of a ZPL object set
+pub const MASTER_NODE_OBJ: u64 = 1;
+
+/// Longest file name in bytes (`ZFS_MAXNAMELEN`), and on datasets with the longname feature active
+pub const MAXNAMELEN: usize = 255;
+pub const MAXNAMELEN_LONG: usize = 1023;
+
+/// The per-dataset feature allowing names up to `MAXNAMELEN_LONG` bytes
+const LONGNAME_FEATURE: &'static str = "org.zfsonlinux:longname";
+
+/// The last ZPL version before system attributes, whose files keep a legacy znode in their bonus
+/// buffer. It's what `create_fs` lays out.
+pub const ZPL_VERSION_USERSPACE: u64 = 4;
+
+/// File type bits of a directory's mode, of a regular file's, and of a character device's
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFCHR: u64 = 0o020000;
+
+/// The `casesensitivity` of a dataset that's case sensitive unless asked otherwise, as SMB does
+const ZFS_CASE_MIXED: u64 = 2;
+
+/// The block of a file grows up to this while it's the only one (`recordsize`)
+// TODO: go by the dataset's recordsize property
+const ZPL_RECORDSIZE: u64 = 128 << 10;
+
+/// Size of the legacy znode_phys_t, including the ACL embedded at its end. Short symlink targets
+/// follow it in the bonus buffer.
+const ZNODE_PHYS_SIZE: usize = 264;
+
+/// Directory entries keep the object number in the low 48 bits of the ZAP value and the file type
+/// (as in `S_IFMT >> 12`) in the top 4 bits
+pub fn dirent_object(value: u64) -> u64 {
+    value & 0x0000FFFFFFFFFFFF
+}
+
+pub fn dirent_type(value: u64) -> u64 {
+    value >> 60
+}
+
+/// The type of a directory entry, from the top bits of its ZAP value
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileType {
+    Fifo,
+    CharDevice,
+    Directory,
+    BlockDevice,
+    Regular,
+    Symlink,
+    Socket,
+    Unknown,
+}
+
+impl FileType {
+    pub fn from_dirent(value: u64) -> Self {
+        Self::from_ifmt(dirent_type(value))
+    }
+
+    /// The type in the file type bits of a mode (`S_IFMT`)
+    pub fn from_mode(mode: u64) -> Self {
+        Self::from_ifmt((mode >> 12) & 0xF)
+    }
+
+    fn from_ifmt(ifmt: u64) -> Self {
+        match ifmt {
+            1 => FileType::Fifo,
+            2 => FileType::CharDevice,
+            4 => FileType::Directory,
+            6 => FileType::BlockDevice,
+            8 => FileType::Regular,
+            10 => FileType::Symlink,
+            12 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+/// An entry of a ZPL directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub object: u64,
+    pub file_type: FileType,
+}
+
+impl DirEntry {
+    pub fn from_zap(name: String, value: u64) -> Self {
+        DirEntry {
+            name: name,
+            object: dirent_object(value),
+            file_type: FileType::from_dirent(value),
+        }
+    }
+}
+
+/// The metadata of a file, from its znode or system attributes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub object: u64,
+    pub mode: u64,
+    pub uid: u64,
+    pub gid: u64,
+    pub size: u64,
+    pub links: u64,
+    pub parent: u64,
+    pub gen: u64,
+    pub rdev: u64,
+    pub flags: u64,
+    pub times: Times,
+    /// Data blocks the file has, from the fill counts in its dnode
+    pub blocks: u64,
+}
+
+impl Stat {
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    /// The (major, minor) numbers of block and character devices
+    pub fn device(&self) -> Option<(u64, u64)> {
+        match self.file_type() {
+            FileType::BlockDevice | FileType::CharDevice => {
+                Some((self.rdev >> 32, self.rdev & 0xFFFFFFFF))
+            }
+            _ => None,
+        }
+    }
+
+    fn from_znode(object: u64, znode: &ZNodePhys) -> Self {
+        Stat {
+            object: object,
+            mode: znode.mode,
+            uid: znode.uid,
+            gid: znode.gid,
+            size: znode.size,
+            links: znode.links,
+            parent: znode.parent,
+            gen: znode.gen,
+            rdev: znode.rdev,
+            flags: znode.flags,
+            times: znode.times(),
+            blocks: 0,
+        }
+    }
+
+    fn from_attrs(object: u64, attrs: &sa::Attrs) -> Result<Self, String> {
+        let get = |name: &str| attrs.get_u64(name).ok_or(format!("No {} attribute", name));
+        let time = |name: &str| {
+            attrs.get_u64_pair(name)
+                 .map(Timestamp::from_phys)
+                 .ok_or(format!("No {} attribute", name))
+        };
+        Ok(Stat {
+            object: object,
+            mode: try!(get("ZPL_MODE")),
+            uid: try!(get("ZPL_UID")),
+            gid: try!(get("ZPL_GID")),
+            size: try!(get("ZPL_SIZE")),
+            links: try!(get("ZPL_LINKS")),
+            parent: try!(get("ZPL_PARENT")),
+            gen: try!(get("ZPL_GEN")),
+            // Only device files have this one
+            rdev: attrs.get_u64("ZPL_RDEV").unwrap_or(0),
+            flags: try!(get("ZPL_FLAGS")),
+            times: Times {
+                atime: try!(time("ZPL_ATIME")),
+                mtime: try!(time("ZPL_MTIME")),
+                ctime: try!(time("ZPL_CTIME")),
+                crtime: try!(time("ZPL_CRTIME")),
+            },
+            blocks: 0,
+        })
+    }
+}
+
+/// An open regular file of a ZPL dataset
+pub struct FileHandle {
+    pub object: u64,
+    pub dnode: DNodePhys,
+    pub size: u64,
+    /// The changes to the file replayed from the intent log, on top of its blocks
+    pub log: Option<zil::ObjectLog>,
+}
+
+impl FileHandle {
+    /// Read up to `len` bytes at `offset`. Reads are cut short at the end of the file.
+    pub fn read(&self, reader: &mut ZfsReader, offset: u64, len: usize) -> Result<Vec<u8>, String> {
+        if offset >= self.size {
+            return Ok(Vec::new());
+        }
+        let len = cmp::min(len as u64, self.size - offset) as usize;
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return dmu::read(reader, &self.dnode, offset, len),
+        };
+        // Replayed writes can go past the last block on disk
+        let on_disk = dmu::allocated_size(&self.dnode).saturating_sub(offset);
+        let mut data = try!(dmu::read(reader,
+                                      &self.dnode,
+                                      offset,
+                                      cmp::min(len as u64, on_disk) as usize));
+        data.resize(len, 0);
+        log.apply(offset, &mut data);
+        Ok(data)
+    }
+
+    /// Read into `buf` from `offset`, like `pread`: returns how many bytes were read, which is
+    /// less than asked for only at the end of the file
+    pub fn read_into(&self,
+                     reader: &mut ZfsReader,
+                     offset: u64,
+                     buf: &mut [u8])
+                     -> Result<usize, String> {
+        let data = try!(self.read(reader, offset, buf.len()));
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Read the whole file
+    pub fn read_all(&self, reader: &mut ZfsReader) -> Result<Vec<u8>, String> {
+        self.read(reader, 0, self.size as usize)
+    }
+
+    /// The holes of the file, as (offset, length) byte ranges. Unlike `dmu::holes`, the ranges
+    /// end at the end of the file, including the implicit hole after the last allocated block.
+    pub fn holes(&self, reader: &mut ZfsReader) -> Result<Vec<(u64, u64)>, String> {
+        let mut holes = Vec::new();
+        for hole in dmu::holes(reader, &self.dnode) {
+            let (offset, len) = try!(hole);
+            if offset >= self.size {
+                break;
+            }
+            holes.push((offset, cmp::min(len, self.size - offset)));
+        }
+
+        let allocated = cmp::min(dmu::allocated_size(&self.dnode), self.size);
+        if allocated < self.size {
+            // A hole running up to the last allocated block continues to the end of the file
+            match holes.last_mut() {
+                Some(&mut (offset, ref mut len)) if offset + *len == allocated => {
+                    *len = self.size - offset;
+                }
+                _ => holes.push((allocated, self.size - allocated)),
+            }
+        }
+        Ok(holes)
+    }
+
+    /// `lseek(SEEK_DATA)`: the first offset at or after `offset` that isn't in a hole, None if
+    /// there's no data past `offset`
+    pub fn seek_data(&self, reader: &mut ZfsReader, offset: u64) -> Result<Option<u64>, String> {
+        let mut offset = offset;
+        for (start, len) in try!(self.holes(reader)) {
+            if start > offset {
+                break;
+            }
+            offset = cmp::max(offset, start + len);
+        }
+        Ok(if offset < self.size { Some(offset) } else { None })
+    }
+
+    /// `lseek(SEEK_HOLE)`: the first offset at or after `offset` that is in a hole. The end of the
+    /// file counts as a hole. None if `offset` is past the end of the file.
+    pub fn seek_hole(&self, reader: &mut ZfsReader, offset: u64) -> Result<Option<u64>, String> {
+        if offset >= self.size {
+            return Ok(None);
+        }
+        let hole = try!(self.holes(reader))
+                       .into_iter()
+                       .find(|&(start, len)| start + len > offset)
+                       .map(|(start, _)| cmp::max(start, offset));
+        Ok(Some(hole.unwrap_or(self.size)))
+    }
+
+    /// The names of the file's extended attributes, those kept in its system attributes first.
+    /// They're named as Linux keeps them: attributes of the `user.` namespace without the prefix,
+    /// those of the others with it, like the POSIX ACL in `system.posix_acl_access`.
+    pub fn xattrs(&self, reader: &mut ZfsReader, dataset: &Dataset) -> Result<Vec<String>, String> {
+        let (sa_xattrs, dir) = try!(file_xattrs(reader, dataset, self.object, &self.dnode));
+        let mut names: Vec<String> = sa_xattrs.into_iter().map(|(name, _)| name).collect();
+        if let Some(dir) = dir {
+            for (name, _) in try!(zap::iter(reader, &dataset.objset, dir)) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// The value of the file's extended attribute `name`, None if it has none of that name
+    pub fn get_xattr(&self,
+                     reader: &mut ZfsReader,
+                     dataset: &Dataset,
+                     name: &str)
+                     -> Result<Option<Vec<u8>>, String> {
+        let (sa_xattrs, dir) = try!(file_xattrs(reader, dataset, self.object, &self.dnode));
+        if let Some((_, value)) = sa_xattrs.into_iter().find(|&(ref xattr, _)| xattr == name) {
+            return Ok(Some(value));
+        }
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        let object = match zap::lookup(reader, &dataset.objset, dir, name) {
+            Ok(value) => dirent_object(value),
+            Err(_) => return Ok(None),
+        };
+        // The value is the data of a file in the directory
+        let dnode = try!(dataset.objset.dnode(reader, object));
+        let size = try!(stat_dnode(reader, dataset, object, &dnode)).size;
+        if size == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        dmu::read(reader, &dnode, 0, size as usize).map(Some)
+    }
+}
+
+/// A byte range of a file, as `compare` found it in the two versions
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub offset: u64,
+    pub len: u64,
+    pub changed: bool,
+}
+
+/// The outcome of `compare`: the file in regions that are either identical or changed, in order
+/// and without gaps up to the end of the longer version
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub regions: Vec<Region>,
+    /// Data blocks that had to be read, because their block pointers couldn't tell
+    pub blocks_read: u64,
+}
+
+impl Comparison {
+    pub fn is_identical(&self) -> bool {
+        self.regions.iter().all(|region| !region.changed)
+    }
+
+    /// Add `len` bytes at the end, merged with the last region if it's the same kind
+    fn push(&mut self, len: u64, changed: bool) {
+        if len == 0 {
+            return;
+        }
+        if let Some(last) = self.regions.last_mut() {
+            if last.changed == changed {
+                last.len += len;
+                return;
+            }
+        }
+        let offset = self.regions.last().map_or(0, |last| last.offset + last.len);
+        self.regions.push(Region {
+            offset: offset,
+            len: len,
+            changed: changed,
+        });
+    }
+}
+
+/// A block of the tree of one of the files `compare` walks. The shorter tree is walked as if it
+/// had as many levels as the taller one, with the levels it's missing above its dnode.
+#[derive(Copy, Clone)]
+enum CompareNode {
+    Block(BlockPtr),
+    Hole,
+    /// The dnode itself, one level above its block pointers
+    Dnode,
+    /// A level above the dnode, with the dnode (or the level below) as its first child
+    Above,
+}
+
+/// Bytes `compare` reads at a time of files it has to read whole
+const COMPARE_CHUNK: u64 = 128 << 10;
+
+/// Compare the file at `path_a` of `a` with the one at `path_b` of `b`, e.g. the same file in
+/// two snapshots, or in a dataset and its replica. See `compare_files`.
+pub fn compare(reader: &mut ZfsReader,
+               a: &Dataset,
+               path_a: &str,
+               b: &Dataset,
+               path_b: &str)
+               -> Result<Comparison, String> {
+    let a = try!(open(reader, a, path_a));
+    let b = try!(open(reader, b, path_b));
+    compare_files(reader, &a, &b)
+}
+
+/// Compare two versions of a file block by block, from their block pointers: a block with the
+/// same address and birth txg in both is the same block, and so is everything under it, so the
+/// unchanged parts of snapshots of a file are skipped whole. Data blocks at different addresses
+/// are the same if their checksums are, as after replication. Data is only read for blocks
+/// compressed differently, and for files whose block sizes don't match, which are read whole.
+pub fn compare_files(reader: &mut ZfsReader,
+                     a: &FileHandle,
+                     b: &FileHandle)
+                     -> Result<Comparison, String> {
+    let mut comparison = Comparison {
+        regions: Vec::new(),
+        blocks_read: 0,
+    };
+    let (common, end) = (cmp::min(a.size, b.size), cmp::max(a.size, b.size));
+    let block_size = a.dnode.data_block_size();
+    // Changes replayed from the intent log aren't in the block pointers
+    if block_size == 0 || block_size != b.dnode.data_block_size() ||
+       a.dnode.indblkshift != b.dnode.indblkshift || a.log.is_some() || b.log.is_some() {
+        let mut offset = 0;
+        while offset < common {
+            let len = cmp::min(COMPARE_CHUNK, common - offset);
+            let same = try!(a.read(reader, offset, len as usize)) ==
+                       try!(b.read(reader, offset, len as usize));
+            comparison.blocks_read += 2 * ((len + block_size - 1) / cmp::max(block_size, 1));
+            comparison.push(len, !same);
+            offset += len;
+        }
+    } else if common > 0 {
+        let levels = cmp::max(a.dnode.nlevels, b.dnode.nlevels) as u64;
+        let top = |dnode: &DNodePhys| {
+            match dnode.nlevels as u64 {
+                0 => CompareNode::Hole,
+                nlevels if nlevels == levels => CompareNode::Dnode,
+                _ => CompareNode::Above,
+            }
+        };
+        let blocks = (common - 1) / block_size + 1;
+        try!(compare_nodes(reader,
+                           (a, top(&a.dnode)),
+                           (b, top(&b.dnode)),
+                           levels,
+                           0,
+                           blocks,
+                           &mut comparison));
+        // The last block may go past the end of the shorter version
+        if let Some(last) = comparison.regions.last_mut() {
+            last.len -= blocks * block_size - common;
+        }
+        comparison.regions.retain(|region| region.len > 0);
+    }
+    comparison.push(end - common, true);
+    Ok(comparison)
+}
+
+/// The children of `node`, at `level` of the tree of `file`
+fn compare_children(reader: &mut ZfsReader,
+                    file: &FileHandle,
+                    node: CompareNode,
+                    level: u64)
+                    -> Result<Vec<CompareNode>, String> {
+    let mut children = vec![CompareNode::Hole; 1 << file.dnode.epbs()];
+    match node {
+        CompareNode::Block(block_ptr) => {
+            let data = try!(reader.read_block(&block_ptr).map_err(|e| format!("{:?}", e)));
+            for (i, child) in children.iter_mut().enumerate().take(data.len() / 128) {
+                let block_ptr = try!(BlockPtr::from_bytes_order(&data[i * 128..],
+                                                                block_ptr.data_order())
+                                         .map_err(|e| format!("{:?}", e)));
+                if !block_ptr.is_hole() {
+                    *child = CompareNode::Block(block_ptr);
+                }
+            }
+        }
+        CompareNode::Hole => {}
+        CompareNode::Dnode => {
+            for (i, child) in children.iter_mut().enumerate().take(file.dnode.nblkptr as usize) {
+                let block_ptr = file.dnode.get_blockptr(i);
+                if !block_ptr.is_hole() {
+                    *child = CompareNode::Block(block_ptr);
+                }
+            }
+        }
+        CompareNode::Above => {
+            children[0] = if level - 1 == file.dnode.nlevels as u64 {
+                CompareNode::Dnode
+            } else {
+                CompareNode::Above
+            };
+        }
+    }
+    Ok(children)
+}
+
+/// Whether the block pointers tell the two blocks at `level` apart, or not: None if it takes
+/// looking under them, or at their data
+fn compare_block_ptrs(a: &BlockPtr, b: &BlockPtr, level: u64) -> Option<bool> {
+    if a.is_embedded() || b.is_embedded() {
+        return Some(a.is_embedded() && b.is_embedded() && a.as_bytes() == b.as_bytes());
+    }
+    let (a_dvas, b_dvas) = (a.dvas, b.dvas);
+    let (a_birth, b_birth) = (a.birth_txg, b.birth_txg);
+    if a_dvas[0] == b_dvas[0] && a_birth == b_birth {
+        return Some(true);
+    }
+    if level > 0 {
+        return None;
+    }
+    let (a_sum, b_sum) = (a.checksum, b.checksum);
+    if a.lsize() != b.lsize() {
+        Some(false)
+    } else if a.compression() != b.compression() {
+        None
+    } else {
+        Some(a.checksum() == b.checksum() && a_sum == b_sum)
+    }
+}
+
+/// Compare the subtrees under `a` and `b` at `level`, which start at block `first`, up to block
+/// `end`
+fn compare_nodes(reader: &mut ZfsReader,
+                 a: (&FileHandle, CompareNode),
+                 b: (&FileHandle, CompareNode),
+                 level: u64,
+                 first: u64,
+                 end: u64,
+                 comparison: &mut Comparison)
+                 -> Result<(), String> {
+    let block_size = a.0.dnode.data_block_size();
+    let span = 1u64.checked_shl((a.0.dnode.epbs() * level) as u32).unwrap_or(u64::max_value());
+    let blocks = cmp::min(span, end - first);
+    let same = match (a.1, b.1) {
+        (CompareNode::Hole, CompareNode::Hole) => Some(true),
+        (CompareNode::Block(a), CompareNode::Block(b)) => compare_block_ptrs(&a, &b, level),
+        (CompareNode::Block(_), CompareNode::Hole) |
+        (CompareNode::Hole, CompareNode::Block(_)) if level == 0 => Some(false),
+        _ => None,
+    };
+    if let Some(same) = same {
+        comparison.push(blocks * block_size, !same);
+        return Ok(());
+    }
+
+    if level == 0 {
+        // Only blocks compressed differently get here
+        let same = try!(dmu::read_block(reader, &a.0.dnode, first)) ==
+                   try!(dmu::read_block(reader, &b.0.dnode, first));
+        comparison.blocks_read += 2;
+        comparison.push(block_size, !same);
+        return Ok(());
+    }
+    let a_children = try!(compare_children(reader, a.0, a.1, level));
+    let b_children = try!(compare_children(reader, b.0, b.1, level));
+    let child_span = span >> a.0.dnode.epbs();
+    for (i, (a_child, b_child)) in a_children.into_iter().zip(b_children).enumerate() {
+        let child_first = first + i as u64 * child_span;
+        if child_first >= end {
+            break;
+        }
+        try!(compare_nodes(reader,
+                           (a.0, a_child),
+                           (b.0, b_child),
+                           level - 1,
+                           child_first,
+                           end,
+                           comparison));
+    }
+    Ok(())
+}
+
+/// Symlinks followed while resolving a single path before giving up (`MAXSYMLINKS`)
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Resolve `path` (relative to the root of `dataset`) to the object number of its dnode.
+/// Symlinks in the middle of the path are always followed, one in the last component only if
+/// `follow` is set. Absolute targets are resolved from the root of `dataset`, not of the host.
+pub fn lookup(reader: &mut ZfsReader,
+              dataset: &Dataset,
+              path: &str,
+              follow: bool)
+              -> Result<u64, String> {
+    let objset = &dataset.objset;
+    let root = try!(zap::lookup(reader, objset, MASTER_NODE_OBJ, "ROOT"));
+    let mt = match_type(reader, dataset);
+
+    let mut components = VecDeque::new();
+    push_components(path, &mut components);
+    let mut symlinks = 0;
+    let mut object = root;
+    let mut dnode = try!(objset.dnode(reader, object));
+    while let Some(name) = components.pop_front() {
+        if dnode.object_type != ObjectType::DirectoryContents as u8 {
+            return Err(format!("{}: not a directory", path));
+        }
+
+        let child = if name == ".." {
+            if object == root {
+                root
+            } else {
+                try!(stat_dnode(reader, dataset, object, &dnode)).parent
+            }
+        } else {
+            let value = try!(lookup_entry(reader, dataset, object, &dnode, &name, mt)
+                                 .map_err(|_| format!("{}: no such file or directory", path)));
+            if FileType::from_dirent(value) == FileType::Symlink &&
+               (follow || !components.is_empty()) {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(format!("{}: too many levels of symbolic links", path));
+                }
+                let target = try!(readlink_object(reader, dataset, dirent_object(value)));
+                if push_components(&target, &mut components) {
+                    object = root;
+                    dnode = try!(objset.dnode(reader, object));
+                }
+                // Carry on from the directory holding the link
+                continue;
+            }
+            dirent_object(value)
+        };
+        object = child;
+        dnode = try!(objset.dnode(reader, object));
+    }
+
+    Ok(object)
+}
+
+/// How names are matched in the directories of `dataset`: as the ZAP of each directory
+/// normalizes them, by the `normalization` property and folding case unless the dataset is case
+/// sensitive. Datasets of mixed case sensitivity only match names of another case for SMB.
+fn match_type(reader: &mut ZfsReader, dataset: &Dataset) -> zap::MatchType {
+    match zap::lookup(reader, &dataset.objset, MASTER_NODE_OBJ, "casesensitivity") {
+        Ok(ZFS_CASE_MIXED) => zap::MatchType::NormalizeMatchCase,
+        _ => zap::MatchType::Normalize,
+    }
+}
+
+/// The ZAP value of `name` in directory `dir`, whose dnode is `dnode`, through the dentry cache.
+/// Names are matched as `mt` says.
+fn lookup_entry(reader: &mut ZfsReader,
+                dataset: &Dataset,
+                dir: u64,
+                dnode: &DNodePhys,
+                name: &str,
+                mt: zap::MatchType)
+                -> Result<u64, String> {
+    let dir_txg = dcache::dir_txg(dnode);
+    if let Some(value) = reader.dcache.lookup(dataset.object, dir, dir_txg, name) {
+        return Ok(value);
+    }
+    let (_, value) = try!(zap::lookup_match(reader, &dataset.objset, dir, name, mt));
+    reader.dcache.insert(dataset.object, dir, dir_txg, name, value);
+    Ok(value)
+}
+
+/// Put the components of `path` in front of `components`. Returns whether `path` is absolute.
+fn push_components(path: &str, components: &mut VecDeque<String>) -> bool {
+    for name in path.split('/').rev() {
+        if !name.is_empty() && name != "." {
+            components.push_front(name.to_owned());
+        }
+    }
+    path.starts_with('/')
+}
+
+/// The target of the symlink at `path` of `dataset`
+pub fn readlink(reader: &mut ZfsReader, dataset: &Dataset, path: &str) -> Result<String, String> {
+    let object = try!(lookup(reader, dataset, path, false));
+    readlink_object(reader, dataset, object)
+}
+
+/// The target of symlink `object`. Short targets are kept with the file's metadata (after the
+/// legacy znode in the bonus buffer, or as a system attribute), longer ones in the file's data.
+pub fn readlink_object(reader: &mut ZfsReader,
+                       dataset: &Dataset,
+                       object: u64)
+                       -> Result<String, String> {
+    let dnode = try!(dataset.objset.dnode(reader, object));
+    let stat = try!(stat_dnode(reader, dataset, object, &dnode));
+    if stat.file_type() != FileType::Symlink {
+        return Err(format!("Object {} is not a symlink", object));
+    }
+
+    let size = stat.size as usize;
+    let embedded = if dnode.bonus_type == ObjectType::ZNode as u8 {
+        let bonus = dnode.get_bonus();
+        let bonus_len = dnode.bonus_len as usize;
+        if ZNODE_PHYS_SIZE + size <= cmp::min(bonus_len, bonus.len()) {
+            Some(bonus[ZNODE_PHYS_SIZE..ZNODE_PHYS_SIZE + size].to_vec())
+        } else {
+            None
+        }
+    } else {
+        let attrs = try!(sa_attrs(reader, dataset, object, &dnode));
+        attrs.get("ZPL_SYMLINK").map(|target| target.to_vec())
+    };
+    let target = match embedded {
+        Some(target) => target,
+        None => try!(dmu::read(reader, &dnode, 0, size)),
+    };
+    String::from_utf8(target).map_err(|_| format!("Symlink {} is not UTF-8", object))
+}
+
+/// Deepest directory nesting `object_path` follows before assuming a parent loop: a path of
+/// single letter names, as long as a path can be
+const MAX_PATH_DEPTH: usize = 4096 / 2;
+
+/// The path of object `object` of `dataset`, found by following the parent links up to the root
+/// and looking for the object in each parent directory. Of the paths of a file with several hard
+/// links, only the one in the directory it was created in (or last linked into) is found.
+pub fn object_path(reader: &mut ZfsReader,
+                   dataset: &Dataset,
+                   object: u64)
+                   -> Result<String, String> {
+    let objset = &dataset.objset;
+    let root = try!(zap::lookup(reader, objset, MASTER_NODE_OBJ, "ROOT"));
+
+    let mut names = Vec::new();
+    let mut child = object;
+    while child != root {
+        if names.len() >= MAX_PATH_DEPTH {
+            return Err(format!("Object {} is too deep, the parent links loop", object));
+        }
+        let parent = try!(stat_object(reader, dataset, child)).parent;
+        let name = try!(zap::iter(reader, objset, parent))
+                       .find(|&(_, value)| dirent_object(value) == child)
+                       .map(|(name, _)| name);
+        names.push(try!(name.ok_or(format!("Object {} isn't in its parent directory {}",
+                                           child,
+                                           parent))));
+        child = parent;
+    }
+
+    names.reverse();
+    Ok(format!("/{}", names.join("/")))
+}
+
+/// List the directory at `path` of `dataset`. `.` and `..` are not included. The dnodes of the
+/// entries are prefetched.
+pub fn readdir(reader: &mut ZfsReader,
+               dataset: &Dataset,
+               path: &str)
+               -> Result<Vec<DirEntry>, String> {
+    let object = try!(lookup(reader, dataset, path, true));
+    let dnode = try!(dataset.objset.dnode(reader, object));
+    if dnode.object_type != ObjectType::DirectoryContents as u8 {
+        return Err(format!("{}: not a directory", path));
+    }
+    let entries: Vec<DirEntry> = try!(zap::iter(reader, &dataset.objset, object))
+                                     .map(|(name, value)| DirEntry::from_zap(name, value))
+                                     .collect();
+
+    // Callers usually go on to look at every entry
+    let objects: Vec<u64> = entries.iter().map(|entry| entry.object).collect();
+    try!(dataset.objset.prefetch_dnodes(reader, &objects));
+
+    Ok(entries)
+}
+
+/// Whether directory `object` of `dataset` has no entries (besides `.` and `..`), without
+/// listing them: see `zap::count`
+pub fn dir_is_empty(reader: &mut ZfsReader,
+                    dataset: &Dataset,
+                    object: u64)
+                    -> Result<bool, String> {
+    let dnode = try!(dataset.objset.dnode(reader, object));
+    if dnode.object_type != ObjectType::DirectoryContents as u8 {
+        return Err(format!("Object {} is not a directory", object));
+    }
+    Ok(try!(zap::count(reader, &dataset.objset, object)) == 0)
+}
+
+/// Open the regular file at `path` of `dataset` for reading. Devices, fifos and sockets are
+/// refused even though their dnodes look like plain files.
+pub fn open(reader: &mut ZfsReader, dataset: &Dataset, path: &str) -> Result<FileHandle, String> {
+    let object = try!(lookup(reader, dataset, path, true));
+    let dnode = try!(dataset.objset.dnode(reader, object));
+    let stat = try!(stat_dnode(reader, dataset, object, &dnode));
+    if dnode.object_type != ObjectType::PlainFileContents as u8 ||
+       stat.file_type() != FileType::Regular {
+        return Err(format!("{}: not a regular file", path));
+    }
+    let size = stat.size;
+
+    Ok(FileHandle {
+        object: object,
+        dnode: dnode,
+        size: size,
+        log: dataset.zil.object(object).cloned(),
+    })
+}
+
+/// The metadata of the file at `path` of `dataset`, following symlinks
+pub fn stat(reader: &mut ZfsReader, dataset: &Dataset, path: &str) -> Result<Stat, String> {
+    let object = try!(lookup(reader, dataset, path, true));
+    stat_object(reader, dataset, object)
+}
+
+/// The metadata of the file at `path` of `dataset`. A symlink in the last component isn't
+/// followed.
+pub fn lstat(reader: &mut ZfsReader, dataset: &Dataset, path: &str) -> Result<Stat, String> {
+    let object = try!(lookup(reader, dataset, path, false));
+    stat_object(reader, dataset, object)
+}
+
+/// The metadata of object `object` of `dataset`
+pub fn stat_object(reader: &mut ZfsReader, dataset: &Dataset, object: u64) -> Result<Stat, String> {
+    let dnode = try!(dataset.objset.dnode(reader, object));
+    stat_dnode(reader, dataset, object, &dnode)
+}
+
+/// The metadata of object `object` of `dataset`, whose dnode was read already
+pub fn stat_dnode(reader: &mut ZfsReader,
+                  dataset: &Dataset,
+                  object: u64,
+                  dnode: &DNodePhys)
+                  -> Result<Stat, String> {
+    let mut stat = if dnode.bonus_type == ObjectType::ZNode as u8 {
+        let znode = try!(ZNodePhys::from_bytes(dnode.get_bonus()));
+        Stat::from_znode(object, &znode)
+    } else if dnode.bonus_type == sa::DMU_OT_SA {
+        let attrs = try!(sa_attrs(reader, dataset, object, dnode));
+        try!(Stat::from_attrs(object, &attrs))
+    } else {
+        return Err(format!("Object {} is not a file", object));
+    };
+    stat.blocks = dnode.fill_count();
+    if let Some(log) = dataset.zil.object(object) {
+        stat.size = log.size(stat.size);
+    }
+    Ok(stat)
+}
+
+/// Regenerate the user/group space and object accounting of `dataset`, e.g. when
+/// `userobj_accounting` was just enabled, from the owner of every file
+pub fn rebuild_user_accounting(reader: &mut ZfsReader,
+                               dataset: &Dataset)
+                               -> Result<UserAccounting, String> {
+    let objset = &dataset.objset;
+    // Object sets written before system attributes have no registry
+    let registry = match zap::lookup(reader, objset, MASTER_NODE_OBJ, "SA_ATTRS") {
+        Ok(sa_obj) => Some(try!(sa::Registry::load(reader, objset, sa_obj))),
+        Err(_) => None,
+    };
+
+    objset.rebuild_user_accounting(reader, |reader, object, dnode| {
+        if dnode.bonus_type == ObjectType::ZNode as u8 {
+            return ZNodePhys::from_bytes(dnode.get_bonus())
+                       .ok()
+                       .map(|znode| (znode.uid, znode.gid));
+        }
+        match registry {
+            Some(ref registry) if dnode.bonus_type == sa::DMU_OT_SA => {
+                let extra = match dnode_extra(reader, objset, object, dnode) {
+                    Ok(extra) => extra,
+                    Err(_) => return None,
+                };
+                registry.dnode_attrs(reader, dnode, &extra).ok().and_then(|attrs| {
+                    match (attrs.get_u64("ZPL_UID"), attrs.get_u64("ZPL_GID")) {
+                        (Some(uid), Some(gid)) => Some((uid, gid)),
+                        _ => None,
+                    }
+                })
+            }
+            // Not a file: ZAPs and other internal objects aren't charged to anyone
+            _ => None,
+        }
+    })
+}
+
+fn sa_attrs(reader: &mut ZfsReader,
+            dataset: &Dataset,
+            object: u64,
+            dnode: &DNodePhys)
+            -> Result<sa::Attrs, String> {
+    // TODO: Cache the registry per dataset
+    let objset = &dataset.objset;
+    let sa_obj = try!(zap::lookup(reader, objset, MASTER_NODE_OBJ, "SA_ATTRS"));
+    let registry = try!(sa::Registry::load(reader, objset, sa_obj));
+    let extra = try!(dnode_extra(reader, objset, object, dnode));
+    registry.dnode_attrs(reader, dnode, &extra)
+}
+
+/// The extended attributes of file `object`, whose dnode is `dnode`: those packed in its system
+/// attributes (`xattr=sa`), and the hidden directory holding the others as files, if it has one
+fn file_xattrs(reader: &mut ZfsReader,
+               dataset: &Dataset,
+               object: u64,
+               dnode: &DNodePhys)
+               -> Result<(Vec<(String, Vec<u8>)>, Option<u64>), String> {
+    let (sa_xattrs, dir) = if dnode.bonus_type == ObjectType::ZNode as u8 {
+        (Vec::new(), try!(ZNodePhys::from_bytes(dnode.get_bonus())).xattr)
+    } else if dnode.bonus_type == sa::DMU_OT_SA {
+        let attrs = try!(sa_attrs(reader, dataset, object, dnode));
+        let sa_xattrs = match attrs.get("ZPL_DXATTR") {
+            Some(packed) => try!(decode_sa_xattrs(packed)),
+            None => Vec::new(),
+        };
+        (sa_xattrs, attrs.get_u64("ZPL_XATTR").unwrap_or(0))
+    } else {
+        return Err(format!("Object {} is not a file", object));
+    };
+    Ok((sa_xattrs, if dir == 0 { None } else { Some(dir) }))
+}
+
+/// Decode the extended attributes packed in a `ZPL_DXATTR` system attribute, an XDR nvlist of
+/// byte arrays
+fn decode_sa_xattrs(packed: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let nv = try!(spa_config::decode(&mut packed.to_vec())
+                      .map_err(|_| "Bad packed extended attributes".to_owned()));
+    let mut xattrs = Vec::new();
+    for (name, value) in nv.pairs {
+        match value {
+            NvValue::ByteArray(value) | NvValue::Uint8Array(value) => xattrs.push((name, value)),
+            _ => return Err(format!("Extended attribute {} is not a byte array", name)),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// The slots after the first one of `dnode`, object `object`, if it's a large dnode
+fn dnode_extra(reader: &mut ZfsReader,
+               objset: &ObjectSet,
+               object: u64,
+               dnode: &DNodePhys)
+               -> Result<Vec<u8>, String> {
+    if dnode.extra_slots == 0 {
+        return Ok(Vec::new());
+    }
+    objset.dnode_extra(reader, object).map(|(_, extra)| extra)
+}
+
+/// Lay out an empty filesystem in the new objset of the dataset with MOS object `objset`, in txg
+/// `txg` (`zfs_create_fs`): the master node, the delete queue for files unlinked while open, and
+/// the root directory. It's of `ZPL_VERSION_USERSPACE`, so the root directory has a legacy znode.
+pub fn create_fs(dsl_pool: &mut DslPool, txg: u64, objset: u64) {
+    const DELETE_QUEUE_OBJ: u64 = 2;
+    const ROOT_OBJ: u64 = 3;
+    // Any salt will do, it only has to stay the same for the life of the ZAP
+    let salt = |object: u64| (txg << 32 ^ objset << 16 ^ object) | 1;
+    // "." and ".." make for its size and links
+    let bonus = new_znode(S_IFDIR | 0o755, 2, 2, ROOT_OBJ, txg);
+
+    let master = [("VERSION", ZPL_VERSION_USERSPACE),
+                  ("DELETE_QUEUE", DELETE_QUEUE_OBJ),
+                  ("ROOT", ROOT_OBJ)];
+    let objects = [(MASTER_NODE_OBJ, ObjectType::MasterNode, &master[..], &[][..]),
+                   (DELETE_QUEUE_OBJ, ObjectType::DeleteQueue, &[], &[]),
+                   (ROOT_OBJ, ObjectType::DirectoryContents, &[], &bonus[..])];
+    dsl_pool.create_objset(txg, objset, ObjectSetType::Zfs);
+    for &(object, object_type, entries, bonus) in &objects {
+        let block = zap::mzap_block(entries, salt(object)).unwrap();
+        let bonus_type = if bonus.is_empty() { ObjectType::None } else { ObjectType::ZNode };
+        let dnode = DNodePhys::new(object_type, block.len() as u64, bonus_type, bonus);
+        dsl_pool.dirty_dnode(txg, objset, object, dnode);
+        dsl_pool.dirty_block(txg, objset, object, 0, block);
+    }
+}
+
+/// The bonus buffer of a new file with a legacy znode, created now in txg `txg`. The ACL after
+/// the znode is left empty.
+fn new_znode(mode: u64, size: u64, links: u64, parent: u64, txg: u64) -> Vec<u8> {
+    let now = now();
+    let mut znode = ZNodePhys::from_bytes(&[0; ZNODE_PHYS_SIZE]).unwrap();
+    znode.atime = now;
+    znode.mtime = now;
+    znode.ctime = now;
+    znode.crtime = now;
+    znode.gen = txg;
+    znode.mode = mode;
+    znode.size = size;
+    znode.parent = parent;
+    znode.links = links;
+    let mut bonus = znode.as_bytes().to_vec();
+    bonus.resize(ZNODE_PHYS_SIZE, 0);
+    bonus
+}
+
+/// The time as a ZPL timestamp
+fn now() -> [u64; 2] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    [now.as_secs(), now.subsec_nanos() as u64]
+}
+
+/// Create an empty regular file at `path` of `dataset` with permissions `mode` (`zfs_create`), in
+/// a transaction of its own in the open txg of `dsl_pool`. The directory it goes in has to exist
+/// and can't have an entry of that name yet, names matched as `lookup` matches them, or this
+/// fails with `Exists`; a name the dataset's `NameRules` don't take fails with `Invalid`. Returns
+/// the object number of the new file.
+///
+/// Like the rest of the write path, this takes `dataset` as it was opened after the last sync,
+/// and finds what the txgs in flight change of it in `dsl_pool`.
+pub fn create(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              mos: &ObjectSet,
+              dataset: &Dataset,
+              path: &str,
+              mode: u64)
+              -> zfs::Result<u64> {
+    create_node(reader, dsl_pool, mos, dataset, path, S_IFREG | mode & 0o7777)
+}
+
+/// Create an empty directory at `path` of `dataset` with permissions `mode` (`zfs_mkdir`), like
+/// `create` does files. Its `..` is one more link to the directory it goes in.
+pub fn mkdir(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             mos: &ObjectSet,
+             dataset: &Dataset,
+             path: &str,
+             mode: u64)
+             -> zfs::Result<u64> {
+    create_node(reader, dsl_pool, mos, dataset, path, S_IFDIR | mode & 0o7777)
+}
+
+/// Create a regular file or a directory, by the file type bits of `mode`
+fn create_node(reader: &mut ZfsReader,
+               dsl_pool: &mut DslPool,
+               mos: &ObjectSet,
+               dataset: &Dataset,
+               path: &str,
+               mode: u64)
+               -> zfs::Result<u64> {
+    let (dir_path, name) = split_path(path);
+    let rules = try!(NameRules::for_dataset(reader, mos, dataset).map_err(|_| zfs::Error::Invalid));
+    try!(rules.check(name.as_bytes()).map_err(|_| zfs::Error::Invalid));
+    let dir = try!(pending_lookup(reader, dsl_pool, dataset, dir_path));
+    let dir_dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+    if dir_dnode.object_type != ObjectType::DirectoryContents as u8 {
+        return Err(zfs::Error::Invalid);
+    }
+    // TODO: lay out the system attributes of new files on datasets of later ZPL versions
+    if dir_dnode.bonus_type != ObjectType::ZNode as u8 {
+        return Err(zfs::Error::UnsupportedFeature);
+    }
+    // A name that only differs from an entry's in normalization or case is taken too
+    if try!(pending_entry(reader, dsl_pool, dataset, dir, name)).is_some() {
+        return Err(zfs::Error::Exists);
+    }
+
+    let mut tx = Tx::create(dataset.object);
+    tx.hold_zap(dir, true, Some(name));
+    try!(dsl_pool.check_space(dataset, tx.space_needed()));
+    let txg = try!(tx.assign(dsl_pool));
+    let result = create_tx(reader, dsl_pool, dataset, dir, name, mode, &tx, txg);
+    tx.commit(dsl_pool);
+    result
+}
+
+fn create_tx(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             dir: u64,
+             name: &str,
+             mode: u64,
+             tx: &Tx,
+             txg: u64)
+             -> zfs::Result<u64> {
+    let is_dir = FileType::from_mode(mode) == FileType::Directory;
+    let object = if is_dir {
+        // "." and ".." make for its size and links
+        let bonus = new_znode(mode, 2, 2, dir, txg);
+        let dnode = DNodePhys::new(ObjectType::DirectoryContents, 512, ObjectType::ZNode, &bonus);
+        let object = try!(dmu::create_object(reader, dsl_pool, &dataset.objset, dnode, tx));
+        // Any salt will do, it only has to stay the same for the life of the ZAP
+        let block = try!(zap::mzap_block(&[], (txg << 32 ^ object) | 1)
+                             .ok_or(zfs::Error::Invalid));
+        dsl_pool.dirty_block(txg, dataset.object, object, 0, block);
+        object
+    } else {
+        let bonus = new_znode(mode, 0, 1, dir, txg);
+        let dnode = DNodePhys::new(ObjectType::PlainFileContents, 512, ObjectType::ZNode, &bonus);
+        try!(dmu::create_object(reader, dsl_pool, &dataset.objset, dnode, tx))
+    };
+    let value = object | (mode >> 12 & 0xF) << 60;
+    if let Err(e) = zap::add(reader, dsl_pool, &dataset.objset, dir, name, value, tx) {
+        dsl_pool.free_object(txg, dataset.object, object);
+        return Err(e);
+    }
+
+    // One more entry, read after the ZAP changed, and a new directory's ".." links back to it
+    let mut dir_dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+    let stat = try!(pending_stat(reader, dataset, dir, &dir_dnode));
+    let links = if is_dir { stat.links + 1 } else { stat.links };
+    let now = now();
+    try!(set_attrs(reader,
+                   dataset,
+                   &mut dir_dnode,
+                   &[("ZPL_SIZE", &[stat.size + 1]),
+                     ("ZPL_LINKS", &[links]),
+                     ("ZPL_MTIME", &now),
+                     ("ZPL_CTIME", &now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, dir, dir_dnode);
+    Ok(object)
+}
+
+/// Write `data` at `offset` of regular file `object` of `dataset` (`zfs_write`), in a
+/// transaction of its own. The file grows to take the write in. While the file has a single
+/// block, the block grows first, up to the record size. Fails with `QuotaExceeded` if the
+/// dataset's quotas don't leave room for the write. The write is logged to the dataset's intent
+/// log, for `fsync` to commit.
+pub fn write(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             object: u64,
+             offset: u64,
+             data: &[u8])
+             -> zfs::Result<()> {
+    let dnode = try!(pending_file(reader, dsl_pool, dataset, object));
+    let end = try!(offset.checked_add(data.len() as u64).ok_or(zfs::Error::Invalid));
+    let block_size = dnode.data_block_size();
+    let single_block = dnode.maxblkid == 0 &&
+                       dsl_pool.pending_max_blkid(dataset.object, object).unwrap_or(0) == 0;
+    let grow_to = if single_block && end > block_size && block_size < ZPL_RECORDSIZE {
+        Some(cmp::min(end.next_power_of_two(), ZPL_RECORDSIZE))
+    } else {
+        None
+    };
+
+    let mut tx = Tx::create(dataset.object);
+    tx.hold_write(object, offset, data.len() as u64);
+    if let Some(grow_to) = grow_to {
+        tx.hold_write(object, 0, grow_to);
+    }
+    try!(dsl_pool.check_space(dataset, tx.space_needed()));
+    let txg = try!(tx.assign(dsl_pool));
+    let result = write_tx(reader, dsl_pool, dataset, object, offset, data, grow_to, &tx, txg);
+    if result.is_ok() {
+        dsl_pool.zil(dataset.object).log_write(txg, object, offset, data, false);
+    }
+    tx.commit(dsl_pool);
+    result
+}
+
+fn write_tx(reader: &mut ZfsReader,
+            dsl_pool: &mut DslPool,
+            dataset: &Dataset,
+            object: u64,
+            offset: u64,
+            data: &[u8],
+            grow_to: Option<u64>,
+            tx: &Tx,
+            txg: u64)
+            -> zfs::Result<()> {
+    if let Some(grow_to) = grow_to {
+        // `dmu_object_set_blocksize`: the block is rewritten at its new size
+        let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+        let mut block = match dsl_pool.pending_block(dataset.object, object, 0) {
+            Some(block) => block.to_vec(),
+            None if dnode.nlevels > 0 => {
+                try!(dmu::read_block(reader, &dnode, 0).map_err(|_| zfs::Error::Invalid))
+            }
+            None => Vec::new(),
+        };
+        block.resize(grow_to as usize, 0);
+        dnode.data_blk_sz_sec = (grow_to / 512) as u16;
+        dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+        dsl_pool.dirty_block(txg, dataset.object, object, 0, block);
+    }
+    try!(dmu::write(reader, dsl_pool, &dataset.objset, object, offset, data, tx));
+
+    let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let size = try!(pending_stat(reader, dataset, object, &dnode)).size;
+    let end = offset + data.len() as u64;
+    let now = now();
+    try!(set_attrs(reader,
+                   dataset,
+                   &mut dnode,
+                   &[("ZPL_SIZE", &[cmp::max(size, end)]),
+                     ("ZPL_MTIME", &now),
+                     ("ZPL_CTIME", &now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    Ok(())
+}
+
+/// Change the size of regular file `object` of `dataset` to `size` (`zfs_freesp`), in a
+/// transaction of its own. What's cut off is freed (see `dmu::free`), so a file that grows again
+/// reads zeros past its old end. The truncate is logged like a write.
+pub fn truncate(reader: &mut ZfsReader,
+                dsl_pool: &mut DslPool,
+                dataset: &Dataset,
+                object: u64,
+                size: u64)
+                -> zfs::Result<()> {
+    let dnode = try!(pending_file(reader, dsl_pool, dataset, object));
+    let pending_end = dsl_pool.pending_max_blkid(dataset.object, object)
+                              .map_or(0, |blkid| (blkid + 1) * dnode.data_block_size());
+    let end = cmp::max(dmu::allocated_size(&dnode), pending_end);
+
+    let mut tx = Tx::create(dataset.object);
+    if size < end {
+        tx.hold_write(object, size, end - size);
+    }
+    let txg = try!(tx.assign(dsl_pool));
+    let result = truncate_tx(reader, dsl_pool, dataset, object, size, end, &tx, txg);
+    if result.is_ok() {
+        dsl_pool.zil(dataset.object).log_truncate(txg, object, size, 0);
+    }
+    tx.commit(dsl_pool);
+    result
+}
+
+fn truncate_tx(reader: &mut ZfsReader,
+               dsl_pool: &mut DslPool,
+               dataset: &Dataset,
+               object: u64,
+               size: u64,
+               end: u64,
+               tx: &Tx,
+               txg: u64)
+               -> zfs::Result<()> {
+    if size < end {
+        try!(dmu::free(reader, dsl_pool, &dataset.objset, object, size, end - size, tx));
+    }
+    let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let now = now();
+    try!(set_attrs(reader,
+                   dataset,
+                   &mut dnode,
+                   &[("ZPL_SIZE", &[size]), ("ZPL_MTIME", &now), ("ZPL_CTIME", &now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    Ok(())
+}
+
+/// Free `len` bytes at `offset` of regular file `object` of `dataset`, like `fallocate` with
+/// `FALLOC_FL_PUNCH_HOLE` (`zfs_space`), in a transaction of its own. The range reads as zeros
+/// after; the size stays as it is, and nothing past it is touched. Logged like a truncate.
+pub fn punch_hole(reader: &mut ZfsReader,
+                  dsl_pool: &mut DslPool,
+                  dataset: &Dataset,
+                  object: u64,
+                  offset: u64,
+                  len: u64)
+                  -> zfs::Result<()> {
+    let dnode = try!(pending_file(reader, dsl_pool, dataset, object));
+    let size = try!(pending_stat(reader, dataset, object, &dnode)).size;
+    let end = cmp::min(offset.saturating_add(len), size);
+    if offset >= end {
+        return Ok(());
+    }
+
+    let mut tx = Tx::create(dataset.object);
+    tx.hold_write(object, offset, end - offset);
+    let txg = try!(tx.assign(dsl_pool));
+    let result = punch_hole_tx(reader, dsl_pool, dataset, object, offset, end, &tx, txg);
+    if result.is_ok() {
+        dsl_pool.zil(dataset.object).log_truncate(txg, object, offset, end - offset);
+    }
+    tx.commit(dsl_pool);
+    result
+}
+
+fn punch_hole_tx(reader: &mut ZfsReader,
+                 dsl_pool: &mut DslPool,
+                 dataset: &Dataset,
+                 object: u64,
+                 offset: u64,
+                 end: u64,
+                 tx: &Tx,
+                 txg: u64)
+                 -> zfs::Result<()> {
+    try!(dmu::free(reader, dsl_pool, &dataset.objset, object, offset, end - offset, tx));
+    let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let now = now();
+    try!(set_attrs(reader, dataset, &mut dnode, &[("ZPL_MTIME", &now), ("ZPL_CTIME", &now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    Ok(())
+}
+
+/// Remove the entry at `path` of `dataset` (`zfs_remove`), in a transaction of its own. The file
+/// goes with its last link, or if it's held open, once it's released (see `hold_file`).
+/// Directories aren't removed this way.
+pub fn unlink(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              dataset: &Dataset,
+              path: &str)
+              -> zfs::Result<()> {
+    let (dir_path, name) = split_path(path);
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(zfs::Error::Invalid);
+    }
+    let dir = try!(pending_lookup(reader, dsl_pool, dataset, dir_path));
+    let (name, value) = try!(try!(pending_entry(reader, dsl_pool, dataset, dir, name))
+                                 .ok_or(zfs::Error::NoEntity));
+    // Directories go through `rmdir`
+    if FileType::from_dirent(value) == FileType::Directory {
+        return Err(zfs::Error::Invalid);
+    }
+
+    let object = dirent_object(value);
+    let mut tx = Tx::create(dataset.object);
+    tx.hold_zap(dir, false, Some(&name));
+    if dsl_pool.is_held(dataset.object, object) {
+        tx.hold_zap(try!(delete_queue(reader, dataset)), true, Some(&queue_name(object)));
+    }
+    let txg = try!(tx.assign(dsl_pool));
+    let result = unlink_tx(reader, dsl_pool, dataset, dir, &name, object, false, &tx, txg);
+    tx.commit(dsl_pool);
+    result
+}
+
+/// Remove the directory at `path` of `dataset` (`zfs_rmdir`), in a transaction of its own. It
+/// has to be empty, or this fails with `Exists`. The directory it was in loses the link its `..`
+/// made.
+pub fn rmdir(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             path: &str)
+             -> zfs::Result<()> {
+    let (dir_path, name) = split_path(path);
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(zfs::Error::Invalid);
+    }
+    let dir = try!(pending_lookup(reader, dsl_pool, dataset, dir_path));
+    let (name, value) = try!(try!(pending_entry(reader, dsl_pool, dataset, dir, name))
+                                 .ok_or(zfs::Error::NoEntity));
+    if FileType::from_dirent(value) != FileType::Directory {
+        return Err(zfs::Error::Invalid);
+    }
+    let object = dirent_object(value);
+    let entries = try!(zap::pending_entries(reader,
+                                            dsl_pool,
+                                            &dataset.objset,
+                                            dataset.object,
+                                            object));
+    if !entries.is_empty() {
+        return Err(zfs::Error::Exists);
+    }
+
+    let mut tx = Tx::create(dataset.object);
+    tx.hold_zap(dir, false, Some(&name));
+    let txg = try!(tx.assign(dsl_pool));
+    let result = unlink_tx(reader, dsl_pool, dataset, dir, &name, object, true, &tx, txg);
+    tx.commit(dsl_pool);
+    result
+}
+
+fn unlink_tx(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             dir: u64,
+             name: &str,
+             object: u64,
+             is_dir: bool,
+             tx: &Tx,
+             txg: u64)
+             -> zfs::Result<()> {
+    try!(zap::remove(reader, dsl_pool, &dataset.objset, dir, name, tx));
+    let now = now();
+    let mut dir_dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+    let stat = try!(pending_stat(reader, dataset, dir, &dir_dnode));
+    let links = if is_dir { stat.links.saturating_sub(1) } else { stat.links };
+    try!(set_attrs(reader,
+                   dataset,
+                   &mut dir_dnode,
+                   &[("ZPL_SIZE", &[stat.size.saturating_sub(1)]),
+                     ("ZPL_LINKS", &[links]),
+                     ("ZPL_MTIME", &now),
+                     ("ZPL_CTIME", &now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, dir, dir_dnode);
+
+    let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let links = try!(pending_stat(reader, dataset, object, &dnode)).links;
+    // A directory has no other links than its entry and its own "."
+    if is_dir {
+        dsl_pool.free_object(txg, dataset.object, object);
+    } else if links <= 1 {
+        try!(unlinked(reader, dsl_pool, dataset, object, dnode, &now, tx, txg));
+    } else {
+        try!(set_attrs(reader,
+                       dataset,
+                       &mut dnode,
+                       &[("ZPL_LINKS", &[links - 1]), ("ZPL_CTIME", &now)]));
+        dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    }
+    Ok(())
+}
+
+/// File `object` of `dataset`, whose dnode is `dnode`, lost its last link in the txg `tx` is
+/// assigned to (`zfs_link_destroy`). It's freed, unless it's held open: then it's left without
+/// links on the delete queue, which `tx` has to hold, until `release_file` frees it.
+fn unlinked(reader: &mut ZfsReader,
+            dsl_pool: &mut DslPool,
+            dataset: &Dataset,
+            object: u64,
+            mut dnode: DNodePhys,
+            now: &[u64],
+            tx: &Tx,
+            txg: u64)
+            -> zfs::Result<()> {
+    if !dsl_pool.is_held(dataset.object, object) {
+        dsl_pool.free_object(txg, dataset.object, object);
+        return Ok(());
+    }
+    try!(set_attrs(reader, dataset, &mut dnode, &[("ZPL_LINKS", &[0]), ("ZPL_CTIME", now)]));
+    dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    let queue = try!(delete_queue(reader, dataset));
+    zap::add(reader, dsl_pool, &dataset.objset, queue, &queue_name(object), object, tx)
+}
+
+/// The delete queue of `dataset`: the files unlinked while they were held open
+fn delete_queue(reader: &mut ZfsReader, dataset: &Dataset) -> zfs::Result<u64> {
+    zap::lookup(reader, &dataset.objset, MASTER_NODE_OBJ, "DELETE_QUEUE")
+        .map_err(|_| zfs::Error::NoEntity)
+}
+
+/// The name of the entry of `object` on the delete queue (`zap_add_int`)
+fn queue_name(object: u64) -> String {
+    format!("{:x}", object)
+}
+
+/// Hold regular file `object` of `dataset` open (`zfs_open`), for as long as a frontend has it
+/// open: if its last link goes in the meantime, it stays until `release_file` lets go of the last
+/// hold. The holds are kept in `dsl_pool` and don't outlive it.
+pub fn hold_file(reader: &mut ZfsReader,
+                 dsl_pool: &mut DslPool,
+                 dataset: &Dataset,
+                 object: u64)
+                 -> zfs::Result<()> {
+    try!(pending_file(reader, dsl_pool, dataset, object));
+    dsl_pool.hold_object(dataset.object, object);
+    Ok(())
+}
+
+/// Let go of a hold `hold_file` took (`zfs_close`). Once the last one goes, the file is freed if
+/// it was unlinked in the meantime, taken off the delete queue in a transaction of its own.
+pub fn release_file(reader: &mut ZfsReader,
+                    dsl_pool: &mut DslPool,
+                    dataset: &Dataset,
+                    object: u64)
+                    -> zfs::Result<()> {
+    if !dsl_pool.release_object(dataset.object, object) {
+        return Ok(());
+    }
+    let dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    if try!(pending_stat(reader, dataset, object, &dnode)).links > 0 {
+        return Ok(());
+    }
+    free_unlinked(reader, dsl_pool, dataset, &[object])
+}
+
+/// Free the files left on the delete queue of `dataset` (`zfs_unlinked_drain`), as it's mounted:
+/// they were still open when the pool was last exported, or went down. Those held open through
+/// `dsl_pool` are left to `release_file`.
+pub fn drain_delete_queue(reader: &mut ZfsReader,
+                          dsl_pool: &mut DslPool,
+                          dataset: &Dataset)
+                          -> zfs::Result<()> {
+    let queue = try!(delete_queue(reader, dataset));
+    let objects: Vec<u64> = try!(zap::pending_entries(reader,
+                                                      dsl_pool,
+                                                      &dataset.objset,
+                                                      dataset.object,
+                                                      queue))
+                                .into_iter()
+                                .map(|(_, object)| object)
+                                .filter(|&object| !dsl_pool.is_held(dataset.object, object))
+                                .collect();
+    if objects.is_empty() {
+        return Ok(());
+    }
+    free_unlinked(reader, dsl_pool, dataset, &objects)
+}
+
+/// Take `objects` off the delete queue of `dataset` and free them (`zfs_rmnode`), in a
+/// transaction of their own
+fn free_unlinked(reader: &mut ZfsReader,
+                 dsl_pool: &mut DslPool,
+                 dataset: &Dataset,
+                 objects: &[u64])
+                 -> zfs::Result<()> {
+    let queue = try!(delete_queue(reader, dataset));
+    let mut tx = Tx::create(dataset.object);
+    for &object in objects {
+        tx.hold_zap(queue, false, Some(&queue_name(object)));
+    }
+    let txg = try!(tx.assign(dsl_pool));
+    let result = free_unlinked_tx(reader, dsl_pool, dataset, queue, objects, &tx, txg);
+    tx.commit(dsl_pool);
+    result
+}
+
+fn free_unlinked_tx(reader: &mut ZfsReader,
+                    dsl_pool: &mut DslPool,
+                    dataset: &Dataset,
+                    queue: u64,
+                    objects: &[u64],
+                    tx: &Tx,
+                    txg: u64)
+                    -> zfs::Result<()> {
+    for &object in objects {
+        try!(zap::remove(reader, dsl_pool, &dataset.objset, queue, &queue_name(object), tx));
+        dsl_pool.free_object(txg, dataset.object, object);
+    }
+    Ok(())
+}
+
+/// Commit the writes and truncates logged to the intent log of `dataset` (`zil_commit`), so
+/// they survive a crash before their txg is synced: they're replayed on import (see
+/// `zil::Replay`). The log blocks come from `slog`, the log devices, or from `alloc`. Returns
+/// whether the log on disk has them: a log this commit starts is only found once the txg with
+/// its header is synced. Fails with `Busy` if the dataset has a log that wasn't replayed, the
+/// new one would take its place.
+pub fn fsync(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             alloc: &mut Allocator,
+             slog: Option<&mut Allocator>)
+             -> zfs::Result<bool> {
+    if !dataset.zil.is_empty() {
+        return Err(zfs::Error::Busy);
+    }
+    let txg = dsl_pool.tx.open_txg();
+    let mut zilog = match dsl_pool.zil.remove(&dataset.object) {
+        Some(zilog) => zilog,
+        None => return Ok(true),
+    };
+    let result = {
+        let dsl_pool = &*dsl_pool;
+        let header = &dataset.objset.phys.zil_header;
+        zilog.commit(reader, header, txg, alloc, slog, &mut |reader, object, offset, len| {
+            pending_read(reader, dsl_pool, dataset, object, offset, len)
+        })
+    };
+    dsl_pool.zil.insert(dataset.object, zilog);
+    result
+}
+
+/// The directory part and the last component of `path`
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_right_matches('/');
+    match path.rfind('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path),
+    }
+}
+
+/// The dnode of object `object` of `dataset`, with what the txgs in flight in `dsl_pool` change
+fn pending_dnode(reader: &mut ZfsReader,
+                 dsl_pool: &DslPool,
+                 dataset: &Dataset,
+                 object: u64)
+                 -> zfs::Result<DNodePhys> {
+    let dnode = match dsl_pool.pending_dnode(dataset.object, object) {
+        Some(dnode) => dnode,
+        None => try!(dataset.objset.dnode(reader, object).map_err(|_| zfs::Error::NoEntity)),
+    };
+    if dnode.object_type == 0 {
+        return Err(zfs::Error::NoEntity);
+    }
+    Ok(dnode)
+}
+
+/// Like `pending_dnode`, for a regular file
+fn pending_file(reader: &mut ZfsReader,
+                dsl_pool: &DslPool,
+                dataset: &Dataset,
+                object: u64)
+                -> zfs::Result<DNodePhys> {
+    let dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let stat = try!(pending_stat(reader, dataset, object, &dnode));
+    if dnode.object_type != ObjectType::PlainFileContents as u8 ||
+       stat.file_type() != FileType::Regular {
+        return Err(zfs::Error::Invalid);
+    }
+    Ok(dnode)
+}
+
+fn pending_stat(reader: &mut ZfsReader,
+                dataset: &Dataset,
+                object: u64,
+                dnode: &DNodePhys)
+                -> zfs::Result<Stat> {
+    stat_dnode(reader, dataset, object, dnode).map_err(|_| zfs::Error::Invalid)
+}
+
+/// `len` bytes at `offset` of object `object` of `dataset`, with what the txgs in flight write
+fn pending_read(reader: &mut ZfsReader,
+                dsl_pool: &DslPool,
+                dataset: &Dataset,
+                object: u64,
+                offset: u64,
+                len: u64)
+                -> zfs::Result<Vec<u8>> {
+    let dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+    let block_size = dnode.data_block_size();
+    if block_size == 0 {
+        return Err(zfs::Error::Invalid);
+    }
+    let mut data = Vec::with_capacity(len as usize);
+    let end = offset + len;
+    let mut pos = offset;
+    while pos < end {
+        let blkid = pos / block_size;
+        let start = pos % block_size;
+        let count = cmp::min(end - pos, block_size - start);
+        let mut block = match dsl_pool.pending_block(dataset.object, object, blkid) {
+            Some(block) => block.to_vec(),
+            None if dnode.nlevels > 0 && blkid <= dnode.maxblkid => {
+                try!(dmu::read_block(reader, &dnode, blkid).map_err(|_| zfs::Error::Invalid))
+            }
+            None => Vec::new(),
+        };
+        block.resize(block_size as usize, 0);
+        data.extend_from_slice(&block[start as usize..(start + count) as usize]);
+        pos += count;
+    }
+    Ok(data)
+}
+
+/// The entry `name` matches in directory `dir` of `dataset`, with what the txgs in flight change:
+/// its name, which can be another than `name` once normalized or case folded as `lookup` matches
+/// them, and its ZAP value
+fn pending_entry(reader: &mut ZfsReader,
+                 dsl_pool: &DslPool,
+                 dataset: &Dataset,
+                 dir: u64,
+                 name: &str)
+                 -> zfs::Result<Option<(String, u64)>> {
+    let objset = &dataset.objset;
+    let entries = try!(zap::pending_entries(reader, dsl_pool, objset, dataset.object, dir));
+    let norm_flags = try!(zap::pending_norm_flags(reader, dsl_pool, objset, dataset.object, dir));
+    let mt = match_type(reader, dataset);
+    let name = try!(zap::ZapName::new(name, norm_flags, mt).map_err(|_| zfs::Error::Invalid));
+    Ok(entries.into_iter().find(|&(ref entry, _)| name.matches(entry)))
+}
+
+/// Like `lookup`, following symlinks, with what the txgs in flight change of the directories
+fn pending_lookup(reader: &mut ZfsReader,
+                  dsl_pool: &DslPool,
+                  dataset: &Dataset,
+                  path: &str)
+                  -> zfs::Result<u64> {
+    let root = try!(zap::lookup(reader, &dataset.objset, MASTER_NODE_OBJ, "ROOT")
+                        .map_err(|_| zfs::Error::Invalid));
+    let mut components = VecDeque::new();
+    push_components(path, &mut components);
+    let mut symlinks = 0;
+    let mut object = root;
+    while let Some(name) = components.pop_front() {
+        let dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+        if dnode.object_type != ObjectType::DirectoryContents as u8 {
+            return Err(zfs::Error::Invalid);
+        }
+        if name == ".." {
+            if object != root {
+                object = try!(pending_stat(reader, dataset, object, &dnode)).parent;
+            }
+            continue;
+        }
+        let (_, value) = try!(try!(pending_entry(reader, dsl_pool, dataset, object, &name))
+                                  .ok_or(zfs::Error::NoEntity));
+        if FileType::from_dirent(value) == FileType::Symlink {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(zfs::Error::Invalid);
+            }
+            // Symlinks are only ever read from the disk, there's no creating them yet
+            let target = try!(readlink_object(reader, dataset, dirent_object(value))
+                                  .map_err(|_| zfs::Error::Invalid));
+            if push_components(&target, &mut components) {
+                object = root;
+            }
+            continue;
+        }
+        object = dirent_object(value);
+    }
+    Ok(object)
+}
+
+/// Change attributes of the file described by `dnode`, in its legacy znode or in its system
+/// attributes: (`ZPL_*` name, value) pairs, times as (seconds, nanoseconds)
+fn set_attrs(reader: &mut ZfsReader,
+             dataset: &Dataset,
+             dnode: &mut DNodePhys,
+             attrs: &[(&str, &[u64])])
+             -> zfs::Result<()> {
+    if dnode.bonus_type == ObjectType::ZNode as u8 {
+        let mut znode = try!(ZNodePhys::from_bytes(dnode.get_bonus()));
+        for &(name, value) in attrs {
+            match (name, value) {
+                ("ZPL_SIZE", &[size]) => znode.size = size,
+                ("ZPL_LINKS", &[links]) => znode.links = links,
+                ("ZPL_PARENT", &[parent]) => znode.parent = parent,
+                ("ZPL_MTIME", &[secs, nsecs]) => znode.mtime = [secs, nsecs],
+                ("ZPL_CTIME", &[secs, nsecs]) => znode.ctime = [secs, nsecs],
+                _ => return Err(zfs::Error::Invalid),
+            }
+        }
+        let bytes = znode.as_bytes();
+        dnode.bonus_mut()[..bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    } else if dnode.bonus_type == sa::DMU_OT_SA {
+        let objset = &dataset.objset;
+        let sa_obj = try!(zap::lookup(reader, objset, MASTER_NODE_OBJ, "SA_ATTRS")
+                              .map_err(|_| zfs::Error::Invalid));
+        let registry = try!(sa::Registry::load(reader, objset, sa_obj)
+                                .map_err(|_| zfs::Error::Invalid));
+        let bonus_len = dnode.bonus_len as usize;
+        let bonus = dnode.bonus_mut();
+        let bonus_len = cmp::min(bonus_len, bonus.len());
+        for &(name, value) in attrs {
+            let set = try!(registry.set_u64s(&mut bonus[..bonus_len], name, value)
+                                   .map_err(|_| zfs::Error::Invalid));
+            // TODO: attributes in the spill block
+            if !set {
+                return Err(zfs::Error::UnsupportedFeature);
+            }
+        }
+        Ok(())
+    } else {
+        Err(zfs::Error::Invalid)
+    }
+}
+
+/// `renameat2` flags, as passed through by the FUSE frontend
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenameFlags {
+    /// Fail if the target exists (`RENAME_NOREPLACE`)
+    pub noreplace: bool,
+    /// Swap the source and the target, which both have to exist (`RENAME_EXCHANGE`)
+    pub exchange: bool,
+    /// Leave a whiteout in place of the source (`RENAME_WHITEOUT`), as overlayfs does
+    pub whiteout: bool,
+}
+
+impl RenameFlags {
+    pub fn from_bits(bits: u32) -> Result<Self, String> {
+        let flags = RenameFlags {
+            noreplace: bits & 1 != 0,
+            exchange: bits & 2 != 0,
+            whiteout: bits & 4 != 0,
+        };
+        if bits & !7 != 0 || flags.exchange && (flags.noreplace || flags.whiteout) {
+            return Err(format!("Bad rename flags {:X}", bits));
+        }
+        Ok(flags)
+    }
+}
+
+/// Which names a dataset takes for new entries
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NameRules {
+    /// Longest name, in bytes
+    pub max_len: usize,
+    /// Names have to be valid UTF-8 (the `utf8only` property, fixed when the file system was
+    /// created)
+    pub utf8only: bool,
+}
+
+impl NameRules {
+    /// The rules of `dataset`. A per-dataset feature is active when the dataset's own ZAP has an
+    /// entry for it; `utf8only` is kept in the master node.
+    pub fn for_dataset(reader: &mut ZfsReader,
+                       mos: &ObjectSet,
+                       dataset: &Dataset)
+                       -> Result<Self, String> {
+        let longname = zap::lookup(reader, mos, dataset.object, LONGNAME_FEATURE).is_ok();
+        let utf8only = zap::lookup(reader, &dataset.objset, MASTER_NODE_OBJ, "utf8only")
+                           .unwrap_or(0) != 0;
+        Ok(NameRules {
+            max_len: if longname { MAXNAMELEN_LONG } else { MAXNAMELEN },
+            utf8only: utf8only,
+        })
+    }
+
+    /// Check `name`, as it came from the frontend, can be given to a new entry
+    pub fn check(&self, name: &[u8]) -> Result<(), String> {
+        let shown = String::from_utf8_lossy(name);
+        if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') ||
+           name.contains(&0) {
+            return Err(format!("{}: invalid name", shown));
+        }
+        if name.len() > self.max_len {
+            return Err(format!("{}: file name too long", shown));
+        }
+        if self.utf8only && str::from_utf8(name).is_err() {
+            return Err(format!("{}: invalid or incomplete multibyte character", shown));
+        }
+        Ok(())
+    }
+}
+
+/// The changes making up the creation of entry `name` in directory `dir`, naming the object with
+/// ZAP value `value`. `existing` is the ZAP value of `name`, if it exists already.
+pub fn create_ops(rules: &NameRules,
+                  dir: u64,
+                  name: &str,
+                  existing: Option<u64>,
+                  value: u64)
+                  -> Result<Vec<DirOp>, String> {
+    try!(rules.check(name.as_bytes()));
+    if existing.is_some() {
+        return Err(format!("{}: file exists", name));
+    }
+    Ok(vec![DirOp::Add {
+                dir: dir,
+                name: name.to_owned(),
+                value: value,
+            }])
+}
+
+/// One change to the directories (and znodes) involved in a rename
+#[derive(Clone, Debug, PartialEq)]
+pub enum DirOp {
+    Add { dir: u64, name: String, value: u64 },
+    Remove { dir: u64, name: String },
+    /// An existing entry now names another object
+    Update { dir: u64, name: String, value: u64 },
+    /// Replace the entry with a whiteout: a new 0:0 character device
+    Whiteout { dir: u64, name: String },
+    /// A directory moved to another parent, its `..` changes
+    SetParent { object: u64, parent: u64 },
+    /// The replaced object loses a link, and goes away with its last one. A replaced directory
+    /// has to be empty, which is checked (with `dir_is_empty`) when this is applied.
+    Unlink { object: u64 },
+}
+
+/// The changes making up the rename of entry `src` of directory `src_dir` to `dst` of `dst_dir`.
+/// `src_value` and `dst_value` are the entries' ZAP values, if they exist. All of them go into the
+/// same transaction, so that the rename (or exchange) is atomic.
+pub fn rename_ops(src_dir: u64,
+                  src: &str,
+                  src_value: Option<u64>,
+                  dst_dir: u64,
+                  dst: &str,
+                  dst_value: Option<u64>,
+                  flags: RenameFlags)
+                  -> Result<Vec<DirOp>, String> {
+    let src_value = try!(src_value.ok_or(format!("{}: no such file", src)));
+    let src_object = dirent_object(src_value);
+    let src_is_dir = FileType::from_dirent(src_value) == FileType::Directory;
+    let moved_dir = |object: u64, is_dir: bool, parent: u64| {
+        if is_dir && src_dir != dst_dir {
+            Some(DirOp::SetParent {
+                object: object,
+                parent: parent,
+            })
+        } else {
+            None
+        }
+    };
+
+    if src_dir == dst_dir && src == dst {
+        return Ok(Vec::new());
+    }
+
+    let mut ops = Vec::new();
+    if flags.exchange {
+        let dst_value = try!(dst_value.ok_or(format!("{}: no such file", dst)));
+        let dst_is_dir = FileType::from_dirent(dst_value) == FileType::Directory;
+        ops.push(DirOp::Update {
+            dir: src_dir,
+            name: src.to_owned(),
+            value: dst_value,
+        });
+        ops.push(DirOp::Update {
+            dir: dst_dir,
+            name: dst.to_owned(),
+            value: src_value,
+        });
+        ops.extend(moved_dir(src_object, src_is_dir, dst_dir));
+        ops.extend(moved_dir(dirent_object(dst_value), dst_is_dir, src_dir));
+        return Ok(ops);
+    }
+
+    match dst_value {
+        Some(_) if flags.noreplace => return Err(format!("{}: file exists", dst)),
+        // Both names are links to the same file, there's nothing to do
+        Some(dst_value) if dirent_object(dst_value) == src_object => return Ok(ops),
+        Some(dst_value) => {
+            let dst_is_dir = FileType::from_dirent(dst_value) == FileType::Directory;
+            if src_is_dir && !dst_is_dir {
+                return Err(format!("{}: not a directory", dst));
+            }
+            if !src_is_dir && dst_is_dir {
+                return Err(format!("{}: is a directory", dst));
+            }
+            ops.push(DirOp::Update {
+                dir: dst_dir,
+                name: dst.to_owned(),
+                value: src_value,
+            });
+            ops.push(DirOp::Unlink { object: dirent_object(dst_value) });
+        }
+        None => {
+            ops.push(DirOp::Add {
+                dir: dst_dir,
+                name: dst.to_owned(),
+                value: src_value,
+            })
+        }
+    }
+    if flags.whiteout {
+        ops.push(DirOp::Whiteout {
+            dir: src_dir,
+            name: src.to_owned(),
+        });
+    } else {
+        ops.push(DirOp::Remove {
+            dir: src_dir,
+            name: src.to_owned(),
+        });
+    }
+    ops.extend(moved_dir(src_object, src_is_dir, dst_dir));
+    Ok(ops)
+}
+
+/// Rename the entry at `src` of `dataset` to `dst` (`zfs_rename`), in a transaction of its own:
+/// the changes `rename_ops` plans by `flags` all go into it. A directory can't be moved below
+/// itself, and one that's replaced has to be empty, or this fails with `Exists`. The new name has
+/// to pass the dataset's `NameRules`.
+pub fn rename(reader: &mut ZfsReader,
+              dsl_pool: &mut DslPool,
+              mos: &ObjectSet,
+              dataset: &Dataset,
+              src: &str,
+              dst: &str,
+              flags: RenameFlags)
+              -> zfs::Result<()> {
+    let (src_dir_path, src_name) = split_path(src);
+    let (dst_dir_path, dst_name) = split_path(dst);
+    for name in &[src_name, dst_name] {
+        if name.is_empty() || *name == "." || *name == ".." {
+            return Err(zfs::Error::Invalid);
+        }
+    }
+    let rules = try!(NameRules::for_dataset(reader, mos, dataset).map_err(|_| zfs::Error::Invalid));
+    try!(rules.check(dst_name.as_bytes()).map_err(|_| zfs::Error::Invalid));
+    let src_dir = try!(pending_lookup(reader, dsl_pool, dataset, src_dir_path));
+    let dst_dir = try!(pending_lookup(reader, dsl_pool, dataset, dst_dir_path));
+    for &dir in &[src_dir, dst_dir] {
+        let dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+        if dnode.object_type != ObjectType::DirectoryContents as u8 {
+            return Err(zfs::Error::Invalid);
+        }
+        // Whiteouts are new files, with legacy znodes like `create` makes
+        if flags.whiteout && dnode.bonus_type != ObjectType::ZNode as u8 {
+            return Err(zfs::Error::UnsupportedFeature);
+        }
+    }
+    // The entries are changed by the names they have, which `src` and `dst` match
+    let src_entry = try!(pending_entry(reader, dsl_pool, dataset, src_dir, src_name));
+    let (src_name, src_value) = try!(src_entry.ok_or(zfs::Error::NoEntity));
+    let dst_entry = try!(pending_entry(reader, dsl_pool, dataset, dst_dir, dst_name));
+    let dst_value = dst_entry.as_ref().map(|&(_, value)| value);
+    let dst_name = dst_entry.as_ref().map_or(dst_name, |&(ref name, _)| name);
+    match dst_value {
+        None if flags.exchange => return Err(zfs::Error::NoEntity),
+        Some(_) if flags.noreplace => return Err(zfs::Error::Exists),
+        _ => {}
+    }
+    let ops = try!(rename_ops(src_dir,
+                              &src_name,
+                              Some(src_value),
+                              dst_dir,
+                              dst_name,
+                              dst_value,
+                              flags)
+                       .map_err(|_| zfs::Error::Invalid));
+    if ops.is_empty() {
+        return Ok(());
+    }
+
+    let mut tx = Tx::create(dataset.object);
+    for op in &ops {
+        match *op {
+            DirOp::Add { dir, ref name, .. } => tx.hold_zap(dir, true, Some(name)),
+            DirOp::Remove { dir, ref name } |
+            DirOp::Update { dir, ref name, .. } |
+            DirOp::Whiteout { dir, ref name } => tx.hold_zap(dir, false, Some(name)),
+            DirOp::SetParent { object, parent } => {
+                // Walk up from the new parent to the root, which is its own parent
+                let mut dir = parent;
+                loop {
+                    if dir == object {
+                        return Err(zfs::Error::Invalid);
+                    }
+                    let dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+                    let up = try!(pending_stat(reader, dataset, dir, &dnode)).parent;
+                    if up == dir {
+                        break;
+                    }
+                    dir = up;
+                }
+            }
+            DirOp::Unlink { object } => {
+                if dsl_pool.is_held(dataset.object, object) {
+                    let queue = try!(delete_queue(reader, dataset));
+                    tx.hold_zap(queue, true, Some(&queue_name(object)));
+                }
+                let dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+                if dnode.object_type == ObjectType::DirectoryContents as u8 &&
+                   !try!(zap::pending_entries(reader,
+                                              dsl_pool,
+                                              &dataset.objset,
+                                              dataset.object,
+                                              object))
+                        .is_empty() {
+                    return Err(zfs::Error::Exists);
+                }
+            }
+        }
+    }
+    try!(dsl_pool.check_space(dataset, tx.space_needed()));
+    let txg = try!(tx.assign(dsl_pool));
+    let result = rename_tx(reader, dsl_pool, dataset, &ops, &tx, txg);
+    tx.commit(dsl_pool);
+    result
+}
+
+fn rename_tx(reader: &mut ZfsReader,
+             dsl_pool: &mut DslPool,
+             dataset: &Dataset,
+             ops: &[DirOp],
+             tx: &Tx,
+             txg: u64)
+             -> zfs::Result<()> {
+    let now = now();
+    // (directory, entries gained, links gained), set once the entries have changed
+    let mut dirs: Vec<(u64, i64, i64)> = Vec::new();
+    let mut count = |dir: u64, entries: i64, links: i64| {
+        match dirs.iter().position(|&(d, _, _)| d == dir) {
+            Some(i) => {
+                dirs[i].1 += entries;
+                dirs[i].2 += links;
+            }
+            None => dirs.push((dir, entries, links)),
+        }
+    };
+    // Objects whose entries changed, their ctime goes with it
+    let mut moved = Vec::new();
+    for op in ops {
+        match *op {
+            DirOp::Add { dir, ref name, value } => {
+                try!(zap::add(reader, dsl_pool, &dataset.objset, dir, name, value, tx));
+                count(dir, 1, 0);
+                moved.push(dirent_object(value));
+            }
+            DirOp::Remove { dir, ref name } => {
+                try!(zap::remove(reader, dsl_pool, &dataset.objset, dir, name, tx));
+                count(dir, -1, 0);
+            }
+            DirOp::Update { dir, ref name, value } => {
+                try!(zap::update(reader, dsl_pool, &dataset.objset, dir, name, 8, &[value], tx));
+                count(dir, 0, 0);
+                moved.push(dirent_object(value));
+            }
+            DirOp::Whiteout { dir, ref name } => {
+                let bonus = new_znode(S_IFCHR, 0, 1, dir, txg);
+                let dnode = DNodePhys::new(ObjectType::PlainFileContents,
+                                           512,
+                                           ObjectType::ZNode,
+                                           &bonus);
+                let object = try!(dmu::create_object(reader, dsl_pool, &dataset.objset, dnode, tx));
+                let value = object | (S_IFCHR >> 12) << 60;
+                try!(zap::update(reader, dsl_pool, &dataset.objset, dir, name, 8, &[value], tx));
+                count(dir, 0, 0);
+            }
+            DirOp::SetParent { object, parent } => {
+                // Its ".." is a link to the new parent rather than the old one
+                let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+                let old_parent = try!(pending_stat(reader, dataset, object, &dnode)).parent;
+                try!(set_attrs(reader, dataset, &mut dnode, &[("ZPL_PARENT", &[parent])]));
+                dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+                count(old_parent, 0, -1);
+                count(parent, 0, 1);
+            }
+            DirOp::Unlink { object } => {
+                let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+                let stat = try!(pending_stat(reader, dataset, object, &dnode));
+                if stat.file_type() == FileType::Directory {
+                    // Found empty before the transaction was assigned
+                    count(stat.parent, 0, -1);
+                    dsl_pool.free_object(txg, dataset.object, object);
+                } else if stat.links <= 1 {
+                    try!(unlinked(reader, dsl_pool, dataset, object, dnode, &now, tx, txg));
+                } else {
+                    try!(set_attrs(reader,
+                                   dataset,
+                                   &mut dnode,
+                                   &[("ZPL_LINKS", &[stat.links - 1]), ("ZPL_CTIME", &now)]));
+                    dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+                }
+            }
+        }
+    }
+
+    for object in moved {
+        let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, object));
+        try!(set_attrs(reader, dataset, &mut dnode, &[("ZPL_CTIME", &now)]));
+        dsl_pool.dirty_dnode(txg, dataset.object, object, dnode);
+    }
+    for (dir, entries, links) in dirs {
+        let mut dnode = try!(pending_dnode(reader, dsl_pool, dataset, dir));
+        let stat = try!(pending_stat(reader, dataset, dir, &dnode));
+        try!(set_attrs(reader,
+                       dataset,
+                       &mut dnode,
+                       &[("ZPL_SIZE", &[(stat.size as i64 + entries) as u64]),
+                         ("ZPL_LINKS", &[(stat.links as i64 + links) as u64]),
+                         ("ZPL_MTIME", &now),
+                         ("ZPL_CTIME", &now)]));
+        dsl_pool.dirty_dnode(txg, dataset.object, dir, dnode);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_dirent_value() {
+    // A directory (S_IFDIR >> 12 == 4) with object number 34
+    let value = 0x4000000000000022;
+    assert_eq!(dirent_object(value), 34);
+    assert_eq!(dirent_type(value), 4);
+    assert_eq!(dirent_object(34), 34);
+    assert_eq!(DirEntry::from_zap("home".to_owned(), value),
+               DirEntry {
+                   name: "home".to_owned(),
+                   object: 34,
+                   file_type: FileType::Directory,
+               });
+    assert_eq!(FileType::from_dirent(0xA000000000000102), FileType::Symlink);
+    assert_eq!(FileType::from_mode(0o100644), FileType::Regular);
+}
+
+#[test]
+fn test_symlink_components() {
+    let mut components = VecDeque::new();
+    assert!(!push_components("b/./c/", &mut components));
+    assert_eq!(components, vec!["b", "c"]);
+
+    // A symlink `a -> /x/../y` in the middle of `a/b/c`
+    assert!(push_components("/x/../y", &mut components));
+    assert_eq!(components, vec!["x", "..", "y", "b", "c"]);
+}
+
+#[test]
+fn test_rename_ops() {
+    let file = 0x8000000000000010;
+    let other_file = 0x8000000000000011;
+    let dir = 0x4000000000000020;
+    let exchange = RenameFlags::from_bits(2).unwrap();
+    assert!(RenameFlags::from_bits(2 | 4).is_err());
+
+    // Moving a directory to another parent
+    assert_eq!(rename_ops(3, "a", Some(dir), 4, "b", None, RenameFlags::default()).unwrap(),
+               vec![DirOp::Add {
+                        dir: 4,
+                        name: "b".to_owned(),
+                        value: dir,
+                    },
+                    DirOp::Remove {
+                        dir: 3,
+                        name: "a".to_owned(),
+                    },
+                    DirOp::SetParent {
+                        object: 0x20,
+                        parent: 4,
+                    }]);
+
+    // Exchanging a file and a directory across directories
+    assert_eq!(rename_ops(3, "a", Some(file), 4, "b", Some(dir), exchange).unwrap(),
+               vec![DirOp::Update {
+                        dir: 3,
+                        name: "a".to_owned(),
+                        value: dir,
+                    },
+                    DirOp::Update {
+                        dir: 4,
+                        name: "b".to_owned(),
+                        value: file,
+                    },
+                    DirOp::SetParent {
+                        object: 0x20,
+                        parent: 3,
+                    }]);
+    assert!(rename_ops(3, "a", Some(file), 4, "b", None, exchange).is_err());
+
+    // Replacing a file, leaving a whiteout behind
+    let whiteout = RenameFlags::from_bits(4).unwrap();
+    assert_eq!(rename_ops(3, "a", Some(file), 3, "b", Some(other_file), whiteout).unwrap(),
+               vec![DirOp::Update {
+                        dir: 3,
+                        name: "b".to_owned(),
+                        value: file,
+                    },
+                    DirOp::Unlink { object: 0x11 },
+                    DirOp::Whiteout {
+                        dir: 3,
+                        name: "a".to_owned(),
+                    }]);
+
+    let noreplace = RenameFlags::from_bits(1).unwrap();
+    assert!(rename_ops(3, "a", Some(file), 3, "b", Some(other_file), noreplace).is_err());
+    assert!(rename_ops(3, "a", Some(file), 3, "b", Some(dir), RenameFlags::default()).is_err());
+    assert_eq!(rename_ops(3, "a", Some(file), 3, "a", Some(file), exchange).unwrap(), vec![]);
+    assert!(rename_ops(3, "a", None, 3, "b", None, RenameFlags::default()).is_err());
+}
+
+#[test]
+fn test_name_rules() {
+    let rules = NameRules {
+        max_len: MAXNAMELEN,
+        utf8only: false,
+    };
+    assert!(rules.check(&[b'a'; 255]).is_ok());
+    assert!(rules.check(&[b'a'; 256]).is_err());
+    assert!(rules.check(b"caf\xE9").is_ok());
+    for name in &[&b""[..], b".", b"..", b"a/b", b"a\0"] {
+        assert!(rules.check(name).is_err());
+    }
+
+    let rules = NameRules {
+        max_len: MAXNAMELEN_LONG,
+        utf8only: true,
+    };
+    assert!(rules.check(&[b'a'; 1023]).is_ok());
+    assert!(rules.check(&[b'a'; 1024]).is_err());
+    assert!(rules.check("café".as_bytes()).is_ok());
+    assert!(rules.check(b"caf\xE9").is_err());
+
+    let long = "x".repeat(300);
+    assert_eq!(create_ops(&rules, 4, &long, None, 0x8000000000000010).unwrap(),
+               vec![DirOp::Add {
+                        dir: 4,
+                        name: long.clone(),
+                        value: 0x8000000000000010,
+                    }]);
+    assert!(create_ops(&rules, 4, &long, Some(0x8000000000000011), 0x8000000000000010).is_err());
+}
+
+#[test]
+fn test_compare_files() {
+    use std::collections::BTreeMap;
+    use super::arcache::ArCache;
+    use super::dmu_zfetch::Zfetch;
+    use super::dnode_sync;
+    use super::metaslab::VdevAllocator;
+    use super::vdev_io::MemVdev;
+    use super::zio;
+    use super::zio_compress::ZIO_COMPRESS_OFF;
+
+    let mut reader = ZfsReader {
+        zio: zio::Reader::new(MemVdev::new(vec![0; 8 << 20])),
+        arc: ArCache::new(),
+        dcache: dcache::DentryCache::new(),
+        zfetch: Zfetch::new(),
+    };
+    let mut alloc = VdevAllocator::new(0, 9, 4 << 20, None);
+    let mut sync = |reader: &mut ZfsReader, dnode: &mut DNodePhys, txg, blocks: &[(u64, u8)]| {
+        let blocks: BTreeMap<u64, Vec<u8>> = blocks.iter()
+                                                   .map(|&(blkid, byte)| (blkid, vec![byte; 512]))
+                                                   .collect();
+        let mut freed = Vec::new();
+        dnode_sync::sync_dnode(reader,
+                               &mut alloc,
+                               None,
+                               ZIO_COMPRESS_OFF,
+                               txg,
+                               dnode,
+                               &blocks,
+                               &mut freed)
+            .unwrap();
+    };
+
+    // 21 blocks of 512 bytes under two levels of 8 block pointer indirect blocks
+    let mut dnode = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    dnode.object_type = ObjectType::PlainFileContents as u8;
+    dnode.nblkptr = 1;
+    dnode.indblkshift = 10;
+    dnode.data_blk_sz_sec = 1;
+    let blocks: Vec<_> = (0..21).map(|blkid| (blkid, blkid as u8)).collect();
+    sync(&mut reader, &mut dnode, 5, &blocks);
+    let a = FileHandle {
+        object: 2,
+        dnode: dnode,
+        size: 21 * 512,
+        log: None,
+    };
+
+    // Later on, blocks 3 and 17 were changed, and block 10 written again with the same data
+    sync(&mut reader, &mut dnode, 6, &[(3, 0xff), (10, 10), (17, 0xff)]);
+    let b = FileHandle {
+        object: 2,
+        dnode: dnode,
+        size: 21 * 512 + 100,
+        log: None,
+    };
+    let region = |offset, len, changed| {
+        Region {
+            offset: offset,
+            len: len,
+            changed: changed,
+        }
+    };
+    assert_eq!(compare_files(&mut reader, &a, &b).unwrap(),
+               Comparison {
+                   regions: vec![region(0, 3 * 512, false),
+                                 region(3 * 512, 512, true),
+                                 region(4 * 512, 13 * 512, false),
+                                 region(17 * 512, 512, true),
+                                 region(18 * 512, 3 * 512, false),
+                                 region(21 * 512, 100, true)],
+                   blocks_read: 0,
+               });
+    assert!(compare_files(&mut reader, &a, &a).unwrap().is_identical());
+
+    // A single block file against the first block of the bigger one, with fewer levels
+    let mut small = DNodePhys::from_bytes(&[0; 512]).unwrap();
+    small.object_type = ObjectType::PlainFileContents as u8;
+    small.nblkptr = 1;
+    small.indblkshift = 10;
+    small.data_blk_sz_sec = 1;
+    sync(&mut reader, &mut small, 7, &[(0, 0)]);
+    let small = FileHandle {
+        object: 3,
+        dnode: small,
+        size: 512,
+        log: None,
+    };
+    assert_eq!(compare_files(&mut reader, &small, &a).unwrap().regions,
+               vec![region(0, 512, false), region(512, 20 * 512, true)]);
+}
+
+#[test]
+fn test_write() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_write");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+
+    // Two files in the same txg, the second one's block growing to take the write in
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    let (hello, other) = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let hello = create(reader, &mut dsl_pool, mos, &dataset, "/hello", 0o644).unwrap();
+        write(reader, &mut dsl_pool, &dataset, hello, 0, b"hello world").unwrap();
+        assert_eq!(create(reader, &mut dsl_pool, mos, &dataset, "hello", 0o644),
+                   Err(zfs::Error::Exists));
+        assert_eq!(create(reader, &mut dsl_pool, mos, &dataset, "/none/file", 0o644),
+                   Err(zfs::Error::NoEntity));
+        let other = create(reader, &mut dsl_pool, mos, &dataset, "/other", 0o600).unwrap();
+        write(reader, &mut dsl_pool, &dataset, other, 1000, &[7; 3000]).unwrap();
+        (hello, other)
+    };
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    {
+        let reader = spa.reader_mut().unwrap();
+        let names: Vec<_> = readdir(reader, &dataset, "/")
+                                .unwrap()
+                                .into_iter()
+                                .map(|entry| entry.name)
+                                .collect();
+        assert_eq!(names, vec!["hello", "other"]);
+        let stat = stat(reader, &dataset, "/hello").unwrap();
+        assert_eq!((stat.object, stat.mode, stat.links, stat.size),
+                   (hello, 0o100644, 1, 11));
+        assert_eq!(dataset.objset.read(reader, hello, 0, 11).unwrap(), b"hello world");
+        assert_eq!(stat_object(reader, &dataset, other).unwrap().size, 4000);
+        assert_eq!(dataset.objset.dnode(reader, other).unwrap().data_block_size(), 4096);
+        let mut expected = vec![0; 1000];
+        expected.extend_from_slice(&[7; 3000]);
+        assert_eq!(dataset.objset.read(reader, other, 0, 4000).unwrap(), expected);
+    }
+
+    // What's truncated off reads back as zeros when the file grows again, as do punched holes
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let reader = spa.reader_mut().unwrap();
+        truncate(reader, &mut dsl_pool, &dataset, other, 1500).unwrap();
+        truncate(reader, &mut dsl_pool, &dataset, other, 5000).unwrap();
+        punch_hole(reader, &mut dsl_pool, &dataset, other, 1100, 100).unwrap();
+        // Past the end of the file there's nothing to punch
+        punch_hole(reader, &mut dsl_pool, &dataset, other, 6000, 10).unwrap();
+        unlink(reader, &mut dsl_pool, &dataset, "/hello").unwrap();
+        assert_eq!(unlink(reader, &mut dsl_pool, &dataset, "/hello"),
+                   Err(zfs::Error::NoEntity));
+        assert_eq!(write(reader, &mut dsl_pool, &dataset, hello, 0, b"gone"),
+                   Err(zfs::Error::NoEntity));
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    let names: Vec<_> = readdir(reader, &dataset, "/")
+                            .unwrap()
+                            .into_iter()
+                            .map(|entry| entry.name)
+                            .collect();
+    assert_eq!(names, vec!["other"]);
+    assert_eq!(stat(reader, &dataset, "/").unwrap().size, 3);
+    assert_eq!(stat_object(reader, &dataset, other).unwrap().size, 5000);
+    let mut expected = vec![0; 1000];
+    expected.extend_from_slice(&[7; 500]);
+    expected.resize(4096, 0);
+    for byte in &mut expected[1100..1200] {
+        *byte = 0;
+    }
+    assert_eq!(dataset.objset.read(reader, other, 0, 4096).unwrap(), expected);
+
+    // Nor does the file have extended attributes
+    let file = super::zpl::open(reader, &dataset, "/other").unwrap();
+    assert_eq!(file.xattrs(reader, &dataset).unwrap(), Vec::<String>::new());
+    assert_eq!(file.get_xattr(reader, &dataset, "user.any").unwrap(), None);
+}
+
+#[test]
+fn test_rmdir() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_rmdir");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+
+    let long = format!("/dir/{}", "x".repeat(60));
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        mkdir(reader, &mut dsl_pool, mos, &dataset, "/dir", 0o755).unwrap();
+        let file = create(reader, &mut dsl_pool, mos, &dataset, "/dir/file", 0o644).unwrap();
+        write(reader, &mut dsl_pool, &dataset, file, 0, &[1; 128 << 10]).unwrap();
+        mkdir(reader, &mut dsl_pool, mos, &dataset, "/empty", 0o700).unwrap();
+        // A name too long for a micro ZAP makes the directory a fat ZAP
+        create(reader, &mut dsl_pool, mos, &dataset, &long, 0o644).unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    {
+        let reader = spa.reader_mut().unwrap();
+        let root = stat(reader, &dataset, "/").unwrap();
+        assert_eq!((root.size, root.links), (4, 4));
+        let dir = stat(reader, &dataset, "/dir").unwrap();
+        assert_eq!((dir.mode, dir.size, dir.links), (0o040755, 4, 2));
+        assert_eq!(stat(reader, &dataset, "/dir/file").unwrap().size, 128 << 10);
+        assert_eq!(stat(reader, &dataset, &long).unwrap().size, 0);
+    }
+    let referenced = dataset.phys.referenced_bytes;
+    assert!(referenced >= 128 << 10);
+
+    // The file's blocks go with it
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let reader = spa.reader_mut().unwrap();
+        assert_eq!(rmdir(reader, &mut dsl_pool, &dataset, "/dir"), Err(zfs::Error::Exists));
+        assert_eq!(rmdir(reader, &mut dsl_pool, &dataset, "/dir/file"),
+                   Err(zfs::Error::Invalid));
+        assert_eq!(unlink(reader, &mut dsl_pool, &dataset, "/empty"),
+                   Err(zfs::Error::Invalid));
+        unlink(reader, &mut dsl_pool, &dataset, "/dir/file").unwrap();
+        assert_eq!(rmdir(reader, &mut dsl_pool, &dataset, "/dir"), Err(zfs::Error::Exists));
+        unlink(reader, &mut dsl_pool, &dataset, &long).unwrap();
+        rmdir(reader, &mut dsl_pool, &dataset, "/dir").unwrap();
+        rmdir(reader, &mut dsl_pool, &dataset, "/empty").unwrap();
+        assert_eq!(rmdir(reader, &mut dsl_pool, &dataset, "/empty"),
+                   Err(zfs::Error::NoEntity));
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    assert!(readdir(reader, &dataset, "/").unwrap().is_empty());
+    let root = stat(reader, &dataset, "/").unwrap();
+    assert_eq!((root.size, root.links), (2, 2));
+    assert!(dataset.phys.referenced_bytes + (128 << 10) <= referenced);
+}
+
+#[test]
+fn test_rename() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_rename");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+    let none = RenameFlags::default();
+
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    let replaced = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        create(reader, &mut dsl_pool, mos, &dataset, "/a", 0o644).unwrap();
+        mkdir(reader, &mut dsl_pool, mos, &dataset, "/d", 0o755).unwrap();
+        mkdir(reader, &mut dsl_pool, mos, &dataset, "/d2", 0o755).unwrap();
+        create(reader, &mut dsl_pool, mos, &dataset, "/d/x", 0o644).unwrap()
+    };
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        rename(reader, &mut dsl_pool, mos, &dataset, "/a", "/d/b", none).unwrap();
+        rename(reader, &mut dsl_pool, mos, &dataset, "/d", "/d2/d", none).unwrap();
+        assert_eq!(rename(reader, &mut dsl_pool, mos, &dataset, "/d2", "/d2/d/sub", none),
+                   Err(zfs::Error::Invalid));
+        assert_eq!(rename(reader, &mut dsl_pool, mos, &dataset, "/nothing", "/b", none),
+                   Err(zfs::Error::NoEntity));
+
+        // Replacing a file
+        create(reader, &mut dsl_pool, mos, &dataset, "/c", 0o600).unwrap();
+        let noreplace = RenameFlags::from_bits(1).unwrap();
+        assert_eq!(rename(reader, &mut dsl_pool, mos, &dataset, "/c", "/d2/d/x", noreplace),
+                   Err(zfs::Error::Exists));
+        rename(reader, &mut dsl_pool, mos, &dataset, "/c", "/d2/d/x", none).unwrap();
+
+        // Directories that aren't empty aren't replaced
+        mkdir(reader, &mut dsl_pool, mos, &dataset, "/e", 0o755).unwrap();
+        assert_eq!(rename(reader, &mut dsl_pool, mos, &dataset, "/e", "/d2", none),
+                   Err(zfs::Error::Exists));
+
+        let exchange = RenameFlags::from_bits(2).unwrap();
+        rename(reader, &mut dsl_pool, mos, &dataset, "/e", "/d2/d/b", exchange).unwrap();
+        let whiteout = RenameFlags::from_bits(4).unwrap();
+        rename(reader, &mut dsl_pool, mos, &dataset, "/d2/d/x", "/w", whiteout).unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    let names = |reader: &mut ZfsReader, path| {
+        let mut names: Vec<String> = readdir(reader, &dataset, path)
+                                         .unwrap()
+                                         .into_iter()
+                                         .map(|entry| entry.name)
+                                         .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(reader, "/"), vec!["d2", "e", "w"]);
+    assert_eq!(names(reader, "/d2/d"), vec!["b", "x"]);
+    let root = stat(reader, &dataset, "/").unwrap();
+    assert_eq!((root.size, root.links), (5, 3));
+    let d2 = stat(reader, &dataset, "/d2").unwrap();
+    assert_eq!((d2.size, d2.links), (3, 3));
+    let d = stat(reader, &dataset, "/d2/d").unwrap();
+    assert_eq!((d.size, d.links, d.parent), (4, 3, d2.object));
+    assert_eq!(stat(reader, &dataset, "/d2/d/b").unwrap().parent, d.object);
+    assert_eq!(stat(reader, &dataset, "/e").unwrap().mode, 0o100644);
+    assert_eq!(stat(reader, &dataset, "/w").unwrap().mode, 0o100600);
+    let whiteout = stat(reader, &dataset, "/d2/d/x").unwrap();
+    assert_eq!((whiteout.file_type(), whiteout.device()), (FileType::CharDevice, Some((0, 0))));
+    // The replaced file is gone, its object may have been taken by the whiteout
+    assert!(stat_object(reader, &dataset, replaced)
+                .map_or(true, |stat| stat.file_type() != FileType::Regular));
+}
+
+#[test]
+fn test_delete_queue() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_delete_queue");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+    let none = RenameFlags::default();
+    let queued = |reader: &mut ZfsReader, dataset: &Dataset| {
+        let queue = delete_queue(reader, dataset).unwrap();
+        let mut objects: Vec<u64> = zap::iter(reader, &dataset.objset, queue)
+                                        .unwrap()
+                                        .map(|(_, object)| object)
+                                        .collect();
+        objects.sort();
+        objects
+    };
+
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    let files = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let mut files = Vec::new();
+        for path in &["/a", "/b", "/c", "/d"] {
+            let file = create(reader, &mut dsl_pool, mos, &dataset, path, 0o644).unwrap();
+            write(reader, &mut dsl_pool, &dataset, file, 0, &[1; 128 << 10]).unwrap();
+            files.push(file);
+        }
+        files
+    };
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+    let referenced = open(&mut spa).phys.referenced_bytes;
+
+    // Unlinked or renamed over, files held open stay on the delete queue, the others go
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        hold_file(reader, &mut dsl_pool, &dataset, files[0]).unwrap();
+        hold_file(reader, &mut dsl_pool, &dataset, files[0]).unwrap();
+        hold_file(reader, &mut dsl_pool, &dataset, files[3]).unwrap();
+        unlink(reader, &mut dsl_pool, &dataset, "/a").unwrap();
+        rename(reader, &mut dsl_pool, mos, &dataset, "/b", "/d", none).unwrap();
+        unlink(reader, &mut dsl_pool, &dataset, "/c").unwrap();
+        // Still there to be written to
+        write(reader, &mut dsl_pool, &dataset, files[0], 0, b"open").unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    {
+        let reader = spa.reader_mut().unwrap();
+        assert_eq!(readdir(reader, &dataset, "/").unwrap().len(), 1);
+        assert_eq!(queued(reader, &dataset), vec![files[0], files[3]]);
+        assert_eq!(stat_object(reader, &dataset, files[0]).unwrap().links, 0);
+        assert_eq!(dataset.objset.read(reader, files[0], 0, 5).unwrap(), b"open\x01");
+    }
+    assert!(dataset.phys.referenced_bytes + (128 << 10) <= referenced);
+    let referenced = dataset.phys.referenced_bytes;
+
+    // The first file goes with its last hold. The other one stays queued while it's held, and
+    // is drained once it isn't.
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let reader = spa.reader_mut().unwrap();
+        release_file(reader, &mut dsl_pool, &dataset, files[0]).unwrap();
+        assert!(dsl_pool.is_held(dataset.object, files[0]));
+        release_file(reader, &mut dsl_pool, &dataset, files[0]).unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    assert_eq!(queued(spa.reader_mut().unwrap(), &dataset), vec![files[3]]);
+    assert!(dataset.phys.referenced_bytes + (128 << 10) <= referenced);
+    let referenced = dataset.phys.referenced_bytes;
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    drain_delete_queue(spa.reader_mut().unwrap(), &mut dsl_pool, &dataset).unwrap();
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    assert!(spa.sync(&mut alloc).unwrap().is_none());
+
+    // The holds go with the pool, as if it went down with the file still open
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    assert!(dsl_pool.release_object(dataset.object, files[3]));
+    drain_delete_queue(spa.reader_mut().unwrap(), &mut dsl_pool, &dataset).unwrap();
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    assert!(queued(reader, &dataset).is_empty());
+    assert!(stat_object(reader, &dataset, files[3]).is_err());
+    assert!(dataset.phys.referenced_bytes + (128 << 10) <= referenced);
+}
+
+#[test]
+fn test_normalized_names() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+    use super::u8_textprep::U8_TEXTPREP_TOUPPER;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_normalized_names");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+
+    // A directory whose ZAP folds case, as on a case insensitive dataset
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let smb = mkdir(reader, &mut dsl_pool, mos, &dataset, "/smb", 0o755).unwrap();
+        let mut block = dsl_pool.pending_block(dataset.object, smb, 0).unwrap().to_vec();
+        block[16..24].copy_from_slice(&U8_TEXTPREP_TOUPPER.to_le_bytes());
+        let txg = dsl_pool.tx.open_txg();
+        dsl_pool.dirty_block(txg, dataset.object, smb, 0, block);
+        create(reader, &mut dsl_pool, mos, &dataset, "/smb/Readme", 0o644).unwrap();
+        assert_eq!(create(reader, &mut dsl_pool, mos, &dataset, "/smb/README", 0o644),
+                   Err(zfs::Error::Exists));
+        // Other directories match names exactly
+        create(reader, &mut dsl_pool, mos, &dataset, "/readme", 0o644).unwrap();
+        create(reader, &mut dsl_pool, mos, &dataset, "/README", 0o644).unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        assert_eq!(mkdir(reader, &mut dsl_pool, mos, &dataset, "/smb/readme", 0o755),
+                   Err(zfs::Error::Exists));
+        let noreplace = RenameFlags::from_bits(1).unwrap();
+        let renamed = rename(reader, &mut dsl_pool, mos, &dataset, "/readme", "/smb/README",
+                             noreplace);
+        assert_eq!(renamed, Err(zfs::Error::Exists));
+        unlink(reader, &mut dsl_pool, &dataset, "/smb/README").unwrap();
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    assert!(readdir(reader, &dataset, "/smb").unwrap().is_empty());
+    assert_eq!(readdir(reader, &dataset, "/").unwrap().len(), 3);
+}
+
+#[test]
+fn test_utf8only_names() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_utf8only_names");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        let dataset = dsl_pool.open_dataset(reader, mos, "tank", false).unwrap();
+        let rules = NameRules::for_dataset(reader, mos, &dataset).unwrap();
+        (dataset, rules)
+    };
+
+    let (dataset, rules) = open(&mut spa);
+    assert_eq!(rules,
+               NameRules {
+                   max_len: MAXNAMELEN,
+                   utf8only: false,
+               });
+    assert!(rules.check(b"caf\xE9").is_ok());
+    // The property is only ever set as the file system is created
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let reader = spa.reader_mut().unwrap();
+        let mut tx = Tx::create(dataset.object);
+        tx.hold_zap(MASTER_NODE_OBJ, true, Some("utf8only"));
+        tx.assign(&mut dsl_pool).unwrap();
+        zap::update(reader, &mut dsl_pool, &dataset.objset, MASTER_NODE_OBJ, "utf8only", 8,
+                    &[1], &tx)
+            .unwrap();
+        tx.commit(&mut dsl_pool);
+    }
+    *spa.dsl_pool_mut() = dsl_pool;
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    let (dataset, rules) = open(&mut spa);
+    assert!(rules.utf8only);
+    assert!(rules.check(b"caf\xE9").is_err());
+    assert!(rules.check("café".as_bytes()).is_ok());
+    // Creating and renaming go by the dataset's rules
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        create(reader, &mut dsl_pool, mos, &dataset, "/café", 0o644).unwrap();
+        let long = format!("/{}", "x".repeat(MAXNAMELEN + 1));
+        assert_eq!(create(reader, &mut dsl_pool, mos, &dataset, &long, 0o644),
+                   Err(zfs::Error::Invalid));
+        assert_eq!(rename(reader, &mut dsl_pool, mos, &dataset, "/café", &long,
+                          RenameFlags::default()),
+                   Err(zfs::Error::Invalid));
+    }
+    *spa.dsl_pool_mut() = dsl_pool;
+}
+
+#[test]
+fn test_fsync() {
+    use std::mem;
+    use super::spa;
+    use super::test_util;
+
+    let (mut spa, mut alloc, _device) = test_util::pool("zfs_test_zpl_fsync");
+    let open = |spa: &mut spa::Spa| {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        let dsl_pool = DslPool::open(reader, mos).unwrap();
+        dsl_pool.open_dataset(reader, mos, "tank", false).unwrap()
+    };
+
+    // The first commit starts the log, which is only found once its header is synced
+    let dataset = open(&mut spa);
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    let file = {
+        let (reader, mos) = spa.reader_and_mos().unwrap();
+        assert!(fsync(reader, &mut dsl_pool, &dataset, &mut alloc, None).unwrap());
+        let file = create(reader, &mut dsl_pool, mos, &dataset, "/log", 0o644).unwrap();
+        write(reader, &mut dsl_pool, &dataset, file, 0, b"on disk").unwrap();
+        assert!(!fsync(reader, &mut dsl_pool, &dataset, &mut alloc, None).unwrap());
+        file
+    };
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // A write committed to the log and never synced is replayed when the dataset is opened
+    // again, as after a crash
+    let dataset = open(&mut spa);
+    assert!(dataset.zil.is_empty());
+    let mut dsl_pool = mem::replace(spa.dsl_pool_mut(), DslPool::new());
+    {
+        let reader = spa.reader_mut().unwrap();
+        write(reader, &mut dsl_pool, &dataset, file, 3, b"LOGGED").unwrap();
+        assert_eq!(dsl_pool.zil(dataset.object).itxs().len(), 1);
+        assert!(fsync(reader, &mut dsl_pool, &dataset, &mut alloc, None).unwrap());
+    }
+    let crashed = open(&mut spa);
+    {
+        let reader = spa.reader_mut().unwrap();
+        assert_eq!(crashed.zil.replayed, 1);
+        let handle = super::zpl::open(reader, &crashed, "/log").unwrap();
+        assert_eq!(handle.read_all(reader).unwrap(), b"on LOGGED");
+        assert_eq!(fsync(reader, &mut dsl_pool, &crashed, &mut alloc, None),
+                   Err(zfs::Error::Busy));
+    }
+    mem::replace(spa.dsl_pool_mut(), dsl_pool);
+    spa.sync(&mut alloc).unwrap().unwrap();
+
+    // Once the txg is synced, there's nothing left to replay
+    let dataset = open(&mut spa);
+    let reader = spa.reader_mut().unwrap();
+    assert!(dataset.zil.is_empty());
+    let handle = super::zpl::open(reader, &dataset, "/log").unwrap();
+    assert_eq!(handle.read_all(reader).unwrap(), b"on LOGGED");
+}
+
+#[test]
+fn test_sa_xattrs() {
+    use super::nvpair::NvList;
+    use super::nvstream;
+    use super::xdr;
+
+    let mut nv = NvList::new(1);
+    nv.add("comment".to_owned(), NvValue::ByteArray(b"hello".to_vec()));
+    nv.add("system.posix_acl_access".to_owned(),
+           NvValue::ByteArray(vec![2, 0, 0, 0, 1, 0, 6, 0]));
+    nv.add("empty".to_owned(), NvValue::ByteArray(Vec::new()));
+    let pack = |nv: &NvList| {
+        let mut packed = vec![0; nvstream::encoded_size(nv)];
+        nvstream::encode_nv_list(&mut xdr::MemOps::new(&mut packed), nv).unwrap();
+        packed
+    };
+    assert_eq!(decode_sa_xattrs(&pack(&nv)).unwrap(),
+               vec![("comment".to_owned(), b"hello".to_vec()),
+                    ("system.posix_acl_access".to_owned(), vec![2, 0, 0, 0, 1, 0, 6, 0]),
+                    ("empty".to_owned(), Vec::new())]);
+
+    nv.add("bad".to_owned(), NvValue::Uint64(1));
+    assert!(decode_sa_xattrs(&pack(&nv)).is_err());
+    assert!(decode_sa_xattrs(&[1, 2, 3]).is_err());
+}
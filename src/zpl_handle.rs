@@ -0,0 +1,269 @@
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::ZfsReader;
+use super::dnode::ObjectType;
+use super::dsl_dataset::Dataset;
+use super::zap;
+use super::zpl::{self, DirEntry, FileHandle, FileType, Stat};
+
+// `open(2)` flags, as Linux numbers them
+const O_ACCMODE: u32 = 0o3;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+const O_NOFOLLOW: u32 = 0o400000;
+
+/// The `open(2)` flags a file was opened with, as passed through by the FUSE frontend
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OpenFlags {
+    /// Fail unless it's a directory (`O_DIRECTORY`)
+    pub directory: bool,
+    /// Fail if the last component of the path is a symlink (`O_NOFOLLOW`)
+    pub nofollow: bool,
+}
+
+impl OpenFlags {
+    /// Datasets are read only: opening for writing, creating or truncating is refused. Flags we
+    /// have no use for, like `O_CLOEXEC`, are ignored.
+    pub fn from_bits(bits: u32) -> Result<Self, String> {
+        if bits & O_ACCMODE != 0 || bits & (O_CREAT | O_TRUNC | O_APPEND) != 0 {
+            return Err(format!("Read-only file system (open flags {:o})", bits));
+        }
+        Ok(OpenFlags {
+            directory: bits & O_DIRECTORY != 0,
+            nofollow: bits & O_NOFOLLOW != 0,
+        })
+    }
+}
+
+/// A regular file or a directory opened through a `HandleTable`
+pub struct OpenFile {
+    /// The dataset it's in. The handle keeps it, and the object set it was opened at, around
+    /// for as long as the file is open.
+    pub dataset: Arc<Dataset>,
+    pub object: u64,
+    pub flags: OpenFlags,
+    /// The file's metadata as of the open, system attributes included
+    pub stat: Stat,
+    /// None for directories
+    file: Option<FileHandle>,
+    /// Where `read` carries on from, or for directories the index of the next entry `readdir`
+    /// returns
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    fn file(&self) -> Result<&FileHandle, String> {
+        self.file.as_ref().ok_or(format!("Object {} is a directory", self.object))
+    }
+
+    /// Read up to `len` bytes from the current offset, and move it past them
+    pub fn read(&self, reader: &mut ZfsReader, len: usize) -> Result<Vec<u8>, String> {
+        let file = try!(self.file());
+        let mut offset = self.offset.lock().unwrap();
+        let data = try!(file.read(reader, *offset, len));
+        *offset += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Read up to `len` bytes at `offset`, like `pread`: the current offset is left alone
+    pub fn read_at(&self,
+                   reader: &mut ZfsReader,
+                   offset: u64,
+                   len: usize)
+                   -> Result<Vec<u8>, String> {
+        try!(self.file()).read(reader, offset, len)
+    }
+
+    /// Move the current offset, like `lseek`. Returns the new offset.
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, String> {
+        let mut offset = self.offset.lock().unwrap();
+        let (base, delta) = match pos {
+            SeekFrom::Start(start) => (0, start as i64),
+            SeekFrom::Current(delta) => (*offset, delta),
+            SeekFrom::End(delta) => (self.stat.size, delta),
+        };
+        if delta < 0 && (-delta) as u64 > base {
+            return Err(format!("Seek before the start of object {}", self.object));
+        }
+        *offset = (base as i64 + delta) as u64;
+        Ok(*offset)
+    }
+
+    /// The next `count` entries of the directory, none once they've all been returned. `.` and
+    /// `..` are not included.
+    pub fn readdir(&self, reader: &mut ZfsReader, count: usize) -> Result<Vec<DirEntry>, String> {
+        if self.file.is_some() {
+            return Err(format!("Object {} is not a directory", self.object));
+        }
+        let mut offset = self.offset.lock().unwrap();
+        let entries: Vec<DirEntry> = try!(zap::iter(reader, &self.dataset.objset, self.object))
+                                         .skip(*offset as usize)
+                                         .take(count)
+                                         .map(|(name, value)| DirEntry::from_zap(name, value))
+                                         .collect();
+        *offset += entries.len() as u64;
+        Ok(entries)
+    }
+}
+
+struct Slot<T> {
+    /// Bumped when the slot is freed, so the numbers of closed handles don't reach whatever is
+    /// opened in the slot next
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Values by handle number: the slot index in the low 32 bits, its generation in the high ones
+struct Slots<T> {
+    slots: Vec<Slot<T>>,
+    /// Indices of the empty slots
+    free: Vec<usize>,
+}
+
+impl<T> Slots<T> {
+    fn new() -> Self {
+        Slots {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, value: T) -> u64 {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.value = Some(value);
+        (slot.generation as u64) << 32 | index as u64
+    }
+
+    fn slot(&self, handle: u64) -> Option<&Slot<T>> {
+        self.slots
+            .get((handle & 0xFFFFFFFF) as usize)
+            .filter(|slot| slot.generation as u64 == handle >> 32)
+    }
+
+    fn get(&self, handle: u64) -> Option<&T> {
+        self.slot(handle).and_then(|slot| slot.value.as_ref())
+    }
+
+    fn remove(&mut self, handle: u64) -> Option<T> {
+        if self.get(handle).is_none() {
+            return None;
+        }
+        let index = (handle & 0xFFFFFFFF) as usize;
+        let slot = &mut self.slots[index];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+        slot.value.take()
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+}
+
+/// The open files of the FUSE and Redox frontends, by handle number. A handle is resolved once,
+/// when it's opened; every operation on it after that goes straight to its dnode. Lookups take a
+/// shared lock, so any number of threads can use their handles at once.
+pub struct HandleTable {
+    handles: RwLock<Slots<Arc<OpenFile>>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable { handles: RwLock::new(Slots::new()) }
+    }
+
+    /// Open the regular file or directory at `path` of `dataset`, which should have been opened
+    /// pinned. Returns the number of the new handle.
+    pub fn open(&self,
+                reader: &mut ZfsReader,
+                dataset: &Arc<Dataset>,
+                path: &str,
+                flags: OpenFlags)
+                -> Result<u64, String> {
+        let object = try!(zpl::lookup(reader, dataset, path, !flags.nofollow));
+        let dnode = try!(dataset.objset.dnode(reader, object));
+        let stat = try!(zpl::stat_dnode(reader, dataset, object, &dnode));
+        let file = match stat.file_type() {
+            FileType::Regular if !flags.directory &&
+                                 dnode.object_type == ObjectType::PlainFileContents as u8 => {
+                Some(FileHandle {
+                    object: object,
+                    dnode: dnode,
+                    size: stat.size,
+                    log: dataset.zil.object(object).cloned(),
+                })
+            }
+            FileType::Directory => None,
+            FileType::Symlink if flags.nofollow => {
+                return Err(format!("{}: is a symlink", path));
+            }
+            _ if flags.directory => return Err(format!("{}: not a directory", path)),
+            _ => return Err(format!("{}: not a regular file or directory", path)),
+        };
+
+        let open_file = Arc::new(OpenFile {
+            dataset: dataset.clone(),
+            object: object,
+            flags: flags,
+            stat: stat,
+            file: file,
+            offset: Mutex::new(0),
+        });
+        Ok(self.handles.write().unwrap().insert(open_file))
+    }
+
+    /// The file open under `handle`. It stays usable after the handle is closed, until it's
+    /// dropped.
+    pub fn get(&self, handle: u64) -> Option<Arc<OpenFile>> {
+        self.handles.read().unwrap().get(handle).cloned()
+    }
+
+    /// Close `handle`. Returns whether it was open.
+    pub fn close(&self, handle: u64) -> bool {
+        self.handles.write().unwrap().remove(handle).is_some()
+    }
+
+    /// Number of open handles
+    pub fn len(&self) -> usize {
+        self.handles.read().unwrap().len()
+    }
+}
+
+#[test]
+fn test_handle_slots() {
+    let mut slots = Slots::new();
+    let handles: Vec<u64> = (0..1000).map(|i| slots.insert(i)).collect();
+    assert_eq!(slots.len(), 1000);
+    assert_eq!(slots.get(handles[500]), Some(&500));
+
+    // A closed handle doesn't reach the file opened in its slot after it
+    assert_eq!(slots.remove(handles[500]), Some(500));
+    assert_eq!(slots.remove(handles[500]), None);
+    let reused = slots.insert(1000);
+    assert_eq!(reused & 0xFFFFFFFF, 500);
+    assert_eq!(slots.get(handles[500]), None);
+    assert_eq!(slots.get(reused), Some(&1000));
+    assert_eq!(slots.len(), 1000);
+    assert_eq!(slots.get(5000), None);
+
+    assert_eq!(OpenFlags::from_bits(O_DIRECTORY | O_NOFOLLOW | 0o2000000).unwrap(),
+               OpenFlags {
+                   directory: true,
+                   nofollow: true,
+               });
+    assert!(OpenFlags::from_bits(2).is_err());
+    assert!(OpenFlags::from_bits(O_CREAT).is_err());
+}